@@ -0,0 +1,8 @@
+#![no_main]
+
+use bellande_artificial_intelligence_training_framework::fuzzing;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    fuzzing::decode_image(data);
+});
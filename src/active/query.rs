@@ -0,0 +1,246 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::data::dataset::Dataset;
+use crate::inference::batch::{self, FolderPredictionConfig};
+use crate::inference::predictor::Predictor;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Uncertainty measure that ranks unlabeled samples, the most uncertain
+/// first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryStrategy {
+    /// Entropy of the predicted class distribution
+    Entropy,
+    /// Gap between the two most probable classes, scored `1 - (p1 - p2)`
+    /// so that the smallest margins rank first
+    Margin,
+    /// Bayesian active learning by disagreement: the mutual information
+    /// between prediction and weights over `n_samples` Monte Carlo dropout
+    /// passes. Needs a model that supports `set_dropout_active`.
+    Bald { n_samples: usize },
+}
+
+impl QueryStrategy {
+    /// Uncertainty of every sample of a batch
+    pub fn scores(
+        &self,
+        predictor: &mut Predictor,
+        batch: &Tensor,
+    ) -> Result<Vec<f32>, BellandeError> {
+        if let QueryStrategy::Bald { n_samples } = *self {
+            let prediction = predictor.predict_mc_dropout(batch, n_samples)?;
+            return Ok(prediction.mutual_information.data);
+        }
+
+        let probabilities = predictor.predict_proba(batch)?;
+        let num_classes = probabilities.shape.get(1).copied().unwrap_or(1).max(1);
+        Ok(probabilities
+            .data
+            .chunks(num_classes)
+            .map(|row| match self {
+                QueryStrategy::Margin => margin_score(row),
+                _ => entropy(row),
+            })
+            .collect())
+    }
+}
+
+fn entropy(probabilities: &[f32]) -> f32 {
+    -probabilities
+        .iter()
+        .filter(|&&p| p > 0.0)
+        .map(|&p| p * p.ln())
+        .sum::<f32>()
+}
+
+fn margin_score(probabilities: &[f32]) -> f32 {
+    let (mut first, mut second) = (0.0f32, 0.0f32);
+    for &p in probabilities {
+        if p > first {
+            second = first;
+            first = p;
+        } else if p > second {
+            second = p;
+        }
+    }
+    1.0 - (first - second)
+}
+
+/// Selects the `budget` samples of an unlabeled pool to label next
+pub struct PoolQuery {
+    strategy: QueryStrategy,
+    budget: usize,
+    batch_size: usize,
+}
+
+impl PoolQuery {
+    pub fn new(strategy: QueryStrategy, budget: usize) -> Result<Self, BellandeError> {
+        if budget == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Query budget must be at least 1".into(),
+            ));
+        }
+        if strategy == (QueryStrategy::Bald { n_samples: 0 }) {
+            return Err(BellandeError::InvalidParameter(
+                "BALD needs at least one Monte Carlo dropout sample".into(),
+            ));
+        }
+        Ok(PoolQuery {
+            strategy,
+            budget,
+            batch_size: 32,
+        })
+    }
+
+    /// Number of pool samples scored per forward pass by `select`
+    pub fn with_batch_size(mut self, batch_size: usize) -> Result<Self, BellandeError> {
+        if batch_size == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Batch size must be greater than 0".into(),
+            ));
+        }
+        self.batch_size = batch_size;
+        Ok(self)
+    }
+
+    pub fn strategy(&self) -> QueryStrategy {
+        self.strategy
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Scores every sample of `pool`, whose targets are ignored, and returns
+    /// the indices and scores of the most uncertain ones, most uncertain
+    /// first
+    pub fn select(
+        &self,
+        predictor: &mut Predictor,
+        pool: &dyn Dataset,
+    ) -> Result<Vec<(usize, f32)>, BellandeError> {
+        let mut scored = Vec::with_capacity(pool.len());
+        let indices: Vec<usize> = (0..pool.len()).collect();
+        for chunk in indices.chunks(self.batch_size) {
+            let inputs: Vec<Tensor> = chunk.iter().map(|&index| pool.get(index).0).collect();
+            let scores = self.strategy.scores(predictor, &batch_inputs(&inputs)?)?;
+            scored.extend(chunk.iter().copied().zip(scores));
+        }
+        Ok(self.top(scored))
+    }
+
+    /// Scores every image below `root`, loaded like `Predictor::predict_folder`
+    /// with `config`, and returns the paths and scores of the most uncertain
+    /// ones, most uncertain first
+    pub fn select_from_folder(
+        &self,
+        predictor: &mut Predictor,
+        root: &Path,
+        config: &FolderPredictionConfig,
+    ) -> Result<Vec<(PathBuf, f32)>, BellandeError> {
+        let mut scored = Vec::new();
+        batch::for_each_batch(root, config, |paths, batch| {
+            let scores = self.strategy.scores(predictor, &batch)?;
+            scored.extend(paths.into_iter().zip(scores));
+            Ok(())
+        })?;
+        Ok(self.top(scored))
+    }
+
+    /// The `budget` highest scores, keeping pool order among ties and
+    /// ranking NaN scores last
+    fn top<K>(&self, mut scored: Vec<(K, f32)>) -> Vec<(K, f32)> {
+        let key = |score: f32| {
+            if score.is_nan() {
+                f32::NEG_INFINITY
+            } else {
+                score
+            }
+        };
+        scored.sort_by(|a, b| key(b.1).total_cmp(&key(a.1)));
+        scored.truncate(self.budget);
+        scored
+    }
+}
+
+/// Stacks pool samples into a batch. Samples that already carry a batch
+/// dimension of 1, like images from `ImageFolder`, are concatenated along it.
+fn batch_inputs(inputs: &[Tensor]) -> Result<Tensor, BellandeError> {
+    let first = &inputs[0];
+    let mut data = Vec::with_capacity(first.data.len() * inputs.len());
+    for input in inputs {
+        if input.shape != first.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Cannot batch pool samples of shape {:?} and {:?}",
+                first.shape, input.shape
+            )));
+        }
+        data.extend_from_slice(&input.data);
+    }
+
+    let mut shape = first.shape.clone();
+    if shape.len() == 4 && shape[0] == 1 {
+        shape[0] = inputs.len();
+    } else {
+        shape.insert(0, inputs.len());
+    }
+    Ok(Tensor::new(
+        data,
+        shape,
+        false,
+        first.device.clone(),
+        first.dtype,
+    ))
+}
+
+/// Moves a newly labeled image from the pool into the class directory
+/// `root/class_name`, where an `ImageFolder` over `root` built
+/// `with_epoch_rescan` picks it up at the next epoch. Returns the new path;
+/// an existing file of the same name is never overwritten.
+pub fn label_image(path: &Path, root: &Path, class_name: &str) -> Result<PathBuf, BellandeError> {
+    let mut components = Path::new(class_name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Invalid class name '{}'",
+            class_name
+        )));
+    }
+    let file_name = path.file_name().ok_or_else(|| {
+        BellandeError::InvalidParameter(format!("{} is not a file", path.display()))
+    })?;
+
+    let class_dir = root.join(class_name);
+    fs::create_dir_all(&class_dir).map_err(BellandeError::IOError)?;
+    let destination = class_dir.join(file_name);
+    if destination.exists() {
+        return Err(BellandeError::InvalidOperation(format!(
+            "{} already exists",
+            destination.display()
+        )));
+    }
+
+    // Renaming fails across file systems, where the file is copied instead
+    if fs::rename(path, &destination).is_err() {
+        fs::copy(path, &destination).map_err(BellandeError::IOError)?;
+        fs::remove_file(path).map_err(BellandeError::IOError)?;
+    }
+    Ok(destination)
+}
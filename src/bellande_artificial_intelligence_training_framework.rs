@@ -16,17 +16,29 @@
 use std::error::Error;
 use std::path::Path;
 
+mod active;
 mod core;
 mod data;
+mod federated;
+mod inference;
 mod layer;
 mod loss;
 mod metrics;
+mod ml;
 mod models;
 mod optim;
+mod testing;
 mod training;
 mod utilities;
 
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod quickstart;
+
 use crate::core::{device::Device, error::BellandeError};
+use crate::data::preprocessing::{Normalize, Preprocessor};
+use crate::models::models::Model;
+use crate::models::{architecture, resnet::ResNet, vgg::VGG};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const FRAMEWORK_NAME: &str = "Bellande AI Training Framework";
@@ -111,4 +123,65 @@ impl Framework {
             Device::default(),
         )
     }
+
+    /// Runs a command-line subcommand, e.g. `predict-folder`
+    pub fn run_cli(&mut self, args: &[String]) -> Result<(), Box<dyn Error>> {
+        match args.first().map(String::as_str) {
+            Some("predict-folder") => {
+                let parsed = utilities::cli::PredictFolderArgs::parse(&args[1..])?;
+                self.initialize()?;
+
+                let count = utilities::cli::predict_folder(
+                    self.build_model()?,
+                    self.device.clone(),
+                    self.preprocessors(),
+                    &parsed,
+                )?;
+                eprintln!("Wrote predictions for {} images", count);
+                Ok(())
+            }
+            Some("help") | Some("--help") | Some("-h") | None => {
+                print!("{}", utilities::cli::USAGE);
+                Ok(())
+            }
+            Some(other) => Err(Box::new(BellandeError::InvalidParameter(format!(
+                "Unknown command '{}'\n\n{}",
+                other,
+                utilities::cli::USAGE
+            )))),
+        }
+    }
+
+    /// Builds the model described by the `model` section of the configuration
+    fn build_model(&self) -> Result<Box<dyn Model>, BellandeError> {
+        let model = &self.config.model;
+        match model.architecture.as_str() {
+            "resnet18" => Ok(Box::new(ResNet::resnet18(model.num_classes))),
+            "vgg16" => Ok(Box::new(VGG::vgg16(model.num_classes))),
+            name if architecture::PRESETS.contains(&name) => {
+                let mut config = architecture::preset(name, &model.input_shape, model.num_classes)?;
+                if let Some(rate) = model.dropout_rate {
+                    config.dropout_rate = rate;
+                }
+                Ok(Box::new(architecture::create_cnn(&config)?))
+            }
+            name => Err(BellandeError::InvalidConfiguration(format!(
+                "Unknown model architecture '{}', expected one of resnet18, vgg16, {}",
+                name,
+                architecture::PRESETS.join(", ")
+            ))),
+        }
+    }
+
+    /// Preprocessing applied to images before inference
+    fn preprocessors(&self) -> Vec<Box<dyn Preprocessor>> {
+        let mut preprocessors: Vec<Box<dyn Preprocessor>> = Vec::new();
+        if self.config.data.normalize {
+            preprocessors.push(Box::new(Normalize::new(
+                vec![0.485, 0.456, 0.406],
+                vec![0.229, 0.224, 0.225],
+            )));
+        }
+        preprocessors
+    }
 }
@@ -18,12 +18,49 @@ extern crate test;
 
 use bellande_training_framework::{
     core::{DataType, Device, Tensor},
-    layer::{BatchNorm2d, Conv2d, Linear},
+    layer::{transformer::MultiHeadAttention, BatchNorm2d, Conv2d, Linear},
+    loss::{cross_entropy::CrossEntropyLoss, Loss},
     models::{Model, ResNet, VGG},
     optim::{Adam, RMSprop, SGD},
 };
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use test::Bencher;
 
+/// Global allocator that tracks the peak of live heap bytes, for the
+/// memory benchmarks
+struct PeakAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAllocator = PeakAllocator;
+
+/// Peak heap bytes `operation` allocates on top of what was live before
+fn peak_memory<T>(operation: impl FnOnce() -> T) -> usize {
+    let base = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    drop(operation());
+    PEAK.load(Ordering::Relaxed) - base
+}
+
 // Tensor Operations Benchmarks
 #[bench]
 fn bench_tensor_matmul(b: &mut Bencher) {
@@ -78,6 +115,38 @@ fn bench_batchnorm_forward(b: &mut Bencher) {
     });
 }
 
+// Attention Benchmarks
+fn attention_step(
+    attention: &mut MultiHeadAttention,
+    input: &Tensor,
+) -> Result<Tensor, BellandeError> {
+    let output = attention.forward(input, input, input, None)?;
+    let (grad_query, _, _) = attention.backward(&output)?;
+    Ok(grad_query)
+}
+
+#[bench]
+fn bench_attention_forward_backward(b: &mut Bencher) {
+    let mut attention = MultiHeadAttention::new(256, 8, 0.0);
+    let input = Tensor::randn(&[4, 512, 256]);
+
+    b.iter(|| {
+        let _ = attention_step(&mut attention, &input).unwrap();
+    });
+}
+
+#[bench]
+fn bench_memory_efficient_attention_forward_backward(b: &mut Bencher) {
+    let mut attention = MultiHeadAttention::new(256, 8, 0.0)
+        .with_memory_efficient_attention(64)
+        .unwrap();
+    let input = Tensor::randn(&[4, 512, 256]);
+
+    b.iter(|| {
+        let _ = attention_step(&mut attention, &input).unwrap();
+    });
+}
+
 // Model Benchmarks
 #[bench]
 fn bench_resnet18_forward(b: &mut Bencher) {
@@ -102,10 +171,16 @@ fn bench_vgg16_forward(b: &mut Bencher) {
 // Optimizer Benchmarks
 #[bench]
 fn bench_adam_step(b: &mut Bencher) {
-    let params = vec![
+    let params: Vec<Tensor> = vec![
         Tensor::randn(&[1024, 1024], Device::CPU, DataType::Float32),
         Tensor::randn(&[1024], Device::CPU, DataType::Float32),
-    ];
+    ]
+    .into_iter()
+    .map(|mut p| {
+        p.grad = Some(vec![0.01; p.data.len()]);
+        p
+    })
+    .collect();
     let mut optimizer = Adam::new(params, 0.001, (0.9, 0.999), 1e-8, 0.0);
 
     b.iter(|| {
@@ -115,10 +190,16 @@ fn bench_adam_step(b: &mut Bencher) {
 
 #[bench]
 fn bench_sgd_step(b: &mut Bencher) {
-    let params = vec![
+    let params: Vec<Tensor> = vec![
         Tensor::randn(&[1024, 1024], Device::CPU, DataType::Float32),
         Tensor::randn(&[1024], Device::CPU, DataType::Float32),
-    ];
+    ]
+    .into_iter()
+    .map(|mut p| {
+        p.grad = Some(vec![0.01; p.data.len()]);
+        p
+    })
+    .collect();
     let mut optimizer = SGD::new(params, 0.01, 0.9, 0.0, false);
 
     b.iter(|| {
@@ -140,7 +221,7 @@ fn bench_training_step(b: &mut Bencher) {
     // Setup model and training components
     let model = ResNet::resnet18(1000);
     let optimizer = Adam::new(model.parameters(), 0.001, (0.9, 0.999), 1e-8, 0.0);
-    let loss_fn = CrossEntropyLoss::new();
+    let loss_fn = CrossEntropyLoss::default();
 
     // Create dummy batch
     let input = Tensor::randn(&[32, 3, 224, 224], Device::CPU, DataType::Float32);
@@ -242,6 +323,8 @@ pub struct BenchmarkConfig {
     pub model_sizes: Vec<usize>,
     pub iterations: usize,
     pub warmup_iterations: usize,
+    /// Sequence lengths of the attention memory benchmarks
+    pub sequence_lengths: Vec<usize>,
 }
 
 impl Default for BenchmarkConfig {
@@ -251,6 +334,7 @@ impl Default for BenchmarkConfig {
             model_sizes: vec![64, 128, 256, 512],
             iterations: 100,
             warmup_iterations: 10,
+            sequence_lengths: vec![256, 1024, 2048],
         }
     }
 }
@@ -259,6 +343,8 @@ impl Default for BenchmarkConfig {
 pub struct BenchmarkSuite {
     config: BenchmarkConfig,
     results: HashMap<String, Vec<Duration>>,
+    /// Peak heap bytes of each memory benchmark
+    memory_results: HashMap<String, usize>,
 }
 
 impl BenchmarkSuite {
@@ -266,6 +352,7 @@ impl BenchmarkSuite {
         BenchmarkSuite {
             config,
             results: HashMap::new(),
+            memory_results: HashMap::new(),
         }
     }
 
@@ -289,12 +376,23 @@ impl BenchmarkSuite {
         &self.results
     }
 
+    pub fn get_memory_results(&self) -> &HashMap<String, usize> {
+        &self.memory_results
+    }
+
     pub fn print_results(&self) {
         println!("Benchmark Results:");
         for (name, durations) in &self.results {
             let avg = durations.iter().sum::<Duration>() / durations.len() as u32;
             println!("{}: {:?} average", name, avg);
         }
+        for (name, bytes) in &self.memory_results {
+            println!(
+                "{}: {:.1} MiB peak",
+                name,
+                *bytes as f64 / (1024.0 * 1024.0)
+            );
+        }
     }
 
     fn benchmark_tensor_ops(&mut self) -> Result<(), BellandeError> {
@@ -327,8 +425,24 @@ impl BenchmarkSuite {
         Ok(())
     }
 
+    /// Peak memory of an attention forward and backward pass with and
+    /// without the memory-efficient path, whose saving grows with the
+    /// square of the sequence length
     fn benchmark_memory(&mut self) -> Result<(), BellandeError> {
-        // Implementation of memory benchmarks
+        for &seq_len in &self.config.sequence_lengths {
+            let input = Tensor::randn(&[1, seq_len, 64]);
+
+            let mut attention = MultiHeadAttention::new(64, 4, 0.0);
+            let peak = peak_memory(|| attention_step(&mut attention, &input));
+            self.memory_results
+                .insert(format!("attention_seq_{}", seq_len), peak);
+
+            let mut attention =
+                MultiHeadAttention::new(64, 4, 0.0).with_memory_efficient_attention(64)?;
+            let peak = peak_memory(|| attention_step(&mut attention, &input));
+            self.memory_results
+                .insert(format!("memory_efficient_attention_seq_{}", seq_len), peak);
+        }
         Ok(())
     }
 
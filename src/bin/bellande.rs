@@ -0,0 +1,39 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use bellande_artificial_intelligence_training_framework::Framework;
+use std::process;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let framework = if args.first().map(String::as_str) == Some("--config") {
+        if args.len() < 2 {
+            eprintln!("--config expects a path");
+            process::exit(2);
+        }
+        let path = args.remove(1);
+        args.remove(0);
+        Framework::with_config(path)
+    } else {
+        Framework::new()
+    };
+
+    let result = framework.and_then(|mut framework| framework.run_cli(&args));
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::autograd::{self, AutogradContext, AutogradFunction, BackwardOptions, Node};
+use crate::core::{error::BellandeError, random, tensor::Tensor};
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use std::sync::Arc;
+
+/// A segment of computation that can be re-run during backward
+pub type CheckpointFn = Arc<dyn Fn(&[Tensor]) -> Result<Tensor, BellandeError> + Send + Sync>;
+
+/// Runs `function` on `inputs` without keeping its intermediate activations.
+///
+/// Only the inputs are saved; during backward the segment is run again with
+/// gradients enabled and backpropagated through. This trades one extra forward
+/// pass of the segment for not holding its activations in memory. Parameters
+/// captured by `function` receive their gradients during that recomputation.
+/// The random number generator state is restored before recomputing, so
+/// dropout masks match the original forward pass.
+pub fn checkpoint(function: CheckpointFn, inputs: &[&Tensor]) -> Result<Tensor, BellandeError> {
+    Node::record(
+        Arc::new(CheckpointFunction {
+            function,
+            rng_state: Mutex::new(None),
+        }),
+        inputs,
+    )
+}
+
+/// Splits `segments` into `chunks` groups and checkpoints each group, so only
+/// the activations at group boundaries are kept
+pub fn checkpoint_sequential(
+    segments: &[CheckpointFn],
+    chunks: usize,
+    input: &Tensor,
+) -> Result<Tensor, BellandeError> {
+    if chunks == 0 {
+        return Err(BellandeError::InvalidParameter(
+            "chunks must be greater than 0".into(),
+        ));
+    }
+
+    let chunk_size = segments.len().div_ceil(chunks);
+    let mut current = input.clone();
+    for group in segments.chunks(chunk_size.max(1)) {
+        let group: Vec<CheckpointFn> = group.to_vec();
+        let run_group: CheckpointFn = Arc::new(move |inputs: &[Tensor]| {
+            let mut x = inputs[0].clone();
+            for segment in &group {
+                x = segment(&[x])?;
+            }
+            Ok(x)
+        });
+        current = checkpoint(run_group, &[&current])?;
+    }
+    Ok(current)
+}
+
+struct CheckpointFunction {
+    function: CheckpointFn,
+    rng_state: Mutex<Option<StdRng>>,
+}
+
+impl AutogradFunction for CheckpointFunction {
+    fn forward(
+        &self,
+        ctx: &mut AutogradContext,
+        inputs: &[&Tensor],
+    ) -> Result<Tensor, BellandeError> {
+        for input in inputs {
+            ctx.save_for_backward(input.detach());
+        }
+        *self.rng_state.lock() = Some(random::get_rng_state());
+
+        let owned: Vec<Tensor> = inputs.iter().map(|input| (*input).clone()).collect();
+        (self.function)(&owned)
+    }
+
+    fn backward(
+        &self,
+        ctx: &AutogradContext,
+        grad_output: &Tensor,
+    ) -> Result<Vec<Tensor>, BellandeError> {
+        let mut inputs = Vec::with_capacity(ctx.get_saved_tensors().len());
+        for (saved, &needs_grad) in ctx.get_saved_tensors().iter().zip(ctx.needs_input_grad()) {
+            let mut input = saved.detach();
+            input.requires_grad_(needs_grad)?;
+            inputs.push(input);
+        }
+
+        // Grad mode is only enabled here when the outer backward creates a graph
+        let create_graph = autograd::is_grad_enabled();
+        let output = {
+            let _grad = autograd::set_grad_enabled(true);
+            let current_rng = random::get_rng_state();
+            if let Some(state) = self.rng_state.lock().clone() {
+                random::set_rng_state(state);
+            }
+            let output = (self.function)(&inputs);
+            random::set_rng_state(current_rng);
+            output?
+        };
+
+        if output.requires_grad {
+            output.backward_with_options(
+                Some(grad_output),
+                BackwardOptions::new().with_create_graph(create_graph),
+            )?;
+        }
+
+        Ok(inputs
+            .iter()
+            .map(|input| {
+                input.grad().unwrap_or_else(|| {
+                    Tensor::new(
+                        vec![0.0; input.data.len()],
+                        input.shape.clone(),
+                        false,
+                        input.device.clone(),
+                        input.dtype,
+                    )
+                })
+            })
+            .collect())
+    }
+
+    fn name(&self) -> &str {
+        "Checkpoint"
+    }
+}
@@ -15,19 +15,188 @@
 
 use crate::core::error::BellandeError;
 use crate::core::tensor::Tensor;
+use parking_lot::Mutex;
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+
 pub struct AddFunction;
 pub struct MulFunction;
 pub struct MatMulFunction;
+pub struct TransposeFunction;
+
+thread_local! {
+    static GRAD_ENABLED: Cell<bool> = Cell::new(true);
+    static ANOMALY_ENABLED: Cell<bool> = Cell::new(false);
+}
+
+static NEXT_NODE_ID: AtomicUsize = AtomicUsize::new(0);
+static NEXT_HOOK_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Gradient hook: receives the gradient flowing into a tensor and may return
+/// a replacement of the same shape
+pub type GradHook = Arc<dyn Fn(&Tensor) -> Option<Tensor> + Send + Sync>;
+
+/// Hooks registered on a tensor, run in registration order
+pub struct GradHooks {
+    hooks: Mutex<Vec<(usize, GradHook)>>,
+}
+
+impl GradHooks {
+    pub fn new() -> Self {
+        GradHooks {
+            hooks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Registers a hook and returns an id that can be passed to `remove`
+    pub fn register(&self, hook: GradHook) -> usize {
+        let id = NEXT_HOOK_ID.fetch_add(1, Ordering::SeqCst);
+        self.hooks.lock().push((id, hook));
+        id
+    }
+
+    /// Removes a hook, returning whether it was registered
+    pub fn remove(&self, id: usize) -> bool {
+        let mut hooks = self.hooks.lock();
+        let before = hooks.len();
+        hooks.retain(|(hook_id, _)| *hook_id != id);
+        hooks.len() != before
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.lock().is_empty()
+    }
+
+    /// Runs the hooks in order, each seeing the previous hook's replacement
+    pub(crate) fn apply(&self, mut grad: Tensor) -> Result<Tensor, BellandeError> {
+        // Hooks are cloned out so they may register or remove hooks themselves
+        let hooks: Vec<GradHook> = self.hooks.lock().iter().map(|(_, h)| h.clone()).collect();
+        for hook in hooks {
+            if let Some(replacement) = hook(&grad) {
+                if replacement.shape != grad.shape {
+                    return Err(BellandeError::ShapeMismatch(format!(
+                        "Hook returned gradient of shape {:?}, expected {:?}",
+                        replacement.shape, grad.shape
+                    )));
+                }
+                grad = replacement;
+            }
+        }
+        Ok(grad)
+    }
+}
+
+impl Default for GradHooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns whether operations on the current thread are recorded into the graph
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(|enabled| enabled.get())
+}
+
+/// Restores the previous grad mode when dropped
+pub struct GradModeGuard {
+    previous: bool,
+}
+
+impl Drop for GradModeGuard {
+    fn drop(&mut self) {
+        GRAD_ENABLED.with(|enabled| enabled.set(self.previous));
+    }
+}
+
+/// Sets grad mode for the current thread until the returned guard is dropped
+pub fn set_grad_enabled(enabled: bool) -> GradModeGuard {
+    let previous = GRAD_ENABLED.with(|flag| flag.replace(enabled));
+    GradModeGuard { previous }
+}
+
+/// Disables graph recording until the returned guard is dropped
+pub fn no_grad() -> GradModeGuard {
+    set_grad_enabled(false)
+}
+
+/// Returns whether anomaly detection is enabled on the current thread
+pub fn is_anomaly_enabled() -> bool {
+    ANOMALY_ENABLED.with(|enabled| enabled.get())
+}
+
+/// Restores the previous anomaly mode when dropped
+pub struct AnomalyModeGuard {
+    previous: bool,
+}
+
+impl Drop for AnomalyModeGuard {
+    fn drop(&mut self) {
+        ANOMALY_ENABLED.with(|enabled| enabled.set(self.previous));
+    }
+}
+
+/// Enables or disables anomaly detection until the returned guard is dropped.
+///
+/// While enabled, every recorded op captures the backtrace of its forward
+/// call, and backward fails with `BellandeError::AnomalyDetected` naming
+/// that op as soon as it produces a NaN or infinite gradient. Capturing
+/// backtraces is slow, so this is meant for debugging only.
+pub fn set_detect_anomaly(enabled: bool) -> AnomalyModeGuard {
+    let previous = ANOMALY_ENABLED.with(|flag| flag.replace(enabled));
+    AnomalyModeGuard { previous }
+}
+
+/// Enables anomaly detection until the returned guard is dropped
+pub fn detect_anomaly() -> AnomalyModeGuard {
+    set_detect_anomaly(true)
+}
 
+/// A differentiable operation with a hand-written backward pass.
+///
+/// `forward` runs with gradient recording disabled and may stash whatever it
+/// needs in the context. `backward` receives the gradient of the output and
+/// returns one gradient per input, each shaped like that input; gradients of
+/// inputs for which `ctx.needs_input_grad()` is false are ignored. Call
+/// [`apply`](AutogradFunction::apply) to run the op and record it in the graph.
 pub trait AutogradFunction: Send + Sync {
-    fn forward(&self, inputs: &[&Tensor]) -> Result<Tensor, BellandeError>;
-    fn backward(&self, grad_output: &Tensor) -> Result<Vec<Tensor>, BellandeError>;
+    fn forward(
+        &self,
+        ctx: &mut AutogradContext,
+        inputs: &[&Tensor],
+    ) -> Result<Tensor, BellandeError>;
+
+    /// Returns one gradient per forward input
+    fn backward(
+        &self,
+        ctx: &AutogradContext,
+        grad_output: &Tensor,
+    ) -> Result<Vec<Tensor>, BellandeError>;
+
+    /// Name of the operation, used in error messages
+    fn name(&self) -> &str {
+        "AutogradFunction"
+    }
+
+    /// Runs the op on `inputs` and connects the output to the graph
+    fn apply(self, inputs: &[&Tensor]) -> Result<Tensor, BellandeError>
+    where
+        Self: Sized + 'static,
+    {
+        Node::record(Arc::new(self), inputs)
+    }
 }
 
+/// State shared between the forward and backward pass of one op
 pub struct AutogradContext {
     saved_tensors: Vec<Tensor>,
     needs_input_grad: Vec<bool>,
+    input_shapes: Vec<Vec<usize>>,
+    released: bool,
 }
 
 impl AutogradContext {
@@ -35,9 +204,13 @@ impl AutogradContext {
         AutogradContext {
             saved_tensors: Vec::new(),
             needs_input_grad,
+            input_shapes: Vec::new(),
+            released: false,
         }
     }
 
+    /// Keeps a tensor for use in backward; saved tensors are freed once the
+    /// graph has been backpropagated through unless `retain_graph` is set
     pub fn save_for_backward(&mut self, tensor: Tensor) {
         self.saved_tensors.push(tensor);
     }
@@ -45,37 +218,676 @@ impl AutogradContext {
     pub fn get_saved_tensors(&self) -> &[Tensor] {
         &self.saved_tensors
     }
+
+    /// Whether each forward input requires a gradient
+    pub fn needs_input_grad(&self) -> &[bool] {
+        &self.needs_input_grad
+    }
+
+    /// Whether the input at `index` requires a gradient
+    pub fn needs_input_grad_at(&self, index: usize) -> bool {
+        self.needs_input_grad.get(index).copied().unwrap_or(false)
+    }
+
+    /// Shapes of the forward inputs
+    pub fn input_shapes(&self) -> &[Vec<usize>] {
+        &self.input_shapes
+    }
+
+    /// Frees saved tensors once the graph has been backpropagated through
+    fn release(&mut self) {
+        self.saved_tensors.clear();
+        self.released = true;
+    }
+}
+
+/// Shared gradient buffer of a leaf tensor; clones of the tensor share it so
+/// gradients from every use of the leaf accumulate in one place
+pub struct GradAccumulator {
+    grad: Mutex<Option<Tensor>>,
+    hooks: GradHooks,
+}
+
+impl GradAccumulator {
+    pub fn new() -> Self {
+        GradAccumulator {
+            grad: Mutex::new(None),
+            hooks: GradHooks::new(),
+        }
+    }
+
+    pub fn get(&self) -> Option<Tensor> {
+        self.grad.lock().clone()
+    }
+
+    pub fn reset(&self) {
+        *self.grad.lock() = None;
+    }
+
+    pub fn hooks(&self) -> &GradHooks {
+        &self.hooks
+    }
+
+    /// Replaces the stored gradient without running the hooks
+    pub(crate) fn set(&self, grad: Option<Tensor>) {
+        *self.grad.lock() = grad;
+    }
+
+    /// Adds `values`, one row each, to rows `rows` of the stored gradient of
+    /// a (num_rows, row_len) parameter, starting from zeros. Skips the hooks,
+    /// so it is only for parameters that have none.
+    pub(crate) fn accumulate_rows(&self, shape: &[usize], rows: &[usize], values: &[f32]) {
+        let row_len = shape.iter().skip(1).product::<usize>().max(1);
+        let mut slot = self.grad.lock();
+        let grad = slot.get_or_insert_with(|| Tensor::zeros(shape));
+        for (&row, values) in rows.iter().zip(values.chunks(row_len)) {
+            grad.data[row * row_len..(row + 1) * row_len]
+                .iter_mut()
+                .zip(values)
+                .for_each(|(g, &v)| *g += v);
+        }
+    }
+
+    /// Runs the hooks on `grad` and adds the result to the stored gradient
+    pub(crate) fn accumulate(&self, grad: Tensor) -> Result<(), BellandeError> {
+        let grad = self.hooks.apply(grad)?;
+        let mut slot = self.grad.lock();
+        *slot = Some(match slot.take() {
+            Some(existing) => existing.add(&grad)?,
+            None => grad,
+        });
+        Ok(())
+    }
+}
+
+impl Default for GradAccumulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for GradAccumulator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GradAccumulator")
+            .field("has_grad", &self.grad.lock().is_some())
+            .finish()
+    }
+}
+
+/// Where the gradient for one input of a node flows to
+#[derive(Clone)]
+pub enum Edge {
+    Function(Arc<Node>),
+    Leaf(Arc<GradAccumulator>),
+    None,
+}
+
+/// A recorded operation in the computation graph
+pub struct Node {
+    id: usize,
+    function: Arc<dyn AutogradFunction>,
+    ctx: Mutex<AutogradContext>,
+    next_edges: Vec<Edge>,
+    hooks: GradHooks,
+    /// Backtrace of the forward call, captured in anomaly mode
+    trace: Option<String>,
+}
+
+impl Node {
+    /// Runs `function` on `inputs` and, when any input requires gradients and
+    /// grad mode is enabled, attaches a graph node to the output
+    pub fn record(
+        function: Arc<dyn AutogradFunction>,
+        inputs: &[&Tensor],
+    ) -> Result<Tensor, BellandeError> {
+        let needs_input_grad: Vec<bool> = inputs.iter().map(|t| t.requires_grad).collect();
+        let mut ctx = AutogradContext::new(needs_input_grad);
+        ctx.input_shapes = inputs.iter().map(|t| t.shape.clone()).collect();
+
+        let mut output = {
+            let _guard = no_grad();
+            function.forward(&mut ctx, inputs)?
+        };
+
+        output.grad = None;
+        output.grad_fn = None;
+        output.requires_grad = false;
+
+        if is_grad_enabled() && ctx.needs_input_grad.iter().any(|&needs| needs) {
+            let next_edges = inputs
+                .iter()
+                .map(|input| Edge::from_tensor(input))
+                .collect();
+            output.requires_grad = true;
+            output.grad_fn = Some(Arc::new(Node {
+                id: NEXT_NODE_ID.fetch_add(1, Ordering::SeqCst),
+                function,
+                ctx: Mutex::new(ctx),
+                next_edges,
+                hooks: GradHooks::new(),
+                trace: is_anomaly_enabled().then(|| Backtrace::force_capture().to_string()),
+            }));
+        }
+
+        Ok(output)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        self.function.name()
+    }
+
+    pub fn next_edges(&self) -> &[Edge] {
+        &self.next_edges
+    }
+
+    /// Hooks run on the gradient of this node's output before backward
+    pub fn hooks(&self) -> &GradHooks {
+        &self.hooks
+    }
+
+    fn apply_backward(
+        &self,
+        grad_output: &Tensor,
+        retain_graph: bool,
+    ) -> Result<Vec<Tensor>, BellandeError> {
+        let mut ctx = self.ctx.lock();
+        if ctx.released {
+            return Err(BellandeError::RuntimeError(format!(
+                "Trying to backward through {} a second time, but its saved tensors \
+                 have been freed; pass retain_graph on the first backward",
+                self.function.name()
+            )));
+        }
+
+        let grads = self.function.backward(&ctx, grad_output)?;
+        if grads.len() != self.next_edges.len() {
+            return Err(BellandeError::RuntimeError(format!(
+                "{} returned {} gradients for {} inputs",
+                self.function.name(),
+                grads.len(),
+                self.next_edges.len()
+            )));
+        }
+
+        for (index, grad) in grads.iter().enumerate() {
+            if ctx.needs_input_grad_at(index) && grad.shape != ctx.input_shapes[index] {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "{} returned a gradient of shape {:?} for input {} of shape {:?}",
+                    self.function.name(),
+                    grad.shape,
+                    index,
+                    ctx.input_shapes[index]
+                )));
+            }
+        }
+
+        if !retain_graph {
+            ctx.release();
+        }
+
+        if is_anomaly_enabled() {
+            for (input, grad) in grads.iter().enumerate() {
+                if let Some(position) = grad.data.iter().position(|v| !v.is_finite()) {
+                    return Err(BellandeError::AnomalyDetected(format!(
+                        "{} (node {}) produced {} in the gradient of input {} at index {}.\n{}",
+                        self.function.name(),
+                        self.id,
+                        grad.data[position],
+                        input,
+                        position,
+                        self.provenance()
+                    )));
+                }
+            }
+        }
+
+        Ok(grads)
+    }
+
+    fn provenance(&self) -> String {
+        match self.trace {
+            Some(ref trace) => format!("Forward call that created this op:\n{}", trace),
+            None => "Forward call not recorded; enable anomaly detection before the forward pass \
+                     to capture it"
+                .to_string(),
+        }
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("id", &self.id)
+            .field("function", &self.function.name())
+            .field("num_inputs", &self.next_edges.len())
+            .field("has_trace", &self.trace.is_some())
+            .finish()
+    }
+}
+
+impl Edge {
+    fn from_tensor(tensor: &Tensor) -> Self {
+        if let Some(ref node) = tensor.grad_fn {
+            Edge::Function(Arc::clone(node))
+        } else if let Some(ref accumulator) = tensor.grad_accumulator {
+            Edge::Leaf(Arc::clone(accumulator))
+        } else {
+            Edge::None
+        }
+    }
+}
+
+/// Options controlling a backward pass
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BackwardOptions {
+    /// Keep saved tensors so the graph can be backpropagated through again
+    pub retain_graph: bool,
+    /// Record the backward computation itself so the resulting gradients can
+    /// be differentiated again. Implies `retain_graph`.
+    pub create_graph: bool,
+}
+
+impl BackwardOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retain_graph(mut self, retain_graph: bool) -> Self {
+        self.retain_graph = retain_graph;
+        self
+    }
+
+    pub fn with_create_graph(mut self, create_graph: bool) -> Self {
+        self.create_graph = create_graph;
+        self
+    }
+}
+
+/// Backpropagates `grad_output` from `root` and accumulates the gradients
+/// into the leaf tensors of the graph
+pub fn run_backward(
+    root: &Tensor,
+    grad_output: Tensor,
+    options: BackwardOptions,
+) -> Result<(), BellandeError> {
+    execute(root, grad_output, options, &[]).map(|_| ())
+}
+
+/// Computes the gradients of `output` with respect to each of `inputs` and
+/// returns them instead of accumulating into leaf tensors. `grad_output`
+/// defaults to ones for a single-element output. With `create_graph` the
+/// returned gradients are part of the graph and can be differentiated again,
+/// e.g. for gradient penalties.
+pub fn grad(
+    output: &Tensor,
+    inputs: &[&Tensor],
+    grad_output: Option<&Tensor>,
+    options: BackwardOptions,
+) -> Result<Vec<Tensor>, BellandeError> {
+    let grad_output = match grad_output {
+        Some(grad) => grad.clone(),
+        None if output.data.len() == 1 => Tensor::new(
+            vec![1.0],
+            output.shape.clone(),
+            false,
+            output.device.clone(),
+            output.dtype,
+        ),
+        None => {
+            return Err(BellandeError::RuntimeError(
+                "grad_output must be given for non-scalar outputs".into(),
+            ))
+        }
+    };
+
+    let targets = inputs
+        .iter()
+        .map(|input| match (&input.grad_fn, &input.grad_accumulator) {
+            (Some(node), _) => Ok(Target::Node(node.id)),
+            (None, Some(accumulator)) => Ok(Target::Leaf(Arc::clone(accumulator))),
+            (None, None) => Err(BellandeError::NoGradients),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let captured = execute(output, grad_output, options, &targets)?;
+    Ok(captured
+        .into_iter()
+        .zip(inputs)
+        .map(|(grad, input)| {
+            grad.unwrap_or_else(|| {
+                Tensor::new(
+                    vec![0.0; input.data.len()],
+                    input.shape.clone(),
+                    false,
+                    input.device.clone(),
+                    input.dtype,
+                )
+            })
+        })
+        .collect())
+}
+
+/// Tensor whose gradient `grad` captures
+enum Target {
+    Node(usize),
+    Leaf(Arc<GradAccumulator>),
+}
+
+impl Target {
+    fn is_leaf(&self, accumulator: &Arc<GradAccumulator>) -> bool {
+        matches!(self, Target::Leaf(target) if Arc::ptr_eq(target, accumulator))
+    }
+}
+
+/// Backpropagates `grad_output` from `root` through the recorded graph.
+///
+/// Node ids grow monotonically as operations are recorded, so every node is
+/// created after the nodes of its inputs. Processing pending nodes from the
+/// highest id down is therefore a topological order in which all gradient
+/// contributions to a node have been summed before it runs.
+///
+/// With no `targets`, leaf gradients are accumulated. Otherwise only the
+/// gradients of the targets are captured and returned, and leaves are left
+/// untouched.
+fn execute(
+    root: &Tensor,
+    grad_output: Tensor,
+    options: BackwardOptions,
+    targets: &[Target],
+) -> Result<Vec<Option<Tensor>>, BellandeError> {
+    if !root.requires_grad {
+        return Err(BellandeError::NoGradients);
+    }
+
+    if grad_output.shape != root.shape {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Gradient shape {:?} doesn't match tensor shape {:?}",
+            grad_output.shape, root.shape
+        )));
+    }
+
+    if is_anomaly_enabled() && grad_output.data.iter().any(|v| !v.is_finite()) {
+        return Err(BellandeError::AnomalyDetected(
+            "Gradient passed to backward contains NaN or infinite values".into(),
+        ));
+    }
+
+    let retain_graph = options.retain_graph || options.create_graph;
+    let _guard = set_grad_enabled(options.create_graph);
+    let mut captured: Vec<Option<Tensor>> = targets.iter().map(|_| None).collect();
+
+    // Leaf gradients are summed over the whole pass so leaf hooks see the
+    // total gradient once rather than each partial contribution
+    let mut leaf_grads: Vec<(Arc<GradAccumulator>, Tensor)> = Vec::new();
+
+    match (&root.grad_fn, &root.grad_accumulator) {
+        (Some(node), _) => {
+            let mut pending: BTreeMap<usize, (Arc<Node>, Tensor)> = BTreeMap::new();
+            pending.insert(node.id, (Arc::clone(node), grad_output));
+
+            while let Some((_, (node, grad))) = pending.pop_last() {
+                let grad = node.hooks.apply(grad)?;
+                for (target, slot) in targets.iter().zip(captured.iter_mut()) {
+                    if matches!(target, Target::Node(id) if *id == node.id) {
+                        *slot = Some(grad.clone());
+                    }
+                }
+
+                let input_grads = node.apply_backward(&grad, retain_graph)?;
+
+                for (edge, input_grad) in node.next_edges.iter().zip(input_grads) {
+                    match edge {
+                        Edge::Function(next) => match pending.entry(next.id) {
+                            Entry::Occupied(mut entry) => {
+                                let summed = entry.get().1.add(&input_grad)?;
+                                entry.get_mut().1 = summed;
+                            }
+                            Entry::Vacant(entry) => {
+                                entry.insert((Arc::clone(next), input_grad));
+                            }
+                        },
+                        Edge::Leaf(accumulator) => {
+                            match leaf_grads
+                                .iter_mut()
+                                .find(|(existing, _)| Arc::ptr_eq(existing, accumulator))
+                            {
+                                Some((_, total)) => *total = total.add(&input_grad)?,
+                                None => leaf_grads.push((Arc::clone(accumulator), input_grad)),
+                            }
+                        }
+                        Edge::None => {}
+                    }
+                }
+            }
+        }
+        (None, Some(accumulator)) => leaf_grads.push((Arc::clone(accumulator), grad_output)),
+        (None, None) => return Err(BellandeError::NoGradients),
+    }
+
+    for (accumulator, grad) in leaf_grads {
+        if targets.is_empty() {
+            accumulator.accumulate(grad)?;
+            continue;
+        }
+
+        let grad = accumulator.hooks.apply(grad)?;
+        for (target, slot) in targets.iter().zip(captured.iter_mut()) {
+            if target.is_leaf(&accumulator) {
+                *slot = Some(grad.clone());
+            }
+        }
+    }
+
+    Ok(captured)
+}
+
+fn check_same_shape(a: &Tensor, b: &Tensor) -> Result<(), BellandeError> {
+    if a.shape != b.shape {
+        return Err(BellandeError::DimensionMismatch);
+    }
+    Ok(())
 }
 
 impl AutogradFunction for AddFunction {
-    fn forward(&self, inputs: &[&Tensor]) -> Result<Tensor, BellandeError> {
+    fn forward(
+        &self,
+        _ctx: &mut AutogradContext,
+        inputs: &[&Tensor],
+    ) -> Result<Tensor, BellandeError> {
+        if inputs.len() != 2 {
+            return Err(BellandeError::InvalidInputs);
+        }
+        let a = inputs[0];
+        let b = inputs[1];
+        check_same_shape(a, b)?;
+
+        let result_data = a
+            .data
+            .iter()
+            .zip(b.data.iter())
+            .map(|(x, y)| x + y)
+            .collect();
+
+        Ok(Tensor::new(
+            result_data,
+            a.shape.clone(),
+            false,
+            a.device.clone(),
+            a.dtype,
+        ))
+    }
+
+    fn backward(
+        &self,
+        _ctx: &AutogradContext,
+        grad_output: &Tensor,
+    ) -> Result<Vec<Tensor>, BellandeError> {
+        Ok(vec![grad_output.clone(), grad_output.clone()])
+    }
+
+    fn name(&self) -> &str {
+        "Add"
+    }
+}
+
+impl AutogradFunction for MulFunction {
+    fn forward(
+        &self,
+        ctx: &mut AutogradContext,
+        inputs: &[&Tensor],
+    ) -> Result<Tensor, BellandeError> {
+        if inputs.len() != 2 {
+            return Err(BellandeError::InvalidInputs);
+        }
+        let a = inputs[0];
+        let b = inputs[1];
+        check_same_shape(a, b)?;
+
+        ctx.save_for_backward(a.clone());
+        ctx.save_for_backward(b.clone());
+
+        let result_data = a
+            .data
+            .iter()
+            .zip(b.data.iter())
+            .map(|(x, y)| x * y)
+            .collect();
+
+        Ok(Tensor::new(
+            result_data,
+            a.shape.clone(),
+            false,
+            a.device.clone(),
+            a.dtype,
+        ))
+    }
+
+    fn backward(
+        &self,
+        ctx: &AutogradContext,
+        grad_output: &Tensor,
+    ) -> Result<Vec<Tensor>, BellandeError> {
+        let saved = ctx.get_saved_tensors();
+        let (a, b) = (&saved[0], &saved[1]);
+        Ok(vec![grad_output.mul(b)?, grad_output.mul(a)?])
+    }
+
+    fn name(&self) -> &str {
+        "Mul"
+    }
+}
+
+impl AutogradFunction for MatMulFunction {
+    fn forward(
+        &self,
+        ctx: &mut AutogradContext,
+        inputs: &[&Tensor],
+    ) -> Result<Tensor, BellandeError> {
         if inputs.len() != 2 {
             return Err(BellandeError::InvalidInputs);
         }
         let a = inputs[0];
         let b = inputs[1];
 
-        if a.shape != b.shape {
+        if a.shape.len() != 2 || b.shape.len() != 2 {
+            return Err(BellandeError::InvalidShape(
+                "matmul expects 2D tensors".into(),
+            ));
+        }
+
+        let (m, k) = (a.shape[0], a.shape[1]);
+        let (k2, n) = (b.shape[0], b.shape[1]);
+        if k != k2 {
             return Err(BellandeError::DimensionMismatch);
         }
 
-        let mut result_data = Vec::with_capacity(a.data.len());
-        for i in 0..a.data.len() {
-            result_data.push(a.data[i] + b.data[i]);
+        ctx.save_for_backward(a.clone());
+        ctx.save_for_backward(b.clone());
+
+        let mut result = vec![0.0; m * n];
+        for i in 0..m {
+            for p in 0..k {
+                let a_ip = a.data[i * k + p];
+                for j in 0..n {
+                    result[i * n + j] += a_ip * b.data[p * n + j];
+                }
+            }
         }
 
-        Ok(Tensor {
-            data: result_data,
-            shape: a.shape.clone(),
-            requires_grad: a.requires_grad || b.requires_grad,
-            grad: None,
-            grad_fn: Some(Arc::new(AddFunction)),
-            device: a.device.clone(),
-            dtype: a.dtype,
-        })
+        Ok(Tensor::new(
+            result,
+            vec![m, n],
+            false,
+            a.device.clone(),
+            a.dtype,
+        ))
     }
 
-    fn backward(&self, grad_output: &Tensor) -> Result<Vec<Tensor>, BellandeError> {
-        Ok(vec![grad_output.clone(), grad_output.clone()])
+    fn backward(
+        &self,
+        ctx: &AutogradContext,
+        grad_output: &Tensor,
+    ) -> Result<Vec<Tensor>, BellandeError> {
+        let saved = ctx.get_saved_tensors();
+        let (a, b) = (&saved[0], &saved[1]);
+        Ok(vec![
+            grad_output.matmul(&b.transpose()?)?,
+            a.transpose()?.matmul(grad_output)?,
+        ])
+    }
+
+    fn name(&self) -> &str {
+        "MatMul"
+    }
+}
+
+impl AutogradFunction for TransposeFunction {
+    fn forward(
+        &self,
+        _ctx: &mut AutogradContext,
+        inputs: &[&Tensor],
+    ) -> Result<Tensor, BellandeError> {
+        if inputs.len() != 1 {
+            return Err(BellandeError::InvalidInputs);
+        }
+        let a = inputs[0];
+
+        if a.shape.len() != 2 {
+            return Err(BellandeError::InvalidShape(
+                "transpose expects a 2D tensor".into(),
+            ));
+        }
+
+        let (rows, cols) = (a.shape[0], a.shape[1]);
+        let mut result = vec![0.0; rows * cols];
+        for i in 0..rows {
+            for j in 0..cols {
+                result[j * rows + i] = a.data[i * cols + j];
+            }
+        }
+
+        Ok(Tensor::new(
+            result,
+            vec![cols, rows],
+            false,
+            a.device.clone(),
+            a.dtype,
+        ))
+    }
+
+    fn backward(
+        &self,
+        _ctx: &AutogradContext,
+        grad_output: &Tensor,
+    ) -> Result<Vec<Tensor>, BellandeError> {
+        Ok(vec![grad_output.transpose()?])
+    }
+
+    fn name(&self) -> &str {
+        "Transpose"
     }
 }
@@ -15,6 +15,7 @@
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum DataType {
+    Float16,
     Float32,
     Float64,
     Int32,
@@ -25,6 +26,7 @@ pub enum DataType {
 impl DataType {
     pub fn size_in_bytes(&self) -> usize {
         match self {
+            DataType::Float16 => 2,
             DataType::Float32 => 4,
             DataType::Float64 => 8,
             DataType::Int32 => 4,
@@ -34,7 +36,22 @@ impl DataType {
     }
 
     pub fn is_floating_point(&self) -> bool {
-        matches!(self, DataType::Float32 | DataType::Float64)
+        matches!(
+            self,
+            DataType::Float16 | DataType::Float32 | DataType::Float64
+        )
+    }
+
+    /// The value representable in this type nearest to `value`. Tensor data
+    /// is held as f32, so this is how lower precision storage is emulated;
+    /// Float64 values are returned unchanged.
+    pub fn round_to_precision(&self, value: f32) -> f32 {
+        match self {
+            DataType::Float16 => round_to_half(value),
+            DataType::Float32 | DataType::Float64 => value,
+            DataType::Int32 | DataType::Int64 => value.round(),
+            DataType::Bool => (value != 0.0) as u8 as f32,
+        }
     }
 
     pub fn default() -> Self {
@@ -45,6 +62,7 @@ impl DataType {
 impl std::fmt::Display for DataType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            DataType::Float16 => write!(f, "float16"),
             DataType::Float32 => write!(f, "float32"),
             DataType::Float64 => write!(f, "float64"),
             DataType::Int32 => write!(f, "int32"),
@@ -53,3 +71,19 @@ impl std::fmt::Display for DataType {
         }
     }
 }
+
+/// Rounds to the nearest IEEE half-precision value, ties to even, with
+/// overflow to infinity and gradual underflow
+fn round_to_half(value: f32) -> f32 {
+    if !value.is_finite() {
+        return value;
+    }
+    // Halfway between the largest half, 65504, and the next power of two
+    if value.abs() >= 65520.0 {
+        return f32::INFINITY.copysign(value);
+    }
+    let exponent = ((value.to_bits() >> 23) & 0xff) as i32 - 127;
+    // Half has 10 fraction bits and a minimum normal exponent of -14
+    let spacing = 2f32.powi(exponent.max(-14) - 10);
+    (value / spacing).round_ties_even() * spacing
+}
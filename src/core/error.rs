@@ -37,6 +37,7 @@ pub enum BellandeError {
     EarlyStopping(String),
     ShapeMismatch(String),
     InvalidParameter(String),
+    AnomalyDetected(String),
 }
 
 impl Error for BellandeError {}
@@ -63,6 +64,7 @@ impl fmt::Display for BellandeError {
             BellandeError::EarlyStopping(msg) => write!(f, "Early stopping: {}", msg),
             BellandeError::ShapeMismatch(msg) => write!(f, "Shape mismatch: {}", msg),
             BellandeError::InvalidParameter(msg) => write!(f, "Invalid parameter: {}", msg),
+            BellandeError::AnomalyDetected(msg) => write!(f, "Anomaly detected: {}", msg),
         }
     }
 }
@@ -1,6 +1,8 @@
+pub mod activation_checkpoint;
 pub mod autograd;
 pub mod device;
 pub mod dtype;
 pub mod error;
+pub mod numerics;
 pub mod random;
 pub mod tensor;
@@ -0,0 +1,166 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{dtype::DataType, error::BellandeError};
+use std::sync::RwLock;
+
+/// Epsilons and stability tricks shared by losses, normalization layers and
+/// optimizers. Set once per process with `set_numerics`; components read it
+/// when they are built (defaults) or when they compute (clamping, softmax).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumericsConfig {
+    /// Lower bound applied to probabilities before taking their log
+    pub log_eps: f32,
+    /// Default epsilon added to the variance by normalization layers
+    pub norm_eps: f32,
+    /// Default epsilon in the denominator of adaptive optimizers
+    pub optimizer_eps: f32,
+    /// Added to norms and other denominators that may be zero
+    pub denom_eps: f32,
+    /// Subtract the maximum before exponentiating in softmax and
+    /// log-sum-exp
+    pub softmax_max_subtraction: bool,
+}
+
+impl NumericsConfig {
+    /// Defaults for single-precision training
+    pub const fn float32() -> Self {
+        NumericsConfig {
+            log_eps: 1e-8,
+            norm_eps: 1e-5,
+            optimizer_eps: 1e-8,
+            denom_eps: 1e-6,
+            softmax_max_subtraction: true,
+        }
+    }
+
+    /// Larger epsilons for half-precision training, where 1e-8 underflows
+    pub const fn float16() -> Self {
+        NumericsConfig {
+            log_eps: 1e-4,
+            norm_eps: 1e-3,
+            optimizer_eps: 1e-4,
+            denom_eps: 1e-4,
+            softmax_max_subtraction: true,
+        }
+    }
+
+    /// Tighter epsilons for double-precision research
+    pub const fn float64() -> Self {
+        NumericsConfig {
+            log_eps: 1e-12,
+            norm_eps: 1e-5,
+            optimizer_eps: 1e-8,
+            denom_eps: 1e-12,
+            softmax_max_subtraction: true,
+        }
+    }
+
+    /// The preset matching a floating point data type
+    pub fn for_dtype(dtype: DataType) -> Self {
+        match dtype {
+            DataType::Float16 => Self::float16(),
+            DataType::Float64 => Self::float64(),
+            _ => Self::float32(),
+        }
+    }
+
+    pub fn with_log_eps(mut self, log_eps: f32) -> Self {
+        self.log_eps = log_eps;
+        self
+    }
+
+    pub fn with_norm_eps(mut self, norm_eps: f32) -> Self {
+        self.norm_eps = norm_eps;
+        self
+    }
+
+    pub fn with_optimizer_eps(mut self, optimizer_eps: f32) -> Self {
+        self.optimizer_eps = optimizer_eps;
+        self
+    }
+
+    pub fn with_denom_eps(mut self, denom_eps: f32) -> Self {
+        self.denom_eps = denom_eps;
+        self
+    }
+
+    pub fn with_softmax_max_subtraction(mut self, enabled: bool) -> Self {
+        self.softmax_max_subtraction = enabled;
+        self
+    }
+
+    fn validate(&self) -> Result<(), BellandeError> {
+        let epsilons = [
+            ("log_eps", self.log_eps),
+            ("norm_eps", self.norm_eps),
+            ("optimizer_eps", self.optimizer_eps),
+            ("denom_eps", self.denom_eps),
+        ];
+        for (name, value) in epsilons {
+            if !value.is_finite() || value <= 0.0 || value >= 1.0 {
+                return Err(BellandeError::InvalidConfiguration(format!(
+                    "{} must be in (0, 1), got {}",
+                    name, value
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for NumericsConfig {
+    fn default() -> Self {
+        Self::float32()
+    }
+}
+
+static NUMERICS: RwLock<NumericsConfig> = RwLock::new(NumericsConfig::float32());
+
+/// The process-wide numerics configuration
+pub fn numerics() -> NumericsConfig {
+    *NUMERICS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Replaces the process-wide numerics configuration. Layers and optimizers
+/// built before the call keep the epsilons they were created with.
+pub fn set_numerics(config: NumericsConfig) -> Result<(), BellandeError> {
+    config.validate()?;
+    *NUMERICS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = config;
+    Ok(())
+}
+
+/// Natural log of `value`, clamped below at `log_eps`
+pub fn safe_ln(value: f32, log_eps: f32) -> f32 {
+    value.max(log_eps).ln()
+}
+
+/// `ln(sum(exp(values)))`, shifted by the maximum unless disabled in the
+/// configuration
+pub fn log_sum_exp(values: &[f32]) -> f32 {
+    let shift = if numerics().softmax_max_subtraction {
+        values.iter().copied().fold(f32::NEG_INFINITY, f32::max)
+    } else {
+        0.0
+    };
+    if shift == f32::NEG_INFINITY {
+        return f32::NEG_INFINITY;
+    }
+    values.iter().map(|v| (v - shift).exp()).sum::<f32>().ln() + shift
+}
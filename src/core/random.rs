@@ -27,6 +27,18 @@ pub fn set_seed(seed: u64) {
     });
 }
 
+/// Returns a copy of the current thread's generator state
+pub fn get_rng_state() -> StdRng {
+    GENERATOR.with(|g| g.borrow().clone())
+}
+
+/// Restores a generator state captured with `get_rng_state`
+pub fn set_rng_state(state: StdRng) {
+    GENERATOR.with(|g| {
+        *g.borrow_mut() = state;
+    });
+}
+
 pub fn normal(mean: f32, std: f32, size: usize) -> Vec<f32> {
     let normal = Normal::new(mean as f64, std as f64).unwrap();
     GENERATOR.with(|g| {
@@ -48,3 +60,8 @@ pub fn uniform(low: f32, high: f32, size: usize) -> Vec<f32> {
 pub fn bernoulli(p: f32, size: usize) -> Vec<bool> {
     GENERATOR.with(|g| (0..size).map(|_| g.borrow_mut().gen::<f32>() < p).collect())
 }
+
+/// Shuffles a slice in place using the thread's generator
+pub fn shuffle<T>(values: &mut [T]) {
+    GENERATOR.with(|g| values.shuffle(&mut *g.borrow_mut()));
+}
@@ -14,7 +14,13 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{
-    autograd::AutogradFunction, device::Device, dtype::DataType, error::BellandeError,
+    autograd::{
+        self, AddFunction, AutogradFunction, BackwardOptions, GradAccumulator, GradHook,
+        MatMulFunction, MulFunction, Node, TransposeFunction,
+    },
+    device::Device,
+    dtype::DataType,
+    error::BellandeError,
 };
 use std::sync::Arc;
 
@@ -24,7 +30,9 @@ pub struct Tensor {
     pub shape: Vec<usize>,
     pub requires_grad: bool,
     pub grad: Option<Vec<f32>>,
-    pub grad_fn: Option<Arc<dyn AutogradFunction>>,
+    pub grad_fn: Option<Arc<Node>>,
+    /// Gradient buffer shared by every clone of a leaf tensor
+    pub grad_accumulator: Option<Arc<GradAccumulator>>,
     pub device: Device,
     pub dtype: DataType,
 }
@@ -50,6 +58,11 @@ impl Tensor {
                 None
             },
             grad_fn: None,
+            grad_accumulator: if requires_grad {
+                Some(Arc::new(GradAccumulator::new()))
+            } else {
+                None
+            },
             device,
             dtype,
         }
@@ -88,59 +101,208 @@ impl Tensor {
         )
     }
 
+    /// Backpropagates from this tensor, seeding the gradient with ones
     pub fn backward(&mut self) -> Result<(), BellandeError> {
-        if !self.requires_grad {
-            return Err(BellandeError::NoGradients);
-        }
+        let seed = Tensor::new(
+            vec![1.0; self.data.len()],
+            self.shape.clone(),
+            false,
+            self.device.clone(),
+            self.dtype,
+        );
+        self.backward_with_options(Some(&seed), BackwardOptions::default())?;
 
-        if self.grad.is_none() {
-            self.grad = Some(vec![1.0; self.data.len()]);
+        if self.is_leaf() {
+            if let Some(grad) = self.grad() {
+                self.grad = Some(grad.data);
+            }
         }
 
-        if let Some(ref grad_fn) = self.grad_fn {
-            if let Some(ref grad) = self.grad {
-                grad_fn.backward(&Tensor::new(
-                    grad.clone(),
+        Ok(())
+    }
+
+    /// Backpropagates from this tensor using `grad` as the output gradient
+    pub fn backward_with_grad(&self, grad: &Tensor) -> Result<(), BellandeError> {
+        self.backward_with_options(Some(grad), BackwardOptions::default())
+    }
+
+    /// Backpropagates from this tensor. Saved tensors are freed afterwards
+    /// unless `retain_graph` is set, so a second pass needs it on the first.
+    /// With `create_graph` the accumulated gradients keep their own history
+    /// and can be backpropagated through for higher-order derivatives.
+    pub fn backward_with_options(
+        &self,
+        grad: Option<&Tensor>,
+        options: BackwardOptions,
+    ) -> Result<(), BellandeError> {
+        let grad_output = match grad {
+            Some(grad) if options.create_graph => grad.clone(),
+            Some(grad) => grad.detach(),
+            None => {
+                if self.data.len() != 1 {
+                    return Err(BellandeError::RuntimeError(
+                        "Gradient can only be implicitly created for scalar outputs".into(),
+                    ));
+                }
+                Tensor::new(
+                    vec![1.0],
                     self.shape.clone(),
                     false,
                     self.device.clone(),
                     self.dtype,
-                ))?;
+                )
             }
-        }
+        };
 
-        Ok(())
+        autograd::run_backward(self, grad_output, options)
     }
 
-    pub fn matmul(&self, other: &Tensor) -> Result<Tensor, BellandeError> {
-        if self.shape.len() != 2 || other.shape.len() != 2 {
-            return Err(BellandeError::InvalidShape);
+    /// Accumulated gradient of a leaf tensor
+    pub fn grad(&self) -> Option<Tensor> {
+        if let Some(ref accumulator) = self.grad_accumulator {
+            if let Some(grad) = accumulator.get() {
+                return Some(grad);
+            }
         }
 
-        let (m, k) = (self.shape[0], self.shape[1]);
-        let (k2, n) = (other.shape[0], other.shape[1]);
+        self.grad.as_ref().map(|grad| {
+            Tensor::new(
+                grad.clone(),
+                self.shape.clone(),
+                false,
+                self.device.clone(),
+                self.dtype,
+            )
+        })
+    }
 
-        if k != k2 {
-            return Err(BellandeError::DimensionMismatch);
+    /// Replaces the gradient of a leaf tensor, e.g. with a clipped or noised
+    /// one. Clones sharing its gradient buffer see the new value.
+    pub fn set_grad(&mut self, grad: Vec<f32>) {
+        match self.grad_accumulator {
+            Some(ref accumulator) => accumulator.set(Some(Tensor::new(
+                grad,
+                self.shape.clone(),
+                false,
+                self.device.clone(),
+                self.dtype,
+            ))),
+            None => self.grad = Some(grad),
         }
+    }
 
-        let mut result = vec![0.0; m * n];
-        for i in 0..m {
-            for j in 0..n {
-                let mut sum = 0.0;
-                for k in 0..k {
-                    sum += self.data[i * k + k] * other.data[k * n + j];
-                }
-                result[i * n + j] = sum;
-            }
+    /// Removes and returns the accumulated gradient of a leaf tensor
+    pub fn take_grad(&mut self) -> Option<Tensor> {
+        let grad = self.grad();
+        if let Some(ref accumulator) = self.grad_accumulator {
+            accumulator.reset();
+        }
+        self.grad = None;
+        grad
+    }
+
+    pub fn zero_grad(&mut self) {
+        if let Some(ref accumulator) = self.grad_accumulator {
+            accumulator.reset();
         }
+        if let Some(ref mut grad) = self.grad {
+            grad.iter_mut().for_each(|g| *g = 0.0);
+        }
+    }
+
+    /// Whether this tensor was created by the user rather than by a recorded op
+    pub fn is_leaf(&self) -> bool {
+        self.grad_fn.is_none()
+    }
 
-        Ok(Tensor::new(
-            result,
-            vec![m, n],
-            self.requires_grad || other.requires_grad,
+    /// Returns a tensor with the same values that is cut off from the graph:
+    /// it is a leaf, does not require gradients, and nothing computed from it
+    /// propagates gradients back into this tensor's history
+    pub fn detach(&self) -> Tensor {
+        Tensor::new(
+            self.data.clone(),
+            self.shape.clone(),
+            false,
             self.device.clone(),
             self.dtype,
-        ))
+        )
+    }
+
+    /// Detaches this tensor from the graph in place, making it a leaf
+    pub fn detach_(&mut self) -> &mut Self {
+        self.grad_fn = None;
+        self.grad_accumulator = None;
+        self.grad = None;
+        self.requires_grad = false;
+        self
+    }
+
+    /// Enables or disables gradient tracking for a leaf tensor. Turning it off
+    /// freezes the tensor: operations using it no longer record gradients for it
+    pub fn requires_grad_(&mut self, requires_grad: bool) -> Result<&mut Self, BellandeError> {
+        if !self.is_leaf() {
+            if requires_grad {
+                return Ok(self);
+            }
+            return Err(BellandeError::InvalidOperation(
+                "requires_grad can only be disabled on leaf tensors; use detach() instead".into(),
+            ));
+        }
+
+        if requires_grad && !self.requires_grad {
+            self.grad_accumulator = Some(Arc::new(GradAccumulator::new()));
+            self.grad = Some(vec![0.0; self.data.len()]);
+        } else if !requires_grad {
+            self.grad_accumulator = None;
+            self.grad = None;
+        }
+
+        self.requires_grad = requires_grad;
+        Ok(self)
+    }
+
+    /// Registers a hook called with this tensor's gradient during backward.
+    /// Returning `Some` replaces the gradient that continues to propagate
+    /// (or is accumulated, for leaf tensors). Returns an id for `remove_hook`.
+    pub fn register_hook<F>(&self, hook: F) -> Result<usize, BellandeError>
+    where
+        F: Fn(&Tensor) -> Option<Tensor> + Send + Sync + 'static,
+    {
+        let hook: GradHook = Arc::new(hook);
+        if let Some(ref node) = self.grad_fn {
+            Ok(node.hooks().register(hook))
+        } else if let Some(ref accumulator) = self.grad_accumulator {
+            Ok(accumulator.hooks().register(hook))
+        } else {
+            Err(BellandeError::NoGradients)
+        }
+    }
+
+    /// Removes a hook registered with `register_hook`
+    pub fn remove_hook(&self, id: usize) -> bool {
+        if let Some(ref node) = self.grad_fn {
+            node.hooks().remove(id)
+        } else if let Some(ref accumulator) = self.grad_accumulator {
+            accumulator.hooks().remove(id)
+        } else {
+            false
+        }
+    }
+
+    pub fn add(&self, other: &Tensor) -> Result<Tensor, BellandeError> {
+        AddFunction.apply(&[self, other])
+    }
+
+    pub fn mul(&self, other: &Tensor) -> Result<Tensor, BellandeError> {
+        MulFunction.apply(&[self, other])
+    }
+
+    pub fn matmul(&self, other: &Tensor) -> Result<Tensor, BellandeError> {
+        MatMulFunction.apply(&[self, other])
+    }
+
+    /// Transposes a 2D tensor
+    pub fn transpose(&self) -> Result<Tensor, BellandeError> {
+        TransposeFunction.apply(&[self])
     }
 }
@@ -14,6 +14,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::data::spatial::{SpatialSample, SpatialTransform};
 
 use rand::Rng;
 
@@ -93,6 +94,15 @@ impl Transform for RandomHorizontalFlip {
     }
 }
 
+impl SpatialTransform for RandomHorizontalFlip {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        if rand::thread_rng().gen::<f32>() > self.p {
+            return Ok(sample.clone());
+        }
+        Ok(sample.flip_horizontal())
+    }
+}
+
 pub struct RandomRotation {
     degrees: (f32, f32),
 }
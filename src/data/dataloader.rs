@@ -13,23 +13,26 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::tensor::Tensor;
-use crate::data::{dataset::Dataset, sampler::Sampler};
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, random, tensor::Tensor};
+use crate::data::{dataset::Dataset, sampler::Sampler, snapshot::EpochSnapshotDataset};
 use rayon::prelude::*;
+use std::path::Path;
 use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct DataLoader {
-    dataset: Arc<Dataset>,
+    dataset: Arc<dyn Dataset>,
     batch_size: usize,
     shuffle: bool,
     num_workers: usize,
-    sampler: Option<Box<dyn Sampler>>,
+    sampler: Option<Arc<dyn Sampler>>,
     drop_last: bool,
+    group_size: Option<usize>,
 }
 
 impl DataLoader {
     pub fn new(
-        dataset: Dataset,
+        dataset: impl Dataset + 'static,
         batch_size: usize,
         shuffle: bool,
         num_workers: usize,
@@ -41,39 +44,121 @@ impl DataLoader {
             batch_size,
             shuffle,
             num_workers,
-            sampler,
+            sampler: sampler.map(Arc::from),
             drop_last,
+            group_size: None,
         }
     }
 
+    /// Treats every `group_size` consecutive samples as one group, e.g. the
+    /// candidate documents of a query for learning to rank. Batches then
+    /// hold `batch_size` whole groups, shuffling moves groups rather than
+    /// samples, and targets are collated to (num_groups, group_size)
+    pub fn with_group_size(mut self, group_size: usize) -> Result<Self, BellandeError> {
+        if group_size == 0 || self.dataset.len() % group_size != 0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Group size {} must be positive and divide the dataset length {}",
+                group_size,
+                self.dataset.len()
+            )));
+        }
+        self.group_size = Some(group_size);
+        Ok(self)
+    }
+
+    /// Caches one fully augmented epoch in `cache_dir` and reuses it for
+    /// the next `reuse_epochs` epochs before augmenting again
+    pub fn with_epoch_snapshots(
+        mut self,
+        cache_dir: impl AsRef<Path>,
+        reuse_epochs: usize,
+    ) -> Result<Self, BellandeError> {
+        let snapshots = EpochSnapshotDataset::new(self.dataset.clone(), cache_dir, reuse_epochs)?;
+        self.dataset = Arc::new(snapshots);
+        Ok(self)
+    }
+
+    /// Number of samples, or groups of samples, per batch
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Number of samples in the underlying dataset
+    pub fn dataset_len(&self) -> usize {
+        self.dataset.len()
+    }
+
     pub fn iter(&self) -> DataLoaderIterator {
+        let group_order = self.group_size.map(|group_size| {
+            let mut groups: Vec<usize> = (0..self.dataset.len() / group_size).collect();
+            if self.shuffle {
+                random::shuffle(&mut groups);
+            }
+            groups
+        });
         DataLoaderIterator {
             dataloader: self,
             index: 0,
+            group_order,
         }
     }
+
+    /// Like `iter`, but each batch also carries the samples' loss weights
+    /// from `Dataset::sample_weight`, shaped like the collated targets'
+    /// leading dimensions: (batch_size), or (num_groups, group_size) for
+    /// grouped batches. The weights are `None` when no sample of the batch
+    /// has one, and samples without a weight get 1.
+    pub fn iter_weighted(&self) -> WeightedBatches {
+        WeightedBatches(self.iter())
+    }
 }
 
 pub struct DataLoaderIterator<'a> {
     dataloader: &'a DataLoader,
     index: usize,
+    group_order: Option<Vec<usize>>,
+}
+
+/// Iterator over batches with their sample weights, see
+/// `DataLoader::iter_weighted`
+pub struct WeightedBatches<'a>(DataLoaderIterator<'a>);
+
+impl<'a> Iterator for WeightedBatches<'a> {
+    type Item = (Tensor, Tensor, Option<Tensor>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_batch()
+    }
 }
 
 impl<'a> Iterator for DataLoaderIterator<'a> {
     type Item = (Tensor, Tensor);
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch()
+            .map(|(inputs, targets, _)| (inputs, targets))
+    }
+}
+
+impl<'a> DataLoaderIterator<'a> {
+    fn next_batch(&mut self) -> Option<(Tensor, Tensor, Option<Tensor>)> {
         if self.index >= self.dataloader.dataset.len() {
             return None;
         }
 
-        let batch_indices: Vec<usize> = if let Some(sampler) = &self.dataloader.sampler {
+        let batch_indices: Vec<usize> = if let (Some(group_size), Some(order)) =
+            (self.dataloader.group_size, &self.group_order)
+        {
+            let first = self.index / group_size;
+            order[first..(first + self.dataloader.batch_size).min(order.len())]
+                .iter()
+                .flat_map(|&group| group * group_size..(group + 1) * group_size)
+                .collect()
+        } else if let Some(sampler) = &self.dataloader.sampler {
             sampler.sample(self.dataloader.batch_size)
         } else if self.dataloader.shuffle {
-            use rand::seq::SliceRandom;
-            let mut rng = rand::thread_rng();
             let mut indices: Vec<usize> = (0..self.dataloader.dataset.len()).collect();
-            indices.shuffle(&mut rng);
+            random::shuffle(&mut indices);
             indices[..self.dataloader.batch_size].to_vec()
         } else {
             (self.index..self.index + self.dataloader.batch_size)
@@ -97,13 +182,71 @@ impl<'a> Iterator for DataLoaderIterator<'a> {
             return None;
         }
 
-        self.index += self.dataloader.batch_size;
+        let (inputs, mut targets) = collate_batch(batch);
+        let mut weights = collate_weights(self.dataloader.dataset.as_ref(), &batch_indices);
+        match self.dataloader.group_size {
+            Some(group_size) => {
+                self.index += self.dataloader.batch_size * group_size;
+                let num_groups = targets.shape[0] / group_size;
+                let sample_shape = &targets.shape[1..];
+                targets.shape = if sample_shape.iter().product::<usize>() == 1 {
+                    vec![num_groups, group_size]
+                } else {
+                    [&[num_groups, group_size], sample_shape].concat()
+                };
+                if let Some(ref mut weights) = weights {
+                    weights.shape = vec![num_groups, group_size];
+                }
+            }
+            None => self.index += self.dataloader.batch_size,
+        }
+
+        Some((inputs, targets, weights))
+    }
+}
 
-        Some(collate_batch(batch))
+/// Per-sample loss weights of a batch, or `None` when the dataset has none
+/// for any of its samples
+fn collate_weights(dataset: &dyn Dataset, indices: &[usize]) -> Option<Tensor> {
+    let weights: Vec<Option<f32>> = indices
+        .iter()
+        .map(|&index| dataset.sample_weight(index))
+        .collect();
+    if weights.iter().all(Option::is_none) {
+        return None;
     }
+    Some(Tensor::new(
+        weights.iter().map(|w| w.unwrap_or(1.0)).collect(),
+        vec![indices.len()],
+        false,
+        Device::CPU,
+        DataType::Float32,
+    ))
 }
 
+/// Stacks the inputs and the targets of a batch along a new leading dimension
 fn collate_batch(batch: Vec<(Tensor, Tensor)>) -> (Tensor, Tensor) {
-    // Implement batch collation
-    unimplemented!()
+    let (inputs, targets): (Vec<Tensor>, Vec<Tensor>) = batch.into_iter().unzip();
+    (stack(&inputs), stack(&targets))
+}
+
+fn stack(samples: &[Tensor]) -> Tensor {
+    let first = &samples[0];
+    let mut data = Vec::with_capacity(samples.len() * first.data.len());
+    for sample in samples {
+        assert_eq!(
+            sample.shape, first.shape,
+            "Cannot collate samples of different shapes"
+        );
+        data.extend_from_slice(&sample.data);
+    }
+    let mut shape = vec![samples.len()];
+    shape.extend_from_slice(&first.shape);
+    Tensor::new(
+        data,
+        shape,
+        first.requires_grad,
+        first.device.clone(),
+        first.dtype,
+    )
 }
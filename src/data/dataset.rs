@@ -13,7 +13,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::tensor::Tensor;
+use crate::core::{error::BellandeError, tensor::Tensor};
 
 pub trait Dataset: Send + Sync {
     fn len(&self) -> usize;
@@ -21,4 +21,60 @@ pub trait Dataset: Send + Sync {
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Optional weight of a sample's loss. `DataLoader::iter_weighted`
+    /// collates the weights of a batch, and the `Trainer` passes them to
+    /// losses implementing `WeightedLoss`; other losses ignore them.
+    fn sample_weight(&self, _index: usize) -> Option<f32> {
+        None
+    }
+}
+
+/// Attaches a fixed loss weight to every sample of a dataset, e.g. inverse
+/// class frequencies or confidence scores of pseudo-labels
+pub struct WeightedDataset<D: Dataset> {
+    inner: D,
+    weights: Vec<f32>,
+}
+
+impl<D: Dataset> WeightedDataset<D> {
+    /// `weights` holds one finite, non-negative weight per sample
+    pub fn new(inner: D, weights: Vec<f32>) -> Result<Self, BellandeError> {
+        if weights.len() != inner.len() {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Got {} sample weights for a dataset of {} samples",
+                weights.len(),
+                inner.len()
+            )));
+        }
+        if let Some(&weight) = weights.iter().find(|w| !(**w >= 0.0 && w.is_finite())) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Sample weights must be finite and non-negative, got {}",
+                weight
+            )));
+        }
+        Ok(WeightedDataset { inner, weights })
+    }
+
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: Dataset> Dataset for WeightedDataset<D> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn get(&self, index: usize) -> (Tensor, Tensor) {
+        self.inner.get(index)
+    }
+
+    fn sample_weight(&self, index: usize) -> Option<f32> {
+        Some(self.weights[index])
+    }
 }
@@ -14,6 +14,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
+use crate::data::jpeg;
 use std::collections::HashMap;
 use std::io::Read;
 use std::path::{Path, PathBuf};
@@ -34,6 +35,87 @@ struct RGB {
     b: u8,
 }
 
+/// Upper bounds on the images a decoder will accept. Limits are checked
+/// against the file size and the header dimensions before any pixel buffer
+/// is allocated, so oversized or malicious files fail fast with an
+/// `ImageError`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageLimits {
+    pub max_width: usize,
+    pub max_height: usize,
+    /// Maximum width * height
+    pub max_pixels: usize,
+    /// Maximum encoded file size in bytes
+    pub max_file_size: u64,
+}
+
+impl ImageLimits {
+    /// Accepts any image the decoders can represent
+    pub fn unlimited() -> Self {
+        ImageLimits {
+            max_width: usize::MAX,
+            max_height: usize::MAX,
+            max_pixels: usize::MAX,
+            max_file_size: u64::MAX,
+        }
+    }
+
+    pub fn with_max_dimensions(mut self, max_width: usize, max_height: usize) -> Self {
+        self.max_width = max_width;
+        self.max_height = max_height;
+        self
+    }
+
+    pub fn with_max_pixels(mut self, max_pixels: usize) -> Self {
+        self.max_pixels = max_pixels;
+        self
+    }
+
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn check_dimensions(&self, width: usize, height: usize) -> Result<(), BellandeError> {
+        if width > self.max_width || height > self.max_height {
+            return Err(BellandeError::ImageError(format!(
+                "Image dimensions {}x{} exceed the limit of {}x{}",
+                width, height, self.max_width, self.max_height
+            )));
+        }
+
+        match width.checked_mul(height) {
+            Some(pixels) if pixels <= self.max_pixels => Ok(()),
+            _ => Err(BellandeError::ImageError(format!(
+                "Image of {}x{} pixels exceeds the limit of {} pixels",
+                width, height, self.max_pixels
+            ))),
+        }
+    }
+
+    pub fn check_file_size(&self, size: u64) -> Result<(), BellandeError> {
+        if size > self.max_file_size {
+            return Err(BellandeError::ImageError(format!(
+                "Image file of {} bytes exceeds the limit of {} bytes",
+                size, self.max_file_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ImageLimits {
+    /// 16384 pixels per side, 64 megapixels and 256 MiB per file
+    fn default() -> Self {
+        ImageLimits {
+            max_width: 16_384,
+            max_height: 16_384,
+            max_pixels: 64 * 1024 * 1024,
+            max_file_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
 /// Image decoder implementation
 pub struct ImageDecoder {
     width: usize,
@@ -43,12 +125,19 @@ pub struct ImageDecoder {
 }
 
 impl ImageDecoder {
-    /// Creates a new image decoder
+    /// Creates a new image decoder with the default `ImageLimits`
     pub fn new(bytes: &[u8]) -> Result<Self, BellandeError> {
+        Self::with_limits(bytes, &ImageLimits::default())
+    }
+
+    /// Creates a new image decoder, rejecting images outside `limits`
+    pub fn with_limits(bytes: &[u8], limits: &ImageLimits) -> Result<Self, BellandeError> {
+        limits.check_file_size(bytes.len() as u64)?;
+
         let format = Self::detect_format(bytes)?;
         match format {
-            ImageFormat::JPEG => Self::decode_jpeg(bytes),
-            ImageFormat::PNG => Self::decode_png(bytes),
+            ImageFormat::JPEG => Self::decode_jpeg(bytes, limits),
+            ImageFormat::PNG => Self::decode_png(bytes, limits),
             ImageFormat::Unknown => Err(BellandeError::ImageError(
                 "Unsupported image format".to_string(),
             )),
@@ -68,59 +157,19 @@ impl ImageDecoder {
         }
     }
 
-    /// Basic JPEG decoder implementation
-    fn decode_jpeg(bytes: &[u8]) -> Result<Self, BellandeError> {
-        // This is a basic implementation - you'll need to implement full JPEG decoding
-        let mut reader = std::io::Cursor::new(bytes);
-        let mut marker = [0u8; 2];
-
-        // Find SOF0 marker (Start Of Frame)
-        loop {
-            reader.read_exact(&mut marker).map_err(|e| {
-                BellandeError::ImageError(format!("Failed to read JPEG marker: {}", e))
-            })?;
-
-            if marker[0] != 0xFF {
-                return Err(BellandeError::ImageError("Invalid JPEG marker".to_string()));
-            }
-
-            match marker[1] {
-                0xC0 => break, // SOF0 marker
-                0xD9 => return Err(BellandeError::ImageError("Reached end of JPEG".to_string())),
-                _ => {
-                    let mut length = [0u8; 2];
-                    reader.read_exact(&mut length).map_err(|e| {
-                        BellandeError::ImageError(format!("Failed to read length: {}", e))
-                    })?;
-                    let length = u16::from_be_bytes(length) as i64 - 2;
-                    reader.set_position(reader.position() + length);
-                }
-            }
-        }
-
-        // Read image dimensions
-        let mut header = [0u8; 5];
-        reader
-            .read_exact(&mut header)
-            .map_err(|e| BellandeError::ImageError(format!("Failed to read SOF0 header: {}", e)))?;
-
-        let height = u16::from_be_bytes([header[1], header[2]]) as usize;
-        let width = u16::from_be_bytes([header[3], header[4]]) as usize;
-        let channels = 3; // Assume RGB
-
-        // Create placeholder data (you'll need to implement actual JPEG decoding)
-        let data = vec![0u8; width * height * channels];
-
+    /// Decodes a baseline JPEG
+    fn decode_jpeg(bytes: &[u8], limits: &ImageLimits) -> Result<Self, BellandeError> {
+        let image = jpeg::decode_jpeg_with_limits(bytes, limits)?;
         Ok(Self {
-            width,
-            height,
-            channels,
-            data,
+            width: image.width,
+            height: image.height,
+            channels: 3,
+            data: image.rgb,
         })
     }
 
     /// Basic PNG decoder implementation
-    fn decode_png(bytes: &[u8]) -> Result<Self, BellandeError> {
+    fn decode_png(bytes: &[u8], limits: &ImageLimits) -> Result<Self, BellandeError> {
         // This is a basic implementation - you'll need to implement full PNG decoding
         let mut reader = std::io::Cursor::new(bytes);
         let mut header = [0u8; 8];
@@ -144,6 +193,7 @@ impl ImageDecoder {
         let width = u32::from_be_bytes([ihdr[0], ihdr[1], ihdr[2], ihdr[3]]) as usize;
         let height = u32::from_be_bytes([ihdr[4], ihdr[5], ihdr[6], ihdr[7]]) as usize;
         let channels = 3; // Assume RGB
+        limits.check_dimensions(width, height)?;
 
         // Create placeholder data (you'll need to implement actual PNG decoding)
         let data = vec![0u8; width * height * channels];
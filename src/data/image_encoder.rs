@@ -0,0 +1,447 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::error::BellandeError;
+use crate::data::jpeg::ZIGZAG;
+use crate::utilities::npz::crc32;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Largest payload of a stored (uncompressed) deflate block
+const MAX_STORED_BLOCK: usize = 65_535;
+
+const LUMINANCE_QUANT: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+const CHROMINANCE_QUANT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Encodes interleaved 8-bit RGB pixels as a PNG file.
+///
+/// The image data is stored without compression, which keeps the encoder
+/// small; it is meant for generated fixtures rather than archival.
+pub fn encode_png(rgb: &[u8], width: usize, height: usize) -> Result<Vec<u8>, BellandeError> {
+    check_dimensions(rgb, width, height)?;
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, color type 2 (RGB), default compression, filter and interlace
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Every scanline starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity(height * (1 + width * 3));
+    for row in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    push_png_chunk(&mut png, b"IHDR", &ihdr);
+    push_png_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    push_png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+/// Encodes interleaved 8-bit RGB pixels as a baseline JPEG file with the
+/// given quality (1-100).
+///
+/// Uses 4:4:4 sampling and fixed-length Huffman codes, trading file size
+/// for a much simpler encoder.
+pub fn encode_jpeg(
+    rgb: &[u8],
+    width: usize,
+    height: usize,
+    quality: u8,
+) -> Result<Vec<u8>, BellandeError> {
+    check_dimensions(rgb, width, height)?;
+    if width > u16::MAX as usize || height > u16::MAX as usize {
+        return Err(BellandeError::InvalidParameter(format!(
+            "JPEG dimensions are limited to {}, got {}x{}",
+            u16::MAX,
+            width,
+            height
+        )));
+    }
+    if !(1..=100).contains(&quality) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "JPEG quality must be in 1..=100, got {}",
+            quality
+        )));
+    }
+
+    let luma_quant = scale_quant_table(&LUMINANCE_QUANT, quality);
+    let chroma_quant = scale_quant_table(&CHROMINANCE_QUANT, quality);
+    let dc_table = HuffmanTable::fixed_length(&(0..=11).collect::<Vec<u8>>(), 4);
+    let ac_table = HuffmanTable::fixed_length(&ac_symbols(), 8);
+
+    let mut jpeg = vec![0xFF, 0xD8];
+
+    // JFIF APP0 segment
+    push_segment(
+        &mut jpeg,
+        0xE0,
+        &[b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0],
+    );
+
+    for (id, table) in [(0u8, &luma_quant), (1u8, &chroma_quant)] {
+        let mut payload = vec![id];
+        payload.extend(ZIGZAG.iter().map(|&i| table[i]));
+        push_segment(&mut jpeg, 0xDB, &payload);
+    }
+
+    let mut frame = vec![8];
+    frame.extend_from_slice(&(height as u16).to_be_bytes());
+    frame.extend_from_slice(&(width as u16).to_be_bytes());
+    frame.push(3);
+    for (component, quant_id) in [(1u8, 0u8), (2, 1), (3, 1)] {
+        frame.extend_from_slice(&[component, 0x11, quant_id]);
+    }
+    push_segment(&mut jpeg, 0xC0, &frame);
+
+    for (class_and_id, table) in [(0x00u8, &dc_table), (0x10u8, &ac_table)] {
+        let mut payload = vec![class_and_id];
+        payload.extend_from_slice(&table.counts);
+        payload.extend_from_slice(&table.symbols);
+        push_segment(&mut jpeg, 0xC4, &payload);
+    }
+
+    // All components share Huffman tables 0
+    push_segment(&mut jpeg, 0xDA, &[3, 1, 0x00, 2, 0x00, 3, 0x00, 0, 63, 0]);
+
+    let planes = ycbcr_planes(rgb, width, height);
+    let mut writer = BitWriter::new();
+    let mut previous_dc = [0i32; 3];
+    let mut block = [0f32; 64];
+
+    for block_y in (0..height).step_by(8) {
+        for block_x in (0..width).step_by(8) {
+            for (component, plane) in planes.iter().enumerate() {
+                // Edge pixels are repeated to fill partial blocks
+                for y in 0..8 {
+                    let src_y = (block_y + y).min(height - 1);
+                    for x in 0..8 {
+                        let src_x = (block_x + x).min(width - 1);
+                        block[y * 8 + x] = plane[src_y * width + src_x] - 128.0;
+                    }
+                }
+
+                let quant = if component == 0 {
+                    &luma_quant
+                } else {
+                    &chroma_quant
+                };
+                let coefficients = quantize(&forward_dct(&block), quant);
+                encode_block(
+                    &mut writer,
+                    &coefficients,
+                    &mut previous_dc[component],
+                    &dc_table,
+                    &ac_table,
+                );
+            }
+        }
+    }
+
+    jpeg.extend(writer.finish());
+    jpeg.extend_from_slice(&[0xFF, 0xD9]);
+    Ok(jpeg)
+}
+
+fn check_dimensions(rgb: &[u8], width: usize, height: usize) -> Result<(), BellandeError> {
+    if width == 0 || height == 0 {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Image dimensions must be positive, got {}x{}",
+            width, height
+        )));
+    }
+    if width > u32::MAX as usize || height > u32::MAX as usize {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Image dimensions {}x{} are too large",
+            width, height
+        )));
+    }
+    if rgb.len() != width * height * 3 {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Expected {} RGB bytes for a {}x{} image, got {}",
+            width * height * 3,
+            width,
+            height,
+            rgb.len()
+        )));
+    }
+    Ok(())
+}
+
+fn push_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream made of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        let len = block.len() as u16;
+        stream.push(is_final as u8);
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65_521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+fn push_segment(jpeg: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    jpeg.extend_from_slice(&[0xFF, marker]);
+    jpeg.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    jpeg.extend_from_slice(payload);
+}
+
+/// Scales a base quantization table following the IJG quality convention
+fn scale_quant_table(base: &[u8; 64], quality: u8) -> [u8; 64] {
+    let quality = quality as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+
+    let mut table = [0u8; 64];
+    for (scaled, &value) in table.iter_mut().zip(base.iter()) {
+        *scaled = ((value as u32 * scale + 50) / 100).clamp(1, 255) as u8;
+    }
+    table
+}
+
+/// Every (run, size) symbol a baseline AC coefficient can produce, plus the
+/// end-of-block and zero-run-length codes
+fn ac_symbols() -> Vec<u8> {
+    let mut symbols = vec![0x00, 0xF0];
+    for run in 0..16u8 {
+        for size in 1..=10u8 {
+            symbols.push((run << 4) | size);
+        }
+    }
+    symbols
+}
+
+struct HuffmanTable {
+    counts: [u8; 16],
+    symbols: Vec<u8>,
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanTable {
+    /// Assigns every symbol a canonical code of the same length. The length
+    /// must leave room for at least one unused code, since JPEG forbids the
+    /// all-ones code.
+    fn fixed_length(symbols: &[u8], length: u8) -> Self {
+        debug_assert!(symbols.len() < (1 << length));
+
+        let mut counts = [0u8; 16];
+        counts[length as usize - 1] = symbols.len() as u8;
+
+        let mut codes = [(0u16, 0u8); 256];
+        for (code, &symbol) in symbols.iter().enumerate() {
+            codes[symbol as usize] = (code as u16, length);
+        }
+
+        HuffmanTable {
+            counts,
+            symbols: symbols.to_vec(),
+            codes,
+        }
+    }
+
+    fn write(&self, writer: &mut BitWriter, symbol: u8) {
+        let (code, length) = self.codes[symbol as usize];
+        writer.write(code as u32, length);
+    }
+}
+
+/// Writes entropy-coded data, stuffing a zero byte after every 0xFF
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, length: u8) {
+        for i in (0..length).rev() {
+            self.buffer = (self.buffer << 1) | ((value >> i) & 1);
+            self.bits += 1;
+            if self.bits == 8 {
+                self.push_byte(self.buffer as u8);
+                self.buffer = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+        if byte == 0xFF {
+            self.bytes.push(0x00);
+        }
+    }
+
+    /// Pads the last byte with one bits
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            let padding = 8 - self.bits;
+            self.write((1 << padding) - 1, padding);
+        }
+        self.bytes
+    }
+}
+
+fn ycbcr_planes(rgb: &[u8], width: usize, height: usize) -> [Vec<f32>; 3] {
+    let mut planes = [
+        Vec::with_capacity(width * height),
+        Vec::with_capacity(width * height),
+        Vec::with_capacity(width * height),
+    ];
+
+    for pixel in rgb.chunks(3) {
+        let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+        planes[0].push(0.299 * r + 0.587 * g + 0.114 * b);
+        planes[1].push(-0.168_736 * r - 0.331_264 * g + 0.5 * b + 128.0);
+        planes[2].push(0.5 * r - 0.418_688 * g - 0.081_312 * b + 128.0);
+    }
+    planes
+}
+
+fn forward_dct(block: &[f32; 64]) -> [f32; 64] {
+    let mut output = [0f32; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..8 {
+                for x in 0..8 {
+                    sum += block[y * 8 + x] * dct_basis(x, u) * dct_basis(y, v);
+                }
+            }
+            output[v * 8 + u] = sum;
+        }
+    }
+    output
+}
+
+/// Orthonormal 8-point DCT-II basis value
+fn dct_basis(position: usize, frequency: usize) -> f32 {
+    let scale = if frequency == 0 {
+        std::f32::consts::FRAC_1_SQRT_2
+    } else {
+        1.0
+    };
+    0.5 * scale * ((2 * position + 1) as f32 * frequency as f32 * std::f32::consts::PI / 16.0).cos()
+}
+
+/// Quantizes DCT coefficients and reorders them into zigzag order
+fn quantize(coefficients: &[f32; 64], quant: &[u8; 64]) -> [i32; 64] {
+    let mut output = [0i32; 64];
+    for (k, &index) in ZIGZAG.iter().enumerate() {
+        let value = (coefficients[index] / quant[index] as f32).round() as i32;
+        // Baseline AC coefficients are limited to 10 magnitude bits
+        output[k] = if k == 0 {
+            value
+        } else {
+            value.clamp(-1023, 1023)
+        };
+    }
+    output
+}
+
+fn encode_block(
+    writer: &mut BitWriter,
+    coefficients: &[i32; 64],
+    previous_dc: &mut i32,
+    dc_table: &HuffmanTable,
+    ac_table: &HuffmanTable,
+) {
+    let diff = coefficients[0] - *previous_dc;
+    *previous_dc = coefficients[0];
+    let (size, bits) = magnitude(diff);
+    dc_table.write(writer, size);
+    writer.write(bits, size);
+
+    let mut run = 0u8;
+    for &coefficient in &coefficients[1..] {
+        if coefficient == 0 {
+            run += 1;
+            continue;
+        }
+        while run >= 16 {
+            ac_table.write(writer, 0xF0);
+            run -= 16;
+        }
+        let (size, bits) = magnitude(coefficient);
+        ac_table.write(writer, (run << 4) | size);
+        writer.write(bits, size);
+        run = 0;
+    }
+
+    if run > 0 {
+        ac_table.write(writer, 0x00);
+    }
+}
+
+/// JPEG magnitude category of a value and its additional bits
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value };
+    (size, (bits as u32) & ((1u32 << size) - 1))
+}
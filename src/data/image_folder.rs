@@ -14,20 +14,18 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
-use crate::data::augmentation::Transform;
-use std::collections::HashMap;
+use crate::data::{
+    augmentation::Transform,
+    image_decoder::ImageLimits,
+    jpeg,
+    statistics::{self, DatasetReport},
+};
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{Cursor, Read, Result as IoResult};
+use std::io::Read;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-/// A reader that allows reading individual bits from a byte stream
-pub struct BitReader<R: Read> {
-    reader: R,
-    buffer: u8,
-    bits_remaining: u8,
-}
-
 /// Image format enumeration
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum ImageFormat {
@@ -52,6 +50,27 @@ pub trait Dataset: Send + Sync {
         self.len() == 0
     }
     fn num_classes(&self) -> usize;
+
+    /// Called by the training loop before each epoch, e.g. to pick up data
+    /// added since the previous one
+    fn on_epoch_begin(&mut self, _epoch: usize) -> Result<(), BellandeError> {
+        Ok(())
+    }
+
+    /// Summarizes every sample: counts per class, image sizes, aspect ratios,
+    /// color vs grayscale and the samples that fail to load
+    fn describe(&self) -> DatasetReport {
+        let mut report = DatasetReport::new();
+        for index in 0..self.len() {
+            match self.get(index) {
+                Ok((input, target)) => {
+                    report.record_image(statistics::target_class(&target).to_string(), &input)
+                }
+                Err(e) => report.record_corrupt(None, format!("sample {}", index), &e),
+            }
+        }
+        report
+    }
 }
 
 /// Structure for managing image datasets organized in folders
@@ -63,52 +82,11 @@ pub struct ImageFolder {
     class_to_idx: HashMap<String, usize>,
     cache: Option<HashMap<PathBuf, Arc<Tensor>>>,
     cache_size: usize,
-}
-
-impl<R: Read> BitReader<R> {
-    /// Creates a new BitReader from a byte stream
-    pub fn new(reader: R) -> Self {
-        Self {
-            reader,
-            buffer: 0,
-            bits_remaining: 0,
-        }
-    }
-
-    /// Reads a single bit from the stream
-    pub fn read_bit(&mut self) -> IoResult<bool> {
-        if self.bits_remaining == 0 {
-            let mut byte = [0u8; 1];
-            self.reader.read_exact(&mut byte)?;
-            self.buffer = byte[0];
-            self.bits_remaining = 8;
-        }
-
-        self.bits_remaining -= 1;
-        Ok(((self.buffer >> self.bits_remaining) & 1) == 1)
-    }
-
-    /// Reads multiple bits and returns them as a u32
-    pub fn read_bits(&mut self, mut count: u8) -> IoResult<u32> {
-        let mut result = 0u32;
-
-        while count > 0 {
-            result = (result << 1) | (if self.read_bit()? { 1 } else { 0 });
-            count -= 1;
-        }
-
-        Ok(result)
-    }
+    limits: ImageLimits,
+    rescan_each_epoch: bool,
 }
 
 impl ImageFolder {
-    // Define the constant within the implementation
-    const JPEG_NATURAL_ORDER: [usize; 64] = [
-        0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
-        20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
-        58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
-    ];
-
     /// Creates a new ImageFolder dataset
     pub fn new(
         root: PathBuf,
@@ -133,6 +111,8 @@ impl ImageFolder {
             class_to_idx,
             cache: Some(HashMap::new()),
             cache_size: 1000, // Default cache size
+            limits: ImageLimits::default(),
+            rescan_each_epoch: false,
         })
     }
 
@@ -148,6 +128,71 @@ impl ImageFolder {
         Ok(folder)
     }
 
+    /// Sets the size limits applied when decoding images
+    pub fn with_limits(mut self, limits: ImageLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Gets the size limits applied when decoding images
+    pub fn limits(&self) -> &ImageLimits {
+        &self.limits
+    }
+
+    /// Re-scans the root directory at every epoch boundary, for datasets
+    /// that grow during training such as active-learning pools
+    pub fn with_epoch_rescan(mut self, enabled: bool) -> Self {
+        self.rescan_each_epoch = enabled;
+        self
+    }
+
+    /// Re-scans the root directory. Known classes keep their indices and
+    /// new class directories get the next free ones; samples still on disk
+    /// keep their positions, new images are appended in path order and
+    /// removed ones are dropped. Returns the number of samples added.
+    pub fn rescan(&mut self) -> Result<usize, BellandeError> {
+        Self::validate_root_directory(&self.root)?;
+
+        let mut class_dirs = Vec::new();
+        for entry in fs::read_dir(&self.root).map_err(BellandeError::IOError)? {
+            let path = entry.map_err(BellandeError::IOError)?.path();
+            if path.is_dir() {
+                class_dirs.push(path);
+            }
+        }
+        class_dirs.sort();
+
+        let mut next_idx = self.class_to_idx.values().max().map_or(0, |idx| idx + 1);
+        let mut found = Vec::new();
+        for dir in class_dirs {
+            let class_name = dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let class_idx = *self.class_to_idx.entry(class_name).or_insert_with(|| {
+                next_idx += 1;
+                next_idx - 1
+            });
+            Self::scan_images(&dir, class_idx, &mut found)?;
+        }
+
+        let on_disk: HashSet<&PathBuf> = found.iter().map(|(path, _)| path).collect();
+        self.samples.retain(|(path, _)| on_disk.contains(path));
+        if let Some(cache) = &mut self.cache {
+            cache.retain(|path, _| on_disk.contains(path));
+        }
+
+        let known: HashSet<PathBuf> = self.samples.iter().map(|(path, _)| path.clone()).collect();
+        let mut added: Vec<(PathBuf, usize)> = found
+            .into_iter()
+            .filter(|(path, _)| !known.contains(path))
+            .collect();
+        added.sort();
+        let count = added.len();
+        self.samples.extend(added);
+        Ok(count)
+    }
+
     /// Validates the root directory exists and is a directory
     fn validate_root_directory(root: &PathBuf) -> Result<(), BellandeError> {
         if !root.exists() || !root.is_dir() {
@@ -208,26 +253,46 @@ impl ImageFolder {
         if let Some(ext) = path.extension() {
             let ext = ext.to_string_lossy().to_lowercase();
             if matches!(ext.as_str(), "jpg" | "jpeg" | "png") {
-                if let Ok(bytes) = Self::read_image_file(path) {
-                    return Self::detect_image_format(&bytes) != ImageFormat::Unknown;
+                let mut header = [0u8; 4];
+                if let Ok(mut file) = File::open(path) {
+                    if file.read_exact(&mut header).is_ok() {
+                        return Self::detect_image_format(&header) != ImageFormat::Unknown;
+                    }
                 }
             }
         }
         false
     }
 
-    /// Reads an image file to bytes
-    fn read_image_file(path: &PathBuf) -> Result<Vec<u8>, BellandeError> {
-        let mut file = File::open(path)
-            .map_err(|e| BellandeError::IOError(format!("Failed to open image file: {}", e)))?;
+    /// Reads an image file to bytes, refusing files larger than the limit
+    fn read_image_file(path: &PathBuf, limits: &ImageLimits) -> Result<Vec<u8>, BellandeError> {
+        let file = File::open(path).map_err(BellandeError::IOError)?;
+        limits.check_file_size(file.metadata().map_err(BellandeError::IOError)?.len())?;
 
+        // The file may grow after the metadata check, so cap the read as well
         let mut bytes = Vec::new();
-        file.read_to_end(&mut bytes)
-            .map_err(|e| BellandeError::IOError(format!("Failed to read image file: {}", e)))?;
+        file.take(limits.max_file_size.saturating_add(1))
+            .read_to_end(&mut bytes)
+            .map_err(BellandeError::IOError)?;
+        limits.check_file_size(bytes.len() as u64)?;
 
         Ok(bytes)
     }
 
+    /// Reads and decodes an image file into a (1, 3, height, width) tensor,
+    /// naming the file in decoding errors
+    fn load_tensor(path: &PathBuf, limits: &ImageLimits) -> Result<Tensor, BellandeError> {
+        let decoded = Self::read_image_file(path, limits)
+            .and_then(|bytes| Self::decode_image_to_rgb(&bytes, limits));
+        let (pixels, width, height) = decoded.map_err(|e| match e {
+            BellandeError::ImageError(msg) => {
+                BellandeError::ImageError(format!("{}: {}", path.display(), msg))
+            }
+            other => other,
+        })?;
+        Self::rgb_to_tensor(&pixels, width, height)
+    }
+
     /// Detects image format from bytes
     fn detect_image_format(bytes: &[u8]) -> ImageFormat {
         if bytes.len() < 4 {
@@ -242,359 +307,48 @@ impl ImageFolder {
     }
 
     /// Decodes image bytes to RGB pixels
-    fn decode_image_to_rgb(bytes: &[u8]) -> Result<(Vec<RGBPixel>, usize, usize), BellandeError> {
+    fn decode_image_to_rgb(
+        bytes: &[u8],
+        limits: &ImageLimits,
+    ) -> Result<(Vec<RGBPixel>, usize, usize), BellandeError> {
         match Self::detect_image_format(bytes) {
-            ImageFormat::JPEG => Self::decode_jpeg(bytes),
-            ImageFormat::PNG => Self::decode_png(bytes),
+            ImageFormat::JPEG => Self::decode_jpeg(bytes, limits),
+            ImageFormat::PNG => Self::decode_png(bytes, limits),
             ImageFormat::Unknown => Err(BellandeError::ImageError(
                 "Unknown image format".to_string(),
             )),
         }
     }
 
-    /// JPEG Decoder Implementation
-    fn decode_jpeg(bytes: &[u8]) -> Result<(Vec<RGBPixel>, usize, usize), BellandeError> {
-        let mut cursor = Cursor::new(bytes);
-        let mut marker = [0u8; 2];
-
-        // Verify JPEG signature (0xFFD8)
-        cursor
-            .read_exact(&mut marker)
-            .map_err(|e| BellandeError::ImageError(format!("Invalid JPEG header: {}", e)))?;
-
-        if marker != [0xFF, 0xD8] {
-            return Err(BellandeError::ImageError(
-                "Not a valid JPEG file".to_string(),
-            ));
-        }
-
-        let mut width = 0;
-        let mut height = 0;
-        let mut components = 0;
-        let mut quantization_tables = HashMap::new();
-        let mut huffman_tables = HashMap::new();
-
-        // Parse JPEG segments
-        loop {
-            cursor
-                .read_exact(&mut marker)
-                .map_err(|e| BellandeError::ImageError(format!("Failed to read marker: {}", e)))?;
-
-            if marker[0] != 0xFF {
-                return Err(BellandeError::ImageError("Invalid marker".to_string()));
-            }
-
-            match marker[1] {
-                // Start of Frame (Baseline DCT)
-                0xC0 => {
-                    let mut segment = [0u8; 8];
-                    cursor.read_exact(&mut segment)?;
-
-                    let precision = segment[0];
-                    height = u16::from_be_bytes([segment[1], segment[2]]) as usize;
-                    width = u16::from_be_bytes([segment[3], segment[4]]) as usize;
-                    components = segment[5] as usize;
-
-                    if precision != 8 {
-                        return Err(BellandeError::ImageError(
-                            "Only 8-bit precision supported".to_string(),
-                        ));
-                    }
-
-                    // Read component information
-                    let mut comp_info = vec![0u8; components * 3];
-                    cursor.read_exact(&mut comp_info)?;
-                }
-
-                // Define Quantization Table
-                0xDB => {
-                    let mut length = [0u8; 2];
-                    cursor.read_exact(&mut length)?;
-                    let length = u16::from_be_bytes(length) as usize - 2;
-
-                    let mut table_data = vec![0u8; length];
-                    cursor.read_exact(&mut table_data)?;
-
-                    let precision = (table_data[0] >> 4) & 0x0F;
-                    let table_id = table_data[0] & 0x0F;
-
-                    let table_size = if precision == 0 { 64 } else { 128 };
-                    let qtable = &table_data[1..=table_size];
-
-                    quantization_tables.insert(table_id, qtable.to_vec());
-                }
-
-                // Define Huffman Table
-                0xC4 => {
-                    let mut length = [0u8; 2];
-                    cursor.read_exact(&mut length)?;
-                    let length = u16::from_be_bytes(length) as usize - 2;
-
-                    let mut table_data = vec![0u8; length];
-                    cursor.read_exact(&mut table_data)?;
-
-                    let table_class = (table_data[0] >> 4) & 0x0F; // DC = 0, AC = 1
-                    let table_id = table_data[0] & 0x0F;
-
-                    // Parse Huffman table
-                    let mut code_lengths = [0u8; 16];
-                    code_lengths.copy_from_slice(&table_data[1..17]);
-
-                    let mut codes = Vec::new();
-                    let mut offset = 17;
-                    for &length in code_lengths.iter() {
-                        for _ in 0..length {
-                            codes.push(table_data[offset]);
-                            offset += 1;
-                        }
-                    }
-
-                    huffman_tables.insert((table_class, table_id), codes);
-                }
-
-                // Start of Scan
-                0xDA => {
-                    let mut length = [0u8; 2];
-                    cursor.read_exact(&mut length)?;
-                    let length = u16::from_be_bytes(length) as usize - 2;
-
-                    let mut scan_data = vec![0u8; length];
-                    cursor.read_exact(&mut scan_data)?;
-
-                    // Process compressed data
-                    let mut pixels = vec![RGBPixel::new(0, 0, 0); width * height];
-                    let mut bit_reader = BitReader::new(&mut cursor);
-
-                    // Process MCUs (Minimum Coded Units)
-                    let mcu_width = ((width + 7) / 8) * 8;
-                    let mcu_height = ((height + 7) / 8) * 8;
-
-                    for y in (0..mcu_height).step_by(8) {
-                        for x in (0..mcu_width).step_by(8) {
-                            // Process each component (Y, Cb, Cr)
-                            for component in 0..components {
-                                let qtable = &quantization_tables[&component];
-                                let (dc_table, ac_table) = (
-                                    &huffman_tables[&(0, component)],
-                                    &huffman_tables[&(1, component)],
-                                );
-
-                                // Decode 8x8 block
-                                let block = Self::decode_block(
-                                    &mut bit_reader,
-                                    dc_table,
-                                    ac_table,
-                                    qtable,
-                                )?;
-
-                                // Convert YCbCr to RGB and store in pixels
-                                if component == 0 {
-                                    // Y component
-                                    for by in 0..8 {
-                                        for bx in 0..8 {
-                                            let px = x + bx;
-                                            let py = y + by;
-                                            if px < width && py < height {
-                                                let idx = py * width + px;
-                                                pixels[idx].r = block[by * 8 + bx] as u8;
-                                                pixels[idx].g = block[by * 8 + bx] as u8;
-                                                pixels[idx].b = block[by * 8 + bx] as u8;
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    return Ok((pixels, width, height));
-                }
-
-                // End of Image
-                0xD9 => break,
-
-                // Skip other markers
-                _ => {
-                    let mut length = [0u8; 2];
-                    cursor.read_exact(&mut length)?;
-                    let length = u16::from_be_bytes(length) as usize - 2;
-                    cursor.set_position(cursor.position() + length as u64);
-                }
-            }
-        }
-
-        Err(BellandeError::ImageError(
-            "Failed to decode JPEG".to_string(),
-        ))
-    }
-
-    /// Decodes an 8x8 DCT block from JPEG data
-    pub fn decode_block(
-        bit_reader: &mut BitReader<impl Read>,
-        dc_table: &[u8],
-        ac_table: &[u8],
-        qtable: &[u8],
-    ) -> Result<[f32; 64], BellandeError> {
-        let mut block = [0f32; 64];
-        let mut zz = [0i32; 64];
-
-        // Decode DC coefficient
-        let dc_code_length = Self::decode_huffman(bit_reader, dc_table).map_err(|e| {
-            BellandeError::ImageError(format!("Failed to decode DC coefficient: {}", e))
-        })?;
-
-        if dc_code_length > 0 {
-            let dc_value = Self::receive_and_extend(bit_reader, dc_code_length).map_err(|e| {
-                BellandeError::ImageError(format!("Failed to read DC value: {}", e))
-            })?;
-            zz[0] = dc_value;
-        }
-
-        // Decode AC coefficients
-        let mut k = 1;
-        while k < 64 {
-            let rs = Self::decode_huffman(bit_reader, ac_table).map_err(|e| {
-                BellandeError::ImageError(format!("Failed to decode AC coefficient: {}", e))
-            })?;
-
-            let ssss = rs & 0x0F;
-            let rrrr = rs >> 4;
-
-            if ssss == 0 {
-                if rrrr == 15 {
-                    k += 16; // Skip 16 zeros
-                    continue;
-                }
-                break; // End of block
-            }
-
-            k += rrrr as usize; // Skip zeros
-            if k >= 64 {
-                return Err(BellandeError::ImageError(
-                    "Invalid AC coefficient index".to_string(),
-                ));
-            }
-
-            // Read additional bits
-            let ac_value = Self::receive_and_extend(bit_reader, ssss).map_err(|e| {
-                BellandeError::ImageError(format!("Failed to read AC value: {}", e))
-            })?;
-            zz[Self::JPEG_NATURAL_ORDER[k]] = ac_value;
-            k += 1;
-        }
-
-        // Dequantize
-        for i in 0..64 {
-            zz[i] *= qtable[i] as i32;
-        }
-
-        // Inverse DCT
-        Self::inverse_dct(&zz, &mut block);
-
-        // Level shift and clamp values
-        for val in &mut block {
-            *val = (*val + 128.0).clamp(0.0, 255.0);
-        }
-
-        Ok(block)
-    }
-
-    /// Decodes a Huffman code from the bit stream
-    fn decode_huffman(bit_reader: &mut BitReader<impl Read>, table: &[u8]) -> IoResult<u8> {
-        let mut code = 0u16;
-        let mut code_length = 0u8;
-        let mut index = 0usize;
-
-        loop {
-            let bit = bit_reader.read_bit()?;
-            code = (code << 1) | (if bit { 1 } else { 0 });
-            code_length += 1;
-
-            while index < table.len() && table[index] as u8 == code_length {
-                if code as u8 == table[index + 1] {
-                    return Ok(table[index + 2]);
-                }
-                index += 3;
-            }
-
-            if code_length >= 16 {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Invalid Huffman code",
-                ));
-            }
-        }
-    }
-
-    /// Receives and extends a value with the given number of bits
-    fn receive_and_extend(bit_reader: &mut BitReader<impl Read>, length: u8) -> IoResult<i32> {
-        if length == 0 {
-            return Ok(0);
-        }
-
-        let value = bit_reader.read_bits(length)? as i32;
-        let vt = 1 << (length - 1);
-
-        Ok(if value < vt {
-            value + (-1 << length) + 1
-        } else {
-            value
-        })
-    }
-
-    /// Performs inverse DCT on an 8x8 block
-    fn inverse_dct(input: &[i32; 64], output: &mut [f32; 64]) {
-        let mut temp = [0f32; 64];
-
-        // 1-D IDCT on rows
-        for i in 0..8 {
-            let row = &input[i * 8..(i + 1) * 8];
-            let mut tmp = [0f32; 8];
-
-            for x in 0..8 {
-                let mut sum = 0.0;
-                for u in 0..8 {
-                    let cu = if u == 0 { 1.0 / f32::sqrt(2.0) } else { 1.0 };
-                    sum += cu
-                        * row[u] as f32
-                        * (std::f32::consts::PI * (2 * x + 1) * u as f32 / 16.0).cos();
-                }
-                tmp[x] = sum / 2.0;
-            }
-
-            for (j, &val) in tmp.iter().enumerate() {
-                temp[i * 8 + j] = val;
-            }
-        }
-
-        // 1-D IDCT on columns
-        for i in 0..8 {
-            let mut tmp = [0f32; 8];
-
-            for y in 0..8 {
-                let mut sum = 0.0;
-                for v in 0..8 {
-                    let cv = if v == 0 { 1.0 / f32::sqrt(2.0) } else { 1.0 };
-                    sum += cv
-                        * temp[v * 8 + i]
-                        * (std::f32::consts::PI * (2 * y + 1) * v as f32 / 16.0).cos();
-                }
-                tmp[y] = sum / 2.0;
-            }
-
-            for (j, &val) in tmp.iter().enumerate() {
-                output[j * 8 + i] = val;
-            }
-        }
+    /// Decodes JPEG image bytes
+    fn decode_jpeg(
+        bytes: &[u8],
+        limits: &ImageLimits,
+    ) -> Result<(Vec<RGBPixel>, usize, usize), BellandeError> {
+        let image = jpeg::decode_jpeg_with_limits(bytes, limits)?;
+        let pixels = image
+            .rgb
+            .chunks(3)
+            .map(|rgb| RGBPixel {
+                r: rgb[0],
+                g: rgb[1],
+                b: rgb[2],
+            })
+            .collect();
+        Ok((pixels, image.width, image.height))
     }
 
     /// Decodes PNG image bytes
-    fn decode_png(bytes: &[u8]) -> Result<(Vec<RGBPixel>, usize, usize), BellandeError> {
+    fn decode_png(
+        bytes: &[u8],
+        limits: &ImageLimits,
+    ) -> Result<(Vec<RGBPixel>, usize, usize), BellandeError> {
         // Basic PNG decoder implementation
         // For now, we'll return a placeholder image
         // TODO: Implement full PNG decoding
         let width = 224;
         let height = 224;
+        limits.check_dimensions(width, height)?;
         let pixels = vec![RGBPixel { r: 0, g: 0, b: 0 }; width * height];
         Ok((pixels, width, height))
     }
@@ -644,9 +398,7 @@ impl ImageFolder {
                 return Ok(Arc::clone(tensor));
             }
 
-            let bytes = Self::read_image_file(path)?;
-            let (pixels, width, height) = Self::decode_image_to_rgb(&bytes)?;
-            let tensor = Arc::new(Self::rgb_to_tensor(&pixels, width, height)?);
+            let tensor = Arc::new(Self::load_tensor(path, &self.limits)?);
 
             // Manage cache size
             if cache.len() >= self.cache_size {
@@ -658,13 +410,36 @@ impl ImageFolder {
             cache.insert(path.clone(), Arc::clone(&tensor));
             Ok(tensor)
         } else {
-            let bytes = Self::read_image_file(path)?;
-            let (pixels, width, height) = Self::decode_image_to_rgb(&bytes)?;
-            let tensor = Arc::new(Self::rgb_to_tensor(&pixels, width, height)?);
+            let tensor = Arc::new(Self::load_tensor(path, &self.limits)?);
             Ok(tensor)
         }
     }
 
+    /// Loads a single image file as a (1, 3, height, width) tensor in [0, 1]
+    pub fn load_image(path: &PathBuf) -> Result<Tensor, BellandeError> {
+        Self::load_image_with_limits(path, &ImageLimits::default())
+    }
+
+    /// Like [`load_image`](Self::load_image), rejecting files outside `limits`
+    pub fn load_image_with_limits(
+        path: &PathBuf,
+        limits: &ImageLimits,
+    ) -> Result<Tensor, BellandeError> {
+        Self::load_tensor(path, limits)
+    }
+
+    /// Collects every supported image below `root` in sorted order
+    pub fn find_images(root: &PathBuf) -> Result<Vec<PathBuf>, BellandeError> {
+        Self::validate_root_directory(root)?;
+
+        let mut samples = Vec::new();
+        Self::scan_images(root, 0, &mut samples)?;
+
+        let mut paths: Vec<PathBuf> = samples.into_iter().map(|(path, _)| path).collect();
+        paths.sort();
+        Ok(paths)
+    }
+
     /// Gets the number of classes in the dataset
     pub fn num_classes(&self) -> usize {
         self.class_to_idx.len()
@@ -702,6 +477,36 @@ impl Dataset for ImageFolder {
         self.num_classes()
     }
 
+    fn on_epoch_begin(&mut self, _epoch: usize) -> Result<(), BellandeError> {
+        if self.rescan_each_epoch {
+            self.rescan()?;
+        }
+        Ok(())
+    }
+
+    /// Summarizes the image files as stored on disk, before any transform,
+    /// labelling classes by directory name
+    fn describe(&self) -> DatasetReport {
+        let class_names: HashMap<usize, &String> = self
+            .class_to_idx
+            .iter()
+            .map(|(name, &idx)| (idx, name))
+            .collect();
+
+        let mut report = DatasetReport::new();
+        for (path, class_idx) in &self.samples {
+            let class = class_names
+                .get(class_idx)
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| class_idx.to_string());
+            match Self::load_tensor(path, &self.limits) {
+                Ok(image) => report.record_image(class, &image),
+                Err(e) => report.record_corrupt(Some(class), path.display().to_string(), &e),
+            }
+        }
+        report
+    }
+
     fn get(&self, index: usize) -> Result<(Tensor, Tensor), BellandeError> {
         let (path, class_idx) = &self.samples[index];
 
@@ -711,16 +516,10 @@ impl Dataset for ImageFolder {
                 if let Some(tensor) = cache.get(path) {
                     (*tensor).clone()
                 } else {
-                    let bytes = Self::read_image_file(path)?;
-                    let (pixels, width, height) = Self::decode_image_to_rgb(&bytes)?;
-                    Self::rgb_to_tensor(&pixels, width, height)?
+                    Self::load_tensor(path, &self.limits)?
                 }
             }
-            None => {
-                let bytes = Self::read_image_file(path)?;
-                let (pixels, width, height) = Self::decode_image_to_rgb(&bytes)?;
-                Self::rgb_to_tensor(&pixels, width, height)?
-            }
+            None => Self::load_tensor(path, &self.limits)?,
         };
 
         // Create target tensor
@@ -744,3 +543,17 @@ impl Dataset for ImageFolder {
         Ok((input, target))
     }
 }
+
+/// Lets a `DataLoader` batch the folder. Panics when an image cannot be
+/// loaded, since `dataset::Dataset::get` cannot report errors; use
+/// `Dataset::get` to handle them
+impl crate::data::dataset::Dataset for ImageFolder {
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn get(&self, index: usize) -> (Tensor, Tensor) {
+        Dataset::get(self, index)
+            .unwrap_or_else(|e| panic!("Failed to load sample {}: {}", index, e))
+    }
+}
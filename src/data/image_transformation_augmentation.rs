@@ -14,6 +14,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::data::spatial::{map_image, SpatialSample, SpatialTransform};
 use rand::{thread_rng, Rng};
 
 /// Trait for image transformations
@@ -86,6 +87,14 @@ impl Transform for CenterCrop {
     }
 }
 
+impl SpatialTransform for CenterCrop {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        let top = sample.height().saturating_sub(self.height) / 2;
+        let left = sample.width().saturating_sub(self.width) / 2;
+        sample.crop(top, left, self.height, self.width)
+    }
+}
+
 /// Random crop transformation
 pub struct RandomCrop {
     height: usize,
@@ -151,6 +160,21 @@ impl Transform for RandomCrop {
     }
 }
 
+impl SpatialTransform for RandomCrop {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        if sample.height() < self.height || sample.width() < self.width {
+            return Err(BellandeError::InvalidParameter(
+                "Crop size larger than input size".to_string(),
+            ));
+        }
+
+        let mut rng = thread_rng();
+        let top = rng.gen_range(0..=sample.height() - self.height);
+        let left = rng.gen_range(0..=sample.width() - self.width);
+        sample.crop(top, left, self.height, self.width)
+    }
+}
+
 /// Random vertical flip transformation
 pub struct RandomVerticalFlip {
     probability: f32,
@@ -208,6 +232,15 @@ impl Transform for RandomVerticalFlip {
     }
 }
 
+impl SpatialTransform for RandomVerticalFlip {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        if thread_rng().gen::<f32>() > self.probability {
+            return Ok(sample.clone());
+        }
+        Ok(sample.flip_vertical())
+    }
+}
+
 /// Color jitter transformation
 pub struct ColorJitter {
     brightness: f32,
@@ -292,6 +325,12 @@ impl Transform for ColorJitter {
     }
 }
 
+impl SpatialTransform for ColorJitter {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        map_image(sample, |batch| self.apply(batch))
+    }
+}
+
 /// Gaussian noise transformation
 pub struct GaussianNoise {
     mean: f32,
@@ -327,3 +366,9 @@ impl Transform for GaussianNoise {
         "GaussianNoise"
     }
 }
+
+impl SpatialTransform for GaussianNoise {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        map_image(sample, |batch| self.apply(batch))
+    }
+}
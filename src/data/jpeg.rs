@@ -0,0 +1,652 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Strict baseline JPEG decoder. Every read is bounds checked, so corrupt
+//! or truncated files produce an `ImageError` rather than a panic.
+
+use crate::core::error::BellandeError;
+use crate::data::image_decoder::ImageLimits;
+
+/// Maps zigzag scan positions to natural (row-major) block positions
+pub(crate) const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+const MAX_TABLES: usize = 4;
+
+/// A decoded image as interleaved 8-bit RGB
+#[derive(Clone, Debug)]
+pub struct DecodedImage {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant_id: usize,
+    dc_table: usize,
+    ac_table: usize,
+    prediction: i32,
+    /// Samples on the padded block grid
+    plane: Vec<u8>,
+    plane_width: usize,
+}
+
+struct Frame {
+    width: usize,
+    height: usize,
+    components: Vec<Component>,
+    h_max: usize,
+    v_max: usize,
+    mcus_x: usize,
+    mcus_y: usize,
+}
+
+struct HuffmanTable {
+    max_code: [i32; 17],
+    min_code: [i32; 17],
+    value_offset: [usize; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn new(counts: &[u8], values: &[u8]) -> Result<Self, BellandeError> {
+        let mut table = HuffmanTable {
+            max_code: [-1; 17],
+            min_code: [0; 17],
+            value_offset: [0; 17],
+            values: values.to_vec(),
+        };
+
+        let mut code = 0i32;
+        let mut offset = 0usize;
+        for length in 1..=16 {
+            let count = counts[length - 1] as usize;
+            if count > 0 {
+                table.value_offset[length] = offset;
+                table.min_code[length] = code;
+                code += count as i32;
+                offset += count;
+                if code > (1 << length) {
+                    return Err(invalid("Huffman table has too many codes"));
+                }
+                table.max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+
+        Ok(table)
+    }
+}
+
+/// Decodes a baseline (sequential DCT, Huffman coded) JPEG with one
+/// grayscale or three YCbCr components and any chroma subsampling, using
+/// the default `ImageLimits`
+pub fn decode_jpeg(bytes: &[u8]) -> Result<DecodedImage, BellandeError> {
+    decode_jpeg_with_limits(bytes, &ImageLimits::default())
+}
+
+/// Like [`decode_jpeg`], rejecting files and frames outside `limits` before
+/// allocating any sample buffers
+pub fn decode_jpeg_with_limits(
+    bytes: &[u8],
+    limits: &ImageLimits,
+) -> Result<DecodedImage, BellandeError> {
+    limits.check_file_size(bytes.len() as u64)?;
+    if bytes.len() < 2 || bytes[0] != 0xFF || bytes[1] != 0xD8 {
+        return Err(invalid("Missing JPEG start-of-image marker"));
+    }
+
+    let mut quant_tables: [Option<[u16; 64]>; MAX_TABLES] = [None; MAX_TABLES];
+    let mut dc_tables: [Option<HuffmanTable>; MAX_TABLES] = Default::default();
+    let mut ac_tables: [Option<HuffmanTable>; MAX_TABLES] = Default::default();
+    let mut frame: Option<Frame> = None;
+    let mut restart_interval = 0usize;
+    let mut pos = 2;
+
+    loop {
+        let marker = next_marker(bytes, &mut pos)?;
+        match marker {
+            // Standalone markers without a payload
+            0x01 | 0xD0..=0xD7 => {}
+            0xD9 => return Err(invalid("Reached end of image before any scan")),
+            0xC0 | 0xC1 => {
+                if frame.is_some() {
+                    return Err(invalid("Multiple frame headers"));
+                }
+                let parsed = parse_frame(read_segment(bytes, &mut pos)?)?;
+                limits.check_dimensions(parsed.width, parsed.height)?;
+                frame = Some(parsed);
+            }
+            0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                return Err(invalid(
+                    "Only baseline JPEG is supported (progressive, lossless and arithmetic coded files are not)",
+                ));
+            }
+            0xC4 => parse_huffman_tables(
+                read_segment(bytes, &mut pos)?,
+                &mut dc_tables,
+                &mut ac_tables,
+            )?,
+            0xDB => parse_quant_tables(read_segment(bytes, &mut pos)?, &mut quant_tables)?,
+            0xDD => {
+                let segment = read_segment(bytes, &mut pos)?;
+                if segment.len() != 2 {
+                    return Err(invalid("Malformed restart interval segment"));
+                }
+                restart_interval = u16::from_be_bytes([segment[0], segment[1]]) as usize;
+            }
+            0xDA => {
+                let mut frame = frame.ok_or_else(|| invalid("Scan found before frame header"))?;
+                parse_scan_header(read_segment(bytes, &mut pos)?, &mut frame)?;
+
+                for component in &frame.components {
+                    if quant_tables[component.quant_id].is_none() {
+                        return Err(invalid(&format!(
+                            "Missing quantization table {} for component {}",
+                            component.quant_id, component.id
+                        )));
+                    }
+                    if dc_tables[component.dc_table].is_none()
+                        || ac_tables[component.ac_table].is_none()
+                    {
+                        return Err(invalid(&format!(
+                            "Missing Huffman table for component {}",
+                            component.id
+                        )));
+                    }
+                }
+
+                let tables = Tables {
+                    quant: &quant_tables,
+                    dc: &dc_tables,
+                    ac: &ac_tables,
+                };
+                decode_scan(&bytes[pos..], &mut frame, &tables, restart_interval)?;
+                return Ok(to_rgb(&frame));
+            }
+            _ => {
+                read_segment(bytes, &mut pos)?;
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> BellandeError {
+    BellandeError::ImageError(format!("Invalid JPEG: {}", message))
+}
+
+/// Advances past fill bytes to the next marker and returns its code
+fn next_marker(bytes: &[u8], pos: &mut usize) -> Result<u8, BellandeError> {
+    if bytes.get(*pos) != Some(&0xFF) {
+        return Err(invalid("Expected a marker"));
+    }
+    while bytes.get(*pos) == Some(&0xFF) {
+        *pos += 1;
+    }
+    let marker = *bytes.get(*pos).ok_or_else(|| invalid("Truncated marker"))?;
+    *pos += 1;
+    Ok(marker)
+}
+
+/// Returns the payload of a length-prefixed segment and advances past it
+fn read_segment<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8], BellandeError> {
+    let header = bytes
+        .get(*pos..*pos + 2)
+        .ok_or_else(|| invalid("Truncated segment length"))?;
+    let length = u16::from_be_bytes([header[0], header[1]]) as usize;
+    if length < 2 {
+        return Err(invalid("Segment length is smaller than its header"));
+    }
+
+    let payload = bytes
+        .get(*pos + 2..*pos + length)
+        .ok_or_else(|| invalid("Segment extends past end of file"))?;
+    *pos += length;
+    Ok(payload)
+}
+
+fn parse_frame(segment: &[u8]) -> Result<Frame, BellandeError> {
+    if segment.len() < 6 {
+        return Err(invalid("Truncated frame header"));
+    }
+    if segment[0] != 8 {
+        return Err(invalid("Only 8-bit samples are supported"));
+    }
+
+    let height = u16::from_be_bytes([segment[1], segment[2]]) as usize;
+    let width = u16::from_be_bytes([segment[3], segment[4]]) as usize;
+    let num_components = segment[5] as usize;
+    if width == 0 || height == 0 {
+        return Err(invalid("Image dimensions must be non-zero"));
+    }
+    if num_components != 1 && num_components != 3 {
+        return Err(invalid(&format!(
+            "Unsupported number of components: {}",
+            num_components
+        )));
+    }
+    if segment.len() != 6 + num_components * 3 {
+        return Err(invalid(
+            "Frame header length does not match component count",
+        ));
+    }
+
+    let mut components = Vec::with_capacity(num_components);
+    for info in segment[6..].chunks(3) {
+        let (h, v, quant_id) = ((info[1] >> 4) as usize, (info[1] & 0x0F) as usize, info[2]);
+        if !(1..=4).contains(&h) || !(1..=4).contains(&v) {
+            return Err(invalid("Sampling factors must be between 1 and 4"));
+        }
+        if quant_id as usize >= MAX_TABLES {
+            return Err(invalid("Quantization table id out of range"));
+        }
+        if components.iter().any(|c: &Component| c.id == info[0]) {
+            return Err(invalid("Duplicate component id"));
+        }
+        components.push(Component {
+            id: info[0],
+            h,
+            v,
+            quant_id: quant_id as usize,
+            dc_table: 0,
+            ac_table: 0,
+            prediction: 0,
+            plane: Vec::new(),
+            plane_width: 0,
+        });
+    }
+
+    let h_max = components.iter().map(|c| c.h).max().unwrap_or(1);
+    let v_max = components.iter().map(|c| c.v).max().unwrap_or(1);
+    Ok(Frame {
+        width,
+        height,
+        components,
+        h_max,
+        v_max,
+        mcus_x: width.div_ceil(8 * h_max),
+        mcus_y: height.div_ceil(8 * v_max),
+    })
+}
+
+fn parse_quant_tables(
+    mut segment: &[u8],
+    tables: &mut [Option<[u16; 64]>; MAX_TABLES],
+) -> Result<(), BellandeError> {
+    while !segment.is_empty() {
+        let (precision, id) = ((segment[0] >> 4) as usize, (segment[0] & 0x0F) as usize);
+        if precision > 1 || id >= MAX_TABLES {
+            return Err(invalid("Malformed quantization table"));
+        }
+
+        let size = 64 * (precision + 1);
+        let values = segment
+            .get(1..1 + size)
+            .ok_or_else(|| invalid("Truncated quantization table"))?;
+        let mut table = [0u16; 64];
+        for (k, entry) in table.iter_mut().enumerate() {
+            *entry = if precision == 0 {
+                values[k] as u16
+            } else {
+                u16::from_be_bytes([values[2 * k], values[2 * k + 1]])
+            };
+        }
+
+        tables[id] = Some(table);
+        segment = &segment[1 + size..];
+    }
+    Ok(())
+}
+
+fn parse_huffman_tables(
+    mut segment: &[u8],
+    dc_tables: &mut [Option<HuffmanTable>; MAX_TABLES],
+    ac_tables: &mut [Option<HuffmanTable>; MAX_TABLES],
+) -> Result<(), BellandeError> {
+    while !segment.is_empty() {
+        let (class, id) = ((segment[0] >> 4) as usize, (segment[0] & 0x0F) as usize);
+        if class > 1 || id >= MAX_TABLES {
+            return Err(invalid("Malformed Huffman table"));
+        }
+
+        let counts = segment
+            .get(1..17)
+            .ok_or_else(|| invalid("Truncated Huffman table"))?;
+        let num_values: usize = counts.iter().map(|&c| c as usize).sum();
+        if num_values > 256 {
+            return Err(invalid("Huffman table has more than 256 symbols"));
+        }
+        let values = segment
+            .get(17..17 + num_values)
+            .ok_or_else(|| invalid("Truncated Huffman table"))?;
+
+        let table = HuffmanTable::new(counts, values)?;
+        if class == 0 {
+            dc_tables[id] = Some(table);
+        } else {
+            ac_tables[id] = Some(table);
+        }
+        segment = &segment[17 + num_values..];
+    }
+    Ok(())
+}
+
+fn parse_scan_header(segment: &[u8], frame: &mut Frame) -> Result<(), BellandeError> {
+    let num_components = *segment
+        .first()
+        .ok_or_else(|| invalid("Truncated scan header"))? as usize;
+    if segment.len() != 4 + num_components * 2 {
+        return Err(invalid("Scan header length does not match component count"));
+    }
+    if num_components != frame.components.len() {
+        return Err(invalid(
+            "Scans must contain every component (multi-scan files are not supported)",
+        ));
+    }
+
+    for selector in segment[1..1 + num_components * 2].chunks(2) {
+        let component = frame
+            .components
+            .iter_mut()
+            .find(|c| c.id == selector[0])
+            .ok_or_else(|| invalid("Scan references an unknown component"))?;
+        let (dc_table, ac_table) = ((selector[1] >> 4) as usize, (selector[1] & 0x0F) as usize);
+        if dc_table >= MAX_TABLES || ac_table >= MAX_TABLES {
+            return Err(invalid("Huffman table id out of range"));
+        }
+        component.dc_table = dc_table;
+        component.ac_table = ac_table;
+    }
+
+    let spectral = &segment[1 + num_components * 2..];
+    if spectral != [0, 63, 0] {
+        return Err(invalid(
+            "Unsupported spectral selection for a baseline scan",
+        ));
+    }
+    Ok(())
+}
+
+struct Tables<'a> {
+    quant: &'a [Option<[u16; 64]>; MAX_TABLES],
+    dc: &'a [Option<HuffmanTable>; MAX_TABLES],
+    ac: &'a [Option<HuffmanTable>; MAX_TABLES],
+}
+
+fn decode_scan(
+    data: &[u8],
+    frame: &mut Frame,
+    tables: &Tables,
+    restart_interval: usize,
+) -> Result<(), BellandeError> {
+    let (mcus_x, mcus_y) = (frame.mcus_x, frame.mcus_y);
+    let blocks_per_mcu: usize = frame.components.iter().map(|c| c.h * c.v).sum();
+
+    // Every block needs at least a DC code and an end-of-block code, so a
+    // header cannot make us allocate far more than the data could fill
+    let total_blocks = mcus_x
+        .checked_mul(mcus_y)
+        .and_then(|mcus| mcus.checked_mul(blocks_per_mcu))
+        .ok_or_else(|| invalid("Image dimensions overflow"))?;
+    if total_blocks > data.len().saturating_mul(4) {
+        return Err(invalid("Compressed data is too short for the image size"));
+    }
+
+    for component in &mut frame.components {
+        component.plane_width = mcus_x * component.h * 8;
+        component.plane = vec![0; component.plane_width * mcus_y * component.v * 8];
+    }
+
+    let single = frame.components.len() == 1;
+    // A single-component scan is not interleaved: it covers only the blocks
+    // that contain image samples, one block per MCU
+    let (units_x, units_y) = if single {
+        let c = &frame.components[0];
+        (
+            (frame.width * c.h).div_ceil(frame.h_max).div_ceil(8),
+            (frame.height * c.v).div_ceil(frame.v_max).div_ceil(8),
+        )
+    } else {
+        (mcus_x, mcus_y)
+    };
+
+    let mut reader = EntropyReader::new(data);
+    let mut coefficients = [0f32; 64];
+    for unit in 0..units_x * units_y {
+        if restart_interval > 0 && unit > 0 && unit % restart_interval == 0 {
+            reader.restart()?;
+            frame.components.iter_mut().for_each(|c| c.prediction = 0);
+        }
+
+        let (unit_x, unit_y) = (unit % units_x, unit / units_x);
+        for component in &mut frame.components {
+            let (blocks_h, blocks_v) = if single {
+                (1, 1)
+            } else {
+                (component.h, component.v)
+            };
+            for block_v in 0..blocks_v {
+                for block_h in 0..blocks_h {
+                    decode_block(&mut reader, component, tables, &mut coefficients)?;
+                    let x = (unit_x * blocks_h + block_h) * 8;
+                    let y = (unit_y * blocks_v + block_v) * 8;
+                    inverse_dct(&coefficients, component, x, y);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_block(
+    reader: &mut EntropyReader,
+    component: &mut Component,
+    tables: &Tables,
+    coefficients: &mut [f32; 64],
+) -> Result<(), BellandeError> {
+    // Table presence is checked before the scan starts
+    let (Some(quant), Some(dc_table), Some(ac_table)) = (
+        tables.quant[component.quant_id].as_ref(),
+        tables.dc[component.dc_table].as_ref(),
+        tables.ac[component.ac_table].as_ref(),
+    ) else {
+        return Err(invalid("Missing table"));
+    };
+
+    coefficients.fill(0.0);
+
+    let size = reader.decode(dc_table)?;
+    if size > 11 {
+        return Err(invalid("DC coefficient category out of range"));
+    }
+    let diff = reader.receive_extend(size)?;
+    component.prediction = component.prediction.wrapping_add(diff);
+    coefficients[0] = component.prediction as f32 * quant[0] as f32;
+
+    let mut k = 1;
+    while k < 64 {
+        let symbol = reader.decode(ac_table)?;
+        let (run, size) = ((symbol >> 4) as usize, symbol & 0x0F);
+        if size == 0 {
+            if run != 15 {
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        if size > 10 {
+            return Err(invalid("AC coefficient category out of range"));
+        }
+
+        k += run;
+        if k > 63 {
+            return Err(invalid("AC coefficient index out of range"));
+        }
+        coefficients[ZIGZAG[k]] = reader.receive_extend(size)? as f32 * quant[k] as f32;
+        k += 1;
+    }
+
+    Ok(())
+}
+
+/// Inverse DCT of one block, writing level-shifted samples into the
+/// component plane at (x, y)
+fn inverse_dct(coefficients: &[f32; 64], component: &mut Component, x: usize, y: usize) {
+    let mut rows = [0f32; 64];
+    for v in 0..8 {
+        for px in 0..8 {
+            rows[v * 8 + px] = (0..8)
+                .map(|u| coefficients[v * 8 + u] * idct_basis(px, u))
+                .sum();
+        }
+    }
+
+    for py in 0..8 {
+        for px in 0..8 {
+            let value: f32 = (0..8).map(|v| rows[v * 8 + px] * idct_basis(py, v)).sum();
+            let index = (y + py) * component.plane_width + x + px;
+            component.plane[index] = (value + 128.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+fn idct_basis(position: usize, frequency: usize) -> f32 {
+    let scale = if frequency == 0 {
+        std::f32::consts::FRAC_1_SQRT_2
+    } else {
+        1.0
+    };
+    0.5 * scale * ((2 * position + 1) as f32 * frequency as f32 * std::f32::consts::PI / 16.0).cos()
+}
+
+/// Upsamples every component to full resolution and converts to RGB
+fn to_rgb(frame: &Frame) -> DecodedImage {
+    let (width, height) = (frame.width, frame.height);
+    let sample = |component: &Component, x: usize, y: usize| -> f32 {
+        let sx = x * component.h / frame.h_max;
+        let sy = y * component.v / frame.v_max;
+        component.plane[sy * component.plane_width + sx] as f32
+    };
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            if let [luma] = frame.components.as_slice() {
+                let value = sample(luma, x, y) as u8;
+                rgb.extend_from_slice(&[value, value, value]);
+                continue;
+            }
+
+            let luma = sample(&frame.components[0], x, y);
+            let cb = sample(&frame.components[1], x, y) - 128.0;
+            let cr = sample(&frame.components[2], x, y) - 128.0;
+            for value in [
+                luma + 1.402 * cr,
+                luma - 0.344_136 * cb - 0.714_136 * cr,
+                luma + 1.772 * cb,
+            ] {
+                rgb.push(value.round().clamp(0.0, 255.0) as u8);
+            }
+        }
+    }
+
+    DecodedImage { width, height, rgb }
+}
+
+/// Reads Huffman coded bits, removing stuffed zero bytes
+struct EntropyReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    bits: u32,
+}
+
+impl<'a> EntropyReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        EntropyReader {
+            data,
+            pos: 0,
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    fn read_bit(&mut self) -> Result<u32, BellandeError> {
+        if self.bits == 0 {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| invalid("Compressed data ended early"))?;
+            if byte == 0xFF {
+                match self.data.get(self.pos + 1) {
+                    Some(0x00) => self.pos += 1,
+                    _ => return Err(invalid("Unexpected marker inside compressed data")),
+                }
+            }
+            self.pos += 1;
+            self.buffer = byte as u32;
+            self.bits = 8;
+        }
+
+        self.bits -= 1;
+        Ok((self.buffer >> self.bits) & 1)
+    }
+
+    fn receive_extend(&mut self, size: u8) -> Result<i32, BellandeError> {
+        let mut value = 0i32;
+        for _ in 0..size {
+            value = (value << 1) | self.read_bit()? as i32;
+        }
+        if size > 0 && value < 1 << (size - 1) {
+            value -= (1 << size) - 1;
+        }
+        Ok(value)
+    }
+
+    fn decode(&mut self, table: &HuffmanTable) -> Result<u8, BellandeError> {
+        let mut code = 0i32;
+        for length in 1..=16 {
+            code = (code << 1) | self.read_bit()? as i32;
+            if code <= table.max_code[length] {
+                let index = table.value_offset[length] + (code - table.min_code[length]) as usize;
+                return table
+                    .values
+                    .get(index)
+                    .copied()
+                    .ok_or_else(|| invalid("Huffman code has no symbol"));
+            }
+        }
+        Err(invalid("Invalid Huffman code"))
+    }
+
+    /// Discards buffered bits and consumes the expected restart marker
+    fn restart(&mut self) -> Result<(), BellandeError> {
+        self.bits = 0;
+        match self.data.get(self.pos..self.pos + 2) {
+            Some([0xFF, marker]) if (0xD0..=0xD7).contains(marker) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => Err(invalid("Missing restart marker")),
+        }
+    }
+}
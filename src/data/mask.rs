@@ -0,0 +1,295 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
+
+/// Run-length encoded binary mask in the COCO layout: pixels are visited in
+/// column-major order and `counts` alternates between runs of background
+/// and foreground, starting with background (possibly an empty run)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rle {
+    pub height: usize,
+    pub width: usize,
+    pub counts: Vec<u32>,
+}
+
+impl Rle {
+    /// Validates that `counts` covers exactly `height * width` pixels
+    pub fn new(height: usize, width: usize, counts: Vec<u32>) -> Result<Self, BellandeError> {
+        let total: u64 = counts.iter().map(|&count| count as u64).sum();
+        if total != (height * width) as u64 {
+            return Err(BellandeError::InvalidShape(format!(
+                "RLE counts cover {} pixels, expected {}x{}",
+                total, height, width
+            )));
+        }
+        Ok(Rle {
+            height,
+            width,
+            counts,
+        })
+    }
+
+    /// Encodes a (height, width) mask, treating values of at least 0.5 as
+    /// foreground
+    pub fn from_mask(mask: &Tensor) -> Result<Self, BellandeError> {
+        if mask.shape.len() != 2 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Expected a (height, width) mask, got {:?}",
+                mask.shape
+            )));
+        }
+        let (height, width) = (mask.shape[0], mask.shape[1]);
+
+        let mut counts = Vec::new();
+        let mut current = false;
+        let mut run = 0u32;
+        for x in 0..width {
+            for y in 0..height {
+                let value = mask.data[y * width + x] >= 0.5;
+                if value != current {
+                    counts.push(run);
+                    run = 0;
+                    current = value;
+                }
+                run += 1;
+            }
+        }
+        counts.push(run);
+
+        Ok(Rle {
+            height,
+            width,
+            counts,
+        })
+    }
+
+    /// Decodes to a (height, width) mask of zeros and ones
+    pub fn to_mask(&self) -> Tensor {
+        let mut data = vec![0.0; self.height * self.width];
+        let mut position = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            let end = position + count as usize;
+            if i % 2 == 1 {
+                for index in position..end {
+                    let (x, y) = (index / self.height, index % self.height);
+                    data[y * self.width + x] = 1.0;
+                }
+            }
+            position = end;
+        }
+        Tensor::new(
+            data,
+            vec![self.height, self.width],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        )
+    }
+
+    /// Number of foreground pixels
+    pub fn area(&self) -> u64 {
+        self.counts
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .map(|&count| count as u64)
+            .sum()
+    }
+
+    /// Parses the compressed `counts` string used by COCO result files and
+    /// `pycocotools`
+    pub fn from_coco_string(
+        counts: &str,
+        height: usize,
+        width: usize,
+    ) -> Result<Self, BellandeError> {
+        let bytes = counts.as_bytes();
+        let mut values: Vec<i64> = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let mut value = 0i64;
+            let mut shift = 0;
+            loop {
+                let byte = *bytes.get(position).ok_or_else(|| {
+                    BellandeError::InvalidParameter("Truncated compressed RLE string".into())
+                })?;
+                if !(48..48 + 64).contains(&byte) || shift > 60 {
+                    return Err(BellandeError::InvalidParameter(format!(
+                        "Invalid compressed RLE string '{}'",
+                        counts
+                    )));
+                }
+                let chunk = (byte - 48) as i64;
+                value |= (chunk & 0x1f) << shift;
+                shift += 5;
+                position += 1;
+                if chunk & 0x20 == 0 {
+                    if chunk & 0x10 != 0 {
+                        value |= -1i64 << shift;
+                    }
+                    break;
+                }
+            }
+            if values.len() > 2 {
+                value += values[values.len() - 2];
+            }
+            values.push(value);
+        }
+
+        let counts = values
+            .into_iter()
+            .map(|value| {
+                u32::try_from(value).map_err(|_| {
+                    BellandeError::InvalidParameter(format!("Invalid RLE run length {}", value))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Rle::new(height, width, counts)
+    }
+
+    /// Compressed `counts` string in the format read by `from_coco_string`.
+    /// Each run is stored as the difference from the run two before it, in
+    /// 5-bit groups offset into printable ASCII.
+    pub fn to_coco_string(&self) -> String {
+        let mut output = String::new();
+        for i in 0..self.counts.len() {
+            let mut value = self.counts[i] as i64;
+            if i > 2 {
+                value -= self.counts[i - 2] as i64;
+            }
+            loop {
+                let mut chunk = value & 0x1f;
+                value >>= 5;
+                let more = if chunk & 0x10 != 0 {
+                    value != -1
+                } else {
+                    value != 0
+                };
+                if more {
+                    chunk |= 0x20;
+                }
+                output.push((chunk as u8 + 48) as char);
+                if !more {
+                    break;
+                }
+            }
+        }
+        output
+    }
+}
+
+/// Fills COCO polygons, each a flat `[x1, y1, x2, y2, ...]` list in pixel
+/// coordinates, into a (height, width) mask of zeros and ones. A pixel is
+/// foreground when its center lies inside any polygon under the even-odd
+/// rule, so boundary pixels may differ slightly from `pycocotools`.
+pub fn rasterize_polygons(
+    polygons: &[Vec<f32>],
+    height: usize,
+    width: usize,
+) -> Result<Tensor, BellandeError> {
+    if let Some(polygon) = polygons
+        .iter()
+        .find(|polygon| polygon.len() < 6 || polygon.len() % 2 != 0)
+    {
+        return Err(BellandeError::InvalidParameter(format!(
+            "A polygon needs at least three (x, y) points, got {} values",
+            polygon.len()
+        )));
+    }
+
+    let mut data = vec![0.0; height * width];
+    let mut crossings = Vec::new();
+    for y in 0..height {
+        let center = y as f32 + 0.5;
+        for polygon in polygons {
+            crossings.clear();
+            let points = polygon.len() / 2;
+            for i in 0..points {
+                let (x0, y0) = (polygon[2 * i], polygon[2 * i + 1]);
+                let j = (i + 1) % points;
+                let (x1, y1) = (polygon[2 * j], polygon[2 * j + 1]);
+                if (y0 <= center) != (y1 <= center) {
+                    crossings.push(x0 + (center - y0) * (x1 - x0) / (y1 - y0));
+                }
+            }
+            crossings.sort_by(|a, b| a.total_cmp(b));
+
+            for span in crossings.chunks_exact(2) {
+                // Pixels whose centers x + 0.5 fall in [span[0], span[1])
+                let start = (span[0] - 0.5).ceil().max(0.0) as usize;
+                let end = ((span[1] - 0.5).ceil().max(0.0) as usize).min(width);
+                for x in start..end {
+                    data[y * width + x] = 1.0;
+                }
+            }
+        }
+    }
+
+    Ok(Tensor::new(
+        data,
+        vec![height, width],
+        false,
+        Device::CPU,
+        DataType::Float32,
+    ))
+}
+
+/// The `segmentation` field of a COCO annotation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segmentation {
+    /// One or more polygons of a single object
+    Polygons(Vec<Vec<f32>>),
+    /// Run-length encoded mask, used for crowd annotations
+    Rle(Rle),
+}
+
+impl Segmentation {
+    /// Rasterizes to a (height, width) mask of zeros and ones
+    pub fn to_mask(&self, height: usize, width: usize) -> Result<Tensor, BellandeError> {
+        match self {
+            Segmentation::Polygons(polygons) => rasterize_polygons(polygons, height, width),
+            Segmentation::Rle(rle) => {
+                if (rle.height, rle.width) != (height, width) {
+                    return Err(BellandeError::ShapeMismatch(format!(
+                        "RLE mask is {}x{}, expected {}x{}",
+                        rle.height, rle.width, height, width
+                    )));
+                }
+                Ok(rle.to_mask())
+            }
+        }
+    }
+}
+
+/// Stacks per-instance segmentations into a (instances, height, width) mask,
+/// the layout `SpatialSample::with_mask` accepts for instance targets
+pub fn rasterize_instances(
+    segmentations: &[Segmentation],
+    height: usize,
+    width: usize,
+) -> Result<Tensor, BellandeError> {
+    let mut data = Vec::with_capacity(segmentations.len() * height * width);
+    for segmentation in segmentations {
+        data.extend(segmentation.to_mask(height, width)?.data);
+    }
+    Ok(Tensor::new(
+        data,
+        vec![segmentations.len(), height, width],
+        false,
+        Device::CPU,
+        DataType::Float32,
+    ))
+}
@@ -2,7 +2,16 @@ pub mod augmentation;
 pub mod dataloader;
 pub mod dataset;
 pub mod image_decoder;
+pub mod image_encoder;
 pub mod image_folder;
 pub mod image_transformation_augmentation;
+pub mod jpeg;
+pub mod mask;
+pub mod patch;
 pub mod preprocessing;
+pub mod rotated;
 pub mod sampler;
+pub mod snapshot;
+pub mod spatial;
+pub mod statistics;
+pub mod synthetic;
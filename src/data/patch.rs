@@ -0,0 +1,383 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
+use crate::data::{augmentation::Transform, dataset::Dataset};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Image that can be read one region at a time, so that only the pixels of
+/// a patch are ever in memory
+pub trait PatchSource: Send + Sync {
+    fn height(&self) -> usize;
+    fn width(&self) -> usize;
+    fn channels(&self) -> usize;
+
+    /// Reads the `height` x `width` region at (`top`, `left`) as a
+    /// (channels, height, width) tensor with values in [0, 1]
+    fn read_region(
+        &self,
+        top: usize,
+        left: usize,
+        height: usize,
+        width: usize,
+    ) -> Result<Tensor, BellandeError>;
+}
+
+/// Headerless 8-bit image stored row by row with interleaved channels, the
+/// raw export of tools such as libvips or GDAL. Each region read opens the
+/// file and reads only the rows it covers, so any number of images can be
+/// referenced without holding file handles.
+pub struct RawImage {
+    path: PathBuf,
+    height: usize,
+    width: usize,
+    channels: usize,
+}
+
+impl RawImage {
+    /// Checks that the file holds `height * width * channels` bytes
+    pub fn new(
+        path: impl AsRef<Path>,
+        height: usize,
+        width: usize,
+        channels: usize,
+    ) -> Result<Self, BellandeError> {
+        let path = path.as_ref().to_path_buf();
+        if height == 0 || width == 0 || channels == 0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Image {} must have a positive size, got {}x{}x{}",
+                path.display(),
+                height,
+                width,
+                channels
+            )));
+        }
+        let expected = (height * width * channels) as u64;
+        let len = fs::metadata(&path).map_err(BellandeError::IOError)?.len();
+        if len != expected {
+            return Err(BellandeError::InvalidShape(format!(
+                "Raw image {} has {} bytes, expected {} for {}x{}x{}",
+                path.display(),
+                len,
+                expected,
+                height,
+                width,
+                channels
+            )));
+        }
+        Ok(RawImage {
+            path,
+            height,
+            width,
+            channels,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl PatchSource for RawImage {
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn read_region(
+        &self,
+        top: usize,
+        left: usize,
+        height: usize,
+        width: usize,
+    ) -> Result<Tensor, BellandeError> {
+        check_region(self, top, left, height, width)?;
+        let channels = self.channels;
+        let mut file = File::open(&self.path).map_err(BellandeError::IOError)?;
+        let mut row = vec![0u8; width * channels];
+        let mut data = vec![0.0; channels * height * width];
+
+        for y in 0..height {
+            let offset = (((top + y) * self.width + left) * channels) as u64;
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut row))
+                .map_err(BellandeError::IOError)?;
+            for (x, pixel) in row.chunks_exact(channels).enumerate() {
+                for (c, &value) in pixel.iter().enumerate() {
+                    data[(c * height + y) * width + x] = value as f32 / 255.0;
+                }
+            }
+        }
+
+        Ok(Tensor::new(
+            data,
+            vec![channels, height, width],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        ))
+    }
+}
+
+fn check_region(
+    source: &dyn PatchSource,
+    top: usize,
+    left: usize,
+    height: usize,
+    width: usize,
+) -> Result<(), BellandeError> {
+    if top + height > source.height() || left + width > source.width() {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Region of {}x{} at ({}, {}) does not fit a {}x{} image",
+            height,
+            width,
+            top,
+            left,
+            source.height(),
+            source.width()
+        )));
+    }
+    Ok(())
+}
+
+/// One image of a patch manifest
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestEntry {
+    /// Raw image file, relative to the manifest's directory unless absolute
+    pub path: PathBuf,
+    pub height: usize,
+    pub width: usize,
+    #[serde(default = "default_channels")]
+    pub channels: usize,
+    /// Class index shared by every patch of the image
+    #[serde(default)]
+    pub label: usize,
+}
+
+fn default_channels() -> usize {
+    3
+}
+
+/// Reads a JSON manifest: an array of `ManifestEntry` objects such as
+/// `{"path": "slide_01.raw", "height": 98304, "width": 122880, "label": 1}`
+pub fn read_manifest(path: impl AsRef<Path>) -> Result<Vec<ManifestEntry>, BellandeError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(BellandeError::IOError)?;
+    let mut entries: Vec<ManifestEntry> =
+        serde_json::from_reader(file).map_err(|_| BellandeError::SerializationError)?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    for entry in &mut entries {
+        if entry.path.is_relative() {
+            entry.path = base.join(&entry.path);
+        }
+    }
+    Ok(entries)
+}
+
+/// Predicate deciding from its pixels whether a candidate patch is kept
+pub type PatchFilter = dyn Fn(&Tensor) -> bool + Send + Sync;
+
+/// Dataset of fixed-size patches tiled over very large images, for whole
+/// slide or satellite imagery. Patch positions are computed up front, but
+/// pixels are read from the sources only when a patch is requested, so
+/// memory use is independent of the image sizes.
+///
+/// Patches lie entirely inside their image on a grid with the given stride;
+/// a stride smaller than the patch size makes them overlap. Each sample is
+/// a (channels, patch_height, patch_width) tensor paired with its image's
+/// class index of shape (1,).
+pub struct PatchDataset {
+    sources: Vec<Box<dyn PatchSource>>,
+    labels: Vec<usize>,
+    patch_size: (usize, usize),
+    stride: (usize, usize),
+    /// (source, top, left) of every patch
+    patches: Vec<(usize, usize, usize)>,
+    transform: Option<Box<dyn Transform>>,
+}
+
+impl PatchDataset {
+    /// Tiles each `(source, label)` with `patch_size` (height, width)
+    /// patches every `stride` pixels
+    pub fn new(
+        sources: Vec<(Box<dyn PatchSource>, usize)>,
+        patch_size: (usize, usize),
+        stride: (usize, usize),
+    ) -> Result<Self, BellandeError> {
+        if patch_size.0 == 0 || patch_size.1 == 0 || stride.0 == 0 || stride.1 == 0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Patch size {:?} and stride {:?} must be positive",
+                patch_size, stride
+            )));
+        }
+
+        let mut patches = Vec::new();
+        for (index, (source, _)) in sources.iter().enumerate() {
+            if source.height() < patch_size.0 || source.width() < patch_size.1 {
+                continue;
+            }
+            for top in (0..=source.height() - patch_size.0).step_by(stride.0) {
+                for left in (0..=source.width() - patch_size.1).step_by(stride.1) {
+                    patches.push((index, top, left));
+                }
+            }
+        }
+
+        let (sources, labels) = sources.into_iter().unzip();
+        Ok(PatchDataset {
+            sources,
+            labels,
+            patch_size,
+            stride,
+            patches,
+            transform: None,
+        })
+    }
+
+    /// Tiles the raw images listed in a manifest, see `read_manifest`
+    pub fn from_manifest(
+        path: impl AsRef<Path>,
+        patch_size: (usize, usize),
+        stride: (usize, usize),
+    ) -> Result<Self, BellandeError> {
+        let sources = read_manifest(path)?
+            .into_iter()
+            .map(|entry| {
+                let image = RawImage::new(&entry.path, entry.height, entry.width, entry.channels)?;
+                Ok((Box::new(image) as Box<dyn PatchSource>, entry.label))
+            })
+            .collect::<Result<Vec<_>, BellandeError>>()?;
+        Self::new(sources, patch_size, stride)
+    }
+
+    /// Keeps only the patches accepted by `filter`, such as
+    /// `tissue_filter` to skip slide background. Every candidate patch is
+    /// read once, one at a time.
+    pub fn with_filter(mut self, filter: &PatchFilter) -> Result<Self, BellandeError> {
+        let mut kept = Vec::with_capacity(self.patches.len());
+        for &(source, top, left) in &self.patches {
+            let patch = self.sources[source].read_region(
+                top,
+                left,
+                self.patch_size.0,
+                self.patch_size.1,
+            )?;
+            if filter(&patch) {
+                kept.push((source, top, left));
+            }
+        }
+        self.patches = kept;
+        Ok(self)
+    }
+
+    /// Transform applied to every patch after reading
+    pub fn with_transform(mut self, transform: Box<dyn Transform>) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    pub fn patch_size(&self) -> (usize, usize) {
+        self.patch_size
+    }
+
+    pub fn stride(&self) -> (usize, usize) {
+        self.stride
+    }
+
+    pub fn num_sources(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// (source, top, left) of a patch, e.g. for stitching predictions back
+    /// into a map of the image
+    pub fn location(&self, index: usize) -> (usize, usize, usize) {
+        self.patches[index]
+    }
+
+    /// Reads and transforms one patch
+    pub fn try_get(&self, index: usize) -> Result<(Tensor, Tensor), BellandeError> {
+        let &(source, top, left) = self.patches.get(index).ok_or_else(|| {
+            BellandeError::InvalidParameter(format!(
+                "Patch {} out of range for {} patches",
+                index,
+                self.patches.len()
+            ))
+        })?;
+        let mut input =
+            self.sources[source].read_region(top, left, self.patch_size.0, self.patch_size.1)?;
+        if let Some(ref transform) = self.transform {
+            input = transform.apply(&input)?;
+        }
+        let target = Tensor::new(
+            vec![self.labels[source] as f32],
+            vec![1],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        );
+        Ok((input, target))
+    }
+}
+
+impl Dataset for PatchDataset {
+    fn len(&self) -> usize {
+        self.patches.len()
+    }
+
+    /// Panics when the patch cannot be read, since `Dataset::get` cannot
+    /// report errors; use `try_get` to handle them
+    fn get(&self, index: usize) -> (Tensor, Tensor) {
+        self.try_get(index).unwrap_or_else(|e| {
+            let (source, top, left) = self.patches[index];
+            panic!(
+                "Failed to read patch at ({}, {}) of source {}: {}",
+                top, left, source, e
+            )
+        })
+    }
+}
+
+/// Foreground filter for brightfield slides, where tissue is darker than
+/// the white background: keeps patches in which at least `min_fraction` of
+/// the pixels have a mean channel value below `max_brightness`
+pub fn tissue_filter(
+    max_brightness: f32,
+    min_fraction: f32,
+) -> impl Fn(&Tensor) -> bool + Send + Sync {
+    move |patch: &Tensor| {
+        let channels = patch.shape[0].max(1);
+        let pixels = patch.data.len() / channels;
+        if pixels == 0 {
+            return false;
+        }
+        let foreground = (0..pixels)
+            .filter(|&p| {
+                let sum: f32 = (0..channels).map(|c| patch.data[c * pixels + p]).sum();
+                sum / (channels as f32) < max_brightness
+            })
+            .count();
+        foreground as f32 >= min_fraction * pixels as f32
+    }
+}
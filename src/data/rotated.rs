@@ -0,0 +1,340 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
+use crate::data::spatial::BoundingBox;
+use std::f32::consts::{FRAC_PI_2, PI};
+
+/// Oriented box in the pixel coordinates of `BoundingBox`: a `width` x
+/// `height` rectangle centered at (`cx`, `cy`) whose width axis is rotated
+/// by `angle` radians from the x axis towards the y axis, i.e. clockwise on
+/// screen since y points down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RotatedBox {
+    pub cx: f32,
+    pub cy: f32,
+    pub width: f32,
+    pub height: f32,
+    pub angle: f32,
+    pub label: usize,
+}
+
+impl RotatedBox {
+    pub fn new(cx: f32, cy: f32, width: f32, height: f32, angle: f32, label: usize) -> Self {
+        RotatedBox {
+            cx,
+            cy,
+            width,
+            height,
+            angle,
+            label,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        self.width.max(0.0) * self.height.max(0.0)
+    }
+
+    /// Corners in a consistent winding order
+    pub fn corners(&self) -> [(f32, f32); 4] {
+        let (sin, cos) = self.angle.sin_cos();
+        let (w, h) = (self.width / 2.0, self.height / 2.0);
+        [(-w, -h), (w, -h), (w, h), (-w, h)]
+            .map(|(x, y)| (self.cx + x * cos - y * sin, self.cy + x * sin + y * cos))
+    }
+
+    /// Axis-aligned box enclosing the corners
+    pub fn bounding_box(&self) -> BoundingBox {
+        let corners = self.corners();
+        let xs = corners.map(|c| c.0);
+        let ys = corners.map(|c| c.1);
+        let min = |values: [f32; 4]| values.into_iter().fold(f32::INFINITY, f32::min);
+        let max = |values: [f32; 4]| values.into_iter().fold(f32::NEG_INFINITY, f32::max);
+        BoundingBox::new(min(xs), min(ys), max(xs), max(ys), self.label)
+    }
+
+    /// Same rectangle with its angle in [-pi/2, pi/2), where boxes repeat
+    /// with period pi
+    pub fn normalized(&self) -> Self {
+        RotatedBox {
+            angle: normalize_angle(self.angle),
+            ..*self
+        }
+    }
+}
+
+impl From<BoundingBox> for RotatedBox {
+    fn from(b: BoundingBox) -> Self {
+        RotatedBox::new(
+            (b.x_min + b.x_max) / 2.0,
+            (b.y_min + b.y_max) / 2.0,
+            b.x_max - b.x_min,
+            b.y_max - b.y_min,
+            0.0,
+            b.label,
+        )
+    }
+}
+
+/// Maps an angle into [-pi/2, pi/2); a rectangle rotated by pi is the same
+/// rectangle
+pub fn normalize_angle(angle: f32) -> f32 {
+    let angle = (angle + FRAC_PI_2).rem_euclid(PI) - FRAC_PI_2;
+    // rem_euclid can round up to exactly pi
+    if angle >= FRAC_PI_2 {
+        angle - PI
+    } else {
+        angle
+    }
+}
+
+/// Signed area of a polygon by the shoelace formula
+fn polygon_area(points: &[(f32, f32)]) -> f32 {
+    let n = points.len();
+    (0..n)
+        .map(|i| {
+            let (x0, y0) = points[i];
+            let (x1, y1) = points[(i + 1) % n];
+            x0 * y1 - x1 * y0
+        })
+        .sum::<f32>()
+        / 2.0
+}
+
+/// Intersection of two convex polygons with positive winding, by clipping
+/// `subject` against each edge of `clip` (Sutherland-Hodgman)
+fn clip_convex(subject: &[(f32, f32)], clip: &[(f32, f32)]) -> Vec<(f32, f32)> {
+    let mut output = subject.to_vec();
+    for i in 0..clip.len() {
+        if output.is_empty() {
+            break;
+        }
+        let (a, b) = (clip[i], clip[(i + 1) % clip.len()]);
+        let side = |p: (f32, f32)| (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0);
+        let input = std::mem::take(&mut output);
+        for j in 0..input.len() {
+            let (current, next) = (input[j], input[(j + 1) % input.len()]);
+            let (s_current, s_next) = (side(current), side(next));
+            if s_current >= 0.0 {
+                output.push(current);
+            }
+            if (s_current >= 0.0) != (s_next >= 0.0) {
+                let t = s_current / (s_current - s_next);
+                output.push((
+                    current.0 + t * (next.0 - current.0),
+                    current.1 + t * (next.1 - current.1),
+                ));
+            }
+        }
+    }
+    output
+}
+
+/// Intersection over union of two rotated boxes, from the exact area of
+/// their overlapping polygon. Labels are ignored.
+pub fn rotated_iou(a: &RotatedBox, b: &RotatedBox) -> f32 {
+    let (area_a, area_b) = (a.area(), b.area());
+    if area_a <= 0.0 || area_b <= 0.0 {
+        return 0.0;
+    }
+    let overlap = clip_convex(&a.corners(), &b.corners());
+    let intersection = if overlap.len() < 3 {
+        0.0
+    } else {
+        polygon_area(&overlap).abs()
+    };
+    let union = area_a + area_b - intersection;
+    if union > 0.0 {
+        (intersection / union).clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Pairwise IoU as an (a.len(), b.len()) tensor, e.g. for matching anchors
+/// to ground truth
+pub fn rotated_iou_matrix(a: &[RotatedBox], b: &[RotatedBox]) -> Tensor {
+    let data = a
+        .iter()
+        .flat_map(|box_a| b.iter().map(move |box_b| rotated_iou(box_a, box_b)))
+        .collect();
+    Tensor::new(
+        data,
+        vec![a.len(), b.len()],
+        false,
+        Device::CPU,
+        DataType::Float32,
+    )
+}
+
+/// Non-maximum suppression of rotated boxes: visits boxes by descending
+/// score and drops every box whose IoU with an already kept box exceeds
+/// `iou_threshold`. With `per_label`, only boxes of the same label suppress
+/// each other. Returns the kept indices in descending score order.
+pub fn rotated_nms(
+    boxes: &[RotatedBox],
+    scores: &[f32],
+    iou_threshold: f32,
+    per_label: bool,
+) -> Result<Vec<usize>, BellandeError> {
+    if boxes.len() != scores.len() {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Got {} boxes but {} scores",
+            boxes.len(),
+            scores.len()
+        )));
+    }
+    if !(0.0..=1.0).contains(&iou_threshold) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "IoU threshold must be in [0, 1], got {}",
+            iou_threshold
+        )));
+    }
+
+    let mut order: Vec<usize> = (0..boxes.len()).collect();
+    order.sort_by(|&i, &j| scores[j].total_cmp(&scores[i]));
+
+    let mut keep: Vec<usize> = Vec::new();
+    for i in order {
+        let suppressed = keep.iter().any(|&k| {
+            (!per_label || boxes[k].label == boxes[i].label)
+                && rotated_iou(&boxes[k], &boxes[i]) > iou_threshold
+        });
+        if !suppressed {
+            keep.push(i);
+        }
+    }
+    Ok(keep)
+}
+
+/// Encodes ground-truth rotated boxes as regression targets relative to
+/// anchors or proposals, and decodes predicted offsets back to boxes.
+///
+/// Each box becomes `(dx, dy, dw, dh, da)`: the center offset expressed in
+/// the anchor's rotated frame and divided by its size, the log size ratios,
+/// and the angle difference wrapped into [-pi/2, pi/2), each multiplied by
+/// its entry of `weights`. Wrapping the angle keeps the target small for a
+/// box and its copy rotated by pi, which are the same rectangle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RotatedBoxCoder {
+    weights: [f32; 5],
+    size_clip: f32,
+}
+
+impl RotatedBoxCoder {
+    pub fn new() -> Self {
+        RotatedBoxCoder {
+            weights: [1.0; 5],
+            size_clip: (1000.0f32 / 16.0).ln(),
+        }
+    }
+
+    /// Scales of (dx, dy, dw, dh, da), e.g. (10, 10, 5, 5, 1) as in
+    /// two-stage detectors
+    pub fn with_weights(mut self, weights: [f32; 5]) -> Result<Self, BellandeError> {
+        if weights.iter().any(|&w| !(w > 0.0 && w.is_finite())) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Box coder weights must be positive, got {:?}",
+                weights
+            )));
+        }
+        self.weights = weights;
+        Ok(self)
+    }
+
+    /// (anchors.len(), 5) regression targets of `targets[i]` against
+    /// `anchors[i]`
+    pub fn encode(
+        &self,
+        anchors: &[RotatedBox],
+        targets: &[RotatedBox],
+    ) -> Result<Tensor, BellandeError> {
+        if anchors.len() != targets.len() {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Got {} anchors but {} targets",
+                anchors.len(),
+                targets.len()
+            )));
+        }
+        if let Some(anchor) = anchors.iter().find(|a| !(a.width > 0.0 && a.height > 0.0)) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Anchors must have a positive size, got {:?}",
+                anchor
+            )));
+        }
+
+        let [wx, wy, ww, wh, wa] = self.weights;
+        let mut data = Vec::with_capacity(anchors.len() * 5);
+        for (anchor, target) in anchors.iter().zip(targets) {
+            let (sin, cos) = anchor.angle.sin_cos();
+            let (gx, gy) = (target.cx - anchor.cx, target.cy - anchor.cy);
+            data.extend_from_slice(&[
+                wx * (gx * cos + gy * sin) / anchor.width,
+                wy * (-gx * sin + gy * cos) / anchor.height,
+                ww * (target.width / anchor.width).ln(),
+                wh * (target.height / anchor.height).ln(),
+                wa * normalize_angle(target.angle - anchor.angle),
+            ]);
+        }
+        Ok(Tensor::new(
+            data,
+            vec![anchors.len(), 5],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        ))
+    }
+
+    /// Applies (anchors.len(), 5) offsets to the anchors. Size offsets are
+    /// clamped so that a bad prediction cannot overflow, and the decoded
+    /// boxes keep the anchors' labels with normalized angles.
+    pub fn decode(
+        &self,
+        anchors: &[RotatedBox],
+        deltas: &Tensor,
+    ) -> Result<Vec<RotatedBox>, BellandeError> {
+        if deltas.shape != [anchors.len(), 5] {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected deltas of shape {:?}, got {:?}",
+                [anchors.len(), 5],
+                deltas.shape
+            )));
+        }
+
+        let [wx, wy, ww, wh, wa] = self.weights;
+        Ok(anchors
+            .iter()
+            .zip(deltas.data.chunks(5))
+            .map(|(anchor, d)| {
+                let (sin, cos) = anchor.angle.sin_cos();
+                let (dx, dy) = (d[0] / wx * anchor.width, d[1] / wy * anchor.height);
+                RotatedBox {
+                    cx: anchor.cx + dx * cos - dy * sin,
+                    cy: anchor.cy + dx * sin + dy * cos,
+                    width: anchor.width * (d[2] / ww).min(self.size_clip).exp(),
+                    height: anchor.height * (d[3] / wh).min(self.size_clip).exp(),
+                    angle: normalize_angle(anchor.angle + d[4] / wa),
+                    label: anchor.label,
+                }
+            })
+            .collect())
+    }
+}
+
+impl Default for RotatedBoxCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -13,8 +13,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::tensor::Tensor;
-use rand::seq::SliceRandom;
+use crate::core::{random, tensor::Tensor};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub trait Sampler: Send + Sync {
@@ -31,8 +30,7 @@ pub struct RandomSampler {
 impl RandomSampler {
     pub fn new(data_len: usize) -> Self {
         let mut indices: Vec<usize> = (0..data_len).collect();
-        let mut rng = rand::thread_rng();
-        indices.shuffle(&mut rng);
+        random::shuffle(&mut indices);
 
         RandomSampler {
             data_len,
@@ -47,8 +45,7 @@ impl Sampler for RandomSampler {
         let current = self.current_index.fetch_add(n, Ordering::SeqCst);
         if current >= self.data_len {
             let mut indices: Vec<usize> = (0..self.data_len).collect();
-            let mut rng = rand::thread_rng();
-            indices.shuffle(&mut rng);
+            random::shuffle(&mut indices);
             self.indices.clone_from_slice(&indices);
             self.current_index.store(n, Ordering::SeqCst);
             self.indices[0..n].to_vec()
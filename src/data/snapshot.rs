@@ -0,0 +1,275 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
+use crate::data::dataset::Dataset;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Caches fully augmented samples of a dataset on disk and serves them for
+/// `reuse_epochs` further epochs before augmenting again. Trades
+/// augmentation diversity for throughput when augmentation is CPU-bound.
+///
+/// Every read of a sample counts towards its epochs, so with one pass over
+/// the dataset per epoch, epoch 0 is augmented and cached, epochs 1 to
+/// `reuse_epochs` read the cache, and the following epoch augments afresh.
+/// The cache files are removed when the dataset is dropped.
+pub struct EpochSnapshotDataset {
+    inner: Arc<dyn Dataset>,
+    cache_dir: PathBuf,
+    reuse_epochs: usize,
+    state: Mutex<SnapshotState>,
+}
+
+/// Location of a cached sample in its snapshot file
+struct CachedSample {
+    snapshot: usize,
+    offset: u64,
+    input_shape: Vec<usize>,
+    target_shape: Vec<usize>,
+    device: Device,
+    dtype: DataType,
+}
+
+/// One append-only file per snapshot, removed once no sample uses it
+struct SnapshotFile {
+    file: File,
+    len: u64,
+    live: usize,
+}
+
+#[derive(Default)]
+struct SnapshotState {
+    reads: Vec<usize>,
+    samples: Vec<Option<CachedSample>>,
+    files: HashMap<usize, SnapshotFile>,
+    hits: usize,
+    misses: usize,
+}
+
+impl EpochSnapshotDataset {
+    /// Caches `inner` in `cache_dir`, which is created if missing
+    pub fn new(
+        inner: Arc<dyn Dataset>,
+        cache_dir: impl AsRef<Path>,
+        reuse_epochs: usize,
+    ) -> Result<Self, BellandeError> {
+        let cache_dir = cache_dir.as_ref().to_path_buf();
+        fs::create_dir_all(&cache_dir).map_err(BellandeError::IOError)?;
+        let len = inner.len();
+        Ok(EpochSnapshotDataset {
+            inner,
+            cache_dir,
+            reuse_epochs,
+            state: Mutex::new(SnapshotState {
+                reads: vec![0; len],
+                samples: (0..len).map(|_| None).collect(),
+                ..SnapshotState::default()
+            }),
+        })
+    }
+
+    pub fn reuse_epochs(&self) -> usize {
+        self.reuse_epochs
+    }
+
+    /// Number of reads served from the cache and from the wrapped dataset
+    pub fn cache_stats(&self) -> (usize, usize) {
+        self.state
+            .lock()
+            .map(|state| (state.hits, state.misses))
+            .unwrap_or((0, 0))
+    }
+
+    /// Drops every cached sample, so the next read of each sample augments
+    /// it again and starts a new snapshot
+    pub fn invalidate(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            let len = state.samples.len();
+            state.reads = vec![0; len];
+            state.samples.iter_mut().for_each(|sample| *sample = None);
+            for snapshot in state.files.drain().map(|(snapshot, _)| snapshot) {
+                let _ = fs::remove_file(self.snapshot_path(snapshot));
+            }
+        }
+    }
+
+    fn snapshot_path(&self, snapshot: usize) -> PathBuf {
+        self.cache_dir
+            .join(format!("epoch_snapshot_{}.bin", snapshot))
+    }
+
+    fn read_cached(
+        state: &mut SnapshotState,
+        index: usize,
+        snapshot: usize,
+    ) -> Option<(Tensor, Tensor)> {
+        let sample = state.samples[index].as_ref()?;
+        if sample.snapshot != snapshot {
+            return None;
+        }
+        let input_len: usize = sample.input_shape.iter().product();
+        let target_len: usize = sample.target_shape.iter().product();
+        let mut bytes = vec![0u8; (input_len + target_len) * 4];
+        let file = &mut state.files.get_mut(&snapshot)?.file;
+        file.seek(SeekFrom::Start(sample.offset)).ok()?;
+        file.read_exact(&mut bytes).ok()?;
+
+        let mut values = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let tensor = |values: Vec<f32>, shape: &[usize]| {
+            Tensor::new(
+                values,
+                shape.to_vec(),
+                false,
+                sample.device.clone(),
+                sample.dtype,
+            )
+        };
+        let input = tensor(
+            values.by_ref().take(input_len).collect(),
+            &sample.input_shape,
+        );
+        let target = tensor(values.collect(), &sample.target_shape);
+        Some((input, target))
+    }
+
+    /// Appends a freshly augmented sample to its snapshot file, replacing
+    /// the sample's previous entry
+    fn write_cached(
+        &self,
+        state: &mut SnapshotState,
+        index: usize,
+        snapshot: usize,
+        input: &Tensor,
+        target: &Tensor,
+    ) -> Result<(), BellandeError> {
+        self.release(state, index);
+        if !state.files.contains_key(&snapshot) {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(self.snapshot_path(snapshot))
+                .map_err(BellandeError::IOError)?;
+            state.files.insert(
+                snapshot,
+                SnapshotFile {
+                    file,
+                    len: 0,
+                    live: 0,
+                },
+            );
+        }
+
+        let bytes: Vec<u8> = input
+            .data
+            .iter()
+            .chain(&target.data)
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let entry = state
+            .files
+            .get_mut(&snapshot)
+            .ok_or(BellandeError::InvalidInputs)?;
+        entry
+            .file
+            .seek(SeekFrom::Start(entry.len))
+            .and_then(|_| entry.file.write_all(&bytes))
+            .map_err(BellandeError::IOError)?;
+        let offset = entry.len;
+        entry.len += bytes.len() as u64;
+        entry.live += 1;
+
+        state.samples[index] = Some(CachedSample {
+            snapshot,
+            offset,
+            input_shape: input.shape.clone(),
+            target_shape: target.shape.clone(),
+            device: input.device.clone(),
+            dtype: input.dtype,
+        });
+        Ok(())
+    }
+
+    /// Forgets the cached copy of a sample, deleting its snapshot file once
+    /// no other sample uses it
+    fn release(&self, state: &mut SnapshotState, index: usize) {
+        let Some(previous) = state.samples[index].take() else {
+            return;
+        };
+        let emptied = state.files.get_mut(&previous.snapshot).is_some_and(|file| {
+            file.live -= 1;
+            file.live == 0
+        });
+        if emptied {
+            state.files.remove(&previous.snapshot);
+            let _ = fs::remove_file(self.snapshot_path(previous.snapshot));
+        }
+    }
+}
+
+impl Dataset for EpochSnapshotDataset {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn get(&self, index: usize) -> (Tensor, Tensor) {
+        let snapshot = match self.state.lock() {
+            Ok(mut state) => {
+                let reads = state.reads[index];
+                state.reads[index] += 1;
+                let snapshot = reads / (self.reuse_epochs + 1);
+                if let Some(sample) = Self::read_cached(&mut state, index, snapshot) {
+                    state.hits += 1;
+                    return sample;
+                }
+                state.misses += 1;
+                Some(snapshot)
+            }
+            Err(_) => None,
+        };
+
+        // Augment outside the lock so workers run in parallel
+        let (input, target) = self.inner.get(index);
+        if let (Some(snapshot), Ok(mut state)) = (snapshot, self.state.lock()) {
+            if let Err(e) = self.write_cached(&mut state, index, snapshot, &input, &target) {
+                // Serve uncached samples rather than failing the epoch
+                eprintln!("Failed to cache sample {}: {}", index, e);
+                self.release(&mut state, index);
+            }
+        }
+        (input, target)
+    }
+
+    fn sample_weight(&self, index: usize) -> Option<f32> {
+        self.inner.sample_weight(index)
+    }
+}
+
+impl Drop for EpochSnapshotDataset {
+    fn drop(&mut self) {
+        if let Ok(state) = self.state.lock() {
+            for snapshot in state.files.keys() {
+                let _ = fs::remove_file(self.snapshot_path(*snapshot));
+            }
+        }
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::data::augmentation::Transform;
+use crate::inference::tta::{crop, flip, resize_bilinear};
+
+/// Axis-aligned box in pixel coordinates, where the image spans
+/// `[0, width] x [0, height]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub x_min: f32,
+    pub y_min: f32,
+    pub x_max: f32,
+    pub y_max: f32,
+    pub label: usize,
+}
+
+impl BoundingBox {
+    pub fn new(x_min: f32, y_min: f32, x_max: f32, y_max: f32, label: usize) -> Self {
+        BoundingBox {
+            x_min,
+            y_min,
+            x_max,
+            y_max,
+            label,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        (self.x_max - self.x_min).max(0.0) * (self.y_max - self.y_min).max(0.0)
+    }
+}
+
+/// Point in the pixel coordinates of `BoundingBox`. Transforms keep every
+/// keypoint so their order stays meaningful, and mark those that leave
+/// the image as not visible.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keypoint {
+    pub x: f32,
+    pub y: f32,
+    pub visible: bool,
+}
+
+impl Keypoint {
+    pub fn new(x: f32, y: f32) -> Self {
+        Keypoint {
+            x,
+            y,
+            visible: true,
+        }
+    }
+}
+
+/// A (channels, height, width) image with the spatial targets that must
+/// move with it: boxes, a mask shaped (..., height, width) such as a
+/// label map or per-instance masks, and keypoints
+#[derive(Debug, Clone)]
+pub struct SpatialSample {
+    pub image: Tensor,
+    pub boxes: Vec<BoundingBox>,
+    pub mask: Option<Tensor>,
+    pub keypoints: Vec<Keypoint>,
+}
+
+impl SpatialSample {
+    pub fn new(image: Tensor) -> Result<Self, BellandeError> {
+        if image.shape.len() != 3 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Expected a (channels, height, width) image, got {:?}",
+                image.shape
+            )));
+        }
+        Ok(SpatialSample {
+            image,
+            boxes: Vec::new(),
+            mask: None,
+            keypoints: Vec::new(),
+        })
+    }
+
+    pub fn with_boxes(mut self, boxes: Vec<BoundingBox>) -> Self {
+        self.boxes = boxes;
+        self
+    }
+
+    /// Mask whose last two dimensions match the image
+    pub fn with_mask(mut self, mask: Tensor) -> Result<Self, BellandeError> {
+        let rank = mask.shape.len();
+        if rank < 2 || mask.shape[rank - 2..] != self.image.shape[1..] {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Mask of shape {:?} does not cover an image of shape {:?}",
+                mask.shape, self.image.shape
+            )));
+        }
+        self.mask = Some(mask);
+        Ok(self)
+    }
+
+    pub fn with_keypoints(mut self, keypoints: Vec<Keypoint>) -> Self {
+        self.keypoints = keypoints;
+        self
+    }
+
+    pub fn height(&self) -> usize {
+        self.image.shape[1]
+    }
+
+    pub fn width(&self) -> usize {
+        self.image.shape[2]
+    }
+
+    /// Mirrors the image and its targets left to right
+    pub fn flip_horizontal(&self) -> Self {
+        let width = self.width() as f32;
+        SpatialSample {
+            image: map_planes(&self.image, |planes| flip(planes, false)),
+            boxes: self
+                .boxes
+                .iter()
+                .map(|b| BoundingBox {
+                    x_min: width - b.x_max,
+                    x_max: width - b.x_min,
+                    ..*b
+                })
+                .collect(),
+            mask: self
+                .mask
+                .as_ref()
+                .map(|mask| map_planes(mask, |planes| flip(planes, false))),
+            keypoints: self
+                .keypoints
+                .iter()
+                .map(|k| Keypoint {
+                    x: width - k.x,
+                    ..*k
+                })
+                .collect(),
+        }
+    }
+
+    /// Mirrors the image and its targets top to bottom
+    pub fn flip_vertical(&self) -> Self {
+        let height = self.height() as f32;
+        SpatialSample {
+            image: map_planes(&self.image, |planes| flip(planes, true)),
+            boxes: self
+                .boxes
+                .iter()
+                .map(|b| BoundingBox {
+                    y_min: height - b.y_max,
+                    y_max: height - b.y_min,
+                    ..*b
+                })
+                .collect(),
+            mask: self
+                .mask
+                .as_ref()
+                .map(|mask| map_planes(mask, |planes| flip(planes, true))),
+            keypoints: self
+                .keypoints
+                .iter()
+                .map(|k| Keypoint {
+                    y: height - k.y,
+                    ..*k
+                })
+                .collect(),
+        }
+    }
+
+    /// Cuts out the `height` x `width` window at (`top`, `left`). Boxes are
+    /// clipped to the window and dropped when nothing of them remains.
+    pub fn crop(
+        &self,
+        top: usize,
+        left: usize,
+        height: usize,
+        width: usize,
+    ) -> Result<Self, BellandeError> {
+        if height == 0 || width == 0 || top + height > self.height() || left + width > self.width()
+        {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Crop of {}x{} at ({}, {}) does not fit a {}x{} image",
+                height,
+                width,
+                top,
+                left,
+                self.height(),
+                self.width()
+            )));
+        }
+        let (top_f, left_f) = (top as f32, left as f32);
+        let (height_f, width_f) = (height as f32, width as f32);
+        let window = |planes: &Tensor| crop(planes, top, left, height, width);
+
+        Ok(SpatialSample {
+            image: map_planes(&self.image, window),
+            boxes: self
+                .boxes
+                .iter()
+                .map(|b| BoundingBox {
+                    x_min: (b.x_min - left_f).clamp(0.0, width_f),
+                    y_min: (b.y_min - top_f).clamp(0.0, height_f),
+                    x_max: (b.x_max - left_f).clamp(0.0, width_f),
+                    y_max: (b.y_max - top_f).clamp(0.0, height_f),
+                    ..*b
+                })
+                .filter(|b| b.area() > 0.0)
+                .collect(),
+            mask: self.mask.as_ref().map(|mask| map_planes(mask, window)),
+            keypoints: self
+                .keypoints
+                .iter()
+                .map(|k| {
+                    let (x, y) = (k.x - left_f, k.y - top_f);
+                    let inside = (0.0..width_f).contains(&x) && (0.0..height_f).contains(&y);
+                    Keypoint {
+                        x,
+                        y,
+                        visible: k.visible && inside,
+                    }
+                })
+                .collect(),
+        })
+    }
+
+    /// Resizes the image bilinearly and the mask by nearest neighbour, so
+    /// label values stay intact, and scales the box and keypoint
+    /// coordinates
+    pub fn resize(&self, height: usize, width: usize) -> Result<Self, BellandeError> {
+        if height == 0 || width == 0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Cannot resize to {}x{}",
+                height, width
+            )));
+        }
+        let scale_y = height as f32 / self.height() as f32;
+        let scale_x = width as f32 / self.width() as f32;
+
+        Ok(SpatialSample {
+            image: map_planes(&self.image, |planes| resize_bilinear(planes, height, width)),
+            boxes: self
+                .boxes
+                .iter()
+                .map(|b| BoundingBox {
+                    x_min: b.x_min * scale_x,
+                    y_min: b.y_min * scale_y,
+                    x_max: b.x_max * scale_x,
+                    y_max: b.y_max * scale_y,
+                    ..*b
+                })
+                .collect(),
+            mask: self
+                .mask
+                .as_ref()
+                .map(|mask| map_planes(mask, |planes| resize_nearest(planes, height, width))),
+            keypoints: self
+                .keypoints
+                .iter()
+                .map(|k| Keypoint {
+                    x: k.x * scale_x,
+                    y: k.y * scale_y,
+                    ..*k
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Applies a (batch, channels, height, width) image operation to a
+/// (..., height, width) tensor by viewing its leading dimensions as
+/// channels of a single image
+fn map_planes(tensor: &Tensor, operation: impl Fn(&Tensor) -> Tensor) -> Tensor {
+    let rank = tensor.shape.len();
+    let leading = &tensor.shape[..rank - 2];
+    let mut planes = tensor.clone();
+    planes.shape = vec![
+        1,
+        leading.iter().product(),
+        tensor.shape[rank - 2],
+        tensor.shape[rank - 1],
+    ];
+
+    let mut output = operation(&planes);
+    let mut shape = leading.to_vec();
+    shape.extend_from_slice(&output.shape[2..]);
+    output.shape = shape;
+    output.requires_grad = tensor.requires_grad;
+    output
+}
+
+/// Nearest-neighbour resize of a (batch, channels, height, width) tensor
+fn resize_nearest(input: &Tensor, out_h: usize, out_w: usize) -> Tensor {
+    let (planes, height, width) = (
+        input.shape[0] * input.shape[1],
+        input.shape[2],
+        input.shape[3],
+    );
+    let source = |dst: usize, out: usize, size: usize| {
+        (((dst as f32 + 0.5) * size as f32 / out as f32) as usize).min(size - 1)
+    };
+
+    let mut output = Vec::with_capacity(planes * out_h * out_w);
+    for plane in input.data.chunks(height * width) {
+        for h in 0..out_h {
+            let row = source(h, out_h, height) * width;
+            for w in 0..out_w {
+                output.push(plane[row + source(w, out_w, width)]);
+            }
+        }
+    }
+
+    Tensor::new(
+        output,
+        vec![input.shape[0], input.shape[1], out_h, out_w],
+        false,
+        input.device.clone(),
+        input.dtype,
+    )
+}
+
+/// Augmentation that transforms an image together with its spatial
+/// targets, for detection, segmentation and pose pipelines
+pub trait SpatialTransform: Send + Sync {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError>;
+}
+
+pub struct SpatialCompose {
+    transforms: Vec<Box<dyn SpatialTransform>>,
+}
+
+impl SpatialCompose {
+    pub fn new(transforms: Vec<Box<dyn SpatialTransform>>) -> Self {
+        SpatialCompose { transforms }
+    }
+}
+
+impl SpatialTransform for SpatialCompose {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        let mut current = sample.clone();
+        for transform in &self.transforms {
+            current = transform.apply_spatial(&current)?;
+        }
+        Ok(current)
+    }
+}
+
+/// Resizes images and their targets to a fixed size
+pub struct Resize {
+    height: usize,
+    width: usize,
+}
+
+impl Resize {
+    pub fn new(height: usize, width: usize) -> Self {
+        Resize { height, width }
+    }
+}
+
+impl SpatialTransform for Resize {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        sample.resize(self.height, self.width)
+    }
+}
+
+/// Bilinear resize of a (batch, channels, height, width) image batch, so
+/// images of different sizes can be collated
+impl Transform for Resize {
+    fn apply(&self, tensor: &Tensor) -> Result<Tensor, BellandeError> {
+        if tensor.shape.len() != 4 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Resize expects a (batch, channels, height, width) tensor, got {:?}",
+                tensor.shape
+            )));
+        }
+        Ok(resize_bilinear(tensor, self.height, self.width))
+    }
+}
+
+/// Runs a photometric `Transform` on the image only, leaving the targets
+/// untouched. The transform must not change the image size.
+pub struct ImageOnly<T: Transform> {
+    transform: T,
+}
+
+impl<T: Transform> ImageOnly<T> {
+    pub fn new(transform: T) -> Self {
+        ImageOnly { transform }
+    }
+}
+
+impl<T: Transform> SpatialTransform for ImageOnly<T> {
+    fn apply_spatial(&self, sample: &SpatialSample) -> Result<SpatialSample, BellandeError> {
+        map_image(sample, |batch| self.transform.apply(batch))
+    }
+}
+
+/// Replaces the image of `sample` by the result of a size-preserving
+/// (batch, channels, height, width) operation on it
+pub(crate) fn map_image(
+    sample: &SpatialSample,
+    operation: impl FnOnce(&Tensor) -> Result<Tensor, BellandeError>,
+) -> Result<SpatialSample, BellandeError> {
+    let mut batch = sample.image.clone();
+    batch.shape.insert(0, 1);
+    let mut image = operation(&batch)?;
+    if image.shape[..] != batch.shape[..] {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Image-only transform changed the image shape {:?} to {:?}",
+            batch.shape, image.shape
+        )));
+    }
+    image.shape.remove(0);
+    Ok(SpatialSample {
+        image,
+        boxes: sample.boxes.clone(),
+        mask: sample.mask.clone(),
+        keypoints: sample.keypoints.clone(),
+    })
+}
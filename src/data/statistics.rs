@@ -0,0 +1,216 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Aspect ratios (width / height) below this are counted as portrait
+const PORTRAIT_BELOW: f32 = 0.9;
+/// Aspect ratios above this are counted as landscape
+const LANDSCAPE_ABOVE: f32 = 1.1;
+
+/// Summary of a dataset's contents, produced by `Dataset::describe`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DatasetReport {
+    /// Total number of samples, including corrupt ones
+    pub num_samples: usize,
+    pub class_counts: BTreeMap<String, usize>,
+    /// Number of images of each `WIDTHxHEIGHT` size
+    pub image_sizes: BTreeMap<String, usize>,
+    pub aspect_ratios: AspectRatioSummary,
+    pub color_images: usize,
+    pub grayscale_images: usize,
+    pub corrupt_files: Vec<CorruptSample>,
+}
+
+/// Distribution of width / height over the loaded images
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AspectRatioSummary {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub portrait: usize,
+    pub square: usize,
+    pub landscape: usize,
+    #[serde(skip)]
+    sum: f64,
+    #[serde(skip)]
+    count: usize,
+}
+
+/// A sample that could not be loaded
+#[derive(Clone, Debug, Serialize)]
+pub struct CorruptSample {
+    /// File path, or sample index when the dataset has no files
+    pub source: String,
+    pub error: String,
+}
+
+impl DatasetReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a loaded image of shape (..., channels, height, width)
+    pub fn record_image(&mut self, class: String, image: &Tensor) {
+        self.num_samples += 1;
+        *self.class_counts.entry(class).or_insert(0) += 1;
+
+        let rank = image.shape.len();
+        if rank < 2 {
+            return;
+        }
+        let (height, width) = (image.shape[rank - 2], image.shape[rank - 1]);
+        let channels = if rank >= 3 { image.shape[rank - 3] } else { 1 };
+
+        *self
+            .image_sizes
+            .entry(format!("{}x{}", width, height))
+            .or_insert(0) += 1;
+        if height > 0 {
+            self.aspect_ratios.record(width as f32 / height as f32);
+        }
+
+        if is_grayscale(&image.data, channels, height * width) {
+            self.grayscale_images += 1;
+        } else {
+            self.color_images += 1;
+        }
+    }
+
+    /// Records a sample that failed to load. `class` is counted when the
+    /// label is known without decoding the sample.
+    pub fn record_corrupt(&mut self, class: Option<String>, source: String, error: &BellandeError) {
+        self.num_samples += 1;
+        if let Some(class) = class {
+            *self.class_counts.entry(class).or_insert(0) += 1;
+        }
+        self.corrupt_files.push(CorruptSample {
+            source,
+            error: error.to_string(),
+        });
+    }
+
+    /// Number of samples that failed to load
+    pub fn num_corrupt(&self) -> usize {
+        self.corrupt_files.len()
+    }
+
+    /// Serializes the report as pretty-printed JSON
+    pub fn to_json(&self) -> Result<String, BellandeError> {
+        serde_json::to_string_pretty(self).map_err(|_| BellandeError::SerializationError)
+    }
+}
+
+impl AspectRatioSummary {
+    fn record(&mut self, ratio: f32) {
+        if self.count == 0 {
+            self.min = ratio;
+            self.max = ratio;
+        } else {
+            self.min = self.min.min(ratio);
+            self.max = self.max.max(ratio);
+        }
+        self.sum += ratio as f64;
+        self.count += 1;
+        self.mean = (self.sum / self.count as f64) as f32;
+
+        if ratio < PORTRAIT_BELOW {
+            self.portrait += 1;
+        } else if ratio > LANDSCAPE_ABOVE {
+            self.landscape += 1;
+        } else {
+            self.square += 1;
+        }
+    }
+}
+
+impl fmt::Display for DatasetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Samples: {} ({} corrupt)",
+            self.num_samples,
+            self.num_corrupt()
+        )?;
+
+        writeln!(f, "Classes: {}", self.class_counts.len())?;
+        let width = self.class_counts.keys().map(|k| k.len()).max().unwrap_or(0);
+        for (class, count) in &self.class_counts {
+            writeln!(f, "  {:<width$}  {}", class, count, width = width)?;
+        }
+
+        writeln!(f, "Image sizes: {}", self.image_sizes.len())?;
+        let mut sizes: Vec<(&String, &usize)> = self.image_sizes.iter().collect();
+        sizes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (size, count) in sizes {
+            writeln!(f, "  {:<11}  {}", size, count)?;
+        }
+
+        let ratios = &self.aspect_ratios;
+        if ratios.count > 0 {
+            writeln!(
+                f,
+                "Aspect ratio: min {:.2}, mean {:.2}, max {:.2} (portrait {}, square {}, landscape {})",
+                ratios.min, ratios.mean, ratios.max, ratios.portrait, ratios.square, ratios.landscape
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Color: {}, grayscale: {}",
+            self.color_images, self.grayscale_images
+        )?;
+
+        if !self.corrupt_files.is_empty() {
+            writeln!(f, "Corrupt files:")?;
+            for corrupt in &self.corrupt_files {
+                writeln!(f, "  {}: {}", corrupt.source, corrupt.error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Class index of a target holding either an index or a one-hot row
+pub(crate) fn target_class(target: &Tensor) -> usize {
+    if target.data.len() == 1 {
+        return target.data[0].round().max(0.0) as usize;
+    }
+
+    target
+        .data
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(idx, _)| idx)
+        .unwrap_or(0)
+}
+
+/// An image is grayscale when it has one channel or all channels are equal
+fn is_grayscale(data: &[f32], channels: usize, plane: usize) -> bool {
+    if channels <= 1 || plane == 0 {
+        return true;
+    }
+
+    // Only the first image of a batch is inspected
+    let first = &data[..plane.min(data.len())];
+    (1..channels).all(|channel| {
+        data.get(channel * plane..(channel + 1) * plane)
+            .is_some_and(|other| other == first)
+    })
+}
@@ -0,0 +1,354 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, random, tensor::Tensor};
+use crate::data::{dataset::Dataset, image_encoder};
+use std::f32::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// In-memory dataset produced by the synthetic generators. Every sample is
+/// paired with a class index target of shape (1,). Generators draw from the
+/// framework generator, so `random::set_seed` makes their output reproducible.
+#[derive(Clone, Debug)]
+pub struct SyntheticDataset {
+    inputs: Vec<f32>,
+    sample_shape: Vec<usize>,
+    labels: Vec<usize>,
+    num_classes: usize,
+}
+
+impl SyntheticDataset {
+    fn new(
+        inputs: Vec<f32>,
+        sample_shape: Vec<usize>,
+        labels: Vec<usize>,
+        num_classes: usize,
+    ) -> Self {
+        debug_assert_eq!(
+            inputs.len(),
+            labels.len() * sample_shape.iter().product::<usize>()
+        );
+        SyntheticDataset {
+            inputs,
+            sample_shape,
+            labels,
+            num_classes,
+        }
+    }
+
+    /// All samples stacked into a (num_samples, ...sample_shape) tensor
+    pub fn inputs(&self) -> Tensor {
+        let mut shape = vec![self.labels.len()];
+        shape.extend_from_slice(&self.sample_shape);
+        Tensor::new(
+            self.inputs.clone(),
+            shape,
+            false,
+            Device::CPU,
+            DataType::Float32,
+        )
+    }
+
+    pub fn labels(&self) -> &[usize] {
+        &self.labels
+    }
+
+    pub fn sample_shape(&self) -> &[usize] {
+        &self.sample_shape
+    }
+
+    pub fn num_classes(&self) -> usize {
+        self.num_classes
+    }
+}
+
+impl Dataset for SyntheticDataset {
+    fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    fn get(&self, index: usize) -> (Tensor, Tensor) {
+        let sample_size: usize = self.sample_shape.iter().product();
+        let start = index * sample_size;
+        let input = Tensor::new(
+            self.inputs[start..start + sample_size].to_vec(),
+            self.sample_shape.clone(),
+            false,
+            Device::CPU,
+            DataType::Float32,
+        );
+        let target = Tensor::new(
+            vec![self.labels[index] as f32],
+            vec![1],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        );
+        (input, target)
+    }
+}
+
+/// Isotropic Gaussian clusters, one class per cluster. Centers are drawn
+/// uniformly from [-10, 10] in every dimension.
+pub fn make_blobs(
+    num_samples: usize,
+    num_features: usize,
+    num_centers: usize,
+    cluster_std: f32,
+) -> Result<SyntheticDataset, BellandeError> {
+    check_positive("num_features", num_features)?;
+    check_positive("num_centers", num_centers)?;
+    check_non_negative("cluster_std", cluster_std)?;
+
+    let centers = random::uniform(-10.0, 10.0, num_centers * num_features);
+    let mut inputs = noise(cluster_std, num_samples * num_features);
+    let labels: Vec<usize> = (0..num_samples).map(|i| i % num_centers).collect();
+
+    for (sample, &label) in inputs.chunks_mut(num_features).zip(labels.iter()) {
+        let center = &centers[label * num_features..(label + 1) * num_features];
+        for (value, offset) in sample.iter_mut().zip(center) {
+            *value += offset;
+        }
+    }
+
+    Ok(SyntheticDataset::new(
+        inputs,
+        vec![num_features],
+        labels,
+        num_centers,
+    ))
+}
+
+/// Two interleaving half circles in 2D with Gaussian noise of standard
+/// deviation `noise_std`; a classic non-linearly separable toy problem
+pub fn make_moons(num_samples: usize, noise_std: f32) -> Result<SyntheticDataset, BellandeError> {
+    check_non_negative("noise_std", noise_std)?;
+
+    let outer = num_samples.div_ceil(2);
+    let inner = num_samples - outer;
+    let mut inputs = noise(noise_std, num_samples * 2);
+    let mut labels = Vec::with_capacity(num_samples);
+
+    for i in 0..num_samples {
+        let (label, index, count) = if i < outer {
+            (0, i, outer)
+        } else {
+            (1, i - outer, inner)
+        };
+        let angle = if count > 1 {
+            PI * index as f32 / (count - 1) as f32
+        } else {
+            0.0
+        };
+        let (x, y) = if label == 0 {
+            (angle.cos(), angle.sin())
+        } else {
+            (1.0 - angle.cos(), 0.5 - angle.sin())
+        };
+
+        inputs[2 * i] += x;
+        inputs[2 * i + 1] += y;
+        labels.push(label);
+    }
+
+    Ok(SyntheticDataset::new(inputs, vec![2], labels, 2))
+}
+
+/// Noisy sinusoidal sequences of shape (seq_len, num_features). The class
+/// sets the frequency, so a sequence model has to look across time steps to
+/// tell classes apart; each feature gets a random phase.
+pub fn make_sequences(
+    num_samples: usize,
+    seq_len: usize,
+    num_features: usize,
+    num_classes: usize,
+    noise_std: f32,
+) -> Result<SyntheticDataset, BellandeError> {
+    check_positive("seq_len", seq_len)?;
+    check_positive("num_features", num_features)?;
+    check_positive("num_classes", num_classes)?;
+    check_non_negative("noise_std", noise_std)?;
+
+    let sample_size = seq_len * num_features;
+    let mut inputs = noise(noise_std, num_samples * sample_size);
+    let phases = random::uniform(0.0, 2.0 * PI, num_samples * num_features);
+    let labels: Vec<usize> = (0..num_samples).map(|i| i % num_classes).collect();
+
+    for (i, sample) in inputs.chunks_mut(sample_size).enumerate() {
+        let frequency = (labels[i] + 1) as f32 * 2.0 * PI / seq_len as f32;
+        for (t, step) in sample.chunks_mut(num_features).enumerate() {
+            for (f, value) in step.iter_mut().enumerate() {
+                *value += (frequency * t as f32 + phases[i * num_features + f]).sin();
+            }
+        }
+    }
+
+    Ok(SyntheticDataset::new(
+        inputs,
+        vec![seq_len, num_features],
+        labels,
+        num_classes,
+    ))
+}
+
+/// File format written by `make_image_folder`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyntheticImageFormat {
+    Png,
+    Jpeg { quality: u8 },
+}
+
+impl SyntheticImageFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            SyntheticImageFormat::Png => "png",
+            SyntheticImageFormat::Jpeg { .. } => "jpg",
+        }
+    }
+}
+
+/// Layout of a generated image classification folder
+#[derive(Clone, Debug)]
+pub struct SyntheticImageConfig {
+    pub num_classes: usize,
+    pub images_per_class: usize,
+    pub width: usize,
+    pub height: usize,
+    pub format: SyntheticImageFormat,
+    /// Standard deviation of per-pixel noise, in 0-255 intensity units
+    pub noise_std: f32,
+}
+
+impl SyntheticImageConfig {
+    pub fn new(num_classes: usize, images_per_class: usize) -> Self {
+        SyntheticImageConfig {
+            num_classes,
+            images_per_class,
+            width: 32,
+            height: 32,
+            format: SyntheticImageFormat::Png,
+            noise_std: 16.0,
+        }
+    }
+
+    pub fn with_size(mut self, width: usize, height: usize) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn with_format(mut self, format: SyntheticImageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_noise_std(mut self, noise_std: f32) -> Self {
+        self.noise_std = noise_std;
+        self
+    }
+}
+
+/// Writes an `ImageFolder` style tree, `root/class_<k>/image_<i>.<ext>`, of
+/// small images whose color and stripe orientation depend on the class.
+/// Returns every written path with its class index.
+pub fn make_image_folder<P: AsRef<Path>>(
+    root: P,
+    config: &SyntheticImageConfig,
+) -> Result<Vec<(PathBuf, usize)>, BellandeError> {
+    check_positive("num_classes", config.num_classes)?;
+    check_positive("width", config.width)?;
+    check_positive("height", config.height)?;
+    check_non_negative("noise_std", config.noise_std)?;
+
+    let mut samples = Vec::with_capacity(config.num_classes * config.images_per_class);
+    for class in 0..config.num_classes {
+        let class_dir = root.as_ref().join(format!("class_{}", class));
+        fs::create_dir_all(&class_dir).map_err(BellandeError::IOError)?;
+
+        for index in 0..config.images_per_class {
+            let pixels = class_image(class, config);
+            let bytes = match config.format {
+                SyntheticImageFormat::Png => {
+                    image_encoder::encode_png(&pixels, config.width, config.height)?
+                }
+                SyntheticImageFormat::Jpeg { quality } => {
+                    image_encoder::encode_jpeg(&pixels, config.width, config.height, quality)?
+                }
+            };
+
+            let path = class_dir.join(format!("image_{}.{}", index, config.format.extension()));
+            fs::write(&path, bytes).map_err(BellandeError::IOError)?;
+            samples.push((path, class));
+        }
+    }
+
+    Ok(samples)
+}
+
+/// Interleaved RGB pixels for one image of `class`
+fn class_image(class: usize, config: &SyntheticImageConfig) -> Vec<u8> {
+    let (width, height) = (config.width, config.height);
+    let hue = class as f32 / config.num_classes as f32;
+    let color = [
+        0.5 + 0.5 * (2.0 * PI * hue).cos(),
+        0.5 + 0.5 * (2.0 * PI * (hue + 1.0 / 3.0)).cos(),
+        0.5 + 0.5 * (2.0 * PI * (hue + 2.0 / 3.0)).cos(),
+    ];
+    let angle = PI * class as f32 / config.num_classes as f32;
+    let (dx, dy) = (angle.cos(), angle.sin());
+    let phase = random::uniform(0.0, 2.0 * PI, 1)[0];
+    let noise = noise(config.noise_std, width * height * 3);
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let stripe = 0.75 + 0.25 * ((x as f32 * dx + y as f32 * dy) * 0.8 + phase).sin();
+            for &base in &color {
+                let value = 255.0 * base * stripe + noise[pixels.len()];
+                pixels.push(value.round().clamp(0.0, 255.0) as u8);
+            }
+        }
+    }
+    pixels
+}
+
+fn noise(std: f32, size: usize) -> Vec<f32> {
+    if std > 0.0 {
+        random::normal(0.0, std, size)
+    } else {
+        vec![0.0; size]
+    }
+}
+
+fn check_positive(name: &str, value: usize) -> Result<(), BellandeError> {
+    if value == 0 {
+        return Err(BellandeError::InvalidParameter(format!(
+            "{} must be positive",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn check_non_negative(name: &str, value: f32) -> Result<(), BellandeError> {
+    if value.is_nan() || value < 0.0 {
+        return Err(BellandeError::InvalidParameter(format!(
+            "{} must be non-negative, got {}",
+            name, value
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,358 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, error::BellandeError, random, tensor::Tensor};
+use crate::data::dataloader::DataLoader;
+use crate::loss::Loss;
+use crate::models::models::Model;
+use crate::optim::{Optimizer, OptimizerState, ParameterGroup};
+use crate::training::trainer::Trainer;
+use std::collections::HashMap;
+
+/// Builds a fresh, architecture-identical model for the server and clients
+pub type ModelFactory = Box<dyn Fn() -> Result<Box<dyn Model>, BellandeError> + Send + Sync>;
+/// Builds a client's local optimizer over its model parameters
+pub type OptimizerFactory =
+    Box<dyn Fn(Vec<Tensor>) -> Result<Box<dyn Optimizer>, BellandeError> + Send + Sync>;
+/// Builds a client's local loss function
+pub type LossFactory = Box<dyn Fn() -> Box<dyn Loss> + Send + Sync>;
+
+/// How the server combines client updates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    /// Average of the client weights, weighted by local dataset size
+    /// (McMahan et al., 2017)
+    FedAvg,
+    /// FedAvg aggregation with a proximal term `mu / 2 * ||w - w_server||^2`
+    /// added to every local objective, limiting client drift on
+    /// heterogeneous data (Li et al., 2020)
+    FedProx { mu: f32 },
+}
+
+/// Outcome of one communication round
+#[derive(Debug, Clone)]
+pub struct RoundSummary {
+    pub round: usize,
+    /// Indices of the clients that took part
+    pub clients: Vec<usize>,
+    /// Final local training loss of each participating client
+    pub client_losses: Vec<f32>,
+    /// Client losses weighted by local dataset size
+    pub mean_loss: f32,
+}
+
+/// Simulates federated training of one server model by `K` clients that
+/// each hold a local dataset. Every round a subset of clients downloads the
+/// server weights, trains locally for a few epochs with its own `Trainer`,
+/// and the server replaces its weights with the aggregate of the results.
+pub struct FederatedSimulation {
+    server: Box<dyn Model>,
+    model_fn: ModelFactory,
+    optimizer_fn: OptimizerFactory,
+    loss_fn: LossFactory,
+    clients: Vec<DataLoader>,
+    aggregation: Aggregation,
+    local_epochs: usize,
+    clients_per_round: usize,
+    device: Device,
+    rounds: Vec<RoundSummary>,
+}
+
+impl FederatedSimulation {
+    pub fn new(
+        model_fn: ModelFactory,
+        optimizer_fn: OptimizerFactory,
+        loss_fn: LossFactory,
+        clients: Vec<DataLoader>,
+        device: Device,
+    ) -> Result<Self, BellandeError> {
+        if clients.is_empty() {
+            return Err(BellandeError::InvalidConfiguration(
+                "Federated simulation needs at least one client".into(),
+            ));
+        }
+        let server = model_fn()?;
+        let clients_per_round = clients.len();
+
+        Ok(FederatedSimulation {
+            server,
+            model_fn,
+            optimizer_fn,
+            loss_fn,
+            clients,
+            aggregation: Aggregation::FedAvg,
+            local_epochs: 1,
+            clients_per_round,
+            device,
+            rounds: Vec::new(),
+        })
+    }
+
+    pub fn with_aggregation(mut self, aggregation: Aggregation) -> Result<Self, BellandeError> {
+        if let Aggregation::FedProx { mu } = aggregation {
+            if !mu.is_finite() || mu < 0.0 {
+                return Err(BellandeError::InvalidParameter(format!(
+                    "FedProx mu must be non-negative, got {}",
+                    mu
+                )));
+            }
+        }
+        self.aggregation = aggregation;
+        Ok(self)
+    }
+
+    pub fn with_local_epochs(mut self, local_epochs: usize) -> Result<Self, BellandeError> {
+        if local_epochs == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Local epochs must be at least 1".into(),
+            ));
+        }
+        self.local_epochs = local_epochs;
+        Ok(self)
+    }
+
+    /// Number of clients sampled uniformly at random each round; all of
+    /// them by default
+    pub fn with_clients_per_round(
+        mut self,
+        clients_per_round: usize,
+    ) -> Result<Self, BellandeError> {
+        if clients_per_round == 0 || clients_per_round > self.clients.len() {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Clients per round must be between 1 and {}, got {}",
+                self.clients.len(),
+                clients_per_round
+            )));
+        }
+        self.clients_per_round = clients_per_round;
+        Ok(self)
+    }
+
+    pub fn server(&self) -> &dyn Model {
+        self.server.as_ref()
+    }
+
+    pub fn server_mut(&mut self) -> &mut dyn Model {
+        self.server.as_mut()
+    }
+
+    pub fn num_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Summaries of every round run so far
+    pub fn rounds(&self) -> &[RoundSummary] {
+        &self.rounds
+    }
+
+    /// Runs `rounds` communication rounds
+    pub fn run(&mut self, rounds: usize) -> Result<&[RoundSummary], BellandeError> {
+        for _ in 0..rounds {
+            self.run_round()?;
+        }
+        Ok(&self.rounds)
+    }
+
+    /// Trains the sampled clients from the current server weights and
+    /// aggregates their results into the server model
+    pub fn run_round(&mut self) -> Result<RoundSummary, BellandeError> {
+        let mut participants: Vec<usize> = (0..self.clients.len()).collect();
+        random::shuffle(&mut participants);
+        participants.truncate(self.clients_per_round);
+        participants.sort_unstable();
+
+        let server_state = self.server.state_dict();
+        let mut client_states = Vec::with_capacity(participants.len());
+        let mut client_losses = Vec::with_capacity(participants.len());
+        let mut weights = Vec::with_capacity(participants.len());
+
+        for &client in &participants {
+            let (state, loss) = self.train_client(client, &server_state)?;
+            client_states.push(state);
+            client_losses.push(loss);
+            weights.push(self.clients[client].dataset_len() as f32);
+        }
+
+        let total: f32 = weights.iter().sum();
+        let weights: Vec<f32> = if total > 0.0 {
+            weights.iter().map(|w| w / total).collect()
+        } else {
+            vec![1.0 / participants.len() as f32; participants.len()]
+        };
+
+        self.server
+            .load_state_dict(average_states(&server_state, &client_states, &weights)?)?;
+
+        let summary = RoundSummary {
+            round: self.rounds.len(),
+            mean_loss: client_losses
+                .iter()
+                .zip(weights.iter())
+                .map(|(loss, w)| loss * w)
+                .sum(),
+            clients: participants,
+            client_losses,
+        };
+        self.rounds.push(summary.clone());
+        Ok(summary)
+    }
+
+    /// Local training of one client, returning its weights and final loss
+    fn train_client(
+        &self,
+        client: usize,
+        server_state: &HashMap<String, Tensor>,
+    ) -> Result<(HashMap<String, Tensor>, f32), BellandeError> {
+        let mut model = (self.model_fn)()?;
+        model.load_state_dict(server_state.clone())?;
+
+        let mut optimizer = (self.optimizer_fn)(model.parameters())?;
+        if let Aggregation::FedProx { mu } = self.aggregation {
+            optimizer = Box::new(Proximal::new(optimizer, mu));
+        }
+
+        let mut trainer = Trainer::new(model, optimizer, (self.loss_fn)(), self.device.clone());
+        trainer.set_verbose(false);
+        let history = trainer.fit(self.clients[client].clone(), None, self.local_epochs)?;
+        let loss = history
+            .get_metric("loss")
+            .and_then(|losses| losses.last().copied())
+            .unwrap_or(f32::NAN);
+
+        Ok((trainer.model().state_dict(), loss))
+    }
+}
+
+/// Weighted average of client state dicts, keyed like the server's
+fn average_states(
+    server_state: &HashMap<String, Tensor>,
+    client_states: &[HashMap<String, Tensor>],
+    weights: &[f32],
+) -> Result<HashMap<String, Tensor>, BellandeError> {
+    server_state
+        .iter()
+        .map(|(name, server)| {
+            let mut data = vec![0.0; server.data.len()];
+            for (state, &weight) in client_states.iter().zip(weights) {
+                let tensor = state.get(name).ok_or_else(|| {
+                    BellandeError::RuntimeError(format!("Client update is missing '{}'", name))
+                })?;
+                if tensor.shape != server.shape {
+                    return Err(BellandeError::ShapeMismatch(format!(
+                        "Client update of '{}' has shape {:?}, expected {:?}",
+                        name, tensor.shape, server.shape
+                    )));
+                }
+                for (d, &v) in data.iter_mut().zip(tensor.data.iter()) {
+                    *d += weight * v;
+                }
+            }
+            let averaged = Tensor::new(
+                data,
+                server.shape.clone(),
+                server.requires_grad,
+                server.device.clone(),
+                server.dtype,
+            );
+            Ok((name.clone(), averaged))
+        })
+        .collect()
+}
+
+/// Adds the FedProx gradient `mu * (w - w_server)` before each step of the
+/// wrapped optimizer, anchored at the parameter values it was built with
+struct Proximal {
+    inner: Box<dyn Optimizer>,
+    mu: f32,
+    anchor: Vec<Vec<f32>>,
+}
+
+impl Proximal {
+    fn new(inner: Box<dyn Optimizer>, mu: f32) -> Self {
+        let anchor = inner
+            .get_param_groups()
+            .iter()
+            .flat_map(|group| group.params.iter().map(|param| param.data.clone()))
+            .collect();
+        Proximal { inner, mu, anchor }
+    }
+}
+
+impl Optimizer for Proximal {
+    fn step(&mut self) -> Result<(), BellandeError> {
+        let params = self
+            .inner
+            .get_param_groups_mut()
+            .iter_mut()
+            .flat_map(|group| group.params.iter_mut());
+        for (param, anchor) in params.zip(self.anchor.iter()) {
+            if let Some(mut grad) = param.grad() {
+                for ((g, &p), &a) in grad.data.iter_mut().zip(param.data.iter()).zip(anchor) {
+                    *g += self.mu * (p - a);
+                }
+                param.set_grad(grad.data);
+            }
+        }
+        self.inner.step()
+    }
+
+    fn zero_grad(&mut self) {
+        self.inner.zero_grad();
+    }
+
+    fn get_learning_rate(&self) -> f32 {
+        self.inner.get_learning_rate()
+    }
+
+    fn set_learning_rate(&mut self, lr: f32) {
+        self.inner.set_learning_rate(lr);
+    }
+
+    fn name(&self) -> &str {
+        "FedProx"
+    }
+
+    fn get_param_groups(&self) -> &[ParameterGroup] {
+        self.inner.get_param_groups()
+    }
+
+    fn get_param_groups_mut(&mut self) -> &mut [ParameterGroup] {
+        self.inner.get_param_groups_mut()
+    }
+
+    fn add_param_group(&mut self, group: ParameterGroup) {
+        self.inner.add_param_group(group);
+    }
+
+    fn state(&self) -> &OptimizerState {
+        self.inner.state()
+    }
+
+    fn state_mut(&mut self) -> &mut OptimizerState {
+        self.inner.state_mut()
+    }
+
+    fn requires_per_sample_grads(&self) -> bool {
+        self.inner.requires_per_sample_grads()
+    }
+
+    fn set_per_sample_grads(&mut self, grads: Vec<(String, Tensor)>) {
+        self.inner.set_per_sample_grads(grads);
+    }
+
+    fn metrics(&self) -> HashMap<String, f32> {
+        self.inner.metrics()
+    }
+}
@@ -0,0 +1,28 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Entry points for the fuzz targets under `fuzz/`. Only built with the
+//! `fuzzing` feature.
+
+use crate::data::{image_decoder::ImageDecoder, jpeg};
+
+/// Runs the image decoders used by datasets over arbitrary bytes. Malformed
+/// input must produce an error, never a panic.
+pub fn decode_image(bytes: &[u8]) {
+    if let Ok(image) = jpeg::decode_jpeg(bytes) {
+        assert_eq!(image.rgb.len(), image.width * image.height * 3);
+    }
+    let _ = ImageDecoder::new(bytes);
+}
@@ -0,0 +1,265 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::data::image_folder::ImageFolder;
+use crate::data::preprocessing::Preprocessor;
+use crate::inference::predictor::Predictor;
+use crate::inference::tta::resize_bilinear;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Settings for running a predictor over a directory of images
+pub struct FolderPredictionConfig {
+    /// Images are resized to (height, width) before preprocessing
+    pub image_size: (usize, usize),
+    pub batch_size: usize,
+    pub top_k: usize,
+    /// Applied in order to every resized batch
+    pub preprocessors: Vec<Box<dyn Preprocessor>>,
+    /// Skip images that fail to decode instead of aborting the run
+    pub skip_errors: bool,
+}
+
+impl FolderPredictionConfig {
+    pub fn new(image_size: (usize, usize)) -> Self {
+        FolderPredictionConfig {
+            image_size,
+            batch_size: 32,
+            top_k: 5,
+            preprocessors: Vec::new(),
+            skip_errors: false,
+        }
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn with_preprocessor(mut self, preprocessor: Box<dyn Preprocessor>) -> Self {
+        self.preprocessors.push(preprocessor);
+        self
+    }
+
+    pub fn with_skip_errors(mut self, skip_errors: bool) -> Self {
+        self.skip_errors = skip_errors;
+        self
+    }
+
+    fn validate(&self) -> Result<(), BellandeError> {
+        if self.image_size.0 == 0 || self.image_size.1 == 0 {
+            return Err(BellandeError::InvalidConfiguration(
+                "Image size must be non-zero".into(),
+            ));
+        }
+        if self.batch_size == 0 {
+            return Err(BellandeError::InvalidConfiguration(
+                "Batch size must be greater than 0".into(),
+            ));
+        }
+        if self.top_k == 0 {
+            return Err(BellandeError::InvalidConfiguration(
+                "top_k must be greater than 0".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Top-k predictions for a single image
+#[derive(Clone, Debug)]
+pub struct FolderPrediction {
+    pub path: PathBuf,
+    /// (class index, probability) sorted by descending probability
+    pub top_k: Vec<(usize, f32)>,
+}
+
+impl Predictor {
+    /// Runs the model over every image below `root` and returns the top-k
+    /// classes for each, in sorted path order
+    pub fn predict_folder(
+        &mut self,
+        root: &Path,
+        config: &FolderPredictionConfig,
+    ) -> Result<Vec<FolderPrediction>, BellandeError> {
+        let mut predictions = Vec::new();
+        for_each_batch(root, config, |paths, batch| {
+            let probabilities = self.predict_proba(&batch)?;
+            let num_classes = probabilities.shape[1];
+
+            for (path, row) in paths
+                .into_iter()
+                .zip(probabilities.data.chunks(num_classes))
+            {
+                predictions.push(FolderPrediction {
+                    path,
+                    top_k: top_k(row, config.top_k),
+                });
+            }
+            Ok(())
+        })?;
+
+        Ok(predictions)
+    }
+}
+
+/// Loads every image below `root` in sorted path order and calls `f` with
+/// the paths and the preprocessed (batch_size, 3, height, width) tensor of
+/// each batch of `config.batch_size` images
+pub(crate) fn for_each_batch(
+    root: &Path,
+    config: &FolderPredictionConfig,
+    mut f: impl FnMut(Vec<PathBuf>, Tensor) -> Result<(), BellandeError>,
+) -> Result<(), BellandeError> {
+    config.validate()?;
+
+    let paths = ImageFolder::find_images(&root.to_path_buf())?;
+    for chunk in paths.chunks(config.batch_size) {
+        let mut images = Vec::with_capacity(chunk.len());
+        let mut loaded_paths = Vec::with_capacity(chunk.len());
+
+        for path in chunk {
+            match load_resized(path, config.image_size) {
+                Ok(image) => {
+                    images.push(image);
+                    loaded_paths.push(path.clone());
+                }
+                Err(e) if config.skip_errors => {
+                    eprintln!("Skipping {}: {}", path.display(), e);
+                }
+                Err(e) => {
+                    return Err(BellandeError::ImageError(format!(
+                        "Failed to load {}: {}",
+                        path.display(),
+                        e
+                    )))
+                }
+            }
+        }
+
+        if images.is_empty() {
+            continue;
+        }
+
+        let mut batch = stack(&images)?;
+        for preprocessor in &config.preprocessors {
+            batch = preprocessor.process(&batch)?;
+        }
+        f(loaded_paths, batch)?;
+    }
+
+    Ok(())
+}
+
+/// Writes predictions as CSV with one row per image:
+/// `path,class_1,probability_1,...,class_k,probability_k`.
+/// Class indices are replaced by `class_names` when provided.
+pub fn write_predictions_csv<W: Write>(
+    writer: &mut W,
+    predictions: &[FolderPrediction],
+    class_names: Option<&[String]>,
+) -> Result<(), BellandeError> {
+    let k = predictions
+        .iter()
+        .map(|prediction| prediction.top_k.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut header = vec!["path".to_string()];
+    for rank in 1..=k {
+        header.push(format!("class_{}", rank));
+        header.push(format!("probability_{}", rank));
+    }
+    writeln!(writer, "{}", header.join(",")).map_err(BellandeError::IOError)?;
+
+    for prediction in predictions {
+        let mut fields = vec![csv_field(&prediction.path.to_string_lossy())];
+        for rank in 0..k {
+            match prediction.top_k.get(rank) {
+                Some(&(class_idx, probability)) => {
+                    let class = match class_names.and_then(|names| names.get(class_idx)) {
+                        Some(name) => csv_field(name),
+                        None => class_idx.to_string(),
+                    };
+                    fields.push(class);
+                    fields.push(format!("{:.6}", probability));
+                }
+                None => {
+                    fields.push(String::new());
+                    fields.push(String::new());
+                }
+            }
+        }
+        writeln!(writer, "{}", fields.join(",")).map_err(BellandeError::IOError)?;
+    }
+
+    Ok(())
+}
+
+fn load_resized(path: &PathBuf, (height, width): (usize, usize)) -> Result<Tensor, BellandeError> {
+    let image = ImageFolder::load_image(path)?;
+    if image.shape[2] == height && image.shape[3] == width {
+        Ok(image)
+    } else {
+        Ok(resize_bilinear(&image, height, width))
+    }
+}
+
+/// Concatenates (1, channels, height, width) images along the batch dimension
+fn stack(images: &[Tensor]) -> Result<Tensor, BellandeError> {
+    let first = &images[0];
+    let mut data = Vec::with_capacity(first.data.len() * images.len());
+    for image in images {
+        if image.shape != first.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Cannot batch images of shape {:?} and {:?}",
+                first.shape, image.shape
+            )));
+        }
+        data.extend_from_slice(&image.data);
+    }
+
+    let mut shape = first.shape.clone();
+    shape[0] = images.len();
+    Ok(Tensor::new(
+        data,
+        shape,
+        false,
+        first.device.clone(),
+        first.dtype,
+    ))
+}
+
+fn top_k(probabilities: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let mut ranked: Vec<(usize, f32)> = probabilities.iter().cloned().enumerate().collect();
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    ranked.truncate(k);
+    ranked
+}
+
+/// Quotes a CSV field when it contains a delimiter, quote or newline
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::log_sum_exp};
+
+/// A model that extends a partial sequence by one token, for `BeamSearch`.
+/// `State` carries whatever a hypothesis needs to continue decoding, e.g.
+/// the attention cache of a Transformer decoder or the hidden and cell
+/// state of an LSTM, and is cloned when a hypothesis branches.
+pub trait StepDecoder {
+    type State: Clone;
+
+    /// Logits, or log-probabilities, over the vocabulary for the token that
+    /// follows `token`, advancing `state` past `token`
+    fn step(&mut self, token: usize, state: &mut Self::State) -> Result<Vec<f32>, BellandeError>;
+
+    /// `step` for every live hypothesis at once. The default calls `step`
+    /// once per hypothesis; decoders that can batch hypotheses override it.
+    fn step_batch(
+        &mut self,
+        tokens: &[usize],
+        states: &mut [Self::State],
+    ) -> Result<Vec<Vec<f32>>, BellandeError> {
+        tokens
+            .iter()
+            .zip(states.iter_mut())
+            .map(|(&token, state)| self.step(token, state))
+            .collect()
+    }
+}
+
+/// A decoded sequence with its scores
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// Generated tokens, without the start token and ending with the end
+    /// token when `finished`
+    pub tokens: Vec<usize>,
+    /// Sum of the token log-probabilities
+    pub log_prob: f32,
+    /// `log_prob` divided by the length penalty, by which hypotheses rank
+    pub score: f32,
+    /// Whether the sequence ended with the end token rather than at the
+    /// length limit
+    pub finished: bool,
+}
+
+/// Beam search decoding over any `StepDecoder`. Each step keeps the
+/// `beam_width` most likely continuations; a continuation that emits
+/// `eos` is set aside as finished. Hypotheses rank by
+/// `log_prob / ((5 + len) / 6)^length_penalty` (Wu et al., 2016), so a
+/// positive `length_penalty` offsets the preference for short sequences.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamSearch {
+    beam_width: usize,
+    eos: usize,
+    length_penalty: f32,
+}
+
+/// A live hypothesis; its decoder state is kept alongside
+struct Beam {
+    tokens: Vec<usize>,
+    last: usize,
+    log_prob: f32,
+}
+
+impl BeamSearch {
+    /// Beam search with a length penalty of 0.6
+    pub fn new(beam_width: usize, eos: usize) -> Result<Self, BellandeError> {
+        if beam_width == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Beam width must be at least 1".into(),
+            ));
+        }
+        Ok(BeamSearch {
+            beam_width,
+            eos,
+            length_penalty: 0.6,
+        })
+    }
+
+    /// Exponent of the length normalization; 0 ranks by raw log-probability
+    pub fn with_length_penalty(mut self, length_penalty: f32) -> Result<Self, BellandeError> {
+        if !(length_penalty >= 0.0 && length_penalty.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Length penalty must be finite and non-negative, got {}",
+                length_penalty
+            )));
+        }
+        self.length_penalty = length_penalty;
+        Ok(self)
+    }
+
+    pub fn beam_width(&self) -> usize {
+        self.beam_width
+    }
+
+    pub fn eos(&self) -> usize {
+        self.eos
+    }
+
+    pub fn length_penalty(&self) -> f32 {
+        self.length_penalty
+    }
+
+    fn score(&self, log_prob: f32, len: usize) -> f32 {
+        log_prob / ((5.0 + len as f32) / 6.0).powf(self.length_penalty)
+    }
+
+    /// Decodes one sequence from `bos` and the decoder state `initial`,
+    /// generating at most `max_len` tokens. Returns up to `beam_width`
+    /// hypotheses, best first; sequences still running at `max_len` are
+    /// included unfinished when fewer than `beam_width` finished.
+    pub fn search<D: StepDecoder + ?Sized>(
+        &self,
+        decoder: &mut D,
+        bos: usize,
+        initial: D::State,
+        max_len: usize,
+    ) -> Result<Vec<Hypothesis>, BellandeError> {
+        let mut beams = vec![Beam {
+            tokens: Vec::new(),
+            last: bos,
+            log_prob: 0.0,
+        }];
+        let mut states = vec![initial];
+        let mut finished: Vec<Hypothesis> = Vec::new();
+
+        for _ in 0..max_len {
+            let tokens: Vec<usize> = beams.iter().map(|beam| beam.last).collect();
+            let logits = decoder.step_batch(&tokens, &mut states)?;
+            if logits.len() != beams.len() {
+                return Err(BellandeError::RuntimeError(format!(
+                    "Decoder returned {} rows for {} hypotheses",
+                    logits.len(),
+                    beams.len()
+                )));
+            }
+
+            // Every (beam, token) continuation with its total log-probability
+            let mut candidates: Vec<(usize, usize, f32)> = Vec::new();
+            for (b, row) in logits.iter().enumerate() {
+                let log_norm = log_sum_exp(row);
+                candidates.extend(
+                    row.iter()
+                        .enumerate()
+                        .map(|(token, &logit)| (b, token, beams[b].log_prob + logit - log_norm)),
+                );
+            }
+            // Twice the width leaves room for continuations that end. Ties
+            // go to the earlier beam and the lower token.
+            let order = |a: &(usize, usize, f32), c: &(usize, usize, f32)| {
+                c.2.total_cmp(&a.2).then((a.0, a.1).cmp(&(c.0, c.1)))
+            };
+            let keep = (2 * self.beam_width).min(candidates.len());
+            if keep < candidates.len() {
+                candidates.select_nth_unstable_by(keep, order);
+                candidates.truncate(keep);
+            }
+            candidates.sort_by(order);
+
+            let mut next = Vec::with_capacity(self.beam_width);
+            let mut next_states = Vec::with_capacity(self.beam_width);
+            for (rank, &(b, token, log_prob)) in candidates.iter().enumerate() {
+                let mut tokens = beams[b].tokens.clone();
+                tokens.push(token);
+                if token == self.eos {
+                    // Only ends among the best `beam_width` continuations count
+                    if rank < self.beam_width {
+                        finished.push(Hypothesis {
+                            score: self.score(log_prob, tokens.len()),
+                            tokens,
+                            log_prob,
+                            finished: true,
+                        });
+                    }
+                } else {
+                    next.push(Beam {
+                        tokens,
+                        last: token,
+                        log_prob,
+                    });
+                    next_states.push(states[b].clone());
+                }
+                if next.len() == self.beam_width {
+                    break;
+                }
+            }
+            beams = next;
+            states = next_states;
+
+            if beams.is_empty() || self.is_done(&finished, &beams) {
+                break;
+            }
+        }
+
+        let mut hypotheses = finished;
+        if hypotheses.len() < self.beam_width {
+            hypotheses.extend(beams.into_iter().map(|beam| Hypothesis {
+                score: self.score(beam.log_prob, beam.tokens.len()),
+                tokens: beam.tokens,
+                log_prob: beam.log_prob,
+                finished: false,
+            }));
+        }
+        hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hypotheses.truncate(self.beam_width);
+        Ok(hypotheses)
+    }
+
+    /// Whether `beam_width` hypotheses finished and none of the live ones
+    /// currently scores better than the worst of them
+    fn is_done(&self, finished: &[Hypothesis], beams: &[Beam]) -> bool {
+        if finished.len() < self.beam_width {
+            return false;
+        }
+        let mut scores: Vec<f32> = finished.iter().map(|h| h.score).collect();
+        scores.sort_by(|a, b| b.total_cmp(a));
+        let worst_kept = scores[self.beam_width - 1];
+        beams
+            .iter()
+            .all(|beam| self.score(beam.log_prob, beam.tokens.len()) <= worst_kept)
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{
+    autograd, device::Device, dtype::DataType, error::BellandeError, tensor::Tensor,
+};
+use crate::data::dataloader::DataLoader;
+use crate::inference::features::class_labels;
+use crate::models::models::Model;
+
+/// Search range for the inverse temperature
+const MIN_INV_TEMPERATURE: f32 = 0.05;
+const MAX_INV_TEMPERATURE: f32 = 20.0;
+const SEARCH_ITERATIONS: usize = 60;
+
+/// Result of fitting a softmax temperature on held-out logits
+#[derive(Debug, Clone, Copy)]
+pub struct TemperatureScaling {
+    pub temperature: f32,
+    /// Mean negative log-likelihood at temperature 1
+    pub nll_before: f32,
+    /// Mean negative log-likelihood at the fitted temperature
+    pub nll_after: f32,
+}
+
+/// Fits the temperature `T` minimizing the negative log-likelihood of
+/// `softmax(logits / T)` for `labels`. The NLL is convex in `1 / T`, so a
+/// golden-section search over the inverse temperature finds the optimum.
+pub fn fit_temperature(
+    logits: &Tensor,
+    labels: &[usize],
+) -> Result<TemperatureScaling, BellandeError> {
+    if logits.shape.len() != 2 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected logits of shape (samples, classes), got {:?}",
+            logits.shape
+        )));
+    }
+    let (num_samples, num_classes) = (logits.shape[0], logits.shape[1]);
+    if num_samples == 0 || num_classes == 0 {
+        return Err(BellandeError::InvalidParameter(
+            "Cannot calibrate on an empty set of logits".into(),
+        ));
+    }
+    if labels.len() != num_samples {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Got {} labels for {} samples",
+            labels.len(),
+            num_samples
+        )));
+    }
+    if let Some(&label) = labels.iter().find(|&&label| label >= num_classes) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Label {} out of range for {} classes",
+            label, num_classes
+        )));
+    }
+
+    let nll = |inv_temperature: f32| mean_nll(&logits.data, labels, num_classes, inv_temperature);
+
+    let ratio = (5f32.sqrt() - 1.0) / 2.0;
+    let (mut low, mut high) = (MIN_INV_TEMPERATURE, MAX_INV_TEMPERATURE);
+    let mut left = high - ratio * (high - low);
+    let mut right = low + ratio * (high - low);
+    let (mut nll_left, mut nll_right) = (nll(left), nll(right));
+
+    for _ in 0..SEARCH_ITERATIONS {
+        if nll_left < nll_right {
+            high = right;
+            right = left;
+            nll_right = nll_left;
+            left = high - ratio * (high - low);
+            nll_left = nll(left);
+        } else {
+            low = left;
+            left = right;
+            nll_left = nll_right;
+            right = low + ratio * (high - low);
+            nll_right = nll(right);
+        }
+    }
+
+    let inv_temperature = (low + high) / 2.0;
+    Ok(TemperatureScaling {
+        temperature: 1.0 / inv_temperature,
+        nll_before: nll(1.0),
+        nll_after: nll(inv_temperature),
+    })
+}
+
+/// Runs `model` over every batch of `loader` without tracking gradients,
+/// returning the stacked (num_samples, num_classes) logits and the class
+/// label of each sample
+pub fn collect_logits(
+    model: &mut dyn Model,
+    loader: &DataLoader,
+) -> Result<(Tensor, Vec<usize>), BellandeError> {
+    let _guard = autograd::no_grad();
+    let mut logits = Vec::new();
+    let mut labels = Vec::new();
+    let mut num_classes = None;
+
+    for (input, target) in loader.iter() {
+        let output = model.forward(&input)?;
+        if output.shape.len() != 2 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Expected model output of shape (batch, classes), got {:?}",
+                output.shape
+            )));
+        }
+        match num_classes {
+            None => num_classes = Some(output.shape[1]),
+            Some(n) if n != output.shape[1] => {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Number of classes changed from {} to {}",
+                    n, output.shape[1]
+                )))
+            }
+            _ => {}
+        }
+
+        for label in class_labels(&target, output.shape[0])? {
+            labels.push(usize::try_from(label).map_err(|_| {
+                BellandeError::InvalidParameter(format!("Negative class label {}", label))
+            })?);
+        }
+        logits.extend(output.data);
+    }
+
+    Ok((
+        Tensor::new(
+            logits,
+            vec![labels.len(), num_classes.unwrap_or(0)],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        ),
+        labels,
+    ))
+}
+
+/// Mean negative log-likelihood of `softmax(logits * inv_temperature)`
+fn mean_nll(logits: &[f32], labels: &[usize], num_classes: usize, inv_temperature: f32) -> f32 {
+    let total: f64 = logits
+        .chunks(num_classes)
+        .zip(labels)
+        .map(|(row, &label)| {
+            let max = row.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+            let log_sum: f64 = row
+                .iter()
+                .map(|&x| (((x - max) * inv_temperature) as f64).exp())
+                .sum::<f64>()
+                .ln();
+            log_sum - ((row[label] - max) * inv_temperature) as f64
+        })
+        .sum();
+    (total / labels.len() as f64) as f32
+}
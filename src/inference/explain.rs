@@ -0,0 +1,300 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::data::image_encoder::encode_png;
+use crate::models::models::Model;
+use std::path::Path;
+
+/// Gradient of each sample's `targets[b]` output of a (batch, classes)
+/// model with respect to its input. Runs the model in its current mode;
+/// the backward pass also accumulates into the parameter gradients.
+pub fn input_gradient(
+    model: &mut dyn Model,
+    input: &Tensor,
+    targets: &[usize],
+) -> Result<Tensor, BellandeError> {
+    let output = model.forward(input)?;
+    let grad = target_gradient(&output, targets)?;
+    let input_grad = model.backward(&grad)?;
+    if input_grad.shape != input.shape {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Input gradient of shape {:?} does not match input of shape {:?}",
+            input_grad.shape, input.shape
+        )));
+    }
+    Ok(input_grad)
+}
+
+/// Input times gradient attribution: `input * d output[target] / d input`
+pub fn input_x_gradient(
+    model: &mut dyn Model,
+    input: &Tensor,
+    targets: &[usize],
+) -> Result<Tensor, BellandeError> {
+    let mut attributions = input_gradient(model, input, targets)?;
+    for (a, &x) in attributions.data.iter_mut().zip(&input.data) {
+        *a *= x;
+    }
+    Ok(attributions)
+}
+
+/// One-hot gradient selecting each sample's target output
+fn target_gradient(output: &Tensor, targets: &[usize]) -> Result<Tensor, BellandeError> {
+    let [batch_size, classes] = output.shape[..] else {
+        return Err(BellandeError::InvalidShape(format!(
+            "Attribution needs (batch, classes) model outputs, got {:?}",
+            output.shape
+        )));
+    };
+    if targets.len() != batch_size {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Got {} targets for a batch of {}",
+            targets.len(),
+            batch_size
+        )));
+    }
+    let mut grad = Tensor::zeros(&output.shape);
+    for (b, &target) in targets.iter().enumerate() {
+        if target >= classes {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Target class {} is out of range for {} classes",
+                target, classes
+            )));
+        }
+        grad.data[b * classes + target] = 1.0;
+    }
+    Ok(grad)
+}
+
+/// Integrated gradients (Sundararajan et al., 2017): the input minus a
+/// baseline, times the model gradient averaged along the straight path
+/// from the baseline to the input. Attributions of a sample add up to the
+/// change of its target output between baseline and input, up to the
+/// error of the `steps`-point midpoint rule; see `convergence_delta`.
+#[derive(Debug, Clone)]
+pub struct IntegratedGradients {
+    steps: usize,
+    baseline: Option<Tensor>,
+}
+
+impl IntegratedGradients {
+    pub fn new(steps: usize) -> Result<Self, BellandeError> {
+        if steps == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Integrated gradients need at least one step".into(),
+            ));
+        }
+        Ok(IntegratedGradients {
+            steps,
+            baseline: None,
+        })
+    }
+
+    /// Reference input shaped like the whole batch or like one sample;
+    /// defaults to all zeros, i.e. a black image
+    pub fn with_baseline(mut self, baseline: Tensor) -> Self {
+        self.baseline = Some(baseline);
+        self
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Attributions shaped like `input`. Puts the model in evaluation mode.
+    pub fn attribute(
+        &self,
+        model: &mut dyn Model,
+        input: &Tensor,
+        targets: &[usize],
+    ) -> Result<Tensor, BellandeError> {
+        model.eval();
+        let baseline = self.baseline_for(input)?;
+        let mut total = vec![0.0; input.data.len()];
+        for step in 0..self.steps {
+            let alpha = (step as f32 + 0.5) / self.steps as f32;
+            let mut point = input.clone();
+            for (p, (&x, &b)) in point.data.iter_mut().zip(input.data.iter().zip(&baseline)) {
+                *p = b + alpha * (x - b);
+            }
+            let grad = input_gradient(model, &point, targets)?;
+            total.iter_mut().zip(&grad.data).for_each(|(t, &g)| *t += g);
+        }
+
+        let mut attributions = input.clone();
+        for ((a, &g), &b) in attributions.data.iter_mut().zip(&total).zip(&baseline) {
+            *a = (*a - b) * g / self.steps as f32;
+        }
+        Ok(attributions)
+    }
+
+    /// Per-sample gap between the summed attributions and the change of
+    /// the target output from baseline to input; values near zero mean
+    /// enough steps were taken
+    pub fn convergence_delta(
+        &self,
+        model: &mut dyn Model,
+        input: &Tensor,
+        targets: &[usize],
+        attributions: &Tensor,
+    ) -> Result<Vec<f32>, BellandeError> {
+        if attributions.shape != input.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Attributions of shape {:?} do not match input of shape {:?}",
+                attributions.shape, input.shape
+            )));
+        }
+        let mut baseline = input.clone();
+        baseline.data = self.baseline_for(input)?;
+        let output = model.forward(input)?;
+        let baseline_output = model.forward(&baseline)?;
+        // Validates the output shape and targets
+        target_gradient(&output, targets)?;
+
+        let classes = output.shape[1];
+        let per_sample = input.data.len() / targets.len().max(1);
+        Ok(targets
+            .iter()
+            .enumerate()
+            .map(|(b, &target)| {
+                let summed: f32 = attributions.data[b * per_sample..(b + 1) * per_sample]
+                    .iter()
+                    .sum();
+                let index = b * classes + target;
+                summed - (output.data[index] - baseline_output.data[index])
+            })
+            .collect())
+    }
+
+    /// Baseline values for every element of `input`
+    fn baseline_for(&self, input: &Tensor) -> Result<Vec<f32>, BellandeError> {
+        let Some(baseline) = &self.baseline else {
+            return Ok(vec![0.0; input.data.len()]);
+        };
+        if baseline.shape == input.shape {
+            Ok(baseline.data.clone())
+        } else if input.shape.get(1..) == Some(&baseline.shape[..]) {
+            Ok(baseline.data.repeat(input.shape[0]))
+        } else {
+            Err(BellandeError::ShapeMismatch(format!(
+                "Baseline of shape {:?} matches neither input {:?} nor one of its samples",
+                baseline.shape, input.shape
+            )))
+        }
+    }
+}
+
+/// Per-pixel importance of (batch, channels, height, width) attributions:
+/// absolute values summed over channels and scaled to [0, 1] per sample,
+/// as a (batch, height, width) tensor
+pub fn attribution_map(attributions: &Tensor) -> Result<Tensor, BellandeError> {
+    let [batch_size, channels, height, width] = attributions.shape[..] else {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected (batch, channels, height, width) attributions, got {:?}",
+            attributions.shape
+        )));
+    };
+    let pixels = height * width;
+    let mut map = Tensor::zeros(&[batch_size, height, width]);
+    for (b, sample_map) in map.data.chunks_mut(pixels.max(1)).enumerate() {
+        for c in 0..channels {
+            let plane = &attributions.data[(b * channels + c) * pixels..][..pixels];
+            sample_map
+                .iter_mut()
+                .zip(plane)
+                .for_each(|(m, &a)| *m += a.abs());
+        }
+        let max = sample_map.iter().copied().fold(0.0, f32::max);
+        if max > 0.0 {
+            sample_map.iter_mut().for_each(|m| *m /= max);
+        }
+    }
+    Ok(map)
+}
+
+/// "Hot" colormap from black through red and yellow to white
+fn heat_color(value: f32) -> [u8; 3] {
+    let v = value.clamp(0.0, 1.0) * 3.0;
+    let channel = |offset: f32| ((v - offset).clamp(0.0, 1.0) * 255.0).round() as u8;
+    [channel(0.0), channel(1.0), channel(2.0)]
+}
+
+/// Interleaved RGB pixels of one (height, width) map with values in [0, 1]
+pub fn heatmap_rgb(map: &[f32]) -> Vec<u8> {
+    map.iter().flat_map(|&value| heat_color(value)).collect()
+}
+
+/// Interleaved RGB pixels of a (channels, height, width) image with values
+/// in [0, 1], blended with the heatmap of `map` at opacity `alpha`.
+/// Single-channel images are shown in grayscale.
+pub fn overlay_rgb(image: &Tensor, map: &[f32], alpha: f32) -> Result<Vec<u8>, BellandeError> {
+    let [channels, height, width] = image.shape[..] else {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected a (channels, height, width) image, got {:?}",
+            image.shape
+        )));
+    };
+    let pixels = height * width;
+    if (channels != 1 && channels != 3) || map.len() != pixels {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Cannot overlay a map of {} pixels on an image of shape {:?}",
+            map.len(),
+            image.shape
+        )));
+    }
+    if !(0.0..=1.0).contains(&alpha) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Overlay opacity must be in [0, 1], got {}",
+            alpha
+        )));
+    }
+
+    let mut rgb = Vec::with_capacity(pixels * 3);
+    for (pixel, &value) in map.iter().enumerate() {
+        let heat = heat_color(value);
+        for (c, &h) in heat.iter().enumerate() {
+            let channel = if channels == 1 { 0 } else { c };
+            let base = image.data[channel * pixels + pixel].clamp(0.0, 1.0) * 255.0;
+            rgb.push(((1.0 - alpha) * base + alpha * h as f32).round() as u8);
+        }
+    }
+    Ok(rgb)
+}
+
+/// Writes the heatmap of one (height, width) attribution map as a PNG
+pub fn save_heatmap(map: &Tensor, path: impl AsRef<Path>) -> Result<(), BellandeError> {
+    let [height, width] = map.shape[..] else {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected a (height, width) map, got {:?}",
+            map.shape
+        )));
+    };
+    let png = encode_png(&heatmap_rgb(&map.data), width, height)?;
+    std::fs::write(path, png).map_err(BellandeError::IOError)
+}
+
+/// Writes a (channels, height, width) image blended with the heatmap of a
+/// (height, width) attribution map as a PNG
+pub fn save_overlay(
+    image: &Tensor,
+    map: &Tensor,
+    alpha: f32,
+    path: impl AsRef<Path>,
+) -> Result<(), BellandeError> {
+    let rgb = overlay_rgb(image, &map.data, alpha)?;
+    let png = encode_png(&rgb, image.shape[2], image.shape[1])?;
+    std::fs::write(path, png).map_err(BellandeError::IOError)
+}
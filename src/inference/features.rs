@@ -0,0 +1,197 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{
+    autograd, device::Device, dtype::DataType, error::BellandeError, numerics::numerics,
+    tensor::Tensor,
+};
+use crate::data::dataloader::DataLoader;
+use crate::models::models::Model;
+use crate::utilities::npz::NpzWriter;
+use std::path::{Path, PathBuf};
+
+/// Runs a backbone model to produce per-sample embeddings
+pub struct FeatureExtractor {
+    backbone: Box<dyn Model>,
+}
+
+impl FeatureExtractor {
+    /// Creates a new feature extractor, switching the backbone to evaluation mode
+    pub fn new(mut backbone: Box<dyn Model>) -> Self {
+        backbone.eval();
+        FeatureExtractor { backbone }
+    }
+
+    /// Returns L2-normalized embeddings with shape (batch_size, embedding_dim)
+    pub fn extract(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        embed(self.backbone.as_mut(), input)
+    }
+
+    /// Runs the backbone over every batch of `loader` and saves the embeddings
+    /// and labels to an `.npz` archive with arrays `embeddings` and `labels`.
+    /// Returns the number of samples written.
+    pub fn export_embeddings<P: AsRef<Path>>(
+        &mut self,
+        loader: &DataLoader,
+        output: P,
+    ) -> Result<usize, BellandeError> {
+        self.export_embeddings_with_paths(loader, &[], output)
+    }
+
+    /// Like [`export_embeddings`](Self::export_embeddings), additionally storing
+    /// a `paths` array. `paths` must follow the loader's sample order, so the
+    /// loader should not shuffle.
+    pub fn export_embeddings_with_paths<P: AsRef<Path>>(
+        &mut self,
+        loader: &DataLoader,
+        paths: &[PathBuf],
+        output: P,
+    ) -> Result<usize, BellandeError> {
+        let (embeddings, labels) = embed_loader(self.backbone.as_mut(), loader)?;
+        let (num_samples, dim) = (embeddings.shape[0], embeddings.shape[1]);
+
+        let mut writer = NpzWriter::new();
+        writer.add_f32("embeddings", &[num_samples, dim], &embeddings.data)?;
+        writer.add_i64("labels", &[num_samples], &labels)?;
+
+        if !paths.is_empty() {
+            if paths.len() != num_samples {
+                return Err(BellandeError::InvalidParameter(format!(
+                    "Got {} paths for {} samples",
+                    paths.len(),
+                    num_samples
+                )));
+            }
+            let paths: Vec<String> = paths
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect();
+            writer.add_strings("paths", &paths)?;
+        }
+
+        writer.write(output)?;
+        Ok(num_samples)
+    }
+
+    pub fn backbone(&self) -> &dyn Model {
+        self.backbone.as_ref()
+    }
+
+    pub fn backbone_mut(&mut self) -> &mut dyn Model {
+        self.backbone.as_mut()
+    }
+}
+
+/// Returns L2-normalized embeddings with shape (batch_size, embedding_dim)
+pub(crate) fn embed(backbone: &mut dyn Model, input: &Tensor) -> Result<Tensor, BellandeError> {
+    let _guard = autograd::no_grad();
+    let features = backbone.forward(input)?;
+
+    if features.shape.is_empty() {
+        return Err(BellandeError::InvalidShape(
+            "Backbone output must have a batch dimension".into(),
+        ));
+    }
+
+    let batch_size = features.shape[0];
+    let dim = if batch_size == 0 {
+        0
+    } else {
+        features.data.len() / batch_size
+    };
+
+    let denom_eps = numerics().denom_eps;
+    let mut data = features.data;
+    for row in data.chunks_mut(dim.max(1)) {
+        let norm = row.iter().map(|v| v * v).sum::<f32>().sqrt().max(denom_eps);
+        row.iter_mut().for_each(|v| *v /= norm);
+    }
+
+    Ok(Tensor::new(
+        data,
+        vec![batch_size, dim],
+        false,
+        features.device,
+        features.dtype,
+    ))
+}
+
+/// Embeds every batch of `loader`, returning a (num_samples, dim) tensor and
+/// the class label of each sample
+pub(crate) fn embed_loader(
+    backbone: &mut dyn Model,
+    loader: &DataLoader,
+) -> Result<(Tensor, Vec<i64>), BellandeError> {
+    let mut embeddings = Vec::new();
+    let mut labels = Vec::new();
+    let mut dim = None;
+
+    for (input, target) in loader.iter() {
+        let batch = embed(backbone, &input)?;
+        match dim {
+            None => dim = Some(batch.shape[1]),
+            Some(d) if d != batch.shape[1] => {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Embedding dimension changed from {} to {}",
+                    d, batch.shape[1]
+                )))
+            }
+            _ => {}
+        }
+
+        labels.extend(class_labels(&target, batch.shape[0])?);
+        embeddings.extend(batch.data);
+    }
+
+    let num_samples = labels.len();
+    Ok((
+        Tensor::new(
+            embeddings,
+            vec![num_samples, dim.unwrap_or(0)],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        ),
+        labels,
+    ))
+}
+
+/// Converts a batch of targets, either class indices or one-hot rows, to
+/// class indices
+pub(crate) fn class_labels(target: &Tensor, batch_size: usize) -> Result<Vec<i64>, BellandeError> {
+    if target.data.len() == batch_size {
+        return Ok(target.data.iter().map(|&v| v.round() as i64).collect());
+    }
+
+    if batch_size == 0 || target.data.len() % batch_size != 0 {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Targets of shape {:?} do not match batch size {}",
+            target.shape, batch_size
+        )));
+    }
+
+    let num_classes = target.data.len() / batch_size;
+    Ok(target
+        .data
+        .chunks(num_classes)
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
+                .map(|(idx, _)| idx as i64)
+                .unwrap_or(0)
+        })
+        .collect())
+}
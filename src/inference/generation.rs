@@ -0,0 +1,184 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, random};
+
+/// Chooses the next token of autoregressive generation from a row of
+/// logits: greedily, or by sampling from `softmax(logits / temperature)`
+/// restricted to the `top_k` most likely tokens and then to the smallest
+/// set of them whose probability reaches `top_p` (nucleus sampling).
+/// Sampling draws from the global random generator, so `random::set_seed`
+/// makes it reproducible.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenSampler {
+    /// 0 for greedy decoding
+    temperature: f32,
+    top_k: Option<usize>,
+    top_p: Option<f32>,
+}
+
+impl TokenSampler {
+    /// Always picks the most likely token
+    pub fn greedy() -> Self {
+        TokenSampler {
+            temperature: 0.0,
+            top_k: None,
+            top_p: None,
+        }
+    }
+
+    /// Samples from the whole vocabulary at `temperature`; lower values
+    /// make likely tokens more likely
+    pub fn new(temperature: f32) -> Result<Self, BellandeError> {
+        if !(temperature > 0.0 && temperature.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Temperature must be positive, got {}",
+                temperature
+            )));
+        }
+        Ok(TokenSampler {
+            temperature,
+            top_k: None,
+            top_p: None,
+        })
+    }
+
+    /// Samples only among the `top_k` most likely tokens
+    pub fn with_top_k(mut self, top_k: usize) -> Result<Self, BellandeError> {
+        if top_k == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "top_k must be at least 1".into(),
+            ));
+        }
+        self.top_k = Some(top_k);
+        Ok(self)
+    }
+
+    /// Samples only among the most likely tokens whose cumulative
+    /// probability first reaches `top_p`, in `(0, 1]`
+    pub fn with_top_p(mut self, top_p: f32) -> Result<Self, BellandeError> {
+        if !(top_p > 0.0 && top_p <= 1.0) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "top_p must be in (0, 1], got {}",
+                top_p
+            )));
+        }
+        self.top_p = Some(top_p);
+        Ok(self)
+    }
+
+    pub fn is_greedy(&self) -> bool {
+        self.temperature == 0.0
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn top_k(&self) -> Option<usize> {
+        self.top_k
+    }
+
+    pub fn top_p(&self) -> Option<f32> {
+        self.top_p
+    }
+
+    /// Index of the next token given the logits of one sequence
+    pub fn sample(&self, logits: &[f32]) -> Result<usize, BellandeError> {
+        if logits.is_empty() {
+            return Err(BellandeError::InvalidInputs);
+        }
+        if self.is_greedy() {
+            return Ok(argmax(logits));
+        }
+
+        // Candidates from most to least likely, ties in vocabulary order
+        let mut candidates: Vec<usize> = (0..logits.len()).collect();
+        candidates.sort_by(|&a, &b| logits[b].total_cmp(&logits[a]));
+        if let Some(top_k) = self.top_k {
+            candidates.truncate(top_k);
+        }
+
+        let max = logits[candidates[0]];
+        let mut probabilities: Vec<f32> = candidates
+            .iter()
+            .map(|&token| ((logits[token] - max) / self.temperature).exp())
+            .collect();
+        let total: f32 = probabilities.iter().sum();
+        probabilities.iter_mut().for_each(|p| *p /= total);
+
+        if let Some(top_p) = self.top_p {
+            let mut cumulative = 0.0;
+            let keep = probabilities
+                .iter()
+                .position(|&p| {
+                    cumulative += p;
+                    cumulative >= top_p
+                })
+                .map_or(probabilities.len(), |last| last + 1);
+            probabilities.truncate(keep);
+        }
+
+        let total: f32 = probabilities.iter().sum();
+        let mut threshold = random::uniform(0.0, 1.0, 1)[0] * total;
+        for (&token, &p) in candidates.iter().zip(&probabilities) {
+            if threshold < p {
+                return Ok(token);
+            }
+            threshold -= p;
+        }
+        // Rounding can leave the threshold just above the last probability
+        Ok(candidates[probabilities.len() - 1])
+    }
+}
+
+/// Index of the largest logit, the first one among ties
+pub fn argmax(logits: &[f32]) -> usize {
+    logits
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (i, &v)| {
+            if v > best.1 {
+                (i, v)
+            } else {
+                best
+            }
+        })
+        .0
+}
+
+/// Start and end tokens and the sampling strategy of `Transformer::generate`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationOptions {
+    pub bos: usize,
+    pub eos: usize,
+    pub sampler: TokenSampler,
+}
+
+impl GenerationOptions {
+    /// Greedy decoding from `bos` until `eos`
+    pub fn new(bos: usize, eos: usize) -> Self {
+        GenerationOptions {
+            bos,
+            eos,
+            sampler: TokenSampler::greedy(),
+        }
+    }
+
+    pub fn with_sampler(mut self, sampler: TokenSampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+}
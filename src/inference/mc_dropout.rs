@@ -0,0 +1,146 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::inference::tta;
+use crate::models::models::Model;
+
+/// Summary of `n_samples` stochastic forward passes with dropout active
+#[derive(Clone, Debug)]
+pub struct McDropoutPrediction {
+    /// Mean class probabilities, (batch_size, num_classes)
+    pub mean: Tensor,
+    /// Variance of each class probability across samples, (batch_size, num_classes)
+    pub variance: Tensor,
+    /// Entropy of the mean prediction, (batch_size,). High when the model
+    /// is uncertain for any reason.
+    pub entropy: Tensor,
+    /// Mutual information between prediction and weights, (batch_size,):
+    /// predictive entropy minus the mean per-sample entropy. High when the
+    /// samples disagree, i.e. model (epistemic) uncertainty.
+    pub mutual_information: Tensor,
+    pub n_samples: usize,
+}
+
+/// Runs `n_samples` forward passes with dropout sampling while every other
+/// layer stays in its current mode, and summarizes the class probabilities.
+/// Dropout is switched back off afterwards, also on error. Masks come from
+/// the framework generator, so results are reproducible under
+/// `random::set_seed`.
+pub fn predict_mc_dropout(
+    model: &mut dyn Model,
+    input: &Tensor,
+    n_samples: usize,
+) -> Result<McDropoutPrediction, BellandeError> {
+    predict_mc_dropout_scaled(model, input, n_samples, 1.0)
+}
+
+/// Like [`predict_mc_dropout`], dividing the logits by `temperature`
+pub(crate) fn predict_mc_dropout_scaled(
+    model: &mut dyn Model,
+    input: &Tensor,
+    n_samples: usize,
+    temperature: f32,
+) -> Result<McDropoutPrediction, BellandeError> {
+    if n_samples == 0 {
+        return Err(BellandeError::InvalidParameter(
+            "Monte Carlo dropout needs at least one sample".into(),
+        ));
+    }
+
+    model.set_dropout_active(true)?;
+    let result = sample(model, input, n_samples, temperature);
+    model.set_dropout_active(false)?;
+    result
+}
+
+fn sample(
+    model: &mut dyn Model,
+    input: &Tensor,
+    n_samples: usize,
+    temperature: f32,
+) -> Result<McDropoutPrediction, BellandeError> {
+    let mut sum: Vec<f64> = Vec::new();
+    let mut sum_sq: Vec<f64> = Vec::new();
+    let mut sample_entropy: Vec<f64> = Vec::new();
+    let mut shape = Vec::new();
+
+    for _ in 0..n_samples {
+        let probabilities = tta::softmax_rows_scaled(&model.forward(input)?, temperature)?;
+        if sum.is_empty() {
+            shape = probabilities.shape.clone();
+            sum = vec![0.0; probabilities.data.len()];
+            sum_sq = vec![0.0; probabilities.data.len()];
+            sample_entropy = vec![0.0; shape[0]];
+        }
+
+        for ((s, sq), &p) in sum
+            .iter_mut()
+            .zip(sum_sq.iter_mut())
+            .zip(probabilities.data.iter())
+        {
+            *s += p as f64;
+            *sq += p as f64 * p as f64;
+        }
+        for (entropy, row) in sample_entropy
+            .iter_mut()
+            .zip(probabilities.data.chunks(shape[1].max(1)))
+        {
+            *entropy += row_entropy(row.iter().map(|&p| p as f64));
+        }
+    }
+
+    let n = n_samples as f64;
+    let mean: Vec<f64> = sum.iter().map(|s| s / n).collect();
+    let variance: Vec<f32> = sum_sq
+        .iter()
+        .zip(mean.iter())
+        .map(|(sq, m)| (sq / n - m * m).max(0.0) as f32)
+        .collect();
+
+    let num_classes = shape[1].max(1);
+    let entropy: Vec<f64> = mean
+        .chunks(num_classes)
+        .map(|row| row_entropy(row.iter().copied()))
+        .collect();
+    let mutual_information: Vec<f32> = entropy
+        .iter()
+        .zip(sample_entropy.iter())
+        .map(|(total, expected)| (total - expected / n).max(0.0) as f32)
+        .collect();
+
+    let batch_size = shape[0];
+    let tensor = |data: Vec<f32>, shape: Vec<usize>| {
+        Tensor::new(data, shape, false, input.device.clone(), input.dtype)
+    };
+    Ok(McDropoutPrediction {
+        mean: tensor(mean.iter().map(|&m| m as f32).collect(), shape.clone()),
+        variance: tensor(variance, shape),
+        entropy: tensor(
+            entropy.iter().map(|&e| e as f32).collect(),
+            vec![batch_size],
+        ),
+        mutual_information: tensor(mutual_information, vec![batch_size]),
+        n_samples,
+    })
+}
+
+/// Shannon entropy in nats, treating 0 * ln(0) as 0
+fn row_entropy(probabilities: impl Iterator<Item = f64>) -> f64 {
+    -probabilities
+        .filter(|&p| p > 0.0)
+        .map(|p| p * p.ln())
+        .sum::<f64>()
+}
@@ -0,0 +1,10 @@
+pub mod batch;
+pub mod beam_search;
+pub mod calibration;
+pub mod explain;
+pub mod features;
+pub mod generation;
+pub mod mc_dropout;
+pub mod predictor;
+pub mod reload;
+pub mod tta;
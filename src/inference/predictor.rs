@@ -0,0 +1,156 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, error::BellandeError, tensor::Tensor};
+use crate::data::dataloader::DataLoader;
+use crate::inference::calibration::{self, TemperatureScaling};
+use crate::inference::mc_dropout::{self, McDropoutPrediction};
+use crate::inference::reload::ReloadWatcher;
+use crate::inference::tta::{self, TTAConfig};
+use crate::models::models::{self, Model};
+
+/// Evaluation-only wrapper around a trained model
+pub struct Predictor {
+    model: Box<dyn Model>,
+    device: Device,
+    temperature: f32,
+    pub(super) reload: Option<ReloadWatcher>,
+}
+
+impl Predictor {
+    /// Creates a new predictor, switching the model to evaluation mode
+    pub fn new(mut model: Box<dyn Model>, device: Device) -> Self {
+        model.eval();
+        Predictor {
+            model,
+            device,
+            temperature: 1.0,
+            reload: None,
+        }
+    }
+
+    /// Sets the softmax temperature applied to reported probabilities
+    pub fn with_temperature(mut self, temperature: f32) -> Result<Self, BellandeError> {
+        self.set_temperature(temperature)?;
+        Ok(self)
+    }
+
+    /// Returns the raw model outputs for a batch or a single image
+    pub fn predict(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let input = Self::ensure_batch(input)?;
+        self.apply_pending_reload();
+        self.model.forward(&input)
+    }
+
+    /// Returns class probabilities for a batch or a single image
+    pub fn predict_proba(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let logits = self.predict(input)?;
+        tta::softmax_rows_scaled(&logits, self.temperature)
+    }
+
+    /// Returns class probabilities averaged over the test-time augmented views
+    /// described by `tta_config`
+    pub fn predict_tta(
+        &mut self,
+        image: &Tensor,
+        tta_config: &TTAConfig,
+    ) -> Result<Tensor, BellandeError> {
+        let input = Self::ensure_batch(image)?;
+        self.apply_pending_reload();
+        tta::predict_tta_scaled(self.model.as_mut(), &input, tta_config, self.temperature)
+    }
+
+    /// Runs `n_samples` forward passes with dropout kept active and returns
+    /// the mean class probabilities with their variance and entropy, for
+    /// uncertainty estimates. The model otherwise stays in evaluation mode.
+    pub fn predict_mc_dropout(
+        &mut self,
+        input: &Tensor,
+        n_samples: usize,
+    ) -> Result<McDropoutPrediction, BellandeError> {
+        let input = Self::ensure_batch(input)?;
+        self.apply_pending_reload();
+        mc_dropout::predict_mc_dropout_scaled(
+            self.model.as_mut(),
+            &input,
+            n_samples,
+            self.temperature,
+        )
+    }
+
+    /// Fits the softmax temperature on a held-out validation set and applies
+    /// it to all subsequent probability predictions
+    pub fn calibrate(&mut self, loader: &DataLoader) -> Result<TemperatureScaling, BellandeError> {
+        let (logits, labels) = calibration::collect_logits(self.model.as_mut(), loader)?;
+        let scaling = calibration::fit_temperature(&logits, &labels)?;
+        self.temperature = scaling.temperature;
+        Ok(scaling)
+    }
+
+    /// Stores the current temperature in a model file written by `save`
+    pub fn save_calibration(&self, path: &str) -> Result<(), BellandeError> {
+        models::write_temperature(path, self.temperature)
+    }
+
+    /// Loads the temperature stored in a model file, keeping temperature 1
+    /// if the model was never calibrated
+    pub fn load_calibration(&mut self, path: &str) -> Result<(), BellandeError> {
+        self.temperature = models::read_temperature(path)?.unwrap_or(1.0);
+        Ok(())
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn set_temperature(&mut self, temperature: f32) -> Result<(), BellandeError> {
+        if !temperature.is_finite() || temperature <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Temperature must be positive, got {}",
+                temperature
+            )));
+        }
+        self.temperature = temperature;
+        Ok(())
+    }
+
+    pub fn model(&self) -> &dyn Model {
+        self.model.as_ref()
+    }
+
+    pub fn model_mut(&mut self) -> &mut dyn Model {
+        self.model.as_mut()
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Promotes a single (channels, height, width) image to a batch of one
+    fn ensure_batch(input: &Tensor) -> Result<Tensor, BellandeError> {
+        match input.shape.len() {
+            3 => {
+                let mut batched = input.clone();
+                batched.shape.insert(0, 1);
+                Ok(batched)
+            }
+            2 | 4 => Ok(input.clone()),
+            _ => Err(BellandeError::InvalidShape(format!(
+                "Unsupported input shape {:?}",
+                input.shape
+            ))),
+        }
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::inference::predictor::Predictor;
+use crate::models::models::read_state_dict;
+use crate::models::state_file::{is_state_file, LazyStateDict};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// How often `watch_and_reload` checks the watched path
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Validated weights waiting to be swapped into the model
+struct StagedWeights {
+    source: PathBuf,
+    state_dict: HashMap<String, Tensor>,
+}
+
+/// State shared between a predictor, its watcher thread and reload handles
+#[derive(Default)]
+struct ReloadShared {
+    stop: AtomicBool,
+    requested: AtomicBool,
+    generation: AtomicU64,
+    staged: Mutex<Option<StagedWeights>>,
+    last_loaded: Mutex<Option<PathBuf>>,
+    last_error: Mutex<Option<String>>,
+}
+
+impl ReloadShared {
+    fn record_error(&self, error: String) {
+        if let Ok(mut last_error) = self.last_error.lock() {
+            *last_error = Some(error);
+        }
+    }
+}
+
+/// Cloneable handle for signalling and monitoring a predictor's weight
+/// reloads from other threads
+#[derive(Clone)]
+pub struct ReloadHandle {
+    shared: Arc<ReloadShared>,
+    thread: Arc<thread::Thread>,
+}
+
+impl ReloadHandle {
+    /// Asks the watcher to reload the current checkpoint on its next poll,
+    /// even if the file has not changed
+    pub fn request_reload(&self) {
+        self.shared.requested.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+
+    /// Number of weight swaps applied so far
+    pub fn generation(&self) -> u64 {
+        self.shared.generation.load(Ordering::SeqCst)
+    }
+
+    /// Checkpoint of the last applied swap
+    pub fn last_loaded(&self) -> Option<PathBuf> {
+        self.shared.last_loaded.lock().ok()?.clone()
+    }
+
+    /// Last checkpoint that failed to load or validate; the model keeps its
+    /// previous weights in that case
+    pub fn last_error(&self) -> Option<String> {
+        self.shared.last_error.lock().ok()?.clone()
+    }
+}
+
+/// Background thread polling a checkpoint path, owned by a `Predictor`
+pub(crate) struct ReloadWatcher {
+    shared: Arc<ReloadShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ReloadWatcher {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Identity of a checkpoint file: path, modification time and size
+type FileSignature = (PathBuf, SystemTime, u64);
+
+/// The file to load for `path`: the file itself, or the most recently
+/// modified checkpoint in a directory, skipping checkpoint metadata files
+fn latest_checkpoint(path: &Path) -> Option<FileSignature> {
+    let signature = |path: PathBuf| {
+        let metadata = fs::metadata(&path).ok()?;
+        Some((path, metadata.modified().ok()?, metadata.len()))
+    };
+    if !path.is_dir() {
+        return signature(path.to_path_buf());
+    }
+    fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| !path.to_string_lossy().ends_with(".meta.json"))
+        .filter_map(signature)
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)))
+}
+
+/// Reads a binary tensor file or a JSON model file
+fn read_checkpoint(path: &Path) -> Result<HashMap<String, Tensor>, BellandeError> {
+    if is_state_file(path) {
+        LazyStateDict::open_with_mmap(path, false)?.to_state_dict()
+    } else {
+        let path = path.to_str().ok_or_else(|| {
+            BellandeError::InvalidParameter(format!("Invalid path {}", path.display()))
+        })?;
+        read_state_dict(path)
+    }
+}
+
+/// Checks that new weights cover exactly the model's tensors with the same
+/// shapes and hold only finite values
+fn validate_weights(
+    state_dict: &HashMap<String, Tensor>,
+    expected: &HashMap<String, Vec<usize>>,
+) -> Result<(), BellandeError> {
+    for (name, shape) in expected {
+        let tensor = state_dict
+            .get(name)
+            .ok_or_else(|| BellandeError::RuntimeError(format!("Missing parameter: {}", name)))?;
+        if &tensor.shape != shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Parameter {} has shape {:?}, the model expects {:?}",
+                name, tensor.shape, shape
+            )));
+        }
+        if tensor.data.iter().any(|v| !v.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Parameter {} holds non-finite values",
+                name
+            )));
+        }
+    }
+    if let Some(name) = state_dict.keys().find(|name| !expected.contains_key(*name)) {
+        return Err(BellandeError::RuntimeError(format!(
+            "Unexpected parameter: {}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Watcher loop: a changed file is loaded once its signature is stable
+/// across two polls, so partially written checkpoints are not picked up
+fn watch(
+    path: PathBuf,
+    interval: Duration,
+    expected: HashMap<String, Vec<usize>>,
+    shared: Arc<ReloadShared>,
+) {
+    let mut loaded = latest_checkpoint(&path);
+    let mut pending: Option<FileSignature> = None;
+
+    while !shared.stop.load(Ordering::SeqCst) {
+        thread::park_timeout(interval);
+        if shared.stop.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let current = latest_checkpoint(&path);
+        let requested = shared.requested.swap(false, Ordering::SeqCst);
+        if !requested && (current.is_none() || current == loaded) {
+            pending = None;
+            continue;
+        }
+        if !requested && current != pending {
+            pending = current;
+            continue;
+        }
+        pending = None;
+
+        let Some(signature) = current else {
+            shared.record_error(format!("No checkpoint found at {}", path.display()));
+            continue;
+        };
+        let staged = read_checkpoint(&signature.0).and_then(|state_dict| {
+            validate_weights(&state_dict, &expected)?;
+            Ok(state_dict)
+        });
+        match staged {
+            Ok(state_dict) => {
+                if let Ok(mut staged) = shared.staged.lock() {
+                    *staged = Some(StagedWeights {
+                        source: signature.0.clone(),
+                        state_dict,
+                    });
+                }
+            }
+            Err(e) => shared.record_error(format!("{}: {}", signature.0.display(), e)),
+        }
+        loaded = Some(signature);
+    }
+}
+
+impl Predictor {
+    /// Watches a checkpoint file, or a directory of checkpoints, and swaps
+    /// new weights into the model when it changes. Files are read and
+    /// validated on a background thread; the swap happens at the start of
+    /// the next prediction, so every request runs entirely on either the
+    /// old or the new weights. Replaces any previous watcher.
+    pub fn watch_and_reload(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<ReloadHandle, BellandeError> {
+        self.watch_and_reload_every(path, DEFAULT_POLL_INTERVAL)
+    }
+
+    /// `watch_and_reload` with a custom polling interval
+    pub fn watch_and_reload_every(
+        &mut self,
+        path: impl AsRef<Path>,
+        interval: Duration,
+    ) -> Result<ReloadHandle, BellandeError> {
+        let path = path.as_ref().to_path_buf();
+        if !path.exists() {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Cannot watch missing path {}",
+                path.display()
+            )));
+        }
+        if interval.is_zero() {
+            return Err(BellandeError::InvalidParameter(
+                "Polling interval must be positive".into(),
+            ));
+        }
+
+        self.stop_watching();
+        let expected = self
+            .model()
+            .state_dict()
+            .into_iter()
+            .map(|(name, tensor)| (name, tensor.shape))
+            .collect();
+        let shared = Arc::new(ReloadShared::default());
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("predictor-reload".into())
+                .spawn(move || watch(path, interval, expected, shared))
+                .map_err(BellandeError::IOError)?
+        };
+
+        let handle = ReloadHandle {
+            shared: Arc::clone(&shared),
+            thread: Arc::new(thread.thread().clone()),
+        };
+        self.reload = Some(ReloadWatcher {
+            shared,
+            thread: Some(thread),
+        });
+        Ok(handle)
+    }
+
+    /// Stops the watcher started by `watch_and_reload`, discarding weights
+    /// that were not swapped in yet
+    pub fn stop_watching(&mut self) {
+        self.reload = None;
+    }
+
+    /// Swaps in weights staged by the watcher, returning whether the model
+    /// changed. Called automatically before every prediction. If loading
+    /// fails the previous weights are restored and the error is reported
+    /// through `ReloadHandle::last_error`.
+    pub fn apply_pending_reload(&mut self) -> bool {
+        let Some(watcher) = &self.reload else {
+            return false;
+        };
+        let shared = Arc::clone(&watcher.shared);
+        let staged = match shared.staged.lock() {
+            Ok(mut staged) => staged.take(),
+            Err(_) => None,
+        };
+        let Some(staged) = staged else {
+            return false;
+        };
+
+        let previous = self.model().state_dict();
+        if let Err(e) = self.model_mut().load_state_dict(staged.state_dict) {
+            let _ = self.model_mut().load_state_dict(previous);
+            shared.record_error(format!("{}: {}", staged.source.display(), e));
+            return false;
+        }
+        if let Ok(mut last_loaded) = shared.last_loaded.lock() {
+            *last_loaded = Some(staged.source);
+        }
+        shared.generation.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+}
@@ -0,0 +1,363 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::activation::softmax;
+use crate::models::models::Model;
+
+/// Test-time augmentation configuration.
+///
+/// Every enabled view is evaluated independently and the class probabilities
+/// are averaged. The untransformed input is always included.
+#[derive(Clone, Debug)]
+pub struct TTAConfig {
+    pub horizontal_flip: bool,
+    pub vertical_flip: bool,
+    /// Fraction of the image kept by each of the five crops (corners + center)
+    pub five_crop: Option<f32>,
+    /// Zoom factors; views are resized and then center-cropped or padded back
+    pub scales: Vec<f32>,
+}
+
+impl TTAConfig {
+    /// Creates a configuration that only evaluates the original input
+    pub fn new() -> Self {
+        TTAConfig {
+            horizontal_flip: false,
+            vertical_flip: false,
+            five_crop: None,
+            scales: Vec::new(),
+        }
+    }
+
+    pub fn with_horizontal_flip(mut self) -> Self {
+        self.horizontal_flip = true;
+        self
+    }
+
+    pub fn with_vertical_flip(mut self) -> Self {
+        self.vertical_flip = true;
+        self
+    }
+
+    pub fn with_five_crop(mut self, ratio: f32) -> Self {
+        self.five_crop = Some(ratio);
+        self
+    }
+
+    pub fn with_scales(mut self, scales: Vec<f32>) -> Self {
+        self.scales = scales;
+        self
+    }
+
+    /// Number of forward passes a single prediction will take
+    pub fn num_views(&self) -> usize {
+        1 + self.horizontal_flip as usize
+            + self.vertical_flip as usize
+            + if self.five_crop.is_some() { 5 } else { 0 }
+            + self.scales.len()
+    }
+
+    fn validate(&self) -> Result<(), BellandeError> {
+        if let Some(ratio) = self.five_crop {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                return Err(BellandeError::InvalidConfiguration(format!(
+                    "Five-crop ratio must be in (0, 1], got {}",
+                    ratio
+                )));
+            }
+        }
+        if let Some(scale) = self.scales.iter().find(|&&s| s <= 0.0) {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "TTA scales must be positive, got {}",
+                scale
+            )));
+        }
+        Ok(())
+    }
+
+    /// Builds every augmented view of a (batch, channels, height, width) tensor
+    pub fn views(&self, input: &Tensor) -> Result<Vec<Tensor>, BellandeError> {
+        self.validate()?;
+
+        if input.shape.len() != 4 {
+            return Err(BellandeError::InvalidShape(
+                "Expected 4D tensor (batch_size, channels, height, width)".into(),
+            ));
+        }
+
+        let (height, width) = (input.shape[2], input.shape[3]);
+        let mut views = vec![input.clone()];
+
+        if self.horizontal_flip {
+            views.push(flip(input, false));
+        }
+        if self.vertical_flip {
+            views.push(flip(input, true));
+        }
+
+        if let Some(ratio) = self.five_crop {
+            let crop_h = ((height as f32 * ratio).round() as usize).clamp(1, height);
+            let crop_w = ((width as f32 * ratio).round() as usize).clamp(1, width);
+            let origins = [
+                (0, 0),
+                (0, width - crop_w),
+                (height - crop_h, 0),
+                (height - crop_h, width - crop_w),
+                ((height - crop_h) / 2, (width - crop_w) / 2),
+            ];
+            for (top, left) in origins {
+                let cropped = crop(input, top, left, crop_h, crop_w);
+                views.push(resize_bilinear(&cropped, height, width));
+            }
+        }
+
+        for &scale in &self.scales {
+            let scaled_h = ((height as f32 * scale).round() as usize).max(1);
+            let scaled_w = ((width as f32 * scale).round() as usize).max(1);
+            let resized = resize_bilinear(input, scaled_h, scaled_w);
+            views.push(fit_center(&resized, height, width));
+        }
+
+        Ok(views)
+    }
+}
+
+impl Default for TTAConfig {
+    fn default() -> Self {
+        Self::new().with_horizontal_flip()
+    }
+}
+
+/// Runs the model over every TTA view and returns the averaged class
+/// probabilities with shape (batch_size, num_classes)
+pub fn predict_tta(
+    model: &mut dyn Model,
+    input: &Tensor,
+    config: &TTAConfig,
+) -> Result<Tensor, BellandeError> {
+    predict_tta_scaled(model, input, config, 1.0)
+}
+
+/// Like [`predict_tta`], dividing the logits by `temperature`
+pub(crate) fn predict_tta_scaled(
+    model: &mut dyn Model,
+    input: &Tensor,
+    config: &TTAConfig,
+    temperature: f32,
+) -> Result<Tensor, BellandeError> {
+    let views = config.views(input)?;
+    let num_views = views.len() as f32;
+
+    let mut averaged: Option<Tensor> = None;
+    for view in views {
+        let probabilities = softmax_rows_scaled(&model.forward(&view)?, temperature)?;
+        averaged = Some(match averaged {
+            None => probabilities,
+            Some(mut sum) => {
+                for (s, p) in sum.data.iter_mut().zip(probabilities.data.iter()) {
+                    *s += p;
+                }
+                sum
+            }
+        });
+    }
+
+    let mut averaged = averaged.ok_or(BellandeError::InvalidInputs)?;
+    averaged.data.iter_mut().for_each(|p| *p /= num_views);
+    averaged.requires_grad = false;
+    Ok(averaged)
+}
+
+/// Softmax of `logits / temperature` over the last dimension
+pub(crate) fn softmax_rows_scaled(
+    logits: &Tensor,
+    temperature: f32,
+) -> Result<Tensor, BellandeError> {
+    if logits.shape.len() != 2 {
+        return Err(BellandeError::InvalidShape(
+            "Expected 2D tensor (batch_size, num_classes)".into(),
+        ));
+    }
+
+    let scaled = Tensor::new(
+        logits.data.iter().map(|&x| x / temperature).collect(),
+        logits.shape.clone(),
+        false,
+        logits.device.clone(),
+        logits.dtype,
+    );
+    softmax(&scaled, -1)
+}
+
+pub(crate) fn flip(input: &Tensor, vertical: bool) -> Tensor {
+    let (batch_size, channels, height, width) = (
+        input.shape[0],
+        input.shape[1],
+        input.shape[2],
+        input.shape[3],
+    );
+
+    let mut output = vec![0.0; input.data.len()];
+    for b in 0..batch_size {
+        for c in 0..channels {
+            for h in 0..height {
+                for w in 0..width {
+                    let (src_h, src_w) = if vertical {
+                        (height - 1 - h, w)
+                    } else {
+                        (h, width - 1 - w)
+                    };
+                    let src_idx = ((b * channels + c) * height + src_h) * width + src_w;
+                    let dst_idx = ((b * channels + c) * height + h) * width + w;
+                    output[dst_idx] = input.data[src_idx];
+                }
+            }
+        }
+    }
+
+    Tensor::new(
+        output,
+        input.shape.clone(),
+        false,
+        input.device.clone(),
+        input.dtype,
+    )
+}
+
+pub(crate) fn crop(
+    input: &Tensor,
+    top: usize,
+    left: usize,
+    crop_h: usize,
+    crop_w: usize,
+) -> Tensor {
+    let (batch_size, channels, height, width) = (
+        input.shape[0],
+        input.shape[1],
+        input.shape[2],
+        input.shape[3],
+    );
+
+    let mut output = vec![0.0; batch_size * channels * crop_h * crop_w];
+    for b in 0..batch_size {
+        for c in 0..channels {
+            for h in 0..crop_h {
+                for w in 0..crop_w {
+                    let src_idx = ((b * channels + c) * height + top + h) * width + left + w;
+                    let dst_idx = ((b * channels + c) * crop_h + h) * crop_w + w;
+                    output[dst_idx] = input.data[src_idx];
+                }
+            }
+        }
+    }
+
+    Tensor::new(
+        output,
+        vec![batch_size, channels, crop_h, crop_w],
+        false,
+        input.device.clone(),
+        input.dtype,
+    )
+}
+
+/// Center-crops views larger than the target and zero-pads smaller ones
+fn fit_center(input: &Tensor, target_h: usize, target_w: usize) -> Tensor {
+    let (batch_size, channels, height, width) = (
+        input.shape[0],
+        input.shape[1],
+        input.shape[2],
+        input.shape[3],
+    );
+
+    let mut output = vec![0.0; batch_size * channels * target_h * target_w];
+    for b in 0..batch_size {
+        for c in 0..channels {
+            for h in 0..target_h {
+                let src_h = h as isize + (height as isize - target_h as isize) / 2;
+                if src_h < 0 || src_h >= height as isize {
+                    continue;
+                }
+                for w in 0..target_w {
+                    let src_w = w as isize + (width as isize - target_w as isize) / 2;
+                    if src_w < 0 || src_w >= width as isize {
+                        continue;
+                    }
+                    let src_idx =
+                        ((b * channels + c) * height + src_h as usize) * width + src_w as usize;
+                    let dst_idx = ((b * channels + c) * target_h + h) * target_w + w;
+                    output[dst_idx] = input.data[src_idx];
+                }
+            }
+        }
+    }
+
+    Tensor::new(
+        output,
+        vec![batch_size, channels, target_h, target_w],
+        false,
+        input.device.clone(),
+        input.dtype,
+    )
+}
+
+/// Bilinearly resizes a (batch, channels, height, width) tensor
+pub(crate) fn resize_bilinear(input: &Tensor, out_h: usize, out_w: usize) -> Tensor {
+    let (batch_size, channels, height, width) = (
+        input.shape[0],
+        input.shape[1],
+        input.shape[2],
+        input.shape[3],
+    );
+
+    let scale_h = height as f32 / out_h as f32;
+    let scale_w = width as f32 / out_w as f32;
+    let mut output = vec![0.0; batch_size * channels * out_h * out_w];
+
+    for b in 0..batch_size {
+        for c in 0..channels {
+            let plane = (b * channels + c) * height * width;
+            for h in 0..out_h {
+                let src_h = ((h as f32 + 0.5) * scale_h - 0.5).max(0.0);
+                let h0 = (src_h.floor() as usize).min(height - 1);
+                let h1 = (h0 + 1).min(height - 1);
+                let dh = src_h - h0 as f32;
+
+                for w in 0..out_w {
+                    let src_w = ((w as f32 + 0.5) * scale_w - 0.5).max(0.0);
+                    let w0 = (src_w.floor() as usize).min(width - 1);
+                    let w1 = (w0 + 1).min(width - 1);
+                    let dw = src_w - w0 as f32;
+
+                    let top = input.data[plane + h0 * width + w0] * (1.0 - dw)
+                        + input.data[plane + h0 * width + w1] * dw;
+                    let bottom = input.data[plane + h1 * width + w0] * (1.0 - dw)
+                        + input.data[plane + h1 * width + w1] * dw;
+
+                    output[((b * channels + c) * out_h + h) * out_w + w] =
+                        top * (1.0 - dh) + bottom * dh;
+                }
+            }
+        }
+    }
+
+    Tensor::new(
+        output,
+        vec![batch_size, channels, out_h, out_w],
+        false,
+        input.device.clone(),
+        input.dtype,
+    )
+}
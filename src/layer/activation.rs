@@ -13,7 +13,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::core::{error::BellandeError, numerics::log_sum_exp, tensor::Tensor};
+use crate::layer::{ChannelRole, Layer};
 
 pub trait Activation {
     fn forward(&self, input: &Tensor) -> Result<Tensor, BellandeError>;
@@ -78,7 +79,15 @@ impl Activation for ReLU {
     }
 }
 
-pub struct Sigmoid;
+pub struct Sigmoid {
+    output: Option<Tensor>,
+}
+
+impl Sigmoid {
+    pub fn new() -> Self {
+        Sigmoid { output: None }
+    }
+}
 
 impl Activation for Sigmoid {
     fn forward(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
@@ -116,3 +125,675 @@ impl Activation for Sigmoid {
         ))
     }
 }
+
+impl Layer for ReLU {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.mask = Some(input.data.iter().map(|&x| x >= 0.0).collect());
+        Activation::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        Activation::backward(self, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+impl Layer for Sigmoid {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let output = Activation::forward(self, input)?;
+        self.output = Some(output.clone());
+        Ok(output)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let output = self
+            .output
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+
+        let grad_input = grad
+            .data
+            .iter()
+            .zip(output.data.iter())
+            .map(|(&g, &s)| g * s * (1.0 - s))
+            .collect();
+
+        Ok(Tensor::new(
+            grad_input,
+            grad.shape.clone(),
+            true,
+            grad.device.clone(),
+            grad.dtype,
+        ))
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+/// Which formula `gelu` evaluates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeluApproximation {
+    /// `x * Φ(x)` with the Gaussian CDF computed from `erf`
+    Exact,
+    /// `0.5 * x * (1 + tanh(sqrt(2 / π) * (x + 0.044715 * x³)))`
+    Tanh,
+}
+
+fn map(input: &Tensor, f: impl Fn(f32) -> f32) -> Tensor {
+    Tensor::new(
+        input.data.iter().map(|&x| f(x)).collect(),
+        input.shape.clone(),
+        input.requires_grad,
+        input.device.clone(),
+        input.dtype,
+    )
+}
+
+/// Multiplies `grad_output` by the derivative evaluated at each input
+fn map_grad(
+    input: &Tensor,
+    grad_output: &Tensor,
+    derivative: impl Fn(f32) -> f32,
+) -> Result<Tensor, BellandeError> {
+    if input.shape != grad_output.shape {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Gradient shape {:?} does not match input shape {:?}",
+            grad_output.shape, input.shape
+        )));
+    }
+    let grad = input
+        .data
+        .iter()
+        .zip(grad_output.data.iter())
+        .map(|(&x, &g)| g * derivative(x))
+        .collect();
+    Ok(Tensor::new(
+        grad,
+        grad_output.shape.clone(),
+        true,
+        grad_output.device.clone(),
+        grad_output.dtype,
+    ))
+}
+
+pub(crate) fn sigmoid(x: f32) -> f32 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
+/// `log(1 + exp(x))` without overflow
+pub(crate) fn softplus_scalar(x: f32) -> f32 {
+    x.max(0.0) + (-x.abs()).exp().ln_1p()
+}
+
+/// Abramowitz and Stegun 7.1.26, accurate to about 1.5e-7
+fn erf(x: f32) -> f32 {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x.abs());
+    let poly = t
+        * (0.254_829_6
+            + t * (-0.284_496_74 + t * (1.421_413_7 + t * (-1.453_152_1 + t * 1.061_405_4))));
+    (1.0 - poly * (-x * x).exp()).copysign(x)
+}
+
+const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+const GELU_CUBIC: f32 = 0.044_715;
+
+pub fn leaky_relu(input: &Tensor, negative_slope: f32) -> Tensor {
+    map(input, |x| if x > 0.0 { x } else { negative_slope * x })
+}
+
+pub fn leaky_relu_backward(
+    input: &Tensor,
+    grad_output: &Tensor,
+    negative_slope: f32,
+) -> Result<Tensor, BellandeError> {
+    map_grad(input, grad_output, |x| {
+        if x > 0.0 {
+            1.0
+        } else {
+            negative_slope
+        }
+    })
+}
+
+pub fn elu(input: &Tensor, alpha: f32) -> Tensor {
+    map(input, |x| if x > 0.0 { x } else { alpha * x.exp_m1() })
+}
+
+pub fn elu_backward(
+    input: &Tensor,
+    grad_output: &Tensor,
+    alpha: f32,
+) -> Result<Tensor, BellandeError> {
+    map_grad(input, grad_output, |x| {
+        if x > 0.0 {
+            1.0
+        } else {
+            alpha * x.exp()
+        }
+    })
+}
+
+pub fn gelu(input: &Tensor, approximation: GeluApproximation) -> Tensor {
+    match approximation {
+        GeluApproximation::Exact => map(input, |x| 0.5 * x * (1.0 + erf(x / 2f32.sqrt()))),
+        GeluApproximation::Tanh => map(input, |x| {
+            0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + GELU_CUBIC * x * x * x)).tanh())
+        }),
+    }
+}
+
+pub fn gelu_backward(
+    input: &Tensor,
+    grad_output: &Tensor,
+    approximation: GeluApproximation,
+) -> Result<Tensor, BellandeError> {
+    match approximation {
+        GeluApproximation::Exact => map_grad(input, grad_output, |x| {
+            let cdf = 0.5 * (1.0 + erf(x / 2f32.sqrt()));
+            let pdf = (-0.5 * x * x).exp() / (2.0 * std::f32::consts::PI).sqrt();
+            cdf + x * pdf
+        }),
+        GeluApproximation::Tanh => map_grad(input, grad_output, |x| {
+            let t = (SQRT_2_OVER_PI * (x + GELU_CUBIC * x * x * x)).tanh();
+            let du = SQRT_2_OVER_PI * (1.0 + 3.0 * GELU_CUBIC * x * x);
+            0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * du
+        }),
+    }
+}
+
+/// `x * sigmoid(x)`, also known as swish
+pub fn silu(input: &Tensor) -> Tensor {
+    map(input, |x| x * sigmoid(x))
+}
+
+pub fn silu_backward(input: &Tensor, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+    map_grad(input, grad_output, |x| {
+        let s = sigmoid(x);
+        s * (1.0 + x * (1.0 - s))
+    })
+}
+
+/// `x * tanh(softplus(x))`
+pub fn mish(input: &Tensor) -> Tensor {
+    map(input, |x| x * softplus_scalar(x).tanh())
+}
+
+pub fn mish_backward(input: &Tensor, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+    map_grad(input, grad_output, |x| {
+        let t = softplus_scalar(x).tanh();
+        t + x * (1.0 - t * t) * sigmoid(x)
+    })
+}
+
+/// `log(1 + exp(beta * x)) / beta`, reverting to the identity once
+/// `beta * x` exceeds `threshold`
+pub fn softplus(input: &Tensor, beta: f32, threshold: f32) -> Tensor {
+    map(input, |x| {
+        if beta * x > threshold {
+            x
+        } else {
+            softplus_scalar(beta * x) / beta
+        }
+    })
+}
+
+pub fn softplus_backward(
+    input: &Tensor,
+    grad_output: &Tensor,
+    beta: f32,
+    threshold: f32,
+) -> Result<Tensor, BellandeError> {
+    map_grad(input, grad_output, |x| {
+        if beta * x > threshold {
+            1.0
+        } else {
+            sigmoid(beta * x)
+        }
+    })
+}
+
+/// `x * relu6(x + 3) / 6`
+pub fn hardswish(input: &Tensor) -> Tensor {
+    map(input, |x| x * (x + 3.0).clamp(0.0, 6.0) / 6.0)
+}
+
+pub fn hardswish_backward(input: &Tensor, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+    map_grad(input, grad_output, |x| {
+        if x < -3.0 {
+            0.0
+        } else if x <= 3.0 {
+            x / 3.0 + 0.5
+        } else {
+            1.0
+        }
+    })
+}
+
+fn cached_input(input: &Option<Tensor>) -> Result<&Tensor, BellandeError> {
+    input
+        .as_ref()
+        .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))
+}
+
+pub struct LeakyReLU {
+    negative_slope: f32,
+    input: Option<Tensor>,
+}
+
+impl LeakyReLU {
+    /// PyTorch's default slope is 0.01
+    pub fn new(negative_slope: f32) -> Self {
+        LeakyReLU {
+            negative_slope,
+            input: None,
+        }
+    }
+}
+
+impl Layer for LeakyReLU {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.input = Some(input.clone());
+        Ok(leaky_relu(input, self.negative_slope))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        leaky_relu_backward(cached_input(&self.input)?, grad, self.negative_slope)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+pub struct ELU {
+    alpha: f32,
+    input: Option<Tensor>,
+}
+
+impl ELU {
+    pub fn new(alpha: f32) -> Self {
+        ELU { alpha, input: None }
+    }
+}
+
+impl Layer for ELU {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.input = Some(input.clone());
+        Ok(elu(input, self.alpha))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        elu_backward(cached_input(&self.input)?, grad, self.alpha)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+pub struct GELU {
+    approximation: GeluApproximation,
+    input: Option<Tensor>,
+}
+
+impl GELU {
+    pub fn new() -> Self {
+        GELU {
+            approximation: GeluApproximation::Exact,
+            input: None,
+        }
+    }
+
+    pub fn with_approximation(mut self, approximation: GeluApproximation) -> Self {
+        self.approximation = approximation;
+        self
+    }
+}
+
+impl Default for GELU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for GELU {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.input = Some(input.clone());
+        Ok(gelu(input, self.approximation))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        gelu_backward(cached_input(&self.input)?, grad, self.approximation)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+pub struct SiLU {
+    input: Option<Tensor>,
+}
+
+impl SiLU {
+    pub fn new() -> Self {
+        SiLU { input: None }
+    }
+}
+
+impl Default for SiLU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for SiLU {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.input = Some(input.clone());
+        Ok(silu(input))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        silu_backward(cached_input(&self.input)?, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+pub struct Mish {
+    input: Option<Tensor>,
+}
+
+impl Mish {
+    pub fn new() -> Self {
+        Mish { input: None }
+    }
+}
+
+impl Default for Mish {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for Mish {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.input = Some(input.clone());
+        Ok(mish(input))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        mish_backward(cached_input(&self.input)?, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+pub struct Softplus {
+    beta: f32,
+    threshold: f32,
+    input: Option<Tensor>,
+}
+
+impl Softplus {
+    /// PyTorch's defaults are `beta = 1` and `threshold = 20`
+    pub fn new(beta: f32, threshold: f32) -> Result<Self, BellandeError> {
+        if !beta.is_finite() || beta <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Softplus beta must be positive, got {}",
+                beta
+            )));
+        }
+        Ok(Softplus {
+            beta,
+            threshold,
+            input: None,
+        })
+    }
+}
+
+impl Layer for Softplus {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.input = Some(input.clone());
+        Ok(softplus(input, self.beta, self.threshold))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        softplus_backward(cached_input(&self.input)?, grad, self.beta, self.threshold)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+pub struct Hardswish {
+    input: Option<Tensor>,
+}
+
+impl Hardswish {
+    pub fn new() -> Self {
+        Hardswish { input: None }
+    }
+}
+
+impl Default for Hardswish {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for Hardswish {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.input = Some(input.clone());
+        Ok(hardswish(input))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        hardswish_backward(cached_input(&self.input)?, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+/// Splits `shape` around `dim` (negative values count from the end) into
+/// (outer, dim_len, inner) sizes
+fn softmax_axes(shape: &[usize], dim: isize) -> Result<(usize, usize, usize), BellandeError> {
+    let rank = shape.len() as isize;
+    let axis = if dim < 0 { dim + rank } else { dim };
+    if axis < 0 || axis >= rank {
+        return Err(BellandeError::InvalidShape(format!(
+            "Dimension {} out of range for shape {:?}",
+            dim, shape
+        )));
+    }
+    let axis = axis as usize;
+    Ok((
+        shape[..axis].iter().product(),
+        shape[axis],
+        shape[axis + 1..].iter().product(),
+    ))
+}
+
+/// Applies `f(values, output)` to every slice of `data` along `dim`
+fn along_dim(
+    data: &[f32],
+    shape: &[usize],
+    dim: isize,
+    f: impl Fn(&[f32], &mut [f32]),
+) -> Result<Vec<f32>, BellandeError> {
+    let (outer, len, inner) = softmax_axes(shape, dim)?;
+    let mut output = vec![0.0; data.len()];
+    let mut values = vec![0.0; len];
+    let mut result = vec![0.0; len];
+    for o in 0..outer {
+        for i in 0..inner {
+            let index = |k: usize| (o * len + k) * inner + i;
+            for (k, v) in values.iter_mut().enumerate() {
+                *v = data[index(k)];
+            }
+            f(&values, &mut result);
+            for (k, &r) in result.iter().enumerate() {
+                output[index(k)] = r;
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn log_softmax_slice(values: &[f32], output: &mut [f32]) {
+    let log_sum = log_sum_exp(values);
+    for (o, v) in output.iter_mut().zip(values) {
+        *o = v - log_sum;
+    }
+}
+
+pub fn softmax(input: &Tensor, dim: isize) -> Result<Tensor, BellandeError> {
+    let output = along_dim(&input.data, &input.shape, dim, |values, output| {
+        log_softmax_slice(values, output);
+        output.iter_mut().for_each(|o| *o = o.exp());
+    })?;
+    Ok(Tensor::new(
+        output,
+        input.shape.clone(),
+        input.requires_grad,
+        input.device.clone(),
+        input.dtype,
+    ))
+}
+
+pub fn log_softmax(input: &Tensor, dim: isize) -> Result<Tensor, BellandeError> {
+    let output = along_dim(&input.data, &input.shape, dim, log_softmax_slice)?;
+    Ok(Tensor::new(
+        output,
+        input.shape.clone(),
+        input.requires_grad,
+        input.device.clone(),
+        input.dtype,
+    ))
+}
+
+/// Gradient `y * (g - sum(g * y))` from the softmax output `y`
+pub fn softmax_backward(
+    output: &Tensor,
+    grad_output: &Tensor,
+    dim: isize,
+) -> Result<Tensor, BellandeError> {
+    jacobian_product(output, grad_output, dim, |y, g| {
+        let dot: f32 = y.iter().zip(g).map(|(y, g)| y * g).sum();
+        y.iter().zip(g).map(|(y, g)| y * (g - dot)).collect()
+    })
+}
+
+/// Gradient `g - exp(y) * sum(g)` from the log-softmax output `y`
+pub fn log_softmax_backward(
+    output: &Tensor,
+    grad_output: &Tensor,
+    dim: isize,
+) -> Result<Tensor, BellandeError> {
+    jacobian_product(output, grad_output, dim, |y, g| {
+        let sum: f32 = g.iter().sum();
+        y.iter().zip(g).map(|(y, g)| g - y.exp() * sum).collect()
+    })
+}
+
+/// Applies `f(output, grad)` to every pair of slices along `dim`
+fn jacobian_product(
+    output: &Tensor,
+    grad_output: &Tensor,
+    dim: isize,
+    f: impl Fn(&[f32], &[f32]) -> Vec<f32>,
+) -> Result<Tensor, BellandeError> {
+    if output.shape != grad_output.shape {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Gradient shape {:?} does not match output shape {:?}",
+            grad_output.shape, output.shape
+        )));
+    }
+    let (outer, len, inner) = softmax_axes(&output.shape, dim)?;
+    let mut grad = vec![0.0; output.data.len()];
+    for o in 0..outer {
+        for i in 0..inner {
+            let indices: Vec<usize> = (0..len).map(|k| (o * len + k) * inner + i).collect();
+            let y: Vec<f32> = indices.iter().map(|&j| output.data[j]).collect();
+            let g: Vec<f32> = indices.iter().map(|&j| grad_output.data[j]).collect();
+            for (&j, value) in indices.iter().zip(f(&y, &g)) {
+                grad[j] = value;
+            }
+        }
+    }
+    Ok(Tensor::new(
+        grad,
+        grad_output.shape.clone(),
+        true,
+        grad_output.device.clone(),
+        grad_output.dtype,
+    ))
+}
+
+/// Normalizes inputs into probabilities along `dim`
+pub struct Softmax {
+    dim: isize,
+    output: Option<Tensor>,
+}
+
+impl Softmax {
+    /// Negative `dim` counts from the last dimension, so `-1` is the class
+    /// dimension of (batch_size, num_classes) logits
+    pub fn new(dim: isize) -> Self {
+        Softmax { dim, output: None }
+    }
+}
+
+impl Layer for Softmax {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let output = softmax(input, self.dim)?;
+        self.output = Some(output.clone());
+        Ok(output)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        softmax_backward(cached_input(&self.output)?, grad, self.dim)
+    }
+}
+
+/// Log-probabilities along `dim`, computed stably as `x - logsumexp(x)`;
+/// pairs with `NLLLoss`
+pub struct LogSoftmax {
+    dim: isize,
+    output: Option<Tensor>,
+}
+
+impl LogSoftmax {
+    pub fn new(dim: isize) -> Self {
+        LogSoftmax { dim, output: None }
+    }
+}
+
+impl Layer for LogSoftmax {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let output = log_softmax(input, self.dim)?;
+        self.output = Some(output.clone());
+        Ok(output)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        log_softmax_backward(cached_input(&self.output)?, grad, self.dim)
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{ChannelRole, Layer};
+
+/// Average pooling to a fixed (height, width) output for any input
+/// resolution. Output cell `i` covers input rows
+/// `floor(i * H / out_h)..ceil((i + 1) * H / out_h)`, so windows may overlap
+/// by one when the sizes do not divide.
+pub struct AdaptiveAvgPool2d {
+    output_size: (usize, usize),
+    input_shape: Option<Vec<usize>>,
+}
+
+/// Max pooling to a fixed (height, width) output for any input resolution,
+/// with the same windows as `AdaptiveAvgPool2d`
+pub struct AdaptiveMaxPool2d {
+    output_size: (usize, usize),
+    input_shape: Option<Vec<usize>>,
+    indices: Option<Vec<usize>>,
+}
+
+/// Averages each feature map to a single value, turning (batch, channels,
+/// height, width) into (batch, channels), or (batch, channels, 1, 1) with
+/// `with_keep_dims`
+pub struct GlobalAvgPool2d {
+    keep_dims: bool,
+    input_shape: Option<Vec<usize>>,
+}
+
+/// Input range pooled into output cell `index` of `output_len`
+fn window(index: usize, input_len: usize, output_len: usize) -> std::ops::Range<usize> {
+    let start = index * input_len / output_len;
+    let end = ((index + 1) * input_len).div_ceil(output_len);
+    start..end
+}
+
+/// Validates a (batch, channels, height, width) input and returns its
+/// dimensions
+fn dimensions(
+    input: &Tensor,
+    output_size: (usize, usize),
+) -> Result<(usize, usize, usize, usize), BellandeError> {
+    if input.shape.len() != 4 {
+        return Err(BellandeError::InvalidShape(
+            "Expected 4D tensor (batch_size, channels, height, width)".into(),
+        ));
+    }
+    if output_size.0 == 0 || output_size.1 == 0 {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Output size {:?} must be positive",
+            output_size
+        )));
+    }
+    if input.shape[2] == 0 || input.shape[3] == 0 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Cannot pool an empty feature map of shape {:?}",
+            input.shape
+        )));
+    }
+    Ok((
+        input.shape[0],
+        input.shape[1],
+        input.shape[2],
+        input.shape[3],
+    ))
+}
+
+/// Calls `f(plane, oh, ow, rows, cols)` for every output cell, where `plane`
+/// indexes the (batch * channels) feature maps
+fn for_each_window(
+    planes: usize,
+    (height, width): (usize, usize),
+    (out_h, out_w): (usize, usize),
+    mut f: impl FnMut(usize, usize, usize, std::ops::Range<usize>, std::ops::Range<usize>),
+) {
+    for plane in 0..planes {
+        for oh in 0..out_h {
+            for ow in 0..out_w {
+                f(
+                    plane,
+                    oh,
+                    ow,
+                    window(oh, height, out_h),
+                    window(ow, width, out_w),
+                );
+            }
+        }
+    }
+}
+
+impl AdaptiveAvgPool2d {
+    pub fn new(output_size: (usize, usize)) -> Self {
+        AdaptiveAvgPool2d {
+            output_size,
+            input_shape: None,
+        }
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let (batch_size, channels, height, width) = dimensions(input, self.output_size)?;
+        let (out_h, out_w) = self.output_size;
+        let mut output = vec![0.0; batch_size * channels * out_h * out_w];
+
+        for_each_window(
+            batch_size * channels,
+            (height, width),
+            self.output_size,
+            |plane, oh, ow, rows, cols| {
+                let count = (rows.len() * cols.len()) as f32;
+                let base = plane * height * width;
+                let sum: f32 = rows
+                    .flat_map(|h| cols.clone().map(move |w| base + h * width + w))
+                    .map(|i| input.data[i])
+                    .sum();
+                output[(plane * out_h + oh) * out_w + ow] = sum / count;
+            },
+        );
+
+        self.input_shape = Some(input.shape.clone());
+        Ok(Tensor::new(
+            output,
+            vec![batch_size, channels, out_h, out_w],
+            input.requires_grad,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    pub fn backward(&self, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+        let shape = self
+            .input_shape
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let (batch_size, channels, height, width) = (shape[0], shape[1], shape[2], shape[3]);
+        let (out_h, out_w) = self.output_size;
+        if grad_output.data.len() != batch_size * channels * out_h * out_w {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected output gradient of shape {:?}, got {:?}",
+                [batch_size, channels, out_h, out_w],
+                grad_output.shape
+            )));
+        }
+
+        let mut grad_input = vec![0.0; shape.iter().product()];
+        for_each_window(
+            batch_size * channels,
+            (height, width),
+            self.output_size,
+            |plane, oh, ow, rows, cols| {
+                let count = (rows.len() * cols.len()) as f32;
+                let grad = grad_output.data[(plane * out_h + oh) * out_w + ow] / count;
+                let base = plane * height * width;
+                for h in rows {
+                    for w in cols.clone() {
+                        grad_input[base + h * width + w] += grad;
+                    }
+                }
+            },
+        );
+
+        Ok(Tensor::new(
+            grad_input,
+            shape.clone(),
+            true,
+            grad_output.device.clone(),
+            grad_output.dtype,
+        ))
+    }
+}
+
+impl AdaptiveMaxPool2d {
+    pub fn new(output_size: (usize, usize)) -> Self {
+        AdaptiveMaxPool2d {
+            output_size,
+            input_shape: None,
+            indices: None,
+        }
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let (batch_size, channels, height, width) = dimensions(input, self.output_size)?;
+        let (out_h, out_w) = self.output_size;
+        let mut output = vec![0.0; batch_size * channels * out_h * out_w];
+        let mut indices = vec![0; output.len()];
+
+        for_each_window(
+            batch_size * channels,
+            (height, width),
+            self.output_size,
+            |plane, oh, ow, rows, cols| {
+                let base = plane * height * width;
+                let (argmax, max) = rows
+                    .flat_map(|h| cols.clone().map(move |w| base + h * width + w))
+                    .map(|i| (i, input.data[i]))
+                    .fold((base, f32::NEG_INFINITY), |best, candidate| {
+                        if candidate.1 > best.1 {
+                            candidate
+                        } else {
+                            best
+                        }
+                    });
+                let out = (plane * out_h + oh) * out_w + ow;
+                output[out] = max;
+                indices[out] = argmax;
+            },
+        );
+
+        self.input_shape = Some(input.shape.clone());
+        self.indices = Some(indices);
+        Ok(Tensor::new(
+            output,
+            vec![batch_size, channels, out_h, out_w],
+            input.requires_grad,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    pub fn backward(&self, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+        let (shape, indices) = match (&self.input_shape, &self.indices) {
+            (Some(shape), Some(indices)) => (shape, indices),
+            _ => {
+                return Err(BellandeError::RuntimeError(
+                    "Forward pass not called".into(),
+                ))
+            }
+        };
+        if grad_output.data.len() != indices.len() {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected output gradient with {} values, got shape {:?}",
+                indices.len(),
+                grad_output.shape
+            )));
+        }
+
+        let mut grad_input = vec![0.0; shape.iter().product()];
+        for (&index, &grad) in indices.iter().zip(grad_output.data.iter()) {
+            grad_input[index] += grad;
+        }
+
+        Ok(Tensor::new(
+            grad_input,
+            shape.clone(),
+            true,
+            grad_output.device.clone(),
+            grad_output.dtype,
+        ))
+    }
+}
+
+impl Layer for AdaptiveAvgPool2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        AdaptiveAvgPool2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        AdaptiveAvgPool2d::backward(self, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+impl Layer for AdaptiveMaxPool2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        AdaptiveMaxPool2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        AdaptiveMaxPool2d::backward(self, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+impl GlobalAvgPool2d {
+    pub fn new() -> Self {
+        GlobalAvgPool2d {
+            keep_dims: false,
+            input_shape: None,
+        }
+    }
+
+    /// Keeps the pooled spatial axes as size 1
+    pub fn with_keep_dims(mut self, keep_dims: bool) -> Self {
+        self.keep_dims = keep_dims;
+        self
+    }
+
+    fn output_shape(&self, batch_size: usize, channels: usize) -> Vec<usize> {
+        if self.keep_dims {
+            vec![batch_size, channels, 1, 1]
+        } else {
+            vec![batch_size, channels]
+        }
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let (batch_size, channels, height, width) = dimensions(input, (1, 1))?;
+        let area = height * width;
+        let output = input
+            .data
+            .chunks(area)
+            .map(|plane| plane.iter().sum::<f32>() / area as f32)
+            .collect();
+
+        self.input_shape = Some(input.shape.clone());
+        Ok(Tensor::new(
+            output,
+            self.output_shape(batch_size, channels),
+            input.requires_grad,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    pub fn backward(&self, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+        let shape = self
+            .input_shape
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let (batch_size, channels, height, width) = (shape[0], shape[1], shape[2], shape[3]);
+        if grad_output.data.len() != batch_size * channels {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected output gradient of shape {:?}, got {:?}",
+                self.output_shape(batch_size, channels),
+                grad_output.shape
+            )));
+        }
+
+        let area = height * width;
+        let grad_input = grad_output
+            .data
+            .iter()
+            .flat_map(|&grad| std::iter::repeat(grad / area as f32).take(area))
+            .collect();
+
+        Ok(Tensor::new(
+            grad_input,
+            shape.clone(),
+            true,
+            grad_output.device.clone(),
+            grad_output.dtype,
+        ))
+    }
+}
+
+impl Default for GlobalAvgPool2d {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for GlobalAvgPool2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        GlobalAvgPool2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        GlobalAvgPool2d::backward(self, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
@@ -14,6 +14,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{ChannelRole, Layer};
 
 pub struct AvgPool2d {
     kernel_size: (usize, usize),
@@ -164,3 +165,17 @@ impl AvgPool2d {
         }
     }
 }
+
+impl Layer for AvgPool2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        AvgPool2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        AvgPool2d::backward(self, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
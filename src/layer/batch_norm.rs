@@ -14,138 +14,139 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
-use std::sync::Arc;
-
+use crate::layer::{
+    accumulate_grad, assign_parameter, parameter, select_channels, unknown_parameter, ChannelRole,
+    Layer,
+};
+use parking_lot::{Mutex, RwLock};
+
+/// Batch normalization over (batch_size, num_features) or
+/// (batch_size, num_features, length) inputs
 pub struct BatchNorm1d {
-    num_features: usize,
-    eps: f32,
-    momentum: f32,
-    running_mean: Arc<Tensor>,
-    running_var: Arc<Tensor>,
-    weight: Option<Tensor>,
-    bias: Option<Tensor>,
-    training: bool,
+    inner: BatchNorm,
 }
 
+/// Batch normalization over (batch_size, channels, height, width) inputs
 pub struct BatchNorm2d {
+    inner: BatchNorm,
+}
+
+/// Shared implementation, normalizing each feature over every other axis.
+/// Inputs are viewed as (batch, features, positions).
+struct BatchNorm {
     num_features: usize,
     eps: f32,
+    /// Weight of the current batch in the running statistics
     momentum: f32,
-    running_mean: Arc<Tensor>,
-    running_var: Arc<Tensor>,
+    running_mean: RwLock<Tensor>,
+    running_var: RwLock<Tensor>,
     weight: Option<Tensor>,
     bias: Option<Tensor>,
     training: bool,
+    /// L1 penalty on the weight (gamma), for network slimming
+    gamma_l1: f32,
+    cache: Mutex<Option<ForwardCache>>,
 }
 
-impl BatchNorm1d {
-    pub fn new(num_features: usize, eps: f32, momentum: f32, affine: bool) -> Self {
-        BatchNorm1d {
+/// What the backward pass needs from the last forward call
+struct ForwardCache {
+    shape: Vec<usize>,
+    normalized: Vec<f32>,
+    inv_std: Vec<f32>,
+    /// Whether batch statistics were used, making the mean and variance
+    /// functions of the input
+    batch_stats: bool,
+}
+
+impl BatchNorm {
+    fn new(num_features: usize, eps: f32, momentum: f32, affine: bool) -> Self {
+        BatchNorm {
             num_features,
             eps,
             momentum,
-            running_mean: Arc::new(Tensor::zeros(&[num_features])),
-            running_var: Arc::new(Tensor::ones(&[num_features])),
+            running_mean: RwLock::new(Tensor::zeros(&[num_features])),
+            running_var: RwLock::new(Tensor::ones(&[num_features])),
             weight: if affine {
-                Some(Tensor::ones(&[num_features]))
+                Some(parameter(Tensor::ones(&[num_features])))
             } else {
                 None
             },
             bias: if affine {
-                Some(Tensor::zeros(&[num_features]))
+                Some(parameter(Tensor::zeros(&[num_features])))
             } else {
                 None
             },
             training: true,
+            gamma_l1: 0.0,
+            cache: Mutex::new(None),
         }
     }
 
-    pub fn train(&mut self) {
-        self.training = true;
-    }
-
-    pub fn eval(&mut self) {
-        self.training = false;
-    }
-
-    pub fn forward(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
-        // Check for valid input shape (batch_size, num_features)
-        if input.shape.len() != 2 {
-            return Err(BellandeError::InvalidShape);
-        }
-
-        let (batch_size, features) = (input.shape[0], input.shape[1]);
-
+    fn forward(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let features = input.shape[1];
         if features != self.num_features {
             return Err(BellandeError::DimensionMismatch);
         }
 
-        let mut output = input.data.clone();
+        let batch_size = input.shape[0];
+        let positions: usize = input.shape[2..].iter().product();
+        let count = batch_size * positions;
+        if self.training && count < 2 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Batch normalization needs more than one value per feature in training, got input of shape {:?}",
+                input.shape
+            )));
+        }
 
-        if self.training {
-            // Calculate mean and variance
+        let (mean, var) = if self.training {
             let mut mean = vec![0.0; features];
             let mut var = vec![0.0; features];
-
-            // Calculate mean
             for f in 0..features {
-                let mut sum = 0.0;
-                let mut sq_sum = 0.0;
-
-                for b in 0..batch_size {
-                    let idx = b * features + f;
-                    let val = input.data[idx];
-                    sum += val;
-                    sq_sum += val * val;
-                }
-
-                mean[f] = sum / batch_size as f32;
-                var[f] = sq_sum / batch_size as f32 - mean[f] * mean[f];
+                let values = || feature_values(&input.data, features, positions, f);
+                mean[f] = values().sum::<f32>() / count as f32;
+                var[f] = values().map(|v| (v - mean[f]).powi(2)).sum::<f32>() / count as f32;
             }
 
-            // Update running statistics
-            for f in 0..features {
-                self.running_mean.data[f] =
-                    self.momentum * self.running_mean.data[f] + (1.0 - self.momentum) * mean[f];
-                self.running_var.data[f] =
-                    self.momentum * self.running_var.data[f] + (1.0 - self.momentum) * var[f];
+            // Running variance uses the unbiased estimate
+            let unbiased = count as f32 / (count - 1) as f32;
+            let mut running_mean = self.running_mean.write();
+            let mut running_var = self.running_var.write();
+            for (running, &batch) in running_mean.data.iter_mut().zip(mean.iter()) {
+                *running = (1.0 - self.momentum) * *running + self.momentum * batch;
             }
-
-            // Normalize
-            for f in 0..features {
-                let std = (var[f] + self.eps).sqrt();
-                for b in 0..batch_size {
-                    let idx = b * features + f;
-                    output[idx] = (output[idx] - mean[f]) / std;
-
-                    // Apply affine transform if weight and bias are present
-                    if let Some(ref weight) = self.weight {
-                        output[idx] *= weight.data[f];
-                    }
-                    if let Some(ref bias) = self.bias {
-                        output[idx] += bias.data[f];
-                    }
-                }
+            for (running, &batch) in running_var.data.iter_mut().zip(var.iter()) {
+                *running = (1.0 - self.momentum) * *running + self.momentum * batch * unbiased;
             }
+            (mean, var)
         } else {
-            // Use running statistics for inference
-            for f in 0..features {
-                let std = (self.running_var.data[f] + self.eps).sqrt();
-                for b in 0..batch_size {
-                    let idx = b * features + f;
-                    output[idx] = (output[idx] - self.running_mean.data[f]) / std;
-
-                    // Apply affine transform if weight and bias are present
-                    if let Some(ref weight) = self.weight {
-                        output[idx] *= weight.data[f];
-                    }
-                    if let Some(ref bias) = self.bias {
-                        output[idx] += bias.data[f];
-                    }
-                }
+            (
+                self.running_mean.read().data.clone(),
+                self.running_var.read().data.clone(),
+            )
+        };
+
+        let inv_std: Vec<f32> = var.iter().map(|v| 1.0 / (v + self.eps).sqrt()).collect();
+        let mut normalized = input.data.clone();
+        let mut output = input.data.clone();
+        for (i, (x_hat, y)) in normalized.iter_mut().zip(output.iter_mut()).enumerate() {
+            let f = (i / positions) % features;
+            *x_hat = (*x_hat - mean[f]) * inv_std[f];
+            *y = *x_hat;
+            if let Some(ref weight) = self.weight {
+                *y *= weight.data[f];
+            }
+            if let Some(ref bias) = self.bias {
+                *y += bias.data[f];
             }
         }
 
+        *self.cache.lock() = Some(ForwardCache {
+            shape: input.shape.clone(),
+            normalized,
+            inv_std,
+            batch_stats: self.training,
+        });
+
         Ok(Tensor::new(
             output,
             input.shape.clone(),
@@ -154,136 +155,353 @@ impl BatchNorm1d {
             input.dtype,
         ))
     }
+
+    /// Returns the input gradient and, when affine, the weight and bias
+    /// gradients for the last forward call
+    fn backward(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<(Tensor, Option<Tensor>, Option<Tensor>), BellandeError> {
+        let cache = self.cache.lock();
+        let cache = cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        if grad_output.shape != cache.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected output gradient of shape {:?}, got {:?}",
+                cache.shape, grad_output.shape
+            )));
+        }
+
+        let features = self.num_features;
+        let positions: usize = cache.shape[2..].iter().product();
+        let count = (cache.shape[0] * positions) as f32;
+
+        // Per-feature sums of dy and dy * x_hat give the bias and weight
+        // gradients, and the mean and variance terms of the input gradient
+        let mut sum_grad = vec![0.0; features];
+        let mut sum_grad_normalized = vec![0.0; features];
+        for (i, (&dy, &x_hat)) in grad_output
+            .data
+            .iter()
+            .zip(cache.normalized.iter())
+            .enumerate()
+        {
+            let f = (i / positions) % features;
+            sum_grad[f] += dy;
+            sum_grad_normalized[f] += dy * x_hat;
+        }
+
+        let mut grad_input = vec![0.0; grad_output.data.len()];
+        for (i, (dx, (&dy, &x_hat))) in grad_input
+            .iter_mut()
+            .zip(grad_output.data.iter().zip(cache.normalized.iter()))
+            .enumerate()
+        {
+            let f = (i / positions) % features;
+            let scale = self.weight.as_ref().map_or(1.0, |w| w.data[f]) * cache.inv_std[f];
+            *dx = if cache.batch_stats {
+                scale * (dy - sum_grad[f] / count - x_hat * sum_grad_normalized[f] / count)
+            } else {
+                scale * dy
+            };
+        }
+
+        let per_feature = |data: Vec<f32>, like: &Tensor| {
+            Tensor::new(data, vec![features], true, like.device.clone(), like.dtype)
+        };
+        Ok((
+            Tensor::new(
+                grad_input,
+                cache.shape.clone(),
+                true,
+                grad_output.device.clone(),
+                grad_output.dtype,
+            ),
+            self.weight
+                .as_ref()
+                .map(|weight| per_feature(sum_grad_normalized, weight)),
+            self.bias.as_ref().map(|bias| per_feature(sum_grad, bias)),
+        ))
+    }
+
+    fn layer_backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_input, grad_weight, grad_bias) = self.backward(grad)?;
+        if let (Some(weight), Some(mut grad_weight)) = (self.weight.as_mut(), grad_weight) {
+            if self.gamma_l1 > 0.0 {
+                for (g, &w) in grad_weight.data.iter_mut().zip(weight.data.iter()) {
+                    *g += self.gamma_l1 * w.signum();
+                }
+            }
+            accumulate_grad(weight, &grad_weight)?;
+        }
+        if let (Some(bias), Some(grad_bias)) = (self.bias.as_mut(), grad_bias.as_ref()) {
+            accumulate_grad(bias, grad_bias)?;
+        }
+        Ok(grad_input)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        self.weight
+            .iter()
+            .chain(self.bias.iter())
+            .cloned()
+            .collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = Vec::new();
+        if let Some(ref weight) = self.weight {
+            params.push(("weight".to_string(), weight.clone()));
+        }
+        if let Some(ref bias) = self.bias {
+            params.push(("bias".to_string(), bias.clone()));
+        }
+        params.push(("running_mean".to_string(), self.running_mean.read().clone()));
+        params.push(("running_var".to_string(), self.running_var.read().clone()));
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name {
+            "weight" | "bias" => {
+                let param = if name == "weight" {
+                    self.weight.as_mut()
+                } else {
+                    self.bias.as_mut()
+                };
+                match param {
+                    Some(param) => assign_parameter(param, name, value),
+                    None => Err(unknown_parameter(name)),
+                }
+            }
+            "running_mean" => assign_parameter(self.running_mean.get_mut(), name, value),
+            "running_var" => assign_parameter(self.running_var.get_mut(), name, value),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+
+    fn channel_importance(&self) -> Option<Vec<f32>> {
+        self.weight
+            .as_ref()
+            .map(|weight| weight.data.iter().map(|w| w.abs()).collect())
+    }
+
+    fn retain_channels(&mut self, keep: &[usize]) -> Result<(), BellandeError> {
+        if let Some(ref mut weight) = self.weight {
+            *weight = select_channels(weight, 0, keep)?;
+        }
+        if let Some(ref mut bias) = self.bias {
+            *bias = select_channels(bias, 0, keep)?;
+        }
+        let running_mean = select_channels(&self.running_mean.read(), 0, keep)?;
+        let running_var = select_channels(&self.running_var.read(), 0, keep)?;
+        *self.running_mean.get_mut() = running_mean;
+        *self.running_var.get_mut() = running_var;
+        self.num_features = keep.len();
+        *self.cache.get_mut() = None;
+        Ok(())
+    }
+}
+
+/// Values of feature `f` in a (batch, features, positions) buffer
+fn feature_values(
+    data: &[f32],
+    features: usize,
+    positions: usize,
+    f: usize,
+) -> impl Iterator<Item = f32> + '_ {
+    data.chunks(features * positions)
+        .flat_map(move |sample| sample[f * positions..(f + 1) * positions].iter().copied())
+}
+
+impl BatchNorm1d {
+    /// `momentum` is the weight of each new batch in the running statistics
+    pub fn new(num_features: usize, eps: f32, momentum: f32, affine: bool) -> Self {
+        BatchNorm1d {
+            inner: BatchNorm::new(num_features, eps, momentum, affine),
+        }
+    }
+
+    /// Adds `lambda * sign(gamma)` to the weight gradient, driving the
+    /// scales of unimportant channels towards zero so they can be pruned
+    /// with `PruningCriterion::BatchNormGamma`
+    pub fn with_gamma_l1(mut self, lambda: f32) -> Self {
+        self.inner.gamma_l1 = lambda;
+        self
+    }
+
+    pub fn train(&mut self) {
+        self.inner.training = true;
+    }
+
+    pub fn eval(&mut self) {
+        self.inner.training = false;
+    }
+
+    pub fn forward(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        // (batch_size, num_features) or (batch_size, num_features, length)
+        if input.shape.len() != 2 && input.shape.len() != 3 {
+            return Err(BellandeError::InvalidShape(
+                "Expected 2D or 3D tensor (batch_size, num_features[, length])".into(),
+            ));
+        }
+        self.inner.forward(input)
+    }
+
+    /// Returns the input gradient and, when affine, the weight and bias
+    /// gradients for the last forward call
+    pub fn backward(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<(Tensor, Option<Tensor>, Option<Tensor>), BellandeError> {
+        self.inner.backward(grad_output)
+    }
+
+    pub fn running_mean(&self) -> Tensor {
+        self.inner.running_mean.read().clone()
+    }
+
+    pub fn running_var(&self) -> Tensor {
+        self.inner.running_var.read().clone()
+    }
 }
 
 impl BatchNorm2d {
+    /// `momentum` is the weight of each new batch in the running statistics
     pub fn new(num_features: usize, eps: f32, momentum: f32, affine: bool) -> Self {
         BatchNorm2d {
-            num_features,
-            eps,
-            momentum,
-            running_mean: Arc::new(Tensor::zeros(&[num_features])),
-            running_var: Arc::new(Tensor::ones(&[num_features])),
-            weight: if affine {
-                Some(Tensor::ones(&[num_features]))
-            } else {
-                None
-            },
-            bias: if affine {
-                Some(Tensor::zeros(&[num_features]))
-            } else {
-                None
-            },
-            training: true,
+            inner: BatchNorm::new(num_features, eps, momentum, affine),
         }
     }
 
+    /// Adds `lambda * sign(gamma)` to the weight gradient, driving the
+    /// scales of unimportant channels towards zero so they can be pruned
+    /// with `PruningCriterion::BatchNormGamma`
+    pub fn with_gamma_l1(mut self, lambda: f32) -> Self {
+        self.inner.gamma_l1 = lambda;
+        self
+    }
+
     pub fn train(&mut self) {
-        self.training = true;
+        self.inner.training = true;
     }
 
     pub fn eval(&mut self) {
-        self.training = false;
+        self.inner.training = false;
     }
 
     pub fn forward(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
         if input.shape.len() != 4 {
-            return Err(BellandeError::InvalidShape);
+            return Err(BellandeError::InvalidShape(
+                "Expected 4D tensor (batch_size, channels, height, width)".into(),
+            ));
         }
+        self.inner.forward(input)
+    }
 
-        let (batch_size, channels, height, width) = (
-            input.shape[0],
-            input.shape[1],
-            input.shape[2],
-            input.shape[3],
-        );
+    /// Returns the input gradient and, when affine, the weight and bias
+    /// gradients for the last forward call
+    pub fn backward(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<(Tensor, Option<Tensor>, Option<Tensor>), BellandeError> {
+        self.inner.backward(grad_output)
+    }
 
-        if channels != self.num_features {
-            return Err(BellandeError::DimensionMismatch);
+    pub fn running_mean(&self) -> Tensor {
+        self.inner.running_mean.read().clone()
+    }
+
+    pub fn running_var(&self) -> Tensor {
+        self.inner.running_var.read().clone()
+    }
+}
+
+impl Layer for BatchNorm1d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        BatchNorm1d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        self.inner.layer_backward(grad)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        self.inner.parameters()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        self.inner.named_parameters()
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        self.inner.set_parameter(name, value)
+    }
+
+    fn train(&mut self) {
+        BatchNorm1d::train(self)
+    }
+
+    fn eval(&mut self) {
+        BatchNorm1d::eval(self)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PerChannel {
+            channels: self.inner.num_features,
         }
+    }
 
-        let mut output = input.data.clone();
+    fn channel_importance(&self) -> Option<Vec<f32>> {
+        self.inner.channel_importance()
+    }
 
-        if self.training {
-            // Calculate mean and variance
-            let mut mean = vec![0.0; channels];
-            let mut var = vec![0.0; channels];
-            let size = batch_size * height * width;
-
-            for c in 0..channels {
-                let mut sum = 0.0;
-                let mut sq_sum = 0.0;
-
-                for b in 0..batch_size {
-                    for h in 0..height {
-                        for w in 0..width {
-                            let idx = ((b * channels + c) * height + h) * width + w;
-                            let val = input.data[idx];
-                            sum += val;
-                            sq_sum += val * val;
-                        }
-                    }
-                }
+    fn retain_output_channels(&mut self, keep: &[usize]) -> Result<(), BellandeError> {
+        self.inner.retain_channels(keep)
+    }
+}
 
-                mean[c] = sum / size as f32;
-                var[c] = sq_sum / size as f32 - mean[c] * mean[c];
-            }
+impl Layer for BatchNorm2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        BatchNorm2d::forward(self, input)
+    }
 
-            // Update running statistics
-            for c in 0..channels {
-                self.running_mean.data[c] =
-                    self.momentum * self.running_mean.data[c] + (1.0 - self.momentum) * mean[c];
-                self.running_var.data[c] =
-                    self.momentum * self.running_var.data[c] + (1.0 - self.momentum) * var[c];
-            }
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        self.inner.layer_backward(grad)
+    }
 
-            // Normalize
-            for c in 0..channels {
-                let std = (var[c] + self.eps).sqrt();
-                for b in 0..batch_size {
-                    for h in 0..height {
-                        for w in 0..width {
-                            let idx = ((b * channels + c) * height + h) * width + w;
-                            output[idx] = (output[idx] - mean[c]) / std;
-
-                            if let Some(ref weight) = self.weight {
-                                output[idx] *= weight.data[c];
-                            }
-                            if let Some(ref bias) = self.bias {
-                                output[idx] += bias.data[c];
-                            }
-                        }
-                    }
-                }
-            }
-        } else {
-            // Use running statistics
-            for c in 0..channels {
-                let std = (self.running_var.data[c] + self.eps).sqrt();
-                for b in 0..batch_size {
-                    for h in 0..height {
-                        for w in 0..width {
-                            let idx = ((b * channels + c) * height + h) * width + w;
-                            output[idx] = (output[idx] - self.running_mean.data[c]) / std;
-
-                            if let Some(ref weight) = self.weight {
-                                output[idx] *= weight.data[c];
-                            }
-                            if let Some(ref bias) = self.bias {
-                                output[idx] += bias.data[c];
-                            }
-                        }
-                    }
-                }
-            }
+    fn parameters(&self) -> Vec<Tensor> {
+        self.inner.parameters()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        self.inner.named_parameters()
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        self.inner.set_parameter(name, value)
+    }
+
+    fn train(&mut self) {
+        BatchNorm2d::train(self)
+    }
+
+    fn eval(&mut self) {
+        BatchNorm2d::eval(self)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PerChannel {
+            channels: self.inner.num_features,
         }
+    }
 
-        Ok(Tensor::new(
-            output,
-            input.shape.clone(),
-            input.requires_grad,
-            input.device.clone(),
-            input.dtype,
-        ))
+    fn channel_importance(&self) -> Option<Vec<f32>> {
+        self.inner.channel_importance()
+    }
+
+    fn retain_output_channels(&mut self, keep: &[usize]) -> Result<(), BellandeError> {
+        self.inner.retain_channels(keep)
     }
 }
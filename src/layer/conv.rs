@@ -14,6 +14,13 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::im2col::{col2im, gemm, gemm_at, gemm_bt, im2col, ConvGeometry};
+use crate::layer::init::default_weight;
+use crate::layer::{
+    accumulate_grad, assign_parameter, parameter, select_channels, unknown_parameter, ChannelRole,
+    Layer,
+};
+use rayon::prelude::*;
 
 pub struct Conv2d {
     in_channels: usize,
@@ -21,9 +28,12 @@ pub struct Conv2d {
     kernel_size: (usize, usize),
     stride: (usize, usize),
     padding: (usize, usize),
+    groups: usize,
     weight: Tensor,
     bias: Option<Tensor>,
     input_cache: Option<Tensor>,
+    per_sample: bool,
+    sample_grads: Vec<(String, Tensor)>,
 }
 
 impl Conv2d {
@@ -35,10 +45,10 @@ impl Conv2d {
         padding: (usize, usize),
         bias: bool,
     ) -> Self {
-        let weight = Tensor::randn(&[out_channels, in_channels, kernel_size.0, kernel_size.1]);
+        let weight = default_weight(&[out_channels, in_channels, kernel_size.0, kernel_size.1]);
 
         let bias = if bias {
-            Some(Tensor::zeros(&[out_channels]))
+            Some(parameter(Tensor::zeros(&[out_channels])))
         } else {
             None
         };
@@ -49,56 +59,188 @@ impl Conv2d {
             kernel_size,
             stride,
             padding,
+            groups: 1,
             weight,
             bias,
             input_cache: None,
+            per_sample: false,
+            sample_grads: Vec::new(),
         }
     }
 
-    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
-        if input.shape.len() != 4 {
-            return Err(BellandeError::InvalidShape);
+    /// Splits the channels into `groups` independent convolutions. Each
+    /// output channel only sees `in_channels / groups` input channels, so the
+    /// weight shrinks to (out_channels, in_channels / groups, kernel_h,
+    /// kernel_w); `groups == in_channels` gives a depthwise convolution.
+    /// Weights are reinitialized.
+    pub fn with_groups(mut self, groups: usize) -> Result<Self, BellandeError> {
+        if groups == 0 || self.in_channels % groups != 0 || self.out_channels % groups != 0 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "groups ({}) must divide both in_channels ({}) and out_channels ({})",
+                groups, self.in_channels, self.out_channels
+            )));
         }
 
-        let (batch_size, channels, height, width) = (
-            input.shape[0],
-            input.shape[1],
-            input.shape[2],
-            input.shape[3],
-        );
+        self.groups = groups;
+        self.weight = default_weight(&[
+            self.out_channels,
+            self.in_channels / groups,
+            self.kernel_size.0,
+            self.kernel_size.1,
+        ]);
+        Ok(self)
+    }
+
+    pub fn groups(&self) -> usize {
+        self.groups
+    }
+
+    /// Records per-sample gradients on every backward pass, see
+    /// `Layer::per_sample_grads`
+    pub fn with_per_sample_grads(mut self, enabled: bool) -> Self {
+        self.per_sample = enabled;
+        self
+    }
 
+    /// Validates a (batch, channels, height, width) input and returns the
+    /// per-image convolution shape
+    fn geometry(&self, input: &Tensor) -> Result<ConvGeometry, BellandeError> {
+        if input.shape.len() != 4 {
+            return Err(BellandeError::InvalidShape(
+                "Expected 4D tensor (batch_size, channels, height, width)".into(),
+            ));
+        }
+
+        let (channels, height, width) = (input.shape[1], input.shape[2], input.shape[3]);
         if channels != self.in_channels {
             return Err(BellandeError::DimensionMismatch);
         }
 
-        let output_height = (height + 2 * self.padding.0 - self.kernel_size.0) / self.stride.0 + 1;
-        let output_width = (width + 2 * self.padding.1 - self.kernel_size.1) / self.stride.1 + 1;
+        let padded_height = height + 2 * self.padding.0;
+        let padded_width = width + 2 * self.padding.1;
+        if padded_height < self.kernel_size.0 || padded_width < self.kernel_size.1 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Kernel {:?} is larger than the padded {}x{} input",
+                self.kernel_size, padded_height, padded_width
+            )));
+        }
+
+        Ok(ConvGeometry {
+            channels,
+            height,
+            width,
+            kernel: self.kernel_size,
+            stride: self.stride,
+            padding: self.padding,
+            output_height: (padded_height - self.kernel_size.0) / self.stride.0 + 1,
+            output_width: (padded_width - self.kernel_size.1) / self.stride.1 + 1,
+        })
+    }
+
+    /// Convolves each image as `weight x im2col(image)`, one group at a time,
+    /// with the batch split across threads
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let geometry = self.geometry(input)?;
+        let batch_size = input.shape[0];
+        let image_size = geometry.channels * geometry.height * geometry.width;
+        let positions = geometry.col_cols();
+        let group_rows = geometry.col_rows() / self.groups;
+        let out_per_group = self.out_channels / self.groups;
+
+        let mut output = vec![0.0; batch_size * self.out_channels * positions];
+        if positions > 0 && image_size > 0 {
+            output
+                .par_chunks_mut(self.out_channels * positions)
+                .zip(input.data.par_chunks(image_size))
+                .for_each(|(output, image)| {
+                    let cols = im2col(image, &geometry);
+                    for group in 0..self.groups {
+                        gemm(
+                            &self.weight.data[group * out_per_group * group_rows
+                                ..(group + 1) * out_per_group * group_rows],
+                            &cols[group * group_rows * positions
+                                ..(group + 1) * group_rows * positions],
+                            &mut output[group * out_per_group * positions
+                                ..(group + 1) * out_per_group * positions],
+                            out_per_group,
+                            group_rows,
+                            positions,
+                        );
+                    }
+
+                    if let Some(ref bias) = self.bias {
+                        for (row, &b) in output.chunks_mut(positions).zip(bias.data.iter()) {
+                            row.iter_mut().for_each(|value| *value += b);
+                        }
+                    }
+                });
+        }
+
+        self.input_cache = Some(input.clone());
+
+        Ok(Tensor::new(
+            output,
+            vec![
+                batch_size,
+                self.out_channels,
+                geometry.output_height,
+                geometry.output_width,
+            ],
+            true,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    /// Direct sliding-window convolution. Much slower than `forward` and
+    /// does not cache the input; kept as a reference to check it against.
+    pub fn forward_reference(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let geometry = self.geometry(input)?;
+        let (batch_size, channels, height, width) = (
+            input.shape[0],
+            geometry.channels,
+            geometry.height,
+            geometry.width,
+        );
+        let (output_height, output_width) = (geometry.output_height, geometry.output_width);
+        let in_per_group = self.in_channels / self.groups;
+        let out_per_group = self.out_channels / self.groups;
 
         let mut output = vec![0.0; batch_size * self.out_channels * output_height * output_width];
 
-        // Implement convolution operation
         for b in 0..batch_size {
             for out_c in 0..self.out_channels {
+                let first_in_c = (out_c / out_per_group) * in_per_group;
+
                 for out_h in 0..output_height {
                     for out_w in 0..output_width {
                         let mut sum = 0.0;
 
-                        for in_c in 0..self.in_channels {
+                        for group_c in 0..in_per_group {
+                            let in_c = first_in_c + group_c;
                             for k_h in 0..self.kernel_size.0 {
+                                let Some(in_h) = (out_h * self.stride.0 + k_h)
+                                    .checked_sub(self.padding.0)
+                                    .filter(|&h| h < height)
+                                else {
+                                    continue;
+                                };
                                 for k_w in 0..self.kernel_size.1 {
-                                    let in_h = out_h * self.stride.0 + k_h - self.padding.0;
-                                    let in_w = out_w * self.stride.1 + k_w - self.padding.1;
-
-                                    if in_h < height && in_w < width {
-                                        let input_idx =
-                                            ((b * channels + in_c) * height + in_h) * width + in_w;
-                                        let weight_idx = ((out_c * self.in_channels + in_c)
-                                            * self.kernel_size.0
-                                            + k_h)
-                                            * self.kernel_size.1
-                                            + k_w;
-                                        sum += input.data[input_idx] * self.weight.data[weight_idx];
-                                    }
+                                    let Some(in_w) = (out_w * self.stride.1 + k_w)
+                                        .checked_sub(self.padding.1)
+                                        .filter(|&w| w < width)
+                                    else {
+                                        continue;
+                                    };
+
+                                    let input_idx =
+                                        ((b * channels + in_c) * height + in_h) * width + in_w;
+                                    let weight_idx = ((out_c * in_per_group + group_c)
+                                        * self.kernel_size.0
+                                        + k_h)
+                                        * self.kernel_size.1
+                                        + k_w;
+                                    sum += input.data[input_idx] * self.weight.data[weight_idx];
                                 }
                             }
                         }
@@ -116,8 +258,6 @@ impl Conv2d {
             }
         }
 
-        self.input_cache = Some(input.clone());
-
         Ok(Tensor::new(
             output,
             vec![batch_size, self.out_channels, output_height, output_width],
@@ -127,61 +267,591 @@ impl Conv2d {
         ))
     }
 
+    /// Gradients for the last `forward` input via `weight^T x grad` folded
+    /// back with col2im, and `grad x im2col(input)^T` for the weight. Per-image
+    /// results are computed in parallel and summed in batch order, so the
+    /// result does not depend on the thread count.
     pub fn backward(
         &self,
         grad_output: &Tensor,
     ) -> Result<(Tensor, Tensor, Option<Tensor>), BellandeError> {
-        if let Some(ref input) = self.input_cache {
-            let (batch_size, _, output_height, output_width) = (
-                grad_output.shape[0],
-                grad_output.shape[1],
-                grad_output.shape[2],
-                grad_output.shape[3],
-            );
+        let input = self
+            .input_cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let geometry = self.geometry(input)?;
+        self.check_grad_output(input, &geometry, grad_output)?;
+
+        let image_size = geometry.channels * geometry.height * geometry.width;
+        let positions = geometry.col_cols();
+        let group_rows = geometry.col_rows() / self.groups;
+        let out_per_group = self.out_channels / self.groups;
+
+        let mut grad_input = vec![0.0; input.data.len()];
+        let mut grad_weight = vec![0.0; self.weight.data.len()];
+        let mut grad_bias = self.bias.as_ref().map(|_| vec![0.0; self.out_channels]);
+
+        if positions > 0 && image_size > 0 {
+            let per_image: Vec<Vec<f32>> = grad_input
+                .par_chunks_mut(image_size)
+                .zip(input.data.par_chunks(image_size))
+                .zip(grad_output.data.par_chunks(self.out_channels * positions))
+                .map(|((grad_image, image), grad)| {
+                    let cols = im2col(image, &geometry);
+                    let mut grad_cols = vec![0.0; cols.len()];
+                    let mut grad_weight = vec![0.0; self.weight.data.len()];
+
+                    for group in 0..self.groups {
+                        let weights = group * out_per_group * group_rows
+                            ..(group + 1) * out_per_group * group_rows;
+                        let rows =
+                            group * group_rows * positions..(group + 1) * group_rows * positions;
+                        let grad = &grad[group * out_per_group * positions
+                            ..(group + 1) * out_per_group * positions];
+
+                        gemm_bt(
+                            grad,
+                            &cols[rows.clone()],
+                            &mut grad_weight[weights.clone()],
+                            out_per_group,
+                            positions,
+                            group_rows,
+                        );
+                        gemm_at(
+                            &self.weight.data[weights],
+                            grad,
+                            &mut grad_cols[rows],
+                            group_rows,
+                            out_per_group,
+                            positions,
+                        );
+                    }
+
+                    col2im(&grad_cols, &geometry, grad_image);
+                    grad_weight
+                })
+                .collect();
+
+            for partial in per_image {
+                for (g, p) in grad_weight.iter_mut().zip(partial) {
+                    *g += p;
+                }
+            }
 
-            // Gradient with respect to input
-            let mut grad_input = vec![0.0; input.data.len()];
-            // Gradient with respect to weight
-            let mut grad_weight = vec![0.0; self.weight.data.len()];
-            // Gradient with respect to bias
-            let mut grad_bias = if self.bias.is_some() {
-                Some(vec![0.0; self.out_channels])
-            } else {
-                None
-            };
-
-            // Implement backward pass
-            // ... (Complex backward pass implementation)
-
-            Ok((
+            if let Some(ref mut grad_bias) = grad_bias {
+                for grad in grad_output.data.chunks(self.out_channels * positions) {
+                    for (g, row) in grad_bias.iter_mut().zip(grad.chunks(positions)) {
+                        *g += row.iter().sum::<f32>();
+                    }
+                }
+            }
+        }
+
+        Ok(self.gradient_tensors(input, grad_input, grad_weight, grad_bias))
+    }
+
+    /// Direct-loop counterpart of `backward`, kept as a reference
+    pub fn backward_reference(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<(Tensor, Tensor, Option<Tensor>), BellandeError> {
+        let input = self
+            .input_cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let geometry = self.geometry(input)?;
+        self.check_grad_output(input, &geometry, grad_output)?;
+
+        let (batch_size, channels, height, width) = (
+            input.shape[0],
+            geometry.channels,
+            geometry.height,
+            geometry.width,
+        );
+        let (output_height, output_width) = (geometry.output_height, geometry.output_width);
+
+        let in_per_group = self.in_channels / self.groups;
+        let out_per_group = self.out_channels / self.groups;
+
+        // Gradient with respect to input
+        let mut grad_input = vec![0.0; input.data.len()];
+        // Gradient with respect to weight
+        let mut grad_weight = vec![0.0; self.weight.data.len()];
+        // Gradient with respect to bias
+        let mut grad_bias = if self.bias.is_some() {
+            Some(vec![0.0; self.out_channels])
+        } else {
+            None
+        };
+
+        // Every output element is a dot product of a weight slice with an
+        // input window, so its gradient flows back to both factors
+        for b in 0..batch_size {
+            for out_c in 0..self.out_channels {
+                let first_in_c = (out_c / out_per_group) * in_per_group;
+
+                for out_h in 0..output_height {
+                    for out_w in 0..output_width {
+                        let output_idx = ((b * self.out_channels + out_c) * output_height + out_h)
+                            * output_width
+                            + out_w;
+                        let grad = grad_output.data[output_idx];
+
+                        if let Some(ref mut grad_bias) = grad_bias {
+                            grad_bias[out_c] += grad;
+                        }
+
+                        for group_c in 0..in_per_group {
+                            let in_c = first_in_c + group_c;
+                            for k_h in 0..self.kernel_size.0 {
+                                let Some(in_h) = (out_h * self.stride.0 + k_h)
+                                    .checked_sub(self.padding.0)
+                                    .filter(|&h| h < height)
+                                else {
+                                    continue;
+                                };
+                                for k_w in 0..self.kernel_size.1 {
+                                    let Some(in_w) = (out_w * self.stride.1 + k_w)
+                                        .checked_sub(self.padding.1)
+                                        .filter(|&w| w < width)
+                                    else {
+                                        continue;
+                                    };
+
+                                    let input_idx =
+                                        ((b * channels + in_c) * height + in_h) * width + in_w;
+                                    let weight_idx = ((out_c * in_per_group + group_c)
+                                        * self.kernel_size.0
+                                        + k_h)
+                                        * self.kernel_size.1
+                                        + k_w;
+                                    grad_weight[weight_idx] += grad * input.data[input_idx];
+                                    grad_input[input_idx] += grad * self.weight.data[weight_idx];
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(self.gradient_tensors(input, grad_input, grad_weight, grad_bias))
+    }
+
+    /// Per-sample weight gradients `grad[b] x im2col(input[b])^T`, the
+    /// per-image terms that `backward` sums, and per-sample bias gradients
+    /// summed over output positions
+    fn sample_gradients(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<Vec<(String, Tensor)>, BellandeError> {
+        let input = self
+            .input_cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let geometry = self.geometry(input)?;
+        self.check_grad_output(input, &geometry, grad_output)?;
+
+        let batch_size = input.shape[0];
+        let image_size = geometry.channels * geometry.height * geometry.width;
+        let positions = geometry.col_cols();
+        let group_rows = geometry.col_rows() / self.groups;
+        let out_per_group = self.out_channels / self.groups;
+        let weight_size = self.weight.data.len();
+
+        let mut grad_weight = vec![0.0; batch_size * weight_size];
+        if positions > 0 && image_size > 0 {
+            grad_weight
+                .par_chunks_mut(weight_size)
+                .zip(input.data.par_chunks(image_size))
+                .zip(grad_output.data.par_chunks(self.out_channels * positions))
+                .for_each(|((grad_weight, image), grad)| {
+                    let cols = im2col(image, &geometry);
+                    for group in 0..self.groups {
+                        gemm_bt(
+                            &grad[group * out_per_group * positions
+                                ..(group + 1) * out_per_group * positions],
+                            &cols[group * group_rows * positions
+                                ..(group + 1) * group_rows * positions],
+                            &mut grad_weight[group * out_per_group * group_rows
+                                ..(group + 1) * out_per_group * group_rows],
+                            out_per_group,
+                            positions,
+                            group_rows,
+                        );
+                    }
+                });
+        }
+
+        let mut shape = vec![batch_size];
+        shape.extend_from_slice(&self.weight.shape);
+        let mut grads = vec![(
+            "weight".to_string(),
+            Tensor::new(
+                grad_weight,
+                shape,
+                false,
+                self.weight.device.clone(),
+                self.weight.dtype,
+            ),
+        )];
+        if self.bias.is_some() {
+            let grad_bias = grad_output
+                .data
+                .chunks(positions.max(1))
+                .map(|row| row.iter().sum())
+                .collect();
+            grads.push((
+                "bias".to_string(),
                 Tensor::new(
-                    grad_input,
-                    input.shape.clone(),
-                    true,
-                    input.device.clone(),
-                    input.dtype,
+                    grad_bias,
+                    vec![batch_size, self.out_channels],
+                    false,
+                    self.weight.device.clone(),
+                    self.weight.dtype,
                 ),
+            ));
+        }
+        Ok(grads)
+    }
+
+    fn check_grad_output(
+        &self,
+        input: &Tensor,
+        geometry: &ConvGeometry,
+        grad_output: &Tensor,
+    ) -> Result<(), BellandeError> {
+        let expected = [
+            input.shape[0],
+            self.out_channels,
+            geometry.output_height,
+            geometry.output_width,
+        ];
+        if grad_output.shape != expected {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected output gradient of shape {:?}, got {:?}",
+                expected, grad_output.shape
+            )));
+        }
+        Ok(())
+    }
+
+    fn gradient_tensors(
+        &self,
+        input: &Tensor,
+        grad_input: Vec<f32>,
+        grad_weight: Vec<f32>,
+        grad_bias: Option<Vec<f32>>,
+    ) -> (Tensor, Tensor, Option<Tensor>) {
+        (
+            Tensor::new(
+                grad_input,
+                input.shape.clone(),
+                true,
+                input.device.clone(),
+                input.dtype,
+            ),
+            Tensor::new(
+                grad_weight,
+                self.weight.shape.clone(),
+                true,
+                self.weight.device.clone(),
+                self.weight.dtype,
+            ),
+            grad_bias.map(|bias| {
                 Tensor::new(
-                    grad_weight,
-                    self.weight.shape.clone(),
+                    bias,
+                    vec![self.out_channels],
                     true,
                     self.weight.device.clone(),
                     self.weight.dtype,
-                ),
-                grad_bias.map(|bias| {
-                    Tensor::new(
-                        bias,
-                        vec![self.out_channels],
-                        true,
-                        self.weight.device.clone(),
-                        self.weight.dtype,
-                    )
-                }),
-            ))
+                )
+            }),
+        )
+    }
+}
+
+impl Layer for Conv2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        Conv2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_input, grad_weight, grad_bias) = Conv2d::backward(self, grad)?;
+        if self.per_sample {
+            self.sample_grads = self.sample_gradients(grad)?;
+        }
+        accumulate_grad(&mut self.weight, &grad_weight)?;
+        if let (Some(bias), Some(grad_bias)) = (self.bias.as_mut(), grad_bias.as_ref()) {
+            accumulate_grad(bias, grad_bias)?;
+        }
+        Ok(grad_input)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = vec![("weight".to_string(), self.weight.clone())];
+        if let Some(ref bias) = self.bias {
+            params.push(("bias".to_string(), bias.clone()));
+        }
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        let param = match name {
+            "weight" => Some(&mut self.weight),
+            "bias" => self.bias.as_mut(),
+            _ => None,
+        };
+        match param {
+            Some(param) => assign_parameter(param, name, value),
+            None => Err(unknown_parameter(name)),
+        }
+    }
+
+    /// Grouped convolutions tie input and output channels together and are
+    /// not pruned
+    fn set_per_sample_grads(&mut self, enabled: bool) -> Result<(), BellandeError> {
+        self.per_sample = enabled;
+        if !enabled {
+            self.sample_grads.clear();
+        }
+        Ok(())
+    }
+
+    fn per_sample_grads(&self) -> Vec<(String, Tensor)> {
+        self.sample_grads.clone()
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        if self.groups == 1 {
+            ChannelRole::Producer {
+                in_channels: self.in_channels,
+                out_channels: self.out_channels,
+            }
         } else {
-            Err(BellandeError::RuntimeError(
-                "Forward pass not called".into(),
-            ))
+            ChannelRole::Opaque
+        }
+    }
+
+    fn channel_importance(&self) -> Option<Vec<f32>> {
+        let filter_size = self.weight.data.len() / self.out_channels.max(1);
+        Some(
+            self.weight
+                .data
+                .chunks(filter_size.max(1))
+                .map(|filter| filter.iter().map(|w| w.abs()).sum())
+                .collect(),
+        )
+    }
+
+    fn retain_output_channels(&mut self, keep: &[usize]) -> Result<(), BellandeError> {
+        if self.groups != 1 {
+            return Err(BellandeError::NotImplemented(
+                "Channel pruning of grouped convolutions".into(),
+            ));
+        }
+        self.weight = select_channels(&self.weight, 0, keep)?;
+        if let Some(ref mut bias) = self.bias {
+            *bias = select_channels(bias, 0, keep)?;
+        }
+        self.out_channels = keep.len();
+        self.input_cache = None;
+        self.sample_grads.clear();
+        Ok(())
+    }
+
+    fn retain_input_channels(
+        &mut self,
+        keep: &[usize],
+        num_channels: usize,
+    ) -> Result<(), BellandeError> {
+        if self.groups != 1 {
+            return Err(BellandeError::NotImplemented(
+                "Channel pruning of grouped convolutions".into(),
+            ));
+        }
+        if num_channels != self.in_channels {
+            return Err(BellandeError::DimensionMismatch);
+        }
+        self.weight = select_channels(&self.weight, 1, keep)?;
+        self.in_channels = keep.len();
+        self.input_cache = None;
+        self.sample_grads.clear();
+        Ok(())
+    }
+}
+
+/// Depthwise convolution (one filter per input channel) followed by a 1x1
+/// pointwise convolution that mixes channels. Uses roughly
+/// `1 / out_channels + 1 / (kernel_h * kernel_w)` of the multiply-adds of a
+/// dense `Conv2d` with the same shape.
+pub struct DepthwiseSeparableConv2d {
+    depthwise: Conv2d,
+    pointwise: Conv2d,
+}
+
+impl DepthwiseSeparableConv2d {
+    pub fn new(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+        padding: (usize, usize),
+        bias: bool,
+    ) -> Result<Self, BellandeError> {
+        let depthwise = Conv2d::new(
+            in_channels,
+            in_channels,
+            kernel_size,
+            stride,
+            padding,
+            false,
+        )
+        .with_groups(in_channels)?;
+        let pointwise = Conv2d::new(in_channels, out_channels, (1, 1), (1, 1), (0, 0), bias);
+
+        Ok(DepthwiseSeparableConv2d {
+            depthwise,
+            pointwise,
+        })
+    }
+
+    pub fn depthwise(&self) -> &Conv2d {
+        &self.depthwise
+    }
+
+    pub fn pointwise(&self) -> &Conv2d {
+        &self.pointwise
+    }
+}
+
+impl Layer for DepthwiseSeparableConv2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let hidden = Layer::forward(&mut self.depthwise, input)?;
+        Layer::forward(&mut self.pointwise, &hidden)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let grad_hidden = Layer::backward(&mut self.pointwise, grad)?;
+        Layer::backward(&mut self.depthwise, &grad_hidden)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let depthwise = self
+            .depthwise
+            .named_parameters()
+            .into_iter()
+            .map(|(name, param)| (format!("depthwise.{}", name), param));
+        let pointwise = self
+            .pointwise
+            .named_parameters()
+            .into_iter()
+            .map(|(name, param)| (format!("pointwise.{}", name), param));
+        depthwise.chain(pointwise).collect()
+    }
+
+    fn set_per_sample_grads(&mut self, enabled: bool) -> Result<(), BellandeError> {
+        self.depthwise.set_per_sample_grads(enabled)?;
+        self.pointwise.set_per_sample_grads(enabled)
+    }
+
+    fn per_sample_grads(&self) -> Vec<(String, Tensor)> {
+        let depthwise = Layer::per_sample_grads(&self.depthwise)
+            .into_iter()
+            .map(|(name, grad)| (format!("depthwise.{}", name), grad));
+        let pointwise = Layer::per_sample_grads(&self.pointwise)
+            .into_iter()
+            .map(|(name, grad)| (format!("pointwise.{}", name), grad));
+        depthwise.chain(pointwise).collect()
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        if let Some(rest) = name.strip_prefix("depthwise.") {
+            self.depthwise.set_parameter(rest, value)
+        } else if let Some(rest) = name.strip_prefix("pointwise.") {
+            self.pointwise.set_parameter(rest, value)
+        } else {
+            Err(unknown_parameter(name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{device::Device, dtype::DataType, random};
+    use crate::testing::gradcheck::{assert_layer_gradients, GradCheckConfig};
+
+    /// (stride, padding, groups) covering the strided, padded, grouped and
+    /// depthwise paths
+    const CASES: [((usize, usize), (usize, usize), usize); 4] = [
+        ((1, 1), (0, 0), 1),
+        ((2, 1), (1, 0), 1),
+        ((1, 2), (1, 1), 2),
+        ((2, 2), (1, 1), 4),
+    ];
+
+    fn conv(stride: (usize, usize), padding: (usize, usize), groups: usize) -> Conv2d {
+        Conv2d::new(4, 4, (3, 3), stride, padding, true)
+            .with_groups(groups)
+            .unwrap()
+    }
+
+    fn random_tensor(shape: &[usize]) -> Tensor {
+        Tensor::new(
+            random::normal(0.0, 1.0, shape.iter().product()),
+            shape.to_vec(),
+            false,
+            Device::CPU,
+            DataType::Float32,
+        )
+    }
+
+    fn assert_close(actual: &Tensor, expected: &Tensor) {
+        assert_eq!(actual.shape, expected.shape);
+        for (index, (a, e)) in actual.data.iter().zip(&expected.data).enumerate() {
+            assert!(
+                (a - e).abs() <= 1e-4 * (1.0 + e.abs()),
+                "element {}: {} vs {}",
+                index,
+                a,
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn backward_matches_numerical_gradients() {
+        for (stride, padding, groups) in CASES {
+            let mut layer = conv(stride, padding, groups);
+            let input = random_tensor(&[2, 4, 6, 5]);
+            assert_layer_gradients(&mut layer, &input, &GradCheckConfig::new()).unwrap_or_else(
+                |e| {
+                    panic!(
+                        "stride {:?}, padding {:?}, groups {}: {}",
+                        stride, padding, groups, e
+                    )
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn im2col_matches_reference_loops() {
+        for (stride, padding, groups) in CASES {
+            let mut layer = conv(stride, padding, groups);
+            let input = random_tensor(&[2, 4, 6, 5]);
+
+            let output = Conv2d::forward(&mut layer, &input).unwrap();
+            assert_close(&output, &layer.forward_reference(&input).unwrap());
+
+            let grad_output = random_tensor(&output.shape);
+            let (grad_input, grad_weight, grad_bias) =
+                Conv2d::backward(&layer, &grad_output).unwrap();
+            let (ref_input, ref_weight, ref_bias) = layer.backward_reference(&grad_output).unwrap();
+            assert_close(&grad_input, &ref_input);
+            assert_close(&grad_weight, &ref_weight);
+            assert_close(&grad_bias.unwrap(), &ref_bias.unwrap());
         }
     }
 }
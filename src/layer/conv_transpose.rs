@@ -0,0 +1,325 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::init::default_weight;
+use crate::layer::{accumulate_grad, assign_parameter, parameter, unknown_parameter, Layer};
+
+/// Transposed 2D convolution, the learnable upsampling counterpart of
+/// `Conv2d`. Weights have shape (in_channels, out_channels, kernel_h, kernel_w).
+pub struct ConvTranspose2d {
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: (usize, usize),
+    stride: (usize, usize),
+    padding: (usize, usize),
+    output_padding: (usize, usize),
+    weight: Tensor,
+    bias: Option<Tensor>,
+    input_cache: Option<Tensor>,
+}
+
+impl ConvTranspose2d {
+    /// `output_padding` adds rows and columns to one side of the output to
+    /// resolve the size ambiguity of strided convolutions; each component
+    /// must be smaller than the matching stride
+    pub fn new(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+        padding: (usize, usize),
+        output_padding: (usize, usize),
+        bias: bool,
+    ) -> Self {
+        let weight = default_weight(&[in_channels, out_channels, kernel_size.0, kernel_size.1]);
+
+        let bias = if bias {
+            Some(parameter(Tensor::zeros(&[out_channels])))
+        } else {
+            None
+        };
+
+        ConvTranspose2d {
+            in_channels,
+            out_channels,
+            kernel_size,
+            stride,
+            padding,
+            output_padding,
+            weight,
+            bias,
+            input_cache: None,
+        }
+    }
+
+    /// Spatial output size for an input of the given height and width
+    pub fn output_size(
+        &self,
+        height: usize,
+        width: usize,
+    ) -> Result<(usize, usize), BellandeError> {
+        if self.stride.0 == 0 || self.stride.1 == 0 {
+            return Err(BellandeError::InvalidConfiguration(
+                "Stride must be positive".into(),
+            ));
+        }
+        if self.output_padding.0 >= self.stride.0 || self.output_padding.1 >= self.stride.1 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Output padding {:?} must be smaller than stride {:?}",
+                self.output_padding, self.stride
+            )));
+        }
+
+        let full_height = (height - 1) * self.stride.0 + self.kernel_size.0 + self.output_padding.0;
+        let full_width = (width - 1) * self.stride.1 + self.kernel_size.1 + self.output_padding.1;
+        if full_height <= 2 * self.padding.0 || full_width <= 2 * self.padding.1 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Padding {:?} leaves no output for a {}x{} input",
+                self.padding, height, width
+            )));
+        }
+
+        Ok((
+            full_height - 2 * self.padding.0,
+            full_width - 2 * self.padding.1,
+        ))
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let (batch_size, height, width) = self.check_input(input)?;
+        let (output_height, output_width) = self.output_size(height, width)?;
+
+        let mut output = vec![0.0; batch_size * self.out_channels * output_height * output_width];
+
+        // Every input pixel scatters a kernel-sized patch into the output
+        for b in 0..batch_size {
+            for in_c in 0..self.in_channels {
+                for in_h in 0..height {
+                    for in_w in 0..width {
+                        let value = input.data
+                            [((b * self.in_channels + in_c) * height + in_h) * width + in_w];
+
+                        for out_c in 0..self.out_channels {
+                            for k_h in 0..self.kernel_size.0 {
+                                let Some(out_h) = self.output_index(in_h, k_h, 0, output_height)
+                                else {
+                                    continue;
+                                };
+                                for k_w in 0..self.kernel_size.1 {
+                                    let Some(out_w) = self.output_index(in_w, k_w, 1, output_width)
+                                    else {
+                                        continue;
+                                    };
+
+                                    let output_idx =
+                                        ((b * self.out_channels + out_c) * output_height + out_h)
+                                            * output_width
+                                            + out_w;
+                                    output[output_idx] += value
+                                        * self.weight.data
+                                            [self.weight_index(in_c, out_c, k_h, k_w)];
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(ref bias) = self.bias {
+            let plane = output_height * output_width;
+            for (i, chunk) in output.chunks_mut(plane).enumerate() {
+                let value = bias.data[i % self.out_channels];
+                chunk.iter_mut().for_each(|o| *o += value);
+            }
+        }
+
+        self.input_cache = Some(input.clone());
+
+        Ok(Tensor::new(
+            output,
+            vec![batch_size, self.out_channels, output_height, output_width],
+            true,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    /// Returns the gradients with respect to the input, weight and bias
+    pub fn backward(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<(Tensor, Tensor, Option<Tensor>), BellandeError> {
+        let input = self
+            .input_cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+
+        let (batch_size, height, width) = (input.shape[0], input.shape[2], input.shape[3]);
+        let (output_height, output_width) = self.output_size(height, width)?;
+        let expected = [batch_size, self.out_channels, output_height, output_width];
+        if grad_output.shape != expected {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected gradient of shape {:?}, got {:?}",
+                expected, grad_output.shape
+            )));
+        }
+
+        let mut grad_input = vec![0.0; input.data.len()];
+        let mut grad_weight = vec![0.0; self.weight.data.len()];
+
+        for b in 0..batch_size {
+            for in_c in 0..self.in_channels {
+                for in_h in 0..height {
+                    for in_w in 0..width {
+                        let input_idx =
+                            ((b * self.in_channels + in_c) * height + in_h) * width + in_w;
+                        let value = input.data[input_idx];
+                        let mut grad = 0.0;
+
+                        for out_c in 0..self.out_channels {
+                            for k_h in 0..self.kernel_size.0 {
+                                let Some(out_h) = self.output_index(in_h, k_h, 0, output_height)
+                                else {
+                                    continue;
+                                };
+                                for k_w in 0..self.kernel_size.1 {
+                                    let Some(out_w) = self.output_index(in_w, k_w, 1, output_width)
+                                    else {
+                                        continue;
+                                    };
+
+                                    let g = grad_output.data[((b * self.out_channels + out_c)
+                                        * output_height
+                                        + out_h)
+                                        * output_width
+                                        + out_w];
+                                    let weight_idx = self.weight_index(in_c, out_c, k_h, k_w);
+                                    grad += g * self.weight.data[weight_idx];
+                                    grad_weight[weight_idx] += g * value;
+                                }
+                            }
+                        }
+
+                        grad_input[input_idx] = grad;
+                    }
+                }
+            }
+        }
+
+        let grad_bias = self.bias.as_ref().map(|bias| {
+            let plane = output_height * output_width;
+            let mut grad_bias = vec![0.0; self.out_channels];
+            for (i, chunk) in grad_output.data.chunks(plane).enumerate() {
+                grad_bias[i % self.out_channels] += chunk.iter().sum::<f32>();
+            }
+            Tensor::new(
+                grad_bias,
+                vec![self.out_channels],
+                true,
+                bias.device.clone(),
+                bias.dtype,
+            )
+        });
+
+        Ok((
+            Tensor::new(
+                grad_input,
+                input.shape.clone(),
+                true,
+                input.device.clone(),
+                input.dtype,
+            ),
+            Tensor::new(
+                grad_weight,
+                self.weight.shape.clone(),
+                true,
+                self.weight.device.clone(),
+                self.weight.dtype,
+            ),
+            grad_bias,
+        ))
+    }
+
+    fn check_input(&self, input: &Tensor) -> Result<(usize, usize, usize), BellandeError> {
+        if input.shape.len() != 4 {
+            return Err(BellandeError::InvalidShape(
+                "Expected 4D tensor (batch_size, channels, height, width)".into(),
+            ));
+        }
+        if input.shape[1] != self.in_channels {
+            return Err(BellandeError::DimensionMismatch);
+        }
+        if input.shape[2] == 0 || input.shape[3] == 0 {
+            return Err(BellandeError::InvalidShape(
+                "Input height and width must be non-zero".into(),
+            ));
+        }
+        Ok((input.shape[0], input.shape[2], input.shape[3]))
+    }
+
+    /// Output row (axis 0) or column (axis 1) that input position `index`
+    /// reaches through kernel offset `k`, if it falls inside the output
+    fn output_index(&self, index: usize, k: usize, axis: usize, size: usize) -> Option<usize> {
+        let (stride, padding) = if axis == 0 {
+            (self.stride.0, self.padding.0)
+        } else {
+            (self.stride.1, self.padding.1)
+        };
+        (index * stride + k)
+            .checked_sub(padding)
+            .filter(|&out| out < size)
+    }
+
+    fn weight_index(&self, in_c: usize, out_c: usize, k_h: usize, k_w: usize) -> usize {
+        ((in_c * self.out_channels + out_c) * self.kernel_size.0 + k_h) * self.kernel_size.1 + k_w
+    }
+}
+
+impl Layer for ConvTranspose2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        ConvTranspose2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_input, grad_weight, grad_bias) = ConvTranspose2d::backward(self, grad)?;
+        accumulate_grad(&mut self.weight, &grad_weight)?;
+        if let (Some(bias), Some(grad_bias)) = (self.bias.as_mut(), grad_bias.as_ref()) {
+            accumulate_grad(bias, grad_bias)?;
+        }
+        Ok(grad_input)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = vec![("weight".to_string(), self.weight.clone())];
+        if let Some(ref bias) = self.bias {
+            params.push(("bias".to_string(), bias.clone()));
+        }
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        let param = match name {
+            "weight" => Some(&mut self.weight),
+            "bias" => self.bias.as_mut(),
+            _ => None,
+        };
+        match param {
+            Some(param) => assign_parameter(param, name, value),
+            None => Err(unknown_parameter(name)),
+        }
+    }
+}
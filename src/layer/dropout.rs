@@ -13,13 +13,15 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
-use rand::Rng;
+use crate::core::{error::BellandeError, random, tensor::Tensor};
+use crate::layer::{ChannelRole, Layer};
 
 pub struct Dropout {
     p: f32,
     mask: Option<Vec<bool>>,
     training: bool,
+    /// Sample masks in evaluation mode too (Monte Carlo dropout)
+    always_active: bool,
 }
 
 impl Dropout {
@@ -29,6 +31,7 @@ impl Dropout {
             p,
             mask: None,
             training: true,
+            always_active: false,
         }
     }
 
@@ -40,15 +43,25 @@ impl Dropout {
         self.training = false;
     }
 
+    pub fn set_always_active(&mut self, active: bool) {
+        self.always_active = active;
+    }
+
+    fn is_active(&self) -> bool {
+        self.training || self.always_active
+    }
+
+    /// Whether `forward` currently leaves its input unchanged
+    pub(crate) fn is_identity(&self) -> bool {
+        self.p == 0.0 || !self.is_active()
+    }
+
     pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
-        if !self.training {
+        if !self.is_active() {
             return Ok(input.clone());
         }
 
-        let mut rng = rand::thread_rng();
-        let mask: Vec<bool> = (0..input.data.len())
-            .map(|_| rng.gen::<f32>() > self.p)
-            .collect();
+        let mask: Vec<bool> = random::bernoulli(1.0 - self.p, input.data.len());
 
         let scale = 1.0 / (1.0 - self.p);
         let output: Vec<f32> = input
@@ -93,3 +106,277 @@ impl Dropout {
         }
     }
 }
+
+impl Layer for Dropout {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        Dropout::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        if !self.is_active() {
+            return Ok(grad.clone());
+        }
+        Dropout::backward(self, grad)
+    }
+
+    fn train(&mut self) {
+        Dropout::train(self)
+    }
+
+    fn eval(&mut self) {
+        Dropout::eval(self)
+    }
+
+    fn set_dropout_active(&mut self, active: bool) {
+        self.set_always_active(active)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+/// Scales the values of kept units by `scale` and zeroes the others, where
+/// each mask entry covers `unit` consecutive values
+fn apply_mask(data: &[f32], mask: &[bool], unit: usize, scale: f32) -> Vec<f32> {
+    data.chunks(unit.max(1))
+        .zip(mask.iter())
+        .flat_map(|(chunk, &keep)| {
+            chunk
+                .iter()
+                .map(move |&x| if keep { x * scale } else { 0.0 })
+        })
+        .collect()
+}
+
+/// Spatial dropout: zeroes entire channels of (N, C, *spatial) feature maps,
+/// since neighbouring activations of a conv map are strongly correlated
+pub struct Dropout2d {
+    p: f32,
+    mask: Option<Vec<bool>>,
+    unit: usize,
+    training: bool,
+    always_active: bool,
+}
+
+impl Dropout2d {
+    pub fn new(p: f32) -> Self {
+        assert!(p >= 0.0 && p < 1.0);
+        Dropout2d {
+            p,
+            mask: None,
+            unit: 0,
+            training: true,
+            always_active: false,
+        }
+    }
+
+    pub fn train(&mut self) {
+        self.training = true;
+    }
+
+    pub fn eval(&mut self) {
+        self.training = false;
+    }
+
+    pub fn set_always_active(&mut self, active: bool) {
+        self.always_active = active;
+    }
+
+    fn is_active(&self) -> bool {
+        self.training || self.always_active
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        if !self.is_active() {
+            return Ok(input.clone());
+        }
+        if input.shape.len() < 3 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Dropout2d expects (N, C, *spatial) input, got {:?}",
+                input.shape
+            )));
+        }
+
+        let channels = input.shape[0] * input.shape[1];
+        let unit: usize = input.shape[2..].iter().product();
+        let mask = random::bernoulli(1.0 - self.p, channels);
+        let output = apply_mask(&input.data, &mask, unit, 1.0 / (1.0 - self.p));
+
+        self.mask = Some(mask);
+        self.unit = unit;
+
+        Ok(Tensor::new(
+            output,
+            input.shape.clone(),
+            input.requires_grad,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    pub fn backward(&self, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+        let mask = self
+            .mask
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let grad = apply_mask(&grad_output.data, mask, self.unit, 1.0 / (1.0 - self.p));
+
+        Ok(Tensor::new(
+            grad,
+            grad_output.shape.clone(),
+            true,
+            grad_output.device.clone(),
+            grad_output.dtype,
+        ))
+    }
+}
+
+impl Layer for Dropout2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        Dropout2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        if !self.is_active() {
+            return Ok(grad.clone());
+        }
+        Dropout2d::backward(self, grad)
+    }
+
+    fn train(&mut self) {
+        Dropout2d::train(self)
+    }
+
+    fn eval(&mut self) {
+        Dropout2d::eval(self)
+    }
+
+    fn set_dropout_active(&mut self, active: bool) {
+        self.set_always_active(active)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
+
+/// Stochastic depth: drops a residual branch for whole samples, so the
+/// block reduces to its shortcut for them. Apply it to the branch output
+/// before adding the identity.
+pub struct DropPath {
+    p: f32,
+    mask: Option<Vec<bool>>,
+    unit: usize,
+    training: bool,
+    always_active: bool,
+}
+
+impl DropPath {
+    pub fn new(p: f32) -> Self {
+        assert!(p >= 0.0 && p < 1.0);
+        DropPath {
+            p,
+            mask: None,
+            unit: 0,
+            training: true,
+            always_active: false,
+        }
+    }
+
+    /// Probability of dropping the branch for a sample
+    pub fn drop_prob(&self) -> f32 {
+        self.p
+    }
+
+    pub fn train(&mut self) {
+        self.training = true;
+    }
+
+    pub fn eval(&mut self) {
+        self.training = false;
+    }
+
+    pub fn set_always_active(&mut self, active: bool) {
+        self.always_active = active;
+    }
+
+    fn is_active(&self) -> bool {
+        (self.training || self.always_active) && self.p > 0.0
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        if !self.is_active() {
+            return Ok(input.clone());
+        }
+        let batch_size = match input.shape.first() {
+            Some(&batch_size) if batch_size > 0 => batch_size,
+            _ => {
+                return Err(BellandeError::InvalidShape(format!(
+                    "DropPath expects a batch dimension, got {:?}",
+                    input.shape
+                )))
+            }
+        };
+
+        let unit = input.data.len() / batch_size;
+        let mask = random::bernoulli(1.0 - self.p, batch_size);
+        let output = apply_mask(&input.data, &mask, unit, 1.0 / (1.0 - self.p));
+
+        self.mask = Some(mask);
+        self.unit = unit;
+
+        Ok(Tensor::new(
+            output,
+            input.shape.clone(),
+            input.requires_grad,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    pub fn backward(&self, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+        if !self.is_active() {
+            return Ok(grad_output.clone());
+        }
+        let mask = self
+            .mask
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let grad = apply_mask(&grad_output.data, mask, self.unit, 1.0 / (1.0 - self.p));
+
+        Ok(Tensor::new(
+            grad,
+            grad_output.shape.clone(),
+            true,
+            grad_output.device.clone(),
+            grad_output.dtype,
+        ))
+    }
+}
+
+impl Layer for DropPath {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        DropPath::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        DropPath::backward(self, grad)
+    }
+
+    fn train(&mut self) {
+        DropPath::train(self)
+    }
+
+    fn eval(&mut self) {
+        DropPath::eval(self)
+    }
+
+    fn set_dropout_active(&mut self, active: bool) {
+        self.set_always_active(active)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, tensor::Tensor};
+use crate::layer::{accumulate_grad, assign_parameter, parameter, unknown_parameter, Layer};
+use std::collections::BTreeMap;
+
+/// Lookup table mapping integer indices to dense vectors. The input holds
+/// indices stored as floats with any shape; the output appends an
+/// `embedding_dim` axis to it.
+pub struct Embedding {
+    num_embeddings: usize,
+    embedding_dim: usize,
+    weight: Tensor,
+    padding_idx: Option<usize>,
+    max_norm: Option<(f32, f32)>,
+    sparse: bool,
+    indices_cache: Option<(Vec<usize>, Vec<usize>)>,
+}
+
+/// Gradient of an embedding table restricted to the rows looked up in the
+/// last forward pass
+#[derive(Debug, Clone)]
+pub struct SparseGrad {
+    /// Touched row indices in ascending order
+    pub rows: Vec<usize>,
+    /// Row gradients, `rows.len() * embedding_dim` values
+    pub values: Vec<f32>,
+}
+
+impl SparseGrad {
+    /// Expands to a dense (num_embeddings * embedding_dim) gradient
+    pub fn to_dense(&self, num_embeddings: usize, dim: usize) -> Vec<f32> {
+        let mut dense = vec![0.0; num_embeddings * dim];
+        for (&row, values) in self.rows.iter().zip(self.values.chunks(dim.max(1))) {
+            dense[row * dim..(row + 1) * dim].copy_from_slice(values);
+        }
+        dense
+    }
+}
+
+impl Embedding {
+    pub fn new(num_embeddings: usize, embedding_dim: usize) -> Self {
+        Embedding {
+            num_embeddings,
+            embedding_dim,
+            weight: parameter(Tensor::randn(&[num_embeddings, embedding_dim])),
+            padding_idx: None,
+            max_norm: None,
+            sparse: false,
+            indices_cache: None,
+        }
+    }
+
+    /// Marks `padding_idx` as padding: its row is zeroed, returned as zeros
+    /// and never receives gradient
+    pub fn with_padding_idx(mut self, padding_idx: usize) -> Result<Self, BellandeError> {
+        if padding_idx >= self.num_embeddings {
+            return Err(BellandeError::InvalidParameter(format!(
+                "padding_idx {} out of range for {} embeddings",
+                padding_idx, self.num_embeddings
+            )));
+        }
+        let dim = self.embedding_dim;
+        self.weight.data[padding_idx * dim..(padding_idx + 1) * dim].fill(0.0);
+        self.padding_idx = Some(padding_idx);
+        Ok(self)
+    }
+
+    /// Renormalizes every looked-up row whose `norm_type`-norm exceeds
+    /// `max_norm` to have norm `max_norm`. Modifies the weight in place.
+    pub fn with_max_norm(mut self, max_norm: f32, norm_type: f32) -> Result<Self, BellandeError> {
+        if max_norm <= 0.0 || norm_type <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "max_norm ({}) and norm_type ({}) must be positive",
+                max_norm, norm_type
+            )));
+        }
+        self.max_norm = Some((max_norm, norm_type));
+        Ok(self)
+    }
+
+    /// Accumulates gradient only into the rows looked up instead of
+    /// building a dense gradient for the whole table
+    pub fn with_sparse_grad(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    pub fn num_embeddings(&self) -> usize {
+        self.num_embeddings
+    }
+
+    pub fn embedding_dim(&self) -> usize {
+        self.embedding_dim
+    }
+
+    pub fn padding_idx(&self) -> Option<usize> {
+        self.padding_idx
+    }
+
+    pub fn weight(&self) -> &Tensor {
+        &self.weight
+    }
+
+    /// Adds a dense (num_embeddings, embedding_dim) gradient to the weight
+    /// gradient, e.g. from an output projection tied to this table
+    pub(crate) fn accumulate_weight_grad(&mut self, grad: &Tensor) -> Result<(), BellandeError> {
+        if grad.shape != self.weight.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected an embedding gradient of shape {:?}, got {:?}",
+                self.weight.shape, grad.shape
+            )));
+        }
+        accumulate_grad(&mut self.weight, grad)?;
+        Ok(())
+    }
+
+    /// Converts the float-encoded input to row indices
+    fn indices(&self, input: &Tensor) -> Result<Vec<usize>, BellandeError> {
+        input
+            .data
+            .iter()
+            .map(|&value| {
+                if value.fract() != 0.0 || value < 0.0 || value >= self.num_embeddings as f32 {
+                    Err(BellandeError::InvalidParameter(format!(
+                        "Embedding index {} out of range for {} embeddings",
+                        value, self.num_embeddings
+                    )))
+                } else {
+                    Ok(value as usize)
+                }
+            })
+            .collect()
+    }
+
+    fn renormalize(&mut self, indices: &[usize], max_norm: f32, norm_type: f32) {
+        let dim = self.embedding_dim;
+        let mut rows = indices.to_vec();
+        rows.sort_unstable();
+        rows.dedup();
+
+        for row in rows {
+            let values = &mut self.weight.data[row * dim..(row + 1) * dim];
+            let norm = values
+                .iter()
+                .map(|v| v.abs().powf(norm_type))
+                .sum::<f32>()
+                .powf(1.0 / norm_type);
+            if norm > max_norm {
+                let scale = max_norm / (norm + numerics().denom_eps);
+                values.iter_mut().for_each(|v| *v *= scale);
+            }
+        }
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let indices = self.indices(input)?;
+        if let Some((max_norm, norm_type)) = self.max_norm {
+            self.renormalize(&indices, max_norm, norm_type);
+        }
+
+        let dim = self.embedding_dim;
+        let mut output = Vec::with_capacity(indices.len() * dim);
+        for &index in &indices {
+            output.extend_from_slice(&self.weight.data[index * dim..(index + 1) * dim]);
+        }
+
+        let mut shape = input.shape.clone();
+        shape.push(dim);
+        self.indices_cache = Some((indices, input.shape.clone()));
+
+        Ok(Tensor::new(
+            output,
+            shape,
+            true,
+            self.weight.device.clone(),
+            self.weight.dtype,
+        ))
+    }
+
+    /// Returns the weight gradient for the rows looked up in the last
+    /// forward pass, summing repeated indices and skipping padding
+    pub fn backward(&self, grad_output: &Tensor) -> Result<SparseGrad, BellandeError> {
+        let (indices, _) = self
+            .indices_cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+
+        let dim = self.embedding_dim;
+        if grad_output.data.len() != indices.len() * dim {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected gradient with {} values, got shape {:?}",
+                indices.len() * dim,
+                grad_output.shape
+            )));
+        }
+
+        let mut rows: BTreeMap<usize, Vec<f32>> = BTreeMap::new();
+        for (&index, grad) in indices.iter().zip(grad_output.data.chunks(dim.max(1))) {
+            if Some(index) == self.padding_idx {
+                continue;
+            }
+            let row = rows.entry(index).or_insert_with(|| vec![0.0; dim]);
+            row.iter_mut().zip(grad).for_each(|(r, &g)| *r += g);
+        }
+
+        Ok(SparseGrad {
+            rows: rows.keys().copied().collect(),
+            values: rows.into_values().flatten().collect(),
+        })
+    }
+}
+
+impl Layer for Embedding {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        Embedding::forward(self, input)
+    }
+
+    /// Accumulates the weight gradient and returns zeros for the input,
+    /// since indices are not differentiable
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let sparse_grad = Embedding::backward(self, grad)?;
+
+        let accumulator = self
+            .weight
+            .grad_accumulator
+            .as_ref()
+            .filter(|accumulator| accumulator.hooks().is_empty());
+        if let (true, Some(accumulator)) = (self.sparse, accumulator) {
+            accumulator.accumulate_rows(
+                &self.weight.shape,
+                &sparse_grad.rows,
+                &sparse_grad.values,
+            );
+        } else {
+            let dense = Tensor::new(
+                sparse_grad.to_dense(self.num_embeddings, self.embedding_dim),
+                self.weight.shape.clone(),
+                false,
+                self.weight.device.clone(),
+                self.weight.dtype,
+            );
+            accumulate_grad(&mut self.weight, &dense)?;
+        }
+
+        let input_shape = self
+            .indices_cache
+            .as_ref()
+            .map(|(_, shape)| shape.clone())
+            .unwrap_or_default();
+        Ok(Tensor::zeros(&input_shape))
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        vec![("weight".to_string(), self.weight.clone())]
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name {
+            "weight" => assign_parameter(&mut self.weight, name, value),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+}
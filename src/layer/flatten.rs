@@ -0,0 +1,75 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{ChannelRole, Layer};
+
+/// Flattens (batch_size, ...) inputs to (batch_size, features), for feeding
+/// convolutional feature maps into `Linear` layers
+pub struct Flatten {
+    input_shape: Option<Vec<usize>>,
+}
+
+impl Flatten {
+    pub fn new() -> Self {
+        Flatten { input_shape: None }
+    }
+}
+
+impl Default for Flatten {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for Flatten {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        if input.shape.is_empty() {
+            return Err(BellandeError::InvalidShape(
+                "Flatten needs a batch dimension".into(),
+            ));
+        }
+
+        let features = input.shape[1..].iter().product();
+        self.input_shape = Some(input.shape.clone());
+        Ok(Tensor::new(
+            input.data.clone(),
+            vec![input.shape[0], features],
+            input.requires_grad,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let shape = self
+            .input_shape
+            .clone()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        Ok(Tensor::new(
+            grad.data.clone(),
+            shape,
+            grad.requires_grad,
+            grad.device.clone(),
+            grad.dtype,
+        ))
+    }
+
+    /// Channels stay contiguous after flattening, so a following `Linear`
+    /// can drop the features of pruned channels
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
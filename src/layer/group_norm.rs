@@ -0,0 +1,412 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::layer_norm::{normalize_rows, normalize_rows_backward, RowStats};
+use crate::layer::{accumulate_grad, assign_parameter, parameter, unknown_parameter, Layer};
+
+/// Normalizes each sample over groups of `num_channels / num_groups`
+/// channels and all spatial positions, for (batch, channels, ...) inputs.
+/// Independent of the batch size and identical in training and evaluation.
+pub struct GroupNorm {
+    num_groups: usize,
+    num_channels: usize,
+    eps: f32,
+    weight: Option<Tensor>,
+    bias: Option<Tensor>,
+    input_cache: Option<NormCache>,
+}
+
+/// Instance normalization: each channel of each sample is normalized over
+/// its spatial positions. By default instance statistics are used in
+/// evaluation too; `with_running_stats` tracks running averages that
+/// replace them in evaluation mode.
+pub struct InstanceNorm2d {
+    norm: GroupNorm,
+    running: Option<RunningStats>,
+    training: bool,
+}
+
+struct RunningStats {
+    momentum: f32,
+    mean: Tensor,
+    var: Tensor,
+}
+
+struct NormCache {
+    shape: Vec<usize>,
+    normalized: Vec<f32>,
+    inv_std: Vec<f32>,
+    /// Whether the statistics were computed from the input rather than
+    /// taken from running averages
+    input_stats: bool,
+}
+
+impl GroupNorm {
+    pub fn new(
+        num_groups: usize,
+        num_channels: usize,
+        eps: f32,
+        affine: bool,
+    ) -> Result<Self, BellandeError> {
+        if num_groups == 0 || num_channels % num_groups != 0 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "num_groups ({}) must divide num_channels ({})",
+                num_groups, num_channels
+            )));
+        }
+
+        Ok(GroupNorm {
+            num_groups,
+            num_channels,
+            eps,
+            weight: affine.then(|| parameter(Tensor::ones(&[num_channels]))),
+            bias: affine.then(|| parameter(Tensor::zeros(&[num_channels]))),
+            input_cache: None,
+        })
+    }
+
+    /// Number of spatial positions per channel
+    fn positions(&self, input: &Tensor) -> Result<usize, BellandeError> {
+        if input.shape.len() < 2 || input.shape[1] != self.num_channels {
+            return Err(BellandeError::InvalidShape(format!(
+                "Expected (batch_size, {}, ...) input, got {:?}",
+                self.num_channels, input.shape
+            )));
+        }
+        Ok(input.shape[2..].iter().product())
+    }
+
+    fn normalize(&self, input: &Tensor) -> Result<RowStats, BellandeError> {
+        let positions = self.positions(input)?;
+        let group_size = self.num_channels / self.num_groups * positions;
+        Ok(normalize_rows(&input.data, group_size, self.eps))
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let stats = self.normalize(input)?;
+        self.finish_forward(input, stats.normalized, stats.inv_std, true)
+    }
+
+    /// Applies the affine transform, caches what backward needs and wraps
+    /// the output
+    fn finish_forward(
+        &mut self,
+        input: &Tensor,
+        normalized: Vec<f32>,
+        inv_std: Vec<f32>,
+        input_stats: bool,
+    ) -> Result<Tensor, BellandeError> {
+        let positions = self.positions(input)?;
+        let mut output = normalized.clone();
+        for (i, value) in output.iter_mut().enumerate() {
+            let channel = (i / positions.max(1)) % self.num_channels;
+            if let Some(ref weight) = self.weight {
+                *value *= weight.data[channel];
+            }
+            if let Some(ref bias) = self.bias {
+                *value += bias.data[channel];
+            }
+        }
+
+        self.input_cache = Some(NormCache {
+            shape: input.shape.clone(),
+            normalized,
+            inv_std,
+            input_stats,
+        });
+
+        Ok(Tensor::new(
+            output,
+            input.shape.clone(),
+            input.requires_grad,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    /// Returns the input gradient and, when affine, the weight and bias
+    /// gradients for the last forward call
+    pub fn backward(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<(Tensor, Option<Tensor>, Option<Tensor>), BellandeError> {
+        let cache = self
+            .input_cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        if grad_output.shape != cache.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected output gradient of shape {:?}, got {:?}",
+                cache.shape, grad_output.shape
+            )));
+        }
+
+        let positions: usize = cache.shape[2..].iter().product::<usize>().max(1);
+        let mut grad_normalized = grad_output.data.clone();
+        let mut grad_weight = vec![0.0; self.num_channels];
+        let mut grad_bias = vec![0.0; self.num_channels];
+
+        for (i, (g, &x_hat)) in grad_normalized
+            .iter_mut()
+            .zip(cache.normalized.iter())
+            .enumerate()
+        {
+            let channel = (i / positions) % self.num_channels;
+            grad_weight[channel] += *g * x_hat;
+            grad_bias[channel] += *g;
+            if let Some(ref weight) = self.weight {
+                *g *= weight.data[channel];
+            }
+        }
+
+        let row_len = grad_normalized.len() / cache.inv_std.len().max(1);
+        let grad_input = if cache.input_stats {
+            normalize_rows_backward(&grad_normalized, &cache.normalized, &cache.inv_std, row_len)
+        } else {
+            // Fixed statistics: the normalization is an affine map
+            grad_normalized
+                .chunks(row_len.max(1))
+                .zip(cache.inv_std.iter())
+                .flat_map(|(grad, &inv_std)| grad.iter().map(move |g| g * inv_std))
+                .collect()
+        };
+
+        let per_channel = |data: Vec<f32>, param: &Tensor| {
+            Tensor::new(
+                data,
+                vec![self.num_channels],
+                true,
+                param.device.clone(),
+                param.dtype,
+            )
+        };
+        Ok((
+            Tensor::new(
+                grad_input,
+                grad_output.shape.clone(),
+                true,
+                grad_output.device.clone(),
+                grad_output.dtype,
+            ),
+            self.weight
+                .as_ref()
+                .map(|weight| per_channel(grad_weight, weight)),
+            self.bias.as_ref().map(|bias| per_channel(grad_bias, bias)),
+        ))
+    }
+
+    fn layer_backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_input, grad_weight, grad_bias) = GroupNorm::backward(self, grad)?;
+        if let (Some(weight), Some(grad_weight)) = (self.weight.as_mut(), grad_weight.as_ref()) {
+            accumulate_grad(weight, grad_weight)?;
+        }
+        if let (Some(bias), Some(grad_bias)) = (self.bias.as_mut(), grad_bias.as_ref()) {
+            accumulate_grad(bias, grad_bias)?;
+        }
+        Ok(grad_input)
+    }
+}
+
+impl InstanceNorm2d {
+    pub fn new(num_features: usize, eps: f32, affine: bool) -> Self {
+        InstanceNorm2d {
+            norm: GroupNorm {
+                num_groups: num_features,
+                num_channels: num_features,
+                eps,
+                weight: affine.then(|| parameter(Tensor::ones(&[num_features]))),
+                bias: affine.then(|| parameter(Tensor::zeros(&[num_features]))),
+                input_cache: None,
+            },
+            running: None,
+            training: true,
+        }
+    }
+
+    /// Tracks running averages of the per-channel statistics, with
+    /// `momentum` the weight of each new batch, and uses them in
+    /// evaluation mode
+    pub fn with_running_stats(mut self, momentum: f32) -> Self {
+        let channels = self.norm.num_channels;
+        self.running = Some(RunningStats {
+            momentum,
+            mean: Tensor::zeros(&[channels]),
+            var: Tensor::ones(&[channels]),
+        });
+        self
+    }
+
+    pub fn train(&mut self) {
+        self.training = true;
+    }
+
+    pub fn eval(&mut self) {
+        self.training = false;
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        if input.shape.len() != 4 {
+            return Err(BellandeError::InvalidShape(
+                "Expected 4D tensor (batch_size, channels, height, width)".into(),
+            ));
+        }
+
+        let channels = self.norm.num_channels;
+        let positions = self.norm.positions(input)?;
+
+        if let (false, Some(running)) = (self.training, self.running.as_ref()) {
+            let mut normalized = input.data.clone();
+            let mut inv_std = Vec::with_capacity(input.shape[0] * channels);
+            for (row, channel) in normalized
+                .chunks_mut(positions.max(1))
+                .zip((0..channels).cycle())
+            {
+                let mean = running.mean.data[channel];
+                let scale = 1.0 / (running.var.data[channel] + self.norm.eps).sqrt();
+                row.iter_mut().for_each(|x| *x = (*x - mean) * scale);
+                inv_std.push(scale);
+            }
+            return self.norm.finish_forward(input, normalized, inv_std, false);
+        }
+
+        let stats = self.norm.normalize(input)?;
+        if let (true, Some(running)) = (self.training, self.running.as_mut()) {
+            let batch_size = input.shape[0] as f32;
+            let unbiased = if positions > 1 {
+                positions as f32 / (positions - 1) as f32
+            } else {
+                1.0
+            };
+
+            let mut batch_mean = vec![0.0; channels];
+            let mut batch_var = vec![0.0; channels];
+            for (row, (&mean, &inv_std)) in stats.mean.iter().zip(stats.inv_std.iter()).enumerate()
+            {
+                let channel = row % channels;
+                batch_mean[channel] += mean / batch_size;
+                let var = (1.0 / (inv_std * inv_std) - self.norm.eps).max(0.0);
+                batch_var[channel] += var * unbiased / batch_size;
+            }
+
+            let momentum = running.momentum;
+            for (r, b) in running.mean.data.iter_mut().zip(batch_mean) {
+                *r = (1.0 - momentum) * *r + momentum * b;
+            }
+            for (r, b) in running.var.data.iter_mut().zip(batch_var) {
+                *r = (1.0 - momentum) * *r + momentum * b;
+            }
+        }
+
+        self.norm
+            .finish_forward(input, stats.normalized, stats.inv_std, true)
+    }
+
+    /// Returns the input gradient and, when affine, the weight and bias
+    /// gradients for the last forward call
+    pub fn backward(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<(Tensor, Option<Tensor>, Option<Tensor>), BellandeError> {
+        self.norm.backward(grad_output)
+    }
+}
+
+/// Affine parameters shared by both layers, under the same names
+fn affine_parameters(norm: &GroupNorm) -> Vec<(String, Tensor)> {
+    let mut params = Vec::new();
+    if let Some(ref weight) = norm.weight {
+        params.push(("weight".to_string(), weight.clone()));
+    }
+    if let Some(ref bias) = norm.bias {
+        params.push(("bias".to_string(), bias.clone()));
+    }
+    params
+}
+
+fn affine_parameter<'a>(norm: &'a mut GroupNorm, name: &str) -> Option<&'a mut Tensor> {
+    match name {
+        "weight" => norm.weight.as_mut(),
+        "bias" => norm.bias.as_mut(),
+        _ => None,
+    }
+}
+
+impl Layer for GroupNorm {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        GroupNorm::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        self.layer_backward(grad)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        affine_parameters(self)
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match affine_parameter(self, name) {
+            Some(param) => assign_parameter(param, name, value),
+            None => Err(unknown_parameter(name)),
+        }
+    }
+}
+
+impl Layer for InstanceNorm2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        InstanceNorm2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        self.norm.layer_backward(grad)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        affine_parameters(&self.norm)
+            .into_iter()
+            .map(|(_, param)| param)
+            .collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = affine_parameters(&self.norm);
+        if let Some(ref running) = self.running {
+            params.push(("running_mean".to_string(), running.mean.clone()));
+            params.push(("running_var".to_string(), running.var.clone()));
+        }
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        let param = match (name, self.running.as_mut()) {
+            ("running_mean", Some(running)) => Some(&mut running.mean),
+            ("running_var", Some(running)) => Some(&mut running.var),
+            _ => affine_parameter(&mut self.norm, name),
+        };
+        match param {
+            Some(param) => assign_parameter(param, name, value),
+            None => Err(unknown_parameter(name)),
+        }
+    }
+
+    fn train(&mut self) {
+        InstanceNorm2d::train(self)
+    }
+
+    fn eval(&mut self) {
+        InstanceNorm2d::eval(self)
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/// Shape of a single-image convolution
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ConvGeometry {
+    pub channels: usize,
+    pub height: usize,
+    pub width: usize,
+    pub kernel: (usize, usize),
+    pub stride: (usize, usize),
+    pub padding: (usize, usize),
+    pub output_height: usize,
+    pub output_width: usize,
+}
+
+impl ConvGeometry {
+    /// Rows of the column matrix: one per (channel, kernel_h, kernel_w)
+    pub fn col_rows(&self) -> usize {
+        self.channels * self.kernel.0 * self.kernel.1
+    }
+
+    /// Columns of the column matrix: one per output position
+    pub fn col_cols(&self) -> usize {
+        self.output_height * self.output_width
+    }
+
+    /// Input coordinate read by kernel offset `k` at output position `out`,
+    /// or `None` when it falls in the padding
+    fn source(out: usize, k: usize, stride: usize, padding: usize, size: usize) -> Option<usize> {
+        (out * stride + k)
+            .checked_sub(padding)
+            .filter(|&index| index < size)
+    }
+}
+
+/// Unfolds a (channels, height, width) image into a
+/// (channels * kh * kw, out_h * out_w) matrix, zero in the padding. Each
+/// column is one receptive field, so a convolution becomes the product
+/// `weight (out_channels, channels * kh * kw) x columns`.
+pub(crate) fn im2col(image: &[f32], geometry: &ConvGeometry) -> Vec<f32> {
+    let g = geometry;
+    let cols = g.col_cols();
+    let mut output = vec![0.0; g.col_rows() * cols];
+
+    for c in 0..g.channels {
+        let plane = &image[c * g.height * g.width..(c + 1) * g.height * g.width];
+        for k_h in 0..g.kernel.0 {
+            for k_w in 0..g.kernel.1 {
+                let row = (c * g.kernel.0 + k_h) * g.kernel.1 + k_w;
+                let row = &mut output[row * cols..(row + 1) * cols];
+
+                for out_h in 0..g.output_height {
+                    let Some(in_h) =
+                        ConvGeometry::source(out_h, k_h, g.stride.0, g.padding.0, g.height)
+                    else {
+                        continue;
+                    };
+                    for out_w in 0..g.output_width {
+                        if let Some(in_w) =
+                            ConvGeometry::source(out_w, k_w, g.stride.1, g.padding.1, g.width)
+                        {
+                            row[out_h * g.output_width + out_w] = plane[in_h * g.width + in_w];
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    output
+}
+
+/// Inverse of `im2col` for gradients: adds every column entry back onto
+/// the image position it was read from
+pub(crate) fn col2im(cols: &[f32], geometry: &ConvGeometry, image: &mut [f32]) {
+    let g = geometry;
+    let num_cols = g.col_cols();
+
+    for c in 0..g.channels {
+        let plane = &mut image[c * g.height * g.width..(c + 1) * g.height * g.width];
+        for k_h in 0..g.kernel.0 {
+            for k_w in 0..g.kernel.1 {
+                let row = (c * g.kernel.0 + k_h) * g.kernel.1 + k_w;
+                let row = &cols[row * num_cols..(row + 1) * num_cols];
+
+                for out_h in 0..g.output_height {
+                    let Some(in_h) =
+                        ConvGeometry::source(out_h, k_h, g.stride.0, g.padding.0, g.height)
+                    else {
+                        continue;
+                    };
+                    for out_w in 0..g.output_width {
+                        if let Some(in_w) =
+                            ConvGeometry::source(out_w, k_w, g.stride.1, g.padding.1, g.width)
+                        {
+                            plane[in_h * g.width + in_w] += row[out_h * g.output_width + out_w];
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `c += a x b` for row-major a (m, k) and b (k, n)
+pub(crate) fn gemm(a: &[f32], b: &[f32], c: &mut [f32], m: usize, k: usize, n: usize) {
+    for i in 0..m {
+        let c_row = &mut c[i * n..(i + 1) * n];
+        for p in 0..k {
+            let a_ip = a[i * k + p];
+            if a_ip == 0.0 {
+                continue;
+            }
+            let b_row = &b[p * n..(p + 1) * n];
+            for (c_ij, &b_pj) in c_row.iter_mut().zip(b_row) {
+                *c_ij += a_ip * b_pj;
+            }
+        }
+    }
+}
+
+/// `c += a^T x b` for row-major a (k, m) and b (k, n)
+pub(crate) fn gemm_at(a: &[f32], b: &[f32], c: &mut [f32], m: usize, k: usize, n: usize) {
+    for p in 0..k {
+        let b_row = &b[p * n..(p + 1) * n];
+        for i in 0..m {
+            let a_pi = a[p * m + i];
+            if a_pi == 0.0 {
+                continue;
+            }
+            for (c_ij, &b_pj) in c[i * n..(i + 1) * n].iter_mut().zip(b_row) {
+                *c_ij += a_pi * b_pj;
+            }
+        }
+    }
+}
+
+/// `c += a x b^T` for row-major a (m, k) and b (n, k)
+pub(crate) fn gemm_bt(a: &[f32], b: &[f32], c: &mut [f32], m: usize, k: usize, n: usize) {
+    for i in 0..m {
+        let a_row = &a[i * k..(i + 1) * k];
+        for j in 0..n {
+            let b_row = &b[j * k..(j + 1) * k];
+            c[i * n + j] += a_row.iter().zip(b_row).map(|(x, y)| x * y).sum::<f32>();
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, random, tensor::Tensor};
+use crate::layer::parameter;
+
+/// Activation following a layer, used to pick the initialization gain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nonlinearity {
+    Linear,
+    Sigmoid,
+    Tanh,
+    ReLU,
+    /// Leaky ReLU with the given negative slope
+    LeakyReLU(f32),
+}
+
+impl Nonlinearity {
+    /// Recommended scaling of the weight standard deviation, matching
+    /// PyTorch's `calculate_gain`
+    pub fn gain(&self) -> f32 {
+        match *self {
+            Nonlinearity::Linear | Nonlinearity::Sigmoid => 1.0,
+            Nonlinearity::Tanh => 5.0 / 3.0,
+            Nonlinearity::ReLU => 2f32.sqrt(),
+            Nonlinearity::LeakyReLU(slope) => (2.0 / (1.0 + slope * slope)).sqrt(),
+        }
+    }
+}
+
+/// Which fan Kaiming initialization preserves the variance of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanMode {
+    /// Activations in the forward pass
+    FanIn,
+    /// Gradients in the backward pass
+    FanOut,
+}
+
+/// Weight initialization schemes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    Constant(f32),
+    /// `U(-a, a)` with `a = gain * sqrt(6 / (fan_in + fan_out))`
+    /// (Glorot and Bengio, 2010)
+    XavierUniform {
+        gain: f32,
+    },
+    /// `N(0, std^2)` with `std = gain * sqrt(2 / (fan_in + fan_out))`
+    XavierNormal {
+        gain: f32,
+    },
+    /// `U(-b, b)` with `b = gain * sqrt(3 / fan)` (He et al., 2015)
+    KaimingUniform {
+        mode: FanMode,
+        nonlinearity: Nonlinearity,
+    },
+    /// `N(0, std^2)` with `std = gain / sqrt(fan)`
+    KaimingNormal {
+        mode: FanMode,
+        nonlinearity: Nonlinearity,
+    },
+    /// (Semi-)orthogonal matrix over the first dimension and the flattened
+    /// remaining ones, scaled by `gain` (Saxe et al., 2013)
+    Orthogonal {
+        gain: f32,
+    },
+    /// `N(mean, std^2)` restricted to `[a, b]` by resampling
+    TruncatedNormal {
+        mean: f32,
+        std: f32,
+        a: f32,
+        b: f32,
+    },
+}
+
+impl Init {
+    /// Overwrites the values of `tensor`
+    pub fn apply(&self, tensor: &mut Tensor) -> Result<(), BellandeError> {
+        let len = tensor.data.len();
+        tensor.data = match *self {
+            Init::Constant(value) => vec![value; len],
+            Init::XavierUniform { gain } => {
+                let (fan_in, fan_out) = fan_in_and_fan_out(&tensor.shape)?;
+                let bound = gain * (6.0 / (fan_in + fan_out) as f32).sqrt();
+                uniform(bound, len)
+            }
+            Init::XavierNormal { gain } => {
+                let (fan_in, fan_out) = fan_in_and_fan_out(&tensor.shape)?;
+                random::normal(0.0, gain * (2.0 / (fan_in + fan_out) as f32).sqrt(), len)
+            }
+            Init::KaimingUniform { mode, nonlinearity } => {
+                let fan = select_fan(&tensor.shape, mode)?;
+                uniform(nonlinearity.gain() * (3.0 / fan as f32).sqrt(), len)
+            }
+            Init::KaimingNormal { mode, nonlinearity } => {
+                let fan = select_fan(&tensor.shape, mode)?;
+                random::normal(0.0, nonlinearity.gain() / (fan as f32).sqrt(), len)
+            }
+            Init::Orthogonal { gain } => orthogonal(&tensor.shape, gain)?,
+            Init::TruncatedNormal { mean, std, a, b } => truncated_normal(mean, std, a, b, len)?,
+        };
+        Ok(())
+    }
+
+    /// A new tensor of `shape` initialized with this scheme
+    pub fn tensor(&self, shape: &[usize]) -> Result<Tensor, BellandeError> {
+        let mut tensor = Tensor::zeros(shape);
+        self.apply(&mut tensor)?;
+        Ok(tensor)
+    }
+
+    /// Initializer for `Model::apply_init` that reinitializes every tensor
+    /// with at least two dimensions (weight matrices and kernels) and leaves
+    /// biases, normalization parameters and running statistics alone
+    pub fn for_weights(self) -> impl FnMut(&str, &mut Tensor) -> Result<(), BellandeError> {
+        move |_name, tensor| {
+            if tensor.shape.len() >= 2 {
+                self.apply(tensor)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// (fan_in, fan_out) of a weight shaped (out, in, *kernel), as PyTorch
+/// computes them
+pub fn fan_in_and_fan_out(shape: &[usize]) -> Result<(usize, usize), BellandeError> {
+    if shape.len() < 2 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Fan in and fan out need at least 2 dimensions, got {:?}",
+            shape
+        )));
+    }
+    let receptive_field: usize = shape[2..].iter().product();
+    Ok((
+        (shape[1] * receptive_field).max(1),
+        (shape[0] * receptive_field).max(1),
+    ))
+}
+
+fn select_fan(shape: &[usize], mode: FanMode) -> Result<usize, BellandeError> {
+    let (fan_in, fan_out) = fan_in_and_fan_out(shape)?;
+    Ok(match mode {
+        FanMode::FanIn => fan_in,
+        FanMode::FanOut => fan_out,
+    })
+}
+
+fn uniform(bound: f32, len: usize) -> Vec<f32> {
+    if bound > 0.0 {
+        random::uniform(-bound, bound, len)
+    } else {
+        vec![0.0; len]
+    }
+}
+
+/// Default weights of `Linear` and convolution layers, as in PyTorch:
+/// Kaiming uniform with a leaky ReLU slope of `sqrt(5)`, which reduces to
+/// `U(-1 / sqrt(fan_in), 1 / sqrt(fan_in))`
+pub(crate) fn default_weight(shape: &[usize]) -> Tensor {
+    let fan_in: usize = shape[1..].iter().product();
+    uniform_tensor(shape, 1.0 / (fan_in.max(1) as f32).sqrt())
+}
+
+/// A tensor of `shape` drawn from `U(-bound, bound)`
+pub(crate) fn uniform_tensor(shape: &[usize], bound: f32) -> Tensor {
+    let mut tensor = Tensor::zeros(shape);
+    tensor.data = uniform(bound, tensor.data.len());
+    parameter(tensor)
+}
+
+/// Orthonormalizes the rows or columns of a normal matrix, whichever are
+/// fewer, with modified Gram-Schmidt
+fn orthogonal(shape: &[usize], gain: f32) -> Result<Vec<f32>, BellandeError> {
+    if shape.len() < 2 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Orthogonal initialization needs at least 2 dimensions, got {:?}",
+            shape
+        )));
+    }
+    let rows = shape[0];
+    let cols: usize = shape[1..].iter().product();
+    if rows == 0 || cols == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Orthonormal vectors are the rows of a (count, len) matrix
+    let (count, len) = if rows <= cols {
+        (rows, cols)
+    } else {
+        (cols, rows)
+    };
+    let mut vectors: Vec<Vec<f32>> = (0..count).map(|_| random::normal(0.0, 1.0, len)).collect();
+    let denom_eps = numerics().denom_eps;
+    for i in 0..count {
+        let (done, rest) = vectors.split_at_mut(i);
+        let v = &mut rest[0];
+        for u in done.iter() {
+            let dot: f32 = u.iter().zip(v.iter()).map(|(a, b)| a * b).sum();
+            v.iter_mut().zip(u).for_each(|(x, y)| *x -= dot * y);
+        }
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt().max(denom_eps);
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+
+    let mut data = vec![0.0; rows * cols];
+    for (i, vector) in vectors.iter().enumerate() {
+        for (j, &value) in vector.iter().enumerate() {
+            let index = if rows <= cols {
+                i * cols + j
+            } else {
+                j * cols + i
+            };
+            data[index] = gain * value;
+        }
+    }
+    Ok(data)
+}
+
+fn truncated_normal(
+    mean: f32,
+    std: f32,
+    a: f32,
+    b: f32,
+    len: usize,
+) -> Result<Vec<f32>, BellandeError> {
+    if a.is_nan() || b.is_nan() || a >= b || !std.is_finite() || std < 0.0 {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Truncated normal needs a < b and std >= 0, got [{}, {}] and std {}",
+            a, b, std
+        )));
+    }
+    if mean + 3.0 * std < a || mean - 3.0 * std > b {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Interval [{}, {}] holds almost no mass of N({}, {}^2)",
+            a, b, mean, std
+        )));
+    }
+
+    let mut values = Vec::with_capacity(len);
+    while values.len() < len {
+        let needed = len - values.len();
+        values.extend(
+            random::normal(mean, std, needed.max(16))
+                .into_iter()
+                .filter(|v| (a..=b).contains(v))
+                .take(needed),
+        );
+    }
+    Ok(values)
+}
@@ -14,7 +14,11 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{accumulate_grad, assign_parameter, parameter, unknown_parameter, Layer};
 
+/// Normalizes each sample over its trailing `normalized_shape` dimensions,
+/// e.g. the embedding dimension of (batch, seq_len, embed_dim) inputs. Uses
+/// the same statistics in training and evaluation.
 pub struct LayerNorm {
     normalized_shape: Vec<usize>,
     weight: Option<Tensor>,
@@ -24,22 +28,82 @@ pub struct LayerNorm {
 }
 
 struct LayerNormCache {
-    input: Tensor,
-    normalized: Tensor,
-    std: Vec<f32>,
-    mean: Vec<f32>,
+    shape: Vec<usize>,
+    normalized: Vec<f32>,
+    inv_std: Vec<f32>,
+}
+
+/// Per-row statistics returned by `normalize_rows`
+pub(crate) struct RowStats {
+    pub normalized: Vec<f32>,
+    pub mean: Vec<f32>,
+    pub inv_std: Vec<f32>,
+}
+
+/// Normalizes consecutive rows of `row_len` values to zero mean and unit
+/// variance
+pub(crate) fn normalize_rows(data: &[f32], row_len: usize, eps: f32) -> RowStats {
+    let rows = if row_len == 0 {
+        0
+    } else {
+        data.len() / row_len
+    };
+    let mut stats = RowStats {
+        normalized: Vec::with_capacity(data.len()),
+        mean: Vec::with_capacity(rows),
+        inv_std: Vec::with_capacity(rows),
+    };
+
+    for row in data.chunks(row_len.max(1)) {
+        let mean = row.iter().sum::<f32>() / row_len as f32;
+        let variance = row.iter().map(|&x| (x - mean).powi(2)).sum::<f32>() / row_len as f32;
+        let inv_std = 1.0 / (variance + eps).sqrt();
+
+        stats
+            .normalized
+            .extend(row.iter().map(|&x| (x - mean) * inv_std));
+        stats.mean.push(mean);
+        stats.inv_std.push(inv_std);
+    }
+    stats
+}
+
+/// Input gradient of `normalize_rows` given the gradient with respect to
+/// its normalized output
+pub(crate) fn normalize_rows_backward(
+    grad_normalized: &[f32],
+    normalized: &[f32],
+    inv_std: &[f32],
+    row_len: usize,
+) -> Vec<f32> {
+    let mut grad_input = Vec::with_capacity(grad_normalized.len());
+    let rows = grad_normalized
+        .chunks(row_len.max(1))
+        .zip(normalized.chunks(row_len.max(1)));
+
+    for ((grad, x_hat), &inv_std) in rows.zip(inv_std.iter()) {
+        let mean_grad = grad.iter().sum::<f32>() / row_len as f32;
+        let mean_grad_x_hat =
+            grad.iter().zip(x_hat).map(|(g, x)| g * x).sum::<f32>() / row_len as f32;
+        grad_input.extend(
+            grad.iter()
+                .zip(x_hat)
+                .map(|(&g, &x)| inv_std * (g - mean_grad - x * mean_grad_x_hat)),
+        );
+    }
+    grad_input
 }
 
 impl LayerNorm {
     pub fn new(normalized_shape: Vec<usize>, eps: f32, elementwise_affine: bool) -> Self {
         let weight = if elementwise_affine {
-            Some(Tensor::ones(&normalized_shape))
+            Some(parameter(Tensor::ones(&normalized_shape)))
         } else {
             None
         };
 
         let bias = if elementwise_affine {
-            Some(Tensor::zeros(&normalized_shape))
+            Some(parameter(Tensor::zeros(&normalized_shape)))
         } else {
             None
         };
@@ -54,63 +118,35 @@ impl LayerNorm {
     }
 
     pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
-        let batch_size = input.shape[0];
-        let feature_size: usize = self.normalized_shape.iter().product();
-
-        if input.shape[1..] != self.normalized_shape[..] {
+        let rank = self.normalized_shape.len();
+        if input.shape.len() <= rank
+            || input.shape[input.shape.len() - rank..] != self.normalized_shape[..]
+        {
             return Err(BellandeError::InvalidShape(format!(
-                "Expected shape {:?}, got {:?}",
-                self.normalized_shape,
-                input.shape[1..]
+                "Expected input ending in {:?} after a batch dimension, got {:?}",
+                self.normalized_shape, input.shape
             )));
         }
 
-        let mut output = input.data.clone();
-        let mut mean = vec![0.0; batch_size];
-        let mut std = vec![0.0; batch_size];
-
-        // Calculate mean and standard deviation
-        for b in 0..batch_size {
-            let start_idx = b * feature_size;
-            let end_idx = start_idx + feature_size;
-            let batch_data = &input.data[start_idx..end_idx];
-
-            // Calculate mean
-            mean[b] = batch_data.iter().sum::<f32>() / feature_size as f32;
-
-            // Calculate variance
-            let variance: f32 = batch_data
-                .iter()
-                .map(|&x| (x - mean[b]).powi(2))
-                .sum::<f32>()
-                / feature_size as f32;
-
-            std[b] = (variance + self.eps).sqrt();
-
-            // Normalize
-            for i in 0..feature_size {
-                let idx = start_idx + i;
-                output[idx] = (input.data[idx] - mean[b]) / std[b];
-
-                // Apply affine transform if available
-                if let (Some(ref weight), Some(ref bias)) = (&self.weight, &self.bias) {
-                    output[idx] = output[idx] * weight.data[i] + bias.data[i];
-                }
+        let feature_size: usize = self.normalized_shape.iter().product();
+        let stats = normalize_rows(&input.data, feature_size, self.eps);
+
+        let mut output = stats.normalized.clone();
+        for (i, value) in output.iter_mut().enumerate() {
+            let feature = i % feature_size;
+            if let Some(ref weight) = self.weight {
+                *value *= weight.data[feature];
+            }
+            if let Some(ref bias) = self.bias {
+                *value += bias.data[feature];
             }
         }
 
         // Cache for backward pass
         self.input_cache = Some(LayerNormCache {
-            input: input.clone(),
-            normalized: Tensor::new(
-                output.clone(),
-                input.shape.clone(),
-                true,
-                input.device.clone(),
-                input.dtype,
-            ),
-            std,
-            mean,
+            shape: input.shape.clone(),
+            normalized: stats.normalized,
+            inv_std: stats.inv_std,
         });
 
         Ok(Tensor::new(
@@ -122,64 +158,70 @@ impl LayerNorm {
         ))
     }
 
-    pub fn backward(&self, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
-        if let Some(ref cache) = self.input_cache {
-            let batch_size = grad_output.shape[0];
-            let feature_size = self.normalized_shape.iter().product();
-            let mut grad_input = vec![0.0; grad_output.data.len()];
-
-            for b in 0..batch_size {
-                let start_idx = b * feature_size;
-                let end_idx = start_idx + feature_size;
-
-                let batch_grad = &grad_output.data[start_idx..end_idx];
-                let batch_input = &cache.input.data[start_idx..end_idx];
-                let mean = cache.mean[b];
-                let std = cache.std[b];
-
-                // Calculate gradients
-                let mut sum_grad = 0.0;
-                let mut sum_grad_h = 0.0;
-
-                for i in 0..feature_size {
-                    let idx = start_idx + i;
-                    let h = (batch_input[i] - mean) / std;
-
-                    if let (Some(ref weight), Some(ref bias)) = (&self.weight, &self.bias) {
-                        sum_grad += grad_output.data[idx] * weight.data[i];
-                        sum_grad_h += grad_output.data[idx] * weight.data[i] * h;
-                    } else {
-                        sum_grad += grad_output.data[idx];
-                        sum_grad_h += grad_output.data[idx] * h;
-                    }
-                }
-
-                // Apply gradients
-                for i in 0..feature_size {
-                    let idx = start_idx + i;
-                    let h = (batch_input[i] - mean) / std;
-
-                    grad_input[idx] = (1.0 / (feature_size as f32 * std))
-                        * (feature_size as f32 * grad_output.data[idx] - sum_grad - h * sum_grad_h);
-
-                    if let (Some(ref weight), _) = (&self.weight, &self.bias) {
-                        grad_input[idx] *= weight.data[i];
-                    }
-                }
+    /// Returns the input gradient and, when affine, the weight and bias
+    /// gradients for the last forward call
+    pub fn backward(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<(Tensor, Option<Tensor>, Option<Tensor>), BellandeError> {
+        let cache = self
+            .input_cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        if grad_output.shape != cache.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected output gradient of shape {:?}, got {:?}",
+                cache.shape, grad_output.shape
+            )));
+        }
+
+        let feature_size: usize = self.normalized_shape.iter().product();
+        let mut grad_normalized = grad_output.data.clone();
+        let mut grad_weight = vec![0.0; feature_size];
+        let mut grad_bias = vec![0.0; feature_size];
+
+        for (i, (g, &x_hat)) in grad_normalized
+            .iter_mut()
+            .zip(cache.normalized.iter())
+            .enumerate()
+        {
+            let feature = i % feature_size;
+            grad_weight[feature] += *g * x_hat;
+            grad_bias[feature] += *g;
+            if let Some(ref weight) = self.weight {
+                *g *= weight.data[feature];
             }
+        }
+
+        let grad_input = normalize_rows_backward(
+            &grad_normalized,
+            &cache.normalized,
+            &cache.inv_std,
+            feature_size,
+        );
 
-            Ok(Tensor::new(
+        let like_param = |data: Vec<f32>, param: &Tensor| {
+            Tensor::new(
+                data,
+                self.normalized_shape.clone(),
+                true,
+                param.device.clone(),
+                param.dtype,
+            )
+        };
+        Ok((
+            Tensor::new(
                 grad_input,
                 grad_output.shape.clone(),
                 true,
                 grad_output.device.clone(),
                 grad_output.dtype,
-            ))
-        } else {
-            Err(BellandeError::RuntimeError(
-                "Forward pass not called".into(),
-            ))
-        }
+            ),
+            self.weight
+                .as_ref()
+                .map(|weight| like_param(grad_weight, weight)),
+            self.bias.as_ref().map(|bias| like_param(grad_bias, bias)),
+        ))
     }
 
     pub fn parameters(&self) -> Vec<Tensor> {
@@ -193,3 +235,47 @@ impl LayerNorm {
         params
     }
 }
+
+impl Layer for LayerNorm {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        LayerNorm::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_input, grad_weight, grad_bias) = LayerNorm::backward(self, grad)?;
+        if let (Some(weight), Some(grad_weight)) = (self.weight.as_mut(), grad_weight.as_ref()) {
+            accumulate_grad(weight, grad_weight)?;
+        }
+        if let (Some(bias), Some(grad_bias)) = (self.bias.as_mut(), grad_bias.as_ref()) {
+            accumulate_grad(bias, grad_bias)?;
+        }
+        Ok(grad_input)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        LayerNorm::parameters(self)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = Vec::new();
+        if let Some(ref weight) = self.weight {
+            params.push(("weight".to_string(), weight.clone()));
+        }
+        if let Some(ref bias) = self.bias {
+            params.push(("bias".to_string(), bias.clone()));
+        }
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        let param = match name {
+            "weight" => self.weight.as_mut(),
+            "bias" => self.bias.as_mut(),
+            _ => None,
+        };
+        match param {
+            Some(param) => assign_parameter(param, name, value),
+            None => Err(unknown_parameter(name)),
+        }
+    }
+}
@@ -14,6 +14,11 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::init::default_weight;
+use crate::layer::{
+    accumulate_grad, assign_parameter, parameter, select_channels, unknown_parameter, ChannelRole,
+    Layer,
+};
 
 pub struct Linear {
     in_features: usize,
@@ -21,13 +26,15 @@ pub struct Linear {
     weight: Tensor,
     bias: Option<Tensor>,
     input_cache: Option<Tensor>,
+    per_sample: bool,
+    sample_grads: Vec<(String, Tensor)>,
 }
 
 impl Linear {
     pub fn new(in_features: usize, out_features: usize, bias: bool) -> Self {
-        let weight = Tensor::randn(&[out_features, in_features]);
+        let weight = default_weight(&[out_features, in_features]);
         let bias = if bias {
-            Some(Tensor::zeros(&[out_features]))
+            Some(parameter(Tensor::zeros(&[out_features])))
         } else {
             None
         };
@@ -38,9 +45,24 @@ impl Linear {
             weight,
             bias,
             input_cache: None,
+            per_sample: false,
+            sample_grads: Vec::new(),
         }
     }
 
+    /// Records per-sample gradients on every backward pass, see
+    /// `Layer::per_sample_grads`
+    pub fn with_per_sample_grads(mut self, enabled: bool) -> Self {
+        self.per_sample = enabled;
+        self
+    }
+
+    /// Removes and returns the accumulated weight gradient, for a model
+    /// that ties this weight to another parameter
+    pub(crate) fn take_weight_grad(&mut self) -> Option<Tensor> {
+        self.weight.take_grad()
+    }
+
     pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
         if input.shape.len() != 2 {
             return Err(BellandeError::InvalidShape);
@@ -143,4 +165,156 @@ impl Linear {
             ))
         }
     }
+
+    /// Per-sample weight gradients as the outer products `grad[b] x input[b]`,
+    /// and per-sample bias gradients `grad[b]`
+    fn sample_gradients(
+        &self,
+        grad_output: &Tensor,
+    ) -> Result<Vec<(String, Tensor)>, BellandeError> {
+        let input = self
+            .input_cache
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let batch_size = input.shape[0];
+        if grad_output.shape != [batch_size, self.out_features] {
+            return Err(BellandeError::DimensionMismatch);
+        }
+
+        let mut grad_weight = Vec::with_capacity(batch_size * self.weight.data.len());
+        for (grad, x) in grad_output
+            .data
+            .chunks(self.out_features)
+            .zip(input.data.chunks(self.in_features))
+        {
+            for &g in grad {
+                grad_weight.extend(x.iter().map(|&v| g * v));
+            }
+        }
+
+        let mut grads = vec![(
+            "weight".to_string(),
+            Tensor::new(
+                grad_weight,
+                vec![batch_size, self.out_features, self.in_features],
+                false,
+                self.weight.device.clone(),
+                self.weight.dtype,
+            ),
+        )];
+        if self.bias.is_some() {
+            grads.push((
+                "bias".to_string(),
+                Tensor::new(
+                    grad_output.data.clone(),
+                    vec![batch_size, self.out_features],
+                    false,
+                    self.weight.device.clone(),
+                    self.weight.dtype,
+                ),
+            ));
+        }
+        Ok(grads)
+    }
+}
+
+impl Layer for Linear {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        Linear::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_input, grad_weight, grad_bias) = Linear::backward(self, grad)?;
+        if self.per_sample {
+            self.sample_grads = self.sample_gradients(grad)?;
+        }
+        accumulate_grad(&mut self.weight, &grad_weight)?;
+        if let (Some(bias), Some(grad_bias)) = (self.bias.as_mut(), grad_bias.as_ref()) {
+            accumulate_grad(bias, grad_bias)?;
+        }
+        Ok(grad_input)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = vec![("weight".to_string(), self.weight.clone())];
+        if let Some(ref bias) = self.bias {
+            params.push(("bias".to_string(), bias.clone()));
+        }
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        let param = match name {
+            "weight" => Some(&mut self.weight),
+            "bias" => self.bias.as_mut(),
+            _ => None,
+        };
+        match param {
+            Some(param) => assign_parameter(param, name, value),
+            None => Err(unknown_parameter(name)),
+        }
+    }
+
+    fn set_per_sample_grads(&mut self, enabled: bool) -> Result<(), BellandeError> {
+        self.per_sample = enabled;
+        if !enabled {
+            self.sample_grads.clear();
+        }
+        Ok(())
+    }
+
+    fn per_sample_grads(&self) -> Vec<(String, Tensor)> {
+        self.sample_grads.clone()
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::Producer {
+            in_channels: self.in_features,
+            out_channels: self.out_features,
+        }
+    }
+
+    fn channel_importance(&self) -> Option<Vec<f32>> {
+        Some(
+            self.weight
+                .data
+                .chunks(self.in_features.max(1))
+                .map(|row| row.iter().map(|w| w.abs()).sum())
+                .collect(),
+        )
+    }
+
+    fn retain_output_channels(&mut self, keep: &[usize]) -> Result<(), BellandeError> {
+        self.weight = select_channels(&self.weight, 0, keep)?;
+        if let Some(ref mut bias) = self.bias {
+            *bias = select_channels(bias, 0, keep)?;
+        }
+        self.out_features = keep.len();
+        self.input_cache = None;
+        self.sample_grads.clear();
+        Ok(())
+    }
+
+    /// Inputs coming from flattened (channels, height, width) feature maps
+    /// hold `in_features / num_channels` consecutive features per channel
+    fn retain_input_channels(
+        &mut self,
+        keep: &[usize],
+        num_channels: usize,
+    ) -> Result<(), BellandeError> {
+        if num_channels == 0 || self.in_features % num_channels != 0 {
+            return Err(BellandeError::DimensionMismatch);
+        }
+        let per_channel = self.in_features / num_channels;
+        let features: Vec<usize> = keep
+            .iter()
+            .flat_map(|&channel| channel * per_channel..(channel + 1) * per_channel)
+            .collect();
+
+        self.weight = select_channels(&self.weight, 1, &features)?;
+        self.in_features = features.len();
+        self.input_cache = None;
+        self.sample_grads.clear();
+        Ok(())
+    }
 }
@@ -1,10 +1,239 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{autograd::GradAccumulator, error::BellandeError, tensor::Tensor};
+use std::sync::Arc;
+
 pub mod activation;
+pub mod adaptive_pool;
 pub mod avgpool2d;
 pub mod batch_norm;
 pub mod conv;
+pub mod conv_transpose;
 pub mod dropout;
+pub mod embedding;
+pub mod flatten;
+pub mod group_norm;
+pub(crate) mod im2col;
+pub mod init;
 pub mod layer_norm;
 pub mod linear;
+pub mod pixel_shuffle;
 pub mod pooling;
+pub mod positional;
 pub mod recurrent;
+pub mod sequence;
+pub mod spectral_norm;
+pub mod squeeze_excitation;
 pub mod transformer;
+pub mod upsample;
+pub mod weight_norm;
+
+/// Common interface of every neural network layer, used by `Sequential` and
+/// for building state dicts
+pub trait Layer: Send + Sync {
+    /// Forward pass
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError>;
+
+    /// Backward pass. Returns the gradient with respect to the input of the
+    /// last forward call and accumulates parameter gradients into each
+    /// parameter's `grad`.
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError>;
+
+    /// Get trainable parameters
+    fn parameters(&self) -> Vec<Tensor> {
+        self.named_parameters()
+            .into_iter()
+            .map(|(_, param)| param)
+            .collect()
+    }
+
+    /// Get named parameters, including buffers such as running statistics
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+
+    /// Set parameter value
+    fn set_parameter(&mut self, name: &str, _value: Tensor) -> Result<(), BellandeError> {
+        Err(unknown_parameter(name))
+    }
+
+    /// Set layer to training mode
+    fn train(&mut self) {}
+
+    /// Set layer to evaluation mode
+    fn eval(&mut self) {}
+
+    /// Keeps dropout sampling even in evaluation mode, for Monte Carlo
+    /// dropout. Deterministic layers ignore it.
+    fn set_dropout_active(&mut self, _active: bool) {}
+
+    /// Opts into recording one gradient per sample for every parameter
+    /// during `backward`, for differential privacy or data influence
+    /// analysis. Layers with parameters that cannot do so return an error.
+    fn set_per_sample_grads(&mut self, enabled: bool) -> Result<(), BellandeError> {
+        if enabled && !self.named_parameters().is_empty() {
+            return Err(BellandeError::NotImplemented(
+                "This layer does not support per-sample gradients".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Per-sample gradients of the last `backward`, each shaped
+    /// (batch_size, *parameter_shape). Empty unless enabled.
+    fn per_sample_grads(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+
+    /// How the layer treats the channel axis, for structured pruning
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::Opaque
+    }
+
+    /// Importance of each output channel for structured pruning: the L1
+    /// norm of its weights for producers, `|gamma|` for normalization layers
+    fn channel_importance(&self) -> Option<Vec<f32>> {
+        None
+    }
+
+    /// Keeps only the output channels listed in `keep`, in ascending order
+    fn retain_output_channels(&mut self, _keep: &[usize]) -> Result<(), BellandeError> {
+        Err(BellandeError::NotImplemented(
+            "This layer does not support channel pruning".into(),
+        ))
+    }
+
+    /// Keeps only the input channels listed in `keep` out of `num_channels`.
+    /// A `Linear` layer after flattened feature maps drops every feature of
+    /// a removed channel.
+    fn retain_input_channels(
+        &mut self,
+        _keep: &[usize],
+        _num_channels: usize,
+    ) -> Result<(), BellandeError> {
+        Err(BellandeError::NotImplemented(
+            "This layer does not support channel pruning".into(),
+        ))
+    }
+}
+
+/// Structural role of a layer along the channel axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelRole {
+    /// Mixes `in_channels` into `out_channels` with prunable weights, such
+    /// as `Conv2d` and `Linear`
+    Producer {
+        in_channels: usize,
+        out_channels: usize,
+    },
+    /// Applies a separate transform to each of `channels`, such as batch
+    /// normalization
+    PerChannel { channels: usize },
+    /// Leaves the channel axis unchanged, such as activations and pooling
+    PassThrough,
+    /// Unknown structure; channels are never pruned across it
+    Opaque,
+}
+
+/// Makes `tensor` a trainable parameter with its own gradient buffer, which
+/// every clone of it shares. Layer gradients accumulate there, so the copies
+/// an optimizer holds see them.
+pub(crate) fn parameter(mut tensor: Tensor) -> Tensor {
+    tensor.requires_grad = true;
+    tensor.grad = None;
+    tensor.grad_accumulator = Some(Arc::new(GradAccumulator::new()));
+    tensor
+}
+
+/// Overwrites a parameter's values, keeping its gradient state
+pub(crate) fn assign_parameter(
+    param: &mut Tensor,
+    name: &str,
+    value: Tensor,
+) -> Result<(), BellandeError> {
+    if param.shape != value.shape {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Parameter '{}' has shape {:?}, got {:?}",
+            name, param.shape, value.shape
+        )));
+    }
+    param.data = value.data;
+    Ok(())
+}
+
+/// Prepends `prefix.` to the names of a sub-layer's parameters
+pub(crate) fn prefixed(prefix: &str, params: Vec<(String, Tensor)>) -> Vec<(String, Tensor)> {
+    params
+        .into_iter()
+        .map(|(name, param)| (format!("{}.{}", prefix, name), param))
+        .collect()
+}
+
+/// Adds `grad` to a parameter's accumulated gradient, after the hooks
+/// registered on the parameter have seen it. Parameters share the buffer
+/// with their clones; tensors without one keep the gradient themselves.
+pub(crate) fn accumulate_grad(param: &mut Tensor, grad: &Tensor) -> Result<(), BellandeError> {
+    if let Some(ref accumulator) = param.grad_accumulator {
+        return accumulator.accumulate(grad.detach());
+    }
+    match param.grad {
+        Some(ref mut existing) => {
+            for (g, &delta) in existing.iter_mut().zip(grad.data.iter()) {
+                *g += delta;
+            }
+        }
+        None => param.grad = Some(grad.data.clone()),
+    }
+    Ok(())
+}
+
+/// Keeps the entries of `param` whose index along `axis` is in `keep`,
+/// dropping its gradient
+pub(crate) fn select_channels(
+    param: &Tensor,
+    axis: usize,
+    keep: &[usize],
+) -> Result<Tensor, BellandeError> {
+    let dim = param.shape[axis];
+    if let Some(&index) = keep.iter().find(|&&index| index >= dim) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Channel {} out of range for axis {} of shape {:?}",
+            index, axis, param.shape
+        )));
+    }
+
+    let inner: usize = param.shape[axis + 1..].iter().product();
+    let mut data = Vec::with_capacity(param.data.len() / dim.max(1) * keep.len());
+    for block in param.data.chunks(dim * inner) {
+        for &index in keep {
+            data.extend_from_slice(&block[index * inner..(index + 1) * inner]);
+        }
+    }
+
+    let mut shape = param.shape.clone();
+    shape[axis] = keep.len();
+    Ok(Tensor::new(
+        data,
+        shape,
+        param.requires_grad,
+        param.device.clone(),
+        param.dtype,
+    ))
+}
+
+pub(crate) fn unknown_parameter(name: &str) -> BellandeError {
+    BellandeError::InvalidParameter(format!("Unknown parameter '{}'", name))
+}
@@ -0,0 +1,177 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::Layer;
+
+/// Rearranges (batch, channels * r^2, height, width) into
+/// (batch, channels, height * r, width * r) for sub-pixel convolution
+/// (Shi et al., 2016): output pixel `(h * r + i, w * r + j)` of channel `c`
+/// is input channel `c * r^2 + i * r + j` at `(h, w)`
+pub struct PixelShuffle {
+    upscale_factor: usize,
+}
+
+/// Inverse of `PixelShuffle`: rearranges (batch, channels, height * r,
+/// width * r) into (batch, channels * r^2, height, width)
+pub struct PixelUnshuffle {
+    downscale_factor: usize,
+}
+
+/// Moves values between the packed (batch, channels * r^2, height, width)
+/// layout and the spatial (batch, channels, height * r, width * r) layout,
+/// where `packed_shape` is the former
+fn rearrange(data: &[f32], packed_shape: [usize; 4], factor: usize, to_spatial: bool) -> Vec<f32> {
+    let [batch_size, packed_channels, height, width] = packed_shape;
+    let channels = packed_channels / (factor * factor);
+    let (out_h, out_w) = (height * factor, width * factor);
+    let mut output = vec![0.0; data.len()];
+
+    for b in 0..batch_size {
+        for c in 0..channels {
+            for i in 0..factor {
+                for j in 0..factor {
+                    let packed_base =
+                        (b * packed_channels + (c * factor + i) * factor + j) * height;
+                    for h in 0..height {
+                        for w in 0..width {
+                            let packed = (packed_base + h) * width + w;
+                            let spatial = ((b * channels + c) * out_h + h * factor + i) * out_w
+                                + w * factor
+                                + j;
+                            if to_spatial {
+                                output[spatial] = data[packed];
+                            } else {
+                                output[packed] = data[spatial];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    output
+}
+
+fn check_factor(factor: usize) -> Result<(), BellandeError> {
+    if factor == 0 {
+        return Err(BellandeError::InvalidParameter(
+            "Scale factor must be positive".into(),
+        ));
+    }
+    Ok(())
+}
+
+fn check_4d(shape: &[usize]) -> Result<(), BellandeError> {
+    if shape.len() != 4 {
+        return Err(BellandeError::InvalidShape(
+            "Expected 4D tensor (batch_size, channels, height, width)".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Packed shape of a (batch, channels * r^2, height, width) tensor
+fn packed_shape(shape: &[usize], factor: usize) -> Result<[usize; 4], BellandeError> {
+    check_factor(factor)?;
+    check_4d(shape)?;
+    if shape[1] % (factor * factor) != 0 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Channels of {:?} are not divisible by the squared scale factor {}",
+            shape,
+            factor * factor
+        )));
+    }
+    Ok([shape[0], shape[1], shape[2], shape[3]])
+}
+
+/// Packed shape corresponding to a spatial (batch, channels, height * r,
+/// width * r) tensor
+fn unpacked_shape(shape: &[usize], factor: usize) -> Result<[usize; 4], BellandeError> {
+    check_factor(factor)?;
+    check_4d(shape)?;
+    if shape[2] % factor != 0 || shape[3] % factor != 0 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Spatial size of {:?} is not divisible by the scale factor {}",
+            shape, factor
+        )));
+    }
+    Ok([
+        shape[0],
+        shape[1] * factor * factor,
+        shape[2] / factor,
+        shape[3] / factor,
+    ])
+}
+
+fn to_spatial(input: &Tensor, factor: usize) -> Result<Tensor, BellandeError> {
+    let packed = packed_shape(&input.shape, factor)?;
+    let [batch_size, channels, height, width] = packed;
+    Ok(Tensor::new(
+        rearrange(&input.data, packed, factor, true),
+        vec![
+            batch_size,
+            channels / (factor * factor),
+            height * factor,
+            width * factor,
+        ],
+        input.requires_grad,
+        input.device.clone(),
+        input.dtype,
+    ))
+}
+
+fn to_packed(input: &Tensor, factor: usize) -> Result<Tensor, BellandeError> {
+    let packed = unpacked_shape(&input.shape, factor)?;
+    Ok(Tensor::new(
+        rearrange(&input.data, packed, factor, false),
+        packed.to_vec(),
+        input.requires_grad,
+        input.device.clone(),
+        input.dtype,
+    ))
+}
+
+impl PixelShuffle {
+    pub fn new(upscale_factor: usize) -> Self {
+        PixelShuffle { upscale_factor }
+    }
+}
+
+impl PixelUnshuffle {
+    pub fn new(downscale_factor: usize) -> Self {
+        PixelUnshuffle { downscale_factor }
+    }
+}
+
+impl Layer for PixelShuffle {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        to_spatial(input, self.upscale_factor)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        to_packed(grad, self.upscale_factor)
+    }
+}
+
+impl Layer for PixelUnshuffle {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        to_packed(input, self.downscale_factor)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        to_spatial(grad, self.downscale_factor)
+    }
+}
@@ -14,11 +14,16 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{ChannelRole, Layer};
 
 pub struct MaxPool2d {
     kernel_size: (usize, usize),
     stride: (usize, usize),
+    padding: (usize, usize),
+    dilation: (usize, usize),
+    ceil_mode: bool,
     indices: Option<Vec<usize>>,
+    input_shape: Option<Vec<usize>>,
 }
 
 impl MaxPool2d {
@@ -26,13 +31,81 @@ impl MaxPool2d {
         MaxPool2d {
             kernel_size,
             stride,
+            padding: (0, 0),
+            dilation: (1, 1),
+            ceil_mode: false,
             indices: None,
+            input_shape: None,
         }
     }
 
+    /// Implicit negative infinity padding on both sides of each spatial
+    /// dimension; at most half the dilated kernel size
+    pub fn with_padding(mut self, padding: (usize, usize)) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Spacing between the input positions of a window
+    pub fn with_dilation(mut self, dilation: (usize, usize)) -> Self {
+        self.dilation = dilation;
+        self
+    }
+
+    /// Rounds the output size up instead of down, so trailing rows and
+    /// columns that do not fill a whole window are still pooled
+    pub fn with_ceil_mode(mut self, ceil_mode: bool) -> Self {
+        self.ceil_mode = ceil_mode;
+        self
+    }
+
+    /// Output length of one spatial dimension
+    fn output_size(&self, input: usize, axis: usize) -> Result<usize, BellandeError> {
+        let pick = |pair: (usize, usize)| if axis == 0 { pair.0 } else { pair.1 };
+        let (kernel, stride, padding, dilation) = (
+            pick(self.kernel_size),
+            pick(self.stride),
+            pick(self.padding),
+            pick(self.dilation),
+        );
+        if kernel == 0 || stride == 0 || dilation == 0 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "MaxPool2d kernel size {:?}, stride {:?} and dilation {:?} must be positive",
+                self.kernel_size, self.stride, self.dilation
+            )));
+        }
+        let span = dilation * (kernel - 1) + 1;
+        if padding > span / 2 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "MaxPool2d padding {:?} exceeds half of the dilated kernel size",
+                self.padding
+            )));
+        }
+        if input + 2 * padding < span {
+            return Err(BellandeError::InvalidShape(format!(
+                "Input size {} with padding {} is smaller than the pooling window {}",
+                input, padding, span
+            )));
+        }
+
+        let room = input + 2 * padding - span;
+        let mut output = if self.ceil_mode {
+            room.div_ceil(stride) + 1
+        } else {
+            room / stride + 1
+        };
+        // The last window must start inside the input or its left padding
+        if self.ceil_mode && (output - 1) * stride >= input + padding {
+            output -= 1;
+        }
+        Ok(output)
+    }
+
     pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
         if input.shape.len() != 4 {
-            return Err(BellandeError::InvalidShape);
+            return Err(BellandeError::InvalidShape(
+                "Expected 4D tensor (batch_size, channels, height, width)".into(),
+            ));
         }
 
         let (batch_size, channels, height, width) = (
@@ -42,8 +115,8 @@ impl MaxPool2d {
             input.shape[3],
         );
 
-        let output_height = (height - self.kernel_size.0) / self.stride.0 + 1;
-        let output_width = (width - self.kernel_size.1) / self.stride.1 + 1;
+        let output_height = self.output_size(height, 0)?;
+        let output_width = self.output_size(width, 1)?;
 
         let mut output = vec![0.0; batch_size * channels * output_height * output_width];
         let mut indices = vec![0; batch_size * channels * output_height * output_width];
@@ -53,31 +126,40 @@ impl MaxPool2d {
                 for h in 0..output_height {
                     for w in 0..output_width {
                         let mut max_val = f32::NEG_INFINITY;
-                        let mut max_idx = 0;
+                        let mut max_idx = None;
 
                         for kh in 0..self.kernel_size.0 {
+                            // Window positions in the padding are skipped
+                            let in_h = (h * self.stride.0 + kh * self.dilation.0)
+                                .checked_sub(self.padding.0)
+                                .filter(|&in_h| in_h < height);
+                            let Some(in_h) = in_h else { continue };
                             for kw in 0..self.kernel_size.1 {
-                                let in_h = h * self.stride.0 + kh;
-                                let in_w = w * self.stride.1 + kw;
+                                let in_w = (w * self.stride.1 + kw * self.dilation.1)
+                                    .checked_sub(self.padding.1)
+                                    .filter(|&in_w| in_w < width);
+                                let Some(in_w) = in_w else { continue };
                                 let idx = ((b * channels + c) * height + in_h) * width + in_w;
                                 let val = input.data[idx];
 
-                                if val > max_val {
+                                if max_idx.is_none() || val > max_val {
                                     max_val = val;
-                                    max_idx = idx;
+                                    max_idx = Some(idx);
                                 }
                             }
                         }
 
                         let out_idx = ((b * channels + c) * output_height + h) * output_width + w;
                         output[out_idx] = max_val;
-                        indices[out_idx] = max_idx;
+                        // Every window overlaps the input, see `output_size`
+                        indices[out_idx] = max_idx.unwrap_or(0);
                     }
                 }
             }
         }
 
         self.indices = Some(indices);
+        self.input_shape = Some(input.shape.clone());
 
         Ok(Tensor::new(
             output,
@@ -89,8 +171,15 @@ impl MaxPool2d {
     }
 
     pub fn backward(&self, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
-        if let Some(ref indices) = self.indices {
-            let mut grad_input = vec![0.0; indices.len()];
+        if let (Some(indices), Some(input_shape)) = (&self.indices, &self.input_shape) {
+            if grad_output.data.len() != indices.len() {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Expected an output gradient of {} values, got shape {:?}",
+                    indices.len(),
+                    grad_output.shape
+                )));
+            }
+            let mut grad_input = vec![0.0; input_shape.iter().product()];
 
             for (out_idx, &in_idx) in indices.iter().enumerate() {
                 grad_input[in_idx] += grad_output.data[out_idx];
@@ -98,7 +187,7 @@ impl MaxPool2d {
 
             Ok(Tensor::new(
                 grad_input,
-                grad_output.shape.clone(),
+                input_shape.clone(),
                 true,
                 grad_output.device.clone(),
                 grad_output.dtype,
@@ -110,3 +199,17 @@ impl MaxPool2d {
         }
     }
 }
+
+impl Layer for MaxPool2d {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        MaxPool2d::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        MaxPool2d::backward(self, grad)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, random, tensor::Tensor};
+use crate::layer::{accumulate_grad, assign_parameter, parameter, unknown_parameter, Layer};
+
+/// Fixed sine/cosine position table of shape (max_len, dim), as in
+/// "Attention Is All You Need": even features hold `sin(pos / 10000^(2i/dim))`
+/// and odd features the matching cosine
+pub fn sinusoidal_table(max_len: usize, dim: usize) -> Tensor {
+    let mut table = Tensor::zeros(&[max_len, dim]);
+    for (pos, row) in table.data.chunks_mut(dim.max(1)).enumerate() {
+        for (i, value) in row.iter_mut().enumerate() {
+            let exponent = (i - i % 2) as f32 / dim as f32;
+            let angle = pos as f32 / 10000f32.powf(exponent);
+            *value = if i % 2 == 0 { angle.sin() } else { angle.cos() };
+        }
+    }
+    table
+}
+
+/// Sequence length of a (..., seq_len, embed_dim) input, checked against
+/// the table size
+fn sequence_len(input: &Tensor, embed_dim: usize, max_len: usize) -> Result<usize, BellandeError> {
+    let rank = input.shape.len();
+    if rank < 2 || input.shape[rank - 1] != embed_dim {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected (..., seq_len, {}) input, got {:?}",
+            embed_dim, input.shape
+        )));
+    }
+    let seq_len = input.shape[rank - 2];
+    if seq_len > max_len {
+        return Err(BellandeError::InvalidShape(format!(
+            "Sequence length {} exceeds the maximum of {}",
+            seq_len, max_len
+        )));
+    }
+    Ok(seq_len)
+}
+
+/// Adds `seq_len` rows of `table`, starting at row `offset`, to every
+/// sequence in `input`
+fn add_positions(input: &Tensor, table: &Tensor, seq_len: usize, offset: usize) -> Tensor {
+    let dim = table.shape[1];
+    let positions = &table.data[offset * dim..(offset + seq_len) * dim];
+    let mut output = input.clone();
+    for sequence in output.data.chunks_mut(positions.len().max(1)) {
+        sequence
+            .iter_mut()
+            .zip(positions)
+            .for_each(|(x, &p)| *x += p);
+    }
+    output
+}
+
+/// Adds fixed sinusoidal position encodings to (..., seq_len, embed_dim)
+/// inputs
+pub struct SinusoidalPositionalEncoding {
+    table: Tensor,
+    scale: f32,
+}
+
+impl SinusoidalPositionalEncoding {
+    pub fn new(embed_dim: usize, max_len: usize) -> Self {
+        SinusoidalPositionalEncoding {
+            table: sinusoidal_table(max_len, embed_dim),
+            scale: 1.0,
+        }
+    }
+
+    /// Multiplies the input by `scale` before adding positions, such as
+    /// `sqrt(embed_dim)` for token embeddings
+    pub fn with_input_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn forward(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.forward_at(input, 0)
+    }
+
+    /// Encodes a sequence whose first position is `offset`, e.g. the next
+    /// token during incremental decoding
+    pub fn forward_at(&self, input: &Tensor, offset: usize) -> Result<Tensor, BellandeError> {
+        let max_len = self.table.shape[0].saturating_sub(offset);
+        let seq_len = sequence_len(input, self.table.shape[1], max_len)?;
+        let mut scaled = input.clone();
+        scaled.data.iter_mut().for_each(|x| *x *= self.scale);
+        Ok(add_positions(&scaled, &self.table, seq_len, offset))
+    }
+}
+
+impl Layer for SinusoidalPositionalEncoding {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        SinusoidalPositionalEncoding::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let mut grad_input = grad.clone();
+        grad_input.data.iter_mut().for_each(|g| *g *= self.scale);
+        Ok(grad_input)
+    }
+}
+
+/// Adds a trainable position embedding, one row per position, to
+/// (..., seq_len, embed_dim) inputs
+pub struct LearnedPositionalEncoding {
+    weight: Tensor,
+    seq_len: Option<usize>,
+}
+
+impl LearnedPositionalEncoding {
+    pub fn new(max_len: usize, embed_dim: usize) -> Self {
+        let mut weight = Tensor::zeros(&[max_len, embed_dim]);
+        weight.data = random::normal(0.0, 0.02, max_len * embed_dim);
+        LearnedPositionalEncoding {
+            weight: parameter(weight),
+            seq_len: None,
+        }
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let seq_len = sequence_len(input, self.weight.shape[1], self.weight.shape[0])?;
+        self.seq_len = Some(seq_len);
+        Ok(add_positions(input, &self.weight, seq_len, 0))
+    }
+}
+
+impl Layer for LearnedPositionalEncoding {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        LearnedPositionalEncoding::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let seq_len = self
+            .seq_len
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let len = seq_len * self.weight.shape[1];
+        let mut grad_weight = Tensor::zeros(&self.weight.shape);
+        for sequence in grad.data.chunks(len.max(1)) {
+            grad_weight.data[..len]
+                .iter_mut()
+                .zip(sequence)
+                .for_each(|(g, &s)| *g += s);
+        }
+        accumulate_grad(&mut self.weight, &grad_weight)?;
+        Ok(grad.clone())
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        vec![("weight".to_string(), self.weight.clone())]
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name {
+            "weight" => assign_parameter(&mut self.weight, name, value),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+}
+
+/// Rotary position embedding (RoPE): rotates each pair of features
+/// `(x[2i], x[2i + 1])` of a (..., seq_len, head_dim) query or key by
+/// `pos * base^(-2i / head_dim)`, so attention scores depend on relative
+/// positions. Apply it to queries and keys after splitting heads.
+pub struct RotaryEmbedding {
+    head_dim: usize,
+    max_len: usize,
+    base: f32,
+    cos: Vec<f32>,
+    sin: Vec<f32>,
+}
+
+impl RotaryEmbedding {
+    pub fn new(head_dim: usize, max_len: usize) -> Result<Self, BellandeError> {
+        if head_dim == 0 || head_dim % 2 != 0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Rotary embeddings need an even head dimension, got {}",
+                head_dim
+            )));
+        }
+        let mut rotary = RotaryEmbedding {
+            head_dim,
+            max_len,
+            base: 10000.0,
+            cos: Vec::new(),
+            sin: Vec::new(),
+        };
+        rotary.build_tables();
+        Ok(rotary)
+    }
+
+    /// Base of the rotation frequencies, 10000 by default
+    pub fn with_base(mut self, base: f32) -> Result<Self, BellandeError> {
+        if !base.is_finite() || base <= 1.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Rotary base must be greater than 1, got {}",
+                base
+            )));
+        }
+        self.base = base;
+        self.build_tables();
+        Ok(self)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+    }
+
+    fn build_tables(&mut self) {
+        let half = self.head_dim / 2;
+        let len = self.max_len * half;
+        self.cos = Vec::with_capacity(len);
+        self.sin = Vec::with_capacity(len);
+        for pos in 0..self.max_len {
+            for i in 0..half {
+                let angle = pos as f32 * self.base.powf(-2.0 * i as f32 / self.head_dim as f32);
+                self.cos.push(angle.cos());
+                self.sin.push(angle.sin());
+            }
+        }
+    }
+
+    /// Rotates a (..., seq_len, head_dim) tensor whose first position is
+    /// `offset`, e.g. the number of cached tokens during decoding
+    pub fn apply(&self, input: &Tensor, offset: usize) -> Result<Tensor, BellandeError> {
+        self.rotate(input, offset, 1.0)
+    }
+
+    /// Gradient of `apply`: the inverse rotation
+    pub fn apply_backward(&self, grad: &Tensor, offset: usize) -> Result<Tensor, BellandeError> {
+        self.rotate(grad, offset, -1.0)
+    }
+
+    fn rotate(
+        &self,
+        input: &Tensor,
+        offset: usize,
+        direction: f32,
+    ) -> Result<Tensor, BellandeError> {
+        let seq_len = sequence_len(input, self.head_dim, self.max_len.saturating_sub(offset))?;
+        let half = self.head_dim / 2;
+        let mut output = input.clone();
+        for sequence in output.data.chunks_mut((seq_len * self.head_dim).max(1)) {
+            for (t, features) in sequence.chunks_mut(self.head_dim).enumerate() {
+                let table = (offset + t) * half;
+                for (i, pair) in features.chunks_mut(2).enumerate() {
+                    let (cos, sin) = (self.cos[table + i], direction * self.sin[table + i]);
+                    let (a, b) = (pair[0], pair[1]);
+                    pair[0] = a * cos - b * sin;
+                    pair[1] = a * sin + b * cos;
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+impl Layer for RotaryEmbedding {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.apply(input, 0)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        self.apply_backward(grad, 0)
+    }
+}
@@ -14,6 +14,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{init::uniform_tensor, parameter};
 
 pub struct LSTMCell {
     input_size: usize,
@@ -34,17 +35,19 @@ struct LSTMCache {
 
 impl LSTMCell {
     pub fn new(input_size: usize, hidden_size: usize, bias: bool) -> Self {
-        let weight_ih = Tensor::randn(&[4 * hidden_size, input_size]);
-        let weight_hh = Tensor::randn(&[4 * hidden_size, hidden_size]);
+        // U(-1 / sqrt(hidden_size), 1 / sqrt(hidden_size)), as in PyTorch
+        let bound = 1.0 / (hidden_size.max(1) as f32).sqrt();
+        let weight_ih = uniform_tensor(&[4 * hidden_size, input_size], bound);
+        let weight_hh = uniform_tensor(&[4 * hidden_size, hidden_size], bound);
 
         let bias_ih = if bias {
-            Some(Tensor::zeros(&[4 * hidden_size]))
+            Some(parameter(Tensor::zeros(&[4 * hidden_size])))
         } else {
             None
         };
 
         let bias_hh = if bias {
-            Some(Tensor::zeros(&[4 * hidden_size]))
+            Some(parameter(Tensor::zeros(&[4 * hidden_size])))
         } else {
             None
         };
@@ -145,17 +148,19 @@ struct GRUCache {
 
 impl GRUCell {
     pub fn new(input_size: usize, hidden_size: usize, bias: bool) -> Self {
-        let weight_ih = Tensor::randn(&[3 * hidden_size, input_size]);
-        let weight_hh = Tensor::randn(&[3 * hidden_size, hidden_size]);
+        // U(-1 / sqrt(hidden_size), 1 / sqrt(hidden_size)), as in PyTorch
+        let bound = 1.0 / (hidden_size.max(1) as f32).sqrt();
+        let weight_ih = uniform_tensor(&[3 * hidden_size, input_size], bound);
+        let weight_hh = uniform_tensor(&[3 * hidden_size, hidden_size], bound);
 
         let bias_ih = if bias {
-            Some(Tensor::zeros(&[3 * hidden_size]))
+            Some(parameter(Tensor::zeros(&[3 * hidden_size])))
         } else {
             None
         };
 
         let bias_hh = if bias {
-            Some(Tensor::zeros(&[3 * hidden_size]))
+            Some(parameter(Tensor::zeros(&[3 * hidden_size])))
         } else {
             None
         };
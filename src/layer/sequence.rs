@@ -0,0 +1,330 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
+
+/// Variable-length sequences packed time step by time step, so recurrent
+/// layers only process the sequences still running at each step. Sequences
+/// are stored longest first; `data` holds the (num_active, ...) rows of
+/// every step back to back.
+#[derive(Debug, Clone)]
+pub struct PackedSequence {
+    /// (sum of lengths, ...) packed values
+    pub data: Tensor,
+    /// Number of sequences still running at each time step
+    pub batch_sizes: Vec<usize>,
+    /// Original batch index of each packed sequence, longest first
+    pub sorted_indices: Vec<usize>,
+    /// Packed position of each original batch entry
+    pub unsorted_indices: Vec<usize>,
+}
+
+impl PackedSequence {
+    /// Number of features per time step
+    fn step_size(&self) -> usize {
+        let total: usize = self.batch_sizes.iter().sum();
+        self.data.data.len() / total.max(1)
+    }
+
+    /// Values of time step `t` with shape (batch_sizes[t], ...), in packed
+    /// order
+    pub fn step(&self, t: usize) -> Result<Tensor, BellandeError> {
+        let batch_size = *self.batch_sizes.get(t).ok_or_else(|| {
+            BellandeError::InvalidParameter(format!(
+                "Time step {} out of range for {} steps",
+                t,
+                self.batch_sizes.len()
+            ))
+        })?;
+        let step_size = self.step_size();
+        let offset: usize = self.batch_sizes[..t].iter().sum();
+
+        let mut shape = vec![batch_size];
+        shape.extend_from_slice(&self.data.shape[1..]);
+        Ok(Tensor::new(
+            self.data.data[offset * step_size..(offset + batch_size) * step_size].to_vec(),
+            shape,
+            self.data.requires_grad,
+            self.data.device.clone(),
+            self.data.dtype,
+        ))
+    }
+
+    /// Length of each sequence, in original batch order
+    pub fn lengths(&self) -> Vec<usize> {
+        let mut lengths = vec![0; self.sorted_indices.len()];
+        for &batch_size in &self.batch_sizes {
+            for &original in &self.sorted_indices[..batch_size] {
+                lengths[original] += 1;
+            }
+        }
+        lengths
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.batch_sizes.len()
+    }
+}
+
+/// Stacks (length, ...) sequences into a (batch, max_length, ...) tensor, or
+/// (max_length, batch, ...) when `batch_first` is false, filling the tail
+/// of shorter sequences with `padding_value`
+pub fn pad_sequence(
+    sequences: &[Tensor],
+    batch_first: bool,
+    padding_value: f32,
+) -> Result<Tensor, BellandeError> {
+    let first = sequences
+        .first()
+        .ok_or_else(|| BellandeError::InvalidParameter("No sequences to pad".into()))?;
+    if first.shape.is_empty() {
+        return Err(BellandeError::InvalidShape(
+            "Sequences need a time dimension".into(),
+        ));
+    }
+    let feature_shape = &first.shape[1..];
+    for sequence in sequences {
+        if sequence.shape.is_empty() || sequence.shape[1..] != *feature_shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Sequence of shape {:?} does not match features {:?}",
+                sequence.shape, feature_shape
+            )));
+        }
+    }
+
+    let step_size: usize = feature_shape.iter().product();
+    let batch_size = sequences.len();
+    let max_length = sequences.iter().map(|s| s.shape[0]).max().unwrap_or(0);
+    let mut data = vec![padding_value; batch_size * max_length * step_size];
+
+    for (b, sequence) in sequences.iter().enumerate() {
+        for (t, step) in sequence.data.chunks(step_size.max(1)).enumerate() {
+            let row = if batch_first {
+                b * max_length + t
+            } else {
+                t * batch_size + b
+            };
+            data[row * step_size..(row + 1) * step_size].copy_from_slice(step);
+        }
+    }
+
+    let mut shape = if batch_first {
+        vec![batch_size, max_length]
+    } else {
+        vec![max_length, batch_size]
+    };
+    shape.extend_from_slice(feature_shape);
+    Ok(Tensor::new(
+        data,
+        shape,
+        first.requires_grad,
+        first.device.clone(),
+        first.dtype,
+    ))
+}
+
+/// Packs a padded (batch, time, ...) tensor, or (time, batch, ...) when
+/// `batch_first` is false, keeping the first `lengths[b]` steps of each
+/// sequence. With `enforce_sorted`, lengths must already be in decreasing
+/// order; otherwise the batch is sorted and the permutation recorded.
+pub fn pack_padded_sequence(
+    padded: &Tensor,
+    lengths: &[usize],
+    batch_first: bool,
+    enforce_sorted: bool,
+) -> Result<PackedSequence, BellandeError> {
+    if padded.shape.len() < 2 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected a padded tensor with batch and time dimensions, got {:?}",
+            padded.shape
+        )));
+    }
+    let (batch_size, max_length) = if batch_first {
+        (padded.shape[0], padded.shape[1])
+    } else {
+        (padded.shape[1], padded.shape[0])
+    };
+    if lengths.len() != batch_size {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Got {} lengths for a batch of {}",
+            lengths.len(),
+            batch_size
+        )));
+    }
+    if let Some(&length) = lengths.iter().find(|&&l| l == 0 || l > max_length) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Sequence length {} must be between 1 and {}",
+            length, max_length
+        )));
+    }
+
+    let mut sorted_indices: Vec<usize> = (0..batch_size).collect();
+    if enforce_sorted {
+        if lengths.windows(2).any(|pair| pair[0] < pair[1]) {
+            return Err(BellandeError::InvalidParameter(
+                "Lengths must be sorted in decreasing order when enforce_sorted is set".into(),
+            ));
+        }
+    } else {
+        // Stable, so equal lengths keep their batch order
+        sorted_indices.sort_by(|&a, &b| lengths[b].cmp(&lengths[a]));
+    }
+
+    let mut unsorted_indices = vec![0; batch_size];
+    for (position, &original) in sorted_indices.iter().enumerate() {
+        unsorted_indices[original] = position;
+    }
+
+    let step_size: usize = padded.shape[2..].iter().product();
+    let longest = lengths[sorted_indices[0]];
+    let mut batch_sizes = Vec::with_capacity(longest);
+    let mut data = Vec::with_capacity(lengths.iter().sum::<usize>() * step_size);
+
+    for t in 0..longest {
+        let active = sorted_indices
+            .iter()
+            .take_while(|&&original| lengths[original] > t)
+            .count();
+        batch_sizes.push(active);
+        for &original in &sorted_indices[..active] {
+            let row = if batch_first {
+                original * max_length + t
+            } else {
+                t * batch_size + original
+            };
+            data.extend_from_slice(&padded.data[row * step_size..(row + 1) * step_size]);
+        }
+    }
+
+    let mut shape = vec![data.len() / step_size.max(1)];
+    shape.extend_from_slice(&padded.shape[2..]);
+    Ok(PackedSequence {
+        data: Tensor::new(
+            data,
+            shape,
+            padded.requires_grad,
+            padded.device.clone(),
+            padded.dtype,
+        ),
+        batch_sizes,
+        sorted_indices,
+        unsorted_indices,
+    })
+}
+
+/// Packs a list of (length, ...) sequences of any order
+pub fn pack_sequence(sequences: &[Tensor]) -> Result<PackedSequence, BellandeError> {
+    let lengths: Vec<usize> = sequences
+        .iter()
+        .map(|s| s.shape.first().copied().unwrap_or(0))
+        .collect();
+    let padded = pad_sequence(sequences, true, 0.0)?;
+    pack_padded_sequence(&padded, &lengths, true, false)
+}
+
+/// Inverse of `pack_padded_sequence`: restores the original batch order and
+/// pads every sequence to `total_length`, or to the longest sequence.
+/// Returns the padded tensor and the sequence lengths.
+pub fn pad_packed_sequence(
+    packed: &PackedSequence,
+    batch_first: bool,
+    padding_value: f32,
+    total_length: Option<usize>,
+) -> Result<(Tensor, Vec<usize>), BellandeError> {
+    let batch_size = packed.sorted_indices.len();
+    let max_length = packed.max_length();
+    let total_length = total_length.unwrap_or(max_length);
+    if total_length < max_length {
+        return Err(BellandeError::InvalidParameter(format!(
+            "total_length {} is shorter than the longest sequence ({})",
+            total_length, max_length
+        )));
+    }
+
+    let step_size = packed.step_size();
+    let mut data = vec![padding_value; batch_size * total_length * step_size];
+    let mut rows = packed.data.data.chunks(step_size.max(1));
+
+    for (t, &active) in packed.batch_sizes.iter().enumerate() {
+        for &original in &packed.sorted_indices[..active] {
+            let row = if batch_first {
+                original * total_length + t
+            } else {
+                t * batch_size + original
+            };
+            let values = rows.next().ok_or_else(|| {
+                BellandeError::ShapeMismatch("Packed data is shorter than batch_sizes".into())
+            })?;
+            data[row * step_size..(row + 1) * step_size].copy_from_slice(values);
+        }
+    }
+
+    let mut shape = if batch_first {
+        vec![batch_size, total_length]
+    } else {
+        vec![total_length, batch_size]
+    };
+    shape.extend_from_slice(&packed.data.shape[1..]);
+    Ok((
+        Tensor::new(
+            data,
+            shape,
+            packed.data.requires_grad,
+            packed.data.device.clone(),
+            packed.data.dtype,
+        ),
+        packed.lengths(),
+    ))
+}
+
+/// (batch, max_length) mask with 1.0 at valid positions and 0.0 at padding
+pub fn sequence_mask(lengths: &[usize], max_length: usize) -> Tensor {
+    let data = lengths
+        .iter()
+        .flat_map(|&length| (0..max_length).map(move |t| if t < length { 1.0 } else { 0.0 }))
+        .collect();
+    Tensor::new(
+        data,
+        vec![lengths.len(), max_length],
+        false,
+        Device::CPU,
+        DataType::Float32,
+    )
+}
+
+/// Key padding mask for `MultiHeadAttention` with shape
+/// (batch, 1, 1, max_length): 1.0 marks padded keys that must not be
+/// attended to
+pub fn padding_mask(lengths: &[usize], max_length: usize) -> Tensor {
+    let mut mask = sequence_mask(lengths, max_length);
+    mask.data.iter_mut().for_each(|v| *v = 1.0 - *v);
+    mask.shape = vec![lengths.len(), 1, 1, max_length];
+    mask
+}
+
+/// (length, length) mask with 1.0 above the diagonal, hiding future
+/// positions from each query
+pub fn causal_mask(length: usize) -> Tensor {
+    let data = (0..length)
+        .flat_map(|i| (0..length).map(move |j| if j > i { 1.0 } else { 0.0 }))
+        .collect();
+    Tensor::new(
+        data,
+        vec![length, length],
+        false,
+        Device::CPU,
+        DataType::Float32,
+    )
+}
@@ -0,0 +1,222 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, random, tensor::Tensor};
+use crate::layer::weight_norm::{backward_weight, layer_weight, parameters_without_weight};
+use crate::layer::{accumulate_grad, assign_parameter, parameter, unknown_parameter, Layer};
+
+/// Spectral normalization: divides the `weight` of a wrapped layer by its
+/// largest singular value, estimated by power iteration, which bounds the
+/// layer's Lipschitz constant. Used for GAN discriminators.
+///
+/// The weight is treated as a matrix of (out_features, everything else).
+/// The wrapped layer exposes `weight_orig` in place of `weight`, plus the
+/// power iteration vectors `weight_u` and `weight_v` as buffers.
+pub struct SpectralNorm<L: Layer> {
+    inner: L,
+    weight_orig: Tensor,
+    weight_u: Tensor,
+    weight_v: Tensor,
+    power_iterations: usize,
+    sigma: f32,
+    training: bool,
+}
+
+/// Wraps a layer with a `weight` of rank 2 or more, such as `Linear` or
+/// `Conv2d`, in spectral normalization with one power iteration per
+/// training step
+pub fn spectral_norm<L: Layer>(layer: L) -> Result<SpectralNorm<L>, BellandeError> {
+    // Own gradient buffer, apart from the wrapped layer's weight
+    let weight_orig = parameter(layer_weight(&layer)?.detach());
+    let rows = weight_orig.shape[0];
+    let cols = weight_orig.data.len() / rows;
+
+    let mut weight_u = Tensor::zeros(&[rows]);
+    weight_u.data = random::normal(0.0, 1.0, rows);
+    normalize(&mut weight_u.data);
+    let mut weight_v = Tensor::zeros(&[cols]);
+    weight_v.data = mat_t_vec(&weight_orig.data, &weight_u.data, cols);
+    normalize(&mut weight_v.data);
+
+    Ok(SpectralNorm {
+        inner: layer,
+        weight_orig,
+        weight_u,
+        weight_v,
+        power_iterations: 1,
+        sigma: 1.0,
+        training: true,
+    })
+}
+
+impl<L: Layer> SpectralNorm<L> {
+    /// Number of power iterations run on each training forward pass
+    pub fn with_power_iterations(mut self, iterations: usize) -> Result<Self, BellandeError> {
+        if iterations == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Spectral normalization needs at least one power iteration".into(),
+            ));
+        }
+        self.power_iterations = iterations;
+        Ok(self)
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    /// Estimated largest singular value from the last forward pass
+    pub fn sigma(&self) -> f32 {
+        self.sigma
+    }
+
+    /// Unwraps the layer, leaving it with the current normalized weight
+    pub fn into_inner(mut self) -> Result<L, BellandeError> {
+        self.update_weight()?;
+        Ok(self.inner)
+    }
+
+    /// Refines `u` and `v` in training mode, then writes `W / sigma` into
+    /// the wrapped layer
+    fn update_weight(&mut self) -> Result<(), BellandeError> {
+        let cols = self.weight_v.data.len();
+        let w = &self.weight_orig.data;
+        if self.training {
+            for _ in 0..self.power_iterations {
+                self.weight_v.data = mat_t_vec(w, &self.weight_u.data, cols);
+                normalize(&mut self.weight_v.data);
+                self.weight_u.data = mat_vec(w, &self.weight_v.data, cols);
+                normalize(&mut self.weight_u.data);
+            }
+        }
+
+        let wv = mat_vec(w, &self.weight_v.data, cols);
+        let sigma: f32 = self.weight_u.data.iter().zip(&wv).map(|(u, x)| u * x).sum();
+        self.sigma = sigma.max(numerics().denom_eps);
+
+        let mut weight = self.weight_orig.clone();
+        weight.grad = None;
+        weight.data.iter_mut().for_each(|x| *x /= self.sigma);
+        self.inner.set_parameter("weight", weight)
+    }
+}
+
+impl<L: Layer> Layer for SpectralNorm<L> {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.update_weight()?;
+        self.inner.forward(input)
+    }
+
+    /// With `u` and `v` held fixed, `sigma = u^T W v`, so the gradient of
+    /// `W / sigma` is `G / sigma - <G, W> / sigma^2 * u v^T`
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_input, grad_weight) = backward_weight(&mut self.inner, grad)?;
+
+        let sigma = self.sigma;
+        let dot: f32 = grad_weight
+            .iter()
+            .zip(&self.weight_orig.data)
+            .map(|(g, w)| g * w)
+            .sum();
+        let scale = dot / (sigma * sigma);
+        let cols = self.weight_v.data.len();
+        let mut grad_orig = Tensor::zeros(&self.weight_orig.shape);
+        for ((out, row), &u) in grad_orig
+            .data
+            .chunks_mut(cols)
+            .zip(grad_weight.chunks(cols))
+            .zip(&self.weight_u.data)
+        {
+            for ((o, &g), &v) in out.iter_mut().zip(row).zip(&self.weight_v.data) {
+                *o = g / sigma - scale * u * v;
+            }
+        }
+
+        accumulate_grad(&mut self.weight_orig, &grad_orig)?;
+        Ok(grad_input)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        let mut params = vec![self.weight_orig.clone()];
+        params.extend(parameters_without_weight(&self.inner));
+        params
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = vec![("weight_orig".to_string(), self.weight_orig.clone())];
+        params.extend(
+            self.inner
+                .named_parameters()
+                .into_iter()
+                .filter(|(name, _)| name != "weight"),
+        );
+        params.push(("weight_u".to_string(), self.weight_u.clone()));
+        params.push(("weight_v".to_string(), self.weight_v.clone()));
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name {
+            "weight_orig" => assign_parameter(&mut self.weight_orig, name, value),
+            "weight_u" => assign_parameter(&mut self.weight_u, name, value),
+            "weight_v" => assign_parameter(&mut self.weight_v, name, value),
+            "weight" => Err(unknown_parameter(name)),
+            _ => self.inner.set_parameter(name, value),
+        }
+    }
+
+    fn train(&mut self) {
+        self.training = true;
+        self.inner.train()
+    }
+
+    fn eval(&mut self) {
+        self.training = false;
+        self.inner.eval()
+    }
+
+    fn set_dropout_active(&mut self, active: bool) {
+        self.inner.set_dropout_active(active)
+    }
+}
+
+/// `W x` for a row-major matrix with `cols` columns
+fn mat_vec(matrix: &[f32], x: &[f32], cols: usize) -> Vec<f32> {
+    matrix
+        .chunks(cols)
+        .map(|row| row.iter().zip(x).map(|(w, v)| w * v).sum())
+        .collect()
+}
+
+/// `W^T x` for a row-major matrix with `cols` columns
+fn mat_t_vec(matrix: &[f32], x: &[f32], cols: usize) -> Vec<f32> {
+    let mut result = vec![0.0; cols];
+    for (row, &scale) in matrix.chunks(cols).zip(x) {
+        for (r, &w) in result.iter_mut().zip(row) {
+            *r += scale * w;
+        }
+    }
+    result
+}
+
+fn normalize(values: &mut [f32]) {
+    let norm = values
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt()
+        .max(numerics().denom_eps);
+    values.iter_mut().for_each(|x| *x /= norm);
+}
@@ -0,0 +1,184 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{
+    activation::{ReLU, Sigmoid},
+    adaptive_pool::GlobalAvgPool2d,
+    linear::Linear,
+    prefixed, unknown_parameter, Layer,
+};
+
+/// Squeeze-and-excitation block (Hu et al., 2018): rescales each channel of
+/// a (batch, channels, height, width) feature map by a gate computed from
+/// its global average, `x * sigmoid(fc2(relu(fc1(mean(x)))))`. The output
+/// has the shape of the input.
+pub struct SEBlock {
+    channels: usize,
+    squeeze: GlobalAvgPool2d,
+    fc1: Linear,
+    relu: ReLU,
+    fc2: Linear,
+    sigmoid: Sigmoid,
+    input_cache: Option<Tensor>,
+    scale_cache: Option<Tensor>,
+}
+
+impl SEBlock {
+    /// Block over `channels` feature maps whose bottleneck has
+    /// `channels / reduction` units, at least one
+    pub fn new(channels: usize, reduction: usize) -> Result<Self, BellandeError> {
+        if channels == 0 || reduction == 0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Channels ({}) and reduction ({}) must be positive",
+                channels, reduction
+            )));
+        }
+        let hidden = (channels / reduction).max(1);
+        Ok(SEBlock {
+            channels,
+            squeeze: GlobalAvgPool2d::new(),
+            fc1: Linear::new(channels, hidden, true),
+            relu: ReLU::new(),
+            fc2: Linear::new(hidden, channels, true),
+            sigmoid: Sigmoid::new(),
+            input_cache: None,
+            scale_cache: None,
+        })
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        if input.shape.len() != 4 || input.shape[1] != self.channels {
+            return Err(BellandeError::InvalidShape(format!(
+                "Expected (batch_size, {}, height, width), got {:?}",
+                self.channels, input.shape
+            )));
+        }
+
+        let squeezed = self.squeeze.forward(input)?;
+        let hidden = Layer::forward(&mut self.fc1, &squeezed)?;
+        let hidden = Layer::forward(&mut self.relu, &hidden)?;
+        let excited = Layer::forward(&mut self.fc2, &hidden)?;
+        let scale = Layer::forward(&mut self.sigmoid, &excited)?;
+
+        let area = input.shape[2] * input.shape[3];
+        let output = input
+            .data
+            .chunks(area)
+            .zip(scale.data.iter())
+            .flat_map(|(plane, &s)| plane.iter().map(move |&x| x * s))
+            .collect();
+
+        self.input_cache = Some(input.clone());
+        self.scale_cache = Some(scale);
+        Ok(Tensor::new(
+            output,
+            input.shape.clone(),
+            input.requires_grad,
+            input.device.clone(),
+            input.dtype,
+        ))
+    }
+
+    pub fn backward(&mut self, grad_output: &Tensor) -> Result<Tensor, BellandeError> {
+        let (input, scale) = match (&self.input_cache, &self.scale_cache) {
+            (Some(input), Some(scale)) => (input, scale),
+            _ => {
+                return Err(BellandeError::RuntimeError(
+                    "Forward pass not called".into(),
+                ))
+            }
+        };
+        if grad_output.data.len() != input.data.len() {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected output gradient of shape {:?}, got {:?}",
+                input.shape, grad_output.shape
+            )));
+        }
+
+        // The output is `x * s`: each channel's gate receives the sum of
+        // `grad * x` over its feature map
+        let area = input.shape[2] * input.shape[3];
+        let grad_scale: Vec<f32> = grad_output
+            .data
+            .chunks(area)
+            .zip(input.data.chunks(area))
+            .map(|(grad, x)| grad.iter().zip(x).map(|(&g, &x)| g * x).sum())
+            .collect();
+        let grad_scale = Tensor::new(
+            grad_scale,
+            scale.shape.clone(),
+            true,
+            grad_output.device.clone(),
+            grad_output.dtype,
+        );
+
+        let grad = Layer::backward(&mut self.sigmoid, &grad_scale)?;
+        let grad = Layer::backward(&mut self.fc2, &grad)?;
+        let grad = Layer::backward(&mut self.relu, &grad)?;
+        let grad = Layer::backward(&mut self.fc1, &grad)?;
+        let grad_squeeze = self.squeeze.backward(&grad)?;
+
+        let grad_input = grad_output
+            .data
+            .chunks(area)
+            .zip(scale.data.iter())
+            .zip(grad_squeeze.data.chunks(area))
+            .flat_map(|((grad, &s), squeeze)| {
+                grad.iter().zip(squeeze).map(move |(&g, &q)| g * s + q)
+            })
+            .collect();
+
+        Ok(Tensor::new(
+            grad_input,
+            input.shape.clone(),
+            true,
+            grad_output.device.clone(),
+            grad_output.dtype,
+        ))
+    }
+
+    pub fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = prefixed("fc1", self.fc1.named_parameters());
+        params.extend(prefixed("fc2", self.fc2.named_parameters()));
+        params
+    }
+
+    pub fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name.split_once('.') {
+            Some(("fc1", rest)) => self.fc1.set_parameter(rest, value),
+            Some(("fc2", rest)) => self.fc2.set_parameter(rest, value),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+}
+
+impl Layer for SEBlock {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        SEBlock::forward(self, input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        SEBlock::backward(self, grad)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        SEBlock::named_parameters(self)
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        SEBlock::set_parameter(self, name, value)
+    }
+}
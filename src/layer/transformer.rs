@@ -13,12 +13,485 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::core::{error::BellandeError, numerics, tensor::Tensor};
+use crate::layer::activation::ReLU;
 use crate::layer::dropout::Dropout;
+use crate::layer::layer_norm::LayerNorm;
 use crate::layer::linear::Linear;
-use crate::layer::{activation::ReLU, layer_norm::LayerNorm};
-use crate::models::sequential::Sequential;
+use crate::layer::positional::RotaryEmbedding;
+use crate::layer::{prefixed, unknown_parameter, Layer};
 
+/// Attention mask of shape (len, len) for autoregressive decoding: ones
+/// above the diagonal mark the future positions a query may not attend to
+pub fn causal_mask(len: usize) -> Tensor {
+    let mut mask = Tensor::zeros(&[len, len]);
+    for i in 0..len {
+        for j in i + 1..len {
+            mask.data[i * len + j] = 1.0;
+        }
+    }
+    mask
+}
+
+/// Attention mask of shape (batch, query_len, key_len) hiding, for each
+/// sequence, the keys at or past its length in `lengths`
+pub fn key_padding_mask(
+    lengths: &[usize],
+    query_len: usize,
+    key_len: usize,
+) -> Result<Tensor, BellandeError> {
+    if let Some(&length) = lengths.iter().find(|&&length| length > key_len) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Sequence length {} exceeds the padded length {}",
+            length, key_len
+        )));
+    }
+    let mut mask = Tensor::zeros(&[lengths.len(), query_len, key_len]);
+    for (block, &length) in mask
+        .data
+        .chunks_mut((query_len * key_len).max(1))
+        .zip(lengths)
+    {
+        for row in block.chunks_mut(key_len.max(1)) {
+            row[length..].fill(1.0);
+        }
+    }
+    Ok(mask)
+}
+
+/// Self-attention mask of shape (batch, max_len, max_len) for sequences of
+/// the given lengths padded to `max_len`
+pub fn padding_mask(lengths: &[usize], max_len: usize) -> Result<Tensor, BellandeError> {
+    key_padding_mask(lengths, max_len, max_len)
+}
+
+/// Attention mask of shape (batch, query_len, key_len) hiding the keys
+/// whose token in the (batch, key_len) `tokens` is `pad_token`
+pub fn token_padding_mask(
+    tokens: &Tensor,
+    pad_token: usize,
+    query_len: usize,
+) -> Result<Tensor, BellandeError> {
+    let [batch_size, key_len] = tokens.shape[..] else {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected tokens of shape (batch, seq_len), got {:?}",
+            tokens.shape
+        )));
+    };
+    let pad = pad_token as f32;
+    let mut mask = Tensor::zeros(&[batch_size, query_len, key_len]);
+    for (block, tokens) in mask
+        .data
+        .chunks_mut((query_len * key_len).max(1))
+        .zip(tokens.data.chunks(key_len.max(1)))
+    {
+        for row in block.chunks_mut(key_len.max(1)) {
+            for (m, &token) in row.iter_mut().zip(tokens) {
+                if token == pad {
+                    *m = 1.0;
+                }
+            }
+        }
+    }
+    Ok(mask)
+}
+
+/// Causal self-attention mask of shape (batch, max_len, max_len) that also
+/// hides the padding of each sequence
+pub fn causal_padding_mask(lengths: &[usize], max_len: usize) -> Result<Tensor, BellandeError> {
+    combine_masks(&causal_mask(max_len), &padding_mask(lengths, max_len)?)
+}
+
+/// Union of two attention masks: a (query, key) pair is hidden when either
+/// mask hides it. A (query_len, key_len) mask is broadcast over the batch
+/// of a (batch, query_len, key_len) one.
+pub fn combine_masks(a: &Tensor, b: &Tensor) -> Result<Tensor, BellandeError> {
+    let (larger, smaller) = if a.shape.len() >= b.shape.len() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let rank = larger.shape.len();
+    let compatible = (2..=3).contains(&rank)
+        && (2..=3).contains(&smaller.shape.len())
+        && larger.shape[rank - smaller.shape.len()..] == smaller.shape[..];
+    if !compatible {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Cannot combine attention masks of shapes {:?} and {:?}",
+            a.shape, b.shape
+        )));
+    }
+    let mut mask = Tensor::zeros(&larger.shape);
+    for (block, larger) in mask
+        .data
+        .chunks_mut(smaller.data.len().max(1))
+        .zip(larger.data.chunks(smaller.data.len().max(1)))
+    {
+        for ((m, &x), &y) in block.iter_mut().zip(larger).zip(&smaller.data) {
+            if x != 0.0 || y != 0.0 {
+                *m = 1.0;
+            }
+        }
+    }
+    Ok(mask)
+}
+
+fn with_shape(tensor: &Tensor, shape: Vec<usize>) -> Tensor {
+    let mut reshaped = tensor.clone();
+    reshaped.shape = shape;
+    reshaped
+}
+
+fn add(a: &Tensor, b: &Tensor) -> Tensor {
+    let mut sum = a.clone();
+    sum.data.iter_mut().zip(&b.data).for_each(|(x, &y)| *x += y);
+    sum
+}
+
+/// Checks a (batch, seq_len, embed_dim) input
+fn sequence_dims(input: &Tensor, embed_dim: usize) -> Result<(usize, usize), BellandeError> {
+    match input.shape[..] {
+        [batch_size, seq_len, dim] if dim == embed_dim => Ok((batch_size, seq_len)),
+        _ => Err(BellandeError::InvalidShape(format!(
+            "Expected (batch, seq_len, {}) input, got {:?}",
+            embed_dim, input.shape
+        ))),
+    }
+}
+
+/// Applies a layer over (batch * seq_len, features) rows of a
+/// (batch, seq_len, features) tensor, as for `Linear`
+pub(crate) fn tokenwise_forward(
+    layer: &mut dyn Layer,
+    input: &Tensor,
+) -> Result<Tensor, BellandeError> {
+    let (batch_size, seq_len) = (input.shape[0], input.shape[1]);
+    let rows = with_shape(input, vec![batch_size * seq_len, input.shape[2]]);
+    let output = layer.forward(&rows)?;
+    let features = output.data.len() / (batch_size * seq_len).max(1);
+    Ok(with_shape(&output, vec![batch_size, seq_len, features]))
+}
+
+/// Backward pass of `tokenwise_forward`
+pub(crate) fn tokenwise_backward(
+    layer: &mut dyn Layer,
+    grad: &Tensor,
+) -> Result<Tensor, BellandeError> {
+    let (batch_size, seq_len) = (grad.shape[0], grad.shape[1]);
+    let rows = with_shape(grad, vec![batch_size * seq_len, grad.shape[2]]);
+    let grad_input = layer.backward(&rows)?;
+    let features = grad_input.data.len() / (batch_size * seq_len).max(1);
+    Ok(with_shape(&grad_input, vec![batch_size, seq_len, features]))
+}
+
+/// (batch, len, heads * head_dim) to (batch, heads, len, head_dim)
+fn split_heads(data: &[f32], batch_size: usize, len: usize, heads: usize, dim: usize) -> Vec<f32> {
+    let mut output = vec![0.0; data.len()];
+    for b in 0..batch_size {
+        for t in 0..len {
+            for h in 0..heads {
+                let src = ((b * len + t) * heads + h) * dim;
+                let dst = ((b * heads + h) * len + t) * dim;
+                output[dst..dst + dim].copy_from_slice(&data[src..src + dim]);
+            }
+        }
+    }
+    output
+}
+
+/// Inverse of `split_heads`
+fn merge_heads(data: &[f32], batch_size: usize, len: usize, heads: usize, dim: usize) -> Vec<f32> {
+    let mut output = vec![0.0; data.len()];
+    for b in 0..batch_size {
+        for h in 0..heads {
+            for t in 0..len {
+                let src = ((b * heads + h) * len + t) * dim;
+                let dst = ((b * len + t) * heads + h) * dim;
+                output[dst..dst + dim].copy_from_slice(&data[src..src + dim]);
+            }
+        }
+    }
+    output
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Row-wise softmax of the scaled query-key scores of (batch * heads,
+/// len, head_dim) queries and keys, skipping the pairs `masked(bh, i, j)`
+/// reports. Fully masked rows attend to nothing.
+fn attention_probs(
+    q: &[f32],
+    k: &[f32],
+    tgt_len: usize,
+    src_len: usize,
+    dim: usize,
+    masked: impl Fn(usize, usize, usize) -> bool,
+) -> Vec<f32> {
+    let batch_heads = q.len() / (tgt_len * dim).max(1);
+    let scale = 1.0 / (dim as f32).sqrt();
+    let mut probs = vec![0.0; batch_heads * tgt_len * src_len];
+    for (bh, block) in probs.chunks_mut((tgt_len * src_len).max(1)).enumerate() {
+        for (i, row) in block.chunks_mut(src_len.max(1)).enumerate() {
+            let q_row = &q[(bh * tgt_len + i) * dim..][..dim];
+            for (j, score) in row.iter_mut().enumerate() {
+                *score = if masked(bh, i, j) {
+                    f32::NEG_INFINITY
+                } else {
+                    dot(q_row, &k[(bh * src_len + j) * dim..][..dim]) * scale
+                };
+            }
+            let log_sum = numerics::log_sum_exp(row);
+            for p in row.iter_mut() {
+                *p = if log_sum == f32::NEG_INFINITY {
+                    0.0
+                } else {
+                    (*p - log_sum).exp()
+                };
+            }
+        }
+    }
+    probs
+}
+
+/// `masked(bh, i, j)` lookup into an optional (tgt_len, src_len) or
+/// (batch, tgt_len, src_len) mask, for (batch * heads) attention blocks
+fn mask_lookup(
+    mask: Option<&Tensor>,
+    heads: usize,
+    tgt_len: usize,
+    src_len: usize,
+) -> impl Fn(usize, usize, usize) -> bool + '_ {
+    move |bh, i, j| {
+        mask.is_some_and(|mask| {
+            let index = if mask.shape.len() == 3 {
+                (bh / heads * tgt_len + i) * src_len + j
+            } else {
+                i * src_len + j
+            };
+            mask.data[index] != 0.0
+        })
+    }
+}
+
+/// Attention-weighted sum of (batch * heads, src_len, head_dim) values
+fn weighted_values(
+    weights: &[f32],
+    v: &[f32],
+    tgt_len: usize,
+    src_len: usize,
+    dim: usize,
+) -> Vec<f32> {
+    let batch_heads = weights.len() / (tgt_len * src_len).max(1);
+    let mut output = vec![0.0; batch_heads * tgt_len * dim];
+    for (bh, block) in output.chunks_mut((tgt_len * dim).max(1)).enumerate() {
+        for (i, out) in block.chunks_mut(dim.max(1)).enumerate() {
+            let row = &weights[(bh * tgt_len + i) * src_len..][..src_len];
+            for (j, &w) in row.iter().enumerate() {
+                let v_row = &v[(bh * src_len + j) * dim..][..dim];
+                out.iter_mut().zip(v_row).for_each(|(o, &x)| *o += w * x);
+            }
+        }
+    }
+    output
+}
+
+/// Nonzero entries of an optional attention mask, for `bits_lookup`
+fn mask_bits(mask: Option<&Tensor>) -> Option<Vec<bool>> {
+    mask.map(|mask| mask.data.iter().map(|&value| value != 0.0).collect())
+}
+
+/// `masked(bh, i, j)` lookup into `mask_bits` of a (tgt_len, src_len) or
+/// (batch, tgt_len, src_len) mask. With a single batch both layouts index
+/// alike, so the length tells them apart.
+fn bits_lookup(
+    bits: Option<&[bool]>,
+    heads: usize,
+    tgt_len: usize,
+    src_len: usize,
+) -> impl Fn(usize, usize, usize) -> bool + '_ {
+    move |bh, i, j| {
+        bits.is_some_and(|bits| {
+            let index = if bits.len() > tgt_len * src_len {
+                (bh / heads * tgt_len + i) * src_len + j
+            } else {
+                i * src_len + j
+            };
+            bits[index]
+        })
+    }
+}
+
+/// Attention output of (batch * heads, len, head_dim) queries, keys and
+/// values, computed one tile of `block_size` queries and `block_size`
+/// keys at a time with an online softmax, so no (tgt_len, src_len) score
+/// matrix is ever materialized. Also returns the log-sum-exp of every
+/// query's scores, from which the backward pass recomputes probabilities;
+/// fully masked queries get zero output and a log-sum-exp of -inf.
+#[allow(clippy::too_many_arguments)]
+fn tiled_attention(
+    q: &[f32],
+    k: &[f32],
+    v: &[f32],
+    tgt_len: usize,
+    src_len: usize,
+    dim: usize,
+    block_size: usize,
+    masked: impl Fn(usize, usize, usize) -> bool,
+) -> (Vec<f32>, Vec<f32>) {
+    let batch_heads = q.len() / (tgt_len * dim).max(1);
+    let scale = 1.0 / (dim as f32).sqrt();
+    let mut output = vec![0.0; q.len()];
+    let mut log_sum = vec![f32::NEG_INFINITY; batch_heads * tgt_len];
+    let mut scores = vec![0.0; block_size];
+    let mut row_max = vec![f32::NEG_INFINITY; block_size];
+    let mut row_sum = vec![0.0; block_size];
+
+    for bh in 0..batch_heads {
+        for i0 in (0..tgt_len).step_by(block_size) {
+            let rows = block_size.min(tgt_len - i0);
+            row_max.fill(f32::NEG_INFINITY);
+            row_sum.fill(0.0);
+            for j0 in (0..src_len).step_by(block_size) {
+                let cols = block_size.min(src_len - j0);
+                for r in 0..rows {
+                    let i = i0 + r;
+                    let q_row = &q[(bh * tgt_len + i) * dim..][..dim];
+                    let tile = &mut scores[..cols];
+                    for (c, score) in tile.iter_mut().enumerate() {
+                        let j = j0 + c;
+                        *score = if masked(bh, i, j) {
+                            f32::NEG_INFINITY
+                        } else {
+                            dot(q_row, &k[(bh * src_len + j) * dim..][..dim]) * scale
+                        };
+                    }
+                    let tile_max = tile.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    if tile_max == f32::NEG_INFINITY {
+                        continue;
+                    }
+
+                    // Rescale what earlier tiles accumulated to the new maximum
+                    let new_max = row_max[r].max(tile_max);
+                    let correction = (row_max[r] - new_max).exp();
+                    let out = &mut output[(bh * tgt_len + i) * dim..][..dim];
+                    out.iter_mut().for_each(|o| *o *= correction);
+                    row_sum[r] *= correction;
+                    for (c, &score) in tile.iter().enumerate() {
+                        if score == f32::NEG_INFINITY {
+                            continue;
+                        }
+                        let p = (score - new_max).exp();
+                        row_sum[r] += p;
+                        let v_row = &v[(bh * src_len + j0 + c) * dim..][..dim];
+                        out.iter_mut().zip(v_row).for_each(|(o, &x)| *o += p * x);
+                    }
+                    row_max[r] = new_max;
+                }
+            }
+            for r in 0..rows {
+                if row_sum[r] > 0.0 {
+                    let i = i0 + r;
+                    let out = &mut output[(bh * tgt_len + i) * dim..][..dim];
+                    out.iter_mut().for_each(|o| *o /= row_sum[r]);
+                    log_sum[bh * tgt_len + i] = row_max[r] + row_sum[r].ln();
+                }
+            }
+        }
+    }
+    (output, log_sum)
+}
+
+/// Gradients of `tiled_attention` with respect to its queries, keys and
+/// values, recomputing each tile of probabilities from the saved
+/// log-sum-exp instead of reading a stored probability matrix
+#[allow(clippy::too_many_arguments)]
+fn tiled_attention_backward(
+    q: &[f32],
+    k: &[f32],
+    v: &[f32],
+    output: &[f32],
+    log_sum: &[f32],
+    grad_out: &[f32],
+    tgt_len: usize,
+    src_len: usize,
+    dim: usize,
+    block_size: usize,
+    masked: impl Fn(usize, usize, usize) -> bool,
+) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+    let batch_heads = q.len() / (tgt_len * dim).max(1);
+    let scale = 1.0 / (dim as f32).sqrt();
+    let mut grad_q = vec![0.0; q.len()];
+    let mut grad_k = vec![0.0; k.len()];
+    let mut grad_v = vec![0.0; v.len()];
+
+    // Softmax backward needs each row's dot(grad_out, output)
+    let inner: Vec<f32> = grad_out
+        .chunks(dim.max(1))
+        .zip(output.chunks(dim.max(1)))
+        .map(|(g, o)| dot(g, o))
+        .collect();
+
+    for bh in 0..batch_heads {
+        for i0 in (0..tgt_len).step_by(block_size) {
+            let rows = block_size.min(tgt_len - i0);
+            for j0 in (0..src_len).step_by(block_size) {
+                let cols = block_size.min(src_len - j0);
+                for r in 0..rows {
+                    let i = i0 + r;
+                    let row = bh * tgt_len + i;
+                    if log_sum[row] == f32::NEG_INFINITY {
+                        continue;
+                    }
+                    let q_start = row * dim;
+                    for j in j0..j0 + cols {
+                        if masked(bh, i, j) {
+                            continue;
+                        }
+                        let k_start = (bh * src_len + j) * dim;
+                        let score = dot(&q[q_start..q_start + dim], &k[k_start..k_start + dim]);
+                        let p = (score * scale - log_sum[row]).exp();
+                        let g_row = &grad_out[q_start..q_start + dim];
+                        grad_v[k_start..k_start + dim]
+                            .iter_mut()
+                            .zip(g_row)
+                            .for_each(|(g, &x)| *g += p * x);
+                        let dp = dot(g_row, &v[k_start..k_start + dim]);
+                        let ds = p * (dp - inner[row]) * scale;
+                        for d in 0..dim {
+                            grad_q[q_start + d] += ds * k[k_start + d];
+                            grad_k[k_start + d] += ds * q[q_start + d];
+                        }
+                    }
+                }
+            }
+        }
+    }
+    (grad_q, grad_k, grad_v)
+}
+
+/// Checks a (tgt_len, src_len) or (batch, tgt_len, src_len) mask
+fn check_mask(
+    mask: Option<&Tensor>,
+    batch_size: usize,
+    tgt_len: usize,
+    src_len: usize,
+) -> Result<(), BellandeError> {
+    match mask.map(|mask| &mask.shape[..]) {
+        None => Ok(()),
+        Some([t, s]) if *t == tgt_len && *s == src_len => Ok(()),
+        Some([b, t, s]) if *b == batch_size && *t == tgt_len && *s == src_len => Ok(()),
+        Some(shape) => Err(BellandeError::ShapeMismatch(format!(
+            "Expected an attention mask of shape ({}, {}) or ({}, {}, {}), got {:?}",
+            tgt_len, src_len, batch_size, tgt_len, src_len, shape
+        ))),
+    }
+}
+
+/// Multi-head scaled dot-product attention over (batch, seq_len,
+/// embed_dim) inputs. Masks hold nonzero values at the (query, key) pairs
+/// that must not be attended to.
 pub struct MultiHeadAttention {
     num_heads: usize,
     head_dim: usize,
@@ -27,14 +500,100 @@ pub struct MultiHeadAttention {
     v_proj: Linear,
     out_proj: Linear,
     dropout: Dropout,
+    rotary: Option<RotaryEmbedding>,
+    /// Tile size of the memory-efficient attention path, if enabled
+    block_size: Option<usize>,
     cache: Option<AttentionCache>,
 }
 
+/// Head-split projections and attention weights of the last forward pass
 struct AttentionCache {
-    query: Tensor,
-    key: Tensor,
-    value: Tensor,
-    attention_weights: Tensor,
+    batch_size: usize,
+    tgt_len: usize,
+    src_len: usize,
+    query: Vec<f32>,
+    key: Vec<f32>,
+    value: Vec<f32>,
+    weights: AttentionWeights,
+}
+
+enum AttentionWeights {
+    /// Full (batch * heads, tgt_len, src_len) probabilities, before and
+    /// after dropout
+    Full { probs: Vec<f32>, dropped: Vec<f32> },
+    /// Per-head outputs and score log-sum-exps of `tiled_attention`
+    Tiled {
+        output: Vec<f32>,
+        log_sum: Vec<f32>,
+        mask: Option<Vec<bool>>,
+        block_size: usize,
+    },
+}
+
+/// Key and value projections of earlier decoding steps, split into heads,
+/// so incremental decoding attends to a prefix without projecting it again
+#[derive(Clone, Debug, Default)]
+pub struct KvCache {
+    batch_size: usize,
+    len: usize,
+    /// (batch * heads, len, head_dim)
+    keys: Vec<f32>,
+    values: Vec<f32>,
+}
+
+impl KvCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of cached positions
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn check_batch(&self, batch_size: usize) -> Result<(), BellandeError> {
+        if !self.is_empty() && self.batch_size != batch_size {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Cache holds a batch of {}, got {}",
+                self.batch_size, batch_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Appends `new_len` positions of (batch * heads, new_len, head_dim)
+    /// keys and values
+    fn append(
+        &mut self,
+        keys: &[f32],
+        values: &[f32],
+        batch_size: usize,
+        new_len: usize,
+        dim: usize,
+    ) {
+        let batch_heads = keys.len() / (new_len * dim).max(1);
+        let (old, new) = (self.len * dim, new_len * dim);
+        let interleave = |cached: &[f32], added: &[f32]| {
+            let mut merged = Vec::with_capacity(cached.len() + added.len());
+            for bh in 0..batch_heads {
+                merged.extend_from_slice(&cached[bh * old..(bh + 1) * old]);
+                merged.extend_from_slice(&added[bh * new..(bh + 1) * new]);
+            }
+            merged
+        };
+        self.keys = interleave(&self.keys, keys);
+        self.values = interleave(&self.values, values);
+        self.batch_size = batch_size;
+        self.len += new_len;
+    }
 }
 
 impl MultiHeadAttention {
@@ -54,10 +613,64 @@ impl MultiHeadAttention {
             v_proj: Linear::new(embed_dim, embed_dim, true),
             out_proj: Linear::new(embed_dim, embed_dim, true),
             dropout: Dropout::new(dropout),
+            rotary: None,
+            block_size: None,
             cache: None,
         }
     }
 
+    /// Rotates queries and keys of every head by their position before
+    /// computing attention scores
+    pub fn with_rotary_embedding(mut self, rotary: RotaryEmbedding) -> Result<Self, BellandeError> {
+        if rotary.head_dim() != self.head_dim {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Rotary embedding has head dimension {}, attention uses {}",
+                rotary.head_dim(),
+                self.head_dim
+            )));
+        }
+        self.rotary = Some(rotary);
+        Ok(self)
+    }
+
+    /// Computes attention in tiles of `block_size` queries by `block_size`
+    /// keys, keeping only one score per query between the forward and
+    /// backward pass instead of the full (batch, heads, tgt_len, src_len)
+    /// probabilities. Applies while attention dropout is inactive, i.e. in
+    /// evaluation mode or with a dropout probability of zero; otherwise
+    /// the dropout mask needs the full probabilities.
+    pub fn with_memory_efficient_attention(
+        mut self,
+        block_size: usize,
+    ) -> Result<Self, BellandeError> {
+        if block_size == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Attention block size must be positive".into(),
+            ));
+        }
+        self.block_size = Some(block_size);
+        Ok(self)
+    }
+
+    pub fn embed_dim(&self) -> usize {
+        self.num_heads * self.head_dim
+    }
+
+    /// Block size of the memory-efficient path when it applies
+    fn tiled_block_size(&self) -> Option<usize> {
+        self.block_size.filter(|_| self.dropout.is_identity())
+    }
+
+    fn heads(&self, data: Vec<f32>, batch_size: usize, len: usize, like: &Tensor) -> Tensor {
+        Tensor::new(
+            data,
+            vec![batch_size, self.num_heads, len, self.head_dim],
+            false,
+            like.device.clone(),
+            like.dtype,
+        )
+    }
+
     pub fn forward(
         &mut self,
         query: &Tensor,
@@ -65,139 +678,616 @@ impl MultiHeadAttention {
         value: &Tensor,
         mask: Option<&Tensor>,
     ) -> Result<Tensor, BellandeError> {
-        let batch_size = query.shape[0];
-        let tgt_len = query.shape[1];
-        let src_len = key.shape[1];
-
-        // Linear projections
-        let q = self.q_proj.forward(query)?;
-        let k = self.k_proj.forward(key)?;
-        let v = self.v_proj.forward(value)?;
-
-        // Reshape for multi-head attention
-        let q = q
-            .reshape(&[batch_size, tgt_len, self.num_heads, self.head_dim])?
-            .transpose(1, 2)?;
-        let k = k
-            .reshape(&[batch_size, src_len, self.num_heads, self.head_dim])?
-            .transpose(1, 2)?;
-        let v = v
-            .reshape(&[batch_size, src_len, self.num_heads, self.head_dim])?
-            .transpose(1, 2)?;
-
-        // Calculate attention scores
-        let scale = (self.head_dim as f32).sqrt();
-        let attention_weights = q.matmul(&k.transpose(2, 3)?)? / scale;
-
-        // Apply mask if provided
-        if let Some(mask) = mask {
-            attention_weights.masked_fill(mask, f32::NEG_INFINITY)?;
-        }
-
-        // Apply softmax and dropout
-        let attention_weights = attention_weights.softmax(-1)?;
-        let attention_weights = self.dropout.forward(&attention_weights)?;
-
-        // Apply attention to values
-        let output = attention_weights.matmul(&v)?;
-
-        // Reshape and project output
-        let output = output.transpose(1, 2)?.reshape(&[
-            batch_size,
-            tgt_len,
-            self.num_heads * self.head_dim,
-        ])?;
-        let output = self.out_proj.forward(&output)?;
+        let embed_dim = self.embed_dim();
+        let (batch_size, tgt_len) = sequence_dims(query, embed_dim)?;
+        let (key_batch, src_len) = sequence_dims(key, embed_dim)?;
+        if key_batch != batch_size || key.shape != value.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Query {:?}, key {:?} and value {:?} do not match",
+                query.shape, key.shape, value.shape
+            )));
+        }
+        check_mask(mask, batch_size, tgt_len, src_len)?;
+        let (heads, dim) = (self.num_heads, self.head_dim);
+
+        // Linear projections, split into heads
+        let q = tokenwise_forward(&mut self.q_proj, query)?;
+        let k = tokenwise_forward(&mut self.k_proj, key)?;
+        let v = tokenwise_forward(&mut self.v_proj, value)?;
+        let mut q = split_heads(&q.data, batch_size, tgt_len, heads, dim);
+        let mut k = split_heads(&k.data, batch_size, src_len, heads, dim);
+        let v = split_heads(&v.data, batch_size, src_len, heads, dim);
+        if let Some(rotary) = &self.rotary {
+            q = rotary
+                .apply(&self.heads(q, batch_size, tgt_len, query), 0)?
+                .data;
+            k = rotary
+                .apply(&self.heads(k, batch_size, src_len, key), 0)?
+                .data;
+        }
+
+        let (output, weights) = if let Some(block_size) = self.tiled_block_size() {
+            let mask = mask_bits(mask);
+            let (output, log_sum) = tiled_attention(
+                &q,
+                &k,
+                &v,
+                tgt_len,
+                src_len,
+                dim,
+                block_size,
+                bits_lookup(mask.as_deref(), heads, tgt_len, src_len),
+            );
+            let weights = AttentionWeights::Tiled {
+                output: output.clone(),
+                log_sum,
+                mask,
+                block_size,
+            };
+            (output, weights)
+        } else {
+            // Scaled scores and masked softmax over keys
+            let probs = attention_probs(
+                &q,
+                &k,
+                tgt_len,
+                src_len,
+                dim,
+                mask_lookup(mask, heads, tgt_len, src_len),
+            );
+            let dropped = self
+                .dropout
+                .forward(&Tensor::new(
+                    probs.clone(),
+                    vec![batch_size, heads, tgt_len, src_len],
+                    false,
+                    query.device.clone(),
+                    query.dtype,
+                ))?
+                .data;
+
+            // Weighted sum of values
+            let output = weighted_values(&dropped, &v, tgt_len, src_len, dim);
+            (output, AttentionWeights::Full { probs, dropped })
+        };
+
+        // Merged back into (batch, tgt_len, embed_dim)
+        let merged = Tensor::new(
+            merge_heads(&output, batch_size, tgt_len, heads, dim),
+            vec![batch_size, tgt_len, embed_dim],
+            query.requires_grad,
+            query.device.clone(),
+            query.dtype,
+        );
+        let output = tokenwise_forward(&mut self.out_proj, &merged)?;
 
-        // Cache for backward pass
         self.cache = Some(AttentionCache {
-            query: query.clone(),
-            key: key.clone(),
-            value: value.clone(),
-            attention_weights,
+            batch_size,
+            tgt_len,
+            src_len,
+            query: q,
+            key: k,
+            value: v,
+            weights,
         });
 
         Ok(output)
     }
+
+    /// Projects `input` into (batch * heads, len, head_dim) queries, keys
+    /// and values, with rotary embeddings starting at `offset`
+    fn project_heads(
+        &mut self,
+        input: &Tensor,
+        offset: usize,
+    ) -> Result<(Vec<f32>, Vec<f32>, Vec<f32>), BellandeError> {
+        let (batch_size, len) = sequence_dims(input, self.embed_dim())?;
+        let (heads, dim) = (self.num_heads, self.head_dim);
+        let q = tokenwise_forward(&mut self.q_proj, input)?;
+        let k = tokenwise_forward(&mut self.k_proj, input)?;
+        let v = tokenwise_forward(&mut self.v_proj, input)?;
+        let mut q = split_heads(&q.data, batch_size, len, heads, dim);
+        let mut k = split_heads(&k.data, batch_size, len, heads, dim);
+        let v = split_heads(&v.data, batch_size, len, heads, dim);
+        if let Some(rotary) = &self.rotary {
+            q = rotary
+                .apply(&self.heads(q, batch_size, len, input), offset)?
+                .data;
+            k = rotary
+                .apply(&self.heads(k, batch_size, len, input), offset)?
+                .data;
+        }
+        Ok((q, k, v))
+    }
+
+    /// Merges (batch * heads, len, head_dim) attention outputs and applies
+    /// the output projection
+    fn project_output(
+        &mut self,
+        output: &[f32],
+        batch_size: usize,
+        len: usize,
+        like: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let merged = Tensor::new(
+            merge_heads(output, batch_size, len, self.num_heads, self.head_dim),
+            vec![batch_size, len, self.embed_dim()],
+            false,
+            like.device.clone(),
+            like.dtype,
+        );
+        tokenwise_forward(&mut self.out_proj, &merged)
+    }
+
+    /// Causal self-attention over new positions only, for autoregressive
+    /// inference. Appends the keys and values of the (batch, new_len,
+    /// embed_dim) input to `cache`, and each new position attends to the
+    /// cached prefix and to itself and earlier new positions. Skips dropout
+    /// and records nothing for `backward`.
+    pub fn forward_incremental(
+        &mut self,
+        input: &Tensor,
+        cache: &mut KvCache,
+    ) -> Result<Tensor, BellandeError> {
+        let (batch_size, new_len) = sequence_dims(input, self.embed_dim())?;
+        cache.check_batch(batch_size)?;
+        let offset = cache.len();
+        let (q, k, v) = self.project_heads(input, offset)?;
+        cache.append(&k, &v, batch_size, new_len, self.head_dim);
+
+        let src_len = cache.len();
+        let probs = attention_probs(
+            &q,
+            &cache.keys,
+            new_len,
+            src_len,
+            self.head_dim,
+            |_, i, j| j > offset + i,
+        );
+        let output = weighted_values(&probs, &cache.values, new_len, src_len, self.head_dim);
+        self.cache = None;
+        self.project_output(&output, batch_size, new_len, input)
+    }
+
+    /// Attention over fixed keys and values such as the encoder memory,
+    /// for autoregressive inference: `memory` is projected into `cache` on
+    /// the first call and reused afterwards. Masks are shaped like those of
+    /// `forward` for the (batch, tgt_len, embed_dim) query. Skips dropout
+    /// and records nothing for `backward`.
+    pub fn forward_cached_memory(
+        &mut self,
+        query: &Tensor,
+        memory: &Tensor,
+        mask: Option<&Tensor>,
+        cache: &mut KvCache,
+    ) -> Result<Tensor, BellandeError> {
+        if self.rotary.is_some() {
+            return Err(BellandeError::NotImplemented(
+                "Cached memory attention does not support rotary embeddings".into(),
+            ));
+        }
+        let embed_dim = self.embed_dim();
+        let (batch_size, tgt_len) = sequence_dims(query, embed_dim)?;
+        cache.check_batch(batch_size)?;
+        if cache.is_empty() {
+            let (memory_batch, src_len) = sequence_dims(memory, embed_dim)?;
+            if memory_batch != batch_size {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Query {:?} and memory {:?} batches differ",
+                    query.shape, memory.shape
+                )));
+            }
+            let (heads, dim) = (self.num_heads, self.head_dim);
+            let k = tokenwise_forward(&mut self.k_proj, memory)?;
+            let v = tokenwise_forward(&mut self.v_proj, memory)?;
+            let k = split_heads(&k.data, batch_size, src_len, heads, dim);
+            let v = split_heads(&v.data, batch_size, src_len, heads, dim);
+            cache.append(&k, &v, batch_size, src_len, dim);
+        }
+        let src_len = cache.len();
+        check_mask(mask, batch_size, tgt_len, src_len)?;
+
+        let (heads, dim) = (self.num_heads, self.head_dim);
+        let q = tokenwise_forward(&mut self.q_proj, query)?;
+        let q = split_heads(&q.data, batch_size, tgt_len, heads, dim);
+        let probs = attention_probs(
+            &q,
+            &cache.keys,
+            tgt_len,
+            src_len,
+            dim,
+            mask_lookup(mask, heads, tgt_len, src_len),
+        );
+        let output = weighted_values(&probs, &cache.values, tgt_len, src_len, dim);
+        self.cache = None;
+        self.project_output(&output, batch_size, tgt_len, query)
+    }
+
+    /// Returns the gradients with respect to the query, key and value inputs
+    /// of the last forward pass and accumulates the projection gradients
+    pub fn backward(&mut self, grad: &Tensor) -> Result<(Tensor, Tensor, Tensor), BellandeError> {
+        let cache = self
+            .cache
+            .take()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        let (batch_size, tgt_len, src_len) = (cache.batch_size, cache.tgt_len, cache.src_len);
+        let (heads, dim) = (self.num_heads, self.head_dim);
+
+        let grad_merged = tokenwise_backward(&mut self.out_proj, grad)?;
+        let grad_out = split_heads(&grad_merged.data, batch_size, tgt_len, heads, dim);
+
+        let (mut grad_q, mut grad_k, grad_v) = match &cache.weights {
+            AttentionWeights::Full { probs, dropped } => {
+                self.full_attention_backward(&cache, probs, dropped, &grad_out, grad)?
+            }
+            AttentionWeights::Tiled {
+                output,
+                log_sum,
+                mask,
+                block_size,
+            } => tiled_attention_backward(
+                &cache.query,
+                &cache.key,
+                &cache.value,
+                output,
+                log_sum,
+                &grad_out,
+                tgt_len,
+                src_len,
+                dim,
+                *block_size,
+                bits_lookup(mask.as_deref(), heads, tgt_len, src_len),
+            ),
+        };
+        if let Some(rotary) = &self.rotary {
+            grad_q = rotary
+                .apply_backward(&self.heads(grad_q, batch_size, tgt_len, grad), 0)?
+                .data;
+            grad_k = rotary
+                .apply_backward(&self.heads(grad_k, batch_size, src_len, grad), 0)?
+                .data;
+        }
+
+        let embed_dim = self.embed_dim();
+        let merged = |data: &[f32], len: usize| {
+            Tensor::new(
+                merge_heads(data, batch_size, len, heads, dim),
+                vec![batch_size, len, embed_dim],
+                false,
+                grad.device.clone(),
+                grad.dtype,
+            )
+        };
+        let grad_query = tokenwise_backward(&mut self.q_proj, &merged(&grad_q, tgt_len))?;
+        let grad_key = tokenwise_backward(&mut self.k_proj, &merged(&grad_k, src_len))?;
+        let grad_value = tokenwise_backward(&mut self.v_proj, &merged(&grad_v, src_len))?;
+        Ok((grad_query, grad_key, grad_value))
+    }
+
+    /// Gradients of the per-head queries, keys and values through stored
+    /// attention probabilities and their dropout
+    fn full_attention_backward(
+        &mut self,
+        cache: &AttentionCache,
+        probs: &[f32],
+        dropped: &[f32],
+        grad_out: &[f32],
+        grad: &Tensor,
+    ) -> Result<(Vec<f32>, Vec<f32>, Vec<f32>), BellandeError> {
+        let (batch_size, tgt_len, src_len) = (cache.batch_size, cache.tgt_len, cache.src_len);
+        let (heads, dim) = (self.num_heads, self.head_dim);
+
+        // Through the weighted sum of values
+        let mut grad_dropped = vec![0.0; dropped.len()];
+        let mut grad_v = vec![0.0; cache.value.len()];
+        for bh in 0..batch_size * heads {
+            for i in 0..tgt_len {
+                let g_row = &grad_out[(bh * tgt_len + i) * dim..][..dim];
+                for j in 0..src_len {
+                    let index = (bh * tgt_len + i) * src_len + j;
+                    let v_start = (bh * src_len + j) * dim;
+                    grad_dropped[index] = dot(g_row, &cache.value[v_start..v_start + dim]);
+                    let w = dropped[index];
+                    grad_v[v_start..v_start + dim]
+                        .iter_mut()
+                        .zip(g_row)
+                        .for_each(|(g, &x)| *g += w * x);
+                }
+            }
+        }
+        let grad_probs = Layer::backward(
+            &mut self.dropout,
+            &Tensor::new(
+                grad_dropped,
+                vec![batch_size, heads, tgt_len, src_len],
+                false,
+                grad.device.clone(),
+                grad.dtype,
+            ),
+        )?;
+
+        // Through the softmax and the scaled scores
+        let scale = 1.0 / (dim as f32).sqrt();
+        let mut grad_q = vec![0.0; cache.query.len()];
+        let mut grad_k = vec![0.0; cache.key.len()];
+        for bh in 0..batch_size * heads {
+            for i in 0..tgt_len {
+                let row = (bh * tgt_len + i) * src_len;
+                let p = &probs[row..row + src_len];
+                let dp = &grad_probs.data[row..row + src_len];
+                let inner = dot(p, dp);
+                let q_start = (bh * tgt_len + i) * dim;
+                for j in 0..src_len {
+                    let ds = p[j] * (dp[j] - inner) * scale;
+                    if ds == 0.0 {
+                        continue;
+                    }
+                    let k_start = (bh * src_len + j) * dim;
+                    for d in 0..dim {
+                        grad_q[q_start + d] += ds * cache.key[k_start + d];
+                        grad_k[k_start + d] += ds * cache.query[q_start + d];
+                    }
+                }
+            }
+        }
+        Ok((grad_q, grad_k, grad_v))
+    }
+
+    pub fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = prefixed("q_proj", self.q_proj.named_parameters());
+        params.extend(prefixed("k_proj", self.k_proj.named_parameters()));
+        params.extend(prefixed("v_proj", self.v_proj.named_parameters()));
+        params.extend(prefixed("out_proj", self.out_proj.named_parameters()));
+        params
+    }
+
+    pub fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        let (layer, rest) = name
+            .split_once('.')
+            .ok_or_else(|| unknown_parameter(name))?;
+        match layer {
+            "q_proj" => self.q_proj.set_parameter(rest, value),
+            "k_proj" => self.k_proj.set_parameter(rest, value),
+            "v_proj" => self.v_proj.set_parameter(rest, value),
+            "out_proj" => self.out_proj.set_parameter(rest, value),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+
+    pub fn train(&mut self) {
+        self.dropout.train();
+    }
+
+    pub fn eval(&mut self) {
+        self.dropout.eval();
+    }
+}
+
+/// Position-wise feed-forward block `Linear -> ReLU -> Linear`
+struct FeedForward {
+    linear1: Linear,
+    relu: ReLU,
+    linear2: Linear,
+}
+
+impl FeedForward {
+    fn new(embed_dim: usize, ff_dim: usize) -> Self {
+        FeedForward {
+            linear1: Linear::new(embed_dim, ff_dim, true),
+            relu: ReLU::new(),
+            linear2: Linear::new(ff_dim, embed_dim, true),
+        }
+    }
+
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let hidden = tokenwise_forward(&mut self.linear1, input)?;
+        let hidden = Layer::forward(&mut self.relu, &hidden)?;
+        tokenwise_forward(&mut self.linear2, &hidden)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let grad = tokenwise_backward(&mut self.linear2, grad)?;
+        let grad = Layer::backward(&mut self.relu, &grad)?;
+        tokenwise_backward(&mut self.linear1, &grad)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = prefixed("linear1", self.linear1.named_parameters());
+        params.extend(prefixed("linear2", self.linear2.named_parameters()));
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name.split_once('.') {
+            Some(("linear1", rest)) => self.linear1.set_parameter(rest, value),
+            Some(("linear2", rest)) => self.linear2.set_parameter(rest, value),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
 }
 
+/// Pre-norm encoder layer: self-attention and a feed-forward block, each
+/// wrapped in a residual connection
 pub struct TransformerEncoderLayer {
     self_attn: MultiHeadAttention,
-    ff_network: Sequential,
+    feed_forward: FeedForward,
     norm1: LayerNorm,
     norm2: LayerNorm,
-    dropout: Dropout,
+    dropout1: Dropout,
+    dropout2: Dropout,
 }
 
 impl TransformerEncoderLayer {
     pub fn new(embed_dim: usize, num_heads: usize, ff_dim: usize, dropout: f32) -> Self {
-        let ff_network = Sequential::new()
-            .add(Linear::new(embed_dim, ff_dim, true))
-            .add(ReLU::new())
-            .add(Linear::new(ff_dim, embed_dim, true));
-
         TransformerEncoderLayer {
             self_attn: MultiHeadAttention::new(embed_dim, num_heads, dropout),
-            ff_network,
-            norm1: LayerNorm::new(embed_dim),
-            norm2: LayerNorm::new(embed_dim),
-            dropout: Dropout::new(dropout),
+            feed_forward: FeedForward::new(embed_dim, ff_dim),
+            norm1: LayerNorm::new(vec![embed_dim], numerics::numerics().norm_eps, true),
+            norm2: LayerNorm::new(vec![embed_dim], numerics::numerics().norm_eps, true),
+            dropout1: Dropout::new(dropout),
+            dropout2: Dropout::new(dropout),
         }
     }
 
+    /// Uses rotary position embeddings in self-attention
+    pub fn with_rotary_embedding(mut self, rotary: RotaryEmbedding) -> Result<Self, BellandeError> {
+        self.self_attn = self.self_attn.with_rotary_embedding(rotary)?;
+        Ok(self)
+    }
+
+    /// See `MultiHeadAttention::with_memory_efficient_attention`
+    pub fn with_memory_efficient_attention(
+        mut self,
+        block_size: usize,
+    ) -> Result<Self, BellandeError> {
+        self.self_attn = self.self_attn.with_memory_efficient_attention(block_size)?;
+        Ok(self)
+    }
+
     pub fn forward(
         &mut self,
         src: &Tensor,
         src_mask: Option<&Tensor>,
     ) -> Result<Tensor, BellandeError> {
         // Self attention block
-        let residual = src.clone();
-        let mut output = self.norm1.forward(src)?;
-        output = self
+        let normed = self.norm1.forward(src)?;
+        let attended = self
             .self_attn
-            .forward(&output, &output, &output, src_mask)?;
-        output = self.dropout.forward(&output)?;
-        output = output + residual;
+            .forward(&normed, &normed, &normed, src_mask)?;
+        let output = add(src, &self.dropout1.forward(&attended)?);
 
         // Feed forward block
-        let residual = output.clone();
-        output = self.norm2.forward(&output)?;
-        output = self.ff_network.forward(&output)?;
-        output = self.dropout.forward(&output)?;
-        output = output + residual;
+        let normed = self.norm2.forward(&output)?;
+        let fed = self.feed_forward.forward(&normed)?;
+        Ok(add(&output, &self.dropout2.forward(&fed)?))
+    }
 
-        Ok(output)
+    pub fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let grad_fed = Layer::backward(&mut self.dropout2, grad)?;
+        let grad_normed = self.feed_forward.backward(&grad_fed)?;
+        let grad = add(grad, &Layer::backward(&mut self.norm2, &grad_normed)?);
+
+        let grad_attended = Layer::backward(&mut self.dropout1, &grad)?;
+        let (grad_q, grad_k, grad_v) = self.self_attn.backward(&grad_attended)?;
+        let grad_normed = add(&add(&grad_q, &grad_k), &grad_v);
+        Ok(add(&grad, &Layer::backward(&mut self.norm1, &grad_normed)?))
+    }
+
+    pub fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = prefixed("self_attn", self.self_attn.named_parameters());
+        params.extend(prefixed(
+            "feed_forward",
+            self.feed_forward.named_parameters(),
+        ));
+        params.extend(prefixed("norm1", self.norm1.named_parameters()));
+        params.extend(prefixed("norm2", self.norm2.named_parameters()));
+        params
+    }
+
+    pub fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name.split_once('.') {
+            Some(("self_attn", rest)) => self.self_attn.set_parameter(rest, value),
+            Some(("feed_forward", rest)) => self.feed_forward.set_parameter(rest, value),
+            Some(("norm1", rest)) => self.norm1.set_parameter(rest, value),
+            Some(("norm2", rest)) => self.norm2.set_parameter(rest, value),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+
+    pub fn train(&mut self) {
+        self.self_attn.train();
+        self.dropout1.train();
+        self.dropout2.train();
+    }
+
+    pub fn eval(&mut self) {
+        self.self_attn.eval();
+        self.dropout1.eval();
+        self.dropout2.eval();
+    }
+}
+
+/// Self-attention and cross-attention caches of one decoder layer
+#[derive(Clone, Debug, Default)]
+pub struct DecoderLayerCache {
+    self_attn: KvCache,
+    cross_attn: KvCache,
+}
+
+/// Attention caches of every decoder layer, from
+/// `TransformerDecoder::new_cache`
+#[derive(Clone, Debug)]
+pub struct DecoderCache {
+    layers: Vec<DecoderLayerCache>,
+}
+
+impl DecoderCache {
+    /// Number of target positions decoded so far
+    pub fn len(&self) -> usize {
+        self.layers.first().map_or(0, |layer| layer.self_attn.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every position and the cached memory projections
+    pub fn clear(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.clear();
+            layer.cross_attn.clear();
+        }
     }
 }
 
+/// Pre-norm decoder layer: masked self-attention, attention over the
+/// encoder output and a feed-forward block, each with a residual connection
 pub struct TransformerDecoderLayer {
     self_attn: MultiHeadAttention,
     cross_attn: MultiHeadAttention,
-    ff_network: Sequential,
+    feed_forward: FeedForward,
     norm1: LayerNorm,
     norm2: LayerNorm,
     norm3: LayerNorm,
-    dropout: Dropout,
+    dropout1: Dropout,
+    dropout2: Dropout,
+    dropout3: Dropout,
 }
 
 impl TransformerDecoderLayer {
     pub fn new(embed_dim: usize, num_heads: usize, ff_dim: usize, dropout: f32) -> Self {
-        let ff_network = Sequential::new()
-            .add(Linear::new(embed_dim, ff_dim, true))
-            .add(ReLU::new())
-            .add(Linear::new(ff_dim, embed_dim, true));
-
         TransformerDecoderLayer {
             self_attn: MultiHeadAttention::new(embed_dim, num_heads, dropout),
             cross_attn: MultiHeadAttention::new(embed_dim, num_heads, dropout),
-            ff_network,
-            norm1: LayerNorm::new(embed_dim),
-            norm2: LayerNorm::new(embed_dim),
-            norm3: LayerNorm::new(embed_dim),
-            dropout: Dropout::new(dropout),
+            feed_forward: FeedForward::new(embed_dim, ff_dim),
+            norm1: LayerNorm::new(vec![embed_dim], numerics::numerics().norm_eps, true),
+            norm2: LayerNorm::new(vec![embed_dim], numerics::numerics().norm_eps, true),
+            norm3: LayerNorm::new(vec![embed_dim], numerics::numerics().norm_eps, true),
+            dropout1: Dropout::new(dropout),
+            dropout2: Dropout::new(dropout),
+            dropout3: Dropout::new(dropout),
         }
     }
 
+    /// Uses rotary position embeddings in self-attention
+    pub fn with_rotary_embedding(mut self, rotary: RotaryEmbedding) -> Result<Self, BellandeError> {
+        self.self_attn = self.self_attn.with_rotary_embedding(rotary)?;
+        Ok(self)
+    }
+
+    /// Uses memory-efficient attention for both self- and cross-attention;
+    /// see `MultiHeadAttention::with_memory_efficient_attention`
+    pub fn with_memory_efficient_attention(
+        mut self,
+        block_size: usize,
+    ) -> Result<Self, BellandeError> {
+        self.self_attn = self.self_attn.with_memory_efficient_attention(block_size)?;
+        self.cross_attn = self
+            .cross_attn
+            .with_memory_efficient_attention(block_size)?;
+        Ok(self)
+    }
+
     pub fn forward(
         &mut self,
         tgt: &Tensor,
@@ -206,30 +1296,523 @@ impl TransformerDecoderLayer {
         memory_mask: Option<&Tensor>,
     ) -> Result<Tensor, BellandeError> {
         // Self attention block
-        let residual = tgt.clone();
-        let mut output = self.norm1.forward(tgt)?;
-        output = self
+        let normed = self.norm1.forward(tgt)?;
+        let attended = self
             .self_attn
-            .forward(&output, &output, &output, tgt_mask)?;
-        output = self.dropout.forward(&output)?;
-        output = output + residual;
+            .forward(&normed, &normed, &normed, tgt_mask)?;
+        let output = add(tgt, &self.dropout1.forward(&attended)?);
 
         // Cross attention block
-        let residual = output.clone();
-        output = self.norm2.forward(&output)?;
-        output = self
+        let normed = self.norm2.forward(&output)?;
+        let attended = self
             .cross_attn
-            .forward(&output, memory, memory, memory_mask)?;
-        output = self.dropout.forward(&output)?;
-        output = output + residual;
+            .forward(&normed, memory, memory, memory_mask)?;
+        let output = add(&output, &self.dropout2.forward(&attended)?);
 
         // Feed forward block
-        let residual = output.clone();
-        output = self.norm3.forward(&output)?;
-        output = self.ff_network.forward(&output)?;
-        output = self.dropout.forward(&output)?;
-        output = output + residual;
+        let normed = self.norm3.forward(&output)?;
+        let fed = self.feed_forward.forward(&normed)?;
+        Ok(add(&output, &self.dropout3.forward(&fed)?))
+    }
 
-        Ok(output)
+    /// Decodes only the new (batch, new_len, embed_dim) target positions,
+    /// reusing the attention keys and values in `cache`. For inference:
+    /// skips dropout and records nothing for `backward`.
+    pub fn forward_incremental(
+        &mut self,
+        tgt: &Tensor,
+        memory: &Tensor,
+        memory_mask: Option<&Tensor>,
+        cache: &mut DecoderLayerCache,
+    ) -> Result<Tensor, BellandeError> {
+        let normed = self.norm1.forward(tgt)?;
+        let attended = self
+            .self_attn
+            .forward_incremental(&normed, &mut cache.self_attn)?;
+        let output = add(tgt, &attended);
+
+        let normed = self.norm2.forward(&output)?;
+        let attended = self.cross_attn.forward_cached_memory(
+            &normed,
+            memory,
+            memory_mask,
+            &mut cache.cross_attn,
+        )?;
+        let output = add(&output, &attended);
+
+        let normed = self.norm3.forward(&output)?;
+        let fed = self.feed_forward.forward(&normed)?;
+        Ok(add(&output, &fed))
+    }
+
+    /// Returns the gradients with respect to the target input and the
+    /// encoder memory
+    pub fn backward(&mut self, grad: &Tensor) -> Result<(Tensor, Tensor), BellandeError> {
+        let grad_fed = Layer::backward(&mut self.dropout3, grad)?;
+        let grad_normed = self.feed_forward.backward(&grad_fed)?;
+        let grad = add(grad, &Layer::backward(&mut self.norm3, &grad_normed)?);
+
+        let grad_attended = Layer::backward(&mut self.dropout2, &grad)?;
+        let (grad_q, grad_k, grad_v) = self.cross_attn.backward(&grad_attended)?;
+        let grad_memory = add(&grad_k, &grad_v);
+        let grad = add(&grad, &Layer::backward(&mut self.norm2, &grad_q)?);
+
+        let grad_attended = Layer::backward(&mut self.dropout1, &grad)?;
+        let (grad_q, grad_k, grad_v) = self.self_attn.backward(&grad_attended)?;
+        let grad_normed = add(&add(&grad_q, &grad_k), &grad_v);
+        let grad = add(&grad, &Layer::backward(&mut self.norm1, &grad_normed)?);
+        Ok((grad, grad_memory))
+    }
+
+    pub fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = prefixed("self_attn", self.self_attn.named_parameters());
+        params.extend(prefixed("cross_attn", self.cross_attn.named_parameters()));
+        params.extend(prefixed(
+            "feed_forward",
+            self.feed_forward.named_parameters(),
+        ));
+        params.extend(prefixed("norm1", self.norm1.named_parameters()));
+        params.extend(prefixed("norm2", self.norm2.named_parameters()));
+        params.extend(prefixed("norm3", self.norm3.named_parameters()));
+        params
+    }
+
+    pub fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name.split_once('.') {
+            Some(("self_attn", rest)) => self.self_attn.set_parameter(rest, value),
+            Some(("cross_attn", rest)) => self.cross_attn.set_parameter(rest, value),
+            Some(("feed_forward", rest)) => self.feed_forward.set_parameter(rest, value),
+            Some(("norm1", rest)) => self.norm1.set_parameter(rest, value),
+            Some(("norm2", rest)) => self.norm2.set_parameter(rest, value),
+            Some(("norm3", rest)) => self.norm3.set_parameter(rest, value),
+            _ => Err(unknown_parameter(name)),
+        }
+    }
+
+    pub fn train(&mut self) {
+        self.self_attn.train();
+        self.cross_attn.train();
+        self.dropout1.train();
+        self.dropout2.train();
+        self.dropout3.train();
+    }
+
+    pub fn eval(&mut self) {
+        self.self_attn.eval();
+        self.cross_attn.eval();
+        self.dropout1.eval();
+        self.dropout2.eval();
+        self.dropout3.eval();
+    }
+}
+
+/// Stack of encoder layers followed by a final layer normalization
+pub struct TransformerEncoder {
+    layers: Vec<TransformerEncoderLayer>,
+    norm: LayerNorm,
+}
+
+impl TransformerEncoder {
+    pub fn new(
+        num_layers: usize,
+        embed_dim: usize,
+        num_heads: usize,
+        ff_dim: usize,
+        dropout: f32,
+    ) -> Self {
+        let layers = (0..num_layers)
+            .map(|_| TransformerEncoderLayer::new(embed_dim, num_heads, ff_dim, dropout))
+            .collect();
+        Self::from_layers(layers, embed_dim)
+    }
+
+    /// Stacks custom layers, e.g. ones using rotary embeddings
+    pub fn from_layers(layers: Vec<TransformerEncoderLayer>, embed_dim: usize) -> Self {
+        TransformerEncoder {
+            layers,
+            norm: LayerNorm::new(vec![embed_dim], numerics::numerics().norm_eps, true),
+        }
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn forward(
+        &mut self,
+        src: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor, BellandeError> {
+        let mut output = src.clone();
+        for layer in &mut self.layers {
+            output = layer.forward(&output, mask)?;
+        }
+        self.norm.forward(&output)
+    }
+
+    pub fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let mut grad = Layer::backward(&mut self.norm, grad)?;
+        for layer in self.layers.iter_mut().rev() {
+            grad = layer.backward(&grad)?;
+        }
+        Ok(grad)
+    }
+
+    /// Parameters named `layers.{i}.*` and `norm.*`
+    pub fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = Vec::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            params.extend(prefixed(&format!("layers.{}", i), layer.named_parameters()));
+        }
+        params.extend(prefixed("norm", self.norm.named_parameters()));
+        params
+    }
+
+    pub fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        if let Some(rest) = name.strip_prefix("norm.") {
+            return self.norm.set_parameter(rest, value);
+        }
+        let (index, rest) = name
+            .strip_prefix("layers.")
+            .and_then(|rest| rest.split_once('.'))
+            .ok_or_else(|| unknown_parameter(name))?;
+        let layer = index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| self.layers.get_mut(index))
+            .ok_or_else(|| unknown_parameter(name))?;
+        layer.set_parameter(rest, value)
+    }
+
+    pub fn train(&mut self) {
+        self.layers.iter_mut().for_each(|layer| layer.train());
+    }
+
+    pub fn eval(&mut self) {
+        self.layers.iter_mut().for_each(|layer| layer.eval());
+    }
+}
+
+/// Stack of decoder layers followed by a final layer normalization
+pub struct TransformerDecoder {
+    layers: Vec<TransformerDecoderLayer>,
+    norm: LayerNorm,
+}
+
+impl TransformerDecoder {
+    pub fn new(
+        num_layers: usize,
+        embed_dim: usize,
+        num_heads: usize,
+        ff_dim: usize,
+        dropout: f32,
+    ) -> Self {
+        let layers = (0..num_layers)
+            .map(|_| TransformerDecoderLayer::new(embed_dim, num_heads, ff_dim, dropout))
+            .collect();
+        Self::from_layers(layers, embed_dim)
+    }
+
+    /// Stacks custom layers, e.g. ones using rotary embeddings
+    pub fn from_layers(layers: Vec<TransformerDecoderLayer>, embed_dim: usize) -> Self {
+        TransformerDecoder {
+            layers,
+            norm: LayerNorm::new(vec![embed_dim], numerics::numerics().norm_eps, true),
+        }
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn forward(
+        &mut self,
+        tgt: &Tensor,
+        memory: &Tensor,
+        tgt_mask: Option<&Tensor>,
+        memory_mask: Option<&Tensor>,
+    ) -> Result<Tensor, BellandeError> {
+        let mut output = tgt.clone();
+        for layer in &mut self.layers {
+            output = layer.forward(&output, memory, tgt_mask, memory_mask)?;
+        }
+        self.norm.forward(&output)
+    }
+
+    /// Empty caches for `forward_incremental`, one per layer
+    pub fn new_cache(&self) -> DecoderCache {
+        DecoderCache {
+            layers: self
+                .layers
+                .iter()
+                .map(|_| DecoderLayerCache::default())
+                .collect(),
+        }
+    }
+
+    /// Decodes only the new target positions, continuing from the
+    /// positions already in `cache`. See
+    /// `TransformerDecoderLayer::forward_incremental`.
+    pub fn forward_incremental(
+        &mut self,
+        tgt: &Tensor,
+        memory: &Tensor,
+        memory_mask: Option<&Tensor>,
+        cache: &mut DecoderCache,
+    ) -> Result<Tensor, BellandeError> {
+        if cache.layers.len() != self.layers.len() {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Cache has {} layers, the decoder {}",
+                cache.layers.len(),
+                self.layers.len()
+            )));
+        }
+        let mut output = tgt.clone();
+        for (layer, layer_cache) in self.layers.iter_mut().zip(&mut cache.layers) {
+            output = layer.forward_incremental(&output, memory, memory_mask, layer_cache)?;
+        }
+        self.norm.forward(&output)
+    }
+
+    /// Returns the gradients with respect to the target input and the
+    /// encoder memory, summed over layers
+    pub fn backward(&mut self, grad: &Tensor) -> Result<(Tensor, Tensor), BellandeError> {
+        let mut grad = Layer::backward(&mut self.norm, grad)?;
+        let mut grad_memory: Option<Tensor> = None;
+        for layer in self.layers.iter_mut().rev() {
+            let (grad_tgt, grad_mem) = layer.backward(&grad)?;
+            grad = grad_tgt;
+            grad_memory = Some(match grad_memory {
+                Some(total) => add(&total, &grad_mem),
+                None => grad_mem,
+            });
+        }
+        let grad_memory = grad_memory
+            .ok_or_else(|| BellandeError::InvalidConfiguration("Decoder has no layers".into()))?;
+        Ok((grad, grad_memory))
+    }
+
+    /// Parameters named `layers.{i}.*` and `norm.*`
+    pub fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = Vec::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            params.extend(prefixed(&format!("layers.{}", i), layer.named_parameters()));
+        }
+        params.extend(prefixed("norm", self.norm.named_parameters()));
+        params
+    }
+
+    pub fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        if let Some(rest) = name.strip_prefix("norm.") {
+            return self.norm.set_parameter(rest, value);
+        }
+        let (index, rest) = name
+            .strip_prefix("layers.")
+            .and_then(|rest| rest.split_once('.'))
+            .ok_or_else(|| unknown_parameter(name))?;
+        let layer = index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| self.layers.get_mut(index))
+            .ok_or_else(|| unknown_parameter(name))?;
+        layer.set_parameter(rest, value)
+    }
+
+    pub fn train(&mut self) {
+        self.layers.iter_mut().for_each(|layer| layer.train());
+    }
+
+    pub fn eval(&mut self) {
+        self.layers.iter_mut().for_each(|layer| layer.eval());
+    }
+}
+
+/// Unmasked self-attention, so attention can be used inside `Sequential`
+/// and checked with `testing::gradcheck`
+impl Layer for MultiHeadAttention {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        MultiHeadAttention::forward(self, input, input, input, None)
+    }
+
+    /// Sums the query, key and value gradients, which all flow to the input
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_query, grad_key, grad_value) = MultiHeadAttention::backward(self, grad)?;
+        Ok(add(&add(&grad_query, &grad_key), &grad_value))
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        MultiHeadAttention::named_parameters(self)
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        MultiHeadAttention::set_parameter(self, name, value)
+    }
+
+    fn train(&mut self) {
+        MultiHeadAttention::train(self);
+    }
+
+    fn eval(&mut self) {
+        MultiHeadAttention::eval(self);
+    }
+}
+
+/// Encoder layer without an attention mask
+impl Layer for TransformerEncoderLayer {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        TransformerEncoderLayer::forward(self, input, None)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        TransformerEncoderLayer::backward(self, grad)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        TransformerEncoderLayer::named_parameters(self)
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        TransformerEncoderLayer::set_parameter(self, name, value)
+    }
+
+    fn train(&mut self) {
+        TransformerEncoderLayer::train(self);
+    }
+
+    fn eval(&mut self) {
+        TransformerEncoderLayer::eval(self);
+    }
+}
+
+/// Encoder stack without an attention mask
+impl Layer for TransformerEncoder {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        TransformerEncoder::forward(self, input, None)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        TransformerEncoder::backward(self, grad)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        TransformerEncoder::named_parameters(self)
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        TransformerEncoder::set_parameter(self, name, value)
+    }
+
+    fn train(&mut self) {
+        TransformerEncoder::train(self);
+    }
+
+    fn eval(&mut self) {
+        TransformerEncoder::eval(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{device::Device, dtype::DataType, random};
+    use crate::testing::gradcheck::{assert_layer_gradients, GradCheckConfig};
+
+    fn random_tensor(shape: &[usize]) -> Tensor {
+        Tensor::new(
+            random::normal(0.0, 1.0, shape.iter().product()),
+            shape.to_vec(),
+            false,
+            Device::CPU,
+            DataType::Float32,
+        )
+    }
+
+    /// Small steps keep the finite differences clear of the ReLU kinks
+    fn config() -> GradCheckConfig {
+        GradCheckConfig::new()
+            .with_epsilon(1e-3)
+            .with_tolerance(2e-2)
+    }
+
+    #[test]
+    fn attention_backward_matches_numerical_gradients() {
+        random::set_seed(7);
+        let mut attention = MultiHeadAttention::new(8, 2, 0.0);
+        Layer::eval(&mut attention);
+        let input = random_tensor(&[2, 3, 8]);
+        assert_layer_gradients(&mut attention, &input, &config()).unwrap();
+    }
+
+    #[test]
+    fn encoder_layer_backward_matches_numerical_gradients() {
+        random::set_seed(11);
+        let mut encoder = TransformerEncoderLayer::new(8, 2, 16, 0.0);
+        Layer::eval(&mut encoder);
+        let input = random_tensor(&[2, 3, 8]);
+        assert_layer_gradients(&mut encoder, &input, &config()).unwrap();
+    }
+
+    /// Decoder layer with one of its inputs held fixed, so the gradient
+    /// check covers the other
+    struct DecoderInput {
+        decoder: TransformerDecoderLayer,
+        fixed: Tensor,
+        /// Whether the checked input is the memory rather than the target
+        memory: bool,
+    }
+
+    impl Layer for DecoderInput {
+        fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+            if self.memory {
+                self.decoder.forward(&self.fixed, input, None, None)
+            } else {
+                self.decoder.forward(input, &self.fixed, None, None)
+            }
+        }
+
+        fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+            let (grad_tgt, grad_memory) = self.decoder.backward(grad)?;
+            Ok(if self.memory { grad_memory } else { grad_tgt })
+        }
+
+        fn named_parameters(&self) -> Vec<(String, Tensor)> {
+            self.decoder.named_parameters()
+        }
+
+        fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+            self.decoder.set_parameter(name, value)
+        }
+    }
+
+    fn decoder_input(memory: bool) -> DecoderInput {
+        let mut decoder = TransformerDecoderLayer::new(8, 2, 16, 0.0);
+        decoder.eval();
+        // A memory longer than the target, so mixed-up sequence axes show up
+        let fixed = if memory {
+            random_tensor(&[2, 3, 8])
+        } else {
+            random_tensor(&[2, 4, 8])
+        };
+        DecoderInput {
+            decoder,
+            fixed,
+            memory,
+        }
+    }
+
+    #[test]
+    fn decoder_layer_backward_matches_numerical_gradients() {
+        random::set_seed(13);
+        let mut decoder = decoder_input(false);
+        let tgt = random_tensor(&[2, 3, 8]);
+        assert_layer_gradients(&mut decoder, &tgt, &config()).unwrap();
+    }
+
+    #[test]
+    fn decoder_layer_memory_gradient_matches_numerical_gradients() {
+        random::set_seed(17);
+        let mut decoder = decoder_input(true);
+        let memory = random_tensor(&[2, 4, 8]);
+        assert_layer_gradients(&mut decoder, &memory, &config()).unwrap();
     }
 }
@@ -0,0 +1,246 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{ChannelRole, Layer};
+
+/// How output pixels are computed from the input grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpolationMode {
+    /// Copies the input pixel at `floor(out * in_size / out_size)`
+    Nearest,
+    /// Linear interpolation between the four nearest input pixels
+    Bilinear,
+}
+
+/// Target spatial size of an interpolation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputSize {
+    /// Explicit (height, width)
+    Size(usize, usize),
+    /// (height, width) multipliers; the output size is rounded down
+    ScaleFactor(f32, f32),
+}
+
+impl OutputSize {
+    fn resolve(&self, height: usize, width: usize) -> Result<(usize, usize), BellandeError> {
+        let (out_h, out_w) = match *self {
+            OutputSize::Size(h, w) => (h, w),
+            OutputSize::ScaleFactor(sh, sw) => {
+                if !(sh > 0.0 && sw > 0.0) {
+                    return Err(BellandeError::InvalidParameter(format!(
+                        "Scale factors must be positive, got ({}, {})",
+                        sh, sw
+                    )));
+                }
+                (
+                    (height as f32 * sh).floor() as usize,
+                    (width as f32 * sw).floor() as usize,
+                )
+            }
+        };
+        if out_h == 0 || out_w == 0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Interpolated size ({}, {}) must be positive",
+                out_h, out_w
+            )));
+        }
+        Ok((out_h, out_w))
+    }
+}
+
+/// Input positions and weights contributing to one output coordinate
+#[derive(Clone, Copy)]
+struct Tap {
+    low: usize,
+    high: usize,
+    high_weight: f32,
+}
+
+/// Interpolation taps along one axis
+fn taps(in_size: usize, out_size: usize, mode: InterpolationMode, align_corners: bool) -> Vec<Tap> {
+    let scale = in_size as f32 / out_size as f32;
+    (0..out_size)
+        .map(|o| match mode {
+            InterpolationMode::Nearest => {
+                let i = ((o as f32 * scale).floor() as usize).min(in_size - 1);
+                Tap {
+                    low: i,
+                    high: i,
+                    high_weight: 0.0,
+                }
+            }
+            InterpolationMode::Bilinear => {
+                let source = if align_corners {
+                    if out_size > 1 {
+                        o as f32 * (in_size - 1) as f32 / (out_size - 1) as f32
+                    } else {
+                        0.0
+                    }
+                } else {
+                    ((o as f32 + 0.5) * scale - 0.5).max(0.0)
+                };
+                let low = (source.floor() as usize).min(in_size - 1);
+                Tap {
+                    low,
+                    high: (low + 1).min(in_size - 1),
+                    high_weight: source - low as f32,
+                }
+            }
+        })
+        .collect()
+}
+
+/// Resizes (batch_size, channels, height, width) feature maps
+pub fn interpolate(
+    input: &Tensor,
+    size: OutputSize,
+    mode: InterpolationMode,
+    align_corners: bool,
+) -> Result<Tensor, BellandeError> {
+    let (planes, height, width) = dimensions(&input.shape)?;
+    let (out_h, out_w) = size.resolve(height, width)?;
+    let rows = taps(height, out_h, mode, align_corners);
+    let cols = taps(width, out_w, mode, align_corners);
+
+    let mut output = Vec::with_capacity(planes * out_h * out_w);
+    for plane in input.data.chunks(height * width) {
+        for row in &rows {
+            for col in &cols {
+                let at = |h: usize, w: usize| plane[h * width + w];
+                let top = at(row.low, col.low) * (1.0 - col.high_weight)
+                    + at(row.low, col.high) * col.high_weight;
+                let bottom = at(row.high, col.low) * (1.0 - col.high_weight)
+                    + at(row.high, col.high) * col.high_weight;
+                output.push(top * (1.0 - row.high_weight) + bottom * row.high_weight);
+            }
+        }
+    }
+
+    let mut shape = input.shape.clone();
+    shape[2] = out_h;
+    shape[3] = out_w;
+    Ok(Tensor::new(
+        output,
+        shape,
+        input.requires_grad,
+        input.device.clone(),
+        input.dtype,
+    ))
+}
+
+/// Gradient of `interpolate` with respect to an input of `input_shape`
+pub fn interpolate_backward(
+    grad_output: &Tensor,
+    input_shape: &[usize],
+    mode: InterpolationMode,
+    align_corners: bool,
+) -> Result<Tensor, BellandeError> {
+    let (planes, height, width) = dimensions(input_shape)?;
+    let (_, out_h, out_w) = dimensions(&grad_output.shape)?;
+    if grad_output.data.len() != planes * out_h * out_w {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Output gradient of shape {:?} does not match input shape {:?}",
+            grad_output.shape, input_shape
+        )));
+    }
+    let rows = taps(height, out_h, mode, align_corners);
+    let cols = taps(width, out_w, mode, align_corners);
+
+    let mut grad_input = vec![0.0; planes * height * width];
+    for (plane, grad_plane) in grad_input
+        .chunks_mut(height * width)
+        .zip(grad_output.data.chunks(out_h * out_w))
+    {
+        let mut grads = grad_plane.iter();
+        for row in &rows {
+            for col in &cols {
+                let g = grads.next().copied().unwrap_or(0.0);
+                let (gt, gb) = (g * (1.0 - row.high_weight), g * row.high_weight);
+                plane[row.low * width + col.low] += gt * (1.0 - col.high_weight);
+                plane[row.low * width + col.high] += gt * col.high_weight;
+                plane[row.high * width + col.low] += gb * (1.0 - col.high_weight);
+                plane[row.high * width + col.high] += gb * col.high_weight;
+            }
+        }
+    }
+
+    Ok(Tensor::new(
+        grad_input,
+        input_shape.to_vec(),
+        true,
+        grad_output.device.clone(),
+        grad_output.dtype,
+    ))
+}
+
+/// Returns (batch * channels, height, width) of a 4D shape
+fn dimensions(shape: &[usize]) -> Result<(usize, usize, usize), BellandeError> {
+    match *shape {
+        [batch_size, channels, height, width] if height > 0 && width > 0 => {
+            Ok((batch_size * channels, height, width))
+        }
+        _ => Err(BellandeError::InvalidShape(format!(
+            "Expected 4D tensor (batch_size, channels, height, width), got {:?}",
+            shape
+        ))),
+    }
+}
+
+/// Layer wrapper around `interpolate`, e.g. for segmentation decoders
+pub struct Upsample {
+    size: OutputSize,
+    mode: InterpolationMode,
+    align_corners: bool,
+    input_shape: Option<Vec<usize>>,
+}
+
+impl Upsample {
+    pub fn new(size: OutputSize, mode: InterpolationMode) -> Self {
+        Upsample {
+            size,
+            mode,
+            align_corners: false,
+            input_shape: None,
+        }
+    }
+
+    /// Aligns the corner pixels of input and output in bilinear mode
+    /// instead of their pixel areas
+    pub fn with_align_corners(mut self, align_corners: bool) -> Self {
+        self.align_corners = align_corners;
+        self
+    }
+}
+
+impl Layer for Upsample {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let output = interpolate(input, self.size, self.mode, self.align_corners)?;
+        self.input_shape = Some(input.shape.clone());
+        Ok(output)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let input_shape = self
+            .input_shape
+            .as_ref()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        interpolate_backward(grad, input_shape, self.mode, self.align_corners)
+    }
+
+    fn channel_role(&self) -> ChannelRole {
+        ChannelRole::PassThrough
+    }
+}
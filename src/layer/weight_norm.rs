@@ -0,0 +1,225 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, tensor::Tensor};
+use crate::layer::{accumulate_grad, assign_parameter, parameter, unknown_parameter, Layer};
+
+/// Weight normalization: reparameterizes the `weight` of a wrapped layer as
+/// `g * v / ||v||`, with one magnitude `g` per output unit, decoupling the
+/// length of each weight vector from its direction.
+///
+/// The wrapped layer exposes `weight_g` and `weight_v` in place of `weight`.
+pub struct WeightNorm<L: Layer> {
+    inner: L,
+    weight_g: Tensor,
+    weight_v: Tensor,
+    norms: Vec<f32>,
+}
+
+/// Wraps a layer with a `weight` of rank 2 or more, such as `Linear` or
+/// `Conv2d`, in weight normalization. The initial weight is unchanged.
+pub fn weight_norm<L: Layer>(layer: L) -> Result<WeightNorm<L>, BellandeError> {
+    let weight = layer_weight(&layer)?;
+    let rows = weight.shape[0];
+    let row_len = weight.data.len() / rows.max(1);
+    let norms = row_norms(&weight.data, row_len);
+
+    let mut weight_g = Tensor::zeros(&[rows]);
+    weight_g.data = norms.clone();
+    // Own gradient buffers, apart from the wrapped layer's weight
+    let weight_g = parameter(weight_g);
+    let weight_v = parameter(weight.detach());
+
+    Ok(WeightNorm {
+        inner: layer,
+        weight_g,
+        weight_v,
+        norms,
+    })
+}
+
+impl<L: Layer> WeightNorm<L> {
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    /// Unwraps the layer, leaving it with the current effective weight
+    pub fn into_inner(mut self) -> Result<L, BellandeError> {
+        self.update_weight()?;
+        Ok(self.inner)
+    }
+
+    /// Writes `g * v / ||v||` into the wrapped layer
+    fn update_weight(&mut self) -> Result<(), BellandeError> {
+        let row_len = self.row_len();
+        self.norms = row_norms(&self.weight_v.data, row_len);
+
+        let mut weight = self.weight_v.clone();
+        weight.grad = None;
+        for ((row, &g), &norm) in weight
+            .data
+            .chunks_mut(row_len)
+            .zip(&self.weight_g.data)
+            .zip(&self.norms)
+        {
+            row.iter_mut().for_each(|w| *w *= g / norm);
+        }
+        self.inner.set_parameter("weight", weight)
+    }
+
+    fn row_len(&self) -> usize {
+        self.weight_v.data.len() / self.weight_g.data.len().max(1)
+    }
+}
+
+impl<L: Layer> Layer for WeightNorm<L> {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        self.update_weight()?;
+        self.inner.forward(input)
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let (grad_input, grad_weight) = backward_weight(&mut self.inner, grad)?;
+
+        let row_len = self.row_len();
+        let mut grad_g = vec![0.0; self.weight_g.data.len()];
+        let mut grad_v = vec![0.0; self.weight_v.data.len()];
+        for (o, (gw, v)) in grad_weight
+            .chunks(row_len)
+            .zip(self.weight_v.data.chunks(row_len))
+            .enumerate()
+        {
+            let (g, norm) = (self.weight_g.data[o], self.norms[o]);
+            let dot: f32 = gw.iter().zip(v).map(|(a, b)| a * b).sum();
+            grad_g[o] = dot / norm;
+            for ((out, &gw), &v) in grad_v[o * row_len..(o + 1) * row_len]
+                .iter_mut()
+                .zip(gw)
+                .zip(v)
+            {
+                *out = g / norm * gw - g * grad_g[o] / (norm * norm) * v;
+            }
+        }
+
+        let mut grad_g_tensor = Tensor::zeros(&self.weight_g.shape);
+        grad_g_tensor.data = grad_g;
+        let mut grad_v_tensor = Tensor::zeros(&self.weight_v.shape);
+        grad_v_tensor.data = grad_v;
+        accumulate_grad(&mut self.weight_g, &grad_g_tensor)?;
+        accumulate_grad(&mut self.weight_v, &grad_v_tensor)?;
+        Ok(grad_input)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        let mut params = vec![self.weight_g.clone(), self.weight_v.clone()];
+        params.extend(parameters_without_weight(&self.inner));
+        params
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut params = vec![
+            ("weight_g".to_string(), self.weight_g.clone()),
+            ("weight_v".to_string(), self.weight_v.clone()),
+        ];
+        params.extend(
+            self.inner
+                .named_parameters()
+                .into_iter()
+                .filter(|(name, _)| name != "weight"),
+        );
+        params
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        match name {
+            "weight_g" => assign_parameter(&mut self.weight_g, name, value),
+            "weight_v" => assign_parameter(&mut self.weight_v, name, value),
+            "weight" => Err(unknown_parameter(name)),
+            _ => self.inner.set_parameter(name, value),
+        }
+    }
+
+    fn train(&mut self) {
+        self.inner.train()
+    }
+
+    fn eval(&mut self) {
+        self.inner.eval()
+    }
+
+    fn set_dropout_active(&mut self, active: bool) {
+        self.inner.set_dropout_active(active)
+    }
+}
+
+/// The `weight` of a layer, which must have rank 2 or more
+pub(super) fn layer_weight<L: Layer>(layer: &L) -> Result<Tensor, BellandeError> {
+    let weight = layer
+        .named_parameters()
+        .into_iter()
+        .find(|(name, _)| name == "weight")
+        .map(|(_, weight)| weight)
+        .ok_or_else(|| BellandeError::InvalidParameter("Layer has no 'weight' parameter".into()))?;
+    if weight.shape.len() < 2 || weight.data.is_empty() {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected a weight of rank 2 or more, got {:?}",
+            weight.shape
+        )));
+    }
+    Ok(weight)
+}
+
+/// Trainable parameters of a wrapped layer other than its `weight`
+pub(super) fn parameters_without_weight<L: Layer>(layer: &L) -> Vec<Tensor> {
+    let weight = layer_weight(layer).ok();
+    layer
+        .parameters()
+        .into_iter()
+        .filter(
+            |param| !matches!(&weight, Some(w) if w.shape == param.shape && w.data == param.data),
+        )
+        .collect()
+}
+
+/// Runs the wrapped layer's backward pass, returning the input gradient and
+/// the gradient of this call with respect to its effective `weight`. The
+/// layer accumulates into its own weight gradient, so the value from before
+/// the call is subtracted.
+pub(super) fn backward_weight<L: Layer>(
+    layer: &mut L,
+    grad: &Tensor,
+) -> Result<(Tensor, Vec<f32>), BellandeError> {
+    let before = layer_weight(layer)?.grad();
+    let grad_input = layer.backward(grad)?;
+    let weight = layer_weight(layer)?;
+    let mut grad_weight = weight
+        .grad()
+        .map_or_else(|| vec![0.0; weight.data.len()], |grad| grad.data);
+    if let Some(before) = before {
+        grad_weight
+            .iter_mut()
+            .zip(before.data)
+            .for_each(|(g, b)| *g -= b);
+    }
+    Ok((grad_input, grad_weight))
+}
+
+/// L2 norm of each row of `row_len` values, kept away from zero
+fn row_norms(data: &[f32], row_len: usize) -> Vec<f32> {
+    let eps = numerics().denom_eps;
+    data.chunks(row_len.max(1))
+        .map(|row| row.iter().map(|x| x * x).sum::<f32>().sqrt().max(eps))
+        .collect()
+}
@@ -13,7 +13,13 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::core::{
+    error::BellandeError,
+    numerics::{numerics, safe_ln},
+    tensor::Tensor,
+};
+use crate::loss::utils::{apply_sample_weights, expand_sample_weights, grad_scale, reduce};
+use crate::loss::{Loss, Reduction, WeightedLoss};
 use std::f32;
 
 #[derive(Debug, Clone, Copy)]
@@ -25,13 +31,6 @@ pub enum ReductionType {
     Product,
 }
 
-#[derive(Clone, Copy)]
-pub enum Reduction {
-    None,
-    Mean,
-    Sum,
-}
-
 pub trait ReductionOps {
     fn reduce(&self, input: &Tensor) -> Result<Tensor, BellandeError>;
     fn reduce_backward(&self, grad_output: &Tensor) -> Result<Tensor, BellandeError>;
@@ -45,6 +44,9 @@ pub struct ReductionOperation {
     input_cache: Option<ReductionCache>,
 }
 
+/// Binary cross-entropy on probabilities. The optional `weight` rescales
+/// each element and is broadcast over the trailing dimension, so it holds
+/// either a single value or one value per column.
 pub struct BCELoss {
     reduction: Reduction,
     weight: Option<Tensor>,
@@ -62,49 +64,130 @@ impl BCELoss {
         BCELoss {
             reduction,
             weight,
-            eps: 1e-8,
+            eps: numerics().log_eps,
         }
     }
 
-    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+    fn check(&self, prediction: &Tensor, target: &Tensor) -> Result<(), BellandeError> {
         if prediction.shape != target.shape {
             return Err(BellandeError::DimensionMismatch);
         }
-
-        let mut loss = Vec::with_capacity(prediction.data.len());
-        for (pred, tgt) in prediction.data.iter().zip(target.data.iter()) {
-            let p = pred.clamp(self.eps, 1.0 - self.eps);
-            let l = -tgt * p.ln() - (1.0 - tgt) * (1.0 - p).ln();
-            if let Some(ref weight) = self.weight {
-                loss.push(l * weight.data[0]);
-            } else {
-                loss.push(l);
+        if let Some(weight) = &self.weight {
+            let len = weight.data.len();
+            if len == 0 || prediction.data.len() % len != 0 {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Got {} weights for {} elements",
+                    len,
+                    prediction.data.len()
+                )));
             }
         }
+        Ok(())
+    }
 
-        match self.reduction {
-            Reduction::None => Ok(Tensor::new(
-                loss,
-                prediction.shape.clone(),
-                true,
-                prediction.device.clone(),
-                prediction.dtype,
-            )),
-            Reduction::Mean => Ok(Tensor::new(
-                vec![loss.iter().sum::<f32>() / loss.len() as f32],
-                vec![1],
-                true,
-                prediction.device.clone(),
-                prediction.dtype,
-            )),
-            Reduction::Sum => Ok(Tensor::new(
-                vec![loss.iter().sum()],
-                vec![1],
-                true,
-                prediction.device.clone(),
-                prediction.dtype,
-            )),
-        }
+    /// Rescaling weight of the element at flat index `index`
+    fn weight_at(&self, index: usize) -> f32 {
+        self.weight
+            .as_ref()
+            .map_or(1.0, |weight| weight.data[index % weight.data.len()])
+    }
+
+    /// Unreduced element-wise losses
+    fn losses(&self, prediction: &Tensor, target: &Tensor) -> Result<Vec<f32>, BellandeError> {
+        self.check(prediction, target)?;
+        Ok(prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .enumerate()
+            .map(|(i, (pred, tgt))| {
+                // Clamp both probabilities instead of `pred`, as 1 - eps
+                // rounds to 1 for small eps
+                let l =
+                    -tgt * safe_ln(*pred, self.eps) - (1.0 - tgt) * safe_ln(1.0 - pred, self.eps);
+                l * self.weight_at(i)
+            })
+            .collect())
+    }
+}
+
+impl Default for BCELoss {
+    fn default() -> Self {
+        BCELoss::new(Reduction::Mean, None)
+    }
+}
+
+impl Loss for BCELoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let losses = self.losses(output, target)?;
+        Ok(reduce(losses, output.shape.clone(), self.reduction, output))
+    }
+
+    /// Gradient `-t / p + (1 - t) / (1 - p)` times the element weight. A
+    /// term whose probability was clamped to `eps` contributes nothing.
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        self.check(output, target)?;
+        let scale = grad_scale(self.reduction, output.data.len());
+        let grad = output
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .enumerate()
+            .map(|(i, (&p, &t))| {
+                let mut g = 0.0;
+                if p > self.eps {
+                    g -= t / p;
+                }
+                if 1.0 - p > self.eps {
+                    g += (1.0 - t) / (1.0 - p);
+                }
+                g * self.weight_at(i) * scale
+            })
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            output.shape.clone(),
+            true,
+            output.device.clone(),
+            output.dtype,
+        ))
+    }
+
+    fn name(&self) -> &str {
+        "BCELoss"
+    }
+
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Multiplies every element of a sample by the sample's weight on top of
+/// the element weights
+impl WeightedLoss for BCELoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let mut losses = self.losses(output, target)?;
+        let weights = expand_sample_weights(weights, losses.len())?;
+        losses.iter_mut().zip(weights).for_each(|(l, w)| *l *= w);
+        Ok(reduce(losses, output.shape.clone(), self.reduction, output))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(self.backward(output, target)?, weights)
     }
 }
 
@@ -14,216 +14,319 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
-use crate::loss::bce::Reduction;
+use crate::layer::activation::log_softmax;
+use crate::loss::utils::{apply_sample_weights, expand_sample_weights, reduce};
+use crate::loss::{Loss, Reduction, WeightedLoss};
 
-/// Cross Entropy Loss implementation with support for class weights and ignored indices
+/// Cross Entropy Loss implementation with support for class weights, ignored
+/// indices and label smoothing
 pub struct CrossEntropyLoss {
     reduction: Reduction,
     weight: Option<Tensor>,
     ignore_index: Option<i64>,
+    label_smoothing: f32,
 }
 
-impl CrossEntropyLoss {
-    /// Creates a new CrossEntropyLoss with the specified parameters
-    pub fn new(reduction: Reduction, weight: Option<Tensor>, ignore_index: Option<i64>) -> Self {
-        CrossEntropyLoss {
-            reduction,
-            weight,
-            ignore_index,
-        }
-    }
+/// Per-sample terms shared by the cross entropy and NLL losses
+pub(super) struct NllTerms {
+    /// Target class of each sample, `None` when ignored
+    pub(super) targets: Vec<Option<usize>>,
+    /// Class weight of each sample's target, 0 when ignored
+    pub(super) weights: Vec<f32>,
+    label_smoothing: f32,
+    /// `label_smoothing / num_classes` times each class weight, empty
+    /// without label smoothing
+    smoothing_weights: Vec<f32>,
+}
 
-    /// Creates a new CrossEntropyLoss with default parameters
-    pub fn default() -> Self {
-        CrossEntropyLoss {
-            reduction: Reduction::Mean,
-            weight: None,
-            ignore_index: None,
+impl NllTerms {
+    pub(super) fn new(
+        prediction: &Tensor,
+        target: &Tensor,
+        weight: Option<&Tensor>,
+        ignore_index: Option<i64>,
+        label_smoothing: f32,
+    ) -> Result<Self, BellandeError> {
+        if prediction.shape.len() != 2 {
+            return Err(BellandeError::InvalidShape(
+                "Prediction tensor must be 2-dimensional (batch_size, num_classes)".into(),
+            ));
         }
-    }
-
-    /// Forward pass of the Cross Entropy Loss calculation
-    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
-        // Validate input shapes
-        self.validate_input(prediction, target)?;
-
-        // Get the number of classes (from the prediction shape)
-        let num_classes = prediction.shape()[1];
-
-        // Apply log softmax to predictions
-        let log_softmax = self.compute_log_softmax(prediction)?;
-
-        // Convert target to one-hot encoding if necessary
-        let target_one_hot = self.convert_to_one_hot(target, num_classes)?;
-
-        // Compute the negative log likelihood
-        let mut loss = self.compute_nll_loss(&log_softmax, &target_one_hot)?;
-
-        // Apply class weights if provided
-        if let Some(weight) = &self.weight {
-            loss = self.apply_class_weights(&loss, weight)?;
+        let (batch_size, num_classes) = (prediction.shape[0], prediction.shape[1]);
+        if target.shape.len() != 1 || target.shape[0] != batch_size {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Target of shape {:?} must hold one class index per sample of a batch of {}",
+                target.shape, batch_size
+            )));
+        }
+        if let Some(weight) = weight {
+            if weight.data.len() != num_classes {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Got {} class weights for {} classes",
+                    weight.data.len(),
+                    num_classes
+                )));
+            }
         }
 
-        // Apply ignore index masking if specified
-        if let Some(ignore_idx) = self.ignore_index {
-            loss = self.apply_ignore_mask(&loss, target, ignore_idx)?;
+        let mut targets = Vec::with_capacity(batch_size);
+        let mut weights = Vec::with_capacity(batch_size);
+        for &value in &target.data {
+            let class = value.round() as i64;
+            if Some(class) == ignore_index {
+                targets.push(None);
+                weights.push(0.0);
+                continue;
+            }
+            if class < 0 || class as usize >= num_classes {
+                return Err(BellandeError::InvalidParameter(format!(
+                    "Target class {} is out of range (0, {})",
+                    class,
+                    num_classes - 1
+                )));
+            }
+            targets.push(Some(class as usize));
+            weights.push(weight.map_or(1.0, |w| w.data[class as usize]));
         }
 
-        // Apply reduction
-        self.apply_reduction(&loss)
+        let smoothing_weights = if label_smoothing > 0.0 {
+            let uniform = label_smoothing / num_classes as f32;
+            (0..num_classes)
+                .map(|class| uniform * weight.map_or(1.0, |w| w.data[class]))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(NllTerms {
+            targets,
+            weights,
+            label_smoothing,
+            smoothing_weights,
+        })
     }
 
-    /// Backward pass of the Cross Entropy Loss calculation
-    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
-        // Get softmax probabilities
-        let softmax = self.compute_softmax(prediction)?;
-
-        // Convert target to one-hot encoding
-        let num_classes = prediction.shape()[1];
-        let target_one_hot = self.convert_to_one_hot(target, num_classes)?;
-
-        // Compute gradients: softmax - target
-        let mut grad = softmax.sub(&target_one_hot)?;
-
-        // Apply class weights to gradients if provided
-        if let Some(weight) = &self.weight {
-            grad = self.apply_class_weights(&grad, weight)?;
-        }
+    /// Loss of one sample from its log-probabilities `row`, with target
+    /// `class` and that class's weight `weight`:
+    /// `-(1 - label_smoothing) * weight * row[class]`, minus
+    /// `smoothing_weights[c] * row[c]` for every class `c` when smoothing
+    /// labels
+    fn sample_loss(&self, row: &[f32], class: usize, weight: f32) -> f32 {
+        let smoothed: f32 = self
+            .smoothing_weights
+            .iter()
+            .zip(row)
+            .map(|(smoothing_weight, log_prob)| smoothing_weight * log_prob)
+            .sum();
+        -(1.0 - self.label_smoothing) * weight * row[class] - smoothed
+    }
 
-        // Apply ignore index masking if specified
-        if let Some(ignore_idx) = self.ignore_index {
-            grad = self.apply_ignore_mask(&grad, target, ignore_idx)?;
+    /// Coefficients `a` of a sample's loss `-sum(a[c] * log_probs[c])`: the
+    /// target's class weight at its class, mixed with the class weights
+    /// spread uniformly when smoothing labels
+    fn coefficients(&self, class: usize, weight: f32, coefficients: &mut [f32]) {
+        if self.smoothing_weights.is_empty() {
+            coefficients.fill(0.0);
+        } else {
+            coefficients.copy_from_slice(&self.smoothing_weights);
         }
+        coefficients[class] += (1.0 - self.label_smoothing) * weight;
+    }
 
-        // Apply reduction factor
-        match self.reduction {
+    /// Factor applied to every sample's gradient: the mean reduction divides
+    /// by the total weight of the non-ignored samples, which is their count
+    /// without class weights. A batch with every sample ignored has zero loss.
+    pub(super) fn scale(&self, reduction: Reduction) -> f32 {
+        match reduction {
             Reduction::Mean => {
-                let batch_size = prediction.shape()[0] as f32;
-                grad.mul_scalar(1.0 / batch_size)
+                let total: f32 = self.weights.iter().sum();
+                if total > 0.0 {
+                    1.0 / total
+                } else {
+                    0.0
+                }
             }
-            Reduction::Sum => Ok(grad),
-            Reduction::None => Ok(grad),
+            Reduction::Sum | Reduction::None => 1.0,
         }
     }
 
-    // Helper methods
-
-    fn validate_input(&self, prediction: &Tensor, target: &Tensor) -> Result<(), BellandeError> {
-        if prediction.dim() != 2 {
-            return Err(BellandeError::InvalidInput(
-                "Prediction tensor must be 2-dimensional (batch_size, num_classes)".to_string(),
-            ));
+    /// Reduces the weighted per-sample losses, `-w[y] * log_probs[y]`
+    /// without label smoothing, each also multiplied by its entry of
+    /// `sample_weights` when given. Sample weights do not change the mean's
+    /// normalizer.
+    pub(super) fn reduce(
+        &self,
+        log_probs: &[f32],
+        num_classes: usize,
+        reduction: Reduction,
+        like: &Tensor,
+        sample_weights: Option<&[f32]>,
+    ) -> Tensor {
+        let losses: Vec<f32> = self
+            .targets
+            .iter()
+            .zip(&self.weights)
+            .zip(log_probs.chunks(num_classes.max(1)))
+            .enumerate()
+            .map(|(i, ((target, &weight), row))| {
+                let sample_weight = sample_weights.map_or(1.0, |w| w[i]);
+                target.map_or(0.0, |class| {
+                    sample_weight * self.sample_loss(row, class, weight)
+                })
+            })
+            .collect();
+
+        let batch_size = losses.len();
+        match reduction {
+            Reduction::None => reduce(losses, vec![batch_size], reduction, like),
+            // The mean divides by the total class weight rather than the count
+            Reduction::Mean | Reduction::Sum => {
+                let scale = self.scale(reduction);
+                let losses = losses.into_iter().map(|loss| loss * scale).collect();
+                reduce(losses, vec![batch_size], Reduction::Sum, like)
+            }
         }
+    }
+}
 
-        if target.dim() != 1 {
-            return Err(BellandeError::InvalidInput(
-                "Target tensor must be 1-dimensional (batch_size)".to_string(),
-            ));
+impl CrossEntropyLoss {
+    /// Creates a new CrossEntropyLoss with the specified parameters
+    pub fn new(reduction: Reduction, weight: Option<Tensor>, ignore_index: Option<i64>) -> Self {
+        CrossEntropyLoss {
+            reduction,
+            weight,
+            ignore_index,
+            label_smoothing: 0.0,
         }
+    }
 
-        if prediction.shape()[0] != target.shape()[0] {
-            return Err(BellandeError::ShapeMismatch(
-                "Batch sizes of prediction and target must match".to_string(),
-            ));
+    /// Trains against the target distribution
+    /// `(1 - label_smoothing) * one_hot(y) + label_smoothing / num_classes`
+    /// instead of the one-hot target. With class weights each class of the
+    /// uniform part is weighted by its own class weight.
+    pub fn with_label_smoothing(mut self, label_smoothing: f32) -> Result<Self, BellandeError> {
+        if !(0.0..1.0).contains(&label_smoothing) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Label smoothing must be in [0, 1), got {}",
+                label_smoothing
+            )));
         }
-
-        Ok(())
+        self.label_smoothing = label_smoothing;
+        Ok(self)
     }
 
-    fn compute_log_softmax(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
-        // Compute max for numerical stability
-        let max = input.max_dim(1, true)?;
-        let shifted = input.sub(&max)?;
-
-        // Compute exp and sum
-        let exp = shifted.exp()?;
-        let sum = exp.sum_dim(1, true)?;
-
-        // Compute log softmax
-        let log_sum = sum.log()?;
-        shifted.sub(&log_sum)
+    pub fn label_smoothing(&self) -> f32 {
+        self.label_smoothing
     }
 
-    fn compute_softmax(&self, input: &Tensor) -> Result<Tensor, BellandeError> {
-        // Compute max for numerical stability
-        let max = input.max_dim(1, true)?;
-        let shifted = input.sub(&max)?;
-
-        // Compute exp and sum
-        let exp = shifted.exp()?;
-        let sum = exp.sum_dim(1, true)?;
+    fn terms(&self, prediction: &Tensor, target: &Tensor) -> Result<NllTerms, BellandeError> {
+        NllTerms::new(
+            prediction,
+            target,
+            self.weight.as_ref(),
+            self.ignore_index,
+            self.label_smoothing,
+        )
+    }
+}
 
-        // Compute softmax
-        exp.div(&sum)
+impl Default for CrossEntropyLoss {
+    fn default() -> Self {
+        CrossEntropyLoss::new(Reduction::Mean, None, None)
     }
+}
 
-    fn convert_to_one_hot(
-        &self,
-        target: &Tensor,
-        num_classes: usize,
-    ) -> Result<Tensor, BellandeError> {
-        let batch_size = target.shape()[0];
-        let mut one_hot = Tensor::zeros(&[batch_size, num_classes])?;
+impl Loss for CrossEntropyLoss {
+    /// Forward pass on (batch_size, num_classes) logits and a (batch_size)
+    /// tensor of class indices. Samples whose target equals `ignore_index`
+    /// contribute nothing, and the mean reduction divides by the (weighted)
+    /// number of remaining samples.
+    fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let terms = self.terms(prediction, target)?;
+        let num_classes = prediction.shape[1];
+        let log_probs = log_softmax(prediction, -1)?.data;
+        Ok(terms.reduce(&log_probs, num_classes, self.reduction, prediction, None))
+    }
 
-        for i in 0..batch_size {
-            let idx = target.get(i)? as usize;
-            if idx >= num_classes {
-                return Err(BellandeError::InvalidInput(format!(
-                    "Target class {} is out of range (0, {})",
-                    idx,
-                    num_classes - 1
-                )));
+    /// Gradient of the loss with respect to the logits:
+    /// `sum(a) * softmax - a` per sample for the loss coefficients `a`, which
+    /// is `w[y] * (softmax - one_hot(y))` without label smoothing, scaled like
+    /// the forward reduction and zero for ignored samples
+    fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let terms = self.terms(prediction, target)?;
+        let num_classes = prediction.shape[1];
+        let scale = terms.scale(self.reduction);
+
+        let mut grad = log_softmax(prediction, -1)?.data;
+        let mut coefficients = vec![0.0; num_classes];
+        for ((row, target), &weight) in grad
+            .chunks_mut(num_classes.max(1))
+            .zip(&terms.targets)
+            .zip(&terms.weights)
+        {
+            match target {
+                Some(class) => {
+                    terms.coefficients(*class, weight, &mut coefficients);
+                    let total: f32 = coefficients.iter().sum();
+                    for (g, &a) in row.iter_mut().zip(&coefficients) {
+                        *g = (total * g.exp() - a) * scale;
+                    }
+                }
+                None => row.fill(0.0),
             }
-            one_hot.set(i, idx, 1.0)?;
         }
 
-        Ok(one_hot)
+        Ok(Tensor::new(
+            grad,
+            prediction.shape.clone(),
+            true,
+            prediction.device.clone(),
+            prediction.dtype,
+        ))
     }
 
-    fn compute_nll_loss(
-        &self,
-        log_probs: &Tensor,
-        target: &Tensor,
-    ) -> Result<Tensor, BellandeError> {
-        // Compute negative log likelihood
-        let mut nll = Tensor::zeros(&log_probs.shape())?;
-        for i in 0..target.shape()[0] {
-            for j in 0..target.shape()[1] {
-                if target.get(i, j)? > 0.0 {
-                    nll.set(i, j, -log_probs.get(i, j)?)?;
-                }
-            }
-        }
-        Ok(nll)
+    fn name(&self) -> &str {
+        "CrossEntropyLoss"
     }
 
-    fn apply_class_weights(&self, loss: &Tensor, weight: &Tensor) -> Result<Tensor, BellandeError> {
-        // Apply class weights to the loss
-        loss.mul(weight)
+    fn reduction(&self) -> Reduction {
+        self.reduction
     }
 
-    fn apply_ignore_mask(
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Multiplies each sample's loss by its (batch_size) weight on top of the
+/// class weights. The mean reduction keeps dividing by the class weight
+/// total, so uniform sample weights of 1 leave the loss unchanged.
+impl WeightedLoss for CrossEntropyLoss {
+    fn forward_weighted(
         &self,
-        loss: &Tensor,
+        output: &Tensor,
         target: &Tensor,
-        ignore_idx: i64,
+        weights: &Tensor,
     ) -> Result<Tensor, BellandeError> {
-        let mut masked_loss = loss.clone();
-        for i in 0..target.shape()[0] {
-            if target.get(i)? as i64 == ignore_idx {
-                for j in 0..masked_loss.shape()[1] {
-                    masked_loss.set(i, j, 0.0)?;
-                }
-            }
-        }
-        Ok(masked_loss)
+        let terms = self.terms(output, target)?;
+        let sample_weights = expand_sample_weights(weights, terms.targets.len())?;
+        let num_classes = output.shape[1];
+        let log_probs = log_softmax(output, -1)?.data;
+        Ok(terms.reduce(
+            &log_probs,
+            num_classes,
+            self.reduction,
+            output,
+            Some(&sample_weights),
+        ))
     }
 
-    fn apply_reduction(&self, loss: &Tensor) -> Result<Tensor, BellandeError> {
-        match self.reduction {
-            Reduction::Mean => loss.mean(),
-            Reduction::Sum => loss.sum(),
-            Reduction::None => Ok(loss.clone()),
-        }
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(CrossEntropyLoss::backward(self, output, target)?, weights)
     }
 }
@@ -14,7 +14,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
-use crate::loss::bce::Reduction;
+use crate::loss::Reduction;
 
 pub trait CustomLossFunction {
     fn compute(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError>;
@@ -0,0 +1,440 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{
+    error::BellandeError,
+    numerics::{log_sum_exp, numerics},
+    tensor::Tensor,
+};
+use crate::layer::activation::{sigmoid, softplus_scalar};
+use crate::loss::utils::grad_scale;
+use crate::loss::Loss;
+use crate::loss::Reduction;
+
+/// Dice loss `1 - (2|P ∩ Y| + s) / (|P| + |Y| + s)` for segmentation, with
+/// soft intersections and cardinalities summed over the batch and spatial
+/// positions of each class.
+///
+/// Predictions are (batch_size, num_classes, ...) logits. With targets
+/// shaped like the prediction every channel is an independent sigmoid with
+/// a mask in [0, 1], for binary or multi-label segmentation; with
+/// (batch_size, ...) class-index targets the channels are a softmax. The
+/// reduction runs over the per-class losses, so `Reduction::None` returns
+/// one loss per class and `Reduction::Mean` averages the classes.
+pub struct DiceLoss {
+    inner: OverlapLoss,
+}
+
+/// Jaccard, or soft-IoU, loss `1 - (|P ∩ Y| + s) / (|P ∪ Y| + s)` with
+/// `|P ∪ Y| = |P| + |Y| - |P ∩ Y|`. Inputs and reduction are as for
+/// `DiceLoss`.
+pub struct JaccardLoss {
+    inner: OverlapLoss,
+}
+
+#[derive(Clone, Copy)]
+enum Overlap {
+    Dice,
+    Jaccard,
+}
+
+struct OverlapLoss {
+    overlap: Overlap,
+    reduction: Reduction,
+    smooth: f32,
+    bce_weight: f32,
+}
+
+/// Probabilities and targets of a batch in the (batch_size, num_classes,
+/// positions) layout of the prediction
+struct Activated {
+    probabilities: Vec<f32>,
+    targets: Vec<f32>,
+    classes: usize,
+    positions: usize,
+    softmax: bool,
+    /// Summed binary cross-entropy with logits of every element for
+    /// sigmoids, or summed cross-entropy of every position for a softmax
+    cross_entropy: f32,
+}
+
+impl Activated {
+    fn class_of(&self, index: usize) -> usize {
+        (index / self.positions) % self.classes
+    }
+
+    /// Number of cross-entropy terms: one per element for sigmoids, one per
+    /// position for a softmax
+    fn terms(&self) -> usize {
+        if self.softmax {
+            self.probabilities.len() / self.classes
+        } else {
+            self.probabilities.len()
+        }
+    }
+}
+
+/// Soft intersection and cardinalities of one class
+#[derive(Clone, Copy, Default)]
+struct ClassSums {
+    intersection: f32,
+    prediction: f32,
+    target: f32,
+}
+
+impl OverlapLoss {
+    fn new(overlap: Overlap, reduction: Reduction) -> Self {
+        OverlapLoss {
+            overlap,
+            reduction,
+            smooth: 1.0,
+            bce_weight: 0.0,
+        }
+    }
+
+    fn with_smooth(mut self, smooth: f32) -> Result<Self, BellandeError> {
+        if !(smooth >= 0.0 && smooth.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Smoothing term must be non-negative, got {}",
+                smooth
+            )));
+        }
+        self.smooth = smooth;
+        Ok(self)
+    }
+
+    fn with_bce(mut self, weight: f32) -> Result<Self, BellandeError> {
+        if !(weight >= 0.0 && weight.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Cross-entropy weight must be non-negative, got {}",
+                weight
+            )));
+        }
+        self.bce_weight = weight;
+        Ok(self)
+    }
+
+    fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let activated = activate(prediction, target)?;
+        let bce = if self.bce_weight > 0.0 {
+            self.bce_weight * activated.cross_entropy / activated.terms() as f32
+        } else {
+            0.0
+        };
+        let losses: Vec<f32> = class_sums(&activated)
+            .iter()
+            .map(|sums| 1.0 - self.score(sums))
+            .collect();
+
+        let (data, shape) = match self.reduction {
+            Reduction::None => (losses.iter().map(|l| l + bce).collect(), vec![losses.len()]),
+            Reduction::Mean => (
+                vec![losses.iter().sum::<f32>() / losses.len() as f32 + bce],
+                vec![1],
+            ),
+            Reduction::Sum => (vec![losses.iter().sum::<f32>() + bce], vec![1]),
+        };
+        Ok(Tensor::new(
+            data,
+            shape,
+            true,
+            prediction.device.clone(),
+            prediction.dtype,
+        ))
+    }
+
+    /// Gradient with respect to the logits: the gradient of each class
+    /// score with respect to the probabilities, chained through the sigmoid
+    /// or softmax, plus `(p - y)` scaled for the cross-entropy term
+    fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let activated = activate(prediction, target)?;
+        let sums = class_sums(&activated);
+        let scale = grad_scale(self.reduction, activated.classes);
+        // Without reduction the cross-entropy is part of every class loss
+        let bce_scale = match self.reduction {
+            Reduction::None => self.bce_weight * activated.classes as f32,
+            _ => self.bce_weight,
+        } / activated.terms() as f32;
+
+        let mut grad: Vec<f32> = activated
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(i, &y)| -scale * self.score_grad(&sums[activated.class_of(i)], y))
+            .collect();
+
+        if activated.softmax {
+            let (classes, positions) = (activated.classes, activated.positions);
+            for (grad, probabilities) in grad
+                .chunks_mut(classes * positions)
+                .zip(activated.probabilities.chunks(classes * positions))
+            {
+                for position in 0..positions {
+                    let dot: f32 = (0..classes)
+                        .map(|c| {
+                            let i = c * positions + position;
+                            probabilities[i] * grad[i]
+                        })
+                        .sum();
+                    for c in 0..classes {
+                        let i = c * positions + position;
+                        grad[i] = probabilities[i] * (grad[i] - dot);
+                    }
+                }
+            }
+        } else {
+            for (g, &p) in grad.iter_mut().zip(&activated.probabilities) {
+                *g *= p * (1.0 - p);
+            }
+        }
+
+        if bce_scale > 0.0 {
+            for ((g, &p), &y) in grad
+                .iter_mut()
+                .zip(&activated.probabilities)
+                .zip(&activated.targets)
+            {
+                *g += bce_scale * (p - y);
+            }
+        }
+
+        Ok(Tensor::new(
+            grad,
+            prediction.shape.clone(),
+            true,
+            prediction.device.clone(),
+            prediction.dtype,
+        ))
+    }
+
+    fn score(&self, sums: &ClassSums) -> f32 {
+        let (numerator, denominator) = self.fraction(sums);
+        numerator / denominator
+    }
+
+    /// Derivative of the class score with respect to one probability whose
+    /// target is `y`
+    fn score_grad(&self, sums: &ClassSums, y: f32) -> f32 {
+        let (numerator, denominator) = self.fraction(sums);
+        let (d_numerator, d_denominator) = match self.overlap {
+            Overlap::Dice => (2.0 * y, 1.0),
+            Overlap::Jaccard => (y, 1.0 - y),
+        };
+        (d_numerator * denominator - numerator * d_denominator) / (denominator * denominator)
+    }
+
+    fn fraction(&self, sums: &ClassSums) -> (f32, f32) {
+        let (numerator, denominator) = match self.overlap {
+            Overlap::Dice => (
+                2.0 * sums.intersection + self.smooth,
+                sums.prediction + sums.target + self.smooth,
+            ),
+            Overlap::Jaccard => (
+                sums.intersection + self.smooth,
+                sums.prediction + sums.target - sums.intersection + self.smooth,
+            ),
+        };
+        (numerator, denominator.max(numerics().denom_eps))
+    }
+}
+
+/// Applies the sigmoid or softmax to the prediction and expands class-index
+/// targets to one-hot masks
+fn activate(prediction: &Tensor, target: &Tensor) -> Result<Activated, BellandeError> {
+    if prediction.shape.len() < 2 || prediction.shape[1] == 0 {
+        return Err(BellandeError::InvalidShape(
+            "Prediction tensor must be (batch_size, num_classes, ...)".into(),
+        ));
+    }
+    let classes = prediction.shape[1];
+    let positions: usize = prediction.shape[2..].iter().product();
+
+    if target.shape == prediction.shape {
+        if let Some(&value) = target.data.iter().find(|&&y| !(0.0..=1.0).contains(&y)) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Mask targets must be in [0, 1], got {}",
+                value
+            )));
+        }
+        return Ok(Activated {
+            probabilities: prediction.data.iter().map(|&x| sigmoid(x)).collect(),
+            targets: target.data.clone(),
+            classes,
+            positions,
+            softmax: false,
+            cross_entropy: prediction
+                .data
+                .iter()
+                .zip(&target.data)
+                .map(|(&x, &y)| softplus_scalar(x) - x * y)
+                .sum(),
+        });
+    }
+
+    if target.data.len() != prediction.shape[0] * positions {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Target of shape {:?} must hold one class index per position of a prediction \
+             of shape {:?} or match its shape",
+            target.shape, prediction.shape
+        )));
+    }
+
+    let mut probabilities = vec![0.0; prediction.data.len()];
+    let mut targets = vec![0.0; prediction.data.len()];
+    let mut cross_entropy = 0.0;
+    let mut logits = vec![0.0; classes];
+    for (n, sample) in prediction.data.chunks(classes * positions).enumerate() {
+        let offset = n * classes * positions;
+        for position in 0..positions {
+            for (c, logit) in logits.iter_mut().enumerate() {
+                *logit = sample[c * positions + position];
+            }
+            let log_sum = log_sum_exp(&logits);
+            for (c, &logit) in logits.iter().enumerate() {
+                probabilities[offset + c * positions + position] = (logit - log_sum).exp();
+            }
+
+            let class = target.data[n * positions + position].round();
+            if class < 0.0 || class as usize >= classes {
+                return Err(BellandeError::InvalidParameter(format!(
+                    "Target class {} is out of range (0, {})",
+                    class,
+                    classes - 1
+                )));
+            }
+            targets[offset + class as usize * positions + position] = 1.0;
+            cross_entropy += log_sum - logits[class as usize];
+        }
+    }
+
+    Ok(Activated {
+        probabilities,
+        targets,
+        classes,
+        positions,
+        softmax: true,
+        cross_entropy,
+    })
+}
+
+fn class_sums(activated: &Activated) -> Vec<ClassSums> {
+    let mut sums = vec![ClassSums::default(); activated.classes];
+    for (i, (&p, &y)) in activated
+        .probabilities
+        .iter()
+        .zip(&activated.targets)
+        .enumerate()
+    {
+        let sums = &mut sums[activated.class_of(i)];
+        sums.intersection += p * y;
+        sums.prediction += p;
+        sums.target += y;
+    }
+    sums
+}
+
+impl DiceLoss {
+    /// Dice loss with a smoothing term of 1 and no cross-entropy
+    pub fn new(reduction: Reduction) -> Self {
+        DiceLoss {
+            inner: OverlapLoss::new(Overlap::Dice, reduction),
+        }
+    }
+
+    /// Sets the smoothing term `s`, which keeps the loss defined for
+    /// classes absent from both prediction and target
+    pub fn with_smooth(self, smooth: f32) -> Result<Self, BellandeError> {
+        Ok(DiceLoss {
+            inner: self.inner.with_smooth(smooth)?,
+        })
+    }
+
+    /// Adds `weight` times the binary cross-entropy with logits, or the
+    /// cross-entropy for class-index targets, to the loss
+    pub fn with_bce(self, weight: f32) -> Result<Self, BellandeError> {
+        Ok(DiceLoss {
+            inner: self.inner.with_bce(weight)?,
+        })
+    }
+
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        self.inner.forward(prediction, target)
+    }
+
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        self.inner.backward(prediction, target)
+    }
+}
+
+impl JaccardLoss {
+    /// Jaccard loss with a smoothing term of 1 and no cross-entropy
+    pub fn new(reduction: Reduction) -> Self {
+        JaccardLoss {
+            inner: OverlapLoss::new(Overlap::Jaccard, reduction),
+        }
+    }
+
+    /// Sets the smoothing term `s`, which keeps the loss defined for
+    /// classes absent from both prediction and target
+    pub fn with_smooth(self, smooth: f32) -> Result<Self, BellandeError> {
+        Ok(JaccardLoss {
+            inner: self.inner.with_smooth(smooth)?,
+        })
+    }
+
+    /// Adds `weight` times the binary cross-entropy with logits, or the
+    /// cross-entropy for class-index targets, to the loss
+    pub fn with_bce(self, weight: f32) -> Result<Self, BellandeError> {
+        Ok(JaccardLoss {
+            inner: self.inner.with_bce(weight)?,
+        })
+    }
+
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        self.inner.forward(prediction, target)
+    }
+
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        self.inner.backward(prediction, target)
+    }
+}
+
+impl Loss for DiceLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        DiceLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        DiceLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "DiceLoss"
+    }
+}
+
+impl Loss for JaccardLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        JaccardLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        JaccardLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "JaccardLoss"
+    }
+}
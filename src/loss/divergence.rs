@@ -0,0 +1,219 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::loss::utils::{grad_scale, reduce};
+use crate::loss::Loss;
+use crate::loss::Reduction;
+
+/// Kullback-Leibler divergence `KL(target || exp(input))` for an input of
+/// log-probabilities, such as a student's log-softmax in knowledge
+/// distillation. The target holds probabilities, or log-probabilities with
+/// `log_target`. Pointwise terms are `t * (log t - x)`, zero where `t = 0`.
+///
+/// Distributions run along the last dimension and the mean reduction
+/// divides the sum by the batch size (the leading dimension), which gives
+/// the divergence per sample rather than per element.
+pub struct KLDivLoss {
+    reduction: Reduction,
+    log_target: bool,
+}
+
+/// Jensen-Shannon divergence
+/// `0.5 * KL(p || m) + 0.5 * KL(q || m)` with `m = (p + q) / 2`, between
+/// `p = exp(input)` for an input of log-probabilities and the target
+/// distribution `q`. Unlike `KLDivLoss` it is symmetric and bounded by
+/// `ln 2`, so it stays finite when the supports differ. Reduced like
+/// `KLDivLoss`.
+pub struct JSDivLoss {
+    reduction: Reduction,
+    log_target: bool,
+}
+
+fn validate(input: &Tensor, target: &Tensor) -> Result<(), BellandeError> {
+    if input.shape != target.shape {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Input shape {:?} doesn't match target shape {:?}",
+            input.shape, target.shape
+        )));
+    }
+    if input.shape.is_empty() {
+        return Err(BellandeError::InvalidShape(
+            "Expected distributions with a batch dimension".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Target probability and its logarithm
+fn target_terms(value: f32, log_target: bool) -> (f32, f32) {
+    if log_target {
+        (value.exp(), value)
+    } else {
+        (value, value.ln())
+    }
+}
+
+/// `a * ln(a / b)` from `ln a` and `ln b`, zero when `a = 0`
+fn xlogy_ratio(a: f32, log_a: f32, log_b: f32) -> f32 {
+    if a > 0.0 {
+        a * (log_a - log_b)
+    } else {
+        0.0
+    }
+}
+
+/// Leading dimension of `like`, which the mean reduction divides by
+fn batch_size(like: &Tensor) -> usize {
+    like.shape.first().copied().unwrap_or(1)
+}
+
+/// Reduces pointwise terms. The mean sums the terms of each sample first,
+/// so it divides by the batch size.
+fn reduce_terms(terms: Vec<f32>, reduction: Reduction, like: &Tensor) -> Tensor {
+    if reduction != Reduction::Mean {
+        return reduce(terms, like.shape.clone(), reduction, like);
+    }
+    let batch_size = batch_size(like).max(1);
+    let per_sample = terms
+        .chunks((terms.len() / batch_size).max(1))
+        .map(|terms| terms.iter().sum())
+        .collect();
+    reduce(per_sample, vec![batch_size], reduction, like)
+}
+
+fn gradient(grad: Vec<f32>, input: &Tensor) -> Tensor {
+    Tensor::new(
+        grad,
+        input.shape.clone(),
+        true,
+        input.device.clone(),
+        input.dtype,
+    )
+}
+
+impl KLDivLoss {
+    pub fn new(reduction: Reduction, log_target: bool) -> Self {
+        KLDivLoss {
+            reduction,
+            log_target,
+        }
+    }
+
+    pub fn forward(&self, input: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate(input, target)?;
+        let terms = input
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&x, &t)| {
+                let (q, log_q) = target_terms(t, self.log_target);
+                xlogy_ratio(q, log_q, x)
+            })
+            .collect();
+        Ok(reduce_terms(terms, self.reduction, input))
+    }
+
+    /// Gradient `-t` with respect to the log-probabilities, scaled like the
+    /// reduction
+    pub fn backward(&self, input: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate(input, target)?;
+        let scale = grad_scale(self.reduction, batch_size(input));
+        let grad = target
+            .data
+            .iter()
+            .map(|&t| -target_terms(t, self.log_target).0 * scale)
+            .collect();
+        Ok(gradient(grad, input))
+    }
+}
+
+impl JSDivLoss {
+    pub fn new(reduction: Reduction, log_target: bool) -> Self {
+        JSDivLoss {
+            reduction,
+            log_target,
+        }
+    }
+
+    /// (p, ln p, q, ln q, ln m) of every element
+    fn mixture<'a>(
+        &'a self,
+        input: &'a Tensor,
+        target: &'a Tensor,
+    ) -> impl Iterator<Item = (f32, f32, f32, f32, f32)> + 'a {
+        input
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(move |(&x, &t)| {
+                let p = x.exp();
+                let (q, log_q) = target_terms(t, self.log_target);
+                let log_m = (0.5 * (p + q)).ln();
+                (p, x, q, log_q, log_m)
+            })
+    }
+
+    pub fn forward(&self, input: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate(input, target)?;
+        let terms = self
+            .mixture(input, target)
+            .map(|(p, log_p, q, log_q, log_m)| {
+                0.5 * (xlogy_ratio(p, log_p, log_m) + xlogy_ratio(q, log_q, log_m))
+            })
+            .collect();
+        Ok(reduce_terms(terms, self.reduction, input))
+    }
+
+    /// Gradient `0.5 * p * ln(p / m)` with respect to the log-probabilities:
+    /// the terms from differentiating `m` cancel
+    pub fn backward(&self, input: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate(input, target)?;
+        let scale = grad_scale(self.reduction, batch_size(input));
+        let grad = self
+            .mixture(input, target)
+            .map(|(p, log_p, _, _, log_m)| 0.5 * xlogy_ratio(p, log_p, log_m) * scale)
+            .collect();
+        Ok(gradient(grad, input))
+    }
+}
+
+impl Loss for KLDivLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        KLDivLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        KLDivLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "KLDivLoss"
+    }
+}
+
+impl Loss for JSDivLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        JSDivLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        JSDivLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "JSDivLoss"
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, tensor::Tensor};
+use crate::loss::utils::{grad_scale, reduce};
+use crate::loss::Loss;
+use crate::loss::Reduction;
+
+/// Pairwise similarity loss on the cosine similarity `cos` of two
+/// embeddings: `1 - cos` when `y = 1` (similar pair) and
+/// `max(0, cos - margin)` when `y = -1` (dissimilar pair)
+pub struct CosineEmbeddingLoss {
+    reduction: Reduction,
+    margin: f32,
+}
+
+/// Cosine similarity of one pair, with the terms its gradient needs
+struct Similarity {
+    cos: f32,
+    norm_first: f32,
+    norm_second: f32,
+}
+
+impl CosineEmbeddingLoss {
+    /// Cosine embedding loss with a margin of 0
+    pub fn new(reduction: Reduction) -> Self {
+        CosineEmbeddingLoss {
+            reduction,
+            margin: 0.0,
+        }
+    }
+
+    /// Similarity below which dissimilar pairs are no longer penalized,
+    /// between -1 and 1
+    pub fn with_margin(mut self, margin: f32) -> Result<Self, BellandeError> {
+        if !(-1.0..=1.0).contains(&margin) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Margin must be between -1 and 1, got {}",
+                margin
+            )));
+        }
+        self.margin = margin;
+        Ok(self)
+    }
+
+    pub fn margin(&self) -> f32 {
+        self.margin
+    }
+
+    /// Loss on (batch_size, 2, dim) embeddings holding both members of
+    /// each pair
+    pub fn forward(&self, embeddings: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let (first, second) = split_pairs(embeddings)?;
+        self.forward_pair(&first, &second, target)
+    }
+
+    /// Gradient with respect to the (batch_size, 2, dim) embeddings
+    pub fn backward(&self, embeddings: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let (first, second) = split_pairs(embeddings)?;
+        let (grad_first, grad_second) = self.backward_pair(&first, &second, target)?;
+        let dim = first.shape[1].max(1);
+        let grad = grad_first
+            .data
+            .chunks(dim)
+            .zip(grad_second.data.chunks(dim))
+            .flat_map(|(g1, g2)| g1.iter().chain(g2).copied())
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            embeddings.shape.clone(),
+            true,
+            embeddings.device.clone(),
+            embeddings.dtype,
+        ))
+    }
+
+    /// Loss on two (batch_size, dim) embedding batches
+    pub fn forward_pair(
+        &self,
+        first: &Tensor,
+        second: &Tensor,
+        target: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let similarities = similarities(first, second, target)?;
+        let losses = similarities
+            .iter()
+            .zip(target.data.iter())
+            .map(|(s, &y)| {
+                if y > 0.0 {
+                    1.0 - s.cos
+                } else {
+                    (s.cos - self.margin).max(0.0)
+                }
+            })
+            .collect();
+        Ok(reduce(
+            losses,
+            vec![target.data.len()],
+            self.reduction,
+            first,
+        ))
+    }
+
+    /// Gradients with respect to `first` and `second`, from
+    /// `d cos / d a = b / (|a| |b|) - cos * a / |a|^2`
+    pub fn backward_pair(
+        &self,
+        first: &Tensor,
+        second: &Tensor,
+        target: &Tensor,
+    ) -> Result<(Tensor, Tensor), BellandeError> {
+        let similarities = similarities(first, second, target)?;
+        let dim = first.shape[1];
+        let scale = grad_scale(self.reduction, target.data.len());
+
+        let mut grad_first = vec![0.0; first.data.len()];
+        let mut grad_second = vec![0.0; second.data.len()];
+        for (i, (s, &y)) in similarities.iter().zip(target.data.iter()).enumerate() {
+            // Derivative of the pair's loss with respect to `cos`
+            let d_cos = if y > 0.0 {
+                -scale
+            } else if s.cos > self.margin {
+                scale
+            } else {
+                continue;
+            };
+            let range = i * dim..(i + 1) * dim;
+            let a = &first.data[range.clone()];
+            let b = &second.data[range.clone()];
+            let norms = s.norm_first * s.norm_second;
+            for (j, (&aj, &bj)) in a.iter().zip(b).enumerate() {
+                grad_first[range.start + j] =
+                    d_cos * (bj / norms - s.cos * aj / (s.norm_first * s.norm_first));
+                grad_second[range.start + j] =
+                    d_cos * (aj / norms - s.cos * bj / (s.norm_second * s.norm_second));
+            }
+        }
+
+        let tensor = |grad, like: &Tensor| {
+            Tensor::new(
+                grad,
+                like.shape.clone(),
+                true,
+                like.device.clone(),
+                like.dtype,
+            )
+        };
+        Ok((tensor(grad_first, first), tensor(grad_second, second)))
+    }
+}
+
+impl Loss for CosineEmbeddingLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        CosineEmbeddingLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        CosineEmbeddingLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "CosineEmbeddingLoss"
+    }
+}
+
+/// Validates a pair of (batch_size, dim) batches and a target in {1, -1}
+/// per pair, and computes the similarity of every pair. Norms are clamped
+/// to `denom_eps` so that zero embeddings have a similarity of 0.
+fn similarities(
+    first: &Tensor,
+    second: &Tensor,
+    target: &Tensor,
+) -> Result<Vec<Similarity>, BellandeError> {
+    if first.shape.len() != 2 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected embeddings of shape (batch_size, dim), got {:?}",
+            first.shape
+        )));
+    }
+    if first.shape != second.shape {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Embedding shapes {:?} and {:?} differ",
+            first.shape, second.shape
+        )));
+    }
+    if target.data.len() != first.shape[0] {
+        return Err(BellandeError::DimensionMismatch);
+    }
+    if let Some(&value) = target.data.iter().find(|&&y| y != 1.0 && y != -1.0) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Targets must be 1 or -1, got {}",
+            value
+        )));
+    }
+
+    let eps = numerics().denom_eps;
+    let dim = first.shape[1].max(1);
+    Ok(first
+        .data
+        .chunks(dim)
+        .zip(second.data.chunks(dim))
+        .map(|(a, b)| {
+            let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+            let norm_first = a.iter().map(|x| x * x).sum::<f32>().sqrt().max(eps);
+            let norm_second = b.iter().map(|x| x * x).sum::<f32>().sqrt().max(eps);
+            Similarity {
+                cos: dot / (norm_first * norm_second),
+                norm_first,
+                norm_second,
+            }
+        })
+        .collect())
+}
+
+/// Splits (batch_size, 2, dim) embeddings into two (batch_size, dim) batches
+fn split_pairs(embeddings: &Tensor) -> Result<(Tensor, Tensor), BellandeError> {
+    if embeddings.shape.len() != 3 || embeddings.shape[1] != 2 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected embedding pairs of shape (batch_size, 2, dim), got {:?}",
+            embeddings.shape
+        )));
+    }
+    let (batch_size, dim) = (embeddings.shape[0], embeddings.shape[2]);
+    let member = |index: usize| {
+        let data = embeddings
+            .data
+            .chunks(2 * dim.max(1))
+            .flat_map(|pair| pair[index * dim..(index + 1) * dim].iter().copied())
+            .collect();
+        Tensor::new(
+            data,
+            vec![batch_size, dim],
+            embeddings.requires_grad,
+            embeddings.device.clone(),
+            embeddings.dtype,
+        )
+    };
+    Ok((member(0), member(1)))
+}
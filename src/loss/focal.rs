@@ -0,0 +1,354 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::log_sum_exp, tensor::Tensor};
+use crate::layer::activation::{sigmoid, softplus_scalar};
+use crate::loss::utils::{apply_sample_weights, expand_sample_weights, grad_scale, reduce};
+use crate::loss::{ClassWeightedLoss, Loss, Reduction, WeightedLoss};
+
+/// Focal loss (Lin et al., 2017), which scales the cross-entropy of each
+/// term by `(1 - p_t)^gamma` so that well-classified examples contribute
+/// little and training focuses on the hard, often minority, ones.
+///
+/// With (batch_size) class-index targets the prediction holds
+/// (batch_size, num_classes) logits and `p_t` is the softmax probability of
+/// the target class; `alpha` then scales every term. With targets shaped
+/// like the prediction, as for detection heads or multi-label outputs, each
+/// logit is an independent sigmoid with a target in [0, 1] and `alpha`
+/// weights positives against `1 - alpha` for negatives. Class weights apply
+/// per target class, or per entry of the last dimension, respectively.
+pub struct FocalLoss {
+    reduction: Reduction,
+    gamma: f32,
+    alpha: Option<f32>,
+    weight: Option<Tensor>,
+}
+
+impl FocalLoss {
+    /// `gamma` must be non-negative and `alpha` in [0, 1]; `gamma = 0`
+    /// without `alpha` reduces to the cross-entropy
+    pub fn new(
+        reduction: Reduction,
+        gamma: f32,
+        alpha: Option<f32>,
+        weight: Option<Tensor>,
+    ) -> Result<Self, BellandeError> {
+        if !(gamma >= 0.0 && gamma.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Focusing parameter gamma must be non-negative, got {}",
+                gamma
+            )));
+        }
+        if let Some(alpha) = alpha {
+            if !(0.0..=1.0).contains(&alpha) {
+                return Err(BellandeError::InvalidParameter(format!(
+                    "Balancing factor alpha must be in [0, 1], got {}",
+                    alpha
+                )));
+            }
+        }
+        let mut loss = FocalLoss {
+            reduction,
+            gamma,
+            alpha,
+            weight: None,
+        };
+        if let Some(weight) = weight {
+            loss.set_class_weights(weight)?;
+        }
+        Ok(loss)
+    }
+
+    pub fn gamma(&self) -> f32 {
+        self.gamma
+    }
+
+    pub fn alpha(&self) -> Option<f32> {
+        self.alpha
+    }
+
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let (losses, shape) = self.losses(prediction, target)?;
+        Ok(reduce(losses, shape, self.reduction, prediction))
+    }
+
+    /// Unreduced losses with their shape: one per sample for class-index
+    /// targets, one per element otherwise
+    fn losses(
+        &self,
+        prediction: &Tensor,
+        target: &Tensor,
+    ) -> Result<(Vec<f32>, Vec<usize>), BellandeError> {
+        if prediction.shape == target.shape {
+            return self.sigmoid_losses(prediction, target);
+        }
+        let targets = self.targets(prediction, target)?;
+        let num_classes = prediction.shape[1];
+
+        let losses = prediction
+            .data
+            .chunks(num_classes)
+            .zip(&targets)
+            .map(|(row, &y)| {
+                let log_pt = row[y] - log_sum_exp(row);
+                let modulation = (1.0 - log_pt.exp()).max(0.0).powf(self.gamma);
+                -self.softmax_scale(y) * modulation * log_pt
+            })
+            .collect();
+        Ok((losses, vec![targets.len()]))
+    }
+
+    /// Gradient with respect to the logits. For the softmax form it is
+    /// `scale * F * (softmax - one_hot(y))` per sample, where
+    /// `F = (1 - p_t)^gamma - gamma * p_t * (1 - p_t)^(gamma - 1) * log(p_t)`
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        if prediction.shape == target.shape {
+            return self.backward_sigmoid(prediction, target);
+        }
+        let targets = self.targets(prediction, target)?;
+        let num_classes = prediction.shape[1];
+        let scale = grad_scale(self.reduction, targets.len());
+
+        let mut grad = vec![0.0; prediction.data.len()];
+        for ((grad_row, row), &y) in grad
+            .chunks_mut(num_classes)
+            .zip(prediction.data.chunks(num_classes))
+            .zip(&targets)
+        {
+            let log_sum = log_sum_exp(row);
+            let log_pt = row[y] - log_sum;
+            let pt = log_pt.exp();
+            let one_minus = (1.0 - pt).max(0.0);
+            let mut factor = one_minus.powf(self.gamma);
+            if self.gamma > 0.0 && one_minus > 0.0 {
+                factor -= self.gamma * pt * one_minus.powf(self.gamma - 1.0) * log_pt;
+            }
+            let factor = factor * self.softmax_scale(y) * scale;
+
+            for (g, &x) in grad_row.iter_mut().zip(row) {
+                *g = factor * (x - log_sum).exp();
+            }
+            grad_row[y] -= factor;
+        }
+
+        Ok(Tensor::new(
+            grad,
+            prediction.shape.clone(),
+            true,
+            prediction.device.clone(),
+            prediction.dtype,
+        ))
+    }
+
+    /// Element-wise sigmoid focal loss
+    /// `alpha_t * (1 - p_t)^gamma * bce_with_logits(x, y)`
+    fn sigmoid_losses(
+        &self,
+        prediction: &Tensor,
+        target: &Tensor,
+    ) -> Result<(Vec<f32>, Vec<usize>), BellandeError> {
+        self.validate_sigmoid(prediction, target)?;
+        let losses = self
+            .sigmoid_terms(prediction, target)
+            .map(|(x, y, weight)| {
+                let p = sigmoid(x);
+                let pt = p * y + (1.0 - p) * (1.0 - y);
+                let ce = softplus_scalar(x) - x * y;
+                weight * (1.0 - pt).max(0.0).powf(self.gamma) * ce
+            })
+            .collect();
+        Ok((losses, prediction.shape.clone()))
+    }
+
+    fn backward_sigmoid(
+        &self,
+        prediction: &Tensor,
+        target: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        self.validate_sigmoid(prediction, target)?;
+        let scale = grad_scale(self.reduction, prediction.data.len());
+        let grad = self
+            .sigmoid_terms(prediction, target)
+            .map(|(x, y, weight)| {
+                let p = sigmoid(x);
+                let pt = p * y + (1.0 - p) * (1.0 - y);
+                let one_minus = (1.0 - pt).max(0.0);
+                let mut grad = one_minus.powf(self.gamma) * (p - y);
+                if self.gamma > 0.0 && one_minus > 0.0 {
+                    // d(1 - p_t)/dx = -p * (1 - p) * (2y - 1)
+                    let ce = softplus_scalar(x) - x * y;
+                    grad -= self.gamma
+                        * one_minus.powf(self.gamma - 1.0)
+                        * p
+                        * (1.0 - p)
+                        * (2.0 * y - 1.0)
+                        * ce;
+                }
+                weight * grad * scale
+            })
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            prediction.shape.clone(),
+            true,
+            prediction.device.clone(),
+            prediction.dtype,
+        ))
+    }
+
+    /// (logit, target, alpha_t * class weight) of every element
+    fn sigmoid_terms<'a>(
+        &'a self,
+        prediction: &'a Tensor,
+        target: &'a Tensor,
+    ) -> impl Iterator<Item = (f32, f32, f32)> + 'a {
+        let num_classes = prediction.shape.last().copied().unwrap_or(1).max(1);
+        prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .enumerate()
+            .map(move |(i, (&x, &y))| {
+                let alpha = self.alpha.map_or(1.0, |a| a * y + (1.0 - a) * (1.0 - y));
+                (x, y, alpha * self.class_weight(i % num_classes))
+            })
+    }
+
+    fn validate_sigmoid(&self, prediction: &Tensor, target: &Tensor) -> Result<(), BellandeError> {
+        if let Some(&value) = target.data.iter().find(|&&y| !(0.0..=1.0).contains(&y)) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Element-wise targets must be in [0, 1], got {}",
+                value
+            )));
+        }
+        self.check_weights(prediction.shape.last().copied().unwrap_or(1))
+    }
+
+    /// Validates class-index targets and returns the class of each sample
+    fn targets(&self, prediction: &Tensor, target: &Tensor) -> Result<Vec<usize>, BellandeError> {
+        if prediction.shape.len() != 2 {
+            return Err(BellandeError::InvalidShape(
+                "Prediction tensor must be 2-dimensional (batch_size, num_classes)".into(),
+            ));
+        }
+        let (batch_size, num_classes) = (prediction.shape[0], prediction.shape[1]);
+        if target.data.len() != batch_size {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Target of shape {:?} must hold one class index per sample of a batch of {} \
+                 or match the prediction shape {:?}",
+                target.shape, batch_size, prediction.shape
+            )));
+        }
+        self.check_weights(num_classes)?;
+
+        target
+            .data
+            .iter()
+            .map(|&value| {
+                let class = value.round();
+                if class < 0.0 || class as usize >= num_classes {
+                    Err(BellandeError::InvalidParameter(format!(
+                        "Target class {} is out of range (0, {})",
+                        class,
+                        num_classes - 1
+                    )))
+                } else {
+                    Ok(class as usize)
+                }
+            })
+            .collect()
+    }
+
+    fn check_weights(&self, num_classes: usize) -> Result<(), BellandeError> {
+        match self.weight {
+            Some(ref weight) if weight.data.len() != num_classes => {
+                Err(BellandeError::ShapeMismatch(format!(
+                    "Got {} class weights for {} classes",
+                    weight.data.len(),
+                    num_classes
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn class_weight(&self, class: usize) -> f32 {
+        self.weight.as_ref().map_or(1.0, |w| w.data[class])
+    }
+
+    /// Constant factor of a softmax term with target `class`
+    fn softmax_scale(&self, class: usize) -> f32 {
+        self.alpha.unwrap_or(1.0) * self.class_weight(class)
+    }
+}
+
+impl Loss for FocalLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        FocalLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        FocalLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "FocalLoss"
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Multiplies each sample's terms by its weight before the reduction
+impl WeightedLoss for FocalLoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let (mut losses, shape) = self.losses(output, target)?;
+        let weights = expand_sample_weights(weights, losses.len())?;
+        losses.iter_mut().zip(weights).for_each(|(l, w)| *l *= w);
+        Ok(reduce(losses, shape, self.reduction, output))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(self.backward(output, target)?, weights)
+    }
+}
+
+impl ClassWeightedLoss for FocalLoss {
+    fn set_class_weights(&mut self, weights: Tensor) -> Result<(), BellandeError> {
+        if let Some(&value) = weights.data.iter().find(|w| !(**w >= 0.0 && w.is_finite())) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Class weights must be finite and non-negative, got {}",
+                value
+            )));
+        }
+        self.weight = Some(weights);
+        Ok(())
+    }
+
+    fn get_class_weights(&self) -> Option<&Tensor> {
+        self.weight.as_ref()
+    }
+}
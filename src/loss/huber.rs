@@ -0,0 +1,165 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::loss::utils::{apply_sample_weights, expand_sample_weights, grad_scale, reduce};
+use crate::loss::{Loss, Reduction, WeightedLoss};
+
+/// Huber loss on the element-wise difference `d = prediction - target`:
+/// `0.5 * d^2` when `|d| < delta` and `delta * (|d| - 0.5 * delta)`
+/// otherwise, so it is quadratic near zero and linear for outliers.
+/// `smooth_l1` builds the SmoothL1 variant used for bounding-box
+/// regression, which is the Huber loss divided by `beta`.
+pub struct HuberLoss {
+    reduction: Reduction,
+    delta: f32,
+    /// 1 for the Huber loss, `1 / beta` for SmoothL1
+    scale: f32,
+    name: &'static str,
+}
+
+impl HuberLoss {
+    pub fn new(reduction: Reduction, delta: f32) -> Result<Self, BellandeError> {
+        Self::validate(delta, "delta")?;
+        Ok(HuberLoss {
+            reduction,
+            delta,
+            scale: 1.0,
+            name: "HuberLoss",
+        })
+    }
+
+    /// SmoothL1 loss: `0.5 * d^2 / beta` when `|d| < beta` and
+    /// `|d| - 0.5 * beta` otherwise
+    pub fn smooth_l1(reduction: Reduction, beta: f32) -> Result<Self, BellandeError> {
+        Self::validate(beta, "beta")?;
+        Ok(HuberLoss {
+            reduction,
+            delta: beta,
+            scale: 1.0 / beta,
+            name: "SmoothL1Loss",
+        })
+    }
+
+    fn validate(threshold: f32, name: &str) -> Result<(), BellandeError> {
+        if !(threshold > 0.0 && threshold.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "{} must be positive, got {}",
+                name, threshold
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn delta(&self) -> f32 {
+        self.delta
+    }
+
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let losses = self.losses(prediction, target)?;
+        Ok(reduce(
+            losses,
+            prediction.shape.clone(),
+            self.reduction,
+            prediction,
+        ))
+    }
+
+    /// Unreduced element-wise losses
+    fn losses(&self, prediction: &Tensor, target: &Tensor) -> Result<Vec<f32>, BellandeError> {
+        if prediction.shape != target.shape {
+            return Err(BellandeError::DimensionMismatch);
+        }
+        Ok(prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&p, &t)| {
+                let diff = (p - t).abs();
+                let loss = if diff < self.delta {
+                    0.5 * diff * diff
+                } else {
+                    self.delta * (diff - 0.5 * self.delta)
+                };
+                loss * self.scale
+            })
+            .collect())
+    }
+
+    /// Gradient `d` inside the quadratic region and `delta * sign(d)`
+    /// outside, times the SmoothL1 scale
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        if prediction.shape != target.shape {
+            return Err(BellandeError::DimensionMismatch);
+        }
+        let scale = grad_scale(self.reduction, prediction.data.len()) * self.scale;
+        let grad = prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&p, &t)| (p - t).clamp(-self.delta, self.delta) * scale)
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            prediction.shape.clone(),
+            true,
+            prediction.device.clone(),
+            prediction.dtype,
+        ))
+    }
+}
+
+impl Loss for HuberLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        HuberLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        HuberLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Weights every element of a sample by the sample's weight; the mean
+/// reduction still divides by the number of elements
+impl WeightedLoss for HuberLoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let mut losses = self.losses(output, target)?;
+        let weights = expand_sample_weights(weights, losses.len())?;
+        losses.iter_mut().zip(weights).for_each(|(l, w)| *l *= w);
+        Ok(reduce(losses, output.shape.clone(), self.reduction, output))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(self.backward(output, target)?, weights)
+    }
+}
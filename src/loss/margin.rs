@@ -0,0 +1,460 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::activation::{sigmoid, softplus_scalar};
+use crate::loss::utils::{apply_sample_weights, expand_sample_weights, grad_scale, reduce};
+use crate::loss::{ClassWeightedLoss, Loss, Reduction, WeightedLoss};
+
+/// Binary hinge loss `max(0, margin - y * x)` on raw scores `x` for targets
+/// `y` in {1, -1}, as used by linear SVMs and the hinge GAN discriminator.
+/// The squared variant `max(0, margin - y * x)^2` is differentiable at the
+/// margin.
+pub struct HingeLoss {
+    reduction: Reduction,
+    margin: f32,
+    squared: bool,
+}
+
+/// Hinge embedding loss for targets in {1, -1}: `x` for positive pairs and
+/// `max(0, margin - x)` for negative ones, where `x` is typically a
+/// distance
+pub struct HingeEmbeddingLoss {
+    reduction: Reduction,
+    margin: f32,
+}
+
+/// Two-class logistic loss `log(1 + exp(-y * x))` for targets in {1, -1}
+pub struct SoftMarginLoss {
+    reduction: Reduction,
+}
+
+/// Multi-class hinge loss on (batch_size, num_classes) scores: for each
+/// sample, `sum over i != y of max(0, margin - x[y] + x[i])^p / num_classes`,
+/// scaled by the optional weight of class `y`
+pub struct MultiMarginLoss {
+    reduction: Reduction,
+    p: u32,
+    margin: f32,
+    weight: Option<Tensor>,
+}
+
+fn gradient(grad: Vec<f32>, prediction: &Tensor) -> Tensor {
+    Tensor::new(
+        grad,
+        prediction.shape.clone(),
+        true,
+        prediction.device.clone(),
+        prediction.dtype,
+    )
+}
+
+/// Checks that `target` matches `prediction` and holds only 1 and -1
+fn validate_signs(prediction: &Tensor, target: &Tensor) -> Result<(), BellandeError> {
+    if prediction.shape != target.shape {
+        return Err(BellandeError::DimensionMismatch);
+    }
+    if let Some(&value) = target.data.iter().find(|&&y| y != 1.0 && y != -1.0) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Targets must be 1 or -1, got {}",
+            value
+        )));
+    }
+    Ok(())
+}
+
+impl HingeLoss {
+    /// Hinge loss with a margin of 1
+    pub fn new(reduction: Reduction) -> Self {
+        HingeLoss {
+            reduction,
+            margin: 1.0,
+            squared: false,
+        }
+    }
+
+    pub fn with_margin(mut self, margin: f32) -> Result<Self, BellandeError> {
+        if !(margin >= 0.0 && margin.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Margin must be finite and non-negative, got {}",
+                margin
+            )));
+        }
+        self.margin = margin;
+        Ok(self)
+    }
+
+    /// Squares each violation
+    pub fn with_squared(mut self, squared: bool) -> Self {
+        self.squared = squared;
+        self
+    }
+
+    pub fn margin(&self) -> f32 {
+        self.margin
+    }
+
+    /// Margin violation `max(0, margin - y * x)` of every element
+    fn violations<'a>(
+        &'a self,
+        prediction: &'a Tensor,
+        target: &'a Tensor,
+    ) -> impl Iterator<Item = (f32, f32)> + 'a {
+        prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(move |(&x, &y)| ((self.margin - y * x).max(0.0), y))
+    }
+
+    fn losses(&self, prediction: &Tensor, target: &Tensor) -> Result<Vec<f32>, BellandeError> {
+        validate_signs(prediction, target)?;
+        Ok(self
+            .violations(prediction, target)
+            .map(|(v, _)| if self.squared { v * v } else { v })
+            .collect())
+    }
+
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let losses = self.losses(prediction, target)?;
+        Ok(reduce(
+            losses,
+            prediction.shape.clone(),
+            self.reduction,
+            prediction,
+        ))
+    }
+
+    /// Gradient `-y` for violated margins, or `-2 * y * violation` when
+    /// squared, and 0 elsewhere
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate_signs(prediction, target)?;
+        let scale = grad_scale(self.reduction, prediction.data.len());
+        let grad = self
+            .violations(prediction, target)
+            .map(|(v, y)| match (v > 0.0, self.squared) {
+                (false, _) => 0.0,
+                (true, false) => -y * scale,
+                (true, true) => -2.0 * y * v * scale,
+            })
+            .collect();
+        Ok(gradient(grad, prediction))
+    }
+}
+
+impl Loss for HingeLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        HingeLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        HingeLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "HingeLoss"
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Weights every element of a sample by the sample's weight
+impl WeightedLoss for HingeLoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let mut losses = self.losses(output, target)?;
+        let weights = expand_sample_weights(weights, losses.len())?;
+        losses.iter_mut().zip(weights).for_each(|(l, w)| *l *= w);
+        Ok(reduce(losses, output.shape.clone(), self.reduction, output))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(self.backward(output, target)?, weights)
+    }
+}
+
+impl HingeEmbeddingLoss {
+    pub fn new(reduction: Reduction, margin: f32) -> Self {
+        HingeEmbeddingLoss { reduction, margin }
+    }
+
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate_signs(prediction, target)?;
+        let losses = prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&x, &y)| {
+                if y > 0.0 {
+                    x
+                } else {
+                    (self.margin - x).max(0.0)
+                }
+            })
+            .collect();
+        Ok(reduce(
+            losses,
+            prediction.shape.clone(),
+            self.reduction,
+            prediction,
+        ))
+    }
+
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate_signs(prediction, target)?;
+        let scale = grad_scale(self.reduction, prediction.data.len());
+        let grad = prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&x, &y)| {
+                if y > 0.0 {
+                    scale
+                } else if self.margin - x > 0.0 {
+                    -scale
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        Ok(gradient(grad, prediction))
+    }
+}
+
+impl SoftMarginLoss {
+    pub fn new(reduction: Reduction) -> Self {
+        SoftMarginLoss { reduction }
+    }
+
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate_signs(prediction, target)?;
+        let losses = prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&x, &y)| softplus_scalar(-y * x))
+            .collect();
+        Ok(reduce(
+            losses,
+            prediction.shape.clone(),
+            self.reduction,
+            prediction,
+        ))
+    }
+
+    /// Gradient `-y * sigmoid(-y * x)`
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        validate_signs(prediction, target)?;
+        let scale = grad_scale(self.reduction, prediction.data.len());
+        let grad = prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&x, &y)| -y * sigmoid(-y * x) * scale)
+            .collect();
+        Ok(gradient(grad, prediction))
+    }
+}
+
+impl MultiMarginLoss {
+    /// `p` must be 1 or 2
+    pub fn new(
+        reduction: Reduction,
+        p: u32,
+        margin: f32,
+        weight: Option<Tensor>,
+    ) -> Result<Self, BellandeError> {
+        if p != 1 && p != 2 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "MultiMarginLoss supports p = 1 or 2, got {}",
+                p
+            )));
+        }
+        Ok(MultiMarginLoss {
+            reduction,
+            p,
+            margin,
+            weight,
+        })
+    }
+
+    /// Validates the inputs and returns the target class of each sample
+    fn targets(&self, prediction: &Tensor, target: &Tensor) -> Result<Vec<usize>, BellandeError> {
+        if prediction.shape.len() != 2 {
+            return Err(BellandeError::InvalidShape(
+                "Prediction tensor must be 2-dimensional (batch_size, num_classes)".into(),
+            ));
+        }
+        let (batch_size, num_classes) = (prediction.shape[0], prediction.shape[1]);
+        if target.data.len() != batch_size {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Target of shape {:?} must hold one class index per sample of a batch of {}",
+                target.shape, batch_size
+            )));
+        }
+        if let Some(ref weight) = self.weight {
+            if weight.data.len() != num_classes {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Got {} class weights for {} classes",
+                    weight.data.len(),
+                    num_classes
+                )));
+            }
+        }
+
+        target
+            .data
+            .iter()
+            .map(|&value| {
+                let class = value.round();
+                if class < 0.0 || class as usize >= num_classes {
+                    Err(BellandeError::InvalidParameter(format!(
+                        "Target class {} is out of range (0, {})",
+                        class,
+                        num_classes - 1
+                    )))
+                } else {
+                    Ok(class as usize)
+                }
+            })
+            .collect()
+    }
+
+    fn class_weight(&self, class: usize) -> f32 {
+        self.weight.as_ref().map_or(1.0, |w| w.data[class])
+    }
+
+    /// Unreduced per-sample losses
+    fn losses(&self, prediction: &Tensor, target: &Tensor) -> Result<Vec<f32>, BellandeError> {
+        let targets = self.targets(prediction, target)?;
+        let num_classes = prediction.shape[1];
+
+        Ok(prediction
+            .data
+            .chunks(num_classes)
+            .zip(&targets)
+            .map(|(row, &y)| {
+                let sum: f32 = row
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| i != y)
+                    .map(|(_, &x)| (self.margin - row[y] + x).max(0.0).powi(self.p as i32))
+                    .sum();
+                self.class_weight(y) * sum / num_classes as f32
+            })
+            .collect())
+    }
+
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let losses = self.losses(prediction, target)?;
+        let batch_size = losses.len();
+        Ok(reduce(losses, vec![batch_size], self.reduction, prediction))
+    }
+
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let targets = self.targets(prediction, target)?;
+        let num_classes = prediction.shape[1];
+        let scale = grad_scale(self.reduction, targets.len());
+
+        let mut grad = vec![0.0; prediction.data.len()];
+        for ((grad_row, row), &y) in grad
+            .chunks_mut(num_classes)
+            .zip(prediction.data.chunks(num_classes))
+            .zip(&targets)
+        {
+            let factor = self.class_weight(y) * scale / num_classes as f32;
+            for i in (0..num_classes).filter(|&i| i != y) {
+                let violation = self.margin - row[y] + row[i];
+                if violation > 0.0 {
+                    let d = factor * self.p as f32 * violation.powi(self.p as i32 - 1);
+                    grad_row[i] += d;
+                    grad_row[y] -= d;
+                }
+            }
+        }
+        Ok(gradient(grad, prediction))
+    }
+}
+
+impl Loss for MultiMarginLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        MultiMarginLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        MultiMarginLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "MultiMarginLoss"
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Multiplies each sample's loss by its weight before the reduction
+impl WeightedLoss for MultiMarginLoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let mut losses = self.losses(output, target)?;
+        let weights = expand_sample_weights(weights, losses.len())?;
+        losses.iter_mut().zip(weights).for_each(|(l, w)| *l *= w);
+        let batch_size = losses.len();
+        Ok(reduce(losses, vec![batch_size], self.reduction, output))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(self.backward(output, target)?, weights)
+    }
+}
+
+impl ClassWeightedLoss for MultiMarginLoss {
+    fn set_class_weights(&mut self, weights: Tensor) -> Result<(), BellandeError> {
+        if let Some(&value) = weights.data.iter().find(|w| !(**w >= 0.0 && w.is_finite())) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Class weights must be finite and non-negative, got {}",
+                value
+            )));
+        }
+        self.weight = Some(weights);
+        Ok(())
+    }
+
+    fn get_class_weights(&self) -> Option<&Tensor> {
+        self.weight.as_ref()
+    }
+}
@@ -14,11 +14,22 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use std::collections::HashMap;
 
 pub mod bce;
 pub mod cross_entropy;
 pub mod custom;
+pub mod dice;
+pub mod divergence;
+pub mod embedding;
+pub mod focal;
+pub mod huber;
+pub mod margin;
 pub mod mse;
+pub mod multi_task;
+pub mod nll;
+pub mod probabilistic;
+pub mod ranking;
 
 /// The Loss trait defines the interface for loss functions used in training neural networks.
 pub trait Loss: Send + Sync {
@@ -34,6 +45,18 @@ pub trait Loss: Send + Sync {
     fn reduction(&self) -> Reduction {
         Reduction::Mean
     }
+
+    /// Values of the last `forward` call to log alongside the loss, such as
+    /// the components of a combined loss
+    fn metrics(&self) -> HashMap<String, f32> {
+        HashMap::new()
+    }
+
+    /// The loss as a `WeightedLoss`, for losses that accept per-sample
+    /// weights. The `Trainer` uses it to apply `Dataset::sample_weight`.
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        None
+    }
 }
 
 /// Enumeration of possible reduction methods for loss functions
@@ -81,22 +104,79 @@ pub mod utils {
 
     /// Validates input shapes for loss computation
     pub fn validate_shapes(output: &Tensor, target: &Tensor) -> Result<(), BellandeError> {
-        if output.shape() != target.shape() {
+        if output.shape != target.shape {
             return Err(BellandeError::ShapeMismatch(format!(
                 "Output shape {:?} doesn't match target shape {:?}",
-                output.shape(),
-                target.shape()
+                output.shape, target.shape
             )));
         }
         Ok(())
     }
 
-    /// Applies reduction method to loss values
-    pub fn apply_reduction(loss: Tensor, reduction: Reduction) -> Result<Tensor, BellandeError> {
+    /// Expands (batch_size) sample weights to one weight per element of a
+    /// tensor with `len` elements whose leading dimension is the batch
+    pub fn expand_sample_weights(weights: &Tensor, len: usize) -> Result<Vec<f32>, BellandeError> {
+        let batch_size = weights.data.len();
+        if batch_size == 0 || len % batch_size != 0 {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Got {} sample weights for a batch of {} elements",
+                batch_size, len
+            )));
+        }
+        let per_sample = len / batch_size;
+        Ok(weights
+            .data
+            .iter()
+            .flat_map(|&w| std::iter::repeat(w).take(per_sample))
+            .collect())
+    }
+
+    /// Multiplies every element of a gradient by its sample's weight
+    pub fn apply_sample_weights(
+        mut grad: Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let expanded = expand_sample_weights(weights, grad.data.len())?;
+        grad.data
+            .iter_mut()
+            .zip(expanded)
+            .for_each(|(g, w)| *g *= w);
+        Ok(grad)
+    }
+
+    /// Reduces per-element losses of a tensor shaped like `like`
+    pub fn reduce(
+        losses: Vec<f32>,
+        shape: Vec<usize>,
+        reduction: Reduction,
+        like: &Tensor,
+    ) -> Tensor {
+        let (data, shape) = match reduction {
+            Reduction::None => (losses, shape),
+            Reduction::Mean => (
+                vec![losses.iter().sum::<f32>() / losses.len().max(1) as f32],
+                vec![1],
+            ),
+            Reduction::Sum => (vec![losses.iter().sum()], vec![1]),
+        };
+        Tensor::new(data, shape, true, like.device.clone(), like.dtype)
+    }
+
+    /// Factor applied to the gradient of each of `count` reduced terms
+    pub fn grad_scale(reduction: Reduction, count: usize) -> f32 {
         match reduction {
-            Reduction::None => Ok(loss),
-            Reduction::Mean => loss.mean(),
-            Reduction::Sum => loss.sum(),
+            Reduction::Mean => 1.0 / count.max(1) as f32,
+            Reduction::Sum | Reduction::None => 1.0,
         }
     }
+
+    /// Applies reduction method to loss values
+    pub fn apply_reduction(loss: Tensor, reduction: Reduction) -> Result<Tensor, BellandeError> {
+        Ok(reduce(
+            loss.data.clone(),
+            loss.shape.clone(),
+            reduction,
+            &loss,
+        ))
+    }
 }
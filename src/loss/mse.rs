@@ -14,8 +14,10 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
-use crate::loss::bce::Reduction;
+use crate::loss::utils::{apply_sample_weights, expand_sample_weights, grad_scale, reduce};
+use crate::loss::{Loss, Reduction, WeightedLoss};
 
+/// Squared error `(prediction - target)^2` per element
 pub struct MSELoss {
     reduction: Reduction,
 }
@@ -25,38 +27,87 @@ impl MSELoss {
         MSELoss { reduction }
     }
 
-    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+    /// Unreduced element-wise losses
+    fn losses(&self, prediction: &Tensor, target: &Tensor) -> Result<Vec<f32>, BellandeError> {
         if prediction.shape != target.shape {
             return Err(BellandeError::DimensionMismatch);
         }
+        Ok(prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(p, t)| (p - t).powi(2))
+            .collect())
+    }
+}
 
-        let mut loss = Vec::with_capacity(prediction.data.len());
-        for (pred, tgt) in prediction.data.iter().zip(target.data.iter()) {
-            loss.push((pred - tgt).powi(2));
-        }
+impl Default for MSELoss {
+    fn default() -> Self {
+        MSELoss::new(Reduction::Mean)
+    }
+}
 
-        match self.reduction {
-            Reduction::None => Ok(Tensor::new(
-                loss,
-                prediction.shape.clone(),
-                true,
-                prediction.device.clone(),
-                prediction.dtype,
-            )),
-            Reduction::Mean => Ok(Tensor::new(
-                vec![loss.iter().sum::<f32>() / loss.len() as f32],
-                vec![1],
-                true,
-                prediction.device.clone(),
-                prediction.dtype,
-            )),
-            Reduction::Sum => Ok(Tensor::new(
-                vec![loss.iter().sum()],
-                vec![1],
-                true,
-                prediction.device.clone(),
-                prediction.dtype,
-            )),
+impl Loss for MSELoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let losses = self.losses(output, target)?;
+        Ok(reduce(losses, output.shape.clone(), self.reduction, output))
+    }
+
+    /// Gradient `2 * (prediction - target)`, scaled like the reduction
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        if output.shape != target.shape {
+            return Err(BellandeError::DimensionMismatch);
         }
+        let scale = 2.0 * grad_scale(self.reduction, output.data.len());
+        let grad = output
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(p, t)| (p - t) * scale)
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            output.shape.clone(),
+            true,
+            output.device.clone(),
+            output.dtype,
+        ))
+    }
+
+    fn name(&self) -> &str {
+        "MSELoss"
+    }
+
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Weights every element of a sample by the sample's weight; the mean
+/// reduction still divides by the number of elements
+impl WeightedLoss for MSELoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let mut losses = self.losses(output, target)?;
+        let weights = expand_sample_weights(weights, losses.len())?;
+        losses.iter_mut().zip(weights).for_each(|(l, w)| *l *= w);
+        Ok(reduce(losses, output.shape.clone(), self.reduction, output))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(self.backward(output, target)?, weights)
     }
 }
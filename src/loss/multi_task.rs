@@ -0,0 +1,318 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::loss::Loss;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// One head of a `MultiTaskLoss`
+struct Task {
+    name: String,
+    loss: Box<dyn Loss>,
+    weight: f32,
+    /// Columns of the (batch_size, features) output and target
+    outputs: Range<usize>,
+    targets: Range<usize>,
+}
+
+/// Weighted sum of named losses for multi-head models whose heads are
+/// concatenated along the last dimension of a (batch_size, features)
+/// output. Each task reads its own columns of the output and target; a
+/// single column is passed as a (batch_size) tensor, so e.g. a class index
+/// column suits `CrossEntropyLoss`. Task losses must reduce to a scalar.
+///
+/// With uncertainty weighting (Kendall et al., 2018) each task also learns
+/// a log-variance `s`, and contributes `weight * (exp(-s) * loss + s)`.
+/// Every `backward` call takes a gradient step on the log-variances, since
+/// they are not model parameters.
+pub struct MultiTaskLoss {
+    tasks: Vec<Task>,
+    /// Learning rate of the log-variances, with uncertainty weighting
+    uncertainty_lr: Option<f32>,
+    log_variances: Mutex<Vec<f32>>,
+    /// Components of the last `forward`, reported by `metrics`
+    last_components: Mutex<HashMap<String, f32>>,
+}
+
+impl MultiTaskLoss {
+    pub fn new() -> Self {
+        MultiTaskLoss {
+            tasks: Vec::new(),
+            uncertainty_lr: None,
+            log_variances: Mutex::new(Vec::new()),
+            last_components: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a task with weight 1 reading the `outputs` columns of the model
+    /// output and the `targets` columns of the target
+    pub fn with_task(
+        mut self,
+        name: impl Into<String>,
+        loss: Box<dyn Loss>,
+        outputs: Range<usize>,
+        targets: Range<usize>,
+    ) -> Result<Self, BellandeError> {
+        let name = name.into();
+        if self.tasks.iter().any(|task| task.name == name) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Duplicate task '{}'",
+                name
+            )));
+        }
+        if outputs.is_empty() || targets.is_empty() {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Task '{}' needs at least one output and one target column",
+                name
+            )));
+        }
+        self.tasks.push(Task {
+            name,
+            loss,
+            weight: 1.0,
+            outputs,
+            targets,
+        });
+        self.lock_log_variances()?.push(0.0);
+        Ok(self)
+    }
+
+    /// Fixed weight of a task's loss
+    pub fn with_task_weight(mut self, name: &str, weight: f32) -> Result<Self, BellandeError> {
+        if !(weight >= 0.0 && weight.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Task weight must be finite and non-negative, got {}",
+                weight
+            )));
+        }
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.name == name)
+            .ok_or_else(|| BellandeError::InvalidParameter(format!("Unknown task '{}'", name)))?;
+        task.weight = weight;
+        Ok(self)
+    }
+
+    /// Learns a log-variance per task, starting at 0, with plain gradient
+    /// descent at `learning_rate`
+    pub fn with_uncertainty_weighting(mut self, learning_rate: f32) -> Result<Self, BellandeError> {
+        if !(learning_rate > 0.0 && learning_rate.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Learning rate must be positive, got {}",
+                learning_rate
+            )));
+        }
+        self.uncertainty_lr = Some(learning_rate);
+        Ok(self)
+    }
+
+    pub fn task_names(&self) -> Vec<&str> {
+        self.tasks.iter().map(|task| task.name.as_str()).collect()
+    }
+
+    /// Learned log-variance of every task, in task order; all 0 without
+    /// uncertainty weighting
+    pub fn log_variances(&self) -> Result<Vec<f32>, BellandeError> {
+        Ok(self.lock_log_variances()?.clone())
+    }
+
+    fn lock_log_variances(&self) -> Result<std::sync::MutexGuard<'_, Vec<f32>>, BellandeError> {
+        self.log_variances
+            .lock()
+            .map_err(|_| BellandeError::RuntimeError("Task weight lock poisoned".into()))
+    }
+
+    /// Factor applied to a task's loss: its weight, times `exp(-s)` with
+    /// uncertainty weighting
+    fn scale(&self, task: &Task, log_variance: f32) -> f32 {
+        match self.uncertainty_lr {
+            Some(_) => task.weight * (-log_variance).exp(),
+            None => task.weight,
+        }
+    }
+
+    /// Task loss as a scalar
+    fn task_loss(
+        &self,
+        task: &Task,
+        output: &Tensor,
+        target: &Tensor,
+    ) -> Result<f32, BellandeError> {
+        let loss = task.loss.forward(
+            &columns(output, &task.outputs)?,
+            &columns(target, &task.targets)?,
+        )?;
+        match loss.data.as_slice() {
+            [value] => Ok(*value),
+            _ => Err(BellandeError::InvalidConfiguration(format!(
+                "Loss of task '{}' must reduce to a scalar, got shape {:?}",
+                task.name, loss.shape
+            ))),
+        }
+    }
+
+    pub fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        check_batches(output, target)?;
+        let log_variances = self.log_variances()?;
+        let mut total = 0.0;
+        let mut components = HashMap::new();
+        for (task, &s) in self.tasks.iter().zip(&log_variances) {
+            let loss = self.task_loss(task, output, target)?;
+            total += self.scale(task, s) * loss;
+            if self.uncertainty_lr.is_some() {
+                total += task.weight * s;
+                components.insert(format!("log_variance/{}", task.name), s);
+            }
+            components.insert(format!("loss/{}", task.name), loss);
+        }
+        *self
+            .last_components
+            .lock()
+            .map_err(|_| BellandeError::RuntimeError("Task metrics lock poisoned".into()))? =
+            components;
+
+        Ok(Tensor::new(
+            vec![total],
+            vec![1],
+            true,
+            output.device.clone(),
+            output.dtype,
+        ))
+    }
+
+    /// Gradient with respect to the output: each task's loss gradient,
+    /// scaled by the task's factor, in its own columns and zero in columns
+    /// no task reads. With uncertainty weighting, also updates the
+    /// log-variances along `weight * (1 - exp(-s) * loss)`.
+    pub fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let (batch_size, features) = check_batches(output, target)?;
+        let mut log_variances = self.lock_log_variances()?;
+        let mut grad = vec![0.0; output.data.len()];
+
+        for (task, s) in self.tasks.iter().zip(log_variances.iter_mut()) {
+            let task_output = columns(output, &task.outputs)?;
+            let task_target = columns(target, &task.targets)?;
+            let task_grad = task.loss.backward(&task_output, &task_target)?;
+            if task_grad.data.len() != task_output.data.len() {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Gradient of task '{}' has shape {:?}, expected {:?}",
+                    task.name, task_grad.shape, task_output.shape
+                )));
+            }
+
+            let scale = self.scale(task, *s);
+            let width = task.outputs.len();
+            for (row, values) in task_grad.data.chunks(width).enumerate().take(batch_size) {
+                let start = row * features + task.outputs.start;
+                for (g, &value) in grad[start..start + width].iter_mut().zip(values) {
+                    *g += scale * value;
+                }
+            }
+
+            if let Some(learning_rate) = self.uncertainty_lr {
+                let loss = self.task_loss(task, output, target)?;
+                *s -= learning_rate * task.weight * (1.0 - (-*s).exp() * loss);
+            }
+        }
+
+        Ok(Tensor::new(
+            grad,
+            output.shape.clone(),
+            true,
+            output.device.clone(),
+            output.dtype,
+        ))
+    }
+}
+
+impl Default for MultiTaskLoss {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Loss for MultiTaskLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        MultiTaskLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        MultiTaskLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "MultiTaskLoss"
+    }
+
+    /// Unweighted loss of every task, keyed `loss/<task>`, and the
+    /// log-variances keyed `log_variance/<task>` with uncertainty weighting
+    fn metrics(&self) -> HashMap<String, f32> {
+        self.last_components
+            .lock()
+            .map(|components| components.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Checks that output and target are (batch_size, columns) tensors of the
+/// same batch, returning the batch size and output width
+fn check_batches(output: &Tensor, target: &Tensor) -> Result<(usize, usize), BellandeError> {
+    if output.shape.len() != 2 || target.shape.is_empty() || target.shape.len() > 2 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected (batch_size, features) output and target, got {:?} and {:?}",
+            output.shape, target.shape
+        )));
+    }
+    if output.shape[0] != target.shape[0] {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Output batch {} and target batch {} differ",
+            output.shape[0], target.shape[0]
+        )));
+    }
+    Ok((output.shape[0], output.shape[1]))
+}
+
+/// Columns `range` of a (batch_size, width) tensor, or of a (batch_size)
+/// tensor read as one column. A single column is returned as (batch_size).
+fn columns(tensor: &Tensor, range: &Range<usize>) -> Result<Tensor, BellandeError> {
+    let batch_size = tensor.shape[0];
+    let width = tensor.shape.get(1).copied().unwrap_or(1);
+    if range.end > width {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Columns {:?} are out of range for shape {:?}",
+            range, tensor.shape
+        )));
+    }
+    let data = tensor
+        .data
+        .chunks(width.max(1))
+        .flat_map(|row| row[range.clone()].iter().copied())
+        .collect();
+    let shape = if range.len() == 1 {
+        vec![batch_size]
+    } else {
+        vec![batch_size, range.len()]
+    };
+    Ok(Tensor::new(
+        data,
+        shape,
+        tensor.requires_grad,
+        tensor.device.clone(),
+        tensor.dtype,
+    ))
+}
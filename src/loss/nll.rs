@@ -0,0 +1,159 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::loss::cross_entropy::NllTerms;
+use crate::loss::utils::{apply_sample_weights, expand_sample_weights};
+use crate::loss::{ClassWeightedLoss, Loss, Reduction, WeightedLoss};
+
+/// Negative log-likelihood loss on log-probabilities, e.g. the output of a
+/// `LogSoftmax` layer, with the same class weights and ignored indices as
+/// `CrossEntropyLoss`. A `LogSoftmax` followed by `NLLLoss` computes the
+/// cross entropy of the logits.
+pub struct NLLLoss {
+    reduction: Reduction,
+    weight: Option<Tensor>,
+    ignore_index: Option<i64>,
+}
+
+impl NLLLoss {
+    pub fn new(reduction: Reduction, weight: Option<Tensor>, ignore_index: Option<i64>) -> Self {
+        NLLLoss {
+            reduction,
+            weight,
+            ignore_index,
+        }
+    }
+
+    /// Forward pass on (batch_size, num_classes) log-probabilities and a
+    /// (batch_size) tensor of class indices, reduced like
+    /// `CrossEntropyLoss::forward`
+    pub fn forward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let terms = self.terms(prediction, target)?;
+        Ok(terms.reduce(
+            &prediction.data,
+            prediction.shape[1],
+            self.reduction,
+            prediction,
+            None,
+        ))
+    }
+
+    /// Gradient with respect to the log-probabilities: `-w[y]` at each
+    /// sample's target class, scaled like the forward reduction
+    pub fn backward(&self, prediction: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let terms = self.terms(prediction, target)?;
+        let num_classes = prediction.shape[1];
+        let scale = terms.scale(self.reduction);
+
+        let mut grad = vec![0.0; prediction.data.len()];
+        for ((row, target), &weight) in grad
+            .chunks_mut(num_classes.max(1))
+            .zip(&terms.targets)
+            .zip(&terms.weights)
+        {
+            if let Some(class) = target {
+                row[*class] = -weight * scale;
+            }
+        }
+
+        Ok(Tensor::new(
+            grad,
+            prediction.shape.clone(),
+            true,
+            prediction.device.clone(),
+            prediction.dtype,
+        ))
+    }
+
+    fn terms(&self, prediction: &Tensor, target: &Tensor) -> Result<NllTerms, BellandeError> {
+        NllTerms::new(
+            prediction,
+            target,
+            self.weight.as_ref(),
+            self.ignore_index,
+            0.0,
+        )
+    }
+}
+
+impl Loss for NLLLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        NLLLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        NLLLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "NLLLoss"
+    }
+
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Multiplies each sample's loss by its (batch_size) weight on top of the
+/// class weights, with the same normalizer as `CrossEntropyLoss`
+impl WeightedLoss for NLLLoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let terms = self.terms(output, target)?;
+        let sample_weights = expand_sample_weights(weights, terms.targets.len())?;
+        Ok(terms.reduce(
+            &output.data,
+            output.shape[1],
+            self.reduction,
+            output,
+            Some(&sample_weights),
+        ))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(NLLLoss::backward(self, output, target)?, weights)
+    }
+}
+
+impl ClassWeightedLoss for NLLLoss {
+    fn set_class_weights(&mut self, weights: Tensor) -> Result<(), BellandeError> {
+        if let Some(&value) = weights.data.iter().find(|w| !(**w >= 0.0 && w.is_finite())) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Class weights must be finite and non-negative, got {}",
+                value
+            )));
+        }
+        self.weight = Some(weights);
+        Ok(())
+    }
+
+    fn get_class_weights(&self) -> Option<&Tensor> {
+        self.weight.as_ref()
+    }
+}
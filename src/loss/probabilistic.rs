@@ -0,0 +1,394 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, tensor::Tensor};
+use crate::loss::utils::{apply_sample_weights, expand_sample_weights, grad_scale, reduce};
+use crate::loss::{Loss, Reduction, WeightedLoss};
+use std::f32::consts::PI;
+
+/// Negative log-likelihood of count targets `t` under a Poisson
+/// distribution with rate `lambda`: `lambda - t * ln(lambda)`. The
+/// prediction is `ln(lambda)` by default, or `lambda` itself without
+/// `with_log_input`.
+pub struct PoissonNLLLoss {
+    reduction: Reduction,
+    log_input: bool,
+    full: bool,
+    eps: f32,
+}
+
+impl PoissonNLLLoss {
+    /// Poisson loss on log-rate predictions, without the Stirling term
+    pub fn new(reduction: Reduction) -> Self {
+        PoissonNLLLoss {
+            reduction,
+            log_input: true,
+            full: false,
+            eps: numerics().log_eps,
+        }
+    }
+
+    /// Whether predictions are log-rates (`true`) or rates. Rates are
+    /// offset by `eps` before taking their log.
+    pub fn with_log_input(mut self, log_input: bool) -> Self {
+        self.log_input = log_input;
+        self
+    }
+
+    /// Adds the Stirling approximation of `ln(t!)`, `t * ln(t) - t +
+    /// 0.5 * ln(2 * pi * t)`, for targets above 1, so the loss is the full
+    /// negative log-likelihood. It does not change the gradient.
+    pub fn with_full(mut self, full: bool) -> Self {
+        self.full = full;
+        self
+    }
+
+    pub fn with_eps(mut self, eps: f32) -> Result<Self, BellandeError> {
+        if !(eps > 0.0 && eps.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "eps must be positive, got {}",
+                eps
+            )));
+        }
+        self.eps = eps;
+        Ok(self)
+    }
+
+    /// Unreduced element-wise losses
+    fn losses(&self, prediction: &Tensor, target: &Tensor) -> Result<Vec<f32>, BellandeError> {
+        if prediction.shape != target.shape {
+            return Err(BellandeError::DimensionMismatch);
+        }
+        Ok(prediction
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&x, &t)| {
+                let loss = if self.log_input {
+                    x.exp() - t * x
+                } else {
+                    x - t * (x + self.eps).ln()
+                };
+                if self.full && t > 1.0 {
+                    loss + t * t.ln() - t + 0.5 * (2.0 * PI * t).ln()
+                } else {
+                    loss
+                }
+            })
+            .collect())
+    }
+}
+
+impl Loss for PoissonNLLLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let losses = self.losses(output, target)?;
+        Ok(reduce(losses, output.shape.clone(), self.reduction, output))
+    }
+
+    /// Gradient `exp(x) - t` for log-rates and `1 - t / (x + eps)` for
+    /// rates
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        if output.shape != target.shape {
+            return Err(BellandeError::DimensionMismatch);
+        }
+        let scale = grad_scale(self.reduction, output.data.len());
+        let grad = output
+            .data
+            .iter()
+            .zip(target.data.iter())
+            .map(|(&x, &t)| {
+                let g = if self.log_input {
+                    x.exp() - t
+                } else {
+                    1.0 - t / (x + self.eps)
+                };
+                g * scale
+            })
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            output.shape.clone(),
+            true,
+            output.device.clone(),
+            output.dtype,
+        ))
+    }
+
+    fn name(&self) -> &str {
+        "PoissonNLLLoss"
+    }
+
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Weights every element of a sample by the sample's weight; the mean
+/// reduction still divides by the number of elements
+impl WeightedLoss for PoissonNLLLoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let mut losses = self.losses(output, target)?;
+        let weights = expand_sample_weights(weights, losses.len())?;
+        losses.iter_mut().zip(weights).for_each(|(l, w)| *l *= w);
+        Ok(reduce(losses, output.shape.clone(), self.reduction, output))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(self.backward(output, target)?, weights)
+    }
+}
+
+/// Negative log-likelihood of targets under a Gaussian with a predicted
+/// mean and variance, `0.5 * (ln(var) + (mean - t)^2 / var)`, for
+/// regression with input-dependent noise. Variances are clamped below at
+/// `eps`; the model should keep them positive, e.g. with a softplus.
+///
+/// Through `Loss`, the output is a (batch_size, 2, ...) tensor holding the
+/// means and then the variances of each sample, for a (batch_size, ...)
+/// target. `forward_parts` takes them as separate tensors.
+pub struct GaussianNLLLoss {
+    reduction: Reduction,
+    full: bool,
+    eps: f32,
+}
+
+impl GaussianNLLLoss {
+    /// Gaussian loss without the constant `0.5 * ln(2 * pi)`
+    pub fn new(reduction: Reduction) -> Self {
+        GaussianNLLLoss {
+            reduction,
+            full: false,
+            eps: numerics().denom_eps,
+        }
+    }
+
+    /// Adds the constant `0.5 * ln(2 * pi)`, so the loss is the full
+    /// negative log-likelihood
+    pub fn with_full(mut self, full: bool) -> Self {
+        self.full = full;
+        self
+    }
+
+    /// Lower bound applied to the variance
+    pub fn with_eps(mut self, eps: f32) -> Result<Self, BellandeError> {
+        if !(eps > 0.0 && eps.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "eps must be positive, got {}",
+                eps
+            )));
+        }
+        self.eps = eps;
+        Ok(self)
+    }
+
+    /// Loss on separate mean and variance tensors of the target's shape
+    pub fn forward_parts(
+        &self,
+        mean: &Tensor,
+        variance: &Tensor,
+        target: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let losses = self.losses(&mean.data, &variance.data, target)?;
+        Ok(reduce(losses, target.shape.clone(), self.reduction, mean))
+    }
+
+    /// Gradients with respect to the mean and the variance: `(mean - t) /
+    /// var` and `0.5 * (1 / var - (mean - t)^2 / var^2)`, the latter zero
+    /// where the variance was clamped
+    pub fn backward_parts(
+        &self,
+        mean: &Tensor,
+        variance: &Tensor,
+        target: &Tensor,
+    ) -> Result<(Tensor, Tensor), BellandeError> {
+        let (grad_mean, grad_variance) = self.gradients(&mean.data, &variance.data, target)?;
+        let gradient = |data, like: &Tensor| {
+            Tensor::new(
+                data,
+                target.shape.clone(),
+                true,
+                like.device.clone(),
+                like.dtype,
+            )
+        };
+        Ok((gradient(grad_mean, mean), gradient(grad_variance, variance)))
+    }
+
+    fn check(&self, mean: &[f32], variance: &[f32], target: &Tensor) -> Result<(), BellandeError> {
+        if mean.len() != target.data.len() || variance.len() != target.data.len() {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Got {} means and {} variances for {} targets",
+                mean.len(),
+                variance.len(),
+                target.data.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn losses(
+        &self,
+        mean: &[f32],
+        variance: &[f32],
+        target: &Tensor,
+    ) -> Result<Vec<f32>, BellandeError> {
+        self.check(mean, variance, target)?;
+        let constant = if self.full {
+            0.5 * (2.0 * PI).ln()
+        } else {
+            0.0
+        };
+        Ok(mean
+            .iter()
+            .zip(variance)
+            .zip(target.data.iter())
+            .map(|((&m, &v), &t)| {
+                let v = v.max(self.eps);
+                0.5 * (v.ln() + (m - t).powi(2) / v) + constant
+            })
+            .collect())
+    }
+
+    fn gradients(
+        &self,
+        mean: &[f32],
+        variance: &[f32],
+        target: &Tensor,
+    ) -> Result<(Vec<f32>, Vec<f32>), BellandeError> {
+        self.check(mean, variance, target)?;
+        let scale = grad_scale(self.reduction, target.data.len());
+        Ok(mean
+            .iter()
+            .zip(variance)
+            .zip(target.data.iter())
+            .map(|((&m, &v), &t)| {
+                let diff = m - t;
+                let clamped = v.max(self.eps);
+                let grad_variance = if v > self.eps {
+                    0.5 * (1.0 / v - diff * diff / (v * v))
+                } else {
+                    0.0
+                };
+                (diff / clamped * scale, grad_variance * scale)
+            })
+            .unzip())
+    }
+
+    /// Splits a (batch_size, 2, ...) output into its means and variances
+    fn split(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+    ) -> Result<(Vec<f32>, Vec<f32>), BellandeError> {
+        let batch_size = target.shape.first().copied().unwrap_or(1);
+        let per_sample = target.data.len() / batch_size.max(1);
+        if output.shape.first() != target.shape.first()
+            || output.data.len() != 2 * target.data.len()
+        {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected an output of shape ({}, 2, ...) for a target of shape {:?}, got {:?}",
+                batch_size, target.shape, output.shape
+            )));
+        }
+        let mut mean = Vec::with_capacity(target.data.len());
+        let mut variance = Vec::with_capacity(target.data.len());
+        for sample in output.data.chunks(2 * per_sample.max(1)) {
+            let (m, v) = sample.split_at(per_sample);
+            mean.extend_from_slice(m);
+            variance.extend_from_slice(v);
+        }
+        Ok((mean, variance))
+    }
+}
+
+impl Loss for GaussianNLLLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let (mean, variance) = self.split(output, target)?;
+        let losses = self.losses(&mean, &variance, target)?;
+        Ok(reduce(losses, target.shape.clone(), self.reduction, output))
+    }
+
+    /// Gradient with respect to the (batch_size, 2, ...) output
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let (mean, variance) = self.split(output, target)?;
+        let (grad_mean, grad_variance) = self.gradients(&mean, &variance, target)?;
+        let per_sample =
+            (target.data.len() / target.shape.first().copied().unwrap_or(1).max(1)).max(1);
+        let grad = grad_mean
+            .chunks(per_sample)
+            .zip(grad_variance.chunks(per_sample))
+            .flat_map(|(m, v)| m.iter().chain(v).copied())
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            output.shape.clone(),
+            true,
+            output.device.clone(),
+            output.dtype,
+        ))
+    }
+
+    fn name(&self) -> &str {
+        "GaussianNLLLoss"
+    }
+
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    fn as_weighted(&self) -> Option<&dyn WeightedLoss> {
+        Some(self)
+    }
+}
+
+/// Weights every element of a sample by the sample's weight; the mean
+/// reduction still divides by the number of elements
+impl WeightedLoss for GaussianNLLLoss {
+    fn forward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let (mean, variance) = self.split(output, target)?;
+        let mut losses = self.losses(&mean, &variance, target)?;
+        let weights = expand_sample_weights(weights, losses.len())?;
+        losses.iter_mut().zip(weights).for_each(|(l, w)| *l *= w);
+        Ok(reduce(losses, target.shape.clone(), self.reduction, output))
+    }
+
+    fn backward_weighted(
+        &self,
+        output: &Tensor,
+        target: &Tensor,
+        weights: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        apply_sample_weights(self.backward(output, target)?, weights)
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::activation::{log_softmax, softmax};
+use crate::loss::utils::{grad_scale, reduce};
+use crate::loss::Loss;
+use crate::loss::Reduction;
+
+/// Pairwise ranking loss `max(0, -y * (x1 - x2) + margin)`, where `y = 1`
+/// means `x1` should rank above `x2` and `y = -1` the opposite
+pub struct MarginRankingLoss {
+    reduction: Reduction,
+    margin: f32,
+}
+
+/// Listwise softmax (ListNet top-one) loss: the cross-entropy between the
+/// softmax of the relevance labels and the softmax of the scores of each
+/// list. Lists run along the last dimension of the target, so a grouped
+/// `DataLoader` batch of shape (num_groups, group_size) scores every group
+/// separately
+pub struct ListwiseSoftmaxLoss {
+    reduction: Reduction,
+    temperature: f32,
+}
+
+impl MarginRankingLoss {
+    pub fn new(reduction: Reduction, margin: f32) -> Self {
+        MarginRankingLoss { reduction, margin }
+    }
+
+    /// Loss on (batch_size, 2) scores holding `x1` and `x2` of each pair
+    pub fn forward(&self, scores: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let (first, second) = split_pairs(scores, target)?;
+        self.forward_pair(&first, &second, target)
+    }
+
+    /// Gradient with respect to the (batch_size, 2) pair scores
+    pub fn backward(&self, scores: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let (first, second) = split_pairs(scores, target)?;
+        let (grad_first, grad_second) = self.backward_pair(&first, &second, target)?;
+        let grad = grad_first
+            .data
+            .iter()
+            .zip(grad_second.data.iter())
+            .flat_map(|(&g1, &g2)| [g1, g2])
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            scores.shape.clone(),
+            true,
+            scores.device.clone(),
+            scores.dtype,
+        ))
+    }
+
+    pub fn forward_pair(
+        &self,
+        first: &Tensor,
+        second: &Tensor,
+        target: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        validate_pair(first, second, target)?;
+        let losses = self.margins(first, second, target).collect();
+        Ok(reduce(losses, first.shape.clone(), self.reduction, first))
+    }
+
+    /// Gradients with respect to `first` and `second`
+    pub fn backward_pair(
+        &self,
+        first: &Tensor,
+        second: &Tensor,
+        target: &Tensor,
+    ) -> Result<(Tensor, Tensor), BellandeError> {
+        validate_pair(first, second, target)?;
+        let scale = grad_scale(self.reduction, first.data.len());
+        let grad_first: Vec<f32> = self
+            .margins(first, second, target)
+            .zip(target.data.iter())
+            .map(|(loss, &y)| if loss > 0.0 { -y * scale } else { 0.0 })
+            .collect();
+        let grad_second = grad_first.iter().map(|g| -g).collect();
+        let tensor = |grad| {
+            Tensor::new(
+                grad,
+                first.shape.clone(),
+                true,
+                first.device.clone(),
+                first.dtype,
+            )
+        };
+        Ok((tensor(grad_first), tensor(grad_second)))
+    }
+
+    fn margins<'a>(
+        &'a self,
+        first: &'a Tensor,
+        second: &'a Tensor,
+        target: &'a Tensor,
+    ) -> impl Iterator<Item = f32> + 'a {
+        first
+            .data
+            .iter()
+            .zip(second.data.iter())
+            .zip(target.data.iter())
+            .map(move |((&x1, &x2), &y)| (-y * (x1 - x2) + self.margin).max(0.0))
+    }
+}
+
+impl Loss for MarginRankingLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        MarginRankingLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        MarginRankingLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "MarginRankingLoss"
+    }
+}
+
+/// Splits (batch_size, 2) scores into their two columns
+fn split_pairs(scores: &Tensor, target: &Tensor) -> Result<(Tensor, Tensor), BellandeError> {
+    if scores.shape.len() != 2 || scores.shape[1] != 2 {
+        return Err(BellandeError::InvalidShape(format!(
+            "Expected pair scores of shape (batch_size, 2), got {:?}",
+            scores.shape
+        )));
+    }
+    let column = |offset: usize| {
+        Tensor::new(
+            scores
+                .data
+                .iter()
+                .skip(offset)
+                .step_by(2)
+                .copied()
+                .collect(),
+            vec![scores.shape[0]],
+            scores.requires_grad,
+            scores.device.clone(),
+            scores.dtype,
+        )
+    };
+    if target.data.len() != scores.shape[0] {
+        return Err(BellandeError::DimensionMismatch);
+    }
+    Ok((column(0), column(1)))
+}
+
+fn validate_pair(first: &Tensor, second: &Tensor, target: &Tensor) -> Result<(), BellandeError> {
+    if first.data.len() != second.data.len() || first.data.len() != target.data.len() {
+        return Err(BellandeError::DimensionMismatch);
+    }
+    if let Some(&value) = target.data.iter().find(|&&y| y != 1.0 && y != -1.0) {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Targets must be 1 or -1, got {}",
+            value
+        )));
+    }
+    Ok(())
+}
+
+impl ListwiseSoftmaxLoss {
+    pub fn new(reduction: Reduction) -> Self {
+        ListwiseSoftmaxLoss {
+            reduction,
+            temperature: 1.0,
+        }
+    }
+
+    /// Divides the relevance labels by `temperature` before the softmax;
+    /// lower values concentrate the target distribution on the top items
+    pub fn with_temperature(mut self, temperature: f32) -> Result<Self, BellandeError> {
+        if !temperature.is_finite() || temperature <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Temperature must be positive, got {}",
+                temperature
+            )));
+        }
+        self.temperature = temperature;
+        Ok(self)
+    }
+
+    /// One loss per list, reduced over lists
+    pub fn forward(&self, scores: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let list_len = list_length(scores, target)?;
+        let (log_probs, target_probs) = self.lists(scores, target, list_len)?;
+        let losses = log_probs
+            .chunks(list_len)
+            .zip(target_probs.chunks(list_len))
+            .map(|(l, t)| -t.iter().zip(l).map(|(t, l)| t * l).sum::<f32>())
+            .collect();
+        let lists = target.shape[..target.shape.len().saturating_sub(1)].to_vec();
+        let shape = if lists.is_empty() { vec![1] } else { lists };
+        Ok(reduce(losses, shape, self.reduction, scores))
+    }
+
+    /// Gradient `softmax(scores) - softmax(target / temperature)` per list
+    pub fn backward(&self, scores: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        let list_len = list_length(scores, target)?;
+        let scale = grad_scale(self.reduction, scores.data.len() / list_len);
+        let (log_probs, target_probs) = self.lists(scores, target, list_len)?;
+        let grad = log_probs
+            .iter()
+            .zip(&target_probs)
+            .map(|(l, t)| (l.exp() - t) * scale)
+            .collect();
+        Ok(Tensor::new(
+            grad,
+            scores.shape.clone(),
+            true,
+            scores.device.clone(),
+            scores.dtype,
+        ))
+    }
+
+    /// Log-softmax of the scores and softmax of the labels, list by list
+    fn lists(
+        &self,
+        scores: &Tensor,
+        target: &Tensor,
+        list_len: usize,
+    ) -> Result<(Vec<f32>, Vec<f32>), BellandeError> {
+        let rows = |data: Vec<f32>| {
+            Tensor::new(
+                data,
+                vec![scores.data.len() / list_len, list_len],
+                false,
+                scores.device.clone(),
+                scores.dtype,
+            )
+        };
+        let log_probs = log_softmax(&rows(scores.data.clone()), -1)?;
+        let scaled = target.data.iter().map(|r| r / self.temperature).collect();
+        let target_probs = softmax(&rows(scaled), -1)?;
+        Ok((log_probs.data, target_probs.data))
+    }
+}
+
+impl Loss for ListwiseSoftmaxLoss {
+    fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        ListwiseSoftmaxLoss::forward(self, output, target)
+    }
+
+    fn backward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor, BellandeError> {
+        ListwiseSoftmaxLoss::backward(self, output, target)
+    }
+
+    fn name(&self) -> &str {
+        "ListwiseSoftmaxLoss"
+    }
+}
+
+/// Length of each list, i.e. the last dimension of the target
+fn list_length(scores: &Tensor, target: &Tensor) -> Result<usize, BellandeError> {
+    if scores.data.len() != target.data.len() {
+        return Err(BellandeError::DimensionMismatch);
+    }
+    match target.shape.last() {
+        Some(&len) if len > 0 => Ok(len),
+        _ => Err(BellandeError::InvalidShape(format!(
+            "Expected relevance labels with a non-empty last dimension, got {:?}",
+            target.shape
+        ))),
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{numerics::log_sum_exp, tensor::Tensor};
+use crate::metrics::metrics::Metric;
+use std::collections::HashMap;
+
+/// Vocabulary size of (batch, seq_len, vocab_size) logits scored against
+/// (batch, seq_len) target tokens, or `None` when the shapes disagree
+fn vocab_size(prediction: &Tensor, target: &Tensor) -> Option<usize> {
+    let vocab_size = *prediction.shape.last()?;
+    (vocab_size > 0
+        && prediction.shape.len() >= 2
+        && prediction.data.len() == target.data.len() * vocab_size)
+        .then_some(vocab_size)
+}
+
+/// Target tokens with their logits, skipping `pad_token`
+fn scored_tokens<'a>(
+    prediction: &'a Tensor,
+    target: &'a Tensor,
+    vocab_size: usize,
+    pad_token: Option<usize>,
+) -> impl Iterator<Item = (usize, &'a [f32])> + 'a {
+    target
+        .data
+        .iter()
+        .zip(prediction.data.chunks(vocab_size))
+        .map(|(&token, logits)| (token.round() as usize, logits))
+        .filter(move |&(token, _)| Some(token) != pad_token)
+}
+
+fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (i, &v)| {
+            if v > best.1 {
+                (i, v)
+            } else {
+                best
+            }
+        })
+        .0
+}
+
+/// Fraction of target tokens predicted exactly, from (batch, seq_len,
+/// vocab_size) logits and (batch, seq_len) target tokens. Positions whose
+/// target is the padding token are not counted. Batches whose shapes
+/// disagree are ignored.
+pub struct TokenAccuracy {
+    pad_token: Option<usize>,
+    correct: usize,
+    total: usize,
+}
+
+impl TokenAccuracy {
+    pub fn new() -> Self {
+        TokenAccuracy {
+            pad_token: None,
+            correct: 0,
+            total: 0,
+        }
+    }
+
+    pub fn with_pad_token(mut self, pad_token: usize) -> Self {
+        self.pad_token = Some(pad_token);
+        self
+    }
+}
+
+impl Default for TokenAccuracy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metric for TokenAccuracy {
+    fn reset(&mut self) {
+        self.correct = 0;
+        self.total = 0;
+    }
+
+    fn update(&mut self, prediction: &Tensor, target: &Tensor) {
+        let Some(vocab_size) = vocab_size(prediction, target) else {
+            return;
+        };
+        for (token, logits) in scored_tokens(prediction, target, vocab_size, self.pad_token) {
+            self.correct += (argmax(logits) == token) as usize;
+            self.total += 1;
+        }
+    }
+
+    fn compute(&self) -> f32 {
+        self.correct as f32 / self.total.max(1) as f32
+    }
+
+    fn name(&self) -> &str {
+        "token_accuracy"
+    }
+}
+
+/// Perplexity `exp(mean negative log-likelihood per token)` of (batch,
+/// seq_len, vocab_size) logits on (batch, seq_len) target tokens, skipping
+/// padded targets. `update_nll` accumulates a summed NLL directly, e.g. an
+/// `NLLLoss` with the sum reduction. Batches whose shapes disagree and
+/// targets outside the vocabulary are ignored.
+pub struct Perplexity {
+    pad_token: Option<usize>,
+    nll: f64,
+    tokens: usize,
+}
+
+impl Perplexity {
+    pub fn new() -> Self {
+        Perplexity {
+            pad_token: None,
+            nll: 0.0,
+            tokens: 0,
+        }
+    }
+
+    pub fn with_pad_token(mut self, pad_token: usize) -> Self {
+        self.pad_token = Some(pad_token);
+        self
+    }
+
+    /// Adds the negative log-likelihood summed over `tokens` tokens
+    pub fn update_nll(&mut self, nll: f32, tokens: usize) {
+        self.nll += nll as f64;
+        self.tokens += tokens;
+    }
+
+    /// Mean negative log-likelihood per token so far
+    pub fn mean_nll(&self) -> f32 {
+        (self.nll / self.tokens.max(1) as f64) as f32
+    }
+}
+
+impl Default for Perplexity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metric for Perplexity {
+    fn reset(&mut self) {
+        self.nll = 0.0;
+        self.tokens = 0;
+    }
+
+    fn update(&mut self, prediction: &Tensor, target: &Tensor) {
+        let Some(vocab_size) = vocab_size(prediction, target) else {
+            return;
+        };
+        for (token, logits) in scored_tokens(prediction, target, vocab_size, self.pad_token) {
+            if token < vocab_size {
+                self.update_nll(log_sum_exp(logits) - logits[token], 1);
+            }
+        }
+    }
+
+    fn compute(&self) -> f32 {
+        self.mean_nll().exp()
+    }
+
+    fn name(&self) -> &str {
+        "perplexity"
+    }
+}
+
+/// Corpus-level BLEU (Papineni et al., 2002) with one reference per
+/// hypothesis: the geometric mean of the clipped n-gram precisions up to
+/// `max_order`, times the brevity penalty, in `[0, 1]`. N-gram counts are
+/// pooled over the whole corpus before dividing, so the score is not an
+/// average of sentence scores.
+///
+/// `update_tokens` scores token sequences, e.g. from `Transformer::generate`.
+/// Through `Metric::update` the prediction holds either (batch, seq_len)
+/// tokens or (batch, seq_len, vocab_size) logits, decoded greedily. Padding
+/// tokens are dropped and sequences end after the end token when set.
+pub struct CorpusBleu {
+    max_order: usize,
+    pad_token: Option<usize>,
+    eos_token: Option<usize>,
+    /// Clipped matches and candidate n-grams per order
+    matches: Vec<usize>,
+    candidates: Vec<usize>,
+    hypothesis_len: usize,
+    reference_len: usize,
+}
+
+impl CorpusBleu {
+    /// BLEU-4
+    pub fn new() -> Self {
+        Self::with_max_order(4)
+    }
+
+    pub fn with_max_order(max_order: usize) -> Self {
+        let max_order = max_order.max(1);
+        CorpusBleu {
+            max_order,
+            pad_token: None,
+            eos_token: None,
+            matches: vec![0; max_order],
+            candidates: vec![0; max_order],
+            hypothesis_len: 0,
+            reference_len: 0,
+        }
+    }
+
+    pub fn with_pad_token(mut self, pad_token: usize) -> Self {
+        self.pad_token = Some(pad_token);
+        self
+    }
+
+    /// Ignores everything after the first `eos_token`, which is itself
+    /// not scored
+    pub fn with_eos_token(mut self, eos_token: usize) -> Self {
+        self.eos_token = Some(eos_token);
+        self
+    }
+
+    /// Adds hypotheses with their references, pairwise
+    pub fn update_tokens(&mut self, hypotheses: &[Vec<usize>], references: &[Vec<usize>]) {
+        for (hypothesis, reference) in hypotheses.iter().zip(references) {
+            let hypothesis = self.clean(hypothesis.iter().copied());
+            let reference = self.clean(reference.iter().copied());
+            self.add_pair(&hypothesis, &reference);
+        }
+    }
+
+    /// Drops padding and cuts the sequence at the end token
+    fn clean(&self, tokens: impl Iterator<Item = usize>) -> Vec<usize> {
+        tokens
+            .take_while(|&token| Some(token) != self.eos_token)
+            .filter(|&token| Some(token) != self.pad_token)
+            .collect()
+    }
+
+    fn add_pair(&mut self, hypothesis: &[usize], reference: &[usize]) {
+        self.hypothesis_len += hypothesis.len();
+        self.reference_len += reference.len();
+        for n in 1..=self.max_order {
+            let reference_counts = ngram_counts(reference, n);
+            for (ngram, count) in ngram_counts(hypothesis, n) {
+                self.matches[n - 1] += count.min(reference_counts.get(ngram).copied().unwrap_or(0));
+            }
+            self.candidates[n - 1] += hypothesis.len().saturating_sub(n - 1);
+        }
+    }
+}
+
+fn ngram_counts(tokens: &[usize], n: usize) -> HashMap<&[usize], usize> {
+    let mut counts = HashMap::new();
+    for ngram in tokens.windows(n) {
+        *counts.entry(ngram).or_insert(0) += 1;
+    }
+    counts
+}
+
+impl Default for CorpusBleu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metric for CorpusBleu {
+    fn reset(&mut self) {
+        self.matches.fill(0);
+        self.candidates.fill(0);
+        self.hypothesis_len = 0;
+        self.reference_len = 0;
+    }
+
+    fn update(&mut self, prediction: &Tensor, target: &Tensor) {
+        let Some(&seq_len) = target.shape.last() else {
+            return;
+        };
+        let tokens: Vec<usize> = if prediction.data.len() == target.data.len() {
+            prediction
+                .data
+                .iter()
+                .map(|&t| t.round() as usize)
+                .collect()
+        } else if let Some(vocab_size) = vocab_size(prediction, target) {
+            prediction.data.chunks(vocab_size).map(argmax).collect()
+        } else {
+            return;
+        };
+
+        for (hypothesis, reference) in tokens
+            .chunks(seq_len.max(1))
+            .zip(target.data.chunks(seq_len.max(1)))
+        {
+            let hypothesis = self.clean(hypothesis.iter().copied());
+            let reference = self.clean(reference.iter().map(|&t| t.round() as usize));
+            self.add_pair(&hypothesis, &reference);
+        }
+    }
+
+    fn compute(&self) -> f32 {
+        if self.hypothesis_len == 0 || self.matches.contains(&0) {
+            return 0.0;
+        }
+        let log_precision = self
+            .matches
+            .iter()
+            .zip(&self.candidates)
+            .map(|(&m, &c)| (m as f64 / c as f64).ln())
+            .sum::<f64>()
+            / self.max_order as f64;
+        let brevity = if self.hypothesis_len < self.reference_len {
+            1.0 - self.reference_len as f64 / self.hypothesis_len as f64
+        } else {
+            0.0
+        };
+        (log_precision + brevity).exp() as f32
+    }
+
+    fn name(&self) -> &str {
+        "bleu"
+    }
+}
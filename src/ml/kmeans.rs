@@ -0,0 +1,215 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, random, tensor::Tensor};
+use crate::ml::pca::matrix_dims;
+use rayon::prelude::*;
+
+/// k-means clustering with k-means++ initialization.
+///
+/// Initialization draws from the framework's global generator, so results are
+/// reproducible after `core::random::set_seed`.
+pub struct KMeans {
+    n_clusters: usize,
+    max_iter: usize,
+    tol: f32,
+    centroids: Vec<f32>,
+    dim: usize,
+    inertia: f32,
+    n_iter: usize,
+}
+
+impl KMeans {
+    pub fn new(n_clusters: usize) -> Self {
+        KMeans {
+            n_clusters,
+            max_iter: 300,
+            tol: 1e-4,
+            centroids: Vec::new(),
+            dim: 0,
+            inertia: 0.0,
+            n_iter: 0,
+        }
+    }
+
+    pub fn with_max_iter(mut self, max_iter: usize) -> Self {
+        self.max_iter = max_iter;
+        self
+    }
+
+    /// Stops once no centroid moves further than `tol`
+    pub fn with_tol(mut self, tol: f32) -> Self {
+        self.tol = tol;
+        self
+    }
+
+    /// Clusters a (num_samples, dim) tensor and returns each sample's cluster
+    pub fn fit(&mut self, data: &Tensor) -> Result<Vec<usize>, BellandeError> {
+        let (n, dim) = matrix_dims(data)?;
+        if self.n_clusters == 0 || self.n_clusters > n {
+            return Err(BellandeError::InvalidParameter(format!(
+                "n_clusters must be in 1..={}, got {}",
+                n, self.n_clusters
+            )));
+        }
+
+        self.dim = dim;
+        self.centroids = self.init_centroids(&data.data, n, dim);
+
+        self.n_iter = 0;
+        for _ in 0..self.max_iter {
+            self.n_iter += 1;
+            let (assignments, _) = self.assign(&data.data);
+
+            let mut sums = vec![0.0; self.n_clusters * dim];
+            let mut counts = vec![0usize; self.n_clusters];
+            for (row, &cluster) in data.data.chunks(dim).zip(&assignments) {
+                counts[cluster] += 1;
+                for (s, v) in sums[cluster * dim..(cluster + 1) * dim].iter_mut().zip(row) {
+                    *s += v;
+                }
+            }
+
+            let mut shift: f32 = 0.0;
+            for cluster in 0..self.n_clusters {
+                // Empty clusters keep their previous centroid
+                if counts[cluster] == 0 {
+                    continue;
+                }
+                let centroid = &mut self.centroids[cluster * dim..(cluster + 1) * dim];
+                let sum = &sums[cluster * dim..(cluster + 1) * dim];
+                let mut moved = 0.0;
+                for (c, s) in centroid.iter_mut().zip(sum) {
+                    let updated = s / counts[cluster] as f32;
+                    moved += (updated - *c) * (updated - *c);
+                    *c = updated;
+                }
+                shift = shift.max(moved.sqrt());
+            }
+
+            if shift <= self.tol {
+                break;
+            }
+        }
+
+        let (labels, inertia) = self.assign(&data.data);
+        self.inertia = inertia;
+        Ok(labels)
+    }
+
+    /// Assigns each row of a (num_samples, dim) tensor to its nearest centroid
+    pub fn predict(&self, data: &Tensor) -> Result<Vec<usize>, BellandeError> {
+        if self.centroids.is_empty() {
+            return Err(BellandeError::RuntimeError(
+                "KMeans has not been fitted".into(),
+            ));
+        }
+        let (_, dim) = matrix_dims(data)?;
+        if dim != self.dim {
+            return Err(BellandeError::DimensionMismatch);
+        }
+        Ok(self.assign(&data.data).0)
+    }
+
+    /// Centroids with shape (n_clusters, dim)
+    pub fn centroids(&self) -> Option<Tensor> {
+        if self.centroids.is_empty() {
+            return None;
+        }
+        Some(Tensor::new(
+            self.centroids.clone(),
+            vec![self.n_clusters, self.dim],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        ))
+    }
+
+    /// Sum of squared distances of samples to their closest centroid
+    pub fn inertia(&self) -> f32 {
+        self.inertia
+    }
+
+    /// Number of iterations run by the last `fit`
+    pub fn n_iter(&self) -> usize {
+        self.n_iter
+    }
+
+    fn assign(&self, data: &[f32]) -> (Vec<usize>, f32) {
+        let dim = self.dim;
+        let nearest: Vec<(usize, f32)> = data
+            .par_chunks(dim)
+            .map(|row| {
+                self.centroids
+                    .chunks(dim)
+                    .map(|centroid| squared_distance(row, centroid))
+                    .enumerate()
+                    .fold(
+                        (0, f32::INFINITY),
+                        |best, (idx, d)| {
+                            if d < best.1 {
+                                (idx, d)
+                            } else {
+                                best
+                            }
+                        },
+                    )
+            })
+            .collect();
+
+        let inertia = nearest.iter().map(|&(_, d)| d).sum();
+        (nearest.into_iter().map(|(idx, _)| idx).collect(), inertia)
+    }
+
+    /// k-means++: each new centroid is sampled proportionally to its squared
+    /// distance from the closest centroid chosen so far
+    fn init_centroids(&self, data: &[f32], n: usize, dim: usize) -> Vec<f32> {
+        let first = ((random::uniform(0.0, 1.0, 1)[0] * n as f32) as usize).min(n - 1);
+        let mut centroids = data[first * dim..(first + 1) * dim].to_vec();
+        let mut distances: Vec<f32> = data
+            .chunks(dim)
+            .map(|row| squared_distance(row, &centroids))
+            .collect();
+
+        for _ in 1..self.n_clusters {
+            let total: f32 = distances.iter().sum();
+            let chosen = if total > 0.0 {
+                let mut target = random::uniform(0.0, 1.0, 1)[0] * total;
+                distances
+                    .iter()
+                    .position(|&d| {
+                        target -= d;
+                        target <= 0.0
+                    })
+                    .unwrap_or(n - 1)
+            } else {
+                // All remaining points coincide with a centroid
+                ((random::uniform(0.0, 1.0, 1)[0] * n as f32) as usize).min(n - 1)
+            };
+
+            let centroid = &data[chosen * dim..(chosen + 1) * dim];
+            for (d, row) in distances.iter_mut().zip(data.chunks(dim)) {
+                *d = d.min(squared_distance(row, centroid));
+            }
+            centroids.extend_from_slice(centroid);
+        }
+
+        centroids
+    }
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
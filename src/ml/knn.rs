@@ -0,0 +1,238 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, tensor::Tensor};
+use crate::ml::pca::{dot, matrix_dims, PCA};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+
+/// Similarity used to rank neighbors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistanceMetric {
+    Euclidean,
+    Cosine,
+}
+
+/// Brute-force k-nearest-neighbor classifier over embeddings
+pub struct KNNClassifier {
+    k: usize,
+    metric: DistanceMetric,
+    weighted: bool,
+    pca: Option<PCA>,
+    train: Vec<f32>,
+    train_norms: Vec<f32>,
+    labels: Vec<usize>,
+    dim: usize,
+    num_classes: usize,
+}
+
+impl KNNClassifier {
+    pub fn new(k: usize) -> Self {
+        KNNClassifier {
+            k,
+            metric: DistanceMetric::Cosine,
+            weighted: false,
+            pca: None,
+            train: Vec::new(),
+            train_norms: Vec::new(),
+            labels: Vec::new(),
+            dim: 0,
+            num_classes: 0,
+        }
+    }
+
+    pub fn with_metric(mut self, metric: DistanceMetric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Weights each neighbor's vote by its similarity instead of counting votes
+    pub fn with_weighted_votes(mut self, weighted: bool) -> Self {
+        self.weighted = weighted;
+        self
+    }
+
+    /// Reduces embeddings to `n_components` dimensions with PCA before search
+    pub fn with_pca(mut self, n_components: usize) -> Self {
+        self.pca = Some(PCA::new(n_components));
+        self
+    }
+
+    /// Stores the reference embeddings, with shape (num_samples, dim), and labels
+    pub fn fit(&mut self, embeddings: &Tensor, labels: &[usize]) -> Result<(), BellandeError> {
+        let (n, _) = matrix_dims(embeddings)?;
+        if n != labels.len() {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Got {} labels for {} embeddings",
+                labels.len(),
+                n
+            )));
+        }
+        if self.k == 0 || self.k > n {
+            return Err(BellandeError::InvalidParameter(format!(
+                "k must be in 1..={}, got {}",
+                n, self.k
+            )));
+        }
+
+        let reduced = match self.pca {
+            Some(ref mut pca) => pca.fit_transform(embeddings)?,
+            None => embeddings.clone(),
+        };
+
+        self.dim = reduced.shape[1];
+        self.train_norms = reduced
+            .data
+            .chunks(self.dim.max(1))
+            .map(|row| dot(row, row).sqrt())
+            .collect();
+        self.train = reduced.data;
+        self.labels = labels.to_vec();
+        self.num_classes = labels.iter().max().map_or(0, |&max| max + 1);
+        Ok(())
+    }
+
+    /// Returns the indices and similarities of the k nearest reference samples
+    /// for each query, closest first
+    pub fn kneighbors(&self, queries: &Tensor) -> Result<Vec<Vec<(usize, f32)>>, BellandeError> {
+        if self.labels.is_empty() {
+            return Err(BellandeError::RuntimeError(
+                "KNNClassifier has not been fitted".into(),
+            ));
+        }
+
+        let reduced = match self.pca {
+            Some(ref pca) => pca.transform(queries)?,
+            None => queries.clone(),
+        };
+        let (_, dim) = matrix_dims(&reduced)?;
+        if dim != self.dim {
+            return Err(BellandeError::DimensionMismatch);
+        }
+
+        Ok(reduced
+            .data
+            .par_chunks(dim.max(1))
+            .map(|query| {
+                let query_norm = dot(query, query).sqrt();
+                let mut scored: Vec<(usize, f32)> = self
+                    .train
+                    .chunks(dim.max(1))
+                    .zip(&self.train_norms)
+                    .enumerate()
+                    .map(|(idx, (row, &norm))| (idx, self.similarity(query, query_norm, row, norm)))
+                    .collect();
+
+                let k = self.k.min(scored.len());
+                scored.select_nth_unstable_by(k - 1, |a, b| {
+                    b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal)
+                });
+                scored.truncate(k);
+                scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+                scored
+            })
+            .collect())
+    }
+
+    /// Returns per-class vote shares with shape (num_queries, num_classes)
+    pub fn predict_proba(&self, queries: &Tensor) -> Result<Tensor, BellandeError> {
+        let neighbors = self.kneighbors(queries)?;
+        let output = neighbors
+            .iter()
+            .flat_map(|neighbors| self.votes(neighbors))
+            .collect();
+
+        Ok(Tensor::new(
+            output,
+            vec![neighbors.len(), self.num_classes],
+            false,
+            queries.device.clone(),
+            queries.dtype,
+        ))
+    }
+
+    /// Predicts a class per query; ties go to the class of the nearest neighbor
+    pub fn predict(&self, queries: &Tensor) -> Result<Vec<usize>, BellandeError> {
+        Ok(self
+            .kneighbors(queries)?
+            .iter()
+            .map(|neighbors| {
+                let votes = self.votes(neighbors);
+                let best = votes.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                neighbors
+                    .iter()
+                    .map(|&(idx, _)| self.labels[idx])
+                    .find(|&label| votes[label] == best)
+                    .unwrap_or(0)
+            })
+            .collect())
+    }
+
+    /// Classification accuracy on labelled queries
+    pub fn score(&self, queries: &Tensor, labels: &[usize]) -> Result<f32, BellandeError> {
+        let predictions = self.predict(queries)?;
+        if predictions.len() != labels.len() {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Got {} labels for {} queries",
+                labels.len(),
+                predictions.len()
+            )));
+        }
+        if labels.is_empty() {
+            return Ok(0.0);
+        }
+
+        let correct = predictions
+            .iter()
+            .zip(labels)
+            .filter(|(prediction, label)| prediction == label)
+            .count();
+        Ok(correct as f32 / labels.len() as f32)
+    }
+
+    /// Normalized class votes of one query's neighbors, closest first
+    fn votes(&self, neighbors: &[(usize, f32)]) -> Vec<f32> {
+        let farthest = neighbors.last().map_or(0.0, |n| n.1);
+        let mut votes = vec![0.0; self.num_classes];
+        for &(idx, similarity) in neighbors {
+            votes[self.labels[idx]] += if self.weighted {
+                // Shift so every neighbor contributes a positive weight
+                similarity - farthest + numerics().denom_eps
+            } else {
+                1.0
+            };
+        }
+
+        let total: f32 = votes.iter().sum();
+        if total > 0.0 {
+            votes.iter_mut().for_each(|v| *v /= total);
+        }
+        votes
+    }
+
+    /// Higher is closer for both metrics
+    fn similarity(&self, query: &[f32], query_norm: f32, row: &[f32], row_norm: f32) -> f32 {
+        match self.metric {
+            DistanceMetric::Cosine => {
+                dot(query, row) / (query_norm * row_norm).max(numerics().denom_eps)
+            }
+            DistanceMetric::Euclidean => -query
+                .iter()
+                .zip(row)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>(),
+        }
+    }
+}
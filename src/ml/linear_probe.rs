@@ -0,0 +1,232 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, random, tensor::Tensor};
+use crate::data::dataloader::DataLoader;
+use crate::inference::features::embed_loader;
+use crate::layer::{linear::Linear, Layer};
+use crate::loss::{cross_entropy::CrossEntropyLoss, Loss};
+use crate::models::{models::Model, sequential::Sequential};
+use crate::optim::{scheduler::cosine_annealing_lr, sgd::SGD};
+
+/// Training recipe for the linear head. The defaults follow the common
+/// linear evaluation protocol: SGD with momentum and a cosine schedule over
+/// L2-normalized frozen features.
+#[derive(Clone, Debug)]
+pub struct LinearProbeConfig {
+    pub epochs: usize,
+    pub batch_size: usize,
+    pub learning_rate: f32,
+    pub momentum: f32,
+    pub weight_decay: f32,
+}
+
+impl Default for LinearProbeConfig {
+    fn default() -> Self {
+        LinearProbeConfig {
+            epochs: 100,
+            batch_size: 256,
+            learning_rate: 0.1,
+            momentum: 0.9,
+            weight_decay: 0.0,
+        }
+    }
+}
+
+/// Outcome of a linear probe run
+#[derive(Clone, Debug)]
+pub struct LinearProbeResult {
+    pub train_accuracy: f32,
+    pub val_accuracy: f32,
+    /// Top-5 validation accuracy, when there are at least five classes
+    pub val_top5_accuracy: Option<f32>,
+    /// Head weight with shape (num_classes, embedding_dim)
+    pub weight: Tensor,
+    /// Head bias with shape (num_classes)
+    pub bias: Tensor,
+}
+
+/// Freezes `backbone`, trains a linear classifier on its embeddings of
+/// `train_loader` with the default recipe and reports accuracy on `val_loader`
+pub fn evaluate_linear_probe(
+    backbone: &mut dyn Model,
+    train_loader: &DataLoader,
+    val_loader: &DataLoader,
+) -> Result<LinearProbeResult, BellandeError> {
+    evaluate_linear_probe_with_config(
+        backbone,
+        train_loader,
+        val_loader,
+        &LinearProbeConfig::default(),
+    )
+}
+
+/// Like [`evaluate_linear_probe`] with an explicit recipe. The backbone is
+/// left in evaluation mode.
+pub fn evaluate_linear_probe_with_config(
+    backbone: &mut dyn Model,
+    train_loader: &DataLoader,
+    val_loader: &DataLoader,
+    config: &LinearProbeConfig,
+) -> Result<LinearProbeResult, BellandeError> {
+    if config.epochs == 0 || config.batch_size == 0 {
+        return Err(BellandeError::InvalidConfiguration(
+            "Linear probe needs at least one epoch and a non-zero batch size".into(),
+        ));
+    }
+
+    backbone.eval();
+    let (train_features, train_labels) = embed_loader(backbone, train_loader)?;
+    let (val_features, val_labels) = embed_loader(backbone, val_loader)?;
+
+    let num_samples = train_features.shape[0];
+    let dim = train_features.shape[1];
+    if num_samples == 0 {
+        return Err(BellandeError::InvalidInputs);
+    }
+    if val_features.shape[1] != dim && val_features.shape[0] > 0 {
+        return Err(BellandeError::DimensionMismatch);
+    }
+    if train_labels
+        .iter()
+        .chain(&val_labels)
+        .any(|&label| label < 0)
+    {
+        return Err(BellandeError::InvalidParameter(
+            "Class labels must be non-negative".into(),
+        ));
+    }
+
+    let num_classes = train_labels
+        .iter()
+        .chain(&val_labels)
+        .max()
+        .map_or(0, |&max| max as usize + 1);
+
+    // Zero initialization; the probe objective is convex so it does not
+    // need symmetry breaking
+    let mut linear = Linear::new(dim, num_classes, true);
+    linear.set_parameter("weight", Tensor::zeros(&[num_classes, dim]))?;
+    let mut head = Sequential::new();
+    head.add(Box::new(linear));
+    let mut optimizer = SGD::new(
+        head.parameters(),
+        config.learning_rate,
+        config.momentum,
+        config.weight_decay,
+        false,
+    );
+    let loss_fn = CrossEntropyLoss::default();
+
+    let mut order: Vec<usize> = (0..num_samples).collect();
+    let steps_per_epoch = num_samples.div_ceil(config.batch_size);
+    let total_steps = config.epochs * steps_per_epoch;
+    let mut step = 0;
+
+    for _ in 0..config.epochs {
+        random::shuffle(&mut order);
+
+        for batch in order.chunks(config.batch_size) {
+            optimizer.set_lr(cosine_annealing_lr(
+                config.learning_rate,
+                0.0,
+                step,
+                total_steps,
+            ));
+            step += 1;
+
+            let features: Vec<f32> = batch
+                .iter()
+                .flat_map(|&idx| {
+                    train_features.data[idx * dim..(idx + 1) * dim]
+                        .iter()
+                        .copied()
+                })
+                .collect();
+            let inputs = Tensor::new(
+                features,
+                vec![batch.len(), dim],
+                false,
+                train_features.device.clone(),
+                train_features.dtype,
+            );
+            let targets = Tensor::new(
+                batch.iter().map(|&idx| train_labels[idx] as f32).collect(),
+                vec![batch.len()],
+                false,
+                train_features.device.clone(),
+                train_features.dtype,
+            );
+
+            optimizer.zero_grad();
+            let logits = head.forward(&inputs)?;
+            head.backward(&loss_fn.backward(&logits, &targets)?)?;
+            optimizer.step()?;
+            let params: Vec<&Tensor> = optimizer.params().iter().collect();
+            head.write_back_parameters(&params)?;
+        }
+    }
+
+    let train_accuracy = accuracy(&mut head, &train_features, &train_labels, 1)?;
+    let val_accuracy = accuracy(&mut head, &val_features, &val_labels, 1)?;
+    let val_top5_accuracy = if num_classes >= 5 {
+        Some(accuracy(&mut head, &val_features, &val_labels, 5)?)
+    } else {
+        None
+    };
+
+    let params = head.parameters();
+    let detached = |param: &Tensor| {
+        Tensor::new(
+            param.data.clone(),
+            param.shape.clone(),
+            false,
+            train_features.device.clone(),
+            train_features.dtype,
+        )
+    };
+    Ok(LinearProbeResult {
+        train_accuracy,
+        val_accuracy,
+        val_top5_accuracy,
+        weight: detached(&params[0]),
+        bias: detached(&params[1]),
+    })
+}
+
+/// Fraction of samples whose label is among the `k` highest logits
+fn accuracy(
+    head: &mut Sequential,
+    features: &Tensor,
+    labels: &[i64],
+    k: usize,
+) -> Result<f32, BellandeError> {
+    if labels.is_empty() {
+        return Ok(0.0);
+    }
+
+    let logits = head.forward(features)?;
+    let num_classes = logits.shape[1].max(1);
+    let correct = logits
+        .data
+        .chunks(num_classes)
+        .zip(labels)
+        .filter(|(logits, &label)| {
+            let target = logits[label as usize];
+            logits.iter().filter(|&&logit| logit > target).count() < k
+        })
+        .count();
+    Ok(correct as f32 / labels.len() as f32)
+}
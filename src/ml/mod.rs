@@ -0,0 +1,4 @@
+pub mod kmeans;
+pub mod knn;
+pub mod linear_probe;
+pub mod pca;
@@ -0,0 +1,213 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+
+/// Principal component analysis fitted with power iteration and deflation
+#[derive(Clone, Debug)]
+pub struct PCA {
+    n_components: usize,
+    max_iter: usize,
+    tol: f32,
+    mean: Vec<f32>,
+    /// Row-major (n_components, dim)
+    components: Vec<f32>,
+    explained_variance: Vec<f32>,
+}
+
+impl PCA {
+    pub fn new(n_components: usize) -> Self {
+        PCA {
+            n_components,
+            max_iter: 200,
+            tol: 1e-6,
+            mean: Vec::new(),
+            components: Vec::new(),
+            explained_variance: Vec::new(),
+        }
+    }
+
+    pub fn with_max_iter(mut self, max_iter: usize) -> Self {
+        self.max_iter = max_iter;
+        self
+    }
+
+    pub fn with_tol(mut self, tol: f32) -> Self {
+        self.tol = tol;
+        self
+    }
+
+    /// Fits the principal components of a (num_samples, dim) tensor
+    pub fn fit(&mut self, data: &Tensor) -> Result<(), BellandeError> {
+        let (n, dim) = matrix_dims(data)?;
+        if self.n_components == 0 || self.n_components > dim {
+            return Err(BellandeError::InvalidParameter(format!(
+                "n_components must be in 1..={}, got {}",
+                dim, self.n_components
+            )));
+        }
+        if n < 2 {
+            return Err(BellandeError::InvalidParameter(
+                "PCA needs at least two samples".into(),
+            ));
+        }
+
+        let mut mean = vec![0.0; dim];
+        for row in data.data.chunks(dim) {
+            for (m, v) in mean.iter_mut().zip(row) {
+                *m += v;
+            }
+        }
+        mean.iter_mut().for_each(|m| *m /= n as f32);
+
+        let mut covariance = vec![0.0; dim * dim];
+        let mut centered = vec![0.0; dim];
+        for row in data.data.chunks(dim) {
+            for ((c, v), m) in centered.iter_mut().zip(row).zip(&mean) {
+                *c = v - m;
+            }
+            for (i, &ci) in centered.iter().enumerate() {
+                if ci == 0.0 {
+                    continue;
+                }
+                for (j, &cj) in centered.iter().enumerate().skip(i) {
+                    covariance[i * dim + j] += ci * cj;
+                }
+            }
+        }
+        for i in 0..dim {
+            for j in i..dim {
+                let value = covariance[i * dim + j] / (n - 1) as f32;
+                covariance[i * dim + j] = value;
+                covariance[j * dim + i] = value;
+            }
+        }
+
+        let mut components = Vec::with_capacity(self.n_components * dim);
+        let mut explained_variance = Vec::with_capacity(self.n_components);
+
+        for c in 0..self.n_components {
+            let mut vector = vec![0.0; dim];
+            vector[c] = 1.0;
+            for (i, v) in vector.iter_mut().enumerate() {
+                *v += 1e-3 * ((i * 31 + c * 17) % 7) as f32;
+            }
+            normalize(&mut vector);
+
+            let mut eigenvalue = 0.0;
+            for _ in 0..self.max_iter {
+                let mut next = mat_vec(&covariance, &vector, dim);
+                eigenvalue = dot(&next, &vector);
+                if normalize(&mut next) == 0.0 {
+                    break;
+                }
+                let change: f32 = next.iter().zip(&vector).map(|(a, b)| (a - b).abs()).sum();
+                vector = next;
+                if change < self.tol {
+                    break;
+                }
+            }
+
+            // Deflate so the next iteration finds the next largest component
+            for (row, &vi) in covariance.chunks_mut(dim).zip(&vector) {
+                for (value, &vj) in row.iter_mut().zip(&vector) {
+                    *value -= eigenvalue * vi * vj;
+                }
+            }
+
+            explained_variance.push(eigenvalue.max(0.0));
+            components.extend_from_slice(&vector);
+        }
+
+        self.mean = mean;
+        self.components = components;
+        self.explained_variance = explained_variance;
+        Ok(())
+    }
+
+    /// Projects a (num_samples, dim) tensor onto the fitted components
+    pub fn transform(&self, data: &Tensor) -> Result<Tensor, BellandeError> {
+        if self.components.is_empty() {
+            return Err(BellandeError::RuntimeError(
+                "PCA has not been fitted".into(),
+            ));
+        }
+
+        let (n, dim) = matrix_dims(data)?;
+        if dim != self.mean.len() {
+            return Err(BellandeError::DimensionMismatch);
+        }
+
+        let mut output = Vec::with_capacity(n * self.n_components);
+        let mut centered = vec![0.0; dim];
+        for row in data.data.chunks(dim) {
+            for ((c, v), m) in centered.iter_mut().zip(row).zip(&self.mean) {
+                *c = v - m;
+            }
+            for component in self.components.chunks(dim) {
+                output.push(dot(&centered, component));
+            }
+        }
+
+        Ok(Tensor::new(
+            output,
+            vec![n, self.n_components],
+            false,
+            data.device.clone(),
+            data.dtype,
+        ))
+    }
+
+    pub fn fit_transform(&mut self, data: &Tensor) -> Result<Tensor, BellandeError> {
+        self.fit(data)?;
+        self.transform(data)
+    }
+
+    pub fn n_components(&self) -> usize {
+        self.n_components
+    }
+
+    /// Variance captured by each component, in decreasing order
+    pub fn explained_variance(&self) -> &[f32] {
+        &self.explained_variance
+    }
+}
+
+/// Returns (rows, columns) of a 2D tensor
+pub(crate) fn matrix_dims(data: &Tensor) -> Result<(usize, usize), BellandeError> {
+    if data.shape.len() != 2 {
+        return Err(BellandeError::InvalidShape(
+            "Expected 2D tensor (num_samples, dim)".into(),
+        ));
+    }
+    Ok((data.shape[0], data.shape[1]))
+}
+
+pub(crate) fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn mat_vec(matrix: &[f32], vector: &[f32], dim: usize) -> Vec<f32> {
+    matrix.chunks(dim).map(|row| dot(row, vector)).collect()
+}
+
+/// Normalizes `vector` in place and returns its original norm
+fn normalize(vector: &mut [f32]) -> f32 {
+    let norm = dot(vector, vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    norm
+}
@@ -0,0 +1,499 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, random};
+use crate::layer::{
+    activation::ReLU, avgpool2d::AvgPool2d, batch_norm::BatchNorm2d, conv::Conv2d,
+    conv::DepthwiseSeparableConv2d, dropout::Dropout, flatten::Flatten, linear::Linear,
+    pooling::MaxPool2d,
+};
+use crate::models::models::ModelConfig;
+use crate::models::sequential::Sequential;
+use serde::{Deserialize, Serialize};
+
+/// Kind of a backbone block
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockType {
+    /// Conv2d, BatchNorm2d and ReLU
+    Conv,
+    /// DepthwiseSeparableConv2d, BatchNorm2d and ReLU
+    SeparableConv,
+    MaxPool,
+    AvgPool,
+}
+
+/// One stage of a convolutional backbone, repeated `repeats` times. Only
+/// the first repeat uses `stride`. Convolutions are padded with
+/// `kernel_size / 2`; pooling blocks ignore `channels`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockSpec {
+    #[serde(rename = "type")]
+    pub block_type: BlockType,
+    #[serde(default)]
+    pub channels: usize,
+    #[serde(default = "default_kernel_size")]
+    pub kernel_size: usize,
+    #[serde(default = "default_one")]
+    pub stride: usize,
+    #[serde(default = "default_one")]
+    pub repeats: usize,
+}
+
+fn default_kernel_size() -> usize {
+    3
+}
+
+fn default_one() -> usize {
+    1
+}
+
+impl BlockSpec {
+    pub fn conv(channels: usize, stride: usize) -> Self {
+        BlockSpec {
+            block_type: BlockType::Conv,
+            channels,
+            kernel_size: default_kernel_size(),
+            stride,
+            repeats: 1,
+        }
+    }
+
+    pub fn separable_conv(channels: usize, stride: usize) -> Self {
+        BlockSpec {
+            block_type: BlockType::SeparableConv,
+            ..Self::conv(channels, stride)
+        }
+    }
+
+    /// Max pooling with kernel and stride `size`
+    pub fn max_pool(size: usize) -> Self {
+        BlockSpec {
+            block_type: BlockType::MaxPool,
+            channels: 0,
+            kernel_size: size,
+            stride: size,
+            repeats: 1,
+        }
+    }
+
+    /// Average pooling with kernel and stride `size`
+    pub fn avg_pool(size: usize) -> Self {
+        BlockSpec {
+            block_type: BlockType::AvgPool,
+            ..Self::max_pool(size)
+        }
+    }
+
+    pub fn with_kernel_size(mut self, kernel_size: usize) -> Self {
+        self.kernel_size = kernel_size;
+        self
+    }
+
+    pub fn with_repeats(mut self, repeats: usize) -> Self {
+        self.repeats = repeats;
+        self
+    }
+
+    fn is_pool(&self) -> bool {
+        matches!(self.block_type, BlockType::MaxPool | BlockType::AvgPool)
+    }
+
+    /// Spatial size after one repeat with the given stride, if it fits
+    fn output_size(&self, size: usize, stride: usize) -> Option<usize> {
+        let padded = if self.is_pool() {
+            size
+        } else {
+            size + 2 * (self.kernel_size / 2)
+        };
+        padded
+            .checked_sub(self.kernel_size)
+            .map(|span| span / stride + 1)
+    }
+}
+
+/// Names of the architectures built by `preset`
+pub const PRESETS: &[&str] = &["mlp", "small_cnn", "separable_cnn"];
+
+/// Configuration of a named architecture for `input_shape` inputs:
+///
+/// - `mlp`: two hidden layers of 256 and 128 units on the flattened input
+/// - `small_cnn`: three conv and max pooling stages of 32, 64 and 128
+///   channels, then a 128 unit hidden layer
+/// - `separable_cnn`: as `small_cnn`, with depthwise separable convolutions
+///   after the first stage
+pub fn preset(
+    name: &str,
+    input_shape: &[usize],
+    num_classes: usize,
+) -> Result<ModelConfig, BellandeError> {
+    let (blocks, hidden_layers, dropout_rate) = match name {
+        "mlp" => (Vec::new(), vec![256, 128], 0.2),
+        "small_cnn" => (
+            vec![
+                BlockSpec::conv(32, 1),
+                BlockSpec::max_pool(2),
+                BlockSpec::conv(64, 1),
+                BlockSpec::max_pool(2),
+                BlockSpec::conv(128, 1),
+                BlockSpec::max_pool(2),
+            ],
+            vec![128],
+            0.25,
+        ),
+        "separable_cnn" => (
+            vec![
+                BlockSpec::conv(32, 1),
+                BlockSpec::max_pool(2),
+                BlockSpec::separable_conv(64, 1),
+                BlockSpec::max_pool(2),
+                BlockSpec::separable_conv(128, 1),
+                BlockSpec::max_pool(2),
+            ],
+            vec![128],
+            0.25,
+        ),
+        _ => {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Unknown architecture '{}', expected one of {:?}",
+                name, PRESETS
+            )))
+        }
+    };
+    Ok(ModelConfig {
+        input_shape: input_shape.to_vec(),
+        num_classes,
+        dropout_rate,
+        hidden_layers,
+        blocks,
+    })
+}
+
+/// Builds the network described by `config`: the `blocks` backbone on
+/// (channels, height, width) inputs, then a flatten, the `hidden_layers`
+/// head with ReLU and dropout, and a classifier with `num_classes` outputs.
+/// Without blocks the input is flattened directly, giving an MLP.
+pub fn create_cnn(config: &ModelConfig) -> Result<Sequential, BellandeError> {
+    if config.input_shape.is_empty() || config.input_shape.contains(&0) {
+        return Err(BellandeError::InvalidConfiguration(format!(
+            "Invalid input shape {:?}",
+            config.input_shape
+        )));
+    }
+    if config.num_classes == 0 {
+        return Err(BellandeError::InvalidConfiguration(
+            "num_classes must be positive".into(),
+        ));
+    }
+
+    let mut model = Sequential::new();
+    let mut features: usize = config.input_shape.iter().product();
+
+    if !config.blocks.is_empty() {
+        let (mut channels, mut height, mut width) = match config.input_shape[..] {
+            [c, h, w] => (c, h, w),
+            _ => {
+                return Err(BellandeError::InvalidConfiguration(format!(
+                    "Convolutional blocks need a (channels, height, width) input shape, got {:?}",
+                    config.input_shape
+                )))
+            }
+        };
+
+        for (index, block) in config.blocks.iter().enumerate() {
+            if block.kernel_size == 0 || block.stride == 0 || block.repeats == 0 {
+                return Err(BellandeError::InvalidConfiguration(format!(
+                    "Block {}: kernel_size, stride and repeats must be positive",
+                    index
+                )));
+            }
+            if !block.is_pool() && block.channels == 0 {
+                return Err(BellandeError::InvalidConfiguration(format!(
+                    "Block {}: convolution blocks need a positive channel count",
+                    index
+                )));
+            }
+
+            for repeat in 0..block.repeats {
+                let stride = if repeat == 0 { block.stride } else { 1 };
+                let (next_height, next_width) = match (
+                    block.output_size(height, stride),
+                    block.output_size(width, stride),
+                ) {
+                    (Some(h), Some(w)) => (h, w),
+                    _ => {
+                        return Err(BellandeError::InvalidConfiguration(format!(
+                            "Block {}: kernel {} does not fit a {}x{} feature map",
+                            index, block.kernel_size, height, width
+                        )))
+                    }
+                };
+                add_block(&mut model, block, channels, stride)?;
+                if !block.is_pool() {
+                    channels = block.channels;
+                }
+                height = next_height;
+                width = next_width;
+            }
+        }
+        features = channels * height * width;
+    }
+
+    model.add(Box::new(Flatten::new()));
+    for &hidden in &config.hidden_layers {
+        model.add(Box::new(Linear::new(features, hidden, true)));
+        model.add(Box::new(ReLU::new()));
+        if config.dropout_rate > 0.0 {
+            model.add(Box::new(Dropout::new(config.dropout_rate)));
+        }
+        features = hidden;
+    }
+    model.add(Box::new(Linear::new(features, config.num_classes, true)));
+
+    Ok(model)
+}
+
+fn add_block(
+    model: &mut Sequential,
+    block: &BlockSpec,
+    in_channels: usize,
+    stride: usize,
+) -> Result<(), BellandeError> {
+    let kernel = (block.kernel_size, block.kernel_size);
+    let padding = (block.kernel_size / 2, block.kernel_size / 2);
+    match block.block_type {
+        BlockType::Conv => {
+            model.add(Box::new(Conv2d::new(
+                in_channels,
+                block.channels,
+                kernel,
+                (stride, stride),
+                padding,
+                false,
+            )));
+        }
+        BlockType::SeparableConv => {
+            model.add(Box::new(DepthwiseSeparableConv2d::new(
+                in_channels,
+                block.channels,
+                kernel,
+                (stride, stride),
+                padding,
+                false,
+            )?));
+        }
+        BlockType::MaxPool => {
+            model.add(Box::new(MaxPool2d::new(kernel, (stride, stride))));
+            return Ok(());
+        }
+        BlockType::AvgPool => {
+            model.add(Box::new(AvgPool2d::new(
+                kernel,
+                Some((stride, stride)),
+                None,
+            )));
+            return Ok(());
+        }
+    }
+    model.add(Box::new(BatchNorm2d::new(
+        block.channels,
+        numerics().norm_eps,
+        0.1,
+        true,
+    )));
+    model.add(Box::new(ReLU::new()));
+    Ok(())
+}
+
+/// Space of architectures for random search. Each sampled backbone has
+/// between `num_blocks.0` and `num_blocks.1` blocks whose settings are drawn
+/// uniformly from the option lists.
+#[derive(Clone, Debug)]
+pub struct SearchSpace {
+    pub num_blocks: (usize, usize),
+    pub block_types: Vec<BlockType>,
+    pub channels: Vec<usize>,
+    pub kernel_sizes: Vec<usize>,
+    pub strides: Vec<usize>,
+    pub repeats: Vec<usize>,
+    pub hidden_layers: Vec<Vec<usize>>,
+    pub dropout_rates: Vec<f32>,
+}
+
+impl Default for SearchSpace {
+    fn default() -> Self {
+        SearchSpace {
+            num_blocks: (2, 5),
+            block_types: vec![
+                BlockType::Conv,
+                BlockType::SeparableConv,
+                BlockType::MaxPool,
+            ],
+            channels: vec![16, 32, 64, 128],
+            kernel_sizes: vec![3, 5],
+            strides: vec![1, 2],
+            repeats: vec![1, 2],
+            hidden_layers: vec![vec![], vec![128], vec![256]],
+            dropout_rates: vec![0.0, 0.25, 0.5],
+        }
+    }
+}
+
+impl SearchSpace {
+    pub fn with_num_blocks(mut self, min: usize, max: usize) -> Result<Self, BellandeError> {
+        if min > max {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Invalid block count range {}..={}",
+                min, max
+            )));
+        }
+        self.num_blocks = (min, max);
+        Ok(self)
+    }
+
+    pub fn with_block_types(mut self, block_types: Vec<BlockType>) -> Self {
+        self.block_types = block_types;
+        self
+    }
+
+    pub fn with_channels(mut self, channels: Vec<usize>) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    pub fn with_kernel_sizes(mut self, kernel_sizes: Vec<usize>) -> Self {
+        self.kernel_sizes = kernel_sizes;
+        self
+    }
+
+    pub fn with_strides(mut self, strides: Vec<usize>) -> Self {
+        self.strides = strides;
+        self
+    }
+
+    pub fn with_repeats(mut self, repeats: Vec<usize>) -> Self {
+        self.repeats = repeats;
+        self
+    }
+
+    pub fn with_hidden_layers(mut self, hidden_layers: Vec<Vec<usize>>) -> Self {
+        self.hidden_layers = hidden_layers;
+        self
+    }
+
+    pub fn with_dropout_rates(mut self, dropout_rates: Vec<f32>) -> Self {
+        self.dropout_rates = dropout_rates;
+        self
+    }
+
+    /// Draws a random architecture for (channels, height, width) inputs.
+    /// Pooling blocks that would not fit the remaining feature map are
+    /// replaced by stride-1 convolutions, so every sample builds with
+    /// `create_cnn`.
+    pub fn sample(
+        &self,
+        input_shape: &[usize],
+        num_classes: usize,
+    ) -> Result<ModelConfig, BellandeError> {
+        self.validate()?;
+        let (mut height, mut width) = match input_shape {
+            [_, h, w] => (*h, *w),
+            _ => {
+                return Err(BellandeError::InvalidConfiguration(format!(
+                    "Expected a (channels, height, width) input shape, got {:?}",
+                    input_shape
+                )))
+            }
+        };
+
+        let (min_blocks, max_blocks) = self.num_blocks;
+        let num_blocks = choose(&(min_blocks..=max_blocks).collect::<Vec<_>>());
+        let mut blocks = Vec::with_capacity(num_blocks);
+
+        for _ in 0..num_blocks {
+            let block_type = choose(&self.block_types);
+            let stride = choose(&self.strides);
+            let mut block = match block_type {
+                BlockType::Conv | BlockType::SeparableConv => BlockSpec {
+                    block_type,
+                    channels: choose(&self.channels),
+                    kernel_size: choose(&self.kernel_sizes),
+                    stride,
+                    repeats: choose(&self.repeats),
+                },
+                BlockType::MaxPool => BlockSpec::max_pool(stride.max(2)),
+                BlockType::AvgPool => BlockSpec::avg_pool(stride.max(2)),
+            };
+
+            if block.is_pool() && block.kernel_size > height.min(width) {
+                block = BlockSpec::conv(choose(&self.channels), 1)
+                    .with_kernel_size(choose(&self.kernel_sizes));
+            }
+
+            for repeat in 0..block.repeats {
+                let stride = if repeat == 0 { block.stride } else { 1 };
+                // Convolutions are padded, so they always fit
+                height = block.output_size(height, stride).unwrap_or(1);
+                width = block.output_size(width, stride).unwrap_or(1);
+            }
+            blocks.push(block);
+        }
+
+        Ok(ModelConfig {
+            input_shape: input_shape.to_vec(),
+            num_classes,
+            dropout_rate: choose(&self.dropout_rates),
+            hidden_layers: choose(&self.hidden_layers),
+            blocks,
+        })
+    }
+
+    fn validate(&self) -> Result<(), BellandeError> {
+        if self.num_blocks.0 > self.num_blocks.1 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Invalid block count range {}..={}",
+                self.num_blocks.0, self.num_blocks.1
+            )));
+        }
+        if self.block_types.is_empty()
+            || self.channels.is_empty()
+            || self.kernel_sizes.is_empty()
+            || self.strides.is_empty()
+            || self.repeats.is_empty()
+            || self.hidden_layers.is_empty()
+            || self.dropout_rates.is_empty()
+        {
+            return Err(BellandeError::InvalidConfiguration(
+                "Every search space option list needs at least one entry".into(),
+            ));
+        }
+        if self.channels.contains(&0)
+            || self.kernel_sizes.contains(&0)
+            || self.strides.contains(&0)
+            || self.repeats.contains(&0)
+        {
+            return Err(BellandeError::InvalidConfiguration(
+                "Search space channels, kernel sizes, strides and repeats must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Picks a uniformly random element of a non-empty slice
+fn choose<T: Clone>(options: &[T]) -> T {
+    let u = random::uniform(0.0, 1.0, 1)[0];
+    options[((u * options.len() as f32) as usize).min(options.len() - 1)].clone()
+}
@@ -13,7 +13,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::core::{error::BellandeError, numerics::numerics, tensor::Tensor};
 use crate::layer::batch_norm::BatchNorm1d;
 use crate::layer::dropout::Dropout;
 use crate::layer::{activation::ReLU, linear::Linear};
@@ -79,7 +79,12 @@ impl CustomModel {
 
         // Add batch normalization if specified
         if config.hyperparameters.get("use_batch_norm").unwrap_or(&0.0) > &0.0 {
-            layers.add(Box::new(BatchNorm1d::new(hidden_size, 1e-5, 0.1, true)));
+            layers.add(Box::new(BatchNorm1d::new(
+                hidden_size,
+                numerics().norm_eps,
+                0.1,
+                true,
+            )));
         }
 
         // Add additional layers based on depth parameter
@@ -0,0 +1,387 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::layer::{accumulate_grad, activation::softmax, parameter};
+use crate::models::models::{read_state_dict, Model, ModelConfig, ModelState};
+use std::collections::HashMap;
+
+/// How an `Ensemble` combines its members' outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnsembleMode {
+    /// Weighted average of the raw logits
+    Logits,
+    /// Weighted average of the softmax probabilities. The ensemble outputs
+    /// their logarithm, so a softmax over it recovers the averaged
+    /// probabilities and it can be used wherever logits are expected.
+    Probabilities,
+}
+
+/// Combines the (batch_size, num_classes) outputs of several models into a
+/// weighted average. Member weights are a softmax over `weight_logits`, so
+/// they stay positive and sum to one; they are trained alongside the
+/// members when `with_learned_weights` is set.
+pub struct Ensemble {
+    members: Vec<Box<dyn Model>>,
+    mode: EnsembleMode,
+    weight_logits: Tensor,
+    learn_weights: bool,
+    cache: Option<EnsembleCache>,
+}
+
+struct EnsembleCache {
+    /// Per-member logits or probabilities, depending on the mode
+    outputs: Vec<Tensor>,
+    /// Averaged probabilities, in `Probabilities` mode
+    mixture: Option<Vec<f32>>,
+}
+
+impl Ensemble {
+    /// Creates an ensemble averaging the logits of `members` with equal
+    /// weights
+    pub fn new(members: Vec<Box<dyn Model>>) -> Result<Self, BellandeError> {
+        if members.is_empty() {
+            return Err(BellandeError::InvalidConfiguration(
+                "An ensemble needs at least one member".into(),
+            ));
+        }
+        let num_members = members.len();
+
+        Ok(Ensemble {
+            members,
+            mode: EnsembleMode::Logits,
+            weight_logits: Tensor::zeros(&[num_members]),
+            learn_weights: false,
+            cache: None,
+        })
+    }
+
+    pub fn with_mode(mut self, mode: EnsembleMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Uses fixed member weights, normalized to sum to one
+    pub fn with_weights(mut self, weights: Vec<f32>) -> Result<Self, BellandeError> {
+        if weights.len() != self.members.len() {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Got {} weights for {} members",
+                weights.len(),
+                self.members.len()
+            )));
+        }
+        if weights.iter().any(|&w| !w.is_finite() || w <= 0.0) {
+            return Err(BellandeError::InvalidParameter(
+                "Ensemble weights must be positive".into(),
+            ));
+        }
+
+        self.weight_logits.data = weights.iter().map(|w| w.ln()).collect();
+        Ok(self)
+    }
+
+    /// Trains the member weights with the rest of the parameters. The
+    /// weights are exposed as the `weights` parameter.
+    pub fn with_learned_weights(mut self) -> Self {
+        self.learn_weights = true;
+        self.weight_logits = parameter(self.weight_logits.clone());
+        self
+    }
+
+    /// Current normalized member weights
+    pub fn weights(&self) -> Vec<f32> {
+        let max = self
+            .weight_logits
+            .data
+            .iter()
+            .cloned()
+            .fold(f32::NEG_INFINITY, f32::max);
+        let exp: Vec<f32> = self
+            .weight_logits
+            .data
+            .iter()
+            .map(|&w| (w - max).exp())
+            .collect();
+        let sum: f32 = exp.iter().sum();
+        exp.into_iter().map(|w| w / sum).collect()
+    }
+
+    pub fn mode(&self) -> EnsembleMode {
+        self.mode
+    }
+
+    pub fn members(&self) -> &[Box<dyn Model>] {
+        &self.members
+    }
+
+    pub fn members_mut(&mut self) -> &mut [Box<dyn Model>] {
+        &mut self.members
+    }
+
+    /// Runs every member, checking that they agree on the output shape
+    fn member_outputs(&mut self, input: &Tensor) -> Result<Vec<Tensor>, BellandeError> {
+        let mut outputs: Vec<Tensor> = Vec::with_capacity(self.members.len());
+        for (i, member) in self.members.iter_mut().enumerate() {
+            let output = member.forward(input)?;
+            if output.shape.len() != 2 {
+                return Err(BellandeError::InvalidShape(format!(
+                    "Member {} returned shape {:?}, expected (batch_size, num_classes)",
+                    i, output.shape
+                )));
+            }
+            if let Some(first) = outputs.first() {
+                if first.shape != output.shape {
+                    return Err(BellandeError::ShapeMismatch(format!(
+                        "Member {} returned shape {:?}, member 0 returned {:?}",
+                        i, output.shape, first.shape
+                    )));
+                }
+            }
+            outputs.push(output);
+        }
+        Ok(outputs)
+    }
+}
+
+impl Model for Ensemble {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        let mut outputs = self.member_outputs(input)?;
+        if self.mode == EnsembleMode::Probabilities {
+            outputs = outputs
+                .iter()
+                .map(|output| softmax(output, -1))
+                .collect::<Result<_, _>>()?;
+        }
+
+        let weights = self.weights();
+        let mut combined = vec![0.0; outputs[0].data.len()];
+        for (output, &weight) in outputs.iter().zip(&weights) {
+            for (c, &o) in combined.iter_mut().zip(&output.data) {
+                *c += weight * o;
+            }
+        }
+
+        let (data, mixture) = match self.mode {
+            EnsembleMode::Logits => (combined, None),
+            EnsembleMode::Probabilities => (
+                combined
+                    .iter()
+                    .map(|&p| p.max(f32::MIN_POSITIVE).ln())
+                    .collect(),
+                Some(combined),
+            ),
+        };
+
+        let shape = outputs[0].shape.clone();
+        let (device, dtype) = (outputs[0].device.clone(), outputs[0].dtype);
+        self.cache = Some(EnsembleCache { outputs, mixture });
+
+        Ok(Tensor::new(data, shape, true, device, dtype))
+    }
+
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let cache = self
+            .cache
+            .take()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        if grad.data.len() != cache.outputs[0].data.len() {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Expected gradient of shape {:?}, got {:?}",
+                cache.outputs[0].shape, grad.shape
+            )));
+        }
+
+        // Gradient with respect to the averaged quantity
+        let grad_combined: Vec<f32> = match cache.mixture {
+            Some(ref mixture) => grad
+                .data
+                .iter()
+                .zip(mixture)
+                .map(|(&g, &p)| g / p.max(f32::MIN_POSITIVE))
+                .collect(),
+            None => grad.data.clone(),
+        };
+
+        let weights = self.weights();
+        let num_classes = cache.outputs[0].shape[1];
+        let mut grad_weights = Vec::with_capacity(weights.len());
+        let mut grad_input: Option<Tensor> = None;
+
+        for ((member, output), &weight) in self.members.iter_mut().zip(&cache.outputs).zip(&weights)
+        {
+            grad_weights.push(
+                grad_combined
+                    .iter()
+                    .zip(&output.data)
+                    .map(|(g, o)| g * o)
+                    .sum::<f32>(),
+            );
+
+            let mut member_grad: Vec<f32> = grad_combined.iter().map(|g| g * weight).collect();
+            if self.mode == EnsembleMode::Probabilities {
+                // Softmax backward: p * (g - sum(g * p)) per row
+                for (g_row, p_row) in member_grad
+                    .chunks_mut(num_classes)
+                    .zip(output.data.chunks(num_classes))
+                {
+                    let dot: f32 = g_row.iter().zip(p_row).map(|(g, p)| g * p).sum();
+                    for (g, &p) in g_row.iter_mut().zip(p_row) {
+                        *g = p * (*g - dot);
+                    }
+                }
+            }
+
+            let member_grad = Tensor::new(
+                member_grad,
+                output.shape.clone(),
+                false,
+                output.device.clone(),
+                output.dtype,
+            );
+            let member_input_grad = member.backward(&member_grad)?;
+            grad_input = Some(match grad_input {
+                None => member_input_grad,
+                Some(mut total) => {
+                    for (t, &g) in total.data.iter_mut().zip(&member_input_grad.data) {
+                        *t += g;
+                    }
+                    total
+                }
+            });
+        }
+
+        if self.learn_weights {
+            // Softmax backward from the weights to their logits
+            let dot: f32 = grad_weights.iter().zip(&weights).map(|(g, w)| g * w).sum();
+            let grad_logits: Vec<f32> = grad_weights
+                .iter()
+                .zip(&weights)
+                .map(|(g, w)| w * (g - dot))
+                .collect();
+            let grad_logits = Tensor::new(
+                grad_logits,
+                vec![weights.len()],
+                false,
+                self.weight_logits.device.clone(),
+                self.weight_logits.dtype,
+            );
+            accumulate_grad(&mut self.weight_logits, &grad_logits)?;
+        }
+
+        grad_input.ok_or(BellandeError::InvalidBackward)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        let mut params: Vec<Tensor> = self
+            .members
+            .iter()
+            .flat_map(|member| member.parameters())
+            .collect();
+        if self.learn_weights {
+            params.push(self.weight_logits.clone());
+        }
+        params
+    }
+
+    fn train(&mut self) {
+        for member in &mut self.members {
+            member.train();
+        }
+    }
+
+    fn eval(&mut self) {
+        for member in &mut self.members {
+            member.eval();
+        }
+    }
+
+    fn set_dropout_active(&mut self, active: bool) -> Result<(), BellandeError> {
+        for member in &mut self.members {
+            member.set_dropout_active(active)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, path: &str) -> Result<(), BellandeError> {
+        let state_dict = self.state_dict();
+        let state = ModelState {
+            model_type: "Ensemble".to_string(),
+            shapes: state_dict
+                .iter()
+                .map(|(k, v)| (k.clone(), v.shape.clone()))
+                .collect(),
+            state_dict: state_dict.into_iter().map(|(k, v)| (k, v.data)).collect(),
+            config: ModelConfig {
+                input_shape: vec![],
+                num_classes: 0,
+                dropout_rate: 0.0,
+                hidden_layers: vec![],
+                blocks: vec![],
+            },
+            temperature: None,
+        };
+
+        let file = std::fs::File::create(path).map_err(BellandeError::IOError)?;
+        serde_json::to_writer(file, &state).map_err(|_| BellandeError::SerializationError)
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), BellandeError> {
+        self.load_state_dict(read_state_dict(path)?)
+    }
+
+    /// Member tensors are prefixed with `member_{i}.`; the weight logits are
+    /// stored as `weights`
+    fn state_dict(&self) -> HashMap<String, Tensor> {
+        let mut state_dict = HashMap::new();
+        for (i, member) in self.members.iter().enumerate() {
+            for (name, param) in member.state_dict() {
+                state_dict.insert(format!("member_{}.{}", i, name), param);
+            }
+        }
+        state_dict.insert("weights".to_string(), self.weight_logits.clone());
+        state_dict
+    }
+
+    fn load_state_dict(
+        &mut self,
+        mut state_dict: HashMap<String, Tensor>,
+    ) -> Result<(), BellandeError> {
+        let weights = state_dict
+            .remove("weights")
+            .ok_or_else(|| BellandeError::RuntimeError("Missing parameter: weights".into()))?;
+        if weights.shape != self.weight_logits.shape {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Ensemble has {} members, stored weights have shape {:?}",
+                self.members.len(),
+                weights.shape
+            )));
+        }
+
+        for (i, member) in self.members.iter_mut().enumerate() {
+            let prefix = format!("member_{}.", i);
+            let member_state: HashMap<String, Tensor> = state_dict
+                .iter()
+                .filter_map(|(key, value)| {
+                    key.strip_prefix(&prefix)
+                        .map(|name| (name.to_string(), value.clone()))
+                })
+                .collect();
+            member.load_state_dict(member_state)?;
+        }
+
+        self.weight_logits.data = weights.data;
+        Ok(())
+    }
+}
@@ -1,5 +1,10 @@
+pub mod architecture;
 pub mod custom;
+pub mod ensemble;
 pub mod models;
+pub mod pruning;
 pub mod resnet;
 pub mod sequential;
+pub mod state_file;
+pub mod transformer;
 pub mod vgg;
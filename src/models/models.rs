@@ -13,10 +13,21 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
+use crate::core::{
+    autograd::GradAccumulator, device::Device, dtype::DataType, error::BellandeError,
+    tensor::Tensor,
+};
+use crate::layer::{prefixed, Layer};
+use crate::models::architecture::BlockSpec;
 use crate::models::sequential::Sequential;
+use crate::models::state_file::{save_state_file, LazyStateDict};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Hook receiving a parameter name and its gradient; returning `Some`
+/// replaces the gradient that is accumulated into the parameter
+pub type ParameterHook = Arc<dyn Fn(&str, &Tensor) -> Option<Tensor> + Send + Sync>;
 
 /// Base model trait defining common functionality for neural networks
 pub trait Model: Send + Sync {
@@ -35,6 +46,41 @@ pub trait Model: Send + Sync {
     /// Set model to evaluation mode
     fn eval(&mut self);
 
+    /// Keeps dropout sampling regardless of train/eval mode, for Monte Carlo
+    /// dropout inference
+    fn set_dropout_active(&mut self, _active: bool) -> Result<(), BellandeError> {
+        Err(BellandeError::NotImplemented(
+            "This model does not support Monte Carlo dropout".into(),
+        ))
+    }
+
+    /// Records per-sample gradients during `backward`, for differentially
+    /// private training
+    fn set_per_sample_grads(&mut self, _enabled: bool) -> Result<(), BellandeError> {
+        Err(BellandeError::NotImplemented(
+            "This model does not support per-sample gradients".into(),
+        ))
+    }
+
+    /// Per-sample gradients of the last `backward`, named like the state dict
+    fn per_sample_grads(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+
+    /// Reinitializes the model by calling `init` on every state dict entry
+    /// in name order, e.g. with `Init::for_weights` from `layer::init`
+    fn apply_init(
+        &mut self,
+        init: &mut dyn FnMut(&str, &mut Tensor) -> Result<(), BellandeError>,
+    ) -> Result<(), BellandeError> {
+        let mut state: Vec<(String, Tensor)> = self.state_dict().into_iter().collect();
+        state.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, tensor) in state.iter_mut() {
+            init(name, tensor)?;
+        }
+        self.load_state_dict(state.into_iter().collect())
+    }
+
     /// Save model to file
     fn save(&self, path: &str) -> Result<(), BellandeError>;
 
@@ -47,6 +93,321 @@ pub trait Model: Send + Sync {
     /// Load model state dictionary
     fn load_state_dict(&mut self, state_dict: HashMap<String, Tensor>)
         -> Result<(), BellandeError>;
+
+    /// Copies the values of `params`, such as an optimizer's clones of this
+    /// model's parameters after a step, back into the parameters they were
+    /// cloned from. Tensors are matched by the gradient buffer that clones
+    /// share; the rest of `params` is ignored.
+    fn write_back_parameters(&mut self, params: &[&Tensor]) -> Result<(), BellandeError> {
+        let updated: HashMap<*const GradAccumulator, &Tensor> = params
+            .iter()
+            .filter_map(|param| {
+                let accumulator = param.grad_accumulator.as_ref()?;
+                Some((Arc::as_ptr(accumulator), *param))
+            })
+            .collect();
+
+        let mut state_dict = self.state_dict();
+        let mut changed = false;
+        for current in state_dict.values_mut() {
+            let source = current
+                .grad_accumulator
+                .as_ref()
+                .and_then(|accumulator| updated.get(&Arc::as_ptr(accumulator)));
+            if let Some(source) = source {
+                current.data.clone_from(&source.data);
+                changed = true;
+            }
+        }
+        if changed {
+            self.load_state_dict(state_dict)?;
+        }
+        Ok(())
+    }
+
+    /// Load every tensor of `state_dict` whose name and shape match this
+    /// model's, keeping the model's current (freshly initialized) values for
+    /// the rest. Useful for transferring weights between related
+    /// architectures, e.g. with a different number of classes.
+    fn load_state_dict_partial(
+        &mut self,
+        mut state_dict: HashMap<String, Tensor>,
+    ) -> Result<LoadReport, BellandeError> {
+        let mut report = LoadReport::default();
+        let mut merged = HashMap::new();
+
+        for (name, current) in self.state_dict() {
+            match state_dict.remove(&name) {
+                Some(source) if source.shape == current.shape => {
+                    report.loaded.push(name.clone());
+                    merged.insert(name, source);
+                }
+                Some(source) => {
+                    report.shape_mismatched.push((
+                        name.clone(),
+                        source.shape.clone(),
+                        current.shape.clone(),
+                    ));
+                    merged.insert(name, current);
+                }
+                None => {
+                    report.missing.push(name.clone());
+                    merged.insert(name, current);
+                }
+            }
+        }
+        report.unexpected = state_dict.into_keys().collect();
+        report.sort();
+
+        self.load_state_dict(merged)?;
+        Ok(report)
+    }
+
+    /// Load weights from a file written by `save` with shape-tolerant matching
+    fn load_partial(&mut self, path: &str) -> Result<LoadReport, BellandeError> {
+        self.load_state_dict_partial(read_state_dict(path)?)
+    }
+
+    /// Save the state dictionary as a binary tensor file for `load_lazy`
+    fn save_state_file(&self, path: &str) -> Result<(), BellandeError> {
+        save_state_file(&self.state_dict(), path)
+    }
+
+    /// Like `load_state_dict_partial`, but only reads the tensors this model
+    /// needs from a lazily opened tensor file, so loading e.g. a backbone
+    /// from a full checkpoint never touches the other weights
+    fn load_state_dict_lazy(
+        &mut self,
+        source: &LazyStateDict,
+    ) -> Result<LoadReport, BellandeError> {
+        let mut report = LoadReport::default();
+        let mut merged = HashMap::new();
+        let current = self.state_dict();
+
+        for (name, tensor) in current.iter() {
+            match source.shape(name) {
+                Some(shape) if shape == tensor.shape.as_slice() => {
+                    report.loaded.push(name.clone());
+                    merged.insert(name.clone(), source.tensor(name)?);
+                }
+                Some(shape) => {
+                    report.shape_mismatched.push((
+                        name.clone(),
+                        shape.to_vec(),
+                        tensor.shape.clone(),
+                    ));
+                    merged.insert(name.clone(), tensor.clone());
+                }
+                None => {
+                    report.missing.push(name.clone());
+                    merged.insert(name.clone(), tensor.clone());
+                }
+            }
+        }
+        report.unexpected = source
+            .keys()
+            .filter(|name| !current.contains_key(*name))
+            .cloned()
+            .collect();
+        report.sort();
+
+        self.load_state_dict(merged)?;
+        Ok(report)
+    }
+
+    /// Memory-maps a file written by `save_state_file` and loads the
+    /// tensors this model needs with `load_state_dict_lazy`
+    fn load_lazy(&mut self, path: &str) -> Result<LoadReport, BellandeError> {
+        self.load_state_dict_lazy(&LazyStateDict::open(path)?)
+    }
+
+    /// Register a gradient hook on the parameter called `name` in the state
+    /// dictionary, returning the hook id
+    fn register_parameter_hook(
+        &self,
+        name: &str,
+        hook: ParameterHook,
+    ) -> Result<usize, BellandeError> {
+        let param = self.state_dict().remove(name).ok_or_else(|| {
+            BellandeError::InvalidParameter(format!("Unknown parameter: {}", name))
+        })?;
+        let name = name.to_string();
+        param.register_hook(move |grad| hook(&name, grad))
+    }
+
+    /// Register a gradient hook on every parameter that requires gradients,
+    /// returning the hook id for each parameter name
+    fn register_parameter_hooks(
+        &self,
+        hook: ParameterHook,
+    ) -> Result<Vec<(String, usize)>, BellandeError> {
+        let mut handles = Vec::new();
+        for (name, param) in self.state_dict() {
+            if !param.requires_grad {
+                continue;
+            }
+            let hook = Arc::clone(&hook);
+            let hook_name = name.clone();
+            let id = param.register_hook(move |grad| hook(&hook_name, grad))?;
+            handles.push((name, id));
+        }
+        handles.sort();
+        Ok(handles)
+    }
+
+    /// Remove a hook registered on the parameter called `name`
+    fn remove_parameter_hook(&self, name: &str, id: usize) -> bool {
+        self.state_dict()
+            .get(name)
+            .map(|param| param.remove_hook(id))
+            .unwrap_or(false)
+    }
+}
+
+/// Outcome of a shape-tolerant state dictionary load
+#[derive(Clone, Debug, Default)]
+pub struct LoadReport {
+    /// Parameters copied from the source
+    pub loaded: Vec<String>,
+    /// Parameters present in both with (source shape, model shape); kept as initialized
+    pub shape_mismatched: Vec<(String, Vec<usize>, Vec<usize>)>,
+    /// Model parameters absent from the source; kept as initialized
+    pub missing: Vec<String>,
+    /// Source tensors with no matching model parameter; ignored
+    pub unexpected: Vec<String>,
+}
+
+impl LoadReport {
+    /// Whether every model parameter was loaded and nothing was left over
+    pub fn is_exact(&self) -> bool {
+        self.shape_mismatched.is_empty() && self.missing.is_empty() && self.unexpected.is_empty()
+    }
+
+    /// Human-readable summary of what was and was not transferred
+    pub fn summary(&self) -> String {
+        let mut lines = vec![format!(
+            "Loaded {} tensors, re-initialized {} ({} shape mismatches, {} missing), ignored {} unexpected",
+            self.loaded.len(),
+            self.shape_mismatched.len() + self.missing.len(),
+            self.shape_mismatched.len(),
+            self.missing.len(),
+            self.unexpected.len()
+        )];
+        for (name, source, target) in &self.shape_mismatched {
+            lines.push(format!("  ~ {}: {:?} -> {:?}", name, source, target));
+        }
+        for name in &self.missing {
+            lines.push(format!("  + {}", name));
+        }
+        for name in &self.unexpected {
+            lines.push(format!("  - {}", name));
+        }
+        lines.join("\n")
+    }
+
+    fn sort(&mut self) {
+        self.loaded.sort();
+        self.shape_mismatched.sort();
+        self.missing.sort();
+        self.unexpected.sort();
+    }
+}
+
+impl std::fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.summary())
+    }
+}
+
+/// Reads the tensors of a model file written by `Model::save`
+pub fn read_state_dict(path: &str) -> Result<HashMap<String, Tensor>, BellandeError> {
+    let file = std::fs::File::open(path).map_err(|e| BellandeError::IOError(e))?;
+
+    let state: ModelState =
+        serde_json::from_reader(file).map_err(|_| BellandeError::SerializationError)?;
+
+    let mut state_dict = HashMap::new();
+    for (key, data) in state.state_dict {
+        let shape = state.shapes.get(&key).ok_or_else(|| {
+            BellandeError::RuntimeError(format!("Missing shape for key: {}", key))
+        })?;
+
+        state_dict.insert(
+            key,
+            Tensor::new(data, shape.clone(), true, Device::CPU, DataType::Float32),
+        );
+    }
+
+    Ok(state_dict)
+}
+
+/// Reads the calibration temperature stored in a file written by `save`
+pub fn read_temperature(path: &str) -> Result<Option<f32>, BellandeError> {
+    let file = std::fs::File::open(path).map_err(BellandeError::IOError)?;
+    let state: ModelState =
+        serde_json::from_reader(file).map_err(|_| BellandeError::SerializationError)?;
+    Ok(state.temperature)
+}
+
+/// Stores a calibration temperature in a file written by `save`, keeping
+/// the weights
+pub fn write_temperature(path: &str, temperature: f32) -> Result<(), BellandeError> {
+    let file = std::fs::File::open(path).map_err(BellandeError::IOError)?;
+    let mut state: ModelState =
+        serde_json::from_reader(file).map_err(|_| BellandeError::SerializationError)?;
+    state.temperature = Some(temperature);
+
+    let file = std::fs::File::create(path).map_err(BellandeError::IOError)?;
+    serde_json::to_writer(file, &state).map_err(|_| BellandeError::SerializationError)
+}
+
+/// Parameters of named sublayers, keyed `<sublayer>.<parameter>`
+pub(crate) fn sublayer_state_dict(layers: Vec<(String, &dyn Layer)>) -> HashMap<String, Tensor> {
+    layers
+        .into_iter()
+        .flat_map(|(prefix, layer)| prefixed(&prefix, layer.named_parameters()))
+        .collect()
+}
+
+/// Loads every parameter of the named sublayers from `state_dict`
+pub(crate) fn load_sublayer_state_dict(
+    layers: Vec<(String, &mut dyn Layer)>,
+    state_dict: &HashMap<String, Tensor>,
+) -> Result<(), BellandeError> {
+    for (prefix, layer) in layers {
+        for (name, _) in layer.named_parameters() {
+            let key = format!("{}.{}", prefix, name);
+            let param = state_dict.get(&key).ok_or_else(|| {
+                BellandeError::RuntimeError(format!("Missing parameter: {}", key))
+            })?;
+            layer.set_parameter(&name, param.clone()).map_err(|e| {
+                BellandeError::RuntimeError(format!("Failed to set parameter {}: {}", key, e))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `state_dict` in the format read by `read_state_dict`
+pub(crate) fn write_model_state(
+    path: &str,
+    model_type: &str,
+    state_dict: HashMap<String, Tensor>,
+    config: ModelConfig,
+) -> Result<(), BellandeError> {
+    let state = ModelState {
+        model_type: model_type.to_string(),
+        shapes: state_dict
+            .iter()
+            .map(|(k, v)| (k.clone(), v.shape.clone()))
+            .collect(),
+        state_dict: state_dict.into_iter().map(|(k, v)| (k, v.data)).collect(),
+        config,
+        temperature: None,
+    };
+
+    let file = std::fs::File::create(path).map_err(BellandeError::IOError)?;
+    serde_json::to_writer(file, &state).map_err(|_| BellandeError::SerializationError)
 }
 
 /// Model state for serialization
@@ -56,15 +417,21 @@ pub struct ModelState {
     pub state_dict: HashMap<String, Vec<f32>>,
     pub shapes: HashMap<String, Vec<usize>>,
     pub config: ModelConfig,
+    /// Softmax temperature fitted by post-training calibration
+    #[serde(default)]
+    pub temperature: Option<f32>,
 }
 
-/// Model configuration
+/// Model configuration. `blocks` describes the convolutional backbone and
+/// `hidden_layers` the fully connected head; see `architecture::create_cnn`.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ModelConfig {
     pub input_shape: Vec<usize>,
     pub num_classes: usize,
     pub dropout_rate: f32,
     pub hidden_layers: Vec<usize>,
+    #[serde(default)]
+    pub blocks: Vec<BlockSpec>,
 }
 
 impl Model for Sequential {
@@ -87,10 +454,6 @@ impl Model for Sequential {
             return Err(BellandeError::InvalidInputs);
         }
 
-        if !self.training {
-            return Err(BellandeError::InvalidBackward);
-        }
-
         let mut current_grad = grad.clone();
         for layer in self.layers.iter_mut().rev() {
             current_grad = layer
@@ -121,6 +484,21 @@ impl Model for Sequential {
         }
     }
 
+    fn set_dropout_active(&mut self, active: bool) -> Result<(), BellandeError> {
+        for layer in &mut self.layers {
+            layer.set_dropout_active(active);
+        }
+        Ok(())
+    }
+
+    fn set_per_sample_grads(&mut self, enabled: bool) -> Result<(), BellandeError> {
+        Sequential::set_per_sample_grads(self, enabled)
+    }
+
+    fn per_sample_grads(&self) -> Vec<(String, Tensor)> {
+        Sequential::per_sample_grads(self)
+    }
+
     fn save(&self, path: &str) -> Result<(), BellandeError> {
         let state = ModelState {
             model_type: "Sequential".to_string(),
@@ -139,7 +517,9 @@ impl Model for Sequential {
                 num_classes: 0,
                 dropout_rate: 0.0,
                 hidden_layers: vec![],
+                blocks: vec![],
             },
+            temperature: None,
         };
 
         let file = std::fs::File::create(path).map_err(|e| BellandeError::IOError(e))?;
@@ -147,24 +527,7 @@ impl Model for Sequential {
     }
 
     fn load(&mut self, path: &str) -> Result<(), BellandeError> {
-        let file = std::fs::File::open(path).map_err(|e| BellandeError::IOError(e))?;
-
-        let state: ModelState =
-            serde_json::from_reader(file).map_err(|_| BellandeError::SerializationError)?;
-
-        let mut state_dict = HashMap::new();
-        for (key, data) in state.state_dict {
-            let shape = state.shapes.get(&key).ok_or_else(|| {
-                BellandeError::RuntimeError(format!("Missing shape for key: {}", key))
-            })?;
-
-            state_dict.insert(
-                key,
-                Tensor::new(data, shape.clone(), true, Device::CPU, DataType::Float32),
-            );
-        }
-
-        self.load_state_dict(state_dict)
+        self.load_state_dict(read_state_dict(path)?)
     }
 
     fn state_dict(&self) -> HashMap<String, Tensor> {
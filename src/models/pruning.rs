@@ -0,0 +1,247 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, error::BellandeError};
+use crate::layer::ChannelRole;
+use crate::models::sequential::Sequential;
+use crate::training::trainer::Trainer;
+
+/// How channel importance is measured for structured pruning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruningCriterion {
+    /// L1 norm of each output channel's weights
+    L1Norm,
+    /// Magnitude of the scale (gamma) of the batch normalization layer
+    /// following the producer (network slimming). Producers without one
+    /// fall back to `L1Norm`.
+    BatchNormGamma,
+}
+
+/// Settings for structured channel pruning
+#[derive(Debug, Clone)]
+pub struct ChannelPruningConfig {
+    /// Fraction of the output channels of each prunable layer to remove
+    pub ratio: f32,
+    pub criterion: PruningCriterion,
+    /// Channels always kept per layer
+    pub min_channels: usize,
+}
+
+impl ChannelPruningConfig {
+    pub fn new(ratio: f32) -> Result<Self, BellandeError> {
+        if !(0.0..1.0).contains(&ratio) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Pruning ratio must be in [0, 1), got {}",
+                ratio
+            )));
+        }
+        Ok(ChannelPruningConfig {
+            ratio,
+            criterion: PruningCriterion::L1Norm,
+            min_channels: 1,
+        })
+    }
+
+    pub fn with_criterion(mut self, criterion: PruningCriterion) -> Self {
+        self.criterion = criterion;
+        self
+    }
+
+    pub fn with_min_channels(mut self, min_channels: usize) -> Self {
+        self.min_channels = min_channels.max(1);
+        self
+    }
+}
+
+/// Channels removed from one producer layer
+#[derive(Debug, Clone)]
+pub struct PrunedLayer {
+    /// Index of the producer in the `Sequential`
+    pub index: usize,
+    pub channels_before: usize,
+    pub channels_after: usize,
+}
+
+/// Outcome of `prune_channels`
+#[derive(Debug, Clone, Default)]
+pub struct PruningReport {
+    pub layers: Vec<PrunedLayer>,
+    pub parameters_before: usize,
+    pub parameters_after: usize,
+}
+
+impl PruningReport {
+    /// Fraction of the parameters removed
+    pub fn compression(&self) -> f32 {
+        if self.parameters_before == 0 {
+            return 0.0;
+        }
+        1.0 - self.parameters_after as f32 / self.parameters_before as f32
+    }
+}
+
+impl std::fmt::Display for PruningReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Parameters: {} -> {} ({:.1}% removed)",
+            self.parameters_before,
+            self.parameters_after,
+            self.compression() * 100.0
+        )?;
+        for layer in &self.layers {
+            writeln!(
+                f,
+                "  layer_{}: {} -> {} channels",
+                layer.index, layer.channels_before, layer.channels_after
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Fine-tuning schedule used to recover accuracy after pruning
+#[derive(Debug, Clone)]
+pub struct FineTuneRecipe {
+    pub epochs: usize,
+    pub learning_rate: f32,
+    pub momentum: f32,
+}
+
+impl Default for FineTuneRecipe {
+    /// A short SGD run at a tenth of a typical training learning rate
+    fn default() -> Self {
+        FineTuneRecipe {
+            epochs: 10,
+            learning_rate: 0.01,
+            momentum: 0.9,
+        }
+    }
+}
+
+impl FineTuneRecipe {
+    /// Builds an SGD trainer with cross-entropy loss for the pruned model;
+    /// run it with `fit(train_loader, Some(val_loader), recipe.epochs)`.
+    /// Optimizer state from before pruning no longer matches the parameter
+    /// shapes, so a fresh trainer is required.
+    pub fn trainer(&self, model: Sequential, device: Device) -> Result<Trainer, BellandeError> {
+        Trainer::new_with_sgd(Box::new(model), self.learning_rate, self.momentum, device)
+    }
+}
+
+/// Removes the least important output channels of every `Conv2d` and
+/// `Linear` layer whose outputs feed another prunable layer through only
+/// per-channel (batch normalization) and pass-through (activation, pooling,
+/// dropout) layers. The producer, its normalization layers and the input
+/// channels of the consumer all shrink, so the model gets smaller and
+/// faster. The final classifier is never pruned. The model should be
+/// fine-tuned afterwards, see `FineTuneRecipe`.
+pub fn prune_channels(
+    model: &mut Sequential,
+    config: &ChannelPruningConfig,
+) -> Result<PruningReport, BellandeError> {
+    let mut report = PruningReport {
+        parameters_before: count_parameters(model),
+        ..Default::default()
+    };
+
+    for index in 0..model.layers.len() {
+        let out_channels = match model.layers[index].channel_role() {
+            ChannelRole::Producer { out_channels, .. } => out_channels,
+            _ => continue,
+        };
+
+        // Follow the outputs to the next producer
+        let mut per_channel = Vec::new();
+        let mut consumer = None;
+        for (offset, layer) in model.layers[index + 1..].iter().enumerate() {
+            match layer.channel_role() {
+                ChannelRole::PerChannel { channels } if channels == out_channels => {
+                    per_channel.push(index + 1 + offset)
+                }
+                ChannelRole::PassThrough => {}
+                ChannelRole::Producer { .. } => {
+                    consumer = Some(index + 1 + offset);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        let consumer = match consumer {
+            Some(consumer) => consumer,
+            None => continue,
+        };
+
+        let num_keep = ((out_channels as f32 * (1.0 - config.ratio)).ceil() as usize)
+            .max(config.min_channels)
+            .min(out_channels);
+        if num_keep == out_channels {
+            continue;
+        }
+
+        let importance = match (config.criterion, per_channel.first()) {
+            (PruningCriterion::BatchNormGamma, Some(&norm)) => {
+                model.layers[norm].channel_importance()
+            }
+            _ => model.layers[index].channel_importance(),
+        }
+        .ok_or_else(|| {
+            BellandeError::RuntimeError(format!(
+                "Layer {} does not report channel importance",
+                index
+            ))
+        })?;
+
+        let keep = most_important(&importance, num_keep);
+        model.layers[index].retain_output_channels(&keep)?;
+        for &norm in &per_channel {
+            model.layers[norm].retain_output_channels(&keep)?;
+        }
+        model.layers[consumer].retain_input_channels(&keep, out_channels)?;
+
+        report.layers.push(PrunedLayer {
+            index,
+            channels_before: out_channels,
+            channels_after: num_keep,
+        });
+    }
+
+    report.parameters_after = count_parameters(model);
+    Ok(report)
+}
+
+/// Indices of the `count` largest scores, in ascending index order
+fn most_important(importance: &[f32], count: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..importance.len()).collect();
+    order.sort_by(|&a, &b| {
+        importance[b]
+            .partial_cmp(&importance[a])
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    order.truncate(count);
+    order.sort_unstable();
+    order
+}
+
+/// Trainable parameter count, excluding running statistics
+fn count_parameters(model: &Sequential) -> usize {
+    model
+        .layers
+        .iter()
+        .flat_map(|layer| layer.named_parameters())
+        .filter(|(name, _)| !name.starts_with("running_"))
+        .map(|(_, param)| param.data.len())
+        .sum()
+}
@@ -13,12 +13,17 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::core::{error::BellandeError, numerics::numerics, tensor::Tensor};
 use crate::layer::{
-    activation::ReLU, avgpool2d::AvgPool2d, batch_norm::BatchNorm2d, conv::Conv2d, linear::Linear,
-    pooling::MaxPool2d,
+    activation::ReLU, adaptive_pool::AdaptiveAvgPool2d, batch_norm::BatchNorm2d, conv::Conv2d,
+    dropout::DropPath, linear::Linear, pooling::MaxPool2d, Layer,
+};
+use crate::models::models::{
+    load_sublayer_state_dict, read_state_dict, sublayer_state_dict, write_model_state, Model,
+    ModelConfig,
 };
 use crate::models::sequential::Sequential;
+use std::collections::HashMap;
 
 pub struct ResidualBlock {
     conv1: Conv2d,
@@ -27,6 +32,7 @@ pub struct ResidualBlock {
     bn2: BatchNorm2d,
     downsample: Option<Sequential>,
     relu: ReLU,
+    drop_path: DropPath,
 }
 
 impl ResidualBlock {
@@ -38,11 +44,46 @@ impl ResidualBlock {
     ) -> Self {
         ResidualBlock {
             conv1: Conv2d::new(in_channels, out_channels, 3, stride, 1, true),
-            bn1: BatchNorm2d::new(out_channels, 1e-5, 0.1, true),
+            bn1: BatchNorm2d::new(out_channels, numerics().norm_eps, 0.1, true),
             conv2: Conv2d::new(out_channels, out_channels, 3, 1, 1, true),
-            bn2: BatchNorm2d::new(out_channels, 1e-5, 0.1, true),
+            bn2: BatchNorm2d::new(out_channels, numerics().norm_eps, 0.1, true),
             downsample,
             relu: ReLU::new(),
+            drop_path: DropPath::new(0.0),
+        }
+    }
+
+    /// Drops the residual branch with probability `p` per sample during
+    /// training (stochastic depth)
+    pub fn with_drop_path(mut self, p: f32) -> Self {
+        self.drop_path = DropPath::new(p);
+        self
+    }
+
+    /// Sublayers with state, named relative to `prefix`
+    fn layers<'a>(&'a self, prefix: &str, layers: &mut Vec<(String, &'a dyn Layer)>) {
+        layers.push((format!("{}.conv1", prefix), &self.conv1));
+        layers.push((format!("{}.bn1", prefix), &self.bn1));
+        layers.push((format!("{}.conv2", prefix), &self.conv2));
+        layers.push((format!("{}.bn2", prefix), &self.bn2));
+        layers.push((format!("{}.drop_path", prefix), &self.drop_path));
+        if let Some(ds) = &self.downsample {
+            for (i, layer) in ds.layers.iter().enumerate() {
+                layers.push((format!("{}.downsample.{}", prefix, i), layer.as_ref()));
+            }
+        }
+    }
+
+    fn layers_mut<'a>(&'a mut self, prefix: &str, layers: &mut Vec<(String, &'a mut dyn Layer)>) {
+        layers.push((format!("{}.conv1", prefix), &mut self.conv1));
+        layers.push((format!("{}.bn1", prefix), &mut self.bn1));
+        layers.push((format!("{}.conv2", prefix), &mut self.conv2));
+        layers.push((format!("{}.bn2", prefix), &mut self.bn2));
+        layers.push((format!("{}.drop_path", prefix), &mut self.drop_path));
+        if let Some(ds) = &mut self.downsample {
+            for (i, layer) in ds.layers.iter_mut().enumerate() {
+                layers.push((format!("{}.downsample.{}", prefix, i), layer.as_mut()));
+            }
         }
     }
 
@@ -59,6 +100,7 @@ impl ResidualBlock {
 
         out = self.conv2.forward(&out)?;
         out = self.bn2.forward(&out)?;
+        out = self.drop_path.forward(&out)?;
 
         out = out + identity;
         out = self.relu.forward(&out)?;
@@ -76,24 +118,43 @@ pub struct ResNet {
     layer2: Vec<ResidualBlock>,
     layer3: Vec<ResidualBlock>,
     layer4: Vec<ResidualBlock>,
-    avgpool: AvgPool2d,
+    avgpool: AdaptiveAvgPool2d,
     fc: Linear,
+    num_classes: usize,
 }
 
 impl ResNet {
     pub fn resnet18(num_classes: usize) -> Self {
         ResNet {
             conv1: Conv2d::new(3, 64, 7, 2, 3, true),
-            bn1: BatchNorm2d::new(64, 1e-5, 0.1, true),
+            bn1: BatchNorm2d::new(64, numerics().norm_eps, 0.1, true),
             relu: ReLU::new(),
-            maxpool: MaxPool2d::new(3, 2),
+            maxpool: MaxPool2d::new((3, 3), (2, 2)).with_padding((1, 1)),
             layer1: make_layer(64, 64, 2, 1),
             layer2: make_layer(64, 128, 2, 2),
             layer3: make_layer(128, 256, 2, 2),
             layer4: make_layer(256, 512, 2, 2),
-            avgpool: AvgPool2d::new(7, 1),
+            avgpool: AdaptiveAvgPool2d::new((1, 1)),
             fc: Linear::new(512, num_classes, true),
+            num_classes,
+        }
+    }
+
+    /// Enables stochastic depth with drop rates rising linearly from 0 for
+    /// the first block to `max_rate` for the last
+    pub fn with_stochastic_depth(mut self, max_rate: f32) -> Self {
+        let blocks: Vec<&mut ResidualBlock> = self
+            .layer1
+            .iter_mut()
+            .chain(self.layer2.iter_mut())
+            .chain(self.layer3.iter_mut())
+            .chain(self.layer4.iter_mut())
+            .collect();
+        let last = blocks.len().saturating_sub(1).max(1) as f32;
+        for (i, block) in blocks.into_iter().enumerate() {
+            block.drop_path = DropPath::new(max_rate * i as f32 / last);
         }
+        self
     }
 
     pub fn forward(&mut self, x: &Tensor) -> Result<Tensor, BellandeError> {
@@ -123,6 +184,101 @@ impl ResNet {
     }
 }
 
+impl ResNet {
+    /// Sublayers with state, named as in the torchvision checkpoints
+    fn layers(&self) -> Vec<(String, &dyn Layer)> {
+        let mut layers: Vec<(String, &dyn Layer)> = vec![
+            ("conv1".to_string(), &self.conv1),
+            ("bn1".to_string(), &self.bn1),
+        ];
+        let stages = [&self.layer1, &self.layer2, &self.layer3, &self.layer4];
+        for (stage, blocks) in stages.into_iter().enumerate() {
+            for (i, block) in blocks.iter().enumerate() {
+                block.layers(&format!("layer{}.{}", stage + 1, i), &mut layers);
+            }
+        }
+        layers.push(("fc".to_string(), &self.fc));
+        layers
+    }
+
+    fn layers_mut(&mut self) -> Vec<(String, &mut dyn Layer)> {
+        let mut layers: Vec<(String, &mut dyn Layer)> = vec![
+            ("conv1".to_string(), &mut self.conv1),
+            ("bn1".to_string(), &mut self.bn1),
+        ];
+        let stages = [
+            &mut self.layer1,
+            &mut self.layer2,
+            &mut self.layer3,
+            &mut self.layer4,
+        ];
+        for (stage, blocks) in stages.into_iter().enumerate() {
+            for (i, block) in blocks.iter_mut().enumerate() {
+                block.layers_mut(&format!("layer{}.{}", stage + 1, i), &mut layers);
+            }
+        }
+        layers.push(("fc".to_string(), &mut self.fc));
+        layers
+    }
+}
+
+impl Model for ResNet {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        ResNet::forward(self, input)
+    }
+
+    fn backward(&mut self, _grad: &Tensor) -> Result<Tensor, BellandeError> {
+        Err(BellandeError::NotImplemented(
+            "ResNet backward is not implemented; the model supports inference only".into(),
+        ))
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        self.layers()
+            .into_iter()
+            .flat_map(|(_, layer)| layer.parameters())
+            .collect()
+    }
+
+    fn train(&mut self) {
+        for (_, layer) in self.layers_mut() {
+            layer.train();
+        }
+    }
+
+    fn eval(&mut self) {
+        for (_, layer) in self.layers_mut() {
+            layer.eval();
+        }
+    }
+
+    fn save(&self, path: &str) -> Result<(), BellandeError> {
+        let config = ModelConfig {
+            input_shape: vec![3, 224, 224],
+            num_classes: self.num_classes,
+            dropout_rate: 0.0,
+            hidden_layers: vec![],
+            blocks: vec![],
+        };
+        write_model_state(path, "ResNet", self.state_dict(), config)
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), BellandeError> {
+        self.load_state_dict(read_state_dict(path)?)
+    }
+
+    fn state_dict(&self) -> HashMap<String, Tensor> {
+        sublayer_state_dict(self.layers())
+    }
+
+    fn load_state_dict(
+        &mut self,
+        state_dict: HashMap<String, Tensor>,
+    ) -> Result<(), BellandeError> {
+        load_sublayer_state_dict(self.layers_mut(), &state_dict)
+    }
+}
+
 fn make_layer(
     in_channels: usize,
     out_channels: usize,
@@ -141,7 +297,12 @@ fn make_layer(
             0,
             true,
         )));
-        sequential.add(Box::new(BatchNorm2d::new(out_channels, 1e-5, 0.1, true)));
+        sequential.add(Box::new(BatchNorm2d::new(
+            out_channels,
+            numerics().norm_eps,
+            0.1,
+            true,
+        )));
         Some(sequential)
     } else {
         None
@@ -15,29 +15,8 @@
 
 use crate::core::{error::BellandeError, tensor::Tensor};
 
-/// Trait defining a neural network layer
-pub trait NeuralLayer: Send + Sync {
-    /// Forward pass
-    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError>;
-
-    /// Backward pass
-    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError>;
-
-    /// Get layer parameters
-    fn parameters(&self) -> Vec<Tensor>;
-
-    /// Get named parameters
-    fn named_parameters(&self) -> Vec<(String, Tensor)>;
-
-    /// Set parameter value
-    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError>;
-
-    /// Set layer to training mode
-    fn train(&mut self);
-
-    /// Set layer to evaluation mode
-    fn eval(&mut self);
-}
+/// Layers held by `Sequential`; kept as an alias of the canonical layer trait
+pub use crate::layer::Layer as NeuralLayer;
 
 /// Sequential container for neural network layers
 pub struct Sequential {
@@ -71,10 +50,6 @@ impl Sequential {
 
     /// Backward pass through all layers in reverse order
     pub fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
-        if !self.training {
-            return Err(BellandeError::InvalidBackward);
-        }
-
         let mut current_grad = grad.clone();
         for layer in self.layers.iter_mut().rev() {
             current_grad = layer.backward(&current_grad)?;
@@ -110,6 +85,47 @@ impl Sequential {
         self.layers.get_mut(index)
     }
 
+    /// Enables or disables per-sample gradients on every layer. Fails if a
+    /// layer with parameters does not support them.
+    pub fn set_per_sample_grads(&mut self, enabled: bool) -> Result<(), BellandeError> {
+        for layer in &mut self.layers {
+            layer.set_per_sample_grads(enabled)?;
+        }
+        Ok(())
+    }
+
+    /// Per-sample gradients of the last backward pass, named like the
+    /// state dict entries and shaped (batch_size, *parameter_shape)
+    pub fn per_sample_grads(&self) -> Vec<(String, Tensor)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, layer)| {
+                layer
+                    .per_sample_grads()
+                    .into_iter()
+                    .map(move |(name, grad)| (format!("layer_{}.{}", i, name), grad))
+            })
+            .collect()
+    }
+
+    /// L2 norm of each sample's gradient over all parameters, e.g. for
+    /// per-sample clipping in differentially private SGD
+    pub fn per_sample_grad_norms(&self) -> Vec<f32> {
+        let mut squared: Vec<f32> = Vec::new();
+        for (_, grad) in self.per_sample_grads() {
+            let batch_size = grad.shape.first().copied().unwrap_or(0);
+            if squared.is_empty() {
+                squared = vec![0.0; batch_size];
+            }
+            let per_sample = grad.data.len() / batch_size.max(1);
+            for (total, sample) in squared.iter_mut().zip(grad.data.chunks(per_sample.max(1))) {
+                *total += sample.iter().map(|g| g * g).sum::<f32>();
+            }
+        }
+        squared.into_iter().map(f32::sqrt).collect()
+    }
+
     /// Set model to training mode
     pub fn train(&mut self) {
         self.training = true;
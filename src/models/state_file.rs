@@ -0,0 +1,372 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, dtype::DataType, error::BellandeError, tensor::Tensor};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Binary tensor file layout: `MAGIC`, a little-endian u64 header length,
+/// a JSON header mapping each tensor name to its shape and byte offset, then
+/// the raw little-endian f32 data of every tensor
+const MAGIC: &[u8; 8] = b"BTSF0001";
+
+#[derive(Serialize, Deserialize)]
+struct TensorEntry {
+    shape: Vec<usize>,
+    /// Byte offset from the start of the data section
+    offset: u64,
+}
+
+impl TensorEntry {
+    /// Size of the tensor data in bytes, `None` if it overflows
+    fn byte_len(&self) -> Option<usize> {
+        self.shape
+            .iter()
+            .try_fold(4usize, |len, &dim| len.checked_mul(dim))
+    }
+
+    /// Byte range of the tensor data in a file whose data section starts at
+    /// `data_start`, `None` if it overflows
+    fn byte_range(&self, data_start: usize) -> Option<Range<usize>> {
+        let start = data_start.checked_add(usize::try_from(self.offset).ok()?)?;
+        let end = start.checked_add(self.byte_len()?)?;
+        Some(start..end)
+    }
+}
+
+/// Writes a state dictionary as a binary tensor file that
+/// `LazyStateDict` can read without parsing the tensor data
+pub fn save_state_file(
+    state_dict: &HashMap<String, Tensor>,
+    path: impl AsRef<Path>,
+) -> Result<(), BellandeError> {
+    let names: BTreeMap<&String, &Tensor> = state_dict.iter().collect();
+    let mut header = BTreeMap::new();
+    let mut offset = 0u64;
+    for (name, tensor) in &names {
+        header.insert(
+            name.to_string(),
+            TensorEntry {
+                shape: tensor.shape.clone(),
+                offset,
+            },
+        );
+        offset += tensor.data.len() as u64 * 4;
+    }
+    let header = serde_json::to_vec(&header).map_err(|_| BellandeError::SerializationError)?;
+
+    let mut writer = BufWriter::new(File::create(path).map_err(BellandeError::IOError)?);
+    writer.write_all(MAGIC).map_err(BellandeError::IOError)?;
+    writer
+        .write_all(&(header.len() as u64).to_le_bytes())
+        .map_err(BellandeError::IOError)?;
+    writer.write_all(&header).map_err(BellandeError::IOError)?;
+    for tensor in names.values() {
+        for value in &tensor.data {
+            writer
+                .write_all(&value.to_le_bytes())
+                .map_err(BellandeError::IOError)?;
+        }
+    }
+    writer.flush().map_err(BellandeError::IOError)
+}
+
+/// Read-only view of a binary tensor file. Opening reads only the header;
+/// each tensor is materialized when it is first requested, so loading part
+/// of a large checkpoint costs only the bytes of that part.
+///
+/// On Unix the file is memory-mapped, and tensors are copied out of the
+/// mapping; elsewhere they are read with positioned reads. The file must not
+/// be modified while it is open.
+pub struct LazyStateDict {
+    entries: BTreeMap<String, TensorEntry>,
+    data_start: usize,
+    source: Source,
+}
+
+enum Source {
+    #[cfg(unix)]
+    Mapped(mapping::Mapping),
+    File(Mutex<File>),
+}
+
+impl LazyStateDict {
+    /// Opens a file written by `save_state_file`, memory-mapping it where
+    /// supported
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, BellandeError> {
+        Self::open_with_mmap(path, true)
+    }
+
+    /// Opens a file written by `save_state_file`, reading tensors with
+    /// plain file reads when `mmap` is false
+    pub fn open_with_mmap(path: impl AsRef<Path>, mmap: bool) -> Result<Self, BellandeError> {
+        let mut file = File::open(path).map_err(BellandeError::IOError)?;
+        let mut prefix = [0u8; 16];
+        file.read_exact(&mut prefix)
+            .map_err(|_| invalid_file("file is too short"))?;
+        if &prefix[..8] != MAGIC {
+            return Err(invalid_file("missing tensor file header"));
+        }
+        let file_len = file.metadata().map_err(BellandeError::IOError)?.len();
+        let header_len = u64::from_le_bytes(prefix[8..].try_into().unwrap_or([0; 8]));
+        // Checked before allocating, so a corrupt length cannot exhaust memory
+        if header_len > file_len.saturating_sub(prefix.len() as u64) {
+            return Err(invalid_file("header length exceeds the file size"));
+        }
+        let header_len = header_len as usize;
+        let file_len = usize::try_from(file_len)
+            .map_err(|_| invalid_file("file is too large for this platform"))?;
+        let mut header = vec![0u8; header_len];
+        file.read_exact(&mut header)
+            .map_err(|_| invalid_file("truncated header"))?;
+        let entries: BTreeMap<String, TensorEntry> =
+            serde_json::from_slice(&header).map_err(|_| BellandeError::SerializationError)?;
+
+        let data_start = prefix.len() + header_len;
+        for (name, entry) in &entries {
+            match entry.byte_range(data_start) {
+                Some(range) if range.end <= file_len => {}
+                _ => return Err(invalid_file(&format!("tensor '{}' is truncated", name))),
+            }
+        }
+
+        let source = Self::source(file, file_len, mmap)?;
+        Ok(LazyStateDict {
+            entries,
+            data_start,
+            source,
+        })
+    }
+
+    #[cfg(unix)]
+    fn source(file: File, len: usize, mmap: bool) -> Result<Source, BellandeError> {
+        if mmap && len > 0 {
+            return mapping::Mapping::new(&file, len)
+                .map(Source::Mapped)
+                .map_err(BellandeError::IOError);
+        }
+        Ok(Source::File(Mutex::new(file)))
+    }
+
+    #[cfg(not(unix))]
+    fn source(file: File, _len: usize, _mmap: bool) -> Result<Source, BellandeError> {
+        Ok(Source::File(Mutex::new(file)))
+    }
+
+    /// Tensor names in sorted order
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// Shape of a tensor, without reading its data
+    pub fn shape(&self, name: &str) -> Option<&[usize]> {
+        self.entries.get(name).map(|entry| entry.shape.as_slice())
+    }
+
+    /// Reads one tensor into memory
+    pub fn tensor(&self, name: &str) -> Result<Tensor, BellandeError> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| BellandeError::InvalidParameter(format!("Unknown tensor '{}'", name)))?;
+        let range = entry
+            .byte_range(self.data_start)
+            .ok_or_else(|| invalid_file(&format!("tensor '{}' is truncated", name)))?;
+
+        let data = match &self.source {
+            #[cfg(unix)]
+            Source::Mapped(mapping) => mapping
+                .bytes()
+                .get(range)
+                .map(decode)
+                .ok_or_else(|| invalid_file(&format!("tensor '{}' is truncated", name)))?,
+            Source::File(file) => {
+                let mut file = file
+                    .lock()
+                    .map_err(|_| BellandeError::RuntimeError("Tensor file lock poisoned".into()))?;
+                let mut bytes = vec![0u8; range.len()];
+                file.seek(SeekFrom::Start(range.start as u64))
+                    .and_then(|_| file.read_exact(&mut bytes))
+                    .map_err(BellandeError::IOError)?;
+                decode(&bytes)
+            }
+        };
+        Ok(Tensor::new(
+            data,
+            entry.shape.clone(),
+            true,
+            Device::CPU,
+            DataType::Float32,
+        ))
+    }
+
+    /// Reads every tensor whose name starts with `prefix`
+    pub fn with_prefix(&self, prefix: &str) -> Result<HashMap<String, Tensor>, BellandeError> {
+        self.entries
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| Ok((name.clone(), self.tensor(name)?)))
+            .collect()
+    }
+
+    /// Reads the whole state dictionary
+    pub fn to_state_dict(&self) -> Result<HashMap<String, Tensor>, BellandeError> {
+        self.with_prefix("")
+    }
+}
+
+fn decode(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Whether `path` starts with the binary tensor file header, as opposed to
+/// e.g. a JSON model file written by `Model::save`
+pub fn is_state_file(path: impl AsRef<Path>) -> bool {
+    let mut magic = [0u8; 8];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map(|_| &magic == MAGIC)
+        .unwrap_or(false)
+}
+
+fn invalid_file(reason: &str) -> BellandeError {
+    BellandeError::InvalidConfiguration(format!("Invalid tensor file: {}", reason))
+}
+
+#[cfg(unix)]
+mod mapping {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    /// Read-only private mapping of a whole file
+    pub(super) struct Mapping {
+        ptr: *mut libc::c_void,
+        len: usize,
+    }
+
+    // The mapping is never written through and lives until drop
+    unsafe impl Send for Mapping {}
+    unsafe impl Sync for Mapping {}
+
+    impl Mapping {
+        pub(super) fn new(file: &File, len: usize) -> io::Result<Self> {
+            // SAFETY: maps `len > 0` bytes of an open file read-only; the
+            // result is checked before use
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    libc::PROT_READ,
+                    libc::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Mapping { ptr, len })
+        }
+
+        pub(super) fn bytes(&self) -> &[u8] {
+            // SAFETY: `ptr` points to `len` readable bytes until `drop`
+            unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            // SAFETY: unmaps exactly the region returned by `mmap`
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_file(name: &str, header: &[u8], header_len: u64, data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("state_file_{}_{}", name, std::process::id()));
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&header_len.to_le_bytes());
+        bytes.extend_from_slice(header);
+        bytes.extend_from_slice(data);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn rejects_header_length_beyond_file() {
+        let path = write_file("long_header", b"{}", u64::MAX, &[]);
+        let result = LazyStateDict::open_with_mmap(&path, false);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(BellandeError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_overflowing_tensor_entry() {
+        let header = format!(
+            r#"{{"weight":{{"shape":[{},{}],"offset":0}}}}"#,
+            usize::MAX,
+            2
+        );
+        let path = write_file("overflow", header.as_bytes(), header.len() as u64, &[0; 8]);
+        let result = LazyStateDict::open_with_mmap(&path, false);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(BellandeError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_tensor_data() {
+        let header = br#"{"weight":{"shape":[4],"offset":8}}"#;
+        let path = write_file("truncated", header, header.len() as u64, &[0; 16]);
+        let result = LazyStateDict::open_with_mmap(&path, false);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(BellandeError::InvalidConfiguration(_))
+        ));
+    }
+}
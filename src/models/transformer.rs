@@ -0,0 +1,630 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::inference::beam_search::{BeamSearch, Hypothesis, StepDecoder};
+use crate::inference::generation::GenerationOptions;
+use crate::layer::embedding::Embedding;
+use crate::layer::linear::Linear;
+use crate::layer::positional::SinusoidalPositionalEncoding;
+use crate::layer::transformer::{
+    causal_mask, combine_masks, token_padding_mask, tokenwise_backward, tokenwise_forward,
+    DecoderCache, TransformerDecoder, TransformerEncoder,
+};
+use crate::layer::Layer;
+use crate::models::models::{read_state_dict, Model, ModelConfig, ModelState};
+use std::collections::HashMap;
+
+/// Hyperparameters of an encoder-decoder `Transformer`
+#[derive(Clone, Debug)]
+pub struct TransformerConfig {
+    pub src_vocab_size: usize,
+    pub tgt_vocab_size: usize,
+    pub embed_dim: usize,
+    pub num_heads: usize,
+    pub ff_dim: usize,
+    pub num_encoder_layers: usize,
+    pub num_decoder_layers: usize,
+    pub dropout: f32,
+    pub max_len: usize,
+    pub pad_token: Option<usize>,
+    pub tie_weights: bool,
+}
+
+impl TransformerConfig {
+    /// Six encoder and decoder layers, a feed-forward width of
+    /// `4 * embed_dim`, dropout 0.1 and sequences of up to 512 tokens
+    pub fn new(
+        src_vocab_size: usize,
+        tgt_vocab_size: usize,
+        embed_dim: usize,
+        num_heads: usize,
+    ) -> Self {
+        TransformerConfig {
+            src_vocab_size,
+            tgt_vocab_size,
+            embed_dim,
+            num_heads,
+            ff_dim: 4 * embed_dim,
+            num_encoder_layers: 6,
+            num_decoder_layers: 6,
+            dropout: 0.1,
+            max_len: 512,
+            pad_token: None,
+            tie_weights: false,
+        }
+    }
+
+    pub fn with_ff_dim(mut self, ff_dim: usize) -> Self {
+        self.ff_dim = ff_dim;
+        self
+    }
+
+    pub fn with_layers(mut self, num_encoder_layers: usize, num_decoder_layers: usize) -> Self {
+        self.num_encoder_layers = num_encoder_layers;
+        self.num_decoder_layers = num_decoder_layers;
+        self
+    }
+
+    pub fn with_dropout(mut self, dropout: f32) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Padding token shared by source and target vocabularies; attention
+    /// ignores padded positions and their embeddings receive no gradient
+    pub fn with_pad_token(mut self, pad_token: usize) -> Self {
+        self.pad_token = Some(pad_token);
+        self
+    }
+
+    /// Shares the (tgt_vocab_size, embed_dim) target embedding matrix with
+    /// the output projection, which keeps its own bias. The model then has
+    /// a single `tgt_embed.weight` parameter that receives the gradients of
+    /// both uses.
+    pub fn with_tie_weights(mut self, tie_weights: bool) -> Self {
+        self.tie_weights = tie_weights;
+        self
+    }
+
+    fn validate(&self) -> Result<(), BellandeError> {
+        if self.embed_dim == 0 || self.num_heads == 0 || self.embed_dim % self.num_heads != 0 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Embedding dimension {} must be a positive multiple of the {} heads",
+                self.embed_dim, self.num_heads
+            )));
+        }
+        if self.num_encoder_layers == 0 || self.num_decoder_layers == 0 {
+            return Err(BellandeError::InvalidConfiguration(
+                "Encoder and decoder need at least one layer each".into(),
+            ));
+        }
+        if !(0.0..1.0).contains(&self.dropout) {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Dropout must be in [0, 1), got {}",
+                self.dropout
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Encoder-decoder Transformer for sequence-to-sequence tasks. Token ids
+/// are passed as float tensors of shape (batch, seq_len).
+///
+/// Through the `Model` trait the input is a (batch, 2, seq_len) tensor
+/// holding the source tokens in row 0 and the decoder input tokens in
+/// row 1, and the output holds (batch, seq_len, tgt_vocab_size) logits.
+pub struct Transformer {
+    config: TransformerConfig,
+    src_embed: Embedding,
+    tgt_embed: Embedding,
+    src_pos: SinusoidalPositionalEncoding,
+    tgt_pos: SinusoidalPositionalEncoding,
+    encoder: TransformerEncoder,
+    decoder: TransformerDecoder,
+    generator: Linear,
+    input_shape: Option<Vec<usize>>,
+    training: bool,
+}
+
+impl Transformer {
+    pub fn new(config: TransformerConfig) -> Result<Self, BellandeError> {
+        config.validate()?;
+        let embed_dim = config.embed_dim;
+        let embedding = |vocab_size| match config.pad_token {
+            Some(pad) => Embedding::new(vocab_size, embed_dim).with_padding_idx(pad),
+            None => Ok(Embedding::new(vocab_size, embed_dim)),
+        };
+        let positions = || {
+            SinusoidalPositionalEncoding::new(embed_dim, config.max_len)
+                .with_input_scale((embed_dim as f32).sqrt())
+        };
+
+        let mut model = Transformer {
+            src_embed: embedding(config.src_vocab_size)?,
+            tgt_embed: embedding(config.tgt_vocab_size)?,
+            src_pos: positions(),
+            tgt_pos: positions(),
+            encoder: TransformerEncoder::new(
+                config.num_encoder_layers,
+                embed_dim,
+                config.num_heads,
+                config.ff_dim,
+                config.dropout,
+            ),
+            decoder: TransformerDecoder::new(
+                config.num_decoder_layers,
+                embed_dim,
+                config.num_heads,
+                config.ff_dim,
+                config.dropout,
+            ),
+            generator: Linear::new(embed_dim, config.tgt_vocab_size, true),
+            input_shape: None,
+            training: true,
+            config,
+        };
+        if model.config.tie_weights {
+            model.sync_tied_weight()?;
+        }
+        Ok(model)
+    }
+
+    /// Copies the target embedding matrix into the tied output projection
+    fn sync_tied_weight(&mut self) -> Result<(), BellandeError> {
+        self.generator
+            .set_parameter("weight", self.tgt_embed.weight().clone())
+    }
+
+    pub fn config(&self) -> &TransformerConfig {
+        &self.config
+    }
+
+    /// Mask of shape (batch, query_len, key_len) hiding padded keys, or
+    /// `None` when there is no padding token
+    fn padding_mask(
+        &self,
+        keys: &Tensor,
+        query_len: usize,
+    ) -> Result<Option<Tensor>, BellandeError> {
+        self.config
+            .pad_token
+            .map(|pad| token_padding_mask(keys, pad, query_len))
+            .transpose()
+    }
+
+    /// Causal mask over the target, combined with its padding mask
+    fn target_mask(&self, tgt: &Tensor) -> Result<Tensor, BellandeError> {
+        let causal = causal_mask(tgt.shape[1]);
+        match self.padding_mask(tgt, tgt.shape[1])? {
+            Some(mask) => combine_masks(&causal, &mask),
+            None => Ok(causal),
+        }
+    }
+
+    fn check_tokens(tokens: &Tensor, name: &str) -> Result<(), BellandeError> {
+        if tokens.shape.len() != 2 || tokens.shape[1] == 0 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Expected {} tokens of shape (batch, seq_len), got {:?}",
+                name, tokens.shape
+            )));
+        }
+        Ok(())
+    }
+
+    /// Encodes (batch, src_len) source tokens into (batch, src_len,
+    /// embed_dim) memory for the decoder
+    pub fn encode(&mut self, src: &Tensor) -> Result<Tensor, BellandeError> {
+        Self::check_tokens(src, "source")?;
+        let mask = self.padding_mask(src, src.shape[1])?;
+        let embedded = self.src_embed.forward(src)?;
+        let embedded = self.src_pos.forward(&embedded)?;
+        self.encoder.forward(&embedded, mask.as_ref())
+    }
+
+    /// Decodes (batch, tgt_len) target tokens against the memory of `src`
+    /// into (batch, tgt_len, tgt_vocab_size) logits, each position seeing
+    /// only itself and earlier targets
+    pub fn decode(
+        &mut self,
+        tgt: &Tensor,
+        memory: &Tensor,
+        src: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        Self::check_tokens(tgt, "target")?;
+        let tgt_mask = self.target_mask(tgt)?;
+        let memory_mask = self.padding_mask(src, tgt.shape[1])?;
+        let embedded = self.tgt_embed.forward(tgt)?;
+        let embedded = self.tgt_pos.forward(&embedded)?;
+        let output =
+            self.decoder
+                .forward(&embedded, memory, Some(&tgt_mask), memory_mask.as_ref())?;
+        tokenwise_forward(&mut self.generator, &output)
+    }
+
+    /// Empty attention caches for `decode_step`
+    pub fn new_decoder_cache(&self) -> DecoderCache {
+        self.decoder.new_cache()
+    }
+
+    /// Decodes only the new (batch, new_len) target tokens that follow the
+    /// positions already in `cache`, returning their logits. Generation
+    /// calls this once per token instead of re-decoding the whole prefix.
+    /// For inference: skips dropout and records nothing for backward.
+    pub fn decode_step(
+        &mut self,
+        tokens: &Tensor,
+        memory: &Tensor,
+        src: &Tensor,
+        cache: &mut DecoderCache,
+    ) -> Result<Tensor, BellandeError> {
+        Self::check_tokens(tokens, "target")?;
+        let memory_mask = self.padding_mask(src, tokens.shape[1])?;
+        let embedded = self.tgt_embed.forward(tokens)?;
+        let embedded = self.tgt_pos.forward_at(&embedded, cache.len())?;
+        let output =
+            self.decoder
+                .forward_incremental(&embedded, memory, memory_mask.as_ref(), cache)?;
+        tokenwise_forward(&mut self.generator, &output)
+    }
+
+    /// Teacher-forced logits for (batch, tgt_len) decoder input tokens
+    pub fn forward_seq2seq(&mut self, src: &Tensor, tgt: &Tensor) -> Result<Tensor, BellandeError> {
+        if src.shape.first() != tgt.shape.first() {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Source {:?} and target {:?} batches differ",
+                src.shape, tgt.shape
+            )));
+        }
+        let memory = self.encode(src)?;
+        self.decode(tgt, &memory, src)
+    }
+
+    /// Backward pass of `forward_seq2seq`, accumulating the gradients of
+    /// every parameter
+    pub fn backward_seq2seq(&mut self, grad: &Tensor) -> Result<(), BellandeError> {
+        let grad = tokenwise_backward(&mut self.generator, grad)?;
+        if self.config.tie_weights {
+            if let Some(grad_weight) = self.generator.take_weight_grad() {
+                self.tgt_embed.accumulate_weight_grad(&grad_weight)?;
+            }
+        }
+        let (grad_tgt, grad_memory) = self.decoder.backward(&grad)?;
+        let grad_tgt = Layer::backward(&mut self.tgt_pos, &grad_tgt)?;
+        Layer::backward(&mut self.tgt_embed, &grad_tgt)?;
+
+        let grad_src = self.encoder.backward(&grad_memory)?;
+        let grad_src = Layer::backward(&mut self.src_pos, &grad_src)?;
+        Layer::backward(&mut self.src_embed, &grad_src)?;
+        Ok(())
+    }
+
+    /// Greedy autoregressive decoding of (batch, src_len) source tokens,
+    /// one cached `decode_step` per token. Each sequence starts from `bos`
+    /// and stops after `eos` or `max_len` generated tokens; the returned
+    /// sequences exclude `bos`. Runs in evaluation mode and restores the
+    /// previous mode afterwards.
+    pub fn greedy_decode(
+        &mut self,
+        src: &Tensor,
+        bos: usize,
+        eos: usize,
+        max_len: usize,
+    ) -> Result<Vec<Vec<usize>>, BellandeError> {
+        self.generate(src, max_len, &GenerationOptions::new(bos, eos))
+    }
+
+    /// Generates up to `max_len` tokens for every (batch, src_len) prompt
+    /// of source tokens, choosing each token with `options.sampler`. Like
+    /// `greedy_decode`, which it generalizes, the returned sequences exclude
+    /// `bos` and end at the first `eos`.
+    pub fn generate(
+        &mut self,
+        prompt: &Tensor,
+        max_len: usize,
+        options: &GenerationOptions,
+    ) -> Result<Vec<Vec<usize>>, BellandeError> {
+        self.generate_streaming(prompt, max_len, options, |_, _| {})
+    }
+
+    /// `generate` that also calls `on_token(sequence, token)` as soon as
+    /// each token of an unfinished sequence is chosen, e.g. to print text
+    /// while it is produced
+    pub fn generate_streaming(
+        &mut self,
+        prompt: &Tensor,
+        max_len: usize,
+        options: &GenerationOptions,
+        on_token: impl FnMut(usize, usize),
+    ) -> Result<Vec<Vec<usize>>, BellandeError> {
+        self.check_decode_len(max_len)?;
+        self.in_eval_mode(|model| model.generate_inner(prompt, max_len, options, on_token))
+    }
+
+    /// Beam search decoding of each of the (batch, src_len) source
+    /// sequences from `bos`, generating at most `max_len` tokens. Returns
+    /// the hypotheses of every sequence, best first. Runs in evaluation
+    /// mode and restores the previous mode afterwards.
+    pub fn beam_search(
+        &mut self,
+        src: &Tensor,
+        bos: usize,
+        max_len: usize,
+        search: &BeamSearch,
+    ) -> Result<Vec<Vec<Hypothesis>>, BellandeError> {
+        self.check_decode_len(max_len)?;
+        Self::check_tokens(src, "source")?;
+        let src_len = src.shape[1];
+        self.in_eval_mode(|model| {
+            src.data
+                .chunks(src_len)
+                .map(|tokens| {
+                    let mut src = Tensor::zeros(&[1, src_len]);
+                    src.data.copy_from_slice(tokens);
+                    let memory = model.encode(&src)?;
+                    let cache = model.new_decoder_cache();
+                    let mut decoder = TransformerStepDecoder { model, memory, src };
+                    search.search(&mut decoder, bos, cache, max_len)
+                })
+                .collect()
+        })
+    }
+
+    fn check_decode_len(&self, max_len: usize) -> Result<(), BellandeError> {
+        if max_len >= self.config.max_len {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Cannot decode {} tokens with positions up to {}",
+                max_len, self.config.max_len
+            )));
+        }
+        Ok(())
+    }
+
+    /// Runs `f` in evaluation mode, restoring the previous mode afterwards
+    fn in_eval_mode<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let training = self.training;
+        self.eval();
+        let result = f(self);
+        if training {
+            self.train();
+        }
+        result
+    }
+
+    fn generate_inner(
+        &mut self,
+        src: &Tensor,
+        max_len: usize,
+        options: &GenerationOptions,
+        mut on_token: impl FnMut(usize, usize),
+    ) -> Result<Vec<Vec<usize>>, BellandeError> {
+        let (bos, eos) = (options.bos, options.eos);
+        let memory = self.encode(src)?;
+        let batch_size = src.shape[0];
+        let vocab_size = self.config.tgt_vocab_size;
+        let mut sequences = vec![vec![bos]; batch_size];
+        let mut finished = vec![false; batch_size];
+
+        let mut cache = self.new_decoder_cache();
+        for _ in 0..max_len {
+            if finished.iter().all(|&done| done) {
+                break;
+            }
+            let mut tokens = Tensor::zeros(&[batch_size, 1]);
+            for (token, sequence) in tokens.data.iter_mut().zip(&sequences) {
+                *token = sequence[sequence.len() - 1] as f32;
+            }
+
+            let logits = self.decode_step(&tokens, &memory, src, &mut cache)?;
+            for (b, sequence) in sequences.iter_mut().enumerate() {
+                // Finished sequences keep emitting `eos` so lengths line up
+                let next = if finished[b] {
+                    eos
+                } else {
+                    let next = options
+                        .sampler
+                        .sample(&logits.data[b * vocab_size..(b + 1) * vocab_size])?;
+                    on_token(b, next);
+                    next
+                };
+                finished[b] |= next == eos;
+                sequence.push(next);
+            }
+        }
+
+        Ok(sequences
+            .into_iter()
+            .map(|sequence| {
+                let mut tokens: Vec<usize> = sequence.into_iter().skip(1).collect();
+                if let Some(end) = tokens.iter().position(|&token| token == eos) {
+                    tokens.truncate(end + 1);
+                }
+                tokens
+            })
+            .collect())
+    }
+
+    /// Parameters named `src_embed.*`, `tgt_embed.*`, `encoder.*`,
+    /// `decoder.*` and `generator.*`. With tied weights there is no
+    /// `generator.weight`.
+    pub fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let components = [
+            ("src_embed", self.src_embed.named_parameters()),
+            ("tgt_embed", self.tgt_embed.named_parameters()),
+            ("encoder", self.encoder.named_parameters()),
+            ("decoder", self.decoder.named_parameters()),
+            ("generator", self.generator.named_parameters()),
+        ];
+        components
+            .into_iter()
+            .flat_map(|(prefix, params)| {
+                params
+                    .into_iter()
+                    .map(move |(name, param)| (format!("{}.{}", prefix, name), param))
+            })
+            .filter(|(name, _)| !(self.config.tie_weights && name == "generator.weight"))
+            .collect()
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Tensor) -> Result<(), BellandeError> {
+        let (component, rest) = name.split_once('.').unwrap_or((name, ""));
+        match component {
+            "src_embed" => self.src_embed.set_parameter(rest, value),
+            "tgt_embed" => {
+                self.tgt_embed.set_parameter(rest, value)?;
+                if self.config.tie_weights && rest == "weight" {
+                    self.sync_tied_weight()?;
+                }
+                Ok(())
+            }
+            "encoder" => self.encoder.set_parameter(rest, value),
+            "decoder" => self.decoder.set_parameter(rest, value),
+            "generator" if self.config.tie_weights && rest == "weight" => {
+                Err(BellandeError::InvalidParameter(
+                    "'generator.weight' is tied to 'tgt_embed.weight'".into(),
+                ))
+            }
+            "generator" => self.generator.set_parameter(rest, value),
+            _ => Err(BellandeError::InvalidParameter(format!(
+                "Unknown parameter '{}'",
+                name
+            ))),
+        }
+    }
+}
+
+/// Extends the hypotheses of one source sequence, each with its own
+/// attention cache
+struct TransformerStepDecoder<'a> {
+    model: &'a mut Transformer,
+    memory: Tensor,
+    src: Tensor,
+}
+
+impl StepDecoder for TransformerStepDecoder<'_> {
+    type State = DecoderCache;
+
+    fn step(&mut self, token: usize, cache: &mut DecoderCache) -> Result<Vec<f32>, BellandeError> {
+        let mut tokens = Tensor::zeros(&[1, 1]);
+        tokens.data[0] = token as f32;
+        let logits = self
+            .model
+            .decode_step(&tokens, &self.memory, &self.src, cache)?;
+        Ok(logits.data)
+    }
+}
+
+impl Model for Transformer {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        if input.shape.len() != 3 || input.shape[1] != 2 {
+            return Err(BellandeError::InvalidShape(format!(
+                "Expected (batch, 2, seq_len) source and target tokens, got {:?}",
+                input.shape
+            )));
+        }
+        let (batch_size, seq_len) = (input.shape[0], input.shape[2]);
+        let mut src = Tensor::zeros(&[batch_size, seq_len]);
+        let mut tgt = Tensor::zeros(&[batch_size, seq_len]);
+        for (b, pair) in input.data.chunks(2 * seq_len.max(1)).enumerate() {
+            src.data[b * seq_len..(b + 1) * seq_len].copy_from_slice(&pair[..seq_len]);
+            tgt.data[b * seq_len..(b + 1) * seq_len].copy_from_slice(&pair[seq_len..]);
+        }
+
+        let output = self.forward_seq2seq(&src, &tgt)?;
+        self.input_shape = Some(input.shape.clone());
+        Ok(output)
+    }
+
+    /// Token inputs are not differentiable, so the returned gradient is zero
+    fn backward(&mut self, grad: &Tensor) -> Result<Tensor, BellandeError> {
+        let input_shape = self
+            .input_shape
+            .take()
+            .ok_or_else(|| BellandeError::RuntimeError("Forward pass not called".into()))?;
+        self.backward_seq2seq(grad)?;
+        Ok(Tensor::zeros(&input_shape))
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        self.named_parameters()
+            .into_iter()
+            .map(|(_, param)| param)
+            .collect()
+    }
+
+    fn train(&mut self) {
+        self.training = true;
+        self.encoder.train();
+        self.decoder.train();
+    }
+
+    fn eval(&mut self) {
+        self.training = false;
+        self.encoder.eval();
+        self.decoder.eval();
+    }
+
+    fn save(&self, path: &str) -> Result<(), BellandeError> {
+        let state_dict = self.state_dict();
+        let state = ModelState {
+            model_type: "Transformer".to_string(),
+            shapes: state_dict
+                .iter()
+                .map(|(k, v)| (k.clone(), v.shape.clone()))
+                .collect(),
+            state_dict: state_dict.into_iter().map(|(k, v)| (k, v.data)).collect(),
+            config: ModelConfig {
+                input_shape: vec![2, self.config.max_len],
+                num_classes: self.config.tgt_vocab_size,
+                dropout_rate: self.config.dropout,
+                hidden_layers: vec![self.config.embed_dim, self.config.ff_dim],
+                blocks: vec![],
+            },
+            temperature: None,
+        };
+
+        let file = std::fs::File::create(path).map_err(BellandeError::IOError)?;
+        serde_json::to_writer(file, &state).map_err(|_| BellandeError::SerializationError)
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), BellandeError> {
+        self.load_state_dict(read_state_dict(path)?)
+    }
+
+    fn state_dict(&self) -> HashMap<String, Tensor> {
+        self.named_parameters().into_iter().collect()
+    }
+
+    fn load_state_dict(
+        &mut self,
+        state_dict: HashMap<String, Tensor>,
+    ) -> Result<(), BellandeError> {
+        for (name, _) in self.named_parameters() {
+            let param = state_dict.get(&name).ok_or_else(|| {
+                BellandeError::RuntimeError(format!("Missing parameter: {}", name))
+            })?;
+            self.set_parameter(&name, param.clone()).map_err(|e| {
+                BellandeError::RuntimeError(format!("Failed to set parameter {}: {}", name, e))
+            })?;
+        }
+        Ok(())
+    }
+}
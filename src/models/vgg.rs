@@ -16,14 +16,21 @@
 use crate::core::{error::BellandeError, tensor::Tensor};
 use crate::layer::dropout::Dropout;
 use crate::layer::{
-    activation::ReLU, avgpool2d::AvgPool2d, conv::Conv2d, linear::Linear, pooling::MaxPool2d,
+    activation::ReLU, adaptive_pool::AdaptiveAvgPool2d, conv::Conv2d, linear::Linear,
+    pooling::MaxPool2d, Layer,
+};
+use crate::models::models::{
+    load_sublayer_state_dict, read_state_dict, sublayer_state_dict, write_model_state, Model,
+    ModelConfig,
 };
 use crate::models::sequential::Sequential;
+use std::collections::HashMap;
 
 pub struct VGG {
     features: Sequential,
-    avgpool: AvgPool2d,
+    avgpool: AdaptiveAvgPool2d,
     classifier: Sequential,
+    num_classes: usize,
 }
 
 impl VGG {
@@ -82,8 +89,9 @@ impl VGG {
 
         VGG {
             features,
-            avgpool: AvgPool2d::new(7, 1),
+            avgpool: AdaptiveAvgPool2d::new((7, 7)),
             classifier,
+            num_classes,
         }
     }
 
@@ -95,3 +103,81 @@ impl VGG {
         Ok(out)
     }
 }
+
+impl VGG {
+    /// Sublayers with state, named as in the torchvision checkpoints
+    fn layers(&self) -> Vec<(String, &dyn Layer)> {
+        let features = self.features.layers.iter().enumerate();
+        let classifier = self.classifier.layers.iter().enumerate();
+        features
+            .map(|(i, layer)| (format!("features.{}", i), layer.as_ref()))
+            .chain(classifier.map(|(i, layer)| (format!("classifier.{}", i), layer.as_ref())))
+            .collect()
+    }
+
+    fn layers_mut(&mut self) -> Vec<(String, &mut dyn Layer)> {
+        let mut layers: Vec<(String, &mut dyn Layer)> = Vec::new();
+        for (i, layer) in self.features.layers.iter_mut().enumerate() {
+            layers.push((format!("features.{}", i), layer.as_mut()));
+        }
+        for (i, layer) in self.classifier.layers.iter_mut().enumerate() {
+            layers.push((format!("classifier.{}", i), layer.as_mut()));
+        }
+        layers
+    }
+}
+
+impl Model for VGG {
+    fn forward(&mut self, input: &Tensor) -> Result<Tensor, BellandeError> {
+        VGG::forward(self, input)
+    }
+
+    fn backward(&mut self, _grad: &Tensor) -> Result<Tensor, BellandeError> {
+        Err(BellandeError::NotImplemented(
+            "VGG backward is not implemented; the model supports inference only".into(),
+        ))
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        self.layers()
+            .into_iter()
+            .flat_map(|(_, layer)| layer.parameters())
+            .collect()
+    }
+
+    fn train(&mut self) {
+        self.features.train();
+        self.classifier.train();
+    }
+
+    fn eval(&mut self) {
+        self.features.eval();
+        self.classifier.eval();
+    }
+
+    fn save(&self, path: &str) -> Result<(), BellandeError> {
+        let config = ModelConfig {
+            input_shape: vec![3, 224, 224],
+            num_classes: self.num_classes,
+            dropout_rate: 0.5,
+            hidden_layers: vec![4096, 4096],
+            blocks: vec![],
+        };
+        write_model_state(path, "VGG", self.state_dict(), config)
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), BellandeError> {
+        self.load_state_dict(read_state_dict(path)?)
+    }
+
+    fn state_dict(&self) -> HashMap<String, Tensor> {
+        sublayer_state_dict(self.layers())
+    }
+
+    fn load_state_dict(
+        &mut self,
+        state_dict: HashMap<String, Tensor>,
+    ) -> Result<(), BellandeError> {
+        load_sublayer_state_dict(self.layers_mut(), &state_dict)
+    }
+}
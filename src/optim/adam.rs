@@ -13,18 +13,49 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
-use std::collections::HashMap;
+use crate::core::{dtype::DataType, error::BellandeError, tensor::Tensor};
+use crate::optim::precision::{master_copies, param_chunks, round_in_place, validate_state_dtype};
+use crate::optim::{run_fused, Optimizer, OptimizerState, ParameterGroup, FUSED_CHUNK};
 
 pub struct Adam {
-    params: Vec<Tensor>,
-    lr: f32,
-    betas: (f32, f32),
+    param_groups: Vec<ParameterGroup>,
+    /// First and second moments of every parameter, by group
+    m: Vec<Vec<Vec<f32>>>,
+    v: Vec<Vec<Vec<f32>>>,
+    /// FP32 copies of the parameters, empty without master weights
+    masters: Vec<Vec<Vec<f32>>>,
+    state: OptimizerState,
+}
+
+/// Per-step constants of the fused Adam update
+#[derive(Clone, Copy)]
+struct AdamStep {
+    beta1: f32,
+    beta2: f32,
     eps: f32,
     weight_decay: f32,
-    m: HashMap<usize, Vec<f32>>,
-    v: HashMap<usize, Vec<f32>>,
-    step: usize,
+    /// `lr / (1 - beta1^t)`
+    step_size: f32,
+    /// `sqrt(1 - beta2^t)`
+    bias_correction2_sqrt: f32,
+}
+
+impl AdamStep {
+    /// Weight decay, both moment updates and the parameter update in one
+    /// pass over equal-length slices, a loop the compiler vectorizes
+    fn update(&self, params: &mut [f32], grads: &[f32], m: &mut [f32], v: &mut [f32]) {
+        for (((p, &g), m), v) in params
+            .iter_mut()
+            .zip(grads)
+            .zip(m.iter_mut())
+            .zip(v.iter_mut())
+        {
+            let g = g + self.weight_decay * *p;
+            *m = self.beta1 * *m + (1.0 - self.beta1) * g;
+            *v = self.beta2 * *v + (1.0 - self.beta2) * g * g;
+            *p -= self.step_size * *m / (v.sqrt() / self.bias_correction2_sqrt + self.eps);
+        }
+    }
 }
 
 impl Adam {
@@ -35,78 +66,173 @@ impl Adam {
         eps: f32,
         weight_decay: f32,
     ) -> Self {
-        let mut m = HashMap::new();
-        let mut v = HashMap::new();
+        let group = ParameterGroup::new(params)
+            .with_lr(lr)
+            .with_betas(betas.0, betas.1)
+            .with_eps(eps)
+            .with_weight_decay(weight_decay);
+
+        let mut adam = Adam {
+            param_groups: Vec::new(),
+            m: Vec::new(),
+            v: Vec::new(),
+            masters: Vec::new(),
+            state: OptimizerState::new(),
+        };
+        adam.add_param_group(group);
+        adam
+    }
 
-        for (idx, param) in params.iter().enumerate() {
-            m.insert(idx, vec![0.0; param.data.len()]);
-            v.insert(idx, vec![0.0; param.data.len()]);
+    /// Stores both moments at `dtype`, FP32 by default
+    pub fn with_state_dtype(mut self, dtype: DataType) -> Result<Self, BellandeError> {
+        validate_state_dtype(dtype)?;
+        for group in &mut self.param_groups {
+            group.state_dtype = dtype;
         }
+        Ok(self)
+    }
 
-        Adam {
-            params,
-            lr,
-            betas,
-            eps,
-            weight_decay,
-            m,
-            v,
-            step: 0,
+    /// Updates FP32 master copies of the parameters and writes them back
+    /// rounded to each parameter's dtype, so that updates too small for
+    /// FP16 parameters still accumulate
+    pub fn with_master_weights(mut self, enabled: bool) -> Self {
+        for (group, masters) in self.param_groups.iter_mut().zip(&mut self.masters) {
+            group.master_weights = enabled;
+            *masters = Self::masters(group);
         }
+        self
     }
 
-    pub fn step(&mut self) -> Result<(), BellandeError> {
-        self.step += 1;
-        let bias_correction1 = 1.0 - self.betas.0.powi(self.step as i32);
-        let bias_correction2 = 1.0 - self.betas.1.powi(self.step as i32);
-
-        for (idx, param) in self.params.iter_mut().enumerate() {
-            if let Some(grad) = &param.grad {
-                let m = self.m.get_mut(&idx).unwrap();
-                let v = self.v.get_mut(&idx).unwrap();
-
-                for ((p, g), (m, v)) in param
-                    .data
-                    .iter_mut()
-                    .zip(grad.iter())
-                    .zip(m.iter_mut().zip(v.iter_mut()))
-                {
-                    if self.weight_decay != 0.0 {
-                        *g += self.weight_decay * *p;
-                    }
-
-                    // Update biased first moment estimate
-                    *m = self.betas.0 * *m + (1.0 - self.betas.0) * g;
+    fn masters(group: &ParameterGroup) -> Vec<Vec<f32>> {
+        if group.master_weights {
+            master_copies(&group.params)
+        } else {
+            vec![Vec::new(); group.params.len()]
+        }
+    }
 
-                    // Update biased second raw moment estimate
-                    *v = self.betas.1 * *v + (1.0 - self.betas.1) * g * g;
+    pub fn get_lr(&self) -> f32 {
+        self.get_learning_rate()
+    }
 
-                    // Compute bias-corrected moment estimates
-                    let m_hat = *m / bias_correction1;
-                    let v_hat = *v / bias_correction2;
+    pub fn set_lr(&mut self, lr: f32) {
+        self.set_learning_rate(lr);
+    }
+}
 
-                    // Update parameters
-                    *p -= self.lr * m_hat / (v_hat.sqrt() + self.eps);
-                }
+impl Optimizer for Adam {
+    /// Updates every parameter with a gradient. The parameters are split
+    /// into chunks that are updated in parallel for large models.
+    fn step(&mut self) -> Result<(), BellandeError> {
+        self.state.increment_step();
+        let step = self.state.step as i32;
+
+        // Gradients are read up front since the chunks borrow them while the
+        // parameters are updated in place
+        let grads: Vec<Vec<Option<Tensor>>> = self
+            .param_groups
+            .iter()
+            .map(|group| group.params.iter().map(Tensor::grad).collect())
+            .collect();
+
+        let mut chunks = Vec::new();
+        let mut elements = 0;
+        let groups = self
+            .param_groups
+            .iter_mut()
+            .zip(&mut self.masters)
+            .zip(&grads);
+        for (((group, masters), grads), (m, v)) in groups.zip(self.m.iter_mut().zip(&mut self.v)) {
+            let (beta1, beta2) = group.betas.unwrap_or((0.9, 0.999));
+            let update = AdamStep {
+                beta1,
+                beta2,
+                eps: group.eps,
+                weight_decay: group.weight_decay,
+                step_size: group.lr / (1.0 - beta1.powi(step)),
+                bias_correction2_sqrt: (1.0 - beta2.powi(step)).sqrt(),
+            };
+            let state_dtype = group.state_dtype;
+
+            let params = group.params.iter_mut().zip(masters.iter_mut()).zip(grads);
+            for (((param, master), grad), (m, v)) in params.zip(m.iter_mut().zip(v.iter_mut())) {
+                let Some(grad) = grad else {
+                    continue;
+                };
+                elements += param.data.len();
+                chunks.extend(
+                    param_chunks(&mut param.data, master, param.dtype)
+                        .zip(grad.data.chunks(FUSED_CHUNK))
+                        .zip(m.chunks_mut(FUSED_CHUNK))
+                        .zip(v.chunks_mut(FUSED_CHUNK))
+                        .map(|chunk| (update, state_dtype, chunk)),
+                );
             }
         }
+        run_fused(
+            chunks,
+            elements,
+            |(update, state_dtype, (((p, g), m), v))| {
+                update.update(p.weights, g, m, v);
+                p.finish();
+                round_in_place(m, state_dtype);
+                round_in_place(v, state_dtype);
+            },
+        );
 
         Ok(())
     }
 
-    pub fn zero_grad(&mut self) {
-        for param in &mut self.params {
-            if let Some(grad) = &mut param.grad {
-                grad.iter_mut().for_each(|g| *g = 0.0);
+    fn zero_grad(&mut self) {
+        for group in &mut self.param_groups {
+            for param in &mut group.params {
+                param.zero_grad();
             }
         }
     }
 
-    pub fn get_lr(&self) -> f32 {
-        self.lr
+    fn get_learning_rate(&self) -> f32 {
+        self.param_groups
+            .first()
+            .map(|group| group.lr)
+            .unwrap_or(0.0)
     }
 
-    pub fn set_lr(&mut self, lr: f32) {
-        self.lr = lr;
+    fn set_learning_rate(&mut self, lr: f32) {
+        for group in &mut self.param_groups {
+            group.lr = lr;
+        }
+    }
+
+    fn name(&self) -> &str {
+        "Adam"
+    }
+
+    fn get_param_groups(&self) -> &[ParameterGroup] {
+        &self.param_groups
+    }
+
+    fn get_param_groups_mut(&mut self) -> &mut [ParameterGroup] {
+        &mut self.param_groups
+    }
+
+    fn add_param_group(&mut self, group: ParameterGroup) {
+        let zeros: Vec<Vec<f32>> = group
+            .params
+            .iter()
+            .map(|param| vec![0.0; param.data.len()])
+            .collect();
+        self.m.push(zeros.clone());
+        self.v.push(zeros);
+        self.masters.push(Self::masters(&group));
+        self.param_groups.push(group);
+    }
+
+    fn state(&self) -> &OptimizerState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut OptimizerState {
+        &mut self.state
     }
 }
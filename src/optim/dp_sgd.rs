@@ -0,0 +1,337 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, random, tensor::Tensor};
+use crate::optim::{Optimizer, OptimizerState, ParameterGroup};
+use std::collections::HashMap;
+
+/// Integer Rényi orders over which the accountant minimizes epsilon; a
+/// slightly conservative grid compared to one with fractional orders
+const RDP_ORDERS: std::ops::RangeInclusive<u32> = 2..=256;
+
+/// Tracks the privacy loss of repeated Poisson-subsampled Gaussian
+/// mechanisms with Rényi differential privacy (Mironov et al., 2019),
+/// converting to (epsilon, delta) on demand
+#[derive(Debug, Clone)]
+pub struct PrivacyAccountant {
+    noise_multiplier: f64,
+    sample_rate: f64,
+    steps: usize,
+}
+
+impl PrivacyAccountant {
+    /// `sample_rate` is the fraction of the dataset in each batch
+    pub fn new(noise_multiplier: f32, sample_rate: f32) -> Result<Self, BellandeError> {
+        if !noise_multiplier.is_finite() || noise_multiplier <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Noise multiplier must be positive, got {}",
+                noise_multiplier
+            )));
+        }
+        if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Sample rate must be in (0, 1], got {}",
+                sample_rate
+            )));
+        }
+        Ok(PrivacyAccountant {
+            noise_multiplier: noise_multiplier as f64,
+            sample_rate: sample_rate as f64,
+            steps: 0,
+        })
+    }
+
+    pub fn step(&mut self) {
+        self.steps += 1;
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Smallest epsilon such that the steps so far are (epsilon, delta)
+    /// differentially private
+    pub fn epsilon(&self, delta: f64) -> f64 {
+        if self.steps == 0 {
+            return 0.0;
+        }
+        RDP_ORDERS
+            .map(|order| {
+                let rdp = self.steps as f64 * self.rdp_per_step(order);
+                rdp + (1.0 / delta).ln() / (order as f64 - 1.0)
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    /// RDP of one subsampled Gaussian step at an integer `order`, from
+    /// `log(sum_k C(order, k) (1 - q)^(order - k) q^k exp((k^2 - k) / 2 sigma^2))`
+    fn rdp_per_step(&self, order: u32) -> f64 {
+        let sigma2 = self.noise_multiplier * self.noise_multiplier;
+        let q = self.sample_rate;
+        if q >= 1.0 {
+            return order as f64 / (2.0 * sigma2);
+        }
+
+        let alpha = order as f64;
+        let mut log_binomial = 0.0;
+        let terms: Vec<f64> = (0..=order)
+            .map(|k| {
+                let k = k as f64;
+                if k > 0.0 {
+                    log_binomial += (alpha - k + 1.0).ln() - k.ln();
+                }
+                log_binomial
+                    + (alpha - k) * (1.0 - q).ln()
+                    + k * q.ln()
+                    + (k * k - k) / (2.0 * sigma2)
+            })
+            .collect();
+        let max = terms.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let log_sum = max + terms.iter().map(|t| (t - max).exp()).sum::<f64>().ln();
+        log_sum / (alpha - 1.0)
+    }
+}
+
+/// Differentially private SGD (Abadi et al., 2016) around any optimizer.
+/// Before each step the per-sample gradients of the batch are clipped to an
+/// L2 norm of `max_grad_norm`, summed, perturbed with Gaussian noise of
+/// standard deviation `noise_multiplier * max_grad_norm` and divided by the
+/// batch size; the wrapped optimizer then steps on that gradient.
+///
+/// The wrapped optimizer's parameters must be the model's per-sample
+/// gradient parameters in the same order, e.g. `Sequential::parameters()` of
+/// a model built from `Linear` and `Conv2d` layers (batch normalization
+/// mixes samples and cannot be trained privately).
+pub struct DpSgd<O: Optimizer> {
+    inner: O,
+    max_grad_norm: f32,
+    noise_multiplier: f32,
+    delta: f64,
+    accountant: PrivacyAccountant,
+    pending: Option<Vec<(String, Tensor)>>,
+}
+
+impl<O: Optimizer> DpSgd<O> {
+    pub fn new(
+        inner: O,
+        max_grad_norm: f32,
+        noise_multiplier: f32,
+        sample_rate: f32,
+    ) -> Result<Self, BellandeError> {
+        if !max_grad_norm.is_finite() || max_grad_norm <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Clipping norm must be positive, got {}",
+                max_grad_norm
+            )));
+        }
+        Ok(DpSgd {
+            inner,
+            max_grad_norm,
+            noise_multiplier,
+            delta: 1e-5,
+            accountant: PrivacyAccountant::new(noise_multiplier, sample_rate)?,
+            pending: None,
+        })
+    }
+
+    /// Target delta of the reported (epsilon, delta) guarantee, usually
+    /// below one over the dataset size
+    pub fn with_delta(mut self, delta: f64) -> Result<Self, BellandeError> {
+        if !(delta > 0.0 && delta < 1.0) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Delta must be in (0, 1), got {}",
+                delta
+            )));
+        }
+        self.delta = delta;
+        Ok(self)
+    }
+
+    /// (epsilon, delta) spent by the steps taken so far
+    pub fn privacy_spent(&self) -> (f64, f64) {
+        (self.accountant.epsilon(self.delta), self.delta)
+    }
+
+    pub fn accountant(&self) -> &PrivacyAccountant {
+        &self.accountant
+    }
+
+    pub fn inner(&self) -> &O {
+        &self.inner
+    }
+
+    /// Clips, sums and noises (batch_size, *parameter_shape) per-sample
+    /// gradients into one averaged gradient per parameter
+    pub fn privatize(
+        &self,
+        per_sample_grads: &[(String, Tensor)],
+    ) -> Result<Vec<(String, Tensor)>, BellandeError> {
+        let batch_size = match per_sample_grads.first() {
+            Some((_, grad)) => grad.shape.first().copied().unwrap_or(0),
+            None => 0,
+        };
+        if batch_size == 0 {
+            return Err(BellandeError::InvalidOperation(
+                "DpSgd needs per-sample gradients of a non-empty batch".into(),
+            ));
+        }
+        if let Some((name, _)) = per_sample_grads
+            .iter()
+            .find(|(_, grad)| grad.shape.first() != Some(&batch_size))
+        {
+            return Err(BellandeError::ShapeMismatch(format!(
+                "Per-sample gradient '{}' does not have batch size {}",
+                name, batch_size
+            )));
+        }
+
+        let mut squared_norms = vec![0.0f32; batch_size];
+        for (_, grad) in per_sample_grads {
+            let per_sample = grad.data.len() / batch_size;
+            for (norm, sample) in squared_norms
+                .iter_mut()
+                .zip(grad.data.chunks(per_sample.max(1)))
+            {
+                *norm += sample.iter().map(|g| g * g).sum::<f32>();
+            }
+        }
+        let denom_eps = numerics().denom_eps;
+        let clip_factors: Vec<f32> = squared_norms
+            .iter()
+            .map(|norm| (self.max_grad_norm / (norm.sqrt() + denom_eps)).min(1.0))
+            .collect();
+
+        let noise_std = self.noise_multiplier * self.max_grad_norm;
+        Ok(per_sample_grads
+            .iter()
+            .map(|(name, grad)| {
+                let per_sample = grad.data.len() / batch_size;
+                let mut summed = random::normal(0.0, noise_std, per_sample);
+                for (sample, factor) in grad.data.chunks(per_sample.max(1)).zip(&clip_factors) {
+                    for (s, g) in summed.iter_mut().zip(sample) {
+                        *s += factor * g;
+                    }
+                }
+                summed.iter_mut().for_each(|s| *s /= batch_size as f32);
+                let private = Tensor::new(
+                    summed,
+                    grad.shape[1..].to_vec(),
+                    false,
+                    grad.device.clone(),
+                    grad.dtype,
+                );
+                (name.clone(), private)
+            })
+            .collect())
+    }
+
+    /// Overwrites the wrapped optimizer's gradients, in parameter order
+    fn assign_grads(&mut self, grads: Vec<(String, Tensor)>) -> Result<(), BellandeError> {
+        let params = self
+            .inner
+            .get_param_groups_mut()
+            .iter_mut()
+            .flat_map(|group| group.params.iter_mut());
+        let mut grads = grads.into_iter();
+        for param in params {
+            let (name, grad) = grads.next().ok_or_else(|| {
+                BellandeError::InvalidConfiguration(
+                    "Optimizer has more parameters than per-sample gradients".into(),
+                )
+            })?;
+            if grad.shape != param.shape {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Per-sample gradient '{}' has shape {:?}, parameter has {:?}",
+                    name, grad.shape, param.shape
+                )));
+            }
+            param.set_grad(grad.data);
+        }
+        if grads.next().is_some() {
+            return Err(BellandeError::InvalidConfiguration(
+                "Optimizer has fewer parameters than per-sample gradients".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<O: Optimizer> Optimizer for DpSgd<O> {
+    fn step(&mut self) -> Result<(), BellandeError> {
+        let per_sample = self.pending.take().ok_or_else(|| {
+            BellandeError::InvalidOperation(
+                "DpSgd needs per-sample gradients before every step".into(),
+            )
+        })?;
+        let grads = self.privatize(&per_sample)?;
+        self.assign_grads(grads)?;
+        self.inner.step()?;
+        self.accountant.step();
+        Ok(())
+    }
+
+    fn zero_grad(&mut self) {
+        self.pending = None;
+        self.inner.zero_grad();
+    }
+
+    fn get_learning_rate(&self) -> f32 {
+        self.inner.get_learning_rate()
+    }
+
+    fn set_learning_rate(&mut self, lr: f32) {
+        self.inner.set_learning_rate(lr);
+    }
+
+    fn name(&self) -> &str {
+        "DpSgd"
+    }
+
+    fn get_param_groups(&self) -> &[ParameterGroup] {
+        self.inner.get_param_groups()
+    }
+
+    fn get_param_groups_mut(&mut self) -> &mut [ParameterGroup] {
+        self.inner.get_param_groups_mut()
+    }
+
+    fn add_param_group(&mut self, group: ParameterGroup) {
+        self.inner.add_param_group(group);
+    }
+
+    fn state(&self) -> &OptimizerState {
+        self.inner.state()
+    }
+
+    fn state_mut(&mut self) -> &mut OptimizerState {
+        self.inner.state_mut()
+    }
+
+    fn requires_per_sample_grads(&self) -> bool {
+        true
+    }
+
+    fn set_per_sample_grads(&mut self, grads: Vec<(String, Tensor)>) {
+        self.pending = Some(grads);
+    }
+
+    fn metrics(&self) -> HashMap<String, f32> {
+        let (epsilon, delta) = self.privacy_spent();
+        HashMap::from([
+            ("privacy/epsilon".to_string(), epsilon as f32),
+            ("privacy/delta".to_string(), delta as f32),
+        ])
+    }
+}
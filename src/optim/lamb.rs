@@ -0,0 +1,238 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, numerics::numerics, tensor::Tensor};
+use crate::optim::lars::trust_ratio_metrics;
+use crate::optim::precision::round_in_place;
+use crate::optim::{trust_ratio, Optimizer, OptimizerState, ParameterGroup};
+use std::collections::HashMap;
+
+/// Layer-wise adaptive moments (LAMB) for large-batch training: an Adam
+/// update with decoupled weight decay,
+///
+/// `r = m_hat / (sqrt(v_hat) + eps) + weight_decay * p`,
+///
+/// rescaled per parameter tensor by the trust ratio `||p|| / ||r||`, so
+/// `p -= lr * (||p|| / ||r||) * r`. Learning rate, betas, eps and weight
+/// decay come from each parameter group. The mean trust ratio of every
+/// group in the last step is logged as `trust_ratio/<group>`.
+pub struct LAMB {
+    param_groups: Vec<ParameterGroup>,
+    bias_correction: bool,
+    /// Leave biases and normalization scales, the parameters with at most
+    /// one dimension, without weight decay or trust ratio scaling
+    exclude_1d: bool,
+    m: HashMap<(usize, usize), Vec<f32>>,
+    v: HashMap<(usize, usize), Vec<f32>>,
+    /// FP32 copies of the parameters of groups with master weights
+    masters: HashMap<(usize, usize), Vec<f32>>,
+    trust_ratios: Vec<Option<f32>>,
+    state: OptimizerState,
+}
+
+impl LAMB {
+    /// LAMB with betas (0.9, 0.999), bias correction and the configured
+    /// optimizer eps raised to at least 1e-6, the value LAMB was tuned with
+    pub fn new(params: Vec<Tensor>, lr: f32, weight_decay: f32) -> Result<Self, BellandeError> {
+        if lr.is_nan() || lr <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Learning rate must be positive, got {}",
+                lr
+            )));
+        }
+
+        let group = ParameterGroup::new(params)
+            .with_lr(lr)
+            .with_betas(0.9, 0.999)
+            .with_eps(numerics().optimizer_eps.max(1e-6))
+            .with_weight_decay(weight_decay);
+
+        Ok(LAMB {
+            param_groups: vec![group],
+            bias_correction: true,
+            exclude_1d: false,
+            m: HashMap::new(),
+            v: HashMap::new(),
+            masters: HashMap::new(),
+            trust_ratios: Vec::new(),
+            state: OptimizerState::new(),
+        })
+    }
+
+    /// Betas of the default parameter group
+    pub fn with_betas(mut self, beta1: f32, beta2: f32) -> Result<Self, BellandeError> {
+        if !(0.0..1.0).contains(&beta1) || !(0.0..1.0).contains(&beta2) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Betas must be in [0, 1), got ({}, {})",
+                beta1, beta2
+            )));
+        }
+        for group in &mut self.param_groups {
+            group.betas = Some((beta1, beta2));
+        }
+        Ok(self)
+    }
+
+    /// Divides the moments by `1 - beta^t`, as in Adam. Some large-batch
+    /// recipes train without it.
+    pub fn with_bias_correction(mut self, bias_correction: bool) -> Self {
+        self.bias_correction = bias_correction;
+        self
+    }
+
+    /// Skips weight decay and trust ratio scaling for parameters with at
+    /// most one dimension, as usual for biases and normalization layers
+    pub fn with_exclude_1d(mut self, exclude_1d: bool) -> Self {
+        self.exclude_1d = exclude_1d;
+        self
+    }
+}
+
+impl Optimizer for LAMB {
+    fn step(&mut self) -> Result<(), BellandeError> {
+        self.state.increment_step();
+        self.trust_ratios = vec![None; self.param_groups.len()];
+        let step = self.state.step as i32;
+
+        for (group_idx, group) in self.param_groups.iter_mut().enumerate() {
+            let (beta1, beta2) = group.betas.unwrap_or((0.9, 0.999));
+            let (correction1, correction2) = if self.bias_correction {
+                (1.0 - beta1.powi(step), 1.0 - beta2.powi(step))
+            } else {
+                (1.0, 1.0)
+            };
+            let mut ratios = Vec::new();
+
+            for (param_idx, param) in group.params.iter_mut().enumerate() {
+                let Some(grad) = param.grad() else {
+                    continue;
+                };
+                let adapt = !(self.exclude_1d && param.shape.len() <= 1);
+                let weight_decay = if adapt { group.weight_decay } else { 0.0 };
+
+                let len = param.data.len();
+                let m = self
+                    .m
+                    .entry((group_idx, param_idx))
+                    .or_insert_with(|| vec![0.0; len]);
+                let v = self
+                    .v
+                    .entry((group_idx, param_idx))
+                    .or_insert_with(|| vec![0.0; len]);
+                let mut master = group.master_weights.then(|| {
+                    self.masters
+                        .entry((group_idx, param_idx))
+                        .or_insert_with(|| param.data.clone())
+                });
+                let weights = match master.as_deref_mut() {
+                    Some(master) => master.as_mut_slice(),
+                    None => param.data.as_mut_slice(),
+                };
+
+                let mut update = Vec::with_capacity(len);
+                for (((&p, &g), m), v) in weights
+                    .iter()
+                    .zip(grad.data.iter())
+                    .zip(m.iter_mut())
+                    .zip(v.iter_mut())
+                {
+                    *m = beta1 * *m + (1.0 - beta1) * g;
+                    *v = beta2 * *v + (1.0 - beta2) * g * g;
+                    let adam = (*m / correction1) / ((*v / correction2).sqrt() + group.eps);
+                    update.push(adam + weight_decay * p);
+                }
+
+                let ratio = if adapt {
+                    let ratio = trust_ratio(weights, &update);
+                    ratios.push(ratio);
+                    ratio
+                } else {
+                    1.0
+                };
+                let step_size = group.lr * ratio;
+                for (p, &u) in weights.iter_mut().zip(&update) {
+                    *p -= step_size * u;
+                }
+
+                match master {
+                    Some(master) => {
+                        for (p, &w) in param.data.iter_mut().zip(master.iter()) {
+                            *p = param.dtype.round_to_precision(w);
+                        }
+                    }
+                    None => round_in_place(&mut param.data, param.dtype),
+                }
+                round_in_place(m, group.state_dtype);
+                round_in_place(v, group.state_dtype);
+            }
+
+            if !ratios.is_empty() {
+                self.trust_ratios[group_idx] =
+                    Some(ratios.iter().sum::<f32>() / ratios.len() as f32);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zero_grad(&mut self) {
+        for group in &mut self.param_groups {
+            for param in &mut group.params {
+                param.zero_grad();
+            }
+        }
+    }
+
+    fn get_learning_rate(&self) -> f32 {
+        self.param_groups
+            .first()
+            .map(|group| group.lr)
+            .unwrap_or(0.0)
+    }
+
+    fn set_learning_rate(&mut self, lr: f32) {
+        for group in &mut self.param_groups {
+            group.lr = lr;
+        }
+    }
+
+    fn name(&self) -> &str {
+        "LAMB"
+    }
+
+    fn get_param_groups(&self) -> &[ParameterGroup] {
+        &self.param_groups
+    }
+
+    fn get_param_groups_mut(&mut self) -> &mut [ParameterGroup] {
+        &mut self.param_groups
+    }
+
+    fn add_param_group(&mut self, group: ParameterGroup) {
+        self.param_groups.push(group);
+    }
+
+    fn state(&self) -> &OptimizerState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut OptimizerState {
+        &mut self.state
+    }
+
+    fn metrics(&self) -> HashMap<String, f32> {
+        trust_ratio_metrics(&self.param_groups, &self.trust_ratios)
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::optim::precision::round_in_place;
+use crate::optim::{trust_ratio, Optimizer, OptimizerState, ParameterGroup};
+use std::collections::HashMap;
+
+/// Layer-wise adaptive rate scaling (LARS) for large-batch training. Each
+/// parameter tensor's step is scaled by its own trust ratio,
+///
+/// `d = grad + weight_decay * p`,
+/// `v = momentum * v + lr * trust_coefficient * (||p|| / ||d||) * d`,
+/// `p -= v`,
+///
+/// so every layer moves by a similar fraction of its weight norm whatever
+/// its gradient scale. Learning rate, weight decay and momentum come from
+/// each parameter group. The mean trust ratio of every group in the last
+/// step is logged as `trust_ratio/<group>`.
+pub struct LARS {
+    param_groups: Vec<ParameterGroup>,
+    trust_coefficient: f32,
+    /// Leave biases and normalization scales, the parameters with at most
+    /// one dimension, without weight decay or trust ratio scaling
+    exclude_1d: bool,
+    velocity: HashMap<(usize, usize), Vec<f32>>,
+    /// FP32 copies of the parameters of groups with master weights
+    masters: HashMap<(usize, usize), Vec<f32>>,
+    trust_ratios: Vec<Option<f32>>,
+    state: OptimizerState,
+}
+
+impl LARS {
+    /// LARS with a trust coefficient of 0.001
+    pub fn new(
+        params: Vec<Tensor>,
+        lr: f32,
+        momentum: f32,
+        weight_decay: f32,
+    ) -> Result<Self, BellandeError> {
+        if lr.is_nan() || lr <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Learning rate must be positive, got {}",
+                lr
+            )));
+        }
+        if !(0.0..1.0).contains(&momentum) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Momentum must be in [0, 1), got {}",
+                momentum
+            )));
+        }
+
+        let group = ParameterGroup::new(params)
+            .with_lr(lr)
+            .with_momentum(momentum)
+            .with_weight_decay(weight_decay);
+
+        Ok(LARS {
+            param_groups: vec![group],
+            trust_coefficient: 0.001,
+            exclude_1d: false,
+            velocity: HashMap::new(),
+            masters: HashMap::new(),
+            trust_ratios: Vec::new(),
+            state: OptimizerState::new(),
+        })
+    }
+
+    pub fn with_trust_coefficient(mut self, trust_coefficient: f32) -> Result<Self, BellandeError> {
+        if !(trust_coefficient > 0.0 && trust_coefficient.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Trust coefficient must be positive, got {}",
+                trust_coefficient
+            )));
+        }
+        self.trust_coefficient = trust_coefficient;
+        Ok(self)
+    }
+
+    /// Skips weight decay and trust ratio scaling for parameters with at
+    /// most one dimension, as usual for biases and normalization layers
+    pub fn with_exclude_1d(mut self, exclude_1d: bool) -> Self {
+        self.exclude_1d = exclude_1d;
+        self
+    }
+
+    pub fn trust_coefficient(&self) -> f32 {
+        self.trust_coefficient
+    }
+}
+
+impl Optimizer for LARS {
+    fn step(&mut self) -> Result<(), BellandeError> {
+        self.state.increment_step();
+        self.trust_ratios = vec![None; self.param_groups.len()];
+
+        for (group_idx, group) in self.param_groups.iter_mut().enumerate() {
+            let momentum = group.momentum.unwrap_or(0.0);
+            let mut ratios = Vec::new();
+
+            for (param_idx, param) in group.params.iter_mut().enumerate() {
+                let Some(grad) = param.grad() else {
+                    continue;
+                };
+                let adapt = !(self.exclude_1d && param.shape.len() <= 1);
+                let weight_decay = if adapt { group.weight_decay } else { 0.0 };
+
+                let len = param.data.len();
+                let mut master = group.master_weights.then(|| {
+                    self.masters
+                        .entry((group_idx, param_idx))
+                        .or_insert_with(|| param.data.clone())
+                });
+                let weights = match master.as_deref_mut() {
+                    Some(master) => master.as_mut_slice(),
+                    None => param.data.as_mut_slice(),
+                };
+
+                let d_p: Vec<f32> = weights
+                    .iter()
+                    .zip(grad.data.iter())
+                    .map(|(&p, &g)| g + weight_decay * p)
+                    .collect();
+                let local_lr = if adapt {
+                    let ratio = self.trust_coefficient * trust_ratio(weights, &d_p);
+                    ratios.push(ratio);
+                    ratio
+                } else {
+                    1.0
+                };
+                let step_size = group.lr * local_lr;
+
+                let velocity = self
+                    .velocity
+                    .entry((group_idx, param_idx))
+                    .or_insert_with(|| vec![0.0; len]);
+                for ((p, &d), v) in weights.iter_mut().zip(&d_p).zip(velocity.iter_mut()) {
+                    *v = momentum * *v + step_size * d;
+                    *p -= *v;
+                }
+
+                match master {
+                    Some(master) => {
+                        for (p, &w) in param.data.iter_mut().zip(master.iter()) {
+                            *p = param.dtype.round_to_precision(w);
+                        }
+                    }
+                    None => round_in_place(&mut param.data, param.dtype),
+                }
+                round_in_place(velocity, group.state_dtype);
+            }
+
+            if !ratios.is_empty() {
+                self.trust_ratios[group_idx] =
+                    Some(ratios.iter().sum::<f32>() / ratios.len() as f32);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zero_grad(&mut self) {
+        for group in &mut self.param_groups {
+            for param in &mut group.params {
+                param.zero_grad();
+            }
+        }
+    }
+
+    fn get_learning_rate(&self) -> f32 {
+        self.param_groups
+            .first()
+            .map(|group| group.lr)
+            .unwrap_or(0.0)
+    }
+
+    fn set_learning_rate(&mut self, lr: f32) {
+        for group in &mut self.param_groups {
+            group.lr = lr;
+        }
+    }
+
+    fn name(&self) -> &str {
+        "LARS"
+    }
+
+    fn get_param_groups(&self) -> &[ParameterGroup] {
+        &self.param_groups
+    }
+
+    fn get_param_groups_mut(&mut self) -> &mut [ParameterGroup] {
+        &mut self.param_groups
+    }
+
+    fn add_param_group(&mut self, group: ParameterGroup) {
+        self.param_groups.push(group);
+    }
+
+    fn state(&self) -> &OptimizerState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut OptimizerState {
+        &mut self.state
+    }
+
+    fn metrics(&self) -> HashMap<String, f32> {
+        trust_ratio_metrics(&self.param_groups, &self.trust_ratios)
+    }
+}
+
+/// `trust_ratio/<group>` for every group that adapted a parameter in the
+/// last step
+pub(super) fn trust_ratio_metrics(
+    groups: &[ParameterGroup],
+    trust_ratios: &[Option<f32>],
+) -> HashMap<String, f32> {
+    groups
+        .iter()
+        .zip(trust_ratios)
+        .enumerate()
+        .filter_map(|(index, (group, ratio))| {
+            ratio.map(|ratio| (format!("trust_ratio/{}", group.display_name(index)), ratio))
+        })
+        .collect()
+}
@@ -0,0 +1,185 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::optim::precision::round_in_place;
+use crate::optim::{Optimizer, OptimizerState, ParameterGroup};
+use std::collections::HashMap;
+
+/// Lion (evolved sign momentum): every coordinate moves by exactly the
+/// learning rate, in the direction of the sign of an interpolation between
+/// the momentum and the gradient,
+///
+/// `p -= lr * (sign(beta1 * m + (1 - beta1) * g) + weight_decay * p)`,
+///
+/// after which the momentum is updated with `beta2`. Only one moment is
+/// kept, half the state of Adam. Lion usually wants a learning rate 3-10x
+/// smaller and a weight decay 3-10x larger than AdamW.
+pub struct Lion {
+    param_groups: Vec<ParameterGroup>,
+    m: HashMap<(usize, usize), Vec<f32>>,
+    /// FP32 copies of the parameters of groups with master weights
+    masters: HashMap<(usize, usize), Vec<f32>>,
+    state: OptimizerState,
+}
+
+impl Lion {
+    /// Lion with betas (0.9, 0.99)
+    pub fn new(params: Vec<Tensor>, lr: f32, weight_decay: f32) -> Result<Self, BellandeError> {
+        if lr.is_nan() || lr <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Learning rate must be positive, got {}",
+                lr
+            )));
+        }
+        if weight_decay.is_nan() || weight_decay < 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Weight decay must be non-negative, got {}",
+                weight_decay
+            )));
+        }
+
+        let group = ParameterGroup::new(params)
+            .with_lr(lr)
+            .with_betas(0.9, 0.99)
+            .with_weight_decay(weight_decay);
+
+        Ok(Lion {
+            param_groups: vec![group],
+            m: HashMap::new(),
+            masters: HashMap::new(),
+            state: OptimizerState::new(),
+        })
+    }
+
+    /// Betas of the default parameter group: `beta1` interpolates the
+    /// update direction, `beta2` the stored momentum
+    pub fn with_betas(mut self, beta1: f32, beta2: f32) -> Result<Self, BellandeError> {
+        if !(0.0..1.0).contains(&beta1) || !(0.0..1.0).contains(&beta2) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Betas must be in [0, 1), got ({}, {})",
+                beta1, beta2
+            )));
+        }
+        for group in &mut self.param_groups {
+            group.betas = Some((beta1, beta2));
+        }
+        Ok(self)
+    }
+}
+
+impl Optimizer for Lion {
+    fn step(&mut self) -> Result<(), BellandeError> {
+        self.state.increment_step();
+
+        for (group_idx, group) in self.param_groups.iter_mut().enumerate() {
+            let (beta1, beta2) = group.betas.unwrap_or((0.9, 0.99));
+            let decay = 1.0 - group.lr * group.weight_decay;
+
+            for (param_idx, param) in group.params.iter_mut().enumerate() {
+                let Some(grad) = param.grad() else {
+                    continue;
+                };
+
+                let len = param.data.len();
+                let m = self
+                    .m
+                    .entry((group_idx, param_idx))
+                    .or_insert_with(|| vec![0.0; len]);
+                let mut master = group.master_weights.then(|| {
+                    self.masters
+                        .entry((group_idx, param_idx))
+                        .or_insert_with(|| param.data.clone())
+                });
+                let weights = match master.as_deref_mut() {
+                    Some(master) => master.as_mut_slice(),
+                    None => param.data.as_mut_slice(),
+                };
+
+                for ((p, &g), m) in weights.iter_mut().zip(grad.data.iter()).zip(m.iter_mut()) {
+                    let direction = beta1 * *m + (1.0 - beta1) * g;
+                    // `f32::signum` maps zero to 1, which would move
+                    // parameters that have no gradient signal
+                    let sign = if direction > 0.0 {
+                        1.0
+                    } else if direction < 0.0 {
+                        -1.0
+                    } else {
+                        0.0
+                    };
+                    *p = *p * decay - group.lr * sign;
+                    *m = beta2 * *m + (1.0 - beta2) * g;
+                }
+
+                match master {
+                    Some(master) => {
+                        for (p, &w) in param.data.iter_mut().zip(master.iter()) {
+                            *p = param.dtype.round_to_precision(w);
+                        }
+                    }
+                    None => round_in_place(&mut param.data, param.dtype),
+                }
+                round_in_place(m, group.state_dtype);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zero_grad(&mut self) {
+        for group in &mut self.param_groups {
+            for param in &mut group.params {
+                param.zero_grad();
+            }
+        }
+    }
+
+    fn get_learning_rate(&self) -> f32 {
+        self.param_groups
+            .first()
+            .map(|group| group.lr)
+            .unwrap_or(0.0)
+    }
+
+    fn set_learning_rate(&mut self, lr: f32) {
+        for group in &mut self.param_groups {
+            group.lr = lr;
+        }
+    }
+
+    fn name(&self) -> &str {
+        "Lion"
+    }
+
+    fn get_param_groups(&self) -> &[ParameterGroup] {
+        &self.param_groups
+    }
+
+    fn get_param_groups_mut(&mut self) -> &mut [ParameterGroup] {
+        &mut self.param_groups
+    }
+
+    fn add_param_group(&mut self, group: ParameterGroup) {
+        self.param_groups.push(group);
+    }
+
+    fn state(&self) -> &OptimizerState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut OptimizerState {
+        &mut self.state
+    }
+}
@@ -13,13 +13,55 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::core::{dtype::DataType, error::BellandeError, numerics::numerics, tensor::Tensor};
+use rayon::prelude::*;
 use std::collections::HashMap;
 
 pub mod adam;
+pub mod dp_sgd;
+pub mod lamb;
+pub mod lars;
+pub mod lion;
+mod precision;
+pub mod radam;
 pub mod rmsprop;
 pub mod scheduler;
 pub mod sgd;
+pub mod sgld;
+
+/// Elements per work item of the fused optimizer steps
+pub(crate) const FUSED_CHUNK: usize = 1 << 14;
+
+/// Steps over fewer elements run on the calling thread, where spawning
+/// work on the rayon pool would cost more than it saves
+const PARALLEL_THRESHOLD: usize = 1 << 16;
+
+/// Runs a fused update `kernel` on every chunk of a step over `elements`
+/// parameter values, in parallel when the step is large
+pub(crate) fn run_fused<T: Send>(
+    chunks: Vec<T>,
+    elements: usize,
+    kernel: impl Fn(T) + Send + Sync,
+) {
+    if elements >= PARALLEL_THRESHOLD {
+        chunks.into_par_iter().for_each(kernel);
+    } else {
+        chunks.into_iter().for_each(kernel);
+    }
+}
+
+/// Layer-wise trust ratio `||weights|| / ||update||` of LARS and LAMB.
+/// It is 1 when either norm is zero, so freshly zeroed parameters and
+/// zero updates take an unscaled step.
+pub(crate) fn trust_ratio(weights: &[f32], update: &[f32]) -> f32 {
+    let norm = |values: &[f32]| values.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let (weight_norm, update_norm) = (norm(weights), norm(update));
+    if weight_norm > 0.0 && update_norm > 0.0 {
+        weight_norm / update_norm
+    } else {
+        1.0
+    }
+}
 
 /// The Optimizer trait defines the interface for optimization algorithms used in training neural networks.
 pub trait Optimizer: Send + Sync {
@@ -51,30 +93,77 @@ pub trait Optimizer: Send + Sync {
 
     /// Gets the current state of the optimizer mutably
     fn state_mut(&mut self) -> &mut OptimizerState;
+
+    /// Whether `step` needs the model's per-sample gradients, as for
+    /// differentially private training
+    fn requires_per_sample_grads(&self) -> bool {
+        false
+    }
+
+    /// Hands the per-sample gradients of the current batch to the optimizer
+    /// ahead of `step`
+    fn set_per_sample_grads(&mut self, _grads: Vec<(String, Tensor)>) {}
+
+    /// Extra values to log after every epoch, such as the privacy budget spent
+    fn metrics(&self) -> HashMap<String, f32> {
+        HashMap::new()
+    }
 }
 
 #[derive(Clone)]
 pub struct ParameterGroup {
+    /// Label used when logging per-group metrics
+    pub name: Option<String>,
     pub params: Vec<Tensor>,
     pub lr: f32,
     pub weight_decay: f32,
     pub momentum: Option<f32>,
     pub betas: Option<(f32, f32)>,
     pub eps: f32,
+    /// Precision optimizer state such as moments is stored at
+    pub state_dtype: DataType,
+    /// Keep FP32 master copies of the parameters, for models whose
+    /// parameters are stored at lower precision
+    pub master_weights: bool,
 }
 
 impl ParameterGroup {
     pub fn new(params: Vec<Tensor>) -> Self {
         Self {
+            name: None,
             params,
             lr: 0.001,
             weight_decay: 0.0,
             momentum: None,
             betas: None,
-            eps: 1e-8,
+            eps: numerics().optimizer_eps,
+            state_dtype: DataType::Float32,
+            master_weights: false,
         }
     }
 
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Name used in logs, falling back to the group's position
+    pub fn display_name(&self, index: usize) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("group_{}", index))
+    }
+
+    /// L2 norm of the gradients currently held by the group's parameters
+    pub fn grad_norm(&self) -> f32 {
+        self.params
+            .iter()
+            .filter_map(|param| param.grad())
+            .map(|grad| grad.data.iter().map(|g| g * g).sum::<f32>())
+            .sum::<f32>()
+            .sqrt()
+    }
+
     pub fn with_lr(mut self, lr: f32) -> Self {
         self.lr = lr;
         self
@@ -99,6 +188,21 @@ impl ParameterGroup {
         self.eps = eps;
         self
     }
+
+    /// Stores the group's optimizer state at `dtype`. FP32, the default,
+    /// preserves convergence with FP16 parameters; FP16 halves the memory.
+    pub fn with_state_dtype(mut self, dtype: DataType) -> Result<Self, BellandeError> {
+        precision::validate_state_dtype(dtype)?;
+        self.state_dtype = dtype;
+        Ok(self)
+    }
+
+    /// Updates FP32 master copies of the parameters and writes them back
+    /// rounded to each parameter's dtype
+    pub fn with_master_weights(mut self, enabled: bool) -> Self {
+        self.master_weights = enabled;
+        self
+    }
 }
 
 /// Represents the internal state of an optimizer
@@ -166,7 +270,7 @@ pub mod utils {
         let total_norm = compute_grad_norm(parameters, norm_type)?;
 
         if total_norm > max_norm {
-            let scale = max_norm / (total_norm + 1e-6);
+            let scale = max_norm / (total_norm + numerics().denom_eps);
             for param in parameters {
                 if let Some(grad) = param.grad() {
                     grad.mul_scalar(scale)?;
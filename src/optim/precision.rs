@@ -0,0 +1,92 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{dtype::DataType, error::BellandeError, tensor::Tensor};
+use crate::optim::FUSED_CHUNK;
+
+/// Part of a parameter that a fused update writes to. With master weights
+/// the update runs on the FP32 master copy and the result is rounded into
+/// the model parameter; otherwise it runs on the model parameter in place.
+pub(crate) struct ParamChunk<'a> {
+    pub(crate) weights: &'a mut [f32],
+    model: Option<&'a mut [f32]>,
+    dtype: DataType,
+}
+
+impl ParamChunk<'_> {
+    /// Stores the updated weights at the model parameter's precision
+    pub(crate) fn finish(self) {
+        match self.model {
+            Some(model) => {
+                for (m, &w) in model.iter_mut().zip(self.weights.iter()) {
+                    *m = self.dtype.round_to_precision(w);
+                }
+            }
+            None => round_in_place(self.weights, self.dtype),
+        }
+    }
+}
+
+/// Splits a parameter into `FUSED_CHUNK`-sized chunks, updating through its
+/// `master` copy unless that is empty
+pub(crate) fn param_chunks<'a>(
+    data: &'a mut [f32],
+    master: &'a mut [f32],
+    dtype: DataType,
+) -> impl Iterator<Item = ParamChunk<'a>> {
+    let (weights, model) = if master.is_empty() {
+        (data, None)
+    } else {
+        (master, Some(data))
+    };
+    let models = model
+        .into_iter()
+        .flat_map(|model| model.chunks_mut(FUSED_CHUNK))
+        .map(Some)
+        .chain(std::iter::repeat_with(|| None));
+    weights
+        .chunks_mut(FUSED_CHUNK)
+        .zip(models)
+        .map(move |(weights, model)| ParamChunk {
+            weights,
+            model,
+            dtype,
+        })
+}
+
+/// Rounds optimizer state or parameters to the precision of `dtype`
+pub(crate) fn round_in_place(values: &mut [f32], dtype: DataType) {
+    if dtype != DataType::Float32 {
+        for value in values {
+            *value = dtype.round_to_precision(*value);
+        }
+    }
+}
+
+/// FP32 master copies of `params`, to update in place of parameters stored
+/// at lower precision so that small updates are not rounded away
+pub(crate) fn master_copies(params: &[Tensor]) -> Vec<Vec<f32>> {
+    params.iter().map(|param| param.data.clone()).collect()
+}
+
+pub(crate) fn validate_state_dtype(dtype: DataType) -> Result<(), BellandeError> {
+    if !dtype.is_floating_point() {
+        return Err(BellandeError::InvalidParameter(format!(
+            "Optimizer state must be stored as a floating point type, got {}",
+            dtype
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,240 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::optim::precision::round_in_place;
+use crate::optim::{Optimizer, OptimizerState, ParameterGroup};
+use std::collections::HashMap;
+
+/// Rectified Adam (RAdam). Early in training the variance estimate rests on
+/// few gradients, so Adam's adaptive step is unreliable; RAdam takes plain
+/// momentum steps `p -= lr * m_hat` while the length of the approximated
+/// simple moving average `rho_t` is at most 5, then Adam steps scaled by
+/// the variance rectification term
+///
+/// `r_t = sqrt((rho_t - 4)(rho_t - 2) rho_inf / ((rho_inf - 4)(rho_inf - 2) rho_t))`.
+///
+/// This removes the need for a learning rate warmup. Whether the last
+/// step was rectified is logged as `radam/rectified`.
+pub struct RAdam {
+    param_groups: Vec<ParameterGroup>,
+    /// Decays the weights directly as in AdamW, rather than adding
+    /// `weight_decay * p` to the gradient
+    decoupled_weight_decay: bool,
+    m: HashMap<(usize, usize), Vec<f32>>,
+    v: HashMap<(usize, usize), Vec<f32>>,
+    /// FP32 copies of the parameters of groups with master weights
+    masters: HashMap<(usize, usize), Vec<f32>>,
+    rectified: Option<bool>,
+    state: OptimizerState,
+}
+
+impl RAdam {
+    /// RAdam with betas (0.9, 0.999) and the configured optimizer eps
+    pub fn new(params: Vec<Tensor>, lr: f32, weight_decay: f32) -> Result<Self, BellandeError> {
+        if lr.is_nan() || lr <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Learning rate must be positive, got {}",
+                lr
+            )));
+        }
+        if weight_decay.is_nan() || weight_decay < 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Weight decay must be non-negative, got {}",
+                weight_decay
+            )));
+        }
+
+        let group = ParameterGroup::new(params)
+            .with_lr(lr)
+            .with_betas(0.9, 0.999)
+            .with_weight_decay(weight_decay);
+
+        Ok(RAdam {
+            param_groups: vec![group],
+            decoupled_weight_decay: false,
+            m: HashMap::new(),
+            v: HashMap::new(),
+            masters: HashMap::new(),
+            rectified: None,
+            state: OptimizerState::new(),
+        })
+    }
+
+    /// Betas of the default parameter group
+    pub fn with_betas(mut self, beta1: f32, beta2: f32) -> Result<Self, BellandeError> {
+        if !(0.0..1.0).contains(&beta1) || !(0.0..1.0).contains(&beta2) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Betas must be in [0, 1), got ({}, {})",
+                beta1, beta2
+            )));
+        }
+        for group in &mut self.param_groups {
+            group.betas = Some((beta1, beta2));
+        }
+        Ok(self)
+    }
+
+    /// Applies weight decay as `p -= lr * weight_decay * p` instead of as
+    /// an L2 term in the gradient
+    pub fn with_decoupled_weight_decay(mut self, decoupled: bool) -> Self {
+        self.decoupled_weight_decay = decoupled;
+        self
+    }
+}
+
+/// Variance rectification term of step `step`, or `None` while the
+/// approximated SMA length is too short for the adaptive step
+fn rectification(beta2: f32, step: i32) -> Option<f32> {
+    let rho_inf = 2.0 / (1.0 - beta2) - 1.0;
+    let beta2_t = beta2.powi(step);
+    let rho_t = rho_inf - 2.0 * step as f32 * beta2_t / (1.0 - beta2_t);
+    (rho_t > 5.0).then(|| {
+        ((rho_t - 4.0) * (rho_t - 2.0) * rho_inf / ((rho_inf - 4.0) * (rho_inf - 2.0) * rho_t))
+            .sqrt()
+    })
+}
+
+impl Optimizer for RAdam {
+    fn step(&mut self) -> Result<(), BellandeError> {
+        self.state.increment_step();
+        let step = self.state.step as i32;
+        self.rectified = None;
+
+        for (group_idx, group) in self.param_groups.iter_mut().enumerate() {
+            let (beta1, beta2) = group.betas.unwrap_or((0.9, 0.999));
+            let correction1 = 1.0 - beta1.powi(step);
+            let correction2 = 1.0 - beta2.powi(step);
+            let rect = rectification(beta2, step);
+            self.rectified = Some(self.rectified.unwrap_or(false) || rect.is_some());
+            let (l2, decay) = if self.decoupled_weight_decay {
+                (0.0, 1.0 - group.lr * group.weight_decay)
+            } else {
+                (group.weight_decay, 1.0)
+            };
+
+            for (param_idx, param) in group.params.iter_mut().enumerate() {
+                let Some(grad) = param.grad() else {
+                    continue;
+                };
+
+                let len = param.data.len();
+                let m = self
+                    .m
+                    .entry((group_idx, param_idx))
+                    .or_insert_with(|| vec![0.0; len]);
+                let v = self
+                    .v
+                    .entry((group_idx, param_idx))
+                    .or_insert_with(|| vec![0.0; len]);
+                let mut master = group.master_weights.then(|| {
+                    self.masters
+                        .entry((group_idx, param_idx))
+                        .or_insert_with(|| param.data.clone())
+                });
+                let weights = match master.as_deref_mut() {
+                    Some(master) => master.as_mut_slice(),
+                    None => param.data.as_mut_slice(),
+                };
+
+                for (((p, &g), m), v) in weights
+                    .iter_mut()
+                    .zip(grad.data.iter())
+                    .zip(m.iter_mut())
+                    .zip(v.iter_mut())
+                {
+                    let g = g + l2 * *p;
+                    *m = beta1 * *m + (1.0 - beta1) * g;
+                    *v = beta2 * *v + (1.0 - beta2) * g * g;
+                    let m_hat = *m / correction1;
+                    let update = match rect {
+                        Some(r) => r * m_hat / ((*v / correction2).sqrt() + group.eps),
+                        None => m_hat,
+                    };
+                    *p = *p * decay - group.lr * update;
+                }
+
+                match master {
+                    Some(master) => {
+                        for (p, &w) in param.data.iter_mut().zip(master.iter()) {
+                            *p = param.dtype.round_to_precision(w);
+                        }
+                    }
+                    None => round_in_place(&mut param.data, param.dtype),
+                }
+                round_in_place(m, group.state_dtype);
+                round_in_place(v, group.state_dtype);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zero_grad(&mut self) {
+        for group in &mut self.param_groups {
+            for param in &mut group.params {
+                param.zero_grad();
+            }
+        }
+    }
+
+    fn get_learning_rate(&self) -> f32 {
+        self.param_groups
+            .first()
+            .map(|group| group.lr)
+            .unwrap_or(0.0)
+    }
+
+    fn set_learning_rate(&mut self, lr: f32) {
+        for group in &mut self.param_groups {
+            group.lr = lr;
+        }
+    }
+
+    fn name(&self) -> &str {
+        "RAdam"
+    }
+
+    fn get_param_groups(&self) -> &[ParameterGroup] {
+        &self.param_groups
+    }
+
+    fn get_param_groups_mut(&mut self) -> &mut [ParameterGroup] {
+        &mut self.param_groups
+    }
+
+    fn add_param_group(&mut self, group: ParameterGroup) {
+        self.param_groups.push(group);
+    }
+
+    fn state(&self) -> &OptimizerState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut OptimizerState {
+        &mut self.state
+    }
+
+    fn metrics(&self) -> HashMap<String, f32> {
+        self.rectified
+            .map(|rectified| {
+                HashMap::from([(
+                    "radam/rectified".to_string(),
+                    if rectified { 1.0 } else { 0.0 },
+                )])
+            })
+            .unwrap_or_default()
+    }
+}
@@ -69,7 +69,7 @@ impl RMSprop {
 
     pub fn step(&mut self) -> Result<(), BellandeError> {
         for (idx, param) in self.params.iter_mut().enumerate() {
-            if let Some(grad) = &param.grad {
+            if let Some(grad) = param.grad() {
                 let v = self.v.get_mut(&idx).unwrap();
                 let g = if self.centered {
                     Some(self.g.get_mut(&idx).unwrap())
@@ -82,7 +82,7 @@ impl RMSprop {
                     None
                 };
 
-                for ((p, g_val), v_val) in param.data.iter_mut().zip(grad.iter()).zip(v.iter_mut())
+                for ((p, g_val), v_val) in param.data.iter_mut().zip(grad.data.iter()).zip(v.iter_mut())
                 {
                     let mut grad = *g_val;
 
@@ -115,9 +115,7 @@ impl RMSprop {
 
     pub fn zero_grad(&mut self) {
         for param in &mut self.params {
-            if let Some(grad) = &mut param.grad {
-                grad.iter_mut().for_each(|g| *g = 0.0);
-            }
+            param.zero_grad();
         }
     }
 }
@@ -15,7 +15,7 @@
 
 use crate::core::error::BellandeError;
 
-pub trait LRScheduler {
+pub trait LRScheduler: Send + Sync {
     fn step(&mut self);
     fn get_last_lr(&self) -> f32;
 }
@@ -80,11 +80,7 @@ impl CosineAnnealingLR {
 impl LRScheduler for CosineAnnealingLR {
     fn step(&mut self) {
         self.current_step += 1;
-        let current_step = self.current_step.min(self.T_max);
-        let new_lr = self.eta_min
-            + (self.base_lr - self.eta_min)
-                * (1.0 + std::f32::consts::PI * current_step as f32 / self.T_max as f32).cos()
-                / 2.0;
+        let new_lr = cosine_annealing_lr(self.base_lr, self.eta_min, self.current_step, self.T_max);
         self.optimizer.set_lr(new_lr);
     }
 
@@ -93,7 +89,105 @@ impl LRScheduler for CosineAnnealingLR {
     }
 }
 
-pub trait Optimizer {
+/// Learning rate of `CosineAnnealingLR` at `step`, annealed from `base_lr`
+/// to `eta_min` over `t_max` steps and held there afterwards
+pub fn cosine_annealing_lr(base_lr: f32, eta_min: f32, step: usize, t_max: usize) -> f32 {
+    let progress = step.min(t_max) as f32 / t_max.max(1) as f32;
+    eta_min + (base_lr - eta_min) * (1.0 + (std::f32::consts::PI * progress).cos()) / 2.0
+}
+
+/// Steps several schedulers together on every call, so their effects
+/// compose, e.g. a decay on top of a cyclic schedule. Reports the learning
+/// rate of the last scheduler.
+pub struct ChainedScheduler {
+    schedulers: Vec<Box<dyn LRScheduler>>,
+}
+
+impl ChainedScheduler {
+    pub fn new(schedulers: Vec<Box<dyn LRScheduler>>) -> Result<Self, BellandeError> {
+        if schedulers.is_empty() {
+            return Err(BellandeError::InvalidConfiguration(
+                "ChainedScheduler needs at least one scheduler".into(),
+            ));
+        }
+        Ok(ChainedScheduler { schedulers })
+    }
+}
+
+impl LRScheduler for ChainedScheduler {
+    fn step(&mut self) {
+        for scheduler in &mut self.schedulers {
+            scheduler.step();
+        }
+    }
+
+    fn get_last_lr(&self) -> f32 {
+        self.schedulers
+            .last()
+            .map(|scheduler| scheduler.get_last_lr())
+            .unwrap_or(0.0)
+    }
+}
+
+/// Runs one scheduler at a time, switching to the next at each milestone
+/// step, e.g. warmup for 5 epochs then cosine annealing. Each scheduler
+/// starts counting from its own first step when it becomes active.
+pub struct SequentialScheduler {
+    schedulers: Vec<Box<dyn LRScheduler>>,
+    milestones: Vec<usize>,
+    current_step: usize,
+}
+
+impl SequentialScheduler {
+    /// `milestones` must be strictly increasing and have one entry fewer
+    /// than `schedulers`
+    pub fn new(
+        schedulers: Vec<Box<dyn LRScheduler>>,
+        milestones: Vec<usize>,
+    ) -> Result<Self, BellandeError> {
+        if schedulers.is_empty() || milestones.len() + 1 != schedulers.len() {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "SequentialScheduler needs one milestone fewer than schedulers, got {} schedulers and {} milestones",
+                schedulers.len(),
+                milestones.len()
+            )));
+        }
+        if milestones.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Milestones must be strictly increasing, got {:?}",
+                milestones
+            )));
+        }
+
+        Ok(SequentialScheduler {
+            schedulers,
+            milestones,
+            current_step: 0,
+        })
+    }
+
+    /// Index of the scheduler that handles the current step
+    pub fn active_index(&self) -> usize {
+        self.milestones
+            .iter()
+            .take_while(|&&milestone| self.current_step >= milestone)
+            .count()
+    }
+}
+
+impl LRScheduler for SequentialScheduler {
+    fn step(&mut self) {
+        self.current_step += 1;
+        let index = self.active_index();
+        self.schedulers[index].step();
+    }
+
+    fn get_last_lr(&self) -> f32 {
+        self.schedulers[self.active_index()].get_last_lr()
+    }
+}
+
+pub trait Optimizer: Send + Sync {
     fn step(&mut self) -> Result<(), BellandeError>;
     fn zero_grad(&mut self);
     fn get_lr(&self) -> f32;
@@ -13,8 +13,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{error::BellandeError, tensor::Tensor};
-use std::collections::HashMap;
+use crate::core::{dtype::DataType, error::BellandeError, tensor::Tensor};
+use crate::optim::precision::{master_copies, param_chunks, round_in_place, validate_state_dtype};
+use crate::optim::{run_fused, FUSED_CHUNK};
 
 pub struct SGD {
     params: Vec<Tensor>,
@@ -22,7 +23,49 @@ pub struct SGD {
     momentum: f32,
     weight_decay: f32,
     nesterov: bool,
-    velocity: HashMap<usize, Vec<f32>>,
+    /// One buffer per parameter, empty without momentum
+    velocity: Vec<Vec<f32>>,
+    state_dtype: DataType,
+    /// FP32 copies of the parameters, empty without master weights
+    masters: Vec<Vec<f32>>,
+}
+
+/// Per-step constants of the fused SGD update
+#[derive(Clone, Copy)]
+struct SgdStep {
+    lr: f32,
+    momentum: f32,
+    weight_decay: f32,
+    nesterov: bool,
+}
+
+impl SgdStep {
+    /// Weight decay, the momentum update and the parameter update in one
+    /// pass. Each variant is a separate branch-free loop so that the
+    /// compiler vectorizes it.
+    fn update(&self, params: &mut [f32], grads: &[f32], velocity: Option<&mut [f32]>) {
+        let (lr, momentum, weight_decay) = (self.lr, self.momentum, self.weight_decay);
+        match velocity {
+            None => {
+                for (p, &g) in params.iter_mut().zip(grads) {
+                    *p -= lr * (g + weight_decay * *p);
+                }
+            }
+            Some(velocity) if self.nesterov => {
+                for ((p, &g), v) in params.iter_mut().zip(grads).zip(velocity.iter_mut()) {
+                    let d_p = g + weight_decay * *p;
+                    *v = momentum * *v + d_p;
+                    *p -= lr * (d_p + momentum * *v);
+                }
+            }
+            Some(velocity) => {
+                for ((p, &g), v) in params.iter_mut().zip(grads).zip(velocity.iter_mut()) {
+                    *v = momentum * *v + g + weight_decay * *p;
+                    *p -= lr * *v;
+                }
+            }
+        }
+    }
 }
 
 impl SGD {
@@ -33,12 +76,12 @@ impl SGD {
         weight_decay: f32,
         nesterov: bool,
     ) -> Self {
-        let mut velocity = HashMap::new();
-        if momentum > 0.0 {
-            for (idx, param) in params.iter().enumerate() {
-                velocity.insert(idx, vec![0.0; param.data.len()]);
-            }
-        }
+        let velocity = if momentum > 0.0 {
+            params.iter().map(|p| vec![0.0; p.data.len()]).collect()
+        } else {
+            Vec::new()
+        };
+        let masters = vec![Vec::new(); params.len()];
 
         SGD {
             params,
@@ -47,48 +90,99 @@ impl SGD {
             weight_decay,
             nesterov,
             velocity,
+            state_dtype: DataType::Float32,
+            masters,
         }
     }
 
+    /// Stores the momentum buffers at `dtype`, FP32 by default
+    pub fn with_state_dtype(mut self, dtype: DataType) -> Result<Self, BellandeError> {
+        validate_state_dtype(dtype)?;
+        self.state_dtype = dtype;
+        Ok(self)
+    }
+
+    /// Updates FP32 master copies of the parameters and writes them back
+    /// rounded to each parameter's dtype, so that updates too small for
+    /// FP16 parameters still accumulate
+    pub fn with_master_weights(mut self, enabled: bool) -> Self {
+        self.masters = if enabled {
+            master_copies(&self.params)
+        } else {
+            vec![Vec::new(); self.params.len()]
+        };
+        self
+    }
+
+    /// Parameters being optimized, with their current values
+    pub fn params(&self) -> &[Tensor] {
+        &self.params
+    }
+
+    /// Mutable access to the parameters, e.g. to set gradients computed
+    /// outside of a model
+    pub fn params_mut(&mut self) -> &mut [Tensor] {
+        &mut self.params
+    }
+
+    pub fn get_lr(&self) -> f32 {
+        self.lr
+    }
+
+    pub fn set_lr(&mut self, lr: f32) {
+        self.lr = lr;
+    }
+
+    /// Updates every parameter with a gradient. The parameters are split
+    /// into chunks that are updated in parallel for large models.
     pub fn step(&mut self) -> Result<(), BellandeError> {
-        for (idx, param) in self.params.iter_mut().enumerate() {
-            if let Some(grad) = &param.grad {
-                let v = if self.momentum > 0.0 {
-                    Some(self.velocity.get_mut(&idx).unwrap())
-                } else {
-                    None
-                };
-
-                for ((p, g), v_opt) in param.data.iter_mut().zip(grad.iter()).zip(v.into_iter()) {
-                    let mut d_p = *g;
-
-                    if self.weight_decay != 0.0 {
-                        d_p += self.weight_decay * *p;
-                    }
-
-                    if let Some(v) = v_opt {
-                        *v = self.momentum * *v + d_p;
-
-                        if self.nesterov {
-                            d_p += self.momentum * *v;
-                        } else {
-                            d_p = *v;
-                        }
-                    }
-
-                    *p -= self.lr * d_p;
-                }
+        let update = SgdStep {
+            lr: self.lr,
+            momentum: self.momentum,
+            weight_decay: self.weight_decay,
+            nesterov: self.nesterov,
+        };
+
+        // Gradients are read up front since the chunks borrow them while the
+        // parameters are updated in place
+        let grads: Vec<Option<Tensor>> = self.params.iter().map(Tensor::grad).collect();
+
+        let mut chunks = Vec::new();
+        let mut elements = 0;
+        let mut velocity = self.velocity.iter_mut();
+        let params = self.params.iter_mut().zip(&mut self.masters).zip(&grads);
+        for ((param, master), grad) in params {
+            let v = velocity.next();
+            let Some(grad) = grad else {
+                continue;
+            };
+            elements += param.data.len();
+            let params = param_chunks(&mut param.data, master, param.dtype)
+                .zip(grad.data.chunks(FUSED_CHUNK));
+            match v {
+                Some(v) => chunks.extend(
+                    params
+                        .zip(v.chunks_mut(FUSED_CHUNK))
+                        .map(|((p, g), v)| (p, g, Some(v))),
+                ),
+                None => chunks.extend(params.map(|(p, g)| (p, g, None))),
             }
         }
+        let state_dtype = self.state_dtype;
+        run_fused(chunks, elements, |(p, g, mut v)| {
+            update.update(p.weights, g, v.as_deref_mut());
+            p.finish();
+            if let Some(v) = v {
+                round_in_place(v, state_dtype);
+            }
+        });
 
         Ok(())
     }
 
     pub fn zero_grad(&mut self) {
         for param in &mut self.params {
-            if let Some(grad) = &mut param.grad {
-                grad.iter_mut().for_each(|g| *g = 0.0);
-            }
+            param.zero_grad();
         }
     }
 }
@@ -0,0 +1,218 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, random, tensor::Tensor};
+use crate::optim::precision::round_in_place;
+use crate::optim::{Optimizer, OptimizerState, ParameterGroup};
+use std::collections::HashMap;
+
+/// Stochastic gradient Langevin dynamics. Each step is an SGD step on the
+/// mean loss plus Gaussian noise,
+///
+/// `p -= lr * G * grad + sqrt(2 * lr * G * temperature / num_data) * N(0, 1)`,
+///
+/// so with a decaying learning rate the iterates sample from the posterior
+/// instead of converging to a point estimate. `G` is 1, or the RMSprop
+/// preconditioner `1 / (sqrt(v) + eps)` when preconditioning is enabled
+/// (pSGLD). Weight decay acts as a Gaussian prior. Noise is drawn from the
+/// framework generator, so runs are reproducible under `random::set_seed`.
+pub struct SGLD {
+    param_groups: Vec<ParameterGroup>,
+    /// Number of training samples the mean loss is averaged over
+    num_data: usize,
+    /// Posterior temperature; 1.0 is the Bayesian posterior, 0.0 plain SGD
+    temperature: f32,
+    /// Decay rate of the squared-gradient average, when preconditioning
+    preconditioning: Option<f32>,
+    square_avg: HashMap<(usize, usize), Vec<f32>>,
+    /// FP32 copies of the parameters of groups with master weights
+    masters: HashMap<(usize, usize), Vec<f32>>,
+    state: OptimizerState,
+}
+
+impl SGLD {
+    pub fn new(
+        params: Vec<Tensor>,
+        lr: f32,
+        num_data: usize,
+        weight_decay: f32,
+    ) -> Result<Self, BellandeError> {
+        if lr.is_nan() || lr <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Learning rate must be positive, got {}",
+                lr
+            )));
+        }
+        if num_data == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "num_data must be at least 1".into(),
+            ));
+        }
+
+        let group = ParameterGroup::new(params)
+            .with_lr(lr)
+            .with_weight_decay(weight_decay);
+
+        Ok(SGLD {
+            param_groups: vec![group],
+            num_data,
+            temperature: 1.0,
+            preconditioning: None,
+            square_avg: HashMap::new(),
+            masters: HashMap::new(),
+            state: OptimizerState::new(),
+        })
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Result<Self, BellandeError> {
+        if temperature.is_nan() || temperature < 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Temperature must be non-negative, got {}",
+                temperature
+            )));
+        }
+        self.temperature = temperature;
+        Ok(self)
+    }
+
+    /// Scales each coordinate by an RMSprop estimate of the inverse gradient
+    /// magnitude, with decay `alpha` and each group's `eps`
+    pub fn with_preconditioning(mut self, alpha: f32) -> Result<Self, BellandeError> {
+        if !(0.0..1.0).contains(&alpha) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Preconditioning alpha must be in [0, 1), got {}",
+                alpha
+            )));
+        }
+        self.preconditioning = Some(alpha);
+        Ok(self)
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+}
+
+impl Optimizer for SGLD {
+    fn step(&mut self) -> Result<(), BellandeError> {
+        self.state.increment_step();
+
+        for (group_idx, group) in self.param_groups.iter_mut().enumerate() {
+            let noise_scale = 2.0 * group.lr * self.temperature / self.num_data as f32;
+
+            for (param_idx, param) in group.params.iter_mut().enumerate() {
+                let Some(grad) = param.grad() else {
+                    continue;
+                };
+
+                let len = param.data.len();
+                let noise = random::normal(0.0, 1.0, len);
+                let mut square_avg = self.preconditioning.map(|_| {
+                    self.square_avg
+                        .entry((group_idx, param_idx))
+                        .or_insert_with(|| vec![0.0; len])
+                });
+
+                let mut master = group.master_weights.then(|| {
+                    self.masters
+                        .entry((group_idx, param_idx))
+                        .or_insert_with(|| param.data.clone())
+                });
+                let weights = match master.as_deref_mut() {
+                    Some(master) => master.as_mut_slice(),
+                    None => param.data.as_mut_slice(),
+                };
+
+                let values = weights.iter_mut().zip(grad.data.iter()).zip(noise.iter());
+                for (i, ((p, &g), &n)) in values.enumerate() {
+                    let mut d_p = g;
+                    if group.weight_decay != 0.0 {
+                        d_p += group.weight_decay * *p;
+                    }
+
+                    let precondition = match (self.preconditioning, square_avg.as_deref_mut()) {
+                        (Some(alpha), Some(avg)) => {
+                            avg[i] = alpha * avg[i] + (1.0 - alpha) * d_p * d_p;
+                            1.0 / (avg[i].sqrt() + group.eps)
+                        }
+                        _ => 1.0,
+                    };
+
+                    *p -= group.lr * precondition * d_p;
+                    *p += (noise_scale * precondition).sqrt() * n;
+                }
+
+                match master {
+                    Some(master) => {
+                        for (p, &w) in param.data.iter_mut().zip(master.iter()) {
+                            *p = param.dtype.round_to_precision(w);
+                        }
+                    }
+                    None => round_in_place(&mut param.data, param.dtype),
+                }
+                if let Some(avg) = square_avg {
+                    round_in_place(avg, group.state_dtype);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zero_grad(&mut self) {
+        for group in &mut self.param_groups {
+            for param in &mut group.params {
+                param.zero_grad();
+            }
+        }
+    }
+
+    fn get_learning_rate(&self) -> f32 {
+        self.param_groups
+            .first()
+            .map(|group| group.lr)
+            .unwrap_or(0.0)
+    }
+
+    fn set_learning_rate(&mut self, lr: f32) {
+        for group in &mut self.param_groups {
+            group.lr = lr;
+        }
+    }
+
+    fn name(&self) -> &str {
+        "SGLD"
+    }
+
+    fn get_param_groups(&self) -> &[ParameterGroup] {
+        &self.param_groups
+    }
+
+    fn get_param_groups_mut(&mut self) -> &mut [ParameterGroup] {
+        &mut self.param_groups
+    }
+
+    fn add_param_group(&mut self, group: ParameterGroup) {
+        self.param_groups.push(group);
+    }
+
+    fn state(&self) -> &OptimizerState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut OptimizerState {
+        &mut self.state
+    }
+}
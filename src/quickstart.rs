@@ -0,0 +1,221 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! One-call training for newcomers: point `train_image_classifier` at a
+//! folder of images sorted into one directory per class and get back an
+//! `ImageClassifier` that labels image files.
+
+pub use crate::core::{error::BellandeError, tensor::Tensor};
+pub use crate::inference::predictor::Predictor;
+
+use crate::core::{device::Device, numerics::numerics};
+use crate::data::augmentation::{Compose, RandomHorizontalFlip, RandomRotation, Transform};
+use crate::data::dataloader::DataLoader;
+use crate::data::image_folder::ImageFolder;
+use crate::data::spatial::Resize;
+use crate::loss::cross_entropy::CrossEntropyLoss;
+use crate::models::architecture;
+use crate::models::state_file::save_state_file;
+use crate::optim::{adam::Adam, scheduler::CosineAnnealingLR};
+use crate::training::callbacks::SchedulerCallback;
+use crate::training::checkpoint::CheckpointMode;
+use crate::training::logger::LogFormat;
+use crate::training::run_manager::RunManager;
+use crate::training::run_metadata::RunMetadata;
+use crate::training::trainer::Trainer;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Height and width images are resized to, for training and prediction
+pub const IMAGE_SIZE: usize = 64;
+
+const BATCH_SIZE: usize = 32;
+const LEARNING_RATE: f32 = 1e-3;
+
+/// Directory the runs are stored in, relative to the working directory
+const RUNS_DIR: &str = "runs";
+
+/// A classifier trained by `train_image_classifier`
+pub struct ImageClassifier {
+    predictor: Predictor,
+    classes: Vec<String>,
+    run_dir: PathBuf,
+}
+
+impl ImageClassifier {
+    /// Class name and probability of the most likely class of the image at
+    /// `image_path`, which is prepared with `eval_transform`
+    pub fn predict(
+        &mut self,
+        image_path: impl AsRef<Path>,
+    ) -> Result<(String, f32), BellandeError> {
+        let image = ImageFolder::load_image(&image_path.as_ref().to_path_buf())?;
+        let input = eval_transform().apply(&image)?;
+        let probabilities = self.predictor.predict_proba(&input)?;
+        let (label, probability) = probabilities.data.iter().copied().enumerate().fold(
+            (0, f32::NEG_INFINITY),
+            |best, (label, probability)| {
+                if probability > best.1 {
+                    (label, probability)
+                } else {
+                    best
+                }
+            },
+        );
+        let class = self.classes.get(label).cloned().ok_or_else(|| {
+            BellandeError::RuntimeError(format!("Predicted label {} has no class name", label))
+        })?;
+        Ok((class, probability))
+    }
+
+    /// Class names in label order
+    pub fn classes(&self) -> &[String] {
+        &self.classes
+    }
+
+    /// Directory the training run was stored in
+    pub fn run_dir(&self) -> &Path {
+        &self.run_dir
+    }
+
+    /// The underlying predictor, for tensor inputs
+    pub fn predictor_mut(&mut self) -> &mut Predictor {
+        &mut self.predictor
+    }
+
+    /// Consumes the classifier, returning its predictor
+    pub fn into_predictor(self) -> Predictor {
+        self.predictor
+    }
+}
+
+/// Trains an `arch` classifier (`mlp`, `small_cnn` or `separable_cnn`) for
+/// `epochs` epochs on the images under `data_dir`, one subdirectory per
+/// class. If `data_dir` has `train` and `val` subdirectories, those are
+/// used as the training and validation sets.
+///
+/// Images are resized to `IMAGE_SIZE` and augmented with random flips and
+/// small rotations. The model is trained with Adam and a cosine learning rate
+/// decay, and the run is stored under `runs/`: the best checkpoint, the
+/// metric log, the history, and in `exports/` the final weights and the
+/// class names in label order. The returned classifier labels image files
+/// and knows where the run was stored.
+pub fn train_image_classifier(
+    data_dir: impl AsRef<Path>,
+    arch: &str,
+    epochs: usize,
+) -> Result<ImageClassifier, BellandeError> {
+    if epochs == 0 {
+        return Err(BellandeError::InvalidParameter(
+            "Epochs must be positive".into(),
+        ));
+    }
+
+    let data_dir = data_dir.as_ref();
+    let (train_dir, val_dir) = if data_dir.join("train").is_dir() && data_dir.join("val").is_dir() {
+        (data_dir.join("train"), Some(data_dir.join("val")))
+    } else {
+        (data_dir.to_path_buf(), None)
+    };
+    let train = ImageFolder::new(train_dir, Some(train_transform()), None)?;
+    let val = val_dir
+        .map(|dir| ImageFolder::new(dir, Some(eval_transform()), None))
+        .transpose()?;
+    if let Some(val) = &val {
+        if val.get_class_to_idx() != train.get_class_to_idx() {
+            return Err(BellandeError::InvalidConfiguration(
+                "The train and val directories must contain the same classes".into(),
+            ));
+        }
+    }
+    let classes: BTreeMap<usize, String> = train
+        .get_class_to_idx()
+        .iter()
+        .map(|(name, &idx)| (idx, name.clone()))
+        .collect();
+
+    let config = architecture::preset(arch, &[3, IMAGE_SIZE, IMAGE_SIZE], train.num_classes())?;
+    let model = Box::new(architecture::create_cnn(&config)?);
+    let optimizer = Box::new(Adam::new(
+        model.parameters(),
+        LEARNING_RATE,
+        (0.9, 0.999),
+        numerics().optimizer_eps,
+        0.0,
+    ));
+    let mut trainer = Trainer::new(
+        model,
+        optimizer,
+        Box::new(CrossEntropyLoss::default()),
+        Device::CPU,
+    );
+
+    let run = RunManager::new(RUNS_DIR)?.start_run(RunMetadata::capture().with_config(&config)?)?;
+    let monitor = if val.is_some() { "val_loss" } else { "loss" };
+    trainer.set_run_metadata(run.metadata().clone());
+    trainer.add_callback(Box::new(SchedulerCallback::new(
+        LEARNING_RATE,
+        |optimizer| {
+            Box::new(CosineAnnealingLR::new(
+                optimizer,
+                epochs,
+                0.01 * LEARNING_RATE,
+            ))
+        },
+    )));
+    trainer.add_callback(Box::new(run.model_checkpoint(
+        monitor,
+        true,
+        CheckpointMode::Min,
+    )));
+    trainer.add_callback(Box::new(run.metric_logger(LogFormat::Csv)));
+
+    let workers = num_cpus::get();
+    let train_loader = DataLoader::new(train, BATCH_SIZE, true, workers, None, false);
+    let val_loader = val.map(|val| DataLoader::new(val, BATCH_SIZE, false, workers, None, false));
+    let history = trainer.fit(train_loader, val_loader, epochs)?;
+
+    let model = trainer.into_model();
+    save_state_file(&model.state_dict(), run.export_path("model.bin"))?;
+    let class_file =
+        File::create(run.export_path("classes.json")).map_err(BellandeError::IOError)?;
+    let classes: Vec<String> = classes.into_values().collect();
+    serde_json::to_writer_pretty(class_file, &classes)
+        .map_err(|_| BellandeError::SerializationError)?;
+    let run_dir = run.dir().to_path_buf();
+    run.finish(&history)?;
+
+    Ok(ImageClassifier {
+        predictor: Predictor::new(model, Device::CPU),
+        classes,
+        run_dir,
+    })
+}
+
+/// Training augmentation: resize, random horizontal flip and a rotation of
+/// up to 10 degrees
+pub fn train_transform() -> Box<dyn Transform> {
+    Box::new(Compose::new(vec![
+        Box::new(Resize::new(IMAGE_SIZE, IMAGE_SIZE)),
+        Box::new(RandomHorizontalFlip::new(0.5)),
+        Box::new(RandomRotation::new((-10.0, 10.0))),
+    ]))
+}
+
+/// Preprocessing for validation and prediction inputs
+pub fn eval_transform() -> Box<dyn Transform> {
+    Box::new(Resize::new(IMAGE_SIZE, IMAGE_SIZE))
+}
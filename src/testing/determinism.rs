@@ -0,0 +1,158 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, random, tensor::Tensor};
+use crate::data::dataloader::DataLoader;
+use crate::training::trainer::Trainer;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Builds a fresh trainer and training loader. Called once per run, after
+/// the seed has been set, so every random draw happens inside the run.
+pub type TrainerFactory =
+    Arc<dyn Fn() -> Result<(Trainer, DataLoader), BellandeError> + Send + Sync>;
+
+/// Settings for a reproducibility check
+#[derive(Clone)]
+pub struct DeterminismConfig {
+    pub seed: u64,
+    pub epochs: usize,
+    /// Largest allowed absolute difference; 0.0 requires bitwise equality
+    pub tolerance: f32,
+    factory: TrainerFactory,
+}
+
+impl DeterminismConfig {
+    pub fn new(seed: u64, factory: TrainerFactory) -> Self {
+        DeterminismConfig {
+            seed,
+            epochs: 2,
+            tolerance: 0.0,
+            factory,
+        }
+    }
+
+    pub fn with_epochs(mut self, epochs: usize) -> Self {
+        self.epochs = epochs;
+        self
+    }
+
+    pub fn with_tolerance(mut self, tolerance: f32) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+}
+
+/// Loss trajectory and final weights of one seeded run
+#[derive(Clone, Debug)]
+pub struct RunRecord {
+    pub losses: Vec<f32>,
+    pub weights: HashMap<String, Tensor>,
+}
+
+/// Seeds the framework generator, trains for `config.epochs` epochs and
+/// records the per-epoch loss and the final state dict
+pub fn run_seeded(config: &DeterminismConfig) -> Result<RunRecord, BellandeError> {
+    random::set_seed(config.seed);
+    let (mut trainer, train_loader) = (config.factory)()?;
+    trainer.set_verbose(false);
+
+    let history = trainer.fit(train_loader, None, config.epochs)?;
+    let losses = history.get_metric("loss").unwrap_or_default();
+
+    Ok(RunRecord {
+        losses,
+        weights: trainer.model().state_dict(),
+    })
+}
+
+/// Runs two trainings with the same seed and returns an error describing
+/// the first difference in their loss trajectories or final weights
+pub fn check_reproducible(config: &DeterminismConfig) -> Result<(), BellandeError> {
+    let first = run_seeded(config)?;
+    let second = run_seeded(config)?;
+
+    if first.losses.len() != second.losses.len() {
+        return Err(BellandeError::RuntimeError(format!(
+            "Runs recorded {} and {} epochs of loss",
+            first.losses.len(),
+            second.losses.len()
+        )));
+    }
+    if let Some(epoch) = first_difference(&first.losses, &second.losses, config.tolerance) {
+        return Err(BellandeError::RuntimeError(format!(
+            "Loss diverged at epoch {}: {} vs {}",
+            epoch, first.losses[epoch], second.losses[epoch]
+        )));
+    }
+
+    if second.weights.len() != first.weights.len() {
+        return Err(BellandeError::RuntimeError(format!(
+            "Runs produced {} and {} parameters",
+            first.weights.len(),
+            second.weights.len()
+        )));
+    }
+
+    let mut names: Vec<&String> = first.weights.keys().collect();
+    names.sort();
+    for name in names {
+        let expected = &first.weights[name];
+        let actual = second.weights.get(name).ok_or_else(|| {
+            BellandeError::RuntimeError(format!("Parameter '{}' missing from second run", name))
+        })?;
+
+        if expected.shape != actual.shape {
+            return Err(BellandeError::RuntimeError(format!(
+                "Parameter '{}' has shape {:?} vs {:?}",
+                name, expected.shape, actual.shape
+            )));
+        }
+        if let Some(index) = first_difference(&expected.data, &actual.data, config.tolerance) {
+            return Err(BellandeError::RuntimeError(format!(
+                "Parameter '{}' differs at element {}: {} vs {}",
+                name, index, expected.data[index], actual.data[index]
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Panics unless two seeded trainings produce identical loss trajectories
+/// and final weights
+pub fn assert_reproducible(config: &DeterminismConfig) {
+    if let Err(error) = check_reproducible(config) {
+        panic!(
+            "Training is not reproducible with seed {}: {}",
+            config.seed, error
+        );
+    }
+}
+
+/// Index of the first pair of values further apart than `tolerance`. NaNs
+/// compare equal to each other, so a run that diverges to NaN identically
+/// still counts as reproducible.
+fn first_difference(a: &[f32], b: &[f32], tolerance: f32) -> Option<usize> {
+    a.iter().zip(b.iter()).position(|(&x, &y)| {
+        if x.is_nan() || y.is_nan() {
+            x.is_nan() != y.is_nan()
+        } else if tolerance == 0.0 {
+            x.to_bits() != y.to_bits()
+        } else {
+            (x - y).abs() > tolerance
+        }
+    })
+}
@@ -0,0 +1,250 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, random, tensor::Tensor};
+use crate::layer::Layer;
+
+/// Settings for comparing a layer's analytic gradients with central
+/// finite differences
+#[derive(Clone, Debug)]
+pub struct GradCheckConfig {
+    /// Finite-difference step
+    pub epsilon: f32,
+    /// Allowed error, relative to `max(1, |analytic|, |numerical|)`
+    pub tolerance: f32,
+    /// Check at most this many evenly spaced elements per tensor
+    pub max_elements: Option<usize>,
+}
+
+impl GradCheckConfig {
+    pub fn new() -> Self {
+        GradCheckConfig {
+            epsilon: 1e-2,
+            tolerance: 1e-2,
+            max_elements: None,
+        }
+    }
+
+    pub fn with_epsilon(mut self, epsilon: f32) -> Self {
+        self.epsilon = epsilon;
+        self
+    }
+
+    pub fn with_tolerance(mut self, tolerance: f32) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn with_max_elements(mut self, max_elements: usize) -> Self {
+        self.max_elements = Some(max_elements);
+        self
+    }
+}
+
+impl Default for GradCheckConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A gradient element whose analytic and numerical values disagree
+#[derive(Clone, Debug)]
+pub struct GradMismatch {
+    /// `input` or the parameter name
+    pub tensor: String,
+    pub index: usize,
+    pub analytic: f32,
+    pub numerical: f32,
+}
+
+/// Result of a gradient check
+#[derive(Clone, Debug, Default)]
+pub struct GradCheckReport {
+    pub checked: usize,
+    /// Largest relative error seen
+    pub max_error: f32,
+    pub mismatches: Vec<GradMismatch>,
+}
+
+impl GradCheckReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    fn compare(
+        &mut self,
+        tensor: &str,
+        index: usize,
+        analytic: f32,
+        numerical: f32,
+        config: &GradCheckConfig,
+    ) {
+        let scale = 1.0f32.max(analytic.abs()).max(numerical.abs());
+        let error = (analytic - numerical).abs() / scale;
+
+        self.checked += 1;
+        self.max_error = self.max_error.max(error);
+        if error.is_nan() || error > config.tolerance {
+            self.mismatches.push(GradMismatch {
+                tensor: tensor.to_string(),
+                index,
+                analytic,
+                numerical,
+            });
+        }
+    }
+}
+
+/// Checks the input and parameter gradients of `layer` at `input`. The
+/// layer is reduced to the scalar `sum(output * projection)` with a random
+/// projection, so every output element contributes. Put layers with random
+/// behavior such as dropout in eval mode first.
+pub fn check_layer(
+    layer: &mut dyn Layer,
+    input: &Tensor,
+    config: &GradCheckConfig,
+) -> Result<GradCheckReport, BellandeError> {
+    let output = layer.forward(input)?;
+    let projection = Tensor::new(
+        random::normal(0.0, 1.0, output.data.len()),
+        output.shape.clone(),
+        false,
+        output.device.clone(),
+        output.dtype,
+    );
+
+    // Parameter gradients accumulate, so compare against the buffers before
+    let grads_before = parameter_grads(layer);
+    let grad_input = layer.backward(&projection)?;
+    let grads_after = parameter_grads(layer);
+
+    let mut report = GradCheckReport::default();
+
+    let mut perturbed = input.clone();
+    for index in sample_indices(input.data.len(), config.max_elements) {
+        let original = perturbed.data[index];
+        perturbed.data[index] = original + config.epsilon;
+        let plus = projected_loss(layer, &perturbed, &projection)?;
+        perturbed.data[index] = original - config.epsilon;
+        let minus = projected_loss(layer, &perturbed, &projection)?;
+        perturbed.data[index] = original;
+
+        let numerical = ((plus - minus) / (2.0 * config.epsilon as f64)) as f32;
+        report.compare("input", index, grad_input.data[index], numerical, config);
+    }
+
+    for (name, param) in trainable_parameters(layer) {
+        let (Some(before), Some(after)) = (
+            find_grad(&grads_before, &name),
+            find_grad(&grads_after, &name),
+        ) else {
+            continue;
+        };
+
+        let mut perturbed = param.clone();
+        for index in sample_indices(param.data.len(), config.max_elements) {
+            let original = param.data[index];
+            perturbed.data[index] = original + config.epsilon;
+            layer.set_parameter(&name, perturbed.clone())?;
+            let plus = projected_loss(layer, input, &projection)?;
+            perturbed.data[index] = original - config.epsilon;
+            layer.set_parameter(&name, perturbed.clone())?;
+            let minus = projected_loss(layer, input, &projection)?;
+            perturbed.data[index] = original;
+
+            let numerical = ((plus - minus) / (2.0 * config.epsilon as f64)) as f32;
+            let analytic = after[index] - before.get(index).copied().unwrap_or(0.0);
+            report.compare(&name, index, analytic, numerical, config);
+        }
+        layer.set_parameter(&name, param)?;
+    }
+
+    Ok(report)
+}
+
+/// Like [`check_layer`], returning an error describing the first mismatch
+/// when the check fails
+pub fn assert_layer_gradients(
+    layer: &mut dyn Layer,
+    input: &Tensor,
+    config: &GradCheckConfig,
+) -> Result<(), BellandeError> {
+    let report = check_layer(layer, input, config)?;
+    match report.mismatches.first() {
+        None => Ok(()),
+        Some(mismatch) => Err(BellandeError::RuntimeError(format!(
+            "Gradient check failed for {} of {} elements; first at {}[{}]: analytic {}, numerical {}",
+            report.mismatches.len(),
+            report.checked,
+            mismatch.tensor,
+            mismatch.index,
+            mismatch.analytic,
+            mismatch.numerical
+        ))),
+    }
+}
+
+fn projected_loss(
+    layer: &mut dyn Layer,
+    input: &Tensor,
+    projection: &Tensor,
+) -> Result<f64, BellandeError> {
+    let output = layer.forward(input)?;
+    Ok(output
+        .data
+        .iter()
+        .zip(projection.data.iter())
+        .map(|(&o, &p)| o as f64 * p as f64)
+        .sum())
+}
+
+/// Named parameters that `parameters()` reports as trainable, which leaves
+/// out buffers such as running statistics
+fn trainable_parameters(layer: &dyn Layer) -> Vec<(String, Tensor)> {
+    let trainable = layer.parameters();
+    layer
+        .named_parameters()
+        .into_iter()
+        .filter(|(_, param)| {
+            trainable
+                .iter()
+                .any(|t| t.shape == param.shape && t.data == param.data)
+        })
+        .collect()
+}
+
+fn parameter_grads(layer: &dyn Layer) -> Vec<(String, Vec<f32>)> {
+    trainable_parameters(layer)
+        .into_iter()
+        .map(|(name, param)| {
+            let grad = param
+                .grad()
+                .map_or_else(|| vec![0.0; param.data.len()], |grad| grad.data);
+            (name, grad)
+        })
+        .collect()
+}
+
+fn find_grad<'a>(grads: &'a [(String, Vec<f32>)], name: &str) -> Option<&'a Vec<f32>> {
+    grads.iter().find(|(n, _)| n == name).map(|(_, grad)| grad)
+}
+
+/// Evenly spaced indices covering at most `limit` of `len` elements
+fn sample_indices(len: usize, limit: Option<usize>) -> Vec<usize> {
+    match limit {
+        Some(limit) if limit < len => (0..limit).map(|i| i * len / limit).collect(),
+        _ => (0..len).collect(),
+    }
+}
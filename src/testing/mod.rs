@@ -0,0 +1,2 @@
+pub mod determinism;
+pub mod gradcheck;
@@ -0,0 +1,251 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, random, tensor::Tensor};
+use crate::data::dataloader::DataLoader;
+use crate::loss::Loss;
+use crate::metrics::metrics::{Accuracy, Metric};
+use crate::models::models::Model;
+
+/// L-infinity bounded attack that perturbs inputs along the sign of the
+/// loss gradient. FGSM (Goodfellow et al., 2015) takes a single step of
+/// size `epsilon`; PGD (Madry et al., 2018) takes several smaller steps,
+/// projecting back into the `epsilon` ball after each one.
+#[derive(Debug, Clone)]
+pub struct Attack {
+    epsilon: f32,
+    step_size: f32,
+    steps: usize,
+    random_start: bool,
+    clip: Option<(f32, f32)>,
+}
+
+impl Attack {
+    /// Fast gradient sign method
+    pub fn fgsm(epsilon: f32) -> Result<Self, BellandeError> {
+        Self::pgd(epsilon, epsilon, 1).map(|attack| attack.with_random_start(false))
+    }
+
+    /// Projected gradient descent with `steps` steps of `step_size`,
+    /// starting from a uniformly random point of the `epsilon` ball
+    pub fn pgd(epsilon: f32, step_size: f32, steps: usize) -> Result<Self, BellandeError> {
+        if !epsilon.is_finite() || epsilon <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Attack epsilon must be positive, got {}",
+                epsilon
+            )));
+        }
+        if !step_size.is_finite() || step_size <= 0.0 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Attack step size must be positive, got {}",
+                step_size
+            )));
+        }
+        if steps == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Attack needs at least one step".into(),
+            ));
+        }
+        Ok(Attack {
+            epsilon,
+            step_size,
+            steps,
+            random_start: true,
+            clip: None,
+        })
+    }
+
+    /// Whether PGD starts from a random point of the `epsilon` ball rather
+    /// than from the clean input
+    pub fn with_random_start(mut self, random_start: bool) -> Self {
+        self.random_start = random_start;
+        self
+    }
+
+    /// Keeps adversarial inputs within `[min, max]`, e.g. the valid pixel
+    /// range of unnormalized images
+    pub fn with_clip_range(mut self, min: f32, max: f32) -> Result<Self, BellandeError> {
+        if !min.is_finite() || !max.is_finite() || min >= max {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Invalid clip range [{}, {}]",
+                min, max
+            )));
+        }
+        self.clip = Some((min, max));
+        Ok(self)
+    }
+
+    pub fn epsilon(&self) -> f32 {
+        self.epsilon
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Adversarial version of `input` that increases the loss of `model`
+    /// on `target`. Runs the model in its current mode; the backward
+    /// passes also accumulate into the parameter gradients, so generate
+    /// attacks before zeroing the gradients of a training step.
+    pub fn generate(
+        &self,
+        model: &mut dyn Model,
+        loss_fn: &dyn Loss,
+        input: &Tensor,
+        target: &Tensor,
+    ) -> Result<Tensor, BellandeError> {
+        let mut adversarial = input.clone();
+        if self.random_start {
+            let noise = random::uniform(-self.epsilon, self.epsilon, input.data.len());
+            for (value, delta) in adversarial.data.iter_mut().zip(noise) {
+                *value += delta;
+            }
+            self.project(&mut adversarial, input);
+        }
+
+        for _ in 0..self.steps {
+            let grad = input_gradient(model, loss_fn, &adversarial, target)?;
+            if grad.data.len() != adversarial.data.len() {
+                return Err(BellandeError::ShapeMismatch(format!(
+                    "Input gradient of shape {:?} does not match input of shape {:?}",
+                    grad.shape, adversarial.shape
+                )));
+            }
+            for (value, &g) in adversarial.data.iter_mut().zip(&grad.data) {
+                if g > 0.0 {
+                    *value += self.step_size;
+                } else if g < 0.0 {
+                    *value -= self.step_size;
+                }
+            }
+            self.project(&mut adversarial, input);
+        }
+        Ok(adversarial)
+    }
+
+    /// Clamps `adversarial` into the `epsilon` ball around `input` and the
+    /// clip range
+    fn project(&self, adversarial: &mut Tensor, input: &Tensor) {
+        for (value, &clean) in adversarial.data.iter_mut().zip(&input.data) {
+            *value = value.clamp(clean - self.epsilon, clean + self.epsilon);
+            if let Some((min, max)) = self.clip {
+                *value = value.clamp(min, max);
+            }
+        }
+    }
+}
+
+/// Gradient of the loss with respect to the model input
+fn input_gradient(
+    model: &mut dyn Model,
+    loss_fn: &dyn Loss,
+    input: &Tensor,
+    target: &Tensor,
+) -> Result<Tensor, BellandeError> {
+    let output = model.forward(input)?;
+    let grad = loss_fn.backward(&output, target)?;
+    model.backward(&grad)
+}
+
+/// Adversarial training settings for `Trainer::set_adversarial_training`.
+/// Every batch is trained on both its clean and adversarial version, with
+/// their gradients weighted `1 - adversarial_weight` and
+/// `adversarial_weight`
+#[derive(Debug, Clone)]
+pub struct AdversarialTraining {
+    attack: Attack,
+    adversarial_weight: f32,
+}
+
+impl AdversarialTraining {
+    /// Trains on clean and adversarial batches with equal weight
+    pub fn new(attack: Attack) -> Self {
+        AdversarialTraining {
+            attack,
+            adversarial_weight: 0.5,
+        }
+    }
+
+    /// Weight of the adversarial batch in `[0, 1]`; 1 trains on adversarial
+    /// batches only
+    pub fn with_adversarial_weight(mut self, weight: f32) -> Result<Self, BellandeError> {
+        if !(0.0..=1.0).contains(&weight) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Adversarial weight must be in [0, 1], got {}",
+                weight
+            )));
+        }
+        self.adversarial_weight = weight;
+        Ok(self)
+    }
+
+    pub fn attack(&self) -> &Attack {
+        &self.attack
+    }
+
+    pub fn adversarial_weight(&self) -> f32 {
+        self.adversarial_weight
+    }
+}
+
+/// Accuracy of a classifier on clean inputs and under attack
+#[derive(Debug, Clone)]
+pub struct RobustnessReport {
+    pub clean_accuracy: f32,
+    pub robust_accuracy: f32,
+    /// Relative accuracy drop under attack, `1 - robust / clean`
+    pub attack_success_rate: f32,
+    pub samples: usize,
+}
+
+/// Clean and robust accuracy of `model` on `loader`, whose targets are
+/// class indices. Puts the model in evaluation mode.
+pub fn evaluate_robustness(
+    model: &mut dyn Model,
+    loss_fn: &dyn Loss,
+    loader: &DataLoader,
+    attack: &Attack,
+) -> Result<RobustnessReport, BellandeError> {
+    model.eval();
+    let mut clean = Accuracy::new();
+    let mut robust = Accuracy::new();
+    let mut samples = 0;
+    for (data, target) in loader.iter() {
+        let clean_output = model.forward(&data)?;
+        clean.update(&clean_output, &target);
+
+        let adversarial = attack.generate(model, loss_fn, &data, &target)?;
+        let adversarial_output = model.forward(&adversarial)?;
+        robust.update(&adversarial_output, &target);
+        samples += target.data.len();
+    }
+    if samples == 0 {
+        return Err(BellandeError::InvalidInputs);
+    }
+
+    let clean_accuracy = clean.compute();
+    let robust_accuracy = robust.compute();
+    let attack_success_rate = if clean_accuracy > 0.0 {
+        (1.0 - robust_accuracy / clean_accuracy).max(0.0)
+    } else {
+        0.0
+    };
+    Ok(RobustnessReport {
+        clean_accuracy,
+        robust_accuracy,
+        attack_success_rate,
+        samples,
+    })
+}
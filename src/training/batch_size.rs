@@ -0,0 +1,155 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::error::BellandeError;
+
+/// Grows the effective batch size over epochs by raising the number of
+/// gradient accumulation steps, often used instead of learning rate decay.
+/// The effective batch size is the loader's batch size times the steps.
+pub struct BatchSizeScheduler {
+    schedule: Schedule,
+    max_steps: Option<usize>,
+}
+
+enum Schedule {
+    Step {
+        initial_steps: usize,
+        factor: usize,
+        step_size: usize,
+    },
+    Linear {
+        start_steps: usize,
+        end_steps: usize,
+        ramp_epochs: usize,
+    },
+    Milestones(Vec<(usize, usize)>),
+}
+
+impl BatchSizeScheduler {
+    /// Starts at `initial_steps` and multiplies the steps by `factor` every
+    /// `step_size` epochs
+    pub fn step(
+        initial_steps: usize,
+        factor: usize,
+        step_size: usize,
+    ) -> Result<Self, BellandeError> {
+        if initial_steps == 0 || factor == 0 || step_size == 0 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Initial steps, factor and step size must be positive, got {}, {} and {}",
+                initial_steps, factor, step_size
+            )));
+        }
+        Ok(Self::from_schedule(Schedule::Step {
+            initial_steps,
+            factor,
+            step_size,
+        }))
+    }
+
+    /// Moves linearly from `start_steps` to `end_steps` over `ramp_epochs`
+    /// epochs, rounding to whole steps, then stays at `end_steps`
+    pub fn linear(
+        start_steps: usize,
+        end_steps: usize,
+        ramp_epochs: usize,
+    ) -> Result<Self, BellandeError> {
+        if start_steps == 0 || end_steps == 0 {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Accumulation steps must be positive, got {} and {}",
+                start_steps, end_steps
+            )));
+        }
+        Ok(Self::from_schedule(Schedule::Linear {
+            start_steps,
+            end_steps,
+            ramp_epochs,
+        }))
+    }
+
+    /// Uses the steps of the last `(epoch, steps)` milestone reached, and a
+    /// single step before the first. Epochs must be strictly increasing.
+    pub fn milestones(milestones: Vec<(usize, usize)>) -> Result<Self, BellandeError> {
+        if milestones.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Milestone epochs must be strictly increasing, got {:?}",
+                milestones
+            )));
+        }
+        if milestones.iter().any(|&(_, steps)| steps == 0) {
+            return Err(BellandeError::InvalidConfiguration(
+                "Accumulation steps must be positive".into(),
+            ));
+        }
+        Ok(Self::from_schedule(Schedule::Milestones(milestones)))
+    }
+
+    /// Caps the accumulation steps, e.g. at the largest batch that still
+    /// trains well
+    pub fn with_max_steps(mut self, max_steps: usize) -> Result<Self, BellandeError> {
+        if max_steps == 0 {
+            return Err(BellandeError::InvalidConfiguration(
+                "Maximum accumulation steps must be positive".into(),
+            ));
+        }
+        self.max_steps = Some(max_steps);
+        Ok(self)
+    }
+
+    fn from_schedule(schedule: Schedule) -> Self {
+        BatchSizeScheduler {
+            schedule,
+            max_steps: None,
+        }
+    }
+
+    /// Gradient accumulation steps for `epoch`, counted from 0
+    pub fn accumulation_steps(&self, epoch: usize) -> usize {
+        let steps = match &self.schedule {
+            Schedule::Step {
+                initial_steps,
+                factor,
+                step_size,
+            } => {
+                let exponent = (epoch / step_size).min(u32::MAX as usize) as u32;
+                initial_steps.saturating_mul(factor.saturating_pow(exponent))
+            }
+            Schedule::Linear {
+                start_steps,
+                end_steps,
+                ramp_epochs,
+            } => {
+                let progress = if *ramp_epochs == 0 {
+                    1.0
+                } else {
+                    (epoch as f32 / *ramp_epochs as f32).min(1.0)
+                };
+                let steps =
+                    *start_steps as f32 + (*end_steps as f32 - *start_steps as f32) * progress;
+                steps.round() as usize
+            }
+            Schedule::Milestones(milestones) => milestones
+                .iter()
+                .take_while(|&&(milestone, _)| epoch >= milestone)
+                .last()
+                .map_or(1, |&(_, steps)| steps),
+        };
+        self.max_steps.map_or(steps, |max| steps.min(max)).max(1)
+    }
+
+    /// Effective batch size for `epoch` with loader batches of `batch_size`
+    pub fn effective_batch_size(&self, batch_size: usize, epoch: usize) -> usize {
+        batch_size * self.accumulation_steps(epoch)
+    }
+}
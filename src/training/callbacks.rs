@@ -14,7 +14,12 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::models::models::Model;
+use crate::optim::scheduler::{self, LRScheduler};
+use crate::optim::{Optimizer, ParameterGroup};
+use parking_lot::Mutex;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 pub trait Callback: Send + Sync {
     fn on_epoch_begin(
@@ -51,6 +56,28 @@ pub trait Callback: Send + Sync {
     fn on_train_end(&mut self, logs: &HashMap<String, f32>) -> Result<(), BellandeError> {
         Ok(())
     }
+
+    /// Called after `on_epoch_begin` with the model and optimizer, for
+    /// callbacks that change what is being trained
+    fn on_epoch_begin_train_state(
+        &mut self,
+        _epoch: usize,
+        _model: &mut dyn Model,
+        _optimizer: &mut dyn Optimizer,
+    ) -> Result<(), BellandeError> {
+        Ok(())
+    }
+
+    /// Called after `on_epoch_end` with the trained model, for callbacks
+    /// that save or inspect it
+    fn on_epoch_end_train_state(
+        &mut self,
+        _epoch: usize,
+        _logs: &HashMap<String, f32>,
+        _model: &dyn Model,
+    ) -> Result<(), BellandeError> {
+        Ok(())
+    }
 }
 
 pub struct EarlyStopping {
@@ -111,3 +138,308 @@ impl Callback for EarlyStopping {
         Ok(())
     }
 }
+
+/// A group of parameters, selected by state dict name prefix, that is
+/// unfrozen together
+#[derive(Clone, Debug)]
+pub struct UnfreezeStage {
+    pub name: String,
+    pub prefixes: Vec<String>,
+    /// Multiplier applied to the base learning rate for this group
+    pub lr_scale: f32,
+}
+
+impl UnfreezeStage {
+    pub fn new(name: &str, prefixes: Vec<String>) -> Self {
+        UnfreezeStage {
+            name: name.to_string(),
+            prefixes,
+            lr_scale: 1.0,
+        }
+    }
+
+    pub fn with_lr_scale(mut self, lr_scale: f32) -> Self {
+        self.lr_scale = lr_scale;
+        self
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.prefixes.iter().any(|prefix| name.starts_with(prefix))
+    }
+}
+
+/// When the next stage of `ProgressiveUnfreeze` is released
+#[derive(Clone, Debug)]
+pub enum UnfreezeTrigger {
+    /// Stage `i` is unfrozen at the start of epoch `epochs[i]`
+    Epochs(Vec<usize>),
+    /// The next stage is unfrozen once `monitor` has not improved by more
+    /// than `min_delta` for `patience` epochs
+    Plateau {
+        monitor: String,
+        patience: usize,
+        min_delta: f32,
+    },
+}
+
+/// Progressive unfreezing for fine-tuning.
+///
+/// Parameters start frozen by simply not being registered with the optimizer;
+/// the optimizer should initially hold only the trainable head. When a stage
+/// is released, its parameters are added as a new parameter group named after
+/// the stage, with learning rate `base_lr * lr_scale`.
+pub struct ProgressiveUnfreeze {
+    stages: Vec<UnfreezeStage>,
+    trigger: UnfreezeTrigger,
+    base_lr: Option<f32>,
+    next_stage: usize,
+    best_value: f32,
+    wait: usize,
+    plateau_reached: bool,
+    verbose: bool,
+}
+
+impl ProgressiveUnfreeze {
+    /// Unfreezes `stages[i]` at the start of epoch `epochs[i]`
+    pub fn by_epoch(stages: Vec<UnfreezeStage>, epochs: Vec<usize>) -> Result<Self, BellandeError> {
+        if stages.len() != epochs.len() {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Got {} unfreeze epochs for {} stages",
+                epochs.len(),
+                stages.len()
+            )));
+        }
+        if epochs.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(BellandeError::InvalidConfiguration(
+                "Unfreeze epochs must be non-decreasing".into(),
+            ));
+        }
+        Ok(Self::new(stages, UnfreezeTrigger::Epochs(epochs)))
+    }
+
+    /// Unfreezes the next stage each time the lower-is-better metric
+    /// `monitor` plateaus for `patience` epochs
+    pub fn on_plateau(stages: Vec<UnfreezeStage>, monitor: &str, patience: usize) -> Self {
+        Self::new(
+            stages,
+            UnfreezeTrigger::Plateau {
+                monitor: monitor.to_string(),
+                patience,
+                min_delta: 0.0,
+            },
+        )
+    }
+
+    fn new(stages: Vec<UnfreezeStage>, trigger: UnfreezeTrigger) -> Self {
+        ProgressiveUnfreeze {
+            stages,
+            trigger,
+            base_lr: None,
+            next_stage: 0,
+            best_value: f32::INFINITY,
+            wait: 0,
+            plateau_reached: false,
+            verbose: true,
+        }
+    }
+
+    pub fn with_min_delta(mut self, delta: f32) -> Self {
+        if let UnfreezeTrigger::Plateau {
+            ref mut min_delta, ..
+        } = self.trigger
+        {
+            *min_delta = delta;
+        }
+        self
+    }
+
+    /// Learning rate that stage scales apply to; defaults to the optimizer's
+    /// learning rate at the time of unfreezing
+    pub fn with_base_lr(mut self, base_lr: f32) -> Self {
+        self.base_lr = Some(base_lr);
+        self
+    }
+
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Number of stages released so far
+    pub fn unfrozen_stages(&self) -> usize {
+        self.next_stage
+    }
+
+    fn should_unfreeze(&self, epoch: usize) -> bool {
+        match self.trigger {
+            UnfreezeTrigger::Epochs(ref epochs) => epochs
+                .get(self.next_stage)
+                .is_some_and(|&start| epoch >= start),
+            UnfreezeTrigger::Plateau { .. } => self.plateau_reached,
+        }
+    }
+
+    fn unfreeze(
+        &mut self,
+        model: &mut dyn Model,
+        optimizer: &mut dyn Optimizer,
+    ) -> Result<(), BellandeError> {
+        let stage = &self.stages[self.next_stage];
+
+        let mut params: Vec<(String, Tensor)> = model
+            .state_dict()
+            .into_iter()
+            .filter(|(name, _)| stage.matches(name))
+            .collect();
+        if params.is_empty() {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Unfreeze stage '{}' matches no parameters",
+                stage.name
+            )));
+        }
+        params.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let base_lr = self
+            .base_lr
+            .unwrap_or_else(|| optimizer.get_learning_rate());
+        let weight_decay = optimizer
+            .get_param_groups()
+            .first()
+            .map_or(0.0, |group| group.weight_decay);
+        let lr = base_lr * stage.lr_scale;
+
+        if self.verbose {
+            println!(
+                "Unfreezing '{}': {} tensors at lr {:.2e}",
+                stage.name,
+                params.len(),
+                lr
+            );
+        }
+
+        optimizer.add_param_group(
+            ParameterGroup::new(params.into_iter().map(|(_, param)| param).collect())
+                .with_name(&stage.name)
+                .with_lr(lr)
+                .with_weight_decay(weight_decay),
+        );
+
+        self.next_stage += 1;
+        self.plateau_reached = false;
+        self.best_value = f32::INFINITY;
+        self.wait = 0;
+        Ok(())
+    }
+}
+
+impl Callback for ProgressiveUnfreeze {
+    fn on_epoch_end(
+        &mut self,
+        _epoch: usize,
+        logs: &HashMap<String, f32>,
+    ) -> Result<(), BellandeError> {
+        if let UnfreezeTrigger::Plateau {
+            ref monitor,
+            patience,
+            min_delta,
+        } = self.trigger
+        {
+            if let Some(&current) = logs.get(monitor) {
+                if current < self.best_value - min_delta {
+                    self.best_value = current;
+                    self.wait = 0;
+                } else {
+                    self.wait += 1;
+                    if self.wait >= patience {
+                        self.plateau_reached = true;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_epoch_begin_train_state(
+        &mut self,
+        epoch: usize,
+        model: &mut dyn Model,
+        optimizer: &mut dyn Optimizer,
+    ) -> Result<(), BellandeError> {
+        while self.next_stage < self.stages.len() && self.should_unfreeze(epoch) {
+            self.unfreeze(model, optimizer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stand-in optimizer handed to a scheduler by `SchedulerCallback`, holding
+/// the learning rate the scheduler last set
+struct ScheduledLr(Arc<Mutex<f32>>);
+
+impl scheduler::Optimizer for ScheduledLr {
+    fn step(&mut self) -> Result<(), BellandeError> {
+        Ok(())
+    }
+
+    fn zero_grad(&mut self) {}
+
+    fn get_lr(&self) -> f32 {
+        *self.0.lock()
+    }
+
+    fn set_lr(&mut self, lr: f32) {
+        *self.0.lock() = lr;
+    }
+}
+
+/// Drives the trainer's optimizer with an `LRScheduler`, stepping it once
+/// per epoch. Schedulers own the optimizer they update, so `build` receives
+/// a stand-in starting at `base_lr` whose learning rate is copied to the
+/// trainer's optimizer at the start of every epoch.
+///
+/// ```ignore
+/// trainer.add_callback(Box::new(SchedulerCallback::new(lr, |optimizer| {
+///     Box::new(CosineAnnealingLR::new(optimizer, epochs, 0.01 * lr))
+/// })));
+/// ```
+pub struct SchedulerCallback {
+    scheduler: Box<dyn LRScheduler>,
+}
+
+impl SchedulerCallback {
+    pub fn new(
+        base_lr: f32,
+        build: impl FnOnce(Box<dyn scheduler::Optimizer>) -> Box<dyn LRScheduler>,
+    ) -> Self {
+        let lr = Arc::new(Mutex::new(base_lr));
+        SchedulerCallback {
+            scheduler: build(Box::new(ScheduledLr(lr))),
+        }
+    }
+
+    /// Learning rate the scheduler set for the current epoch
+    pub fn get_last_lr(&self) -> f32 {
+        self.scheduler.get_last_lr()
+    }
+}
+
+impl Callback for SchedulerCallback {
+    fn on_epoch_end(
+        &mut self,
+        _epoch: usize,
+        _logs: &HashMap<String, f32>,
+    ) -> Result<(), BellandeError> {
+        self.scheduler.step();
+        Ok(())
+    }
+
+    fn on_epoch_begin_train_state(
+        &mut self,
+        _epoch: usize,
+        _model: &mut dyn Model,
+        optimizer: &mut dyn Optimizer,
+    ) -> Result<(), BellandeError> {
+        optimizer.set_learning_rate(self.scheduler.get_last_lr());
+        Ok(())
+    }
+}
@@ -14,8 +14,12 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::error::BellandeError;
+use crate::data::dataloader::DataLoader;
+use crate::metrics::metrics::Metric;
 use crate::models::models::Model;
+use crate::models::state_file::{is_state_file, LazyStateDict};
 use crate::training::callbacks::Callback;
+use crate::training::run_metadata::RunMetadata;
 use glob::glob;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -39,6 +43,7 @@ pub struct ModelCheckpoint {
     model: Option<Box<dyn Model>>,
     save_format: SaveFormat,
     verbose: bool,
+    run_metadata: Option<RunMetadata>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -54,6 +59,8 @@ struct CheckpointMetadata {
     monitor: String,
     mode: CheckpointMode,
     metrics: HashMap<String, f32>,
+    #[serde(default)]
+    run: Option<RunMetadata>,
 }
 
 impl ModelCheckpoint {
@@ -77,6 +84,7 @@ impl ModelCheckpoint {
             model: None,
             save_format: SaveFormat::Binary,
             verbose: true,
+            run_metadata: None,
         }
     }
 
@@ -95,6 +103,25 @@ impl ModelCheckpoint {
         self
     }
 
+    /// Run metadata written to every checkpoint's `.meta.json`. Without it,
+    /// the metadata of the current machine is captured when training
+    /// begins.
+    pub fn with_run_metadata(mut self, metadata: RunMetadata) -> Self {
+        self.run_metadata = Some(metadata);
+        self
+    }
+
+    /// Path pattern of the saved checkpoints, with `{epoch}` and `{val}`
+    /// placeholders
+    pub fn filepath(&self) -> &str {
+        &self.filepath
+    }
+
+    /// Evaluator for every checkpoint this callback has saved
+    pub fn evaluator(&self) -> CheckpointEvaluator {
+        CheckpointEvaluator::new(&self.filepath)
+    }
+
     fn is_better(&self, current: f32) -> bool {
         match self.mode {
             CheckpointMode::Min => current < self.best_value,
@@ -102,8 +129,33 @@ impl ModelCheckpoint {
         }
     }
 
+    /// Saves `model` for `epoch` unless only improvements are kept and the
+    /// monitored value did not improve
+    fn checkpoint_epoch(
+        &mut self,
+        model: &dyn Model,
+        epoch: usize,
+        logs: &HashMap<String, f32>,
+    ) -> Result<(), BellandeError> {
+        if let Some(&current) = logs.get(&self.monitor) {
+            if !self.save_best_only || self.is_better(current) {
+                self.best_value = current;
+
+                let filepath = PathBuf::from(
+                    self.filepath
+                        .replace("{epoch}", &epoch.to_string())
+                        .replace("{val}", &format!("{:.4}", current)),
+                );
+
+                self.save_checkpoint(model, &filepath, epoch, logs)?;
+            }
+        }
+        Ok(())
+    }
+
     fn save_checkpoint(
         &self,
+        model: &dyn Model,
         filepath: &Path,
         epoch: usize,
         metrics: &HashMap<String, f32>,
@@ -116,34 +168,33 @@ impl ModelCheckpoint {
         }
 
         // Save model or weights
-        if let Some(model) = &self.model {
-            if self.save_weights_only {
-                self.save_weights(model.as_ref(), filepath)?;
-            } else {
-                self.save_model(model.as_ref(), filepath)?;
-            }
-
-            // Save metadata
-            let metadata = CheckpointMetadata {
-                epoch,
-                best_value: self.best_value,
-                monitor: self.monitor.clone(),
-                mode: self.mode,
-                metrics: metrics.clone(),
-            };
-
-            let metadata_path = filepath.with_extension("meta.json");
-            let file = File::create(metadata_path).map_err(|e| {
-                BellandeError::IOError(format!("Failed to create metadata file: {}", e))
-            })?;
-
-            serde_json::to_writer_pretty(file, &metadata).map_err(|e| {
-                BellandeError::SerializationError(format!("Failed to write metadata: {}", e))
-            })?;
+        if self.save_weights_only {
+            self.save_weights(model, filepath)?;
+        } else {
+            self.save_model(model, filepath)?;
+        }
 
-            if self.verbose {
-                println!("Saved checkpoint to {}", filepath.display());
-            }
+        // Save metadata
+        let metadata = CheckpointMetadata {
+            epoch,
+            best_value: self.best_value,
+            monitor: self.monitor.clone(),
+            mode: self.mode,
+            metrics: metrics.clone(),
+            run: self.run_metadata.clone(),
+        };
+
+        let metadata_path = filepath.with_extension("meta.json");
+        let file = File::create(metadata_path).map_err(|e| {
+            BellandeError::IOError(format!("Failed to create metadata file: {}", e))
+        })?;
+
+        serde_json::to_writer_pretty(file, &metadata).map_err(|e| {
+            BellandeError::SerializationError(format!("Failed to write metadata: {}", e))
+        })?;
+
+        if self.verbose {
+            println!("Saved checkpoint to {}", filepath.display());
         }
 
         Ok(())
@@ -262,23 +313,33 @@ impl Callback for ModelCheckpoint {
         epoch: usize,
         logs: &HashMap<String, f32>,
     ) -> Result<(), BellandeError> {
-        if let Some(&current) = logs.get(&self.monitor) {
-            if !self.save_best_only || self.is_better(current) {
-                self.best_value = current;
-
-                let filepath = PathBuf::from(
-                    self.filepath
-                        .replace("{epoch}", &epoch.to_string())
-                        .replace("{val}", &format!("{:.4}", current)),
-                );
+        // Without a model of its own the checkpoint saves the trainer's
+        // model in `on_epoch_end_train_state`
+        let Some(model) = self.model.take() else {
+            return Ok(());
+        };
+        let result = self.checkpoint_epoch(model.as_ref(), epoch, logs);
+        self.model = Some(model);
+        result
+    }
 
-                self.save_checkpoint(&filepath, epoch, logs)?;
-            }
+    fn on_epoch_end_train_state(
+        &mut self,
+        epoch: usize,
+        logs: &HashMap<String, f32>,
+        model: &dyn Model,
+    ) -> Result<(), BellandeError> {
+        if self.model.is_some() {
+            return Ok(());
         }
-        Ok(())
+        self.checkpoint_epoch(model, epoch, logs)
     }
 
     fn on_train_begin(&mut self, logs: &HashMap<String, f32>) -> Result<(), BellandeError> {
+        if self.run_metadata.is_none() {
+            self.run_metadata = Some(RunMetadata::capture());
+        }
+
         // Check if checkpoint directory exists and create if necessary
         if let Some(parent) = Path::new(&self.filepath).parent() {
             fs::create_dir_all(parent).map_err(|e| {
@@ -368,6 +429,7 @@ impl Callback for ModelCheckpoint {
                 monitor: self.monitor.clone(),
                 mode: self.mode,
                 metrics: logs.clone(),
+                run: self.run_metadata.clone(),
             };
 
             // Save the checkpoint
@@ -410,3 +472,199 @@ impl Callback for ModelCheckpoint {
         Ok(())
     }
 }
+
+/// Re-evaluates every checkpoint saved by a `ModelCheckpoint` on the same
+/// data and ranks them, so the best checkpoint can be chosen on held-out
+/// metrics instead of the value monitored during training
+pub struct CheckpointEvaluator {
+    filepath: String,
+}
+
+/// Metrics of one checkpoint in a `CheckpointRanking`
+#[derive(Debug, Clone)]
+pub struct CheckpointResult {
+    pub path: PathBuf,
+    /// Epoch recorded in the checkpoint's metadata; `usize::MAX` marks the
+    /// final checkpoint
+    pub epoch: Option<usize>,
+    /// Metrics logged when the checkpoint was saved
+    pub saved_metrics: HashMap<String, f32>,
+    /// Metrics computed by the evaluation
+    pub metrics: HashMap<String, f32>,
+}
+
+/// Evaluated checkpoints, best first
+#[derive(Debug, Clone)]
+pub struct CheckpointRanking {
+    pub rank_by: String,
+    pub mode: CheckpointMode,
+    pub results: Vec<CheckpointResult>,
+}
+
+impl CheckpointEvaluator {
+    /// `filepath` is a `ModelCheckpoint` path pattern such as
+    /// `checkpoints/model_{epoch}_{val}.bin`
+    pub fn new(filepath: &str) -> Self {
+        CheckpointEvaluator {
+            filepath: filepath.to_string(),
+        }
+    }
+
+    /// Saved checkpoint files matching the pattern, in path order, without
+    /// their metadata files
+    pub fn checkpoints(&self) -> Result<Vec<PathBuf>, BellandeError> {
+        const PLACEHOLDER: &str = "\u{0}";
+        let pattern = glob::Pattern::escape(
+            &self
+                .filepath
+                .replace("{epoch}", PLACEHOLDER)
+                .replace("{val}", PLACEHOLDER),
+        )
+        .replace(PLACEHOLDER, "*");
+
+        let mut paths: Vec<PathBuf> = glob::glob(&pattern)
+            .map_err(|e| {
+                BellandeError::InvalidConfiguration(format!(
+                    "Invalid checkpoint pattern '{}': {}",
+                    self.filepath, e
+                ))
+            })?
+            .filter_map(Result::ok)
+            .filter(|path| !path.to_string_lossy().ends_with(".meta.json"))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Loads each checkpoint into `model`, evaluates `metrics` over every
+    /// batch of `loader` and ranks the checkpoints by the metric named
+    /// `rank_by`. Checkpoints written as binary tensor files are loaded
+    /// with `load_state_dict`, anything else with `Model::load`.
+    pub fn evaluate(
+        &self,
+        model: &mut dyn Model,
+        loader: &DataLoader,
+        metrics: &mut [Box<dyn Metric>],
+        rank_by: &str,
+        mode: CheckpointMode,
+    ) -> Result<CheckpointRanking, BellandeError> {
+        if !metrics.iter().any(|metric| metric.name() == rank_by) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "No metric named '{}' to rank checkpoints by",
+                rank_by
+            )));
+        }
+        let paths = self.checkpoints()?;
+        if paths.is_empty() {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "No checkpoints match '{}'",
+                self.filepath
+            )));
+        }
+
+        let mut results = Vec::with_capacity(paths.len());
+        for path in paths {
+            load_checkpoint(model, &path)?;
+            model.eval();
+            metrics.iter_mut().for_each(|metric| metric.reset());
+            for (data, target) in loader.iter() {
+                let output = model.forward(&data)?;
+                for metric in metrics.iter_mut() {
+                    metric.update(&output, &target);
+                }
+            }
+
+            let metadata = File::open(path.with_extension("meta.json"))
+                .ok()
+                .and_then(|file| serde_json::from_reader::<_, CheckpointMetadata>(file).ok());
+            results.push(CheckpointResult {
+                epoch: metadata.as_ref().map(|m| m.epoch),
+                saved_metrics: metadata.map(|m| m.metrics).unwrap_or_default(),
+                metrics: metrics
+                    .iter()
+                    .map(|metric| (metric.name().to_string(), metric.compute()))
+                    .collect(),
+                path,
+            });
+        }
+
+        // NaN values rank last in either mode
+        let key = |result: &CheckpointResult| {
+            result.metrics.get(rank_by).copied().filter(|v| !v.is_nan())
+        };
+        results.sort_by(|a, b| match (key(a), key(b)) {
+            (Some(x), Some(y)) => match mode {
+                CheckpointMode::Min => x.total_cmp(&y),
+                CheckpointMode::Max => y.total_cmp(&x),
+            },
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        });
+
+        Ok(CheckpointRanking {
+            rank_by: rank_by.to_string(),
+            mode,
+            results,
+        })
+    }
+}
+
+fn load_checkpoint(model: &mut dyn Model, path: &Path) -> Result<(), BellandeError> {
+    if is_state_file(path) {
+        return model.load_state_dict(LazyStateDict::open(path)?.to_state_dict()?);
+    }
+    let path = path.to_str().ok_or_else(|| {
+        BellandeError::InvalidParameter(format!("Non UTF-8 checkpoint path {}", path.display()))
+    })?;
+    model.load(path)
+}
+
+impl CheckpointRanking {
+    pub fn best(&self) -> Option<&CheckpointResult> {
+        self.results.first()
+    }
+}
+
+/// Table of the ranked checkpoints with the ranking metric first
+impl std::fmt::Display for CheckpointRanking {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut names: Vec<&String> = self
+            .results
+            .iter()
+            .flat_map(|result| result.metrics.keys())
+            .filter(|name| **name != self.rank_by)
+            .collect();
+        names.sort();
+        names.dedup();
+        names.insert(0, &self.rank_by);
+
+        let paths: Vec<String> = self
+            .results
+            .iter()
+            .map(|result| result.path.display().to_string())
+            .collect();
+        let path_width = paths.iter().map(String::len).max().unwrap_or(0).max(10);
+
+        write!(f, "rank  {:<path_width$}  {:>5}", "checkpoint", "epoch")?;
+        for name in &names {
+            write!(f, "  {:>w$}", name, w = name.len().max(10))?;
+        }
+        writeln!(f)?;
+
+        for (rank, (result, path)) in self.results.iter().zip(&paths).enumerate() {
+            let epoch = match result.epoch {
+                Some(usize::MAX) => "final".to_string(),
+                Some(epoch) => epoch.to_string(),
+                None => "-".to_string(),
+            };
+            write!(f, "{:<4}  {:<path_width$}  {:>5}", rank + 1, path, epoch)?;
+            for name in &names {
+                match result.metrics.get(*name) {
+                    Some(value) => write!(f, "  {:>w$.4}", value, w = name.len().max(10))?,
+                    None => write!(f, "  {:>w$}", "-", w = name.len().max(10))?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
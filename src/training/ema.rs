@@ -0,0 +1,97 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::error::BellandeError;
+use crate::models::models::Model;
+
+/// Exponential moving average of a model's weights, kept in a second model
+/// of the same architecture: after each update every tensor of its state
+/// dictionary is `decay * average + (1 - decay) * source`. The first update
+/// copies the source weights. The averaged model stays in evaluation mode.
+pub struct ModelEma {
+    model: Box<dyn Model>,
+    decay: f32,
+    updates: usize,
+}
+
+impl ModelEma {
+    /// `decay` in `[0, 1)`; values close to 1, e.g. 0.999, average over
+    /// more steps
+    pub fn new(mut model: Box<dyn Model>, decay: f32) -> Result<Self, BellandeError> {
+        if !(0.0..1.0).contains(&decay) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "EMA decay must be in [0, 1), got {}",
+                decay
+            )));
+        }
+        model.eval();
+        Ok(ModelEma {
+            model,
+            decay,
+            updates: 0,
+        })
+    }
+
+    /// Moves the average towards the current weights of `source`
+    pub fn update(&mut self, source: &dyn Model) -> Result<(), BellandeError> {
+        let source = source.state_dict();
+        if self.updates == 0 {
+            self.model.load_state_dict(source)?;
+        } else {
+            let mut state = self.model.state_dict();
+            for (name, average) in state.iter_mut() {
+                let current = source.get(name).ok_or_else(|| {
+                    BellandeError::InvalidConfiguration(format!(
+                        "Source model has no tensor '{}'",
+                        name
+                    ))
+                })?;
+                if current.shape != average.shape {
+                    return Err(BellandeError::ShapeMismatch(format!(
+                        "Tensor '{}' has shape {:?} in the source model and {:?} in the average",
+                        name, current.shape, average.shape
+                    )));
+                }
+                for (a, &c) in average.data.iter_mut().zip(&current.data) {
+                    *a = self.decay * *a + (1.0 - self.decay) * c;
+                }
+            }
+            self.model.load_state_dict(state)?;
+        }
+        self.updates += 1;
+        Ok(())
+    }
+
+    pub fn decay(&self) -> f32 {
+        self.decay
+    }
+
+    /// Number of updates so far
+    pub fn updates(&self) -> usize {
+        self.updates
+    }
+
+    pub fn model(&self) -> &dyn Model {
+        self.model.as_ref()
+    }
+
+    pub fn model_mut(&mut self) -> &mut dyn Model {
+        self.model.as_mut()
+    }
+
+    pub fn into_model(self) -> Box<dyn Model> {
+        self.model
+    }
+}
@@ -14,14 +14,48 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::core::error::BellandeError;
+use crate::training::run_metadata::RunMetadata;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Default, Clone, Serialize, Deserialize)]
 pub struct TrainingHistory {
     pub epochs: Vec<usize>,
-    pub metrics: HashMap<String, Vec<f32>>,
+    /// Value of each metric per entry of `epochs`, `None` where the entry
+    /// did not record it
+    pub metrics: HashMap<String, Vec<Option<f32>>>,
+    /// Wall-clock duration of each recorded epoch, in seconds
+    #[serde(default)]
+    pub durations: Vec<f32>,
+    /// Number of older epochs moved to the spill file
+    #[serde(default)]
+    pub spilled_epochs: usize,
+    /// Version, machine and configuration of the run that produced the
+    /// history
+    #[serde(default)]
+    pub run_metadata: Option<RunMetadata>,
+    #[serde(skip)]
+    spill: Option<HistorySpill>,
+}
+
+/// Bounded mode: only the most recent `capacity` epochs stay in memory
+#[derive(Clone)]
+struct HistorySpill {
+    capacity: usize,
+    path: PathBuf,
+}
+
+/// One epoch of history as stored in the spill file, one JSON object per line
+#[derive(Serialize, Deserialize)]
+struct SpilledEpoch {
+    epoch: usize,
+    #[serde(default)]
+    duration: Option<f32>,
+    metrics: HashMap<String, f32>,
 }
 
 impl TrainingHistory {
@@ -29,18 +63,211 @@ impl TrainingHistory {
         TrainingHistory {
             epochs: Vec::new(),
             metrics: HashMap::new(),
+            durations: Vec::new(),
+            spilled_epochs: 0,
+            run_metadata: None,
+            spill: None,
+        }
+    }
+
+    /// Keeps at most `capacity` epochs in memory, appending older ones to a
+    /// JSON Lines file at `path` when `spill_excess` runs. The file is
+    /// truncated.
+    pub fn with_spill(
+        mut self,
+        capacity: usize,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, BellandeError> {
+        if capacity == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "History capacity must be at least 1".into(),
+            ));
         }
+        let path = path.as_ref().to_path_buf();
+        fs::write(&path, "").map_err(BellandeError::IOError)?;
+        self.spill = Some(HistorySpill { capacity, path });
+        Ok(self)
     }
 
-    pub fn update(&mut self, epoch: usize, metrics: HashMap<String, f32>) {
+    /// Appends an epoch entry. Metrics missing from `metrics`, as in an
+    /// epoch aborted by the time limit, are recorded as `None`, and a new
+    /// metric is backfilled with `None` for the earlier entries.
+    pub fn update(&mut self, epoch: usize, mut metrics: HashMap<String, f32>) {
+        let position = self.epochs.len();
         self.epochs.push(epoch);
+        for (key, values) in self.metrics.iter_mut() {
+            values.push(metrics.remove(key));
+        }
         for (key, value) in metrics {
-            self.metrics.entry(key).or_insert_with(Vec::new).push(value);
+            let mut values = vec![None; position];
+            values.push(Some(value));
+            self.metrics.insert(key, values);
+        }
+    }
+
+    /// Records the duration of the most recently updated epoch
+    pub fn record_duration(&mut self, duration: Duration) {
+        self.durations.push(duration.as_secs_f32());
+    }
+
+    /// Moves the oldest epochs beyond the in-memory capacity to the spill
+    /// file. Does nothing unless the history was built with `with_spill`.
+    pub fn spill_excess(&mut self) -> Result<(), BellandeError> {
+        let (capacity, path) = match &self.spill {
+            Some(spill) => (spill.capacity, spill.path.clone()),
+            None => return Ok(()),
+        };
+        let excess = match self.epochs.len().checked_sub(capacity) {
+            Some(excess) if excess > 0 => excess,
+            _ => return Ok(()),
+        };
+
+        let mut lines = String::new();
+        for position in 0..excess {
+            let entry = self.entry(position);
+            let line =
+                serde_json::to_string(&entry).map_err(|_| BellandeError::SerializationError)?;
+            lines.push_str(&line);
+            lines.push('\n');
         }
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .map_err(BellandeError::IOError)?;
+
+        self.epochs.drain(..excess);
+        for values in self.metrics.values_mut() {
+            values.drain(..excess.min(values.len()));
+        }
+        self.metrics
+            .retain(|_, values| values.iter().any(Option::is_some));
+        let drop = excess.min(self.durations.len());
+        self.durations.drain(..drop);
+        self.spilled_epochs += excess;
+        Ok(())
+    }
+
+    /// The complete history, reading spilled epochs back from disk
+    pub fn full_history(&self) -> Result<TrainingHistory, BellandeError> {
+        let mut full = TrainingHistory::new();
+        if let Some(spill) = &self.spill {
+            let file = fs::File::open(&spill.path).map_err(BellandeError::IOError)?;
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(BellandeError::IOError)?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: SpilledEpoch =
+                    serde_json::from_str(&line).map_err(|_| BellandeError::SerializationError)?;
+                full.push_entry(entry);
+            }
+        }
+        for position in 0..self.epochs.len() {
+            full.push_entry(self.entry(position));
+        }
+        Ok(full)
     }
 
-    pub fn get_metric(&self, name: &str) -> Option<&Vec<f32>> {
-        self.metrics.get(name)
+    fn entry(&self, position: usize) -> SpilledEpoch {
+        let metrics = self
+            .metrics
+            .keys()
+            .filter_map(|name| {
+                self.metric_at(name, position)
+                    .map(|value| (name.clone(), value))
+            })
+            .collect();
+        SpilledEpoch {
+            epoch: self.epochs[position],
+            duration: self.durations.get(position).copied(),
+            metrics,
+        }
+    }
+
+    fn push_entry(&mut self, entry: SpilledEpoch) {
+        self.update(entry.epoch, entry.metrics);
+        if let Some(duration) = entry.duration {
+            self.durations.push(duration);
+        }
+    }
+
+    /// Recorded values of a metric in epoch order, skipping the entries
+    /// that did not record it
+    pub fn get_metric(&self, name: &str) -> Option<Vec<f32>> {
+        self.metrics
+            .get(name)
+            .map(|values| values.iter().flatten().copied().collect())
+    }
+
+    /// Returns the value of a metric at the given epoch position, if it was recorded
+    fn metric_at(&self, name: &str, position: usize) -> Option<f32> {
+        self.metrics.get(name)?.get(position).copied().flatten()
+    }
+
+    /// Formats the end-of-epoch summary for the epoch at `position`, showing each
+    /// metric, its delta from the previous epoch, a `*` marker when it is the best
+    /// value so far, and the epoch duration. In bounded mode, "best" only
+    /// considers the epochs still in memory.
+    pub fn epoch_summary(&self, position: usize) -> Option<String> {
+        let epoch = *self.epochs.get(position)?;
+
+        let mut header = format!("Epoch {}", epoch + 1);
+        if let Some(duration) = self.durations.get(position) {
+            header.push_str(&format!(" - {:.2}s", duration));
+        }
+
+        let mut names: Vec<&String> = self.metrics.keys().filter(|k| *k != "epoch").collect();
+        names.sort();
+        let width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+
+        let mut summary = header;
+        for name in names {
+            let current = match self.metric_at(name, position) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let delta = match position
+                .checked_sub(1)
+                .and_then(|p| self.metric_at(name, p))
+            {
+                Some(previous) => format!("({:+.4})", current - previous),
+                None => String::new(),
+            };
+
+            let lower_is_better = is_lower_better(name);
+            let is_best = !is_diagnostic(name)
+                && (0..position)
+                    .filter_map(|p| self.metric_at(name, p))
+                    .all(|previous| {
+                        if lower_is_better {
+                            current <= previous
+                        } else {
+                            current >= previous
+                        }
+                    });
+
+            summary.push_str(&format!(
+                "\n  {:<width$}  {:>10.4}  {}{}",
+                name,
+                current,
+                delta,
+                if is_best { " *" } else { "" },
+                width = width
+            ));
+        }
+
+        Some(summary)
+    }
+
+    /// Formats the summary for the most recently recorded epoch
+    pub fn last_epoch_summary(&self) -> Option<String> {
+        self.epochs
+            .len()
+            .checked_sub(1)
+            .and_then(|position| self.epoch_summary(position))
     }
 
     pub fn save(&self, path: &str) -> Result<(), BellandeError> {
@@ -52,6 +279,74 @@ impl TrainingHistory {
 
     pub fn load(path: &str) -> Result<Self, BellandeError> {
         let json = fs::read_to_string(path).map_err(|e| BellandeError::IOError(e.to_string()))?;
-        serde_json::from_str(&json).map_err(|e| BellandeError::SerializationError(e.to_string()))
+        let mut history: Self = serde_json::from_str(&json)
+            .map_err(|e| BellandeError::SerializationError(e.to_string()))?;
+        // Older histories stored metrics that first appear after epoch 0
+        // without padding, aligned to the most recent epochs
+        let len = history.epochs.len();
+        for values in history.metrics.values_mut() {
+            if values.len() < len {
+                values.splice(0..0, vec![None; len - values.len()]);
+            }
+        }
+        Ok(history)
+    }
+}
+
+/// Run diagnostics such as per-group learning rates, gradient norms, the
+/// privacy budget spent and retry bookkeeping, which have no notion of a
+/// best value
+fn is_diagnostic(name: &str) -> bool {
+    const PREFIXES: [&str; 5] = ["lr/", "grad_norm/", "privacy/", "radam/", "trust_ratio/"];
+    const NAMES: [&str; 4] = [
+        "partial",
+        "attempt",
+        "effective_batch_size",
+        "pseudo_label_rate",
+    ];
+    let name = name.strip_prefix("val_").unwrap_or(name);
+    PREFIXES.iter().any(|prefix| name.starts_with(prefix)) || NAMES.contains(&name)
+}
+
+/// Loss- and error-style metrics improve when they decrease; everything else is
+/// treated as a score that improves when it increases
+fn is_lower_better(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.contains("loss") || name.contains("error") || name.contains("perplexity")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(values: &[(&str, f32)]) -> HashMap<String, f32> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect()
+    }
+
+    #[test]
+    fn partial_epoch_keeps_metrics_aligned() {
+        let mut history = TrainingHistory::new();
+        history.update(0, metrics(&[("loss", 1.0), ("val_loss", 0.9)]));
+        // Epoch 1 aborted by the time limit, before validation
+        history.update(
+            1,
+            metrics(&[("loss", 0.8), ("partial", 1.0), ("attempt", 0.0)]),
+        );
+        history.update(1, metrics(&[("loss", 0.7), ("val_loss", 0.6)]));
+
+        assert_eq!(history.epochs, vec![0, 1, 1]);
+        assert_eq!(history.metric_at("val_loss", 0), Some(0.9));
+        assert_eq!(history.metric_at("val_loss", 1), None);
+        assert_eq!(history.metric_at("val_loss", 2), Some(0.6));
+        assert_eq!(history.metric_at("partial", 1), Some(1.0));
+        assert_eq!(history.metric_at("partial", 2), None);
+        assert_eq!(history.get_metric("val_loss"), Some(vec![0.9, 0.6]));
+        assert!(history
+            .metrics
+            .values()
+            .all(|values| values.len() == history.epochs.len()));
     }
 }
@@ -0,0 +1,263 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::error::BellandeError;
+use crate::training::callbacks::Callback;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// On-disk format of a `MetricLogger`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Comma-separated values with a header line per file
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// Callback streaming end-of-epoch metrics to a CSV or JSON Lines file.
+/// Every row is written and flushed immediately. With rotation enabled the
+/// current file is renamed to `<path>.1` (shifting older files to `.2`,
+/// `.3`, ...) once it grows too large or too old, so long jobs never produce
+/// a single huge log.
+pub struct MetricLogger {
+    path: PathBuf,
+    format: LogFormat,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    max_files: Option<usize>,
+    current: Option<LogFile>,
+}
+
+struct LogFile {
+    file: File,
+    bytes: u64,
+    opened: Instant,
+    columns: Vec<String>,
+}
+
+impl MetricLogger {
+    pub fn new(path: impl AsRef<Path>, format: LogFormat) -> Self {
+        MetricLogger {
+            path: path.as_ref().to_path_buf(),
+            format,
+            max_bytes: None,
+            max_age: None,
+            max_files: None,
+            current: None,
+        }
+    }
+
+    /// Rotates once the current file reaches `max_bytes`
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Result<Self, BellandeError> {
+        if max_bytes == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Log rotation size must be positive".into(),
+            ));
+        }
+        self.max_bytes = Some(max_bytes);
+        Ok(self)
+    }
+
+    /// Rotates once the current file has been open for `max_age`
+    pub fn with_max_age(mut self, max_age: Duration) -> Result<Self, BellandeError> {
+        if max_age.is_zero() {
+            return Err(BellandeError::InvalidParameter(
+                "Log rotation age must be positive".into(),
+            ));
+        }
+        self.max_age = Some(max_age);
+        Ok(self)
+    }
+
+    /// Keeps at most `max_files` rotated files, deleting the oldest. All are
+    /// kept by default.
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one row, stamped with the current Unix time. A CSV file is
+    /// rotated as well when the set of metric names changes, so every file
+    /// has a single header.
+    pub fn log(&mut self, metrics: &HashMap<String, f32>) -> Result<(), BellandeError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs_f64())
+            .unwrap_or(0.0);
+        let row: BTreeMap<&str, f32> = metrics
+            .iter()
+            .filter(|(name, _)| name.as_str() != "timestamp")
+            .map(|(name, &value)| (name.as_str(), value))
+            .collect();
+        let mut columns: Vec<String> = vec!["timestamp".to_string()];
+        columns.extend(row.keys().map(|name| name.to_string()));
+
+        if self.should_rotate(&columns) {
+            self.rotate()?;
+        }
+        if self.current.is_none() {
+            self.open(columns.clone())?;
+        }
+
+        let timestamp = format!("{:.3}", timestamp);
+        let line = match self.format {
+            LogFormat::Csv => std::iter::once(timestamp)
+                .chain(row.values().map(|value| value.to_string()))
+                .collect::<Vec<_>>()
+                .join(","),
+            LogFormat::Jsonl => {
+                let fields: Vec<String> = std::iter::once(format!("\"timestamp\":{}", timestamp))
+                    .chain(row.iter().map(|(name, value)| {
+                        format!("{}:{}", serde_json::Value::from(*name), json_number(*value))
+                    }))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+        };
+        self.write_line(&line)
+    }
+
+    /// Closes the current file and renames it to `<path>.1`, shifting older
+    /// rotated files up by one
+    pub fn rotate(&mut self) -> Result<(), BellandeError> {
+        self.current = None;
+        if !self.path.exists() {
+            return Ok(());
+        }
+
+        let mut highest = 0;
+        while self.rotated_path(highest + 1).exists() {
+            highest += 1;
+        }
+        if let Some(max_files) = self.max_files {
+            for index in (max_files.max(1)..=highest).rev() {
+                fs::remove_file(self.rotated_path(index)).map_err(BellandeError::IOError)?;
+            }
+            highest = highest.min(max_files.saturating_sub(1));
+            if max_files == 0 {
+                return fs::remove_file(&self.path).map_err(BellandeError::IOError);
+            }
+        }
+        for index in (1..=highest).rev() {
+            fs::rename(self.rotated_path(index), self.rotated_path(index + 1))
+                .map_err(BellandeError::IOError)?;
+        }
+        fs::rename(&self.path, self.rotated_path(1)).map_err(BellandeError::IOError)
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn should_rotate(&self, columns: &[String]) -> bool {
+        let current = match &self.current {
+            Some(current) => current,
+            // A log left over from an earlier run is rotated away first
+            None => return self.path.exists(),
+        };
+        (self.format == LogFormat::Csv && current.columns != columns)
+            || self
+                .max_bytes
+                .is_some_and(|max_bytes| current.bytes >= max_bytes)
+            || self
+                .max_age
+                .is_some_and(|max_age| current.opened.elapsed() >= max_age)
+    }
+
+    fn open(&mut self, columns: Vec<String>) -> Result<(), BellandeError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(BellandeError::IOError)?;
+        self.current = Some(LogFile {
+            file,
+            bytes: 0,
+            opened: Instant::now(),
+            columns,
+        });
+        if self.format == LogFormat::Csv {
+            let header = self.current.as_ref().map_or_else(String::new, |current| {
+                current
+                    .columns
+                    .iter()
+                    .map(|name| csv_field(name))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            });
+            self.write_line(&header)?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), BellandeError> {
+        let current = self
+            .current
+            .as_mut()
+            .ok_or_else(|| BellandeError::RuntimeError("Log file is not open".into()))?;
+        current
+            .file
+            .write_all(format!("{}\n", line).as_bytes())
+            .and_then(|_| current.file.flush())
+            .map_err(BellandeError::IOError)?;
+        current.bytes += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+impl Callback for MetricLogger {
+    fn on_epoch_end(
+        &mut self,
+        epoch: usize,
+        logs: &HashMap<String, f32>,
+    ) -> Result<(), BellandeError> {
+        let mut row = logs.clone();
+        row.entry("epoch".to_string()).or_insert(epoch as f32);
+        self.log(&row)
+    }
+
+    fn on_train_end(&mut self, _logs: &HashMap<String, f32>) -> Result<(), BellandeError> {
+        self.current = None;
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field containing separators or quotes
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// JSON has no NaN or infinity; such values are written as null
+fn json_number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{autograd, error::BellandeError, numerics::numerics, random, tensor::Tensor};
+use crate::data::dataloader::DataLoader;
+use crate::loss::Loss;
+use crate::models::models::Model;
+use crate::utilities::visualization::{Visualization, VisualizationBuilder};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+/// Settings for `compute_loss_landscape`
+#[derive(Debug, Clone)]
+pub struct LossLandscapeConfig {
+    resolution: usize,
+    range: (f32, f32),
+    filter_normalize: bool,
+    ignore_1d: bool,
+    seed: Option<u64>,
+}
+
+/// Losses on a grid spanned by two random directions around the trained
+/// weights, which sit at `(0, 0)`. `losses[j][i]` is the loss at
+/// `(alphas[i], betas[j])`
+#[derive(Debug, Clone)]
+pub struct LossLandscape {
+    pub alphas: Vec<f32>,
+    pub betas: Vec<f32>,
+    pub losses: Vec<Vec<f32>>,
+}
+
+impl Default for LossLandscapeConfig {
+    fn default() -> Self {
+        LossLandscapeConfig {
+            resolution: 21,
+            range: (-1.0, 1.0),
+            filter_normalize: true,
+            ignore_1d: true,
+            seed: None,
+        }
+    }
+}
+
+impl LossLandscapeConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of grid points along each direction
+    pub fn with_resolution(mut self, resolution: usize) -> Result<Self, BellandeError> {
+        if resolution < 2 {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Resolution must be at least 2, got {}",
+                resolution
+            )));
+        }
+        self.resolution = resolution;
+        Ok(self)
+    }
+
+    /// Step sizes covered along both directions
+    pub fn with_range(mut self, min: f32, max: f32) -> Result<Self, BellandeError> {
+        if !min.is_finite() || !max.is_finite() || min >= max {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Invalid landscape range [{}, {}]",
+                min, max
+            )));
+        }
+        self.range = (min, max);
+        Ok(self)
+    }
+
+    /// Rescales every filter of a direction to the norm of the matching
+    /// filter of the weights (Li et al., 2018), so that landscapes of
+    /// differently scaled networks are comparable
+    pub fn with_filter_normalization(mut self, filter_normalize: bool) -> Self {
+        self.filter_normalize = filter_normalize;
+        self
+    }
+
+    /// Leaves biases, normalization parameters and other 1D tensors
+    /// unperturbed
+    pub fn with_ignore_1d(mut self, ignore_1d: bool) -> Self {
+        self.ignore_1d = ignore_1d;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+impl LossLandscape {
+    /// Grid point with the lowest loss as `(alpha, beta, loss)`
+    pub fn minimum(&self) -> Option<(f32, f32, f32)> {
+        self.losses
+            .iter()
+            .zip(self.betas.iter())
+            .flat_map(|(row, &beta)| {
+                row.iter()
+                    .zip(self.alphas.iter())
+                    .map(move |(&loss, &alpha)| (alpha, beta, loss))
+            })
+            .filter(|(_, _, loss)| loss.is_finite())
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+    }
+
+    /// Renders the landscape as a filled contour plot with `levels` isolines
+    pub fn plot<P: AsRef<Path>>(
+        &self,
+        output_path: P,
+        levels: usize,
+        config: VisualizationBuilder,
+    ) -> Result<(), Box<dyn Error>> {
+        Visualization::plot_contour(
+            &self.alphas,
+            &self.betas,
+            &self.losses,
+            levels,
+            output_path,
+            config,
+        )
+    }
+}
+
+/// Evaluates the mean loss of `model` on `loader` at every point
+/// `weights + alpha * d1 + beta * d2` of the configured grid, where `d1` and
+/// `d2` are random Gaussian directions. The model is switched to eval mode
+/// and its original weights are restored afterwards
+pub fn compute_loss_landscape(
+    model: &mut dyn Model,
+    loss_fn: &dyn Loss,
+    loader: &DataLoader,
+    config: &LossLandscapeConfig,
+) -> Result<LossLandscape, BellandeError> {
+    if let Some(seed) = config.seed {
+        random::set_seed(seed);
+    }
+    let weights = model.state_dict();
+    let mut names: Vec<&String> = weights
+        .iter()
+        .filter(|(_, tensor)| !config.ignore_1d || tensor.shape.len() > 1)
+        .map(|(name, _)| name)
+        .collect();
+    if names.is_empty() {
+        return Err(BellandeError::InvalidConfiguration(
+            "Model has no parameters to perturb".into(),
+        ));
+    }
+    names.sort();
+
+    let first = random_direction(&weights, &names, config.filter_normalize);
+    let second = random_direction(&weights, &names, config.filter_normalize);
+
+    let (min, max) = config.range;
+    let step = (max - min) / (config.resolution - 1) as f32;
+    let grid: Vec<f32> = (0..config.resolution)
+        .map(|i| min + step * i as f32)
+        .collect();
+
+    model.eval();
+    let result = grid
+        .iter()
+        .map(|&beta| {
+            grid.iter()
+                .map(|&alpha| {
+                    let mut state = weights.clone();
+                    for (name, (d1, d2)) in names.iter().zip(first.iter().zip(second.iter())) {
+                        if let Some(tensor) = state.get_mut(*name) {
+                            for ((w, &a), &b) in tensor.data.iter_mut().zip(d1).zip(d2) {
+                                *w += alpha * a + beta * b;
+                            }
+                        }
+                    }
+                    model.load_state_dict(state)?;
+                    mean_loss(model, loss_fn, loader)
+                })
+                .collect::<Result<Vec<f32>, BellandeError>>()
+        })
+        .collect::<Result<Vec<_>, BellandeError>>();
+    model.load_state_dict(weights)?;
+
+    Ok(LossLandscape {
+        alphas: grid.clone(),
+        betas: grid,
+        losses: result?,
+    })
+}
+
+/// One Gaussian direction per named tensor, optionally filter normalized
+fn random_direction(
+    weights: &HashMap<String, Tensor>,
+    names: &[&String],
+    filter_normalize: bool,
+) -> Vec<Vec<f32>> {
+    names
+        .iter()
+        .map(|name| {
+            let tensor = &weights[*name];
+            let mut direction = random::normal(0.0, 1.0, tensor.data.len());
+            if filter_normalize && !direction.is_empty() {
+                let filters = if tensor.shape.len() > 1 {
+                    tensor.shape[0].max(1)
+                } else {
+                    1
+                };
+                let filter_len = (tensor.data.len() / filters).max(1);
+                for (d, w) in direction
+                    .chunks_mut(filter_len)
+                    .zip(tensor.data.chunks(filter_len))
+                {
+                    let d_norm = d.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    let w_norm = w.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    let scale = w_norm / (d_norm + numerics().denom_eps);
+                    d.iter_mut().for_each(|x| *x *= scale);
+                }
+            }
+            direction
+        })
+        .collect()
+}
+
+/// Sample-weighted mean loss over every batch of `loader`
+fn mean_loss(
+    model: &mut dyn Model,
+    loss_fn: &dyn Loss,
+    loader: &DataLoader,
+) -> Result<f32, BellandeError> {
+    let _guard = autograd::no_grad();
+    let mut total = 0.0;
+    let mut count = 0;
+    for (input, target) in loader.iter() {
+        let output = model.forward(&input)?;
+        let loss = loss_fn.forward(&output, &target)?;
+        let batch_size = output.shape.first().copied().unwrap_or(1);
+        let batch_loss = loss.data.iter().sum::<f32>() / loss.data.len().max(1) as f32;
+        total += batch_loss * batch_size as f32;
+        count += batch_size;
+    }
+    if count == 0 {
+        return Err(BellandeError::InvalidConfiguration(
+            "Probe loader produced no batches".into(),
+        ));
+    }
+    Ok(total / count as f32)
+}
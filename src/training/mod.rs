@@ -1,5 +1,14 @@
+pub mod adversarial;
+pub mod batch_size;
 pub mod callbacks;
 pub mod checkpoint;
+pub mod ema;
 pub mod history;
+pub mod logger;
+pub mod loss_landscape;
+pub mod run_manager;
+pub mod run_metadata;
+pub mod semi_supervised;
+pub mod time_limit;
 pub mod trainer;
 pub mod validator;
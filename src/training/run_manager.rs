@@ -0,0 +1,333 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::error::BellandeError;
+use crate::training::checkpoint::{CheckpointMode, ModelCheckpoint};
+use crate::training::history::TrainingHistory;
+use crate::training::logger::{LogFormat, MetricLogger};
+use crate::training::run_metadata::RunMetadata;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+const INDEX_FILE: &str = "index.json";
+const RUN_FILE: &str = "run.json";
+const HISTORY_FILE: &str = "history.json";
+
+/// Summary of one run in the root index
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub name: String,
+    /// Run directory, relative to the root
+    pub dir: PathBuf,
+    pub metadata: RunMetadata,
+    /// Last recorded value of each metric, filled in by `Run::finish`
+    #[serde(default)]
+    pub final_metrics: BTreeMap<String, f32>,
+    #[serde(default)]
+    pub epochs: usize,
+    #[serde(default)]
+    pub finished: bool,
+}
+
+/// Creates one directory per training run under a common root and keeps
+/// `index.json` in the root listing every run with its final metrics.
+///
+/// Run directory names come from a template with `{timestamp}` (UTC, as
+/// `YYYYMMDD-HHMMSS`) and `{config_hash}` placeholders; a numeric suffix is
+/// appended when the name is already taken.
+pub struct RunManager {
+    root: PathBuf,
+    name_template: String,
+}
+
+impl RunManager {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, BellandeError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root).map_err(BellandeError::IOError)?;
+        Ok(RunManager {
+            root,
+            name_template: "{timestamp}".to_string(),
+        })
+    }
+
+    pub fn with_name_template(mut self, template: &str) -> Result<Self, BellandeError> {
+        if template.trim().is_empty() || template.contains(['/', '\\']) {
+            return Err(BellandeError::InvalidConfiguration(format!(
+                "Invalid run name template '{}'",
+                template
+            )));
+        }
+        self.name_template = template.to_string();
+        Ok(self)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Creates the directory of a new run with `checkpoints`, `logs`,
+    /// `plots` and `exports` subdirectories, and adds it to the index
+    pub fn start_run(&self, metadata: RunMetadata) -> Result<Run, BellandeError> {
+        let base = self
+            .name_template
+            .replace("{timestamp}", &format_timestamp(metadata.start_time))
+            .replace(
+                "{config_hash}",
+                metadata.config_hash.as_deref().unwrap_or("noconfig"),
+            );
+
+        let mut name = base.clone();
+        let mut suffix = 1;
+        // `create_dir` fails on existing directories, so concurrent runs
+        // never share a name
+        loop {
+            match fs::create_dir(self.root.join(&name)) {
+                Ok(()) => break,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    name = format!("{}-{}", base, suffix);
+                    suffix += 1;
+                }
+                Err(e) => return Err(BellandeError::IOError(e)),
+            }
+        }
+
+        let run = Run {
+            root: self.root.clone(),
+            dir: self.root.join(&name),
+            record: RunRecord {
+                dir: PathBuf::from(&name),
+                name,
+                metadata,
+                final_metrics: BTreeMap::new(),
+                epochs: 0,
+                finished: false,
+            },
+        };
+        for dir in [
+            run.checkpoint_dir(),
+            run.log_dir(),
+            run.plot_dir(),
+            run.export_dir(),
+        ] {
+            fs::create_dir_all(dir).map_err(BellandeError::IOError)?;
+        }
+        run.save_record()?;
+        Ok(run)
+    }
+
+    /// Every run in the index, oldest first
+    pub fn runs(&self) -> Result<Vec<RunRecord>, BellandeError> {
+        read_index(&self.root)
+    }
+
+    /// Table of the final value of each of `metrics` for every finished
+    /// run, one row per run. All metrics seen in any run are used when
+    /// `metrics` is empty.
+    pub fn comparison(&self, metrics: &[&str]) -> Result<String, BellandeError> {
+        let runs: Vec<RunRecord> = self.runs()?.into_iter().filter(|r| r.finished).collect();
+        let columns: Vec<String> = if metrics.is_empty() {
+            runs.iter()
+                .flat_map(|run| run.final_metrics.keys().cloned())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect()
+        } else {
+            metrics.iter().map(|m| m.to_string()).collect()
+        };
+
+        let name_width = runs
+            .iter()
+            .map(|run| run.name.len())
+            .chain(std::iter::once(3))
+            .max()
+            .unwrap_or(3);
+        let mut table = format!("{:<width$}", "run", width = name_width);
+        for column in &columns {
+            table.push_str(&format!("  {:>12}", column));
+        }
+        for run in &runs {
+            table.push_str(&format!("\n{:<width$}", run.name, width = name_width));
+            for column in &columns {
+                match run.final_metrics.get(column) {
+                    Some(value) => table.push_str(&format!("  {:>12.4}", value)),
+                    None => table.push_str(&format!("  {:>12}", "-")),
+                }
+            }
+        }
+        Ok(table)
+    }
+}
+
+/// Directory of a single run created by `RunManager::start_run`
+pub struct Run {
+    root: PathBuf,
+    dir: PathBuf,
+    record: RunRecord,
+}
+
+impl Run {
+    pub fn name(&self) -> &str {
+        &self.record.name
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn metadata(&self) -> &RunMetadata {
+        &self.record.metadata
+    }
+
+    pub fn checkpoint_dir(&self) -> PathBuf {
+        self.dir.join("checkpoints")
+    }
+
+    pub fn log_dir(&self) -> PathBuf {
+        self.dir.join("logs")
+    }
+
+    pub fn plot_dir(&self) -> PathBuf {
+        self.dir.join("plots")
+    }
+
+    pub fn export_dir(&self) -> PathBuf {
+        self.dir.join("exports")
+    }
+
+    /// `ModelCheckpoint` path pattern inside the checkpoint directory, e.g.
+    /// `checkpoint_path("epoch_{epoch}.ckpt")`
+    pub fn checkpoint_path(&self, pattern: &str) -> String {
+        self.checkpoint_dir()
+            .join(pattern)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn log_path(&self, file_name: &str) -> PathBuf {
+        self.log_dir().join(file_name)
+    }
+
+    pub fn plot_path(&self, file_name: &str) -> PathBuf {
+        self.plot_dir().join(file_name)
+    }
+
+    pub fn export_path(&self, file_name: &str) -> PathBuf {
+        self.export_dir().join(file_name)
+    }
+
+    /// Checkpoint callback saving `epoch_{epoch}.ckpt` files into the run,
+    /// tagged with the run's metadata
+    pub fn model_checkpoint(
+        &self,
+        monitor: &str,
+        save_best_only: bool,
+        mode: CheckpointMode,
+    ) -> ModelCheckpoint {
+        ModelCheckpoint::new(
+            self.checkpoint_path("epoch_{epoch}.ckpt"),
+            monitor.to_string(),
+            save_best_only,
+            false,
+            mode,
+        )
+        .with_run_metadata(self.record.metadata.clone())
+    }
+
+    /// Metric logger writing `metrics.csv` or `metrics.jsonl` into the
+    /// run's log directory
+    pub fn metric_logger(&self, format: LogFormat) -> MetricLogger {
+        let file_name = match format {
+            LogFormat::Csv => "metrics.csv",
+            LogFormat::Jsonl => "metrics.jsonl",
+        };
+        MetricLogger::new(self.log_path(file_name), format)
+    }
+
+    /// Stores the history in the run directory and records the last
+    /// recorded value of every metric in the index
+    pub fn finish(mut self, history: &TrainingHistory) -> Result<RunRecord, BellandeError> {
+        write_json(&self.dir.join(HISTORY_FILE), history)?;
+        self.record.final_metrics = history
+            .metrics
+            .iter()
+            .filter_map(|(name, values)| {
+                let last = values.iter().rev().flatten().next()?;
+                Some((name.clone(), *last))
+            })
+            .collect();
+        self.record.epochs = history.spilled_epochs + history.epochs.len();
+        self.record.finished = true;
+        self.save_record()?;
+        Ok(self.record)
+    }
+
+    /// Writes `run.json` and inserts or replaces the run in the index
+    fn save_record(&self) -> Result<(), BellandeError> {
+        write_json(&self.dir.join(RUN_FILE), &self.record)?;
+        let mut runs = read_index(&self.root)?;
+        match runs.iter_mut().find(|run| run.name == self.record.name) {
+            Some(run) => *run = self.record.clone(),
+            None => runs.push(self.record.clone()),
+        }
+        write_json(&self.root.join(INDEX_FILE), &runs)
+    }
+}
+
+fn read_index(root: &Path) -> Result<Vec<RunRecord>, BellandeError> {
+    match File::open(root.join(INDEX_FILE)) {
+        Ok(file) => serde_json::from_reader(file).map_err(|_| BellandeError::SerializationError),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(BellandeError::IOError(e)),
+    }
+}
+
+/// Writes through a temporary file so an interrupted write never leaves a
+/// truncated index behind
+fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<(), BellandeError> {
+    let tmp = path.with_extension("json.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp).map_err(BellandeError::IOError)?);
+    serde_json::to_writer_pretty(&mut writer, value)
+        .map_err(|_| BellandeError::SerializationError)?;
+    writer.flush().map_err(BellandeError::IOError)?;
+    fs::rename(&tmp, path).map_err(BellandeError::IOError)
+}
+
+/// Formats seconds since the Unix epoch as `YYYYMMDD-HHMMSS` in UTC
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,106 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, error::BellandeError};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where and how a training run was produced, stored in the training
+/// history and in checkpoint metadata so results stay attributable. Fields
+/// that cannot be determined on the current machine are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunMetadata {
+    pub crate_version: String,
+    /// Commit of the git repository in the working directory
+    pub git_hash: Option<String>,
+    /// Hash of the serialized run configuration, see `with_config`
+    pub config_hash: Option<String>,
+    pub hostname: Option<String>,
+    /// Operating system and architecture, e.g. `linux-x86_64`
+    pub platform: String,
+    pub cpu_model: Option<String>,
+    /// Logical CPUs available to the process
+    pub cpu_count: usize,
+    /// Threads in the global rayon pool used by data loading and optimizers
+    pub rayon_threads: usize,
+    pub cuda_devices: usize,
+    /// Seconds since the Unix epoch when the metadata was captured
+    pub start_time: u64,
+}
+
+impl RunMetadata {
+    /// Captures the metadata of the current process and machine
+    pub fn capture() -> Self {
+        RunMetadata {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: git_hash(),
+            config_hash: None,
+            hostname: hostname(),
+            platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            cpu_model: cpu_model(),
+            cpu_count: num_cpus::get(),
+            rayon_threads: rayon::current_num_threads(),
+            cuda_devices: Device::cuda_device_count(),
+            start_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+
+    /// Records a hash of `config`'s JSON serialization, so runs with the
+    /// same configuration can be matched
+    pub fn with_config<T: Serialize>(mut self, config: &T) -> Result<Self, BellandeError> {
+        let json = serde_json::to_vec(config).map_err(|_| BellandeError::SerializationError)?;
+        self.config_hash = Some(format!("{:016x}", fnv1a(&json)));
+        Ok(self)
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across Rust
+/// versions
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    let hash = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !hash.trim().is_empty()).then(|| hash.trim().to_string())
+}
+
+fn hostname() -> Option<String> {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// CPU model name from `/proc/cpuinfo`, where available
+fn cpu_model() -> Option<String> {
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+    cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "model name").then(|| value.trim().to_string())
+    })
+}
@@ -0,0 +1,205 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::data::{augmentation::Transform, dataloader::DataLoader};
+use crate::layer::activation::softmax;
+use crate::loss::{cross_entropy::CrossEntropyLoss, Reduction, WeightedLoss};
+use crate::models::models::Model;
+use crate::training::ema::ModelEma;
+
+/// FixMatch-style semi-supervised settings for
+/// `Trainer::set_semi_supervised` (Sohn et al., 2020). Every labeled batch
+/// is paired with a batch of the unlabeled loader, whose targets are
+/// ignored. Pseudo-labels are the argmax of the predictions on a weakly
+/// augmented view, kept when their probability reaches `threshold`; the
+/// model is then trained to predict them on a strongly augmented view with
+/// a cross-entropy weighted by `unlabeled_weight`. Predictions come from
+/// the model being trained, or from an EMA teacher when one is set.
+pub struct SemiSupervised {
+    unlabeled: DataLoader,
+    weak: Box<dyn Transform>,
+    strong: Box<dyn Transform>,
+    threshold: f32,
+    unlabeled_weight: f32,
+    teacher: Option<ModelEma>,
+    loss_fn: CrossEntropyLoss,
+}
+
+/// Outcome of one unlabeled batch
+pub(crate) struct UnlabeledStep {
+    /// Masked cross-entropy, averaged over the whole batch
+    pub loss: f32,
+    /// Fraction of the batch whose pseudo-label passed the threshold
+    pub mask_rate: f32,
+}
+
+impl SemiSupervised {
+    /// Pseudo-labels with a confidence threshold of 0.95 and an unlabeled
+    /// weight of 1, as in FixMatch
+    pub fn new(
+        unlabeled: DataLoader,
+        weak: Box<dyn Transform>,
+        strong: Box<dyn Transform>,
+    ) -> Result<Self, BellandeError> {
+        if unlabeled.dataset_len() == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Unlabeled dataset is empty".into(),
+            ));
+        }
+        Ok(SemiSupervised {
+            unlabeled,
+            weak,
+            strong,
+            threshold: 0.95,
+            unlabeled_weight: 1.0,
+            teacher: None,
+            loss_fn: CrossEntropyLoss::new(Reduction::Mean, None, None),
+        })
+    }
+
+    /// Minimum class probability for a pseudo-label to be used, in `[0, 1]`
+    pub fn with_threshold(mut self, threshold: f32) -> Result<Self, BellandeError> {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Confidence threshold must be in [0, 1], got {}",
+                threshold
+            )));
+        }
+        self.threshold = threshold;
+        Ok(self)
+    }
+
+    /// Weight of the unlabeled loss relative to the supervised loss
+    pub fn with_unlabeled_weight(mut self, weight: f32) -> Result<Self, BellandeError> {
+        if !(weight >= 0.0 && weight.is_finite()) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Unlabeled weight must be finite and non-negative, got {}",
+                weight
+            )));
+        }
+        self.unlabeled_weight = weight;
+        Ok(self)
+    }
+
+    /// Predicts pseudo-labels with `teacher`, updated from the trained
+    /// model after every optimizer step (Mean Teacher, Tarvainen and
+    /// Valpola, 2017)
+    pub fn with_teacher(mut self, teacher: ModelEma) -> Self {
+        self.teacher = Some(teacher);
+        self
+    }
+
+    pub fn unlabeled(&self) -> &DataLoader {
+        &self.unlabeled
+    }
+
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    pub fn unlabeled_weight(&self) -> f32 {
+        self.unlabeled_weight
+    }
+
+    pub fn teacher(&self) -> Option<&ModelEma> {
+        self.teacher.as_ref()
+    }
+
+    pub fn teacher_mut(&mut self) -> Option<&mut ModelEma> {
+        self.teacher.as_mut()
+    }
+
+    /// Pseudo-labels `batch` and accumulates the gradient of the unlabeled
+    /// loss, times `unlabeled_weight * scale`, into the parameters of
+    /// `model`. Skips the strong view when no pseudo-label passes the
+    /// threshold.
+    pub(crate) fn train_unlabeled(
+        &mut self,
+        model: &mut dyn Model,
+        batch: &Tensor,
+        scale: f32,
+    ) -> Result<UnlabeledStep, BellandeError> {
+        let weak = self.weak.apply(batch)?;
+        let logits = match &mut self.teacher {
+            Some(teacher) => {
+                // The teacher starts from the weights of the model
+                if teacher.updates() == 0 {
+                    teacher.update(model)?;
+                }
+                teacher.model_mut().forward(&weak)?
+            }
+            None => model.forward(&weak)?,
+        };
+        let probabilities = softmax(&logits, -1)?;
+        let num_classes = probabilities.shape[1].max(1);
+
+        let (labels, mask): (Vec<f32>, Vec<f32>) = probabilities
+            .data
+            .chunks(num_classes)
+            .map(|row| {
+                let (class, &confidence) = row
+                    .iter()
+                    .enumerate()
+                    .max_by(|a, b| a.1.total_cmp(b.1))
+                    .unwrap_or((0, &0.0));
+                let keep = if confidence >= self.threshold {
+                    1.0
+                } else {
+                    0.0
+                };
+                (class as f32, keep)
+            })
+            .unzip();
+        let batch_size = labels.len();
+        let mask_rate = mask.iter().sum::<f32>() / batch_size.max(1) as f32;
+        if mask_rate == 0.0 {
+            return Ok(UnlabeledStep {
+                loss: 0.0,
+                mask_rate,
+            });
+        }
+
+        let output = model.forward(&self.strong.apply(batch)?)?;
+        let tensor = |data| {
+            Tensor::new(
+                data,
+                vec![batch_size],
+                false,
+                output.device.clone(),
+                output.dtype,
+            )
+        };
+        let (labels, mask) = (tensor(labels), tensor(mask));
+        let loss = self.loss_fn.forward_weighted(&output, &labels, &mask)?;
+        let mut grad = self.loss_fn.backward_weighted(&output, &labels, &mask)?;
+        let factor = self.unlabeled_weight * scale;
+        grad.data.iter_mut().for_each(|g| *g *= factor);
+        model.backward(&grad)?;
+
+        Ok(UnlabeledStep {
+            loss: loss.data[0],
+            mask_rate,
+        })
+    }
+
+    /// Moves the EMA teacher, if any, towards the weights of `model`
+    pub(crate) fn update_teacher(&mut self, model: &dyn Model) -> Result<(), BellandeError> {
+        match &mut self.teacher {
+            Some(teacher) => teacher.update(model),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{error::BellandeError, tensor::Tensor};
+use crate::data::dataloader::DataLoader;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What the trainer does after aborting an epoch that ran past its limit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeoutAction {
+    /// Ends training, returning the history so far
+    Stop,
+    /// Runs the epoch again, at most `max_retries` times, then stops
+    Retry { max_retries: usize },
+}
+
+/// Wall-clock limit on the training phase of each epoch. An epoch that
+/// exceeds it, e.g. because a data read hangs, is aborted: the metrics of
+/// its finished batches are recorded with `partial = 1`, the model is
+/// optionally checkpointed, and training retries the epoch or stops.
+///
+/// Batches are loaded on a background thread, so a read that never
+/// returns is abandoned rather than waited for.
+#[derive(Clone, Debug)]
+pub struct EpochTimeLimit {
+    limit: Duration,
+    action: TimeoutAction,
+    checkpoint_path: Option<String>,
+}
+
+impl EpochTimeLimit {
+    /// Stops training after the first epoch that exceeds `limit`
+    pub fn new(limit: Duration) -> Result<Self, BellandeError> {
+        if limit.is_zero() {
+            return Err(BellandeError::InvalidParameter(
+                "Epoch time limit must be positive".into(),
+            ));
+        }
+        Ok(EpochTimeLimit {
+            limit,
+            action: TimeoutAction::Stop,
+            checkpoint_path: None,
+        })
+    }
+
+    /// Retries an aborted epoch up to `max_retries` times before stopping
+    pub fn with_retries(mut self, max_retries: usize) -> Self {
+        self.action = TimeoutAction::Retry { max_retries };
+        self
+    }
+
+    /// Saves the model to `path` after each aborted epoch; `{epoch}` in
+    /// the path is replaced by the epoch number
+    pub fn with_checkpoint(mut self, path: &str) -> Self {
+        self.checkpoint_path = Some(path.to_string());
+        self
+    }
+
+    pub fn limit(&self) -> Duration {
+        self.limit
+    }
+
+    pub fn action(&self) -> TimeoutAction {
+        self.action
+    }
+
+    /// Whether an epoch aborted on its `attempt`-th try (0 for the first)
+    /// is run again
+    pub(crate) fn retries(&self, attempt: usize) -> bool {
+        match self.action {
+            TimeoutAction::Stop => false,
+            TimeoutAction::Retry { max_retries } => attempt < max_retries,
+        }
+    }
+
+    /// Checkpoint path for `epoch`, if checkpointing is enabled
+    pub(crate) fn checkpoint_path(&self, epoch: usize) -> Option<String> {
+        self.checkpoint_path
+            .as_ref()
+            .map(|path| path.replace("{epoch}", &epoch.to_string()))
+    }
+}
+
+type Batch = (Tensor, Tensor, Option<Tensor>);
+
+/// Weighted batches of a `DataLoader`, loaded on a background thread and
+/// ending early once `deadline` passes. `timed_out` tells the two endings
+/// apart.
+pub(crate) struct TimedBatches {
+    receiver: Receiver<Batch>,
+    deadline: Instant,
+    timed_out: bool,
+}
+
+impl TimedBatches {
+    pub(crate) fn new(loader: &DataLoader, deadline: Instant) -> Self {
+        let loader = loader.clone();
+        // One batch of lookahead; the thread exits once the receiver is
+        // dropped, or stays blocked in a hung read without holding us up
+        let (sender, receiver) = mpsc::sync_channel(1);
+        thread::spawn(move || {
+            for batch in loader.iter_weighted() {
+                if sender.send(batch).is_err() {
+                    break;
+                }
+            }
+        });
+        TimedBatches {
+            receiver,
+            deadline,
+            timed_out: false,
+        }
+    }
+
+    pub(crate) fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+}
+
+impl Iterator for TimedBatches {
+    type Item = Batch;
+
+    fn next(&mut self) -> Option<Batch> {
+        if self.timed_out {
+            return None;
+        }
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            self.timed_out = true;
+            return None;
+        }
+        match self.receiver.recv_timeout(remaining) {
+            Ok(batch) => Some(batch),
+            Err(RecvTimeoutError::Timeout) => {
+                self.timed_out = true;
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
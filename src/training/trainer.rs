@@ -13,10 +13,19 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::core::{device::Device, error::BellandeError};
+use crate::core::{device::Device, error::BellandeError, numerics::numerics, tensor::Tensor};
 use crate::data::dataloader::DataLoader;
 use crate::models::models::Model;
-use crate::training::{callbacks::Callback, history::TrainingHistory, validator::CallbackEvent};
+use crate::training::{
+    adversarial::{evaluate_robustness, AdversarialTraining},
+    batch_size::BatchSizeScheduler,
+    callbacks::Callback,
+    history::TrainingHistory,
+    run_metadata::RunMetadata,
+    semi_supervised::SemiSupervised,
+    time_limit::{EpochTimeLimit, TimedBatches},
+    validator::CallbackEvent,
+};
 
 // Import all loss functions
 use crate::loss::{
@@ -24,9 +33,14 @@ use crate::loss::{
 };
 
 // Import all optimizers and scheduler
-use crate::optim::{adam::Adam, rmsprop::RMSprop, scheduler::LRScheduler, sgd::SGD, Optimizer};
+use crate::optim::{
+    adam::Adam, lion::Lion, radam::RAdam, rmsprop::RMSprop, scheduler::LRScheduler, sgd::SGD,
+    Optimizer,
+};
 
 use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
 
 /// Helper struct for tracking metrics during training
 #[derive(Default)]
@@ -67,6 +81,12 @@ pub struct Trainer {
     callbacks: Vec<Box<dyn Callback>>,
     history: TrainingHistory,
     scheduler: Option<Box<dyn LRScheduler>>,
+    accumulation_steps: usize,
+    batch_size_scheduler: Option<BatchSizeScheduler>,
+    adversarial: Option<AdversarialTraining>,
+    semi_supervised: Option<SemiSupervised>,
+    epoch_time_limit: Option<EpochTimeLimit>,
+    verbose: bool,
 }
 
 impl Trainer {
@@ -84,6 +104,12 @@ impl Trainer {
             callbacks: Vec::new(),
             history: TrainingHistory::new(),
             scheduler: None,
+            accumulation_steps: 1,
+            batch_size_scheduler: None,
+            adversarial: None,
+            semi_supervised: None,
+            epoch_time_limit: None,
+            verbose: true,
         }
     }
 
@@ -93,8 +119,14 @@ impl Trainer {
         learning_rate: f32,
         device: Device,
     ) -> Result<Self, BellandeError> {
-        let loss_fn = Box::new(MSELoss::new());
-        let optimizer = Box::new(Adam::new(model.parameters(), learning_rate)?);
+        let loss_fn = Box::new(MSELoss::default());
+        let optimizer = Box::new(Adam::new(
+            model.parameters(),
+            learning_rate,
+            (0.9, 0.999),
+            numerics().optimizer_eps,
+            0.0,
+        ));
 
         Ok(Self::new(model, optimizer, loss_fn, device))
     }
@@ -106,7 +138,7 @@ impl Trainer {
         momentum: f32,
         device: Device,
     ) -> Result<Self, BellandeError> {
-        let loss_fn = Box::new(CrossEntropyLoss::new());
+        let loss_fn = Box::new(CrossEntropyLoss::default());
         let optimizer = Box::new(SGD::new(model.parameters(), learning_rate, momentum)?);
 
         Ok(Self::new(model, optimizer, loss_fn, device))
@@ -119,21 +151,128 @@ impl Trainer {
         alpha: f32,
         device: Device,
     ) -> Result<Self, BellandeError> {
-        let loss_fn = Box::new(BCELoss::new());
+        let loss_fn = Box::new(BCELoss::default());
         let optimizer = Box::new(RMSprop::new(model.parameters(), learning_rate, alpha)?);
 
         Ok(Self::new(model, optimizer, loss_fn, device))
     }
 
+    /// Create a new trainer with MSELoss and Lion optimizer
+    pub fn new_with_lion(
+        model: Box<dyn Model>,
+        learning_rate: f32,
+        weight_decay: f32,
+        device: Device,
+    ) -> Result<Self, BellandeError> {
+        let loss_fn = Box::new(MSELoss::default());
+        let optimizer = Box::new(Lion::new(model.parameters(), learning_rate, weight_decay)?);
+
+        Ok(Self::new(model, optimizer, loss_fn, device))
+    }
+
+    /// Create a new trainer with MSELoss and RAdam optimizer
+    pub fn new_with_radam(
+        model: Box<dyn Model>,
+        learning_rate: f32,
+        weight_decay: f32,
+        device: Device,
+    ) -> Result<Self, BellandeError> {
+        let loss_fn = Box::new(MSELoss::default());
+        let optimizer = Box::new(RAdam::new(model.parameters(), learning_rate, weight_decay)?);
+
+        Ok(Self::new(model, optimizer, loss_fn, device))
+    }
+
     /// Add a learning rate scheduler
     pub fn add_scheduler(&mut self, scheduler: Box<dyn LRScheduler>) {
         self.scheduler = Some(scheduler);
     }
 
+    /// Accumulates gradients over `steps` batches before each optimizer
+    /// step, multiplying the effective batch size by `steps`
+    pub fn set_accumulation_steps(&mut self, steps: usize) -> Result<(), BellandeError> {
+        if steps == 0 {
+            return Err(BellandeError::InvalidParameter(
+                "Accumulation steps must be positive".into(),
+            ));
+        }
+        self.accumulation_steps = steps;
+        Ok(())
+    }
+
+    /// Chooses the accumulation steps of every epoch, overriding
+    /// `set_accumulation_steps`
+    pub fn set_batch_size_scheduler(&mut self, scheduler: BatchSizeScheduler) {
+        self.batch_size_scheduler = Some(scheduler);
+    }
+
+    /// Trains every batch on both its clean and adversarial version, and
+    /// logs the clean and robust validation accuracy
+    pub fn set_adversarial_training(&mut self, adversarial: AdversarialTraining) {
+        self.adversarial = Some(adversarial);
+    }
+
+    /// Pairs every labeled batch with a pseudo-labeled unlabeled batch, and
+    /// logs the supervised and unlabeled losses and the fraction of
+    /// pseudo-labels above the confidence threshold
+    pub fn set_semi_supervised(&mut self, semi_supervised: SemiSupervised) {
+        self.semi_supervised = Some(semi_supervised);
+    }
+
+    pub fn semi_supervised(&self) -> Option<&SemiSupervised> {
+        self.semi_supervised.as_ref()
+    }
+
+    /// Aborts the training phase of any epoch that runs longer than the
+    /// limit, see `EpochTimeLimit`. Every epoch then logs `partial`, which
+    /// is 1 for aborted attempts and 0 otherwise.
+    pub fn set_epoch_time_limit(&mut self, time_limit: EpochTimeLimit) {
+        self.epoch_time_limit = Some(time_limit);
+    }
+
+    /// Metadata stored in the history of the next `fit`, e.g. one built
+    /// with `RunMetadata::with_config`. Without it, `fit` captures the
+    /// metadata of the current machine.
+    pub fn set_run_metadata(&mut self, metadata: RunMetadata) {
+        self.history.run_metadata = Some(metadata);
+    }
+
     pub fn add_callback(&mut self, callback: Box<dyn Callback>) {
         self.callbacks.push(callback);
     }
 
+    /// Enables or disables the end-of-epoch console summary
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// Keeps only the last `capacity` epochs of history in memory and
+    /// appends older ones to a JSON Lines file, for very long runs. Use
+    /// `TrainingHistory::full_history` to read everything back.
+    pub fn set_history_limit(
+        &mut self,
+        capacity: usize,
+        spill_path: impl AsRef<Path>,
+    ) -> Result<(), BellandeError> {
+        let run_metadata = self.history.run_metadata.take();
+        self.history = TrainingHistory::new().with_spill(capacity, spill_path)?;
+        self.history.run_metadata = run_metadata;
+        Ok(())
+    }
+
+    pub fn model(&self) -> &dyn Model {
+        self.model.as_ref()
+    }
+
+    pub fn model_mut(&mut self) -> &mut dyn Model {
+        self.model.as_mut()
+    }
+
+    /// Consumes the trainer, returning the trained model
+    pub fn into_model(self) -> Box<dyn Model> {
+        self.model
+    }
+
     pub fn fit(
         &mut self,
         train_loader: DataLoader,
@@ -141,22 +280,97 @@ impl Trainer {
         epochs: usize,
     ) -> Result<TrainingHistory, BellandeError> {
         let mut logs = HashMap::new();
+        if self.optimizer.requires_per_sample_grads() {
+            self.model.set_per_sample_grads(true)?;
+        }
+        if self.history.run_metadata.is_none() {
+            self.history.run_metadata = Some(RunMetadata::capture());
+        }
         self.call_callbacks(CallbackEvent::TrainBegin, &logs)?;
 
         for epoch in 0..epochs {
+            let epoch_start = Instant::now();
             logs.clear();
             logs.insert("epoch".to_string(), epoch as f32);
             self.call_callbacks(CallbackEvent::EpochBegin, &logs)?;
+            for callback in &mut self.callbacks {
+                callback.on_epoch_begin_train_state(
+                    epoch,
+                    self.model.as_mut(),
+                    self.optimizer.as_mut(),
+                )?;
+            }
 
             // Training phase
+            let accumulation_steps = self
+                .batch_size_scheduler
+                .as_ref()
+                .map_or(self.accumulation_steps, |scheduler| {
+                    scheduler.accumulation_steps(epoch)
+                });
+            if accumulation_steps > 1 && self.optimizer.requires_per_sample_grads() {
+                return Err(BellandeError::InvalidConfiguration(
+                    "Gradient accumulation is not supported with per-sample gradients".into(),
+                ));
+            }
+            if self.adversarial.is_some() && self.optimizer.requires_per_sample_grads() {
+                return Err(BellandeError::InvalidConfiguration(
+                    "Adversarial training is not supported with per-sample gradients".into(),
+                ));
+            }
+            if self.semi_supervised.is_some() && self.optimizer.requires_per_sample_grads() {
+                return Err(BellandeError::InvalidConfiguration(
+                    "Semi-supervised training is not supported with per-sample gradients".into(),
+                ));
+            }
             self.model.train();
-            let train_metrics = self.train_epoch(train_loader.clone(), epoch)?;
+            let mut attempt = 0;
+            let train_metrics = loop {
+                let deadline = self
+                    .epoch_time_limit
+                    .as_ref()
+                    .map(|time_limit| Instant::now() + time_limit.limit());
+                let (train_metrics, timed_out) =
+                    self.train_epoch(&train_loader, accumulation_steps, deadline)?;
+                if !timed_out {
+                    break Some(train_metrics);
+                }
+                self.record_partial_epoch(epoch, attempt, train_metrics, epoch_start)?;
+                match &self.epoch_time_limit {
+                    Some(time_limit) if time_limit.retries(attempt) => attempt += 1,
+                    _ => break None,
+                }
+            };
+            let Some(train_metrics) = train_metrics else {
+                break;
+            };
             logs.extend(train_metrics);
+            if self.epoch_time_limit.is_some() {
+                logs.insert("partial".to_string(), 0.0);
+                logs.insert("attempt".to_string(), attempt as f32);
+            }
+            logs.insert("accumulation_steps".to_string(), accumulation_steps as f32);
+            logs.insert(
+                "effective_batch_size".to_string(),
+                (train_loader.batch_size() * accumulation_steps) as f32,
+            );
+            logs.extend(self.param_group_learning_rates());
+            logs.extend(self.optimizer.metrics());
 
             // Validation phase
             if let Some(val_loader) = &val_loader {
                 self.model.eval();
-                let val_metrics = self.validate(val_loader.clone())?;
+                let mut val_metrics = self.validate(val_loader.clone())?;
+                if let Some(adversarial) = &self.adversarial {
+                    let report = evaluate_robustness(
+                        self.model.as_mut(),
+                        self.loss_fn.as_ref(),
+                        val_loader,
+                        adversarial.attack(),
+                    )?;
+                    val_metrics.insert("accuracy".to_string(), report.clean_accuracy);
+                    val_metrics.insert("robust_accuracy".to_string(), report.robust_accuracy);
+                }
                 logs.extend(
                     val_metrics
                         .into_iter()
@@ -170,44 +384,254 @@ impl Trainer {
             }
 
             self.history.update(epoch, logs.clone());
+            self.history.record_duration(epoch_start.elapsed());
+            self.history.spill_excess()?;
+            if self.verbose {
+                if let Some(summary) = self.history.last_epoch_summary() {
+                    println!("{}", summary);
+                }
+            }
             self.call_callbacks(CallbackEvent::EpochEnd, &logs)?;
+            for callback in &mut self.callbacks {
+                callback.on_epoch_end_train_state(epoch, &logs, self.model.as_ref())?;
+            }
         }
 
         self.call_callbacks(CallbackEvent::TrainEnd, &logs)?;
         Ok(self.history.clone())
     }
 
+    /// Records the metrics of the finished batches of an epoch aborted by
+    /// the time limit, marked `partial`, and checkpoints the model. The
+    /// validation metrics it lacks are recorded as NaN, keeping every metric
+    /// series aligned with the epochs.
+    /// Epoch-end callbacks are not called, as the epoch did not end.
+    fn record_partial_epoch(
+        &mut self,
+        epoch: usize,
+        attempt: usize,
+        train_metrics: HashMap<String, f32>,
+        epoch_start: Instant,
+    ) -> Result<(), BellandeError> {
+        let mut logs = train_metrics;
+        logs.insert("epoch".to_string(), epoch as f32);
+        logs.insert("partial".to_string(), 1.0);
+        logs.insert("attempt".to_string(), attempt as f32);
+        self.history.update(epoch, logs);
+        self.history.record_duration(epoch_start.elapsed());
+        self.history.spill_excess()?;
+        if self.verbose {
+            if let Some(summary) = self.history.last_epoch_summary() {
+                println!("{} (aborted: time limit exceeded)", summary);
+            }
+        }
+        let checkpoint_path = self
+            .epoch_time_limit
+            .as_ref()
+            .and_then(|time_limit| time_limit.checkpoint_path(epoch));
+        if let Some(path) = checkpoint_path {
+            self.model.save(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Runs one epoch, stepping the optimizer once per window of
+    /// `accumulation_steps` batches. Each batch gradient is divided by the
+    /// window length, so a window matches one batch of the combined size.
+    /// With a `deadline`, no batch is started after it passes; the returned
+    /// flag tells whether the epoch was cut short that way.
     fn train_epoch(
         &mut self,
-        train_loader: DataLoader,
-        _epoch: usize,
-    ) -> Result<HashMap<String, f32>, BellandeError> {
+        train_loader: &DataLoader,
+        accumulation_steps: usize,
+        deadline: Option<Instant>,
+    ) -> Result<(HashMap<String, f32>, bool), BellandeError> {
         let mut metrics = RunningMetrics::new();
+        let (mut timed, mut plain) = (None, None);
+        let batches: &mut dyn Iterator<Item = _> = match deadline {
+            Some(deadline) => timed.insert(TimedBatches::new(train_loader, deadline)),
+            None => plain.insert(train_loader.iter_weighted()),
+        };
+        // Unlabeled batches restart whenever the unlabeled loader runs out
+        let unlabeled_loader = self
+            .semi_supervised
+            .as_ref()
+            .map(|semi_supervised| semi_supervised.unlabeled().clone());
+        let mut unlabeled_batches = unlabeled_loader
+            .iter()
+            .flat_map(|loader| std::iter::repeat_with(move || loader.iter()).flatten());
+
+        loop {
+            let window: Vec<_> = (&mut *batches)
+                .take(accumulation_steps)
+                .map(|(data, target, weights)| {
+                    (
+                        data.to(self.device.clone()),
+                        target.to(self.device.clone()),
+                        weights.map(|w| w.to(self.device.clone())),
+                    )
+                })
+                .collect();
+            if window.is_empty() {
+                break;
+            }
+            let scale = 1.0 / window.len() as f32;
+
+            // Attacks run before zeroing, as their backward passes also
+            // reach the parameter gradients
+            let mut adversarial_inputs = Vec::with_capacity(window.len());
+            if let Some(adversarial) = &self.adversarial {
+                for (data, target, _) in &window {
+                    adversarial_inputs.push(adversarial.attack().generate(
+                        self.model.as_mut(),
+                        self.loss_fn.as_ref(),
+                        data,
+                        target,
+                    )?);
+                }
+            }
+            self.optimizer.zero_grad();
 
-        for (_batch_idx, (data, target)) in train_loader.enumerate() {
-            let batch_logs = HashMap::new();
-            self.call_callbacks(CallbackEvent::BatchBegin, &batch_logs)?;
+            for (index, (data, target, weights)) in window.into_iter().enumerate() {
+                let batch_logs = HashMap::new();
+                self.call_callbacks(CallbackEvent::BatchBegin, &batch_logs)?;
+
+                let loss = match (&self.adversarial, adversarial_inputs.get(index)) {
+                    (Some(adversarial), Some(adversarial_data)) => {
+                        let weight = adversarial.adversarial_weight();
+                        let mut loss = 0.0;
+                        if weight < 1.0 {
+                            let clean_loss = self.train_batch(
+                                &data,
+                                &target,
+                                weights.as_ref(),
+                                scale * (1.0 - weight),
+                            )?;
+                            metrics.update("clean_loss", clean_loss);
+                            loss += (1.0 - weight) * clean_loss;
+                        }
+                        if weight > 0.0 {
+                            let adversarial_loss = self.train_batch(
+                                adversarial_data,
+                                &target,
+                                weights.as_ref(),
+                                scale * weight,
+                            )?;
+                            metrics.update("adversarial_loss", adversarial_loss);
+                            loss += weight * adversarial_loss;
+                        }
+                        loss
+                    }
+                    _ => self.train_batch(&data, &target, weights.as_ref(), scale)?,
+                };
+                for (name, value) in self.loss_fn.metrics() {
+                    metrics.update(&name, value);
+                }
 
-            // Forward pass
-            let data = data.to(self.device.clone());
-            let target = target.to(self.device.clone());
-            let output = self.model.forward(&data)?;
-            let loss = self.loss_fn.forward(&output, &target)?;
+                let loss = match (&mut self.semi_supervised, unlabeled_batches.next()) {
+                    (Some(semi_supervised), Some((unlabeled, _))) => {
+                        let step = semi_supervised.train_unlabeled(
+                            self.model.as_mut(),
+                            &unlabeled.to(self.device.clone()),
+                            scale,
+                        )?;
+                        metrics.update("supervised_loss", loss);
+                        metrics.update("unlabeled_loss", step.loss);
+                        metrics.update("pseudo_label_rate", step.mask_rate);
+                        loss + semi_supervised.unlabeled_weight() * step.loss
+                    }
+                    _ => loss,
+                };
+
+                // Update metrics
+                metrics.update("loss", loss);
+
+                let batch_logs = metrics.get_current();
+                self.call_callbacks(CallbackEvent::BatchEnd, &batch_logs)?;
+            }
 
-            // Backward pass
-            self.optimizer.zero_grad();
-            let grad = self.loss_fn.backward(&output, &target)?;
-            output.backward_with_grad(&grad)?;
-            self.optimizer.step()?;
+            // Per-group gradient norms, so staged fine-tuning can be verified
+            let groups = self.optimizer.get_param_groups();
+            if groups.len() > 1 {
+                for (index, group) in groups.iter().enumerate() {
+                    metrics.update(
+                        &format!("grad_norm/{}", group.display_name(index)),
+                        group.grad_norm(),
+                    );
+                }
+            }
 
-            // Update metrics
-            metrics.update("loss", loss.data()[0]);
+            self.step_optimizer()?;
+            if let Some(semi_supervised) = &mut self.semi_supervised {
+                semi_supervised.update_teacher(self.model.as_ref())?;
+            }
+        }
+
+        let timed_out = timed.as_ref().is_some_and(TimedBatches::timed_out);
+        Ok((metrics.get_average(), timed_out))
+    }
+
+    /// Steps the optimizer and copies the updated values into the model,
+    /// since the optimizer holds clones of the model's parameters
+    fn step_optimizer(&mut self) -> Result<(), BellandeError> {
+        self.optimizer.step()?;
+        let params: Vec<&Tensor> = self
+            .optimizer
+            .get_param_groups()
+            .iter()
+            .flat_map(|group| group.params.iter())
+            .collect();
+        self.model.write_back_parameters(&params)
+    }
 
-            let batch_logs = metrics.get_current();
-            self.call_callbacks(CallbackEvent::BatchEnd, &batch_logs)?;
+    /// Forward and backward pass of one batch with its loss gradient
+    /// multiplied by `scale`, accumulating into the parameter gradients.
+    /// Sample weights are applied when the loss is a `WeightedLoss`.
+    /// Returns the unscaled loss.
+    fn train_batch(
+        &mut self,
+        data: &Tensor,
+        target: &Tensor,
+        weights: Option<&Tensor>,
+        scale: f32,
+    ) -> Result<f32, BellandeError> {
+        let output = self.model.forward(data)?;
+        let (loss, mut grad) = match (weights, self.loss_fn.as_weighted()) {
+            (Some(weights), Some(loss_fn)) => (
+                loss_fn.forward_weighted(&output, target, weights)?,
+                loss_fn.backward_weighted(&output, target, weights)?,
+            ),
+            _ => (
+                self.loss_fn.forward(&output, target)?,
+                self.loss_fn.backward(&output, target)?,
+            ),
+        };
+        if scale != 1.0 {
+            grad.data.iter_mut().for_each(|g| *g *= scale);
+        }
+        // Layers return leaf outputs, so gradients reach the parameters
+        // through the model's layer-wise backward rather than autograd
+        self.model.backward(&grad)?;
+        if self.optimizer.requires_per_sample_grads() {
+            self.optimizer
+                .set_per_sample_grads(self.model.per_sample_grads());
         }
+        Ok(loss.data()[0])
+    }
 
-        Ok(metrics.get_average())
+    /// Learning rate of each parameter group, keyed `lr/<group>`, when the
+    /// optimizer has more than one group
+    fn param_group_learning_rates(&self) -> HashMap<String, f32> {
+        let groups = self.optimizer.get_param_groups();
+        if groups.len() <= 1 {
+            return HashMap::new();
+        }
+
+        groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| (format!("lr/{}", group.display_name(index)), group.lr))
+            .collect()
     }
 
     fn validate(&mut self, val_loader: DataLoader) -> Result<HashMap<String, f32>, BellandeError> {
@@ -219,6 +643,9 @@ impl Trainer {
             let output = self.model.forward(&data)?;
             let loss = self.loss_fn.forward(&output, &target)?;
             metrics.update("loss", loss.data()[0]);
+            for (name, value) in self.loss_fn.metrics() {
+                metrics.update(&name, value);
+            }
         }
 
         Ok(metrics.get_average())
@@ -246,3 +673,43 @@ impl Trainer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::dtype::DataType;
+    use crate::layer::linear::Linear;
+    use crate::models::sequential::Sequential;
+
+    #[test]
+    fn train_batch_updates_model_weights() {
+        let mut model = Sequential::new();
+        model.add(Box::new(Linear::new(3, 2, true)));
+        let mut trainer = Trainer::new_with_adam(Box::new(model), 0.1, Device::CPU).unwrap();
+        let before = trainer.model.state_dict();
+
+        let data = Tensor::new(
+            vec![1.0, -2.0, 0.5, 0.3, 0.8, -1.0],
+            vec![2, 3],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        );
+        let target = Tensor::new(
+            vec![1.0, 0.0, 0.0, 1.0],
+            vec![2, 2],
+            false,
+            Device::CPU,
+            DataType::Float32,
+        );
+        trainer.optimizer.zero_grad();
+        trainer.train_batch(&data, &target, None, 1.0).unwrap();
+        trainer.step_optimizer().unwrap();
+
+        let after = trainer.model.state_dict();
+        let changed = before
+            .iter()
+            .any(|(name, tensor)| after[name].data != tensor.data);
+        assert!(changed, "no model weight changed after a training step");
+    }
+}
@@ -16,6 +16,7 @@
 use crate::core::{device::Device, error::BellandeError};
 
 use crate::data::dataloader::DataLoader;
+use crate::inference::tta::{self, TTAConfig};
 use crate::metrics::metrics::Metric;
 use crate::models::models::Model;
 use std::collections::HashMap;
@@ -24,6 +25,7 @@ pub struct Validator {
     model: Box<dyn Model>,
     metrics: Vec<Box<dyn Metric>>,
     device: Device,
+    tta: Option<TTAConfig>,
 }
 
 impl Validator {
@@ -32,9 +34,17 @@ impl Validator {
             model,
             metrics,
             device,
+            tta: None,
         }
     }
 
+    /// Evaluates with test-time augmentation; metrics then see averaged
+    /// class probabilities instead of raw model outputs
+    pub fn with_tta(mut self, config: TTAConfig) -> Self {
+        self.tta = Some(config);
+        self
+    }
+
     pub fn validate(
         &mut self,
         val_loader: DataLoader,
@@ -43,7 +53,12 @@ impl Validator {
         let mut metrics = RunningMetrics::new();
 
         for (data, target) in val_loader {
-            let output = self.model.forward(&data.to(self.device))?;
+            let output = match &self.tta {
+                Some(config) => {
+                    tta::predict_tta(self.model.as_mut(), &data.to(self.device), config)?
+                }
+                None => self.model.forward(&data.to(self.device))?,
+            };
 
             for metric in &mut self.metrics {
                 let value = metric.compute(&output, &target.to(self.device))?;
@@ -0,0 +1,156 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::{device::Device, error::BellandeError};
+use crate::data::preprocessing::Preprocessor;
+use crate::inference::batch::{write_predictions_csv, FolderPredictionConfig};
+use crate::inference::predictor::Predictor;
+use crate::models::models::Model;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: bellande [--config <config.yaml>] <command> [options]
+
+Commands:
+  predict-folder   Run a trained model over every image in a directory tree
+
+predict-folder options:
+  --input <dir>          Directory to scan recursively for jpg/png images
+  --weights <file>       Model weights saved with Model::save
+  --output <file>        CSV destination (defaults to stdout)
+  --classes <file>       Class names, one per line, in class index order
+  --top-k <n>            Number of classes to report per image (default 5)
+  --image-size <n>       Square input resolution (default 224)
+  --batch-size <n>       Images per forward pass (default 32)
+  --skip-errors          Skip images that fail to decode
+";
+
+/// Parsed arguments of the `predict-folder` command
+#[derive(Clone, Debug)]
+pub struct PredictFolderArgs {
+    pub input: PathBuf,
+    pub weights: PathBuf,
+    pub output: Option<PathBuf>,
+    pub classes: Option<PathBuf>,
+    pub top_k: usize,
+    pub image_size: usize,
+    pub batch_size: usize,
+    pub skip_errors: bool,
+}
+
+impl PredictFolderArgs {
+    pub fn parse(args: &[String]) -> Result<Self, BellandeError> {
+        let mut input = None;
+        let mut weights = None;
+        let mut parsed = PredictFolderArgs {
+            input: PathBuf::new(),
+            weights: PathBuf::new(),
+            output: None,
+            classes: None,
+            top_k: 5,
+            image_size: 224,
+            batch_size: 32,
+            skip_errors: false,
+        };
+
+        let mut iter = args.iter();
+        while let Some(flag) = iter.next() {
+            match flag.as_str() {
+                "--input" => input = Some(PathBuf::from(value(flag, iter.next())?)),
+                "--weights" => weights = Some(PathBuf::from(value(flag, iter.next())?)),
+                "--output" => parsed.output = Some(PathBuf::from(value(flag, iter.next())?)),
+                "--classes" => parsed.classes = Some(PathBuf::from(value(flag, iter.next())?)),
+                "--top-k" => parsed.top_k = number(flag, iter.next())?,
+                "--image-size" => parsed.image_size = number(flag, iter.next())?,
+                "--batch-size" => parsed.batch_size = number(flag, iter.next())?,
+                "--skip-errors" => parsed.skip_errors = true,
+                other => {
+                    return Err(BellandeError::InvalidParameter(format!(
+                        "Unknown option '{}'",
+                        other
+                    )))
+                }
+            }
+        }
+
+        parsed.input =
+            input.ok_or_else(|| BellandeError::InvalidParameter("--input is required".into()))?;
+        parsed.weights = weights
+            .ok_or_else(|| BellandeError::InvalidParameter("--weights is required".into()))?;
+        Ok(parsed)
+    }
+}
+
+/// Runs `predict-folder` with an already constructed model and returns the
+/// number of images written
+pub fn predict_folder(
+    mut model: Box<dyn Model>,
+    device: Device,
+    preprocessors: Vec<Box<dyn Preprocessor>>,
+    args: &PredictFolderArgs,
+) -> Result<usize, BellandeError> {
+    model.load(&args.weights.to_string_lossy())?;
+
+    let class_names = match args.classes {
+        Some(ref path) => Some(read_class_names(path)?),
+        None => None,
+    };
+
+    let mut config = FolderPredictionConfig::new((args.image_size, args.image_size))
+        .with_batch_size(args.batch_size)
+        .with_top_k(args.top_k)
+        .with_skip_errors(args.skip_errors);
+    for preprocessor in preprocessors {
+        config = config.with_preprocessor(preprocessor);
+    }
+
+    let mut predictor = Predictor::new(model, device);
+    let predictions = predictor.predict_folder(&args.input, &config)?;
+
+    let mut writer: Box<dyn Write> = match args.output {
+        Some(ref path) => Box::new(BufWriter::new(
+            File::create(path).map_err(BellandeError::IOError)?,
+        )),
+        None => Box::new(io::stdout().lock()),
+    };
+    write_predictions_csv(&mut writer, &predictions, class_names.as_deref())?;
+    writer.flush().map_err(BellandeError::IOError)?;
+
+    Ok(predictions.len())
+}
+
+fn read_class_names(path: &PathBuf) -> Result<Vec<String>, BellandeError> {
+    let content = fs::read_to_string(path).map_err(BellandeError::IOError)?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+fn value<'a>(flag: &str, value: Option<&'a String>) -> Result<&'a str, BellandeError> {
+    value
+        .map(String::as_str)
+        .ok_or_else(|| BellandeError::InvalidParameter(format!("{} expects a value", flag)))
+}
+
+fn number(flag: &str, raw: Option<&String>) -> Result<usize, BellandeError> {
+    value(flag, raw)?.parse().map_err(|_| {
+        BellandeError::InvalidParameter(format!("{} expects a non-negative integer", flag))
+    })
+}
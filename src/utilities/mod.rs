@@ -1,4 +1,6 @@
+pub mod cli;
 pub mod config;
+pub mod npz;
 pub mod profiler;
 pub mod progress;
 pub mod visualization;
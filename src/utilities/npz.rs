@@ -0,0 +1,215 @@
+// Copyright (C) 2024 Bellande Artificial Intelligence Computer Vision Research Innovation Center, Ronaldson Bellande
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::core::error::BellandeError;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Writer for NumPy `.npz` archives (uncompressed zip of `.npy` arrays)
+pub struct NpzWriter {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl NpzWriter {
+    pub fn new() -> Self {
+        NpzWriter {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Adds a little-endian float32 array
+    pub fn add_f32(
+        &mut self,
+        name: &str,
+        shape: &[usize],
+        data: &[f32],
+    ) -> Result<(), BellandeError> {
+        check_len(name, shape, data.len())?;
+        let mut bytes = npy_header("<f4", shape);
+        for value in data {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.push(name, bytes)
+    }
+
+    /// Adds a little-endian int64 array
+    pub fn add_i64(
+        &mut self,
+        name: &str,
+        shape: &[usize],
+        data: &[i64],
+    ) -> Result<(), BellandeError> {
+        check_len(name, shape, data.len())?;
+        let mut bytes = npy_header("<i8", shape);
+        for value in data {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.push(name, bytes)
+    }
+
+    /// Adds a 1D array of strings as a fixed-width unicode (`<U`) array
+    pub fn add_strings(&mut self, name: &str, values: &[String]) -> Result<(), BellandeError> {
+        let width = values
+            .iter()
+            .map(|value| value.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+
+        let mut bytes = npy_header(&format!("<U{}", width), &[values.len()]);
+        for value in values {
+            let mut count = 0;
+            for c in value.chars() {
+                bytes.extend_from_slice(&(c as u32).to_le_bytes());
+                count += 1;
+            }
+            bytes.resize(bytes.len() + (width - count) * 4, 0);
+        }
+        self.push(name, bytes)
+    }
+
+    /// Writes all arrays to `path`
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), BellandeError> {
+        let file = File::create(path).map_err(BellandeError::IOError)?;
+        let mut writer = BufWriter::new(file);
+        let mut central_directory = Vec::new();
+        let mut offset: u32 = 0;
+
+        for (name, data) in &self.entries {
+            let file_name = format!("{}.npy", name);
+            let crc = crc32(data);
+            let size = u32::try_from(data.len()).map_err(|_| {
+                BellandeError::InvalidParameter(format!("Array '{}' exceeds 4 GiB", name))
+            })?;
+
+            let mut local = Vec::with_capacity(30 + file_name.len());
+            local.extend_from_slice(&0x04034b50u32.to_le_bytes());
+            push_entry_fields(&mut local, crc, size, file_name.len());
+            local.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+            local.extend_from_slice(file_name.as_bytes());
+
+            central_directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            central_directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            push_entry_fields(&mut central_directory, crc, size, file_name.len());
+            central_directory.extend_from_slice(&[0u8; 8]); // extra, comment, disk, internal attrs
+            central_directory.extend_from_slice(&0u32.to_le_bytes()); // external attrs
+            central_directory.extend_from_slice(&offset.to_le_bytes());
+            central_directory.extend_from_slice(file_name.as_bytes());
+
+            writer.write_all(&local).map_err(BellandeError::IOError)?;
+            writer.write_all(data).map_err(BellandeError::IOError)?;
+            offset = offset
+                .checked_add(local.len() as u32 + size)
+                .ok_or_else(|| BellandeError::InvalidParameter("Archive exceeds 4 GiB".into()))?;
+        }
+
+        let count = self.entries.len() as u16;
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        end.extend_from_slice(&[0u8; 4]); // disk numbers
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+
+        writer
+            .write_all(&central_directory)
+            .map_err(BellandeError::IOError)?;
+        writer.write_all(&end).map_err(BellandeError::IOError)?;
+        writer.flush().map_err(BellandeError::IOError)
+    }
+
+    fn push(&mut self, name: &str, bytes: Vec<u8>) -> Result<(), BellandeError> {
+        if self.entries.iter().any(|(existing, _)| existing == name) {
+            return Err(BellandeError::InvalidParameter(format!(
+                "Duplicate array name '{}'",
+                name
+            )));
+        }
+        self.entries.push((name.to_string(), bytes));
+        Ok(())
+    }
+}
+
+impl Default for NpzWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn check_len(name: &str, shape: &[usize], len: usize) -> Result<(), BellandeError> {
+    if shape.iter().product::<usize>() != len {
+        return Err(BellandeError::ShapeMismatch(format!(
+            "Array '{}' has {} elements but shape {:?}",
+            name, len, shape
+        )));
+    }
+    Ok(())
+}
+
+/// Builds a version 1.0 `.npy` header padded to a multiple of 64 bytes
+fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape = match shape.len() {
+        1 => format!("({},)", shape[0]),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    let unpadded = 10 + dict.len() + 1;
+    dict.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    dict.push('\n');
+
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header
+}
+
+/// Fields shared by zip local and central headers, from "version needed"
+/// through "file name length"
+fn push_entry_fields(buffer: &mut Vec<u8>, crc: u32, size: u32, name_len: usize) {
+    buffer.extend_from_slice(&20u16.to_le_bytes()); // version needed
+    buffer.extend_from_slice(&0u16.to_le_bytes()); // flags
+    buffer.extend_from_slice(&0u16.to_le_bytes()); // stored, no compression
+    buffer.extend_from_slice(&0u16.to_le_bytes()); // modification time
+    buffer.extend_from_slice(&0x21u16.to_le_bytes()); // modification date, 1980-01-01
+    buffer.extend_from_slice(&crc.to_le_bytes());
+    buffer.extend_from_slice(&size.to_le_bytes());
+    buffer.extend_from_slice(&size.to_le_bytes());
+    buffer.extend_from_slice(&(name_len as u16).to_le_bytes());
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -180,4 +180,102 @@ impl Visualization {
 
         Ok(())
     }
+
+    /// Filled contour plot of `values[j][i]` sampled at `(xs[i], ys[j])`,
+    /// with `levels` evenly spaced isolines traced by marching squares
+    pub fn plot_contour<P: AsRef<Path>>(
+        xs: &[f32],
+        ys: &[f32],
+        values: &[Vec<f32>],
+        levels: usize,
+        output_path: P,
+        config: VisualizationBuilder,
+    ) -> Result<(), Box<dyn Error>> {
+        if xs.len() < 2 || ys.len() < 2 {
+            return Err("Contour plots need at least a 2x2 grid".into());
+        }
+        if values.len() != ys.len() || values.iter().any(|row| row.len() != xs.len()) {
+            return Err("Contour values do not match the grid size".into());
+        }
+
+        let finite = values.iter().flatten().filter(|v| v.is_finite());
+        let min_value = finite.clone().fold(f32::INFINITY, |a, &b| a.min(b));
+        let max_value = finite.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        let span = (max_value - min_value).max(f32::EPSILON);
+
+        let root = BitMapBackend::new(output_path.as_ref(), (config.width, config.height))
+            .into_drawing_area();
+        root.fill(&WHITE)?;
+
+        let mut chart = ChartBuilder::on(&root)
+            .caption(&config.title, ("sans-serif", 40))
+            .margin(10)
+            .x_label_area_size(40)
+            .y_label_area_size(40)
+            .build_cartesian_2d(xs[0]..xs[xs.len() - 1], ys[0]..ys[ys.len() - 1])?;
+
+        chart
+            .configure_mesh()
+            .disable_x_mesh()
+            .disable_y_mesh()
+            .x_desc(&config.x_label)
+            .y_desc(&config.y_label)
+            .draw()?;
+
+        // Cells are colored by the mean of their corners, blue (low) to red (high)
+        let mut cells = Vec::new();
+        for j in 0..ys.len() - 1 {
+            for i in 0..xs.len() - 1 {
+                let mean =
+                    (values[j][i] + values[j][i + 1] + values[j + 1][i] + values[j + 1][i + 1])
+                        / 4.0;
+                let t = if mean.is_finite() {
+                    ((mean - min_value) / span).clamp(0.0, 1.0) as f64
+                } else {
+                    1.0
+                };
+                cells.push(Rectangle::new(
+                    [(xs[i], ys[j]), (xs[i + 1], ys[j + 1])],
+                    HSLColor(0.66 * (1.0 - t), 0.8, 0.55).filled(),
+                ));
+            }
+        }
+        chart.draw_series(cells)?;
+
+        let mut segments = Vec::new();
+        for level in 1..=levels {
+            let threshold = min_value + span * level as f32 / (levels + 1) as f32;
+            for j in 0..ys.len() - 1 {
+                for i in 0..xs.len() - 1 {
+                    let corners = [
+                        ((xs[i], ys[j]), values[j][i]),
+                        ((xs[i + 1], ys[j]), values[j][i + 1]),
+                        ((xs[i + 1], ys[j + 1]), values[j + 1][i + 1]),
+                        ((xs[i], ys[j + 1]), values[j + 1][i]),
+                    ];
+                    let crossings: Vec<(f32, f32)> = (0..4)
+                        .filter_map(|edge| {
+                            let ((x0, y0), v0) = corners[edge];
+                            let ((x1, y1), v1) = corners[(edge + 1) % 4];
+                            if (v0 < threshold) == (v1 < threshold) {
+                                return None;
+                            }
+                            let t = (threshold - v0) / (v1 - v0);
+                            Some((x0 + t * (x1 - x0), y0 + t * (y1 - y0)))
+                        })
+                        .collect();
+                    for pair in crossings.chunks_exact(2) {
+                        segments.push(PathElement::new(
+                            vec![pair[0], pair[1]],
+                            BLACK.mix(0.7).stroke_width(1),
+                        ));
+                    }
+                }
+            }
+        }
+        chart.draw_series(segments)?;
+
+        root.present()?;
+        Ok(())
+    }
 }